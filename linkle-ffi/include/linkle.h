@@ -0,0 +1,91 @@
+/*
+ * C bindings to linkle, the Nintendo file format manipulation library.
+ *
+ * Inputs are buffers owned by the caller. Outputs are LinkleBuffers
+ * allocated by linkle, which must be released with linkle_buffer_free.
+ *
+ * Functions return LINKLE_OK, or the exit code the linkle binary uses for the
+ * kind of error, in which case linkle_last_error gives the message.
+ */
+
+#ifndef LINKLE_H
+#define LINKLE_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define LINKLE_OK 0
+/* Anything else, like a panic. */
+#define LINKLE_ERROR_OTHER 1
+/* An input or argument isn't in the expected format. */
+#define LINKLE_ERROR_PARSE 2
+/* The input parsed but isn't valid, like a NACP with inconsistent fields. */
+#define LINKLE_ERROR_VALIDATION 3
+/* Keys are missing. */
+#define LINKLE_ERROR_MISSING_KEYS 4
+/* Other IO errors. */
+#define LINKLE_ERROR_IO 5
+
+/* Data allocated by linkle, to release with linkle_buffer_free. */
+typedef struct LinkleBuffer {
+    uint8_t *data;
+    size_t len;
+} LinkleBuffer;
+
+/* A file to put in a PFS0 or a RomFS. RomFS names are paths like
+ * "data/level1.bin". */
+typedef struct LinkleFile {
+    const char *name;
+    const uint8_t *data;
+    size_t len;
+} LinkleFile;
+
+/* Called for each file extracted from an archive, with its name and content,
+ * which are only valid during the call. Returning anything but 0 stops the
+ * extraction, and the extracting function returns that value. */
+typedef int (*LinkleFileCallback)(void *user_data, const char *name, const uint8_t *data,
+                                  size_t len);
+
+/* Message of the last error of this thread, or NULL after a success. It stays
+ * valid until the next linkle call on this thread. */
+const char *linkle_last_error(void);
+
+/* Releases a buffer returned by linkle. Null buffers are ignored. */
+void linkle_buffer_free(LinkleBuffer buffer);
+
+/* Converts an ELF to an NSO. */
+int linkle_nso_from_elf(const uint8_t *elf, size_t elf_len, LinkleBuffer *out);
+
+/* Converts an ELF to an NRO. nacp_json, a NACP descriptor in JSON, and the
+ * JPEG icon are optional: pass NULL to leave them out. */
+int linkle_nro_from_elf(const uint8_t *elf, size_t elf_len, const char *nacp_json,
+                        const uint8_t *icon, size_t icon_len, LinkleBuffer *out);
+
+/* Builds an NPDM from its JSON, as npdmtool does: the ACID public key and
+ * signature are left zeroed. */
+int linkle_npdm_from_json(const char *json, LinkleBuffer *out);
+
+/* Packs count files into a PFS0. */
+int linkle_pfs0_pack(const LinkleFile *files, size_t count, LinkleBuffer *out);
+
+/* Calls callback with each file of a PFS0 or HFS0. */
+int linkle_pfs0_extract(const uint8_t *pfs0, size_t pfs0_len, LinkleFileCallback callback,
+                        void *user_data);
+
+/* Packs count files into a RomFS image, creating their directories. */
+int linkle_romfs_pack(const LinkleFile *files, size_t count, LinkleBuffer *out);
+
+/* Calls callback with each file of a RomFS image, named by its path within
+ * the image, like "/data/level1.bin". */
+int linkle_romfs_extract(const uint8_t *romfs, size_t romfs_len, LinkleFileCallback callback,
+                         void *user_data);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,427 @@
+//! C bindings to linkle, for toolchains that can't use the Rust crate, like
+//! devkitPro pipelines. Everything works on buffers: inputs are pointers and
+//! lengths owned by the caller, outputs are `LinkleBuffer`s allocated here and
+//! released with `linkle_buffer_free`.
+//!
+//! Functions return `LINKLE_OK`, or the exit code of the linkle binary for the
+//! kind of error (see `ErrorClass`), in which case `linkle_last_error` gives
+//! the message. The declarations are in `include/linkle.h`.
+//!
+//! # Safety
+//!
+//! Pointers must be null or valid for the given lengths, and strings must be
+//! NUL-terminated. Null pointers are reported as errors, except where they
+//! stand for optional arguments.
+
+// The safety requirements are the same for all functions, see above.
+#![allow(clippy::missing_safety_doc)]
+
+use linkle::error::{Error, ErrorClass};
+use linkle::format::nacp::{DescriptorFormat, NacpFile};
+use linkle::format::npdm::Npdm;
+use linkle::format::nxo::NxoFile;
+use linkle::format::pfs0::Pfs0;
+use linkle::format::romfs::{RomFsBuilder, RomFsEntry, RomFsReader};
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::io::{self, Cursor, Read};
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+pub const LINKLE_OK: c_int = 0;
+
+/// Data allocated by linkle, to release with `linkle_buffer_free`.
+#[repr(C)]
+pub struct LinkleBuffer {
+    pub data: *mut u8,
+    pub len: usize,
+}
+
+/// A file to put in a PFS0 or a RomFS. RomFS names are paths like
+/// `data/level1.bin`.
+#[repr(C)]
+pub struct LinkleFile {
+    pub name: *const c_char,
+    pub data: *const u8,
+    pub len: usize,
+}
+
+/// Called for each file extracted from an archive, with its name and content,
+/// which are only valid during the call. Returning anything but 0 stops the
+/// extraction, and the extracting function returns that value.
+pub type LinkleFileCallback = extern "C" fn(
+    user_data: *mut c_void,
+    name: *const c_char,
+    data: *const u8,
+    len: usize,
+) -> c_int;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::default();
+}
+
+/// What went wrong in a call, turned into its error code.
+enum FfiError {
+    Linkle(Error),
+    /// A null pointer or a string that isn't UTF-8.
+    Argument(&'static str),
+    /// Returned by a callback.
+    Callback(c_int),
+}
+
+impl From<Error> for FfiError {
+    fn from(error: Error) -> FfiError {
+        FfiError::Linkle(error)
+    }
+}
+
+impl From<io::Error> for FfiError {
+    fn from(error: io::Error) -> FfiError {
+        FfiError::Linkle(error.into())
+    }
+}
+
+fn set_last_error(message: String) {
+    // Messages can't hold NULs, as they are C strings.
+    let message = CString::new(message.replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// Runs `f`, turning its errors and panics into error codes and setting the
+/// last error, as panics can't unwind into C.
+fn ffi_call<F: FnOnce() -> Result<(), FfiError>>(f: F) -> c_int {
+    LAST_ERROR.with(|last| *last.borrow_mut() = None);
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => LINKLE_OK,
+        Ok(Err(FfiError::Linkle(error))) => {
+            set_last_error(error.to_string());
+            error.class().exit_code()
+        }
+        Ok(Err(FfiError::Argument(message))) => {
+            set_last_error(message.to_string());
+            ErrorClass::Parse.exit_code()
+        }
+        Ok(Err(FfiError::Callback(code))) => {
+            set_last_error(format!("stopped by the callback, which returned {}", code));
+            code
+        }
+        Err(_) => {
+            set_last_error("internal error, linkle panicked".to_string());
+            ErrorClass::Other.exit_code()
+        }
+    }
+}
+
+/// The `len` bytes at `data`, which may only be null if `len` is 0.
+unsafe fn input<'a>(data: *const u8, len: usize) -> Result<&'a [u8], FfiError> {
+    if len == 0 {
+        Ok(&[])
+    } else if data.is_null() {
+        Err(FfiError::Argument("null buffer"))
+    } else {
+        Ok(std::slice::from_raw_parts(data, len))
+    }
+}
+
+unsafe fn input_str<'a>(string: *const c_char) -> Result<&'a str, FfiError> {
+    if string.is_null() {
+        return Err(FfiError::Argument("null string"));
+    }
+    CStr::from_ptr(string)
+        .to_str()
+        .map_err(|_| FfiError::Argument("string isn't valid UTF-8"))
+}
+
+unsafe fn input_files<'a>(
+    files: *const LinkleFile,
+    count: usize,
+) -> Result<Vec<(&'a str, &'a [u8])>, FfiError> {
+    if count == 0 {
+        return Ok(Vec::new());
+    }
+    if files.is_null() {
+        return Err(FfiError::Argument("null file list"));
+    }
+    std::slice::from_raw_parts(files, count)
+        .iter()
+        .map(|file| Ok((input_str(file.name)?, input(file.data, file.len)?)))
+        .collect()
+}
+
+/// Hands `data` over to the caller through `out`.
+unsafe fn output(out: *mut LinkleBuffer, data: Vec<u8>) -> Result<(), FfiError> {
+    if out.is_null() {
+        return Err(FfiError::Argument("null output buffer"));
+    }
+    let mut data = data.into_boxed_slice();
+    *out = LinkleBuffer {
+        data: data.as_mut_ptr(),
+        len: data.len(),
+    };
+    std::mem::forget(data);
+    Ok(())
+}
+
+fn call_back(
+    callback: LinkleFileCallback,
+    user_data: *mut c_void,
+    name: &str,
+    data: &[u8],
+) -> Result<(), FfiError> {
+    let name = CString::new(name).map_err(|_| FfiError::Argument("file name holds a NUL"))?;
+    match callback(user_data, name.as_ptr(), data.as_ptr(), data.len()) {
+        0 => Ok(()),
+        code => Err(FfiError::Callback(code)),
+    }
+}
+
+/// Message of the last error of this thread, or null after a success. It stays
+/// valid until the next linkle call on this thread.
+#[no_mangle]
+pub extern "C" fn linkle_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| match &*last.borrow() {
+        Some(message) => message.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+/// Releases a buffer returned by linkle. Null buffers are ignored.
+#[no_mangle]
+pub unsafe extern "C" fn linkle_buffer_free(buffer: LinkleBuffer) {
+    if !buffer.data.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+            buffer.data,
+            buffer.len,
+        )));
+    }
+}
+
+/// Converts an ELF to an NSO.
+#[no_mangle]
+pub unsafe extern "C" fn linkle_nso_from_elf(
+    elf: *const u8,
+    elf_len: usize,
+    out: *mut LinkleBuffer,
+) -> c_int {
+    ffi_call(|| {
+        let mut nxo = NxoFile::from_reader(Cursor::new(input(elf, elf_len)?))?;
+        let mut data = Vec::new();
+        nxo.write_nso(&mut data)?;
+        output(out, data)
+    })
+}
+
+/// Converts an ELF to an NRO. `nacp_json`, a NACP descriptor in JSON, and the
+/// JPEG `icon` are optional: pass null to leave them out.
+#[no_mangle]
+pub unsafe extern "C" fn linkle_nro_from_elf(
+    elf: *const u8,
+    elf_len: usize,
+    nacp_json: *const c_char,
+    icon: *const u8,
+    icon_len: usize,
+    out: *mut LinkleBuffer,
+) -> c_int {
+    ffi_call(|| {
+        let mut nxo = NxoFile::from_reader(Cursor::new(input(elf, elf_len)?))?;
+        let nacp = if nacp_json.is_null() {
+            None
+        } else {
+            let json = input_str(nacp_json)?;
+            let nacp = NacpFile::from_descriptor(json.as_bytes(), DescriptorFormat::Json)?;
+            nacp.validate()?;
+            Some(nacp)
+        };
+        let icon = if icon.is_null() {
+            None
+        } else {
+            Some(input(icon, icon_len)?)
+        };
+        let mut data = Vec::new();
+        nxo.write_nro(&mut data, None, icon, nacp)?;
+        output(out, data)
+    })
+}
+
+/// Builds an NPDM from its JSON, as npdmtool does: the ACID public key and
+/// signature are left zeroed.
+#[no_mangle]
+pub unsafe extern "C" fn linkle_npdm_from_json(
+    json: *const c_char,
+    out: *mut LinkleBuffer,
+) -> c_int {
+    ffi_call(|| {
+        let npdm = Npdm::from_reader(input_str(json)?.as_bytes())?;
+        output(out, npdm.to_bytes(&[0; 0x100])?)
+    })
+}
+
+/// Packs `count` files into a PFS0.
+#[no_mangle]
+pub unsafe extern "C" fn linkle_pfs0_pack(
+    files: *const LinkleFile,
+    count: usize,
+    out: *mut LinkleBuffer,
+) -> c_int {
+    ffi_call(|| {
+        let mut pfs0 = Pfs0::new();
+        for (name, data) in input_files(files, count)? {
+            pfs0.push_data(name.to_string(), data.to_vec())?;
+        }
+        let mut data = Cursor::new(Vec::new());
+        pfs0.write_pfs0(&mut data)?;
+        output(out, data.into_inner())
+    })
+}
+
+/// Calls `callback` with each file of a PFS0 or HFS0.
+#[no_mangle]
+pub unsafe extern "C" fn linkle_pfs0_extract(
+    pfs0: *const u8,
+    pfs0_len: usize,
+    callback: LinkleFileCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    ffi_call(|| {
+        let data = input(pfs0, pfs0_len)?.to_vec();
+        let pfs0 = Pfs0::from_reader(Cursor::new(data))?;
+        for file in pfs0.files() {
+            let mut file = file?;
+            let mut content = Vec::new();
+            file.read_to_end(&mut content)?;
+            call_back(callback, user_data, file.file_name(), &content)?;
+        }
+        Ok(())
+    })
+}
+
+/// Packs `count` files into a RomFS image, creating their directories.
+#[no_mangle]
+pub unsafe extern "C" fn linkle_romfs_pack(
+    files: *const LinkleFile,
+    count: usize,
+    out: *mut LinkleBuffer,
+) -> c_int {
+    ffi_call(|| {
+        let mut builder = RomFsBuilder::new();
+        for (name, data) in input_files(files, count)? {
+            let size = data.len() as u64;
+            builder.add_file(name, Cursor::new(data.to_vec()), size)?;
+        }
+        let mut data = Vec::new();
+        builder.build().write(&mut data)?;
+        output(out, data)
+    })
+}
+
+/// Calls `callback` with each file of a RomFS image, named by its path within
+/// the image, like `/data/level1.bin`.
+#[no_mangle]
+pub unsafe extern "C" fn linkle_romfs_extract(
+    romfs: *const u8,
+    romfs_len: usize,
+    callback: LinkleFileCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    ffi_call(|| {
+        let mut reader = RomFsReader::from_reader(Cursor::new(input(romfs, romfs_len)?))?;
+        for entry in reader.entries()? {
+            if let RomFsEntry::File { path, offset, size } = entry {
+                let mut content = Vec::new();
+                reader.open_file(offset, size)?.read_to_end(&mut content)?;
+                call_back(callback, user_data, &path, &content)?;
+            }
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    extern "C" fn collect(
+        user_data: *mut c_void,
+        name: *const c_char,
+        data: *const u8,
+        len: usize,
+    ) -> c_int {
+        let files = unsafe { &mut *(user_data as *mut Vec<(String, Vec<u8>)>) };
+        let name = unsafe { CStr::from_ptr(name) }
+            .to_str()
+            .unwrap()
+            .to_string();
+        files.push((
+            name,
+            unsafe { std::slice::from_raw_parts(data, len) }.to_vec(),
+        ));
+        0
+    }
+
+    fn round_trip(
+        pack: unsafe extern "C" fn(*const LinkleFile, usize, *mut LinkleBuffer) -> c_int,
+        extract: unsafe extern "C" fn(*const u8, usize, LinkleFileCallback, *mut c_void) -> c_int,
+    ) -> Vec<(String, Vec<u8>)> {
+        let names = [
+            CString::new("main").unwrap(),
+            CString::new("data/a.bin").unwrap(),
+        ];
+        let contents: [&[u8]; 2] = [b"code", b"level"];
+        let files = names
+            .iter()
+            .zip(&contents)
+            .map(|(name, data)| LinkleFile {
+                name: name.as_ptr(),
+                data: data.as_ptr(),
+                len: data.len(),
+            })
+            .collect::<Vec<_>>();
+        let mut buffer = LinkleBuffer {
+            data: ptr::null_mut(),
+            len: 0,
+        };
+        let mut extracted: Vec<(String, Vec<u8>)> = Vec::new();
+        unsafe {
+            assert_eq!(pack(files.as_ptr(), files.len(), &mut buffer), LINKLE_OK);
+            let user_data = &mut extracted as *mut _ as *mut c_void;
+            assert_eq!(
+                extract(buffer.data, buffer.len, collect, user_data),
+                LINKLE_OK
+            );
+            linkle_buffer_free(buffer);
+        }
+        extracted.sort();
+        extracted
+    }
+
+    #[test]
+    fn archives_round_trip() {
+        let file = |name: &str, data: &[u8]| (name.to_string(), data.to_vec());
+        assert_eq!(
+            round_trip(linkle_pfs0_pack, linkle_pfs0_extract),
+            [file("data/a.bin", b"level"), file("main", b"code")]
+        );
+        assert_eq!(
+            round_trip(linkle_romfs_pack, linkle_romfs_extract),
+            [file("/data/a.bin", b"level"), file("/main", b"code")]
+        );
+    }
+
+    #[test]
+    fn errors_have_codes_and_messages() {
+        let json = CString::new("{}").unwrap();
+        let mut buffer = LinkleBuffer {
+            data: ptr::null_mut(),
+            len: 0,
+        };
+        let code = unsafe { linkle_npdm_from_json(json.as_ptr(), &mut buffer) };
+        assert_eq!(code, ErrorClass::Parse.exit_code());
+        assert!(buffer.data.is_null());
+        let message = unsafe { CStr::from_ptr(linkle_last_error()) };
+        assert!(message.to_str().unwrap().contains("missing field"));
+
+        let code = unsafe { linkle_nso_from_elf(b"ELF?".as_ptr(), 4, &mut buffer) };
+        assert_ne!(code, LINKLE_OK);
+    }
+}
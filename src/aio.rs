@@ -0,0 +1,106 @@
+//! Async wrappers for servers using tokio, like packaging services building
+//! NSPs or RomFS images from uploads.
+//!
+//! The builders and readers of linkle are synchronous and need seekable
+//! files, so streams are spooled to anonymous temporary files, and the actual
+//! work runs on tokio's blocking threads. Executor threads only copy data.
+
+use crate::error::Error;
+use crate::format::pfs0::Pfs0;
+use crate::format::romfs::{RomFsBuilder, RomFsReader};
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom};
+use tokio::io::{AsyncRead, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+/// Runs `f` on a blocking thread, for the synchronous functions of linkle.
+pub async fn blocking<T, F>(f: F) -> Result<T, Error>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, Error> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?
+}
+
+/// Copies `reader` to an anonymous temporary file, rewound, which the
+/// synchronous readers can then open from `blocking`.
+pub async fn spool<R: AsyncRead + Unpin>(mut reader: R) -> Result<File, Error> {
+    let file = blocking(|| Ok(tempfile::tempfile()?)).await?;
+    let mut file = tokio::fs::File::from_std(file);
+    tokio::io::copy(&mut reader, &mut file).await?;
+    file.flush().await?;
+    file.seek(SeekFrom::Start(0)).await?;
+    Ok(file.into_std().await)
+}
+
+/// Runs `build` on a blocking thread, writing to an anonymous temporary file,
+/// then copies the result to `writer`. The file is seekable, as PFS0s need.
+pub async fn write_with<W, F>(writer: &mut W, build: F) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+    F: FnOnce(&mut File) -> Result<(), Error> + Send + 'static,
+{
+    let file = blocking(move || {
+        let mut file = tempfile::tempfile()?;
+        build(&mut file)?;
+        file.seek(SeekFrom::Start(0))?;
+        Ok(file)
+    })
+    .await?;
+    let mut file = tokio::fs::File::from_std(file);
+    tokio::io::copy(&mut file, writer).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Builds a PFS0, like an NSP, out of streams named by `files`, writing it to
+/// `writer`.
+pub async fn write_pfs0<R, W>(files: Vec<(String, R)>, writer: &mut W) -> Result<(), Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut spooled = Vec::with_capacity(files.len());
+    for (name, reader) in files {
+        spooled.push((name, spool(reader).await?));
+    }
+    write_with(writer, move |out| {
+        let mut pfs0 = Pfs0::new();
+        for (name, file) in spooled {
+            pfs0.push_reader(name, file)?;
+        }
+        pfs0.write_pfs0(out)?;
+        Ok(())
+    })
+    .await
+}
+
+/// Builds a RomFS image out of streams at the paths given by `files`, like
+/// `data/level1.bin`, writing it to `writer`.
+pub async fn write_romfs<R, W>(files: Vec<(String, R)>, writer: &mut W) -> Result<(), Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut spooled = Vec::with_capacity(files.len());
+    for (path, reader) in files {
+        spooled.push((path, spool(reader).await?));
+    }
+    write_with(writer, move |out| {
+        let mut builder = RomFsBuilder::new();
+        for (path, file) in spooled {
+            let size = file.metadata()?.len();
+            builder.add_file(&path, file, size)?;
+        }
+        builder.build().write(out)?;
+        Ok(())
+    })
+    .await
+}
+
+/// Opens the RomFS image streamed by `reader`.
+pub async fn open_romfs<R: AsyncRead + Unpin>(reader: R) -> Result<RomFsReader<File>, Error> {
+    let file = spool(reader).await?;
+    blocking(move || RomFsReader::from_reader(file)).await
+}
@@ -216,6 +216,7 @@ struct PackageMetadata {
     romfs: Option<String>,
     nacp: Option<NacpFile>,
     icon: Option<String>,
+    reencode_icon: Option<bool>,
     title_id: Option<String>,
 }
 
@@ -260,6 +261,8 @@ fn main() {
         .after_help(CARGO_OPTIONS)
         .get_matches_from(args);
 
+    linkle::logger::init(&["cargo_nro", "linkle"], 0, 0);
+
     let metadata = cargo_metadata::MetadataCommand::new().exec().unwrap();
 
     let rust_target_path = match env::var("RUST_TARGET_PATH") {
@@ -349,8 +352,11 @@ fn main() {
                     None
                 };
 
-                let icon_file = icon_file.map(|v| v.to_string_lossy().into_owned());
-                let icon_file = icon_file.as_ref().map(|v| v.as_ref());
+                let reencode_icon = target_metadata.reencode_icon.unwrap_or(false);
+                let icon = icon_file.map(|path| {
+                    linkle::format::icon::load_icon(&path, reencode_icon)
+                        .unwrap_or_else(|err| panic!("{}", err))
+                });
 
                 let mut nacp = target_metadata.nacp.unwrap_or_default();
                 nacp.name.get_or_insert(package.name.clone());
@@ -359,6 +365,13 @@ fn main() {
                 if nacp.title_id.is_none() {
                     nacp.title_id = target_metadata.title_id;
                 }
+                nacp.apply_preset();
+                if let Err(err) = nacp.validate() {
+                    panic!(
+                        "Invalid NACP metadata for {}: {}",
+                        artifact.target.name, err
+                    );
+                }
 
                 let romfs =
                     generate_debuginfo_romfs(Path::new(&artifact.filenames[0]), romfs).unwrap();
@@ -371,7 +384,7 @@ fn main() {
                     .write_nro(
                         &mut File::create(new_name.clone()).unwrap(),
                         Some(romfs),
-                        icon_file,
+                        icon.as_deref(),
                         Some(nacp),
                     )
                     .unwrap();
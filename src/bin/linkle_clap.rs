@@ -3,27 +3,104 @@ extern crate structopt;
 extern crate linkle;
 
 use linkle::error::ResultExt;
+use std::convert::TryFrom;
 use std::fs::{File, OpenOptions};
 use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
 #[structopt(name = "linkle", about = "The legendary hero")]
+struct Args {
+    /// How errors are printed: `human`, or `json` for a JSON object on
+    /// stderr with the error class, exit code, message and the file or field
+    /// it is about.
+    #[structopt(
+        long = "error-format",
+        default_value = "human",
+        possible_values = &["human", "json"],
+        global = true
+    )]
+    error_format: String,
+    /// Prints more messages, like every file written. Repeat it for even
+    /// more. The level can also be set with `LINKLE_LOG`.
+    #[structopt(short = "v", long = "verbose", parse(from_occurrences), global = true)]
+    verbose: usize,
+    /// Prints fewer messages: only warnings and errors, or only errors when
+    /// repeated.
+    #[structopt(short = "q", long = "quiet", parse(from_occurrences), global = true)]
+    quiet: usize,
+    /// Writes a <file>.sha256 next to every file built, in the format of
+    /// sha256sum.
+    #[structopt(long = "checksums", global = true)]
+    checksums: bool,
+    /// Writes the SHA-256 of every file built to this manifest, in the
+    /// format of sha256sum, along with the <file>.sha256 files.
+    #[structopt(parse(from_os_str), long = "checksum-manifest", global = true)]
+    checksum_manifest: Option<PathBuf>,
+    /// File of `rights_id = titlekey` pairs to use, instead of the
+    /// title.keys next to the keyset.
+    #[structopt(parse(from_os_str), long = "titlekeys", global = true)]
+    titlekeys: Option<PathBuf>,
+    /// File of console unique keys to use, instead of the console.keys next
+    /// to the keyset.
+    #[structopt(parse(from_os_str), long = "console-keys", global = true)]
+    console_keys: Option<PathBuf>,
+    /// Refuses to replace existing output files, unless --force is given.
+    #[structopt(long = "no-clobber", global = true)]
+    no_clobber: bool,
+    /// Replaces existing output files despite --no-clobber, and the
+    /// templates of `new`. `build` also builds the up to date outputs.
+    #[structopt(long = "force", global = true)]
+    force: bool,
+    /// Asks for the required fields missing from NPDM and NACP JSON
+    /// descriptors when stdin is a terminal, and offers to write them back.
+    #[structopt(long = "prompt", global = true)]
+    prompt: bool,
+    /// Checks inputs before using them, rejecting out of bounds and
+    /// overlapping regions, like the sections of NCAs or the tables of PFS0s
+    /// and saves. For files that can't be trusted, like downloaded ones.
+    #[structopt(long = "strict", global = true)]
+    strict: bool,
+    #[structopt(subcommand)]
+    command: Opt,
+}
+
+#[derive(StructOpt)]
 enum Opt {
-    /// Create a NRO file from an ELF file.
+    /// Create a NRO file from an ELF file, or NROs from several ELFs.
     #[structopt(name = "nro")]
     Nro {
-        /// Sets the input file to use.
-        input_file: String,
+        /// Sets the input files to use. Glob patterns like `build/*.elf` are
+        /// expanded, and directories stand for the ELFs they hold. With
+        /// several inputs, the output is a directory.
+        #[structopt(required = true)]
+        input_files: Vec<String>,
 
-        /// Sets the output file to use.
+        /// Sets the output file to use, or the output directory with several
+        /// inputs.
         output_file: String,
 
+        /// Number of files converted in parallel with several inputs.
+        /// Defaults to the number of CPUs.
+        #[structopt(short = "j", long = "jobs")]
+        jobs: Option<usize>,
+
+        /// Rebuilds the output each time one of the inputs changes, until
+        /// interrupted.
+        #[structopt(long = "watch")]
+        watch: bool,
+
         /// Sets the icon to use when bundling into an NRO.
         #[structopt(long = "icon-path")]
         icon: Option<String>,
 
+        /// Converts the icon to a 256x256 baseline JPEG if it isn't one,
+        /// instead of failing.
+        #[structopt(long = "reencode-icon")]
+        reencode_icon: bool,
+
         /// Sets the directory to use as RomFs when bundling into an NRO.
         #[structopt(long = "romfs-path")]
         romfs: Option<String>,
@@ -32,31 +109,75 @@ enum Opt {
         #[structopt(long = "nacp-path")]
         nacp: Option<String>,
     },
-    /// Create a NSO file from an ELF file.
+    /// Create a NSO file from an ELF file, or NSOs from several ELFs.
     #[structopt(name = "nso")]
     Nso {
-        /// Sets the input file to use.
-        input_file: String,
-        /// Sets the output file to use.
+        /// Sets the input files to use. Glob patterns like `build/*.elf` are
+        /// expanded, and directories stand for the ELFs they hold. With
+        /// several inputs, the output is a directory.
+        #[structopt(required = true)]
+        input_files: Vec<String>,
+        /// Sets the output file to use, or the output directory with several
+        /// inputs.
         output_file: String,
+        /// Number of files converted in parallel with several inputs.
+        /// Defaults to the number of CPUs.
+        #[structopt(short = "j", long = "jobs")]
+        jobs: Option<usize>,
+        /// Rebuilds the output each time one of the inputs changes, until
+        /// interrupted.
+        #[structopt(long = "watch")]
+        watch: bool,
     },
-    /// Create a KIP file from an ELF and an NPDM file.
+    /// Create a KIP file from an ELF and an NPDM file, or KIPs from a
+    /// directory of ELFs.
     #[structopt(name = "kip")]
     Kip {
-        /// Sets the input ELF file to use.
+        /// Sets the input ELF file to use. A glob pattern or a directory
+        /// converts all the ELFs it stands for, and the output is a
+        /// directory.
         input_file: String,
-        /// Sets the input NPDM JSON file to use.
+        /// Sets the input NPDM JSON file to use. When converting several
+        /// ELFs, it can be a directory holding a <name>.json for each
+        /// <name>.elf.
         npdm_file: String,
+        /// Sets the output file to use, or the output directory with several
+        /// inputs.
+        output_file: String,
+        /// Number of files converted in parallel with several inputs.
+        /// Defaults to the number of CPUs.
+        #[structopt(short = "j", long = "jobs")]
+        jobs: Option<usize>,
+    },
+    /// Create an NPDM file from an NPDM JSON file, like npdmtool does.
+    #[structopt(name = "npdm")]
+    Npdm {
+        /// Sets the input NPDM JSON file to use.
+        input_file: String,
         /// Sets the output file to use.
         output_file: String,
     },
-    /// Create a PFS0 or NSP file from a directory.
+    /// Create a PFS0 or NSP file from a directory, or from the files at the
+    /// root of a ZIP archive.
     #[structopt(name = "pfs0"/*, raw(alias = "nsp")*/)]
     Pfs0 {
-        /// Sets the input directory to use.
+        /// Sets the input directory or .zip archive to use.
         input_directory: String,
         /// Sets the output file to use.
-        output_file: String,
+        #[structopt(required_unless = "dry-run")]
+        output_file: Option<String>,
+        /// Skips the files matching this glob pattern. Can be repeated.
+        #[structopt(long = "exclude", number_of_values = 1)]
+        exclude: Vec<String>,
+        /// Only keeps the files matching this glob pattern. Can be repeated.
+        #[structopt(long = "include", number_of_values = 1)]
+        include: Vec<String>,
+        /// What to do with symbolic links: follow, skip or error.
+        #[structopt(long = "symlinks", default_value = "follow")]
+        symlinks: linkle::filter::SymlinkPolicy,
+        /// Prints where each file would end up without writing the PFS0.
+        #[structopt(long = "dry-run")]
+        dry_run: bool,
     },
     /// Extract a PFS0 or NSP file.
     #[structopt(name = "pfs0_extract"/*, raw(alias = "nsp")*/)]
@@ -65,6 +186,23 @@ enum Opt {
         input_file: String,
         /// Sets the output directory to extract the PFS0 into.
         output_directory: String,
+        /// Only extracts the entry with this name. Use "-" as the output
+        /// directory to write it to stdout.
+        #[structopt(long = "file")]
+        file: Option<String>,
+    },
+    /// Add files to an existing PFS0 or NSP file.
+    #[structopt(name = "pfs0_append")]
+    Pfs0Append {
+        /// Sets the input PFS0 to use.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+        /// Sets the output file to use. May be the same as the input file.
+        #[structopt(parse(from_os_str))]
+        output_file: PathBuf,
+        /// Sets the files to add.
+        #[structopt(parse(from_os_str), required = true)]
+        files: Vec<PathBuf>,
     },
     /// Create a NACP file from a JSON file.
     #[structopt(name = "nacp")]
@@ -74,19 +212,335 @@ enum Opt {
         /// Sets the output file to use.
         output_file: String,
     },
+    /// Convert a NACP file back into the JSON file used to create it.
+    #[structopt(name = "nacp_extract")]
+    NacpExtract {
+        /// Sets the input NACP file to use.
+        input_file: String,
+        /// Sets the output file to use, as JSON unless it ends in .toml, .yaml
+        /// or .yml. Use "-" to write it to stdout.
+        output_file: String,
+    },
+    /// Extract the content of a CNMT, as found in meta NCAs, to JSON.
+    #[structopt(name = "cnmt_extract")]
+    CnmtExtract {
+        /// Sets the input CNMT file to use.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+        /// Sets the output JSON file to use. Use "-" to write it to stdout.
+        #[structopt(parse(from_os_str))]
+        output_file: PathBuf,
+    },
+    /// Rewrite a NACP descriptor in its canonical form: presets applied,
+    /// fields with their default value left out, languages named by their
+    /// code and fields in a stable order.
+    #[structopt(name = "nacp_canonicalize")]
+    NacpCanonicalize {
+        /// Sets the input descriptor, or NACP file, to use.
+        input_file: String,
+        /// Sets the output file to use, as JSON unless it ends in .toml,
+        /// .yaml or .yml. Use "-" to write it to stdout.
+        output_file: String,
+    },
+    /// Change some fields of a NACP file, keeping the rest of it unchanged.
+    #[structopt(name = "nacp_patch")]
+    NacpPatch {
+        /// Sets the NACP file to patch.
+        input_file: String,
+        /// Writes the result to this file instead of overwriting the input.
+        #[structopt(short = "o", long = "output")]
+        output_file: Option<String>,
+        /// Sets a field, e.g. `version=1.0.1` or `lang.ja.name=...`. Values
+        /// are used as strings, or as JSON when the field isn't a string.
+        #[structopt(long = "set", number_of_values = 1)]
+        set: Vec<String>,
+        /// Applies the fields of a JSON, TOML or YAML file, using the same
+        /// fields as the files given to `nacp`.
+        #[structopt(long = "patch")]
+        patch: Option<String>,
+    },
+    /// Create a control directory, holding the NACP and the icons, from a
+    /// NACP descriptor.
+    #[structopt(name = "control")]
+    Control {
+        /// Sets the NACP descriptor to use.
+        input_file: String,
+        /// Sets the output directory to use.
+        #[structopt(parse(from_os_str))]
+        output_directory: PathBuf,
+        /// Sets the icon of the languages without their own, in any common
+        /// image format. It is converted to a 256x256 JPEG.
+        #[structopt(long = "icon", parse(from_os_str))]
+        icon: Option<PathBuf>,
+        /// Sets the icon of a language, e.g. `ja=icon_ja.png`.
+        #[structopt(long = "lang-icon", number_of_values = 1)]
+        lang_icons: Vec<String>,
+        /// Also packs the control directory into this RomFS file.
+        #[structopt(long = "romfs", parse(from_os_str))]
+        romfs: Option<PathBuf>,
+    },
+    /// Compare two NACPs field by field. Each of them can be a NACP file or a
+    /// JSON, TOML or YAML file like the ones given to `nacp`.
+    #[structopt(name = "nacp_diff")]
+    NacpDiff {
+        /// Sets the NACP to compare from.
+        old_file: String,
+        /// Sets the NACP to compare to.
+        new_file: String,
+        /// Prints the changes as JSON.
+        #[structopt(long = "json")]
+        json: bool,
+    },
     /// Create a RomFS file from a directory.
     #[structopt(name = "romfs")]
     Romfs {
-        /// Sets the input directory to use.
+        /// Sets the input directory, or .zip/.tar/.tar.gz archive, to use.
         #[structopt(parse(from_os_str))]
         input_directory: PathBuf,
+        /// Sets the output file to use.
+        #[structopt(parse(from_os_str), required_unless = "dry-run")]
+        output_file: Option<PathBuf>,
+        /// Merges this directory, or archive, over the input directory,
+        /// replacing files with the same path. Can be repeated, later overlays
+        /// win.
+        #[structopt(parse(from_os_str), long = "overlay", number_of_values = 1)]
+        overlay: Vec<PathBuf>,
+        /// Skips the files and directories matching this glob pattern. Can be repeated.
+        #[structopt(long = "exclude", number_of_values = 1)]
+        exclude: Vec<String>,
+        /// Only keeps the files matching this glob pattern. Can be repeated.
+        #[structopt(long = "include", number_of_values = 1)]
+        include: Vec<String>,
+        /// What to do with file names that are not ASCII: allow, error, skip
+        /// or transliterate.
+        #[structopt(long = "path-encoding", default_value = "allow")]
+        path_encoding: linkle::format::romfs::PathEncodingPolicy,
+        /// What to do with symbolic links: follow, skip or error.
+        #[structopt(long = "symlinks", default_value = "error")]
+        symlinks: linkle::filter::SymlinkPolicy,
+        /// Number of threads reading the input files. Defaults to the number
+        /// of CPUs.
+        #[structopt(short = "j", long = "jobs")]
+        jobs: Option<usize>,
+        /// Writes the SHA-256 of every file of the built image to this file.
+        #[structopt(parse(from_os_str), long = "manifest")]
+        manifest: Option<PathBuf>,
+        /// Number of buckets of the directory hash table: default, auto or
+        /// a number.
+        #[structopt(long = "dir-hash-buckets", default_value = "default")]
+        dir_hash_buckets: linkle::format::romfs::HashTableSize,
+        /// Number of buckets of the file hash table: default, auto or a
+        /// number.
+        #[structopt(long = "file-hash-buckets", default_value = "default")]
+        file_hash_buckets: linkle::format::romfs::HashTableSize,
+        /// Prints the size of the image and of its parts without writing it.
+        #[structopt(long = "dry-run", conflicts_with = "manifest")]
+        dry_run: bool,
+        /// Skips the build when the inputs have the same content as the last
+        /// time this command ran, remembered in .linkle-cache.
+        #[structopt(long = "cache")]
+        cache: bool,
+        /// Rebuilds the output each time one of the inputs changes, until
+        /// interrupted.
+        #[structopt(long = "watch")]
+        watch: bool,
+    },
+    /// List the content of a RomFS file.
+    #[structopt(name = "romfs_list")]
+    RomfsList {
+        /// Sets the input RomFS to use.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+        /// Only lists entries up to this many directories deep.
+        #[structopt(long = "depth")]
+        depth: Option<usize>,
+        /// Only lists the files matching this glob pattern (e.g. '**/*.bfres'),
+        /// as a flat list.
+        #[structopt(long = "find")]
+        find: Option<String>,
+        /// Prints the entries as JSON.
+        #[structopt(long = "json")]
+        json: bool,
+    },
+    /// Replace the content of a file inside a RomFS image, without rebuilding
+    /// it. The new content can't be bigger than the space used by the old one.
+    #[structopt(name = "romfs_replace")]
+    RomfsReplace {
+        /// Sets the RomFS to modify.
+        #[structopt(parse(from_os_str))]
+        romfs_file: PathBuf,
+        /// Path of the file to replace inside the RomFS (e.g. /data/a.bin).
+        path: String,
+        /// Sets the file holding the new content.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+    },
+    /// Extract a RomFS file.
+    #[structopt(name = "romfs_extract")]
+    RomfsExtract {
+        /// Sets the input RomFS to use.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+        /// Sets the output directory to extract the RomFS into.
+        #[structopt(parse(from_os_str))]
+        output_directory: PathBuf,
+        /// Only extracts the file at this path (e.g. /data/config.bin). Use
+        /// "-" as the output directory to write it to stdout.
+        #[structopt(long = "file")]
+        file: Option<String>,
+        /// Writes the SHA-256 of every file of the image to this file.
+        #[structopt(parse(from_os_str), long = "manifest", conflicts_with = "file")]
+        manifest: Option<PathBuf>,
+    },
+    /// Check the files of a RomFS against a manifest written by `romfs` or
+    /// `romfs_extract`.
+    #[structopt(name = "romfs_verify")]
+    RomfsVerify {
+        /// Sets the RomFS to check.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+        /// Sets the manifest to check against.
+        #[structopt(parse(from_os_str))]
+        manifest: PathBuf,
+        /// Prints the mismatches as JSON.
+        #[structopt(long = "json")]
+        json: bool,
+    },
+    /// Compare the files of two RomFS images.
+    #[structopt(name = "romfs_diff")]
+    RomfsDiff {
+        /// Sets the RomFS to compare from.
+        #[structopt(parse(from_os_str))]
+        old_file: PathBuf,
+        /// Sets the RomFS to compare to.
+        #[structopt(parse(from_os_str))]
+        new_file: PathBuf,
+        /// Prints the changes as JSON.
+        #[structopt(long = "json")]
+        json: bool,
+    },
+    /// List the content of a save file.
+    #[structopt(name = "save_list")]
+    SaveList {
+        /// Sets the input save file to use.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+        /// Prints the entries as JSON.
+        #[structopt(long = "json")]
+        json: bool,
+    },
+    /// Extract the files of a save file.
+    #[structopt(name = "save_extract")]
+    SaveExtract {
+        /// Sets the input save file to use.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+        /// Sets the output directory to extract the save into.
+        #[structopt(parse(from_os_str))]
+        output_directory: PathBuf,
+    },
+    /// Create an NCA file with plaintext sections from PFS0 and RomFS images.
+    #[structopt(name = "nca")]
+    Nca {
         /// Sets the output file to use.
         #[structopt(parse(from_os_str))]
         output_file: PathBuf,
+
+        /// Adds a section, as TYPE=FILE where TYPE is pfs0 or romfs. Can be
+        /// repeated, sections are numbered in order.
+        #[structopt(long = "section", number_of_values = 1, required = true)]
+        sections: Vec<String>,
+
+        /// Content type: program, meta, control, manual, data or
+        /// public_data.
+        #[structopt(long = "content-type", default_value = "program")]
+        content_type: linkle::format::nca::ContentType,
+
+        /// Program ID, in hexadecimal.
+        #[structopt(long = "program-id", parse(try_from_str = parse_program_id))]
+        program_id: u64,
+
+        /// Key generation written in the header.
+        #[structopt(long = "key-generation", default_value = "0")]
+        key_generation: u8,
+
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
     },
-    /// Print all the keys generated from our keyfile.
-    #[structopt(name = "keygen")]
-    Keygen {
+    /// Create a program NCA from an ExeFS directory, holding a main.npdm, and
+    /// a RomFS directory.
+    #[structopt(name = "nca_program")]
+    NcaProgram {
+        /// Sets the output file to use.
+        #[structopt(parse(from_os_str))]
+        output_file: PathBuf,
+
+        /// Sets the ExeFS directory to use.
+        #[structopt(parse(from_os_str), long = "exefs")]
+        exefs: PathBuf,
+
+        /// Sets the RomFS directory to use.
+        #[structopt(parse(from_os_str), long = "romfs")]
+        romfs: Option<PathBuf>,
+
+        /// Sets the logo directory to use, holding the NintendoLogo.png and
+        /// StartupMovie.gif shown while the program starts.
+        #[structopt(parse(from_os_str), long = "logo")]
+        logo: Option<PathBuf>,
+
+        /// Program NCA of the base title, making this the program NCA of an
+        /// update, whose RomFS only stores the differences with the base one.
+        #[structopt(parse(from_os_str), long = "base", requires = "romfs")]
+        base: Option<PathBuf>,
+
+        /// Ticket giving the titlekey of the base NCA, when it uses titlekey
+        /// crypto and it isn't in title.keys.
+        #[structopt(parse(from_os_str), long = "ticket", number_of_values = 1)]
+        tickets: Vec<PathBuf>,
+
+        /// Program ID, in hexadecimal. Defaults to the one of main.npdm.
+        #[structopt(long = "program-id", parse(try_from_str = parse_program_id))]
+        program_id: Option<u64>,
+
+        /// Index of the program in a multi-program title, stored in the header
+        /// and used as the ID offset of the NCA in the CNMT. Its program ID
+        /// should be the application ID plus the index.
+        #[structopt(long = "program-index", default_value = "0")]
+        program_index: u8,
+
+        /// Rebuilds main.npdm from this NPDM JSON, in the format of npdmtool.
+        /// Its program ID is replaced by --program-id.
+        #[structopt(parse(from_os_str), long = "npdm")]
+        npdm: Option<PathBuf>,
+
+        /// Signs the ACID of the main.npdm built from --npdm with this
+        /// RSA-2048 private key, as PEM or DER.
+        #[structopt(parse(from_os_str), long = "acid-signing-key", requires = "npdm")]
+        acid_signing_key: Option<PathBuf>,
+
+        /// Key generation to encrypt the NCA with.
+        #[structopt(long = "key-generation", default_value = "0")]
+        key_generation: u8,
+
+        /// RSA-2048 private key matching the ACID public key of main.npdm,
+        /// as PEM or DER, signing the header.
+        #[structopt(parse(from_os_str), long = "signing-key")]
+        signing_key: Option<PathBuf>,
+
+        /// Leaves the sections in plaintext.
+        #[structopt(long = "plaintext")]
+        plaintext: bool,
+
+        /// Compresses the sections with LZ4, as newer titles do. Older
+        /// firmwares can't read compressed sections.
+        #[structopt(long = "compress")]
+        compress: bool,
+
         /// Use development keys instead of retail
         #[structopt(short = "d", long = "dev")]
         dev: bool,
@@ -94,223 +548,6086 @@ enum Opt {
         /// Key file to use
         #[structopt(parse(from_os_str), short = "k", long = "keyset")]
         keyfile: Option<PathBuf>,
+    },
+    /// Create a control NCA from a control directory, holding a control.nacp
+    /// and the icons, as written by `control`.
+    #[structopt(name = "nca_control")]
+    NcaControl {
+        /// Sets the output file to use.
+        #[structopt(parse(from_os_str))]
+        output_file: PathBuf,
 
-        /// Print only the minimum amount of keys without losing information.
-        #[structopt(short = "m", long = "minimal")]
-        minimal: bool,
+        /// Sets the control directory to use.
+        #[structopt(parse(from_os_str), long = "control")]
+        control: PathBuf,
 
-        /// Show console unique keys along with non-console-unique keys.
-        #[structopt(long = "console-unique")]
-        show_console_unique: bool,
+        /// Program ID, in hexadecimal. Defaults to the one of control.nacp.
+        #[structopt(long = "program-id", parse(try_from_str = parse_program_id))]
+        program_id: Option<u64>,
+
+        /// Index of the program in a multi-program title, stored in the header
+        /// and used as the ID offset of the NCA in the CNMT. Its program ID
+        /// should be the application ID plus the index.
+        #[structopt(long = "program-index", default_value = "0")]
+        program_index: u8,
+
+        /// Key generation to encrypt the NCA with.
+        #[structopt(long = "key-generation", default_value = "0")]
+        key_generation: u8,
+
+        /// Leaves the sections in plaintext.
+        #[structopt(long = "plaintext")]
+        plaintext: bool,
+
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
     },
-}
+    /// Create a manual NCA from a directory of HTML documents, to list in the
+    /// CNMT as the html_document or legal_information of a title.
+    #[structopt(name = "nca_manual")]
+    NcaManual {
+        /// Sets the output file to use.
+        #[structopt(parse(from_os_str))]
+        output_file: PathBuf,
 
-fn create_nxo(
-    format: &str,
-    input_file: &str,
-    output_file: &str,
-    icon_file: Option<&str>,
-    romfs_dir: Option<&str>,
-    nacp_file: Option<&str>,
-) -> Result<(), linkle::error::Error> {
-    let romfs_dir = if let Some(romfs_path) = romfs_dir {
-        Some(linkle::format::romfs::RomFs::from_directory(Path::new(
-            &romfs_path,
-        ))?)
-    } else {
-        None
-    };
-    let nacp_file = if let Some(nacp_path) = nacp_file {
-        Some(
-            linkle::format::nacp::NacpFile::from_file(&nacp_path)
-                .map_err(|err| (err, &nacp_path))?,
-        )
-    } else {
-        None
-    };
+        /// Sets the documents directory to use, the root of the RomFS.
+        #[structopt(parse(from_os_str), long = "documents")]
+        documents: PathBuf,
 
-    let mut nxo =
-        linkle::format::nxo::NxoFile::from_elf(&input_file).map_err(|err| (err, &input_file))?;
-    let mut option = OpenOptions::new();
-    let output_option = option.write(true).create(true).truncate(true);
-    match format {
-        "nro" => {
-            let mut out_file = output_option
-                .open(output_file)
-                .map_err(|err| (err, output_file))?;
-            nxo.write_nro(&mut out_file, romfs_dir, icon_file.as_deref(), nacp_file)
-                .map_err(|err| (err, output_file))?;
-        }
-        "nso" => {
-            let mut out_file = output_option
-                .open(output_file)
-                .map_err(|err| (err, output_file))?;
-            nxo.write_nso(&mut out_file)
-                .map_err(|err| (err, output_file))?;
-        }
-        _ => process::exit(1),
-    }
-    Ok(())
-}
+        /// Program ID of the title, in hexadecimal.
+        #[structopt(long = "program-id", parse(try_from_str = parse_program_id))]
+        program_id: u64,
 
-fn create_kip(
-    input_file: &str,
-    npdm_file: &str,
-    output_file: &str,
-) -> Result<(), linkle::error::Error> {
-    let mut nxo =
-        linkle::format::nxo::NxoFile::from_elf(&input_file).map_err(|err| (err, &input_file))?;
-    let npdm = serde_json::from_reader(File::open(npdm_file).map_err(|err| (err, npdm_file))?)?;
+        /// Index of the program in a multi-program title, stored in the header
+        /// and used as the ID offset of the NCA in the CNMT. Its program ID
+        /// should be the application ID plus the index.
+        #[structopt(long = "program-index", default_value = "0")]
+        program_index: u8,
 
-    let mut option = OpenOptions::new();
-    let output_option = option.write(true).create(true).truncate(true);
-    output_option.open(output_file)?;
+        /// Key generation to encrypt the NCA with.
+        #[structopt(long = "key-generation", default_value = "0")]
+        key_generation: u8,
 
-    nxo.write_kip1(
-        &mut output_option
-            .open(output_file)
-            .map_err(|err| (err, output_file))?,
-        &npdm,
-    )
-    .map_err(|err| (err, output_file))?;
-    Ok(())
-}
+        /// Leaves the sections in plaintext.
+        #[structopt(long = "plaintext")]
+        plaintext: bool,
 
-fn create_pfs0(input_directory: &str, output_file: &str) -> Result<(), linkle::error::Error> {
-    let mut pfs0 = linkle::format::pfs0::Pfs0::from_directory(&input_directory)?;
-    let mut option = OpenOptions::new();
-    let output_option = option.write(true).create(true).truncate(true);
-    pfs0.write_pfs0(
-        &mut output_option
-            .open(output_file)
-            .map_err(|err| (err, output_file))?,
-    )
-    .map_err(|err| (err, output_file))?;
-    Ok(())
-}
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
 
-fn extract_pfs0(input_path: &str, output_directory: &str) -> Result<(), linkle::error::Error> {
-    let input_file = File::open(input_path).map_err(|err| (err, input_path))?;
-    let pfs0 = linkle::format::pfs0::Pfs0::from_reader(input_file).with_path(input_path)?;
-    let mut option = OpenOptions::new();
-    let output_option = option.write(true).create(true).truncate(true);
-    let path = Path::new(output_directory);
-    match std::fs::create_dir(path) {
-        Ok(()) => (),
-        Err(ref err) if err.kind() == std::io::ErrorKind::AlreadyExists => (),
-        Err(err) => return Err((err, path).into()),
-    }
-    for file in pfs0.files() {
-        let mut file = file?;
-        let name = path.join(file.file_name());
-        println!("Writing {}", file.file_name());
-        let mut out_file = output_option.open(&name).map_err(|err| (err, &name))?;
-        std::io::copy(&mut file, &mut out_file).map_err(|err| (err, &name))?;
-    }
-    Ok(())
-}
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+    },
+    /// Create the meta NCA of a title, listing the NCAs given with --content in
+    /// its CNMT.
+    #[structopt(name = "nca_meta")]
+    NcaMeta {
+        /// Sets the output file to use.
+        #[structopt(parse(from_os_str))]
+        output_file: PathBuf,
 
-fn create_nacp(input_file: &str, output_file: &str) -> Result<(), linkle::error::Error> {
-    let mut nacp = linkle::format::nacp::NacpFile::from_file(&input_file)?;
-    let mut option = OpenOptions::new();
-    let output_option = option.write(true).create(true).truncate(true);
-    let mut out_file = output_option
-        .open(output_file)
-        .map_err(|err| (err, output_file))?;
-    nacp.write(&mut out_file)
-        .map_err(|err| (err, output_file))?;
-    Ok(())
-}
+        /// Adds an NCA to the title, as TYPE=FILE. TYPE is one of program,
+        /// data, control, html_document, legal_information or delta_fragment.
+        /// The program of the title it belongs to is given by the program index
+        /// in its header.
+        #[structopt(long = "content", number_of_values = 1)]
+        contents: Vec<String>,
 
-fn create_romfs(input_directory: &Path, output_file: &Path) -> Result<(), linkle::error::Error> {
-    let romfs = linkle::format::romfs::RomFs::from_directory(&input_directory)?;
-    let mut option = OpenOptions::new();
-    let output_option = option.write(true).create(true).truncate(true);
-    let mut out_file = output_option
-        .open(output_file)
-        .map_err(|err| (err, output_file))?;
-    romfs
-        .write(&mut out_file)
-        .map_err(|err| (err, output_file))?;
-    Ok(())
-}
+        /// Type of the title: application, patch or add_on_content.
+        #[structopt(long = "type", default_value = "application")]
+        meta_type: linkle::format::cnmt::ContentMetaType,
 
-fn print_keys(
-    is_dev: bool,
-    key_path: Option<&Path>,
-    console_unique: bool,
-    minimal: bool,
-) -> Result<(), linkle::error::Error> {
-    let keys = if is_dev {
-        linkle::pki::Keys::new_dev(key_path).unwrap()
-    } else {
-        linkle::pki::Keys::new_retail(key_path).unwrap()
-    };
+        /// Title ID, in hexadecimal.
+        #[structopt(long = "title-id", parse(try_from_str = parse_program_id))]
+        title_id: u64,
 
-    keys.write(&mut std::io::stdout(), console_unique, minimal)
-        .unwrap();
-    Ok(())
-}
+        /// Version of the title.
+        #[structopt(long = "title-version", default_value = "0")]
+        title_version: u32,
 
-fn to_opt_ref<U: ?Sized, T: AsRef<U>>(s: &Option<T>) -> Option<&U> {
-    s.as_ref().map(AsRef::as_ref)
-}
+        /// Minimum system version required to launch the title.
+        #[structopt(long = "required-system-version", default_value = "0")]
+        required_system_version: u32,
 
-fn process_args(app: &Opt) {
-    let res = match app {
-        Opt::Nro {
-            ref input_file,
-            ref output_file,
-            ref icon,
-            ref romfs,
-            ref nacp,
-        } => create_nxo(
-            "nro",
-            input_file,
-            output_file,
-            to_opt_ref(icon),
-            to_opt_ref(romfs),
-            to_opt_ref(nacp),
-        ),
-        Opt::Nso {
-            ref input_file,
-            ref output_file,
-        } => create_nxo("nso", input_file, output_file, None, None, None),
-        Opt::Kip {
-            ref input_file,
-            ref npdm_file,
-            ref output_file,
-        } => create_kip(input_file, npdm_file, output_file),
-        Opt::Pfs0 {
-            ref input_directory,
-            ref output_file,
-        } => create_pfs0(input_directory, output_file),
-        Opt::Pfs0Extract {
-            ref input_file,
-            ref output_directory,
-        } => extract_pfs0(input_file, output_directory),
-        Opt::Nacp {
-            ref input_file,
-            ref output_file,
-        } => create_nacp(input_file, output_file),
-        Opt::Romfs {
-            ref input_directory,
-            ref output_file,
-        } => create_romfs(input_directory, output_file),
-        Opt::Keygen {
-            dev,
-            ref keyfile,
-            show_console_unique,
-            minimal,
-        } => print_keys(*dev, to_opt_ref(keyfile), *show_console_unique, *minimal),
-    };
+        /// Key generation to encrypt the NCA with.
+        #[structopt(long = "key-generation", default_value = "0")]
+        key_generation: u8,
 
-    if let Err(e) = res {
-        println!("Error: {}", e);
-        process::exit(1)
-    }
-}
+        /// Leaves the sections in plaintext.
+        #[structopt(long = "plaintext")]
+        plaintext: bool,
 
-fn main() {
-    process_args(&Opt::from_args());
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+    },
+    /// Check the signatures of the header of an NCA file: the first one
+    /// against the fixed key of its signature key generation, and the second
+    /// one of program NCAs against the ACID public key of their NPDM. Also
+    /// checks the hashes of every section.
+    #[structopt(name = "nca_verify")]
+    NcaVerify {
+        /// Sets the input file to use.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+
+        /// NCA patched by the input NCA, to check the hashes of its BKTR
+        /// section.
+        #[structopt(parse(from_os_str), long = "base")]
+        base: Option<PathBuf>,
+
+        /// Ticket giving the titlekey of NCAs using titlekey crypto, when it
+        /// isn't in title.keys. Personalized tickets need the eticket RSA key
+        /// of their console. Can be given several times.
+        #[structopt(parse(from_os_str), long = "ticket", number_of_values = 1)]
+        tickets: Vec<PathBuf>,
+
+        /// Prints the status of the signatures and sections as JSON.
+        #[structopt(long = "json")]
+        json: bool,
+
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+    },
+    /// Re-encrypt an NCA for another key generation, e.g. to install it on a
+    /// console with an older firmware. NCAs using titlekey crypto are
+    /// converted to standard crypto, which needs their titlekey in title.keys.
+    #[structopt(name = "nca_reencrypt")]
+    NcaReencrypt {
+        /// Sets the input file to use.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+
+        /// Sets the output file to use.
+        #[structopt(parse(from_os_str))]
+        output_file: PathBuf,
+
+        /// Key generation to encrypt the NCA with.
+        #[structopt(long = "key-generation")]
+        key_generation: u8,
+
+        /// Signs the header again with this RSA-2048 private key, as PEM or
+        /// DER, keeping the second signature valid.
+        #[structopt(parse(from_os_str), long = "signing-key")]
+        signing_key: Option<PathBuf>,
+
+        /// Ticket giving the titlekey of NCAs using titlekey crypto, when it
+        /// isn't in title.keys. Personalized tickets need the eticket RSA key
+        /// of their console. Can be given several times.
+        #[structopt(parse(from_os_str), long = "ticket", number_of_values = 1)]
+        tickets: Vec<PathBuf>,
+
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+    },
+    /// Sign the header of an NCA again with a custom RSA-2048 key, replacing
+    /// its second signature. The key of program NCAs must match the ACID
+    /// public key of their NPDM.
+    #[structopt(name = "nca_resign")]
+    NcaResign {
+        /// Sets the input file to use.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+
+        /// Sets the output file to use.
+        #[structopt(parse(from_os_str))]
+        output_file: PathBuf,
+
+        /// RSA-2048 private key signing the header, as PEM or DER.
+        #[structopt(parse(from_os_str), long = "signing-key")]
+        signing_key: PathBuf,
+
+        /// Ticket giving the titlekey of NCAs using titlekey crypto, when it
+        /// isn't in title.keys. Personalized tickets need the eticket RSA key
+        /// of their console. Can be given several times.
+        #[structopt(parse(from_os_str), long = "ticket", number_of_values = 1)]
+        tickets: Vec<PathBuf>,
+
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+    },
+    /// Print the header of an NCA file.
+    #[structopt(name = "nca_info")]
+    NcaInfo {
+        /// Sets the input NCA to use.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+
+        /// Prints the header as JSON.
+        #[structopt(long = "json")]
+        json: bool,
+
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+    },
+    /// Extract the sections of an NCA file.
+    #[structopt(name = "nca_extract")]
+    NcaExtract {
+        /// Sets the input NCA to use.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+
+        /// Extracts the files of the first PFS0 section, the ExeFS of program
+        /// NCAs, to this directory.
+        #[structopt(parse(from_os_str), long = "exefs")]
+        exefs: Option<PathBuf>,
+
+        /// Extracts the files of the RomFS section to this directory.
+        #[structopt(parse(from_os_str), long = "romfs")]
+        romfs: Option<PathBuf>,
+
+        /// Writes every decrypted section, as is, to this directory.
+        #[structopt(parse(from_os_str), long = "sections")]
+        sections: Option<PathBuf>,
+
+        /// NCA patched by the input NCA, e.g. the program NCA of the base
+        /// title when extracting the RomFS of an update.
+        #[structopt(parse(from_os_str), long = "base")]
+        base: Option<PathBuf>,
+
+        /// Ticket giving the titlekey of NCAs using titlekey crypto, when it
+        /// isn't in title.keys. Personalized tickets need the eticket RSA key
+        /// of their console. Can be given several times.
+        #[structopt(parse(from_os_str), long = "ticket", number_of_values = 1)]
+        tickets: Vec<PathBuf>,
+
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+    },
+    /// Print the build date of a Package1, the firmware it's guessed to come
+    /// from and the sections of its PK11.
+    #[structopt(name = "package1_info")]
+    Package1Info {
+        /// Sets the input Package1 to use.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+
+        /// Prints the information as JSON.
+        #[structopt(long = "json")]
+        json: bool,
+
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+    },
+    /// Extract the package1ldr and the warmboot, NX bootloader and secure
+    /// monitor sections of a Package1.
+    #[structopt(name = "package1_extract")]
+    Package1Extract {
+        /// Sets the input Package1 to use.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+
+        /// Sets the output directory to extract the sections into.
+        #[structopt(parse(from_os_str))]
+        output_directory: PathBuf,
+
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+    },
+    /// Print the header of a Package2, checking its signature and the hashes
+    /// of its sections, and list the KIPs of its INI1.
+    #[structopt(name = "package2_info")]
+    Package2Info {
+        /// Sets the input Package2 to use.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+
+        /// Prints the information as JSON.
+        #[structopt(long = "json")]
+        json: bool,
+
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+    },
+    /// Extract the kernel and the INI1 of a Package2, along with the KIPs of
+    /// the INI1.
+    #[structopt(name = "package2_extract")]
+    Package2Extract {
+        /// Sets the input Package2 to use.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+
+        /// Sets the output directory to extract the kernel and INI1 into.
+        #[structopt(parse(from_os_str))]
+        output_directory: PathBuf,
+
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+    },
+    /// Extract Package1 from a raw BOOT0 dump. The main copy is used, or the
+    /// backup one if the main copy is invalid.
+    #[structopt(name = "boot0_extract")]
+    Boot0Extract {
+        /// Sets the input BOOT0 dump to use.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+
+        /// Sets the output Package1 file to use.
+        #[structopt(parse(from_os_str))]
+        output_file: PathBuf,
+    },
+    /// Extract Package2 from a raw BCPKG2 partition dump. If the dump of the
+    /// Sub partition is given, it is used when the Main one is invalid.
+    #[structopt(name = "bcpkg2_extract")]
+    Bcpkg2Extract {
+        /// Sets the input BCPKG2 Main partition dump to use.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+
+        /// Sets the output Package2 file to use.
+        #[structopt(parse(from_os_str))]
+        output_file: PathBuf,
+
+        /// BCPKG2 Sub partition dump, a copy of the Main one.
+        #[structopt(parse(from_os_str), long = "sub")]
+        sub: Option<PathBuf>,
+
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+    },
+    /// Replace the INI1 of a Package2, or some of its KIPs, and encrypt it
+    /// again. The signature isn't updated, so it only boots with signature
+    /// checks patched out.
+    #[structopt(name = "package2_repack")]
+    Package2Repack {
+        /// Sets the input Package2 to use.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+
+        /// Sets the output file to use.
+        #[structopt(parse(from_os_str))]
+        output_file: PathBuf,
+
+        /// INI1 replacing the one of the package.
+        #[structopt(parse(from_os_str), long = "ini1")]
+        ini1: Option<PathBuf>,
+
+        /// KIP replacing the one with the same name in the INI1, or added to it
+        /// if there is none. Can be given several times.
+        #[structopt(parse(from_os_str), long = "kip", number_of_values = 1)]
+        kips: Vec<PathBuf>,
+
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+    },
+    /// Create an unsigned common ticket, giving the titlekey of the NCAs with
+    /// the given rights ID.
+    #[structopt(name = "tik")]
+    Tik {
+        /// Sets the output file to use.
+        #[structopt(parse(from_os_str))]
+        output_file: PathBuf,
+
+        /// Rights ID, in hexadecimal. Its last byte is the master key revision
+        /// the titlekey is encrypted with.
+        #[structopt(long = "rights-id", parse(try_from_str = parse_hex_key))]
+        rights_id: [u8; 0x10],
+
+        /// Decrypted titlekey, in hexadecimal.
+        #[structopt(long = "titlekey", parse(try_from_str = parse_hex_key))]
+        titlekey: [u8; 0x10],
+
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+    },
+    /// Print the content of a ticket, and its titlekey if the keyset has the
+    /// titlekek it is encrypted with.
+    #[structopt(name = "tik_info")]
+    TikInfo {
+        /// Sets the input file to use.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+
+        /// Prints the ticket as JSON.
+        #[structopt(long = "json")]
+        json: bool,
+
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+    },
+    /// Convert a personalized ticket to an unsigned common one, decrypting
+    /// its titlekey with the eticket RSA key of the console it was issued to.
+    #[structopt(name = "tik_depersonalize")]
+    TikDepersonalize {
+        /// Sets the input personalized ticket to use.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+
+        /// Sets the output common ticket to use.
+        #[structopt(parse(from_os_str))]
+        output_file: PathBuf,
+
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+    },
+    /// Print the certificates of a certificate chain, checking its structure.
+    #[structopt(name = "cert_info")]
+    CertInfo {
+        /// Sets the input file to use.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+
+        /// Prints the certificates as JSON.
+        #[structopt(long = "json")]
+        json: bool,
+    },
+    /// Build an installable NSP from an ELF or an ExeFS, an NPDM descriptor, a
+    /// NACP descriptor, an icon and a RomFS directory. The program, control
+    /// and meta NCAs are built and packed together.
+    #[structopt(name = "nsp")]
+    Nsp {
+        /// Sets the output file to use.
+        #[structopt(parse(from_os_str))]
+        output_file: PathBuf,
+
+        /// Sets the ELF file to use, converted to the main NSO of the ExeFS.
+        #[structopt(long = "elf")]
+        elf: Option<String>,
+
+        /// Sets the ExeFS directory to use. Its main and main.npdm are
+        /// replaced by the ones built from --elf and --npdm.
+        #[structopt(parse(from_os_str), long = "exefs")]
+        exefs: Option<PathBuf>,
+
+        /// Sets the NPDM JSON to use, in the format of npdmtool.
+        #[structopt(parse(from_os_str), long = "npdm")]
+        npdm: Option<PathBuf>,
+
+        /// Sets the NACP descriptor to use.
+        #[structopt(long = "nacp")]
+        nacp: String,
+
+        /// Sets the icon to use, in any common image format.
+        #[structopt(parse(from_os_str), long = "icon")]
+        icon: Option<PathBuf>,
+
+        /// Sets the RomFS directory to use.
+        #[structopt(parse(from_os_str), long = "romfs")]
+        romfs: Option<PathBuf>,
+
+        /// Sets the logo directory to use, holding the NintendoLogo.png and
+        /// StartupMovie.gif shown while the program starts.
+        #[structopt(parse(from_os_str), long = "logo")]
+        logo: Option<PathBuf>,
+
+        /// Sets the directory of HTML documents of the manual NCA of the
+        /// title, its HtmlDocument.
+        #[structopt(parse(from_os_str), long = "html-document")]
+        html_document: Option<PathBuf>,
+
+        /// Sets the directory of HTML documents of the legal information NCA
+        /// of the title.
+        #[structopt(parse(from_os_str), long = "legal-information")]
+        legal_information: Option<PathBuf>,
+
+        /// Program NCA of the base title, making this an update NSP. Its
+        /// program NCA only stores the differences with the RomFS of the base,
+        /// and its CNMT is the one of the patch title.
+        #[structopt(parse(from_os_str), long = "base", requires = "romfs")]
+        base: Option<PathBuf>,
+
+        /// Ticket giving the titlekey of the base NCA, when it uses titlekey
+        /// crypto and it isn't in title.keys.
+        #[structopt(parse(from_os_str), long = "ticket", number_of_values = 1)]
+        tickets: Vec<PathBuf>,
+
+        /// Version of the title.
+        #[structopt(long = "title-version", default_value = "0")]
+        title_version: u32,
+
+        /// Key generation to encrypt the NCAs with.
+        #[structopt(long = "key-generation", default_value = "0")]
+        key_generation: u8,
+
+        /// Compresses the sections of the NCAs with LZ4, as newer titles do. Older
+        /// firmwares can't read compressed sections.
+        #[structopt(long = "compress")]
+        compress: bool,
+
+        /// Signs the header of the program NCA with this RSA-2048 private
+        /// key, as PEM or DER. Its public key goes in the ACID of the NPDM.
+        #[structopt(parse(from_os_str), long = "signing-key")]
+        signing_key: Option<PathBuf>,
+
+        /// Signs the ACID of the NPDM built from --npdm with this RSA-2048
+        /// private key, as PEM or DER.
+        #[structopt(parse(from_os_str), long = "acid-signing-key", requires = "npdm")]
+        acid_signing_key: Option<PathBuf>,
+
+        /// Encrypts the program and control NCAs with a random titlekey, given
+        /// by a common ticket packed along them with its certificate chain.
+        #[structopt(long = "titlekey-crypto")]
+        titlekey_crypto: bool,
+
+        /// Takes the certificate chain of the ticket from this file instead of
+        /// the common.cert next to the keyset.
+        #[structopt(parse(from_os_str), long = "cert")]
+        cert: Option<PathBuf>,
+
+        /// Skips the build when the inputs have the same content as the last
+        /// time this command ran, remembered in .linkle-cache.
+        #[structopt(long = "cache")]
+        cache: bool,
+
+        /// Rebuilds the output each time one of the inputs changes, until
+        /// interrupted.
+        #[structopt(long = "watch")]
+        watch: bool,
+
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+    },
+    /// Build an installable NSP of add-on content (DLC) of an application,
+    /// holding the data NCA built from a RomFS directory.
+    #[structopt(name = "nsp_add_on_content")]
+    NspAddOnContent {
+        /// Sets the output file to use.
+        #[structopt(parse(from_os_str))]
+        output_file: PathBuf,
+
+        /// Sets the RomFS directory to use.
+        #[structopt(parse(from_os_str), long = "romfs")]
+        romfs: PathBuf,
+
+        /// ID of the application, in hexadecimal.
+        #[structopt(long = "application-id", parse(try_from_str = parse_program_id))]
+        application_id: u64,
+
+        /// Index of the add-on content, from 1 to 2000. Its ID is the
+        /// application ID plus 0x1000 plus the index.
+        #[structopt(long = "index", default_value = "1")]
+        index: u16,
+
+        /// Version of the title.
+        #[structopt(long = "title-version", default_value = "0")]
+        title_version: u32,
+
+        /// Minimum version of the application required to use the add-on
+        /// content.
+        #[structopt(long = "required-application-version", default_value = "0")]
+        required_application_version: u32,
+
+        /// Key generation to encrypt the NCAs with.
+        #[structopt(long = "key-generation", default_value = "0")]
+        key_generation: u8,
+
+        /// Encrypts the data NCA with a random titlekey, given by a common
+        /// ticket packed along it with its certificate chain.
+        #[structopt(long = "titlekey-crypto")]
+        titlekey_crypto: bool,
+
+        /// Takes the certificate chain of the ticket from this file instead of
+        /// the common.cert next to the keyset.
+        #[structopt(parse(from_os_str), long = "cert")]
+        cert: Option<PathBuf>,
+
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+    },
+    /// Convert the NCAs of an NSP using titlekey crypto to standard crypto,
+    /// dropping its tickets and certificates, so it installs without them.
+    /// The titlekeys come from the tickets of the NSP, or title.keys.
+    #[structopt(name = "nsp_convert")]
+    NspConvert {
+        /// Sets the input file to use.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+
+        /// Sets the output file to use.
+        #[structopt(parse(from_os_str))]
+        output_file: PathBuf,
+
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+    },
+    /// Extract the ExeFS and RomFS of the program NCA of an NSP in one go,
+    /// decrypting NCAs using titlekey crypto with the tickets of the NSP or
+    /// title.keys.
+    #[structopt(name = "nsp_extract")]
+    NspExtract {
+        /// Sets the input NSP to use.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+
+        /// Extracts the files of the ExeFS to this directory.
+        #[structopt(parse(from_os_str), long = "exefs")]
+        exefs: Option<PathBuf>,
+
+        /// Extracts the files of the RomFS to this directory.
+        #[structopt(parse(from_os_str), long = "romfs")]
+        romfs: Option<PathBuf>,
+
+        /// NCA patched by the program NCA, e.g. the program NCA of the base
+        /// title when extracting the RomFS of an update.
+        #[structopt(parse(from_os_str), long = "base")]
+        base: Option<PathBuf>,
+
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+    },
+    /// Print all the keys generated from our keyfile.
+    #[structopt(name = "keygen")]
+    Keygen {
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+
+        /// Print only the minimum amount of keys without losing information.
+        #[structopt(short = "m", long = "minimal")]
+        minimal: bool,
+
+        /// Show console unique keys along with non-console-unique keys.
+        #[structopt(long = "console-unique")]
+        show_console_unique: bool,
+    },
+    /// Check the keyfile for malformed, unknown and inconsistent keys, and
+    /// against the expected hashes of keys if given.
+    #[structopt(name = "keys_verify")]
+    KeysVerify {
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+
+        /// File of `name = sha256` lines giving the expected SHA-256 of keys.
+        #[structopt(parse(from_os_str), long = "hashes")]
+        hashes: Option<PathBuf>,
+
+        /// Prints the problems as JSON.
+        #[structopt(long = "json")]
+        json: bool,
+    },
+    /// Decrypt a raw dump of a BIS partition of the eMMC with the BIS keys of
+    /// the console.
+    #[structopt(name = "bis_decrypt")]
+    BisDecrypt {
+        /// Partition of the dump: prodinfo, prodinfof, safe, system or user.
+        partition: linkle::format::bis::BisPartition,
+
+        /// Sets the input partition dump to use.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+
+        /// Sets the output file to use.
+        #[structopt(parse(from_os_str))]
+        output_file: PathBuf,
+
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+    },
+    /// Decrypt an NCA or save from the SD card, encrypted with the SD card
+    /// keys of the console. Split NCAs, stored as directories, are joined.
+    #[structopt(name = "sd_decrypt")]
+    SdDecrypt {
+        /// Sets the input file, from the Nintendo directory of the SD card.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+
+        /// Sets the output file to use.
+        #[structopt(parse(from_os_str))]
+        output_file: PathBuf,
+
+        /// Path of the file from Nintendo/Contents or Nintendo/save, e.g.
+        /// /registered/000000ab/<id>.nca. Guessed from the input path if
+        /// not given.
+        #[structopt(long = "path")]
+        sd_path: Option<String>,
+
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+    },
+    /// Detect the format of a file and print a summary of it.
+    #[structopt(name = "info")]
+    Info {
+        /// Sets the input file to use.
+        #[structopt(parse(from_os_str))]
+        input_file: PathBuf,
+
+        /// Prints the format and summary as JSON.
+        #[structopt(long = "json")]
+        json: bool,
+
+        /// Prints the headers of NPDMs, NSOs, NROs, PFS0s and HFS0s as they
+        /// are stored in the file, as JSON.
+        #[structopt(long = "raw")]
+        raw: bool,
+
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+    },
+    /// Print the formats this build supports, the commands handling them,
+    /// and which of them the keys allow.
+    #[structopt(name = "formats")]
+    Formats {
+        /// Prints them as JSON.
+        #[structopt(long = "json")]
+        json: bool,
+
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+    },
+    /// Run the build pipeline declared in a linkle.toml: its commands, then
+    /// the NSO, NRO and NSP built from its ELF, in dependency order. Outputs
+    /// newer than all of their inputs are left as they are.
+    #[structopt(name = "build")]
+    Build {
+        /// Sets the pipeline file to use.
+        #[structopt(
+            parse(from_os_str),
+            short = "c",
+            long = "config",
+            default_value = "linkle.toml"
+        )]
+        config: PathBuf,
+
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+    },
+    /// Run the linkle commands matching a hactool command line, for scripts
+    /// written for hactool. Only its most common options are supported.
+    #[structopt(name = "hactool")]
+    Hactool(HactoolOpt),
+    /// Build an NSP from directories laid out like for hacbrewpack: exefs,
+    /// romfs, logo, control, htmldoc and legalinfo. It is written to
+    /// hacbrewpack_nsp/<title id>.nsp, with the keys of keys.dat.
+    #[structopt(name = "hacbrewpack")]
+    Hacbrewpack(HacbrewpackOpt),
+    /// Create ready-to-edit templates of the NPDM and NACP JSON files of a
+    /// new sysmodule or application.
+    #[structopt(name = "new")]
+    New(NewOpt),
+}
+
+/// The programs `linkle new` has templates for.
+#[derive(StructOpt)]
+enum NewOpt {
+    /// Create the npdm.json of a sysmodule.
+    #[structopt(name = "sysmodule")]
+    Sysmodule {
+        /// Sets the title ID of the sysmodule.
+        #[structopt(parse(try_from_str = parse_program_id))]
+        title_id: u64,
+        #[structopt(flatten)]
+        output: NewOutputOpt,
+    },
+    /// Create the npdm.json and nacp.json of an application.
+    #[structopt(name = "app")]
+    App {
+        /// Sets the title ID of the application.
+        #[structopt(parse(try_from_str = parse_program_id), default_value = "0100000000010000")]
+        title_id: u64,
+        /// Sets the author shown by the home menu.
+        #[structopt(long = "author", default_value = "Unknown")]
+        author: String,
+        #[structopt(flatten)]
+        output: NewOutputOpt,
+    },
+}
+
+#[derive(StructOpt)]
+struct NewOutputOpt {
+    /// Sets the name of the program. Defaults to sysmodule or app.
+    #[structopt(long = "name")]
+    name: Option<String>,
+
+    /// Sets the directory the templates are written to.
+    #[structopt(
+        parse(from_os_str),
+        short = "o",
+        long = "output-dir",
+        default_value = "."
+    )]
+    output_dir: PathBuf,
+}
+
+/// The hactool options understood by `linkle hactool`, with their hactool
+/// names.
+#[derive(StructOpt)]
+struct HactoolOpt {
+    /// Sets the input file to use.
+    #[structopt(parse(from_os_str))]
+    input_file: PathBuf,
+
+    /// Type of the input file: nca, pfs0 (or exefs, nsp), romfs, save,
+    /// package1 (or pk11), package2 (or pk21), or npdm, nso, kip1, ini1 and
+    /// hfs0 for their info only.
+    #[structopt(short = "t", long = "intype", default_value = "nca")]
+    intype: String,
+
+    /// Prints the info of the file. Without -i, -x or -y, the info is
+    /// printed and the file extracted, like hactool does.
+    #[structopt(short = "i", long = "info")]
+    info: bool,
+
+    /// Extracts the file to the directories given.
+    #[structopt(short = "x", long = "extract")]
+    extract: bool,
+
+    /// Verifies the hashes and signatures of an NCA.
+    #[structopt(short = "y", long = "verify")]
+    verify: bool,
+
+    /// Extracts the ExeFS of an NCA, or of the program NCA of an NSP, to this
+    /// directory.
+    #[structopt(parse(from_os_str), long = "exefsdir")]
+    exefsdir: Option<PathBuf>,
+
+    /// Extracts the RomFS of an NCA, or of the program NCA of an NSP, to this
+    /// directory.
+    #[structopt(parse(from_os_str), long = "romfsdir")]
+    romfsdir: Option<PathBuf>,
+
+    /// Extracts the files of a PFS0, RomFS, save, Package1 or Package2 to
+    /// this directory.
+    #[structopt(parse(from_os_str), long = "outdir")]
+    outdir: Option<PathBuf>,
+
+    /// NCA patched by the input NCA, to extract the RomFS of an update.
+    #[structopt(parse(from_os_str), long = "basenca")]
+    basenca: Option<PathBuf>,
+
+    /// Use development keys instead of retail
+    #[structopt(short = "d", long = "dev")]
+    dev: bool,
+
+    /// Key file to use
+    #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+    keyfile: Option<PathBuf>,
+}
+
+/// The hacbrewpack options understood by `linkle hacbrewpack`, with their
+/// hacbrewpack names and defaults.
+#[derive(StructOpt)]
+struct HacbrewpackOpt {
+    /// Sets the ExeFS directory to use, holding main and main.npdm.
+    #[structopt(parse(from_os_str), long = "exefsdir", default_value = "exefs")]
+    exefsdir: PathBuf,
+
+    /// Sets the RomFS directory to use.
+    #[structopt(parse(from_os_str), long = "romfsdir", default_value = "romfs")]
+    romfsdir: PathBuf,
+
+    /// Sets the logo directory to use, holding NintendoLogo.png and
+    /// StartupMovie.gif.
+    #[structopt(parse(from_os_str), long = "logodir", default_value = "logo")]
+    logodir: PathBuf,
+
+    /// Sets the control directory to use, holding control.nacp and the
+    /// icon_<language>.dat icons.
+    #[structopt(parse(from_os_str), long = "controldir", default_value = "control")]
+    controldir: PathBuf,
+
+    /// Sets the HTML document directory to use, if it exists.
+    #[structopt(parse(from_os_str), long = "htmldocdir", default_value = "htmldoc")]
+    htmldocdir: PathBuf,
+
+    /// Sets the legal information directory to use, if it exists.
+    #[structopt(parse(from_os_str), long = "legalinfodir", default_value = "legalinfo")]
+    legalinfodir: PathBuf,
+
+    /// Sets the directory the NSP is written to.
+    #[structopt(parse(from_os_str), long = "nspdir", default_value = "hacbrewpack_nsp")]
+    nspdir: PathBuf,
+
+    /// Builds the program NCA without a RomFS.
+    #[structopt(long = "noromfs")]
+    noromfs: bool,
+
+    /// Builds the program NCA without a logo.
+    #[structopt(long = "nologo")]
+    nologo: bool,
+
+    /// Key generation to encrypt the NCAs with.
+    #[structopt(long = "keygeneration", default_value = "1")]
+    keygeneration: u8,
+
+    /// Title ID of the NSP. It must be the program ID of main.npdm, as linkle
+    /// doesn't patch the NPDM and NACP.
+    #[structopt(long = "titleid", parse(try_from_str = parse_program_id))]
+    titleid: Option<u64>,
+
+    /// Use development keys instead of retail
+    #[structopt(short = "d", long = "dev")]
+    dev: bool,
+
+    /// Key file to use, keys.dat by default, or else the usual keyset.
+    #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+    keyfile: Option<PathBuf>,
+}
+
+fn create_nxo(
+    format: &str,
+    input_file: &str,
+    output_file: &str,
+    icon_file: Option<&str>,
+    reencode_icon: bool,
+    romfs_dir: Option<&str>,
+    nacp_file: Option<&str>,
+) -> Result<(), linkle::error::Error> {
+    let icon = if let Some(icon_path) = icon_file {
+        Some(linkle::format::icon::load_icon(
+            Path::new(icon_path),
+            reencode_icon,
+        )?)
+    } else {
+        None
+    };
+    let romfs_dir = if let Some(romfs_path) = romfs_dir {
+        Some(linkle::format::romfs::RomFs::from_directory(Path::new(
+            &romfs_path,
+        ))?)
+    } else {
+        None
+    };
+    let nacp_file = if let Some(nacp_path) = nacp_file {
+        let nacp = load_nacp_descriptor(nacp_path)?;
+        nacp.validate().with_path(nacp_path)?;
+        Some(nacp)
+    } else {
+        None
+    };
+
+    let mut nxo =
+        linkle::format::nxo::NxoFile::from_elf(&input_file).map_err(|err| (err, &input_file))?;
+    let mut out_file = Output::create(output_file)?;
+    match format {
+        "nro" => nxo
+            .write_nro(&mut out_file, romfs_dir, icon.as_deref(), nacp_file)
+            .with_path(output_file)?,
+        "nso" => nxo.write_nso(&mut out_file).with_path(output_file)?,
+        _ => process::exit(1),
+    }
+    out_file.finish()
+}
+
+/// Runs `convert` on the input file and the output file, or, with several
+/// inputs, glob patterns or directories of ELFs, on every input file and a
+/// file of the output directory named after it with the `extension`
+/// extension. Those are
+/// converted on `jobs` threads, printing a line per file as they are done.
+fn convert_files<F>(
+    patterns: &[String],
+    output: &str,
+    extension: &str,
+    jobs: Option<usize>,
+    convert: F,
+) -> Result<(), linkle::error::Error>
+where
+    F: Fn(&str, &str) -> Result<(), linkle::error::Error> + Send + Sync + 'static,
+{
+    use snafu::GenerateBacktrace;
+    use std::sync::{mpsc, Arc, Mutex};
+
+    if let [input] = patterns {
+        if !linkle::filter::is_glob(input) && !Path::new(input).is_dir() {
+            return convert(input, output);
+        }
+    }
+
+    let mut inputs = Vec::new();
+    for pattern in patterns {
+        inputs.extend(expand_input(pattern)?);
+    }
+    let output_directory = Path::new(output);
+    std::fs::create_dir_all(output_directory).map_err(|err| (err, output_directory))?;
+    let mut tasks = Vec::new();
+    let mut output_names = std::collections::HashSet::new();
+    for input in inputs {
+        let name = Path::new(input.file_name().unwrap_or_default()).with_extension(extension);
+        let output = output_directory.join(&name);
+        if !output_names.insert(name) {
+            return Err((
+                std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    "several inputs have this name",
+                ),
+                output,
+            )
+                .into());
+        }
+        tasks.push((input, output));
+    }
+
+    let total = tasks.len();
+    let jobs = jobs.unwrap_or_else(num_cpus::get).max(1).min(total);
+    let tasks = Arc::new(Mutex::new(tasks.into_iter()));
+    let convert = Arc::new(convert);
+    let (results, receiver) = mpsc::channel();
+    let workers = (0..jobs)
+        .map(|_| {
+            let tasks = tasks.clone();
+            let convert = convert.clone();
+            let results = results.clone();
+            std::thread::spawn(move || loop {
+                let task = tasks.lock().unwrap().next();
+                let (input, output) = match task {
+                    Some(task) => task,
+                    None => break,
+                };
+                // A panic only fails its file, the hook already printed it.
+                let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    convert(&input.to_string_lossy(), &output.to_string_lossy())
+                }));
+                let result = match result {
+                    Ok(result) => result.map_err(|err| err.to_string()),
+                    Err(_) => Err("panicked".to_string()),
+                };
+                if results.send((input, output, result)).is_err() {
+                    break;
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    drop(results);
+
+    let mut failed = 0;
+    for (input, output, result) in receiver {
+        match result {
+            Ok(()) => log::info!("{} -> {}", input.display(), output.display()),
+            Err(err) => {
+                failed += 1;
+                log::error!("{} failed: {}", input.display(), err);
+            }
+        }
+    }
+    for worker in workers {
+        worker.join().expect("a conversion thread panicked");
+    }
+    if failed > 0 {
+        return Err(linkle::error::Error::BatchFailed {
+            failed,
+            total,
+            backtrace: snafu::Backtrace::generate(),
+        });
+    }
+    log::info!("Converted {} file(s)", total);
+    Ok(())
+}
+
+/// The files an input of `convert_files` stands for: the files matching a
+/// glob pattern, the `*.elf` files of a directory, or else the file itself.
+fn expand_input(pattern: &str) -> Result<Vec<PathBuf>, linkle::error::Error> {
+    if linkle::filter::is_glob(pattern) {
+        linkle::filter::expand_glob(pattern)
+    } else if Path::new(pattern).is_dir() {
+        let pattern = Path::new(pattern).join("*.elf");
+        linkle::filter::expand_glob(&pattern.to_string_lossy())
+    } else {
+        Ok(vec![PathBuf::from(pattern)])
+    }
+}
+
+/// The files `watch_inputs` looks at for the inputs of `convert_files`. New
+/// files matching the patterns or added to the directories are picked up as
+/// well.
+fn watched_files(patterns: &[String]) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for pattern in patterns {
+        files.extend(expand_input(pattern).unwrap_or_default());
+    }
+    files
+}
+
+/// Size and modification time of every file under some paths.
+type WatchSnapshot = std::collections::BTreeMap<PathBuf, (Option<std::time::SystemTime>, u64)>;
+
+fn watch_snapshot(inputs: &[PathBuf], output: Option<&Path>) -> WatchSnapshot {
+    let mut snapshot = WatchSnapshot::new();
+    for input in inputs {
+        let walker = ignore::WalkBuilder::new(input)
+            .standard_filters(false)
+            .follow_links(true)
+            .build();
+        // Missing and vanishing files are just left out, they show up as a
+        // change once they are back.
+        for entry in walker.filter_map(Result::ok) {
+            let path = entry.path();
+            let is_output = match output {
+                Some(output) => path.starts_with(output),
+                None => false,
+            };
+            if is_output {
+                continue;
+            }
+            if let Ok(metadata) = std::fs::metadata(path) {
+                if metadata.is_file() {
+                    let modified = metadata.modified().ok();
+                    snapshot.insert(path.to_path_buf(), (modified, metadata.len()));
+                }
+            }
+        }
+    }
+    snapshot
+}
+
+/// Runs `build`, then again each time a file under the paths returned by
+/// `inputs` is added, removed or modified, until interrupted. Files under
+/// `output` are left out, and failed builds are only reported, so they can
+/// be fixed without restarting.
+fn watch_inputs<I, F>(
+    inputs: I,
+    output: Option<&Path>,
+    mut build: F,
+) -> Result<(), linkle::error::Error>
+where
+    I: Fn() -> Vec<PathBuf>,
+    F: FnMut() -> Result<(), linkle::error::Error>,
+{
+    let poll_interval = std::time::Duration::from_millis(500);
+    let mut snapshot = watch_snapshot(&inputs(), output);
+    loop {
+        if let Err(err) = build().and_then(|()| write_checksums()) {
+            log::error!("{}", err);
+        }
+        log::info!("Watching {} file(s) for changes", snapshot.len());
+        loop {
+            std::thread::sleep(poll_interval);
+            let current = watch_snapshot(&inputs(), output);
+            if current != snapshot {
+                snapshot = current;
+                break;
+            }
+        }
+        // Wait for the files to settle, e.g. while a linker writes the ELF.
+        loop {
+            std::thread::sleep(poll_interval);
+            let current = watch_snapshot(&inputs(), output);
+            if current == snapshot {
+                break;
+            }
+            snapshot = current;
+        }
+        log::info!("Change detected, rebuilding");
+    }
+}
+
+/// Directory of the `--cache` entries, in the current directory.
+const CACHE_DIR: &str = ".linkle-cache";
+
+/// An input file of a cached build, with the size and modification time its
+/// hash was computed at, so it is only hashed again once they change.
+#[derive(Clone, serde_derive::Serialize, serde_derive::Deserialize)]
+struct CachedInput {
+    modified: Option<std::time::SystemTime>,
+    len: u64,
+    hash: String,
+}
+
+/// What `--cache` remembers of the last build of a command.
+#[derive(Default, serde_derive::Serialize, serde_derive::Deserialize)]
+struct CacheEntry {
+    inputs: std::collections::BTreeMap<PathBuf, CachedInput>,
+    outputs: WatchSnapshot,
+}
+
+fn hash_file(path: &Path) -> std::io::Result<String> {
+    use sha2::{Digest, Sha256};
+    use std::io::Read;
+
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 0x10000];
+    loop {
+        let size = file.read(&mut buf)?;
+        if size == 0 {
+            break;
+        }
+        hasher.update(&buf[..size]);
+    }
+    Ok(hex(&hasher.finalize()))
+}
+
+/// Runs `build`, unless the same command line already built `outputs` from
+/// inputs with the same content, as recorded in `CACHE_DIR`. Inputs are
+/// compared by hash, so touching a file doesn't rebuild, and outputs by size
+/// and modification time, so deleting or modifying one does.
+fn cached_build<F>(
+    inputs: &[PathBuf],
+    outputs: &[&Path],
+    build: F,
+) -> Result<(), linkle::error::Error>
+where
+    F: FnOnce() -> Result<(), linkle::error::Error>,
+{
+    use sha2::{Digest, Sha256};
+
+    if outputs.iter().any(|&output| output == Path::new("-")) {
+        return build();
+    }
+    // The entry is found by the command line, less the options that don't
+    // change the output.
+    let mut args = std::env::args_os().skip(1);
+    let mut command = Vec::new();
+    while let Some(arg) = args.next() {
+        let arg = arg.to_string_lossy().into_owned();
+        let is_flag = |short: char, long: &str| {
+            arg == long
+                || (arg.len() > 1 && arg.starts_with('-') && arg[1..].chars().all(|v| v == short))
+        };
+        if arg == "--error-format" || arg == "--checksum-manifest" {
+            args.next();
+        } else if !(is_flag('v', "--verbose")
+            || is_flag('q', "--quiet")
+            || arg == "--watch"
+            || arg == "--checksums"
+            || arg.starts_with("--error-format=")
+            || arg.starts_with("--checksum-manifest="))
+        {
+            command.push(arg);
+        }
+    }
+    let command = command.join("\0");
+    let entry_path = Path::new(CACHE_DIR).join(format!(
+        "{}.json",
+        hex(&Sha256::digest(command.as_bytes())[..0x10]).to_lowercase()
+    ));
+    let previous: CacheEntry = std::fs::read(&entry_path)
+        .ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default();
+
+    let mut entry = CacheEntry::default();
+    let snapshot = watch_snapshot(inputs, outputs.first().copied());
+    for (path, (modified, len)) in snapshot {
+        if path.components().any(|v| v.as_os_str() == CACHE_DIR) {
+            continue;
+        }
+        let input = match previous.inputs.get(&path) {
+            Some(input) if input.modified == modified && input.len == len => input.clone(),
+            _ => CachedInput {
+                modified,
+                len,
+                hash: hash_file(&path).map_err(|err| (err, &path))?,
+            },
+        };
+        entry.inputs.insert(path, input);
+    }
+    let outputs = outputs.iter().map(|v| v.to_path_buf()).collect::<Vec<_>>();
+    let output_stamps = || watch_snapshot(&outputs, None);
+
+    let unchanged = previous.inputs.len() == entry.inputs.len()
+        && previous
+            .inputs
+            .iter()
+            .zip(&entry.inputs)
+            .all(|(old, new)| old.0 == new.0 && old.1.hash == new.1.hash);
+    let current_outputs = output_stamps();
+    if unchanged && current_outputs.len() == outputs.len() && current_outputs == previous.outputs {
+        for output in &outputs {
+            log::info!("{} is up to date", output.display());
+        }
+        queue_checksums(&outputs);
+        return Ok(());
+    }
+
+    build()?;
+    entry.outputs = output_stamps();
+    let saved = std::fs::create_dir_all(CACHE_DIR)
+        .and_then(|()| Ok(serde_json::to_vec(&entry)?))
+        .and_then(|data| std::fs::write(&entry_path, data));
+    if let Err(err) = saved {
+        log::warn!("failed to update {}: {}", entry_path.display(), err);
+    }
+    Ok(())
+}
+
+fn create_kip(
+    input_file: &str,
+    npdm_file: &str,
+    output_file: &str,
+) -> Result<(), linkle::error::Error> {
+    let mut nxo =
+        linkle::format::nxo::NxoFile::from_elf(&input_file).map_err(|err| (err, &input_file))?;
+    let npdm = load_json_descriptor(Path::new(npdm_file), &[])?;
+
+    let mut out_file = Output::create(output_file)?;
+    nxo.write_kip1(&mut out_file, &npdm)
+        .with_path(output_file)?;
+    out_file.finish()
+}
+
+/// Builds an NPDM with the bytes npdmtool gives for the same JSON: the ACID
+/// public key and signature are left zeroed.
+fn create_npdm(input_file: &str, output_file: &str) -> Result<(), linkle::error::Error> {
+    use std::io::Write;
+
+    let npdm: linkle::format::npdm::Npdm =
+        load_json_descriptor(Path::new(input_file), &[]).with_path(input_file)?;
+    let data = npdm.to_bytes(&[0; 0x100]).with_path(input_file)?;
+    let mut out_file = Output::create(output_file)?;
+    out_file
+        .write_all(&data)
+        .map_err(|err| (err, output_file))?;
+    out_file.finish()
+}
+
+/// Builds a PFS0 out of `input_directory`, only printing its layout when
+/// there is no `output_file`.
+fn create_pfs0(
+    input_directory: &str,
+    output_file: Option<&str>,
+    filter: &linkle::filter::PathFilter,
+    symlinks: linkle::filter::SymlinkPolicy,
+) -> Result<(), linkle::error::Error> {
+    let pfs0 = if input_directory.to_ascii_lowercase().ends_with(".zip") {
+        let file = File::open(input_directory).map_err(|err| (err, input_directory))?;
+        linkle::format::vfs::ZipFs::new(file).and_then(|zip| {
+            linkle::format::pfs0::Pfs0::from_vfs_with_filter(std::rc::Rc::new(zip), filter)
+        })
+    } else {
+        linkle::format::pfs0::Pfs0::from_directory_with_options(input_directory, filter, symlinks)
+    };
+    let mut pfs0 = pfs0.map_err(|err| (err, input_directory))?;
+    let output_file = match output_file {
+        Some(output_file) => output_file,
+        None => return print_pfs0_layout(&pfs0),
+    };
+    let mut out_file = Output::create(output_file)?;
+    pfs0.write_pfs0(&mut out_file)
+        .map_err(|err| (err, output_file))?;
+    out_file.finish()
+}
+
+fn print_pfs0_layout(pfs0: &linkle::format::pfs0::Pfs0) -> Result<(), linkle::error::Error> {
+    let layout = pfs0.layout()?;
+    println!("Header:       {:#x}", layout.header_size);
+    println!(
+        "String table: {:#x} ({:#x} bytes of padding)",
+        layout.string_table_size, layout.string_table_padding
+    );
+    println!(
+        "Total size:   {:#x} ({} bytes)",
+        layout.total_size, layout.total_size
+    );
+    println!();
+    for file in layout.files.iter() {
+        println!(
+            "  {:#010x} {} ({:#x} bytes)",
+            file.offset, file.name, file.size
+        );
+    }
+    Ok(())
+}
+
+fn extract_pfs0(input_path: &str, output_directory: &str) -> Result<(), linkle::error::Error> {
+    let input_file = open_input(input_path)?;
+    let pfs0 = linkle::format::pfs0::Pfs0::from_reader_with_mode(input_file, parse_mode())
+        .with_path(input_path)?;
+    let path = Path::new(output_directory);
+    match std::fs::create_dir(path) {
+        Ok(()) => (),
+        Err(ref err) if err.kind() == std::io::ErrorKind::AlreadyExists => (),
+        Err(err) => return Err((err, path).into()),
+    }
+    for file in pfs0.files() {
+        let mut file = file?;
+        let name = entry_output_path(input_path, path, file.file_name())?;
+        log::info!("Writing {}", file.file_name());
+        let mut out_file = Output::create(&name)?;
+        std::io::copy(&mut file, &mut out_file).map_err(|err| (err, &name))?;
+        out_file.finish()?;
+    }
+    Ok(())
+}
+
+fn extract_pfs0_file(
+    input_path: &str,
+    output_directory: &str,
+    file_name: &str,
+) -> Result<(), linkle::error::Error> {
+    let input_file = open_input(input_path)?;
+    let pfs0 = linkle::format::pfs0::Pfs0::from_reader_with_mode(input_file, parse_mode())
+        .with_path(input_path)?;
+    let mut file = match pfs0.into_file(file_name) {
+        Some(file) => file?,
+        None => {
+            return Err((
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("No entry named {} in the PFS0", file_name),
+                ),
+                input_path,
+            )
+                .into())
+        }
+    };
+
+    if output_directory == "-" {
+        let stdout = std::io::stdout();
+        std::io::copy(&mut file, &mut stdout.lock())?;
+        return Ok(());
+    }
+
+    let path = Path::new(output_directory);
+    match std::fs::create_dir(path) {
+        Ok(()) => (),
+        Err(ref err) if err.kind() == std::io::ErrorKind::AlreadyExists => (),
+        Err(err) => return Err((err, path).into()),
+    }
+    let name = entry_output_path(input_path, path, file.file_name())?;
+    log::info!("Writing {}", file.file_name());
+    let mut out_file = Output::create(&name)?;
+    std::io::copy(&mut file, &mut out_file).map_err(|err| (err, &name))?;
+    out_file.finish()
+}
+
+fn append_pfs0(
+    input_path: &Path,
+    output_path: &Path,
+    files: &[PathBuf],
+) -> Result<(), linkle::error::Error> {
+    let input_file = open_input(input_path)?;
+    let mut pfs0 = linkle::format::pfs0::Pfs0::from_reader_with_mode(input_file, parse_mode())
+        .with_path(input_path)?;
+    for file in files {
+        pfs0.push_file(file.clone()).map_err(|err| (err, file))?;
+    }
+
+    // The existing entries are read from the input while writing, which
+    // works in place as the output only replaces it once written.
+    let mut out_file = Output::create(output_path)?;
+    pfs0.write_pfs0(&mut out_file)
+        .map_err(|err| (err, output_path))?;
+    drop(pfs0);
+    out_file.finish()
+}
+
+fn create_nacp(input_file: &str, output_file: &str) -> Result<(), linkle::error::Error> {
+    let mut nacp = load_nacp_descriptor(input_file)?;
+    nacp.validate().with_path(input_file)?;
+    let mut out_file = Output::create(output_file)?;
+    nacp.write(&mut out_file).with_path(output_file)?;
+    out_file.finish()
+}
+
+fn patch_nacp(
+    input_file: &str,
+    output_file: Option<&str>,
+    set: &[String],
+    patch: Option<&str>,
+) -> Result<(), linkle::error::Error> {
+    let mut buf = std::fs::read(input_file).map_err(|err| (err, input_file))?;
+    let mut nacp = linkle::format::nacp::NacpFile::from_reader(&buf[..]).with_path(input_file)?;
+
+    if let Some(patch) = patch {
+        let patch =
+            linkle::format::nacp::NacpFile::load_patch(patch).map_err(|err| (err, patch))?;
+        nacp.apply_patch(&patch)?;
+    }
+    for field in set {
+        let mut parts = field.splitn(2, '=');
+        let key = parts.next().unwrap_or("");
+        let value = match parts.next() {
+            Some(value) => value,
+            None => {
+                use snafu::GenerateBacktrace;
+                return Err(linkle::error::Error::InvalidArgument {
+                    argument: format!("--set {}", field),
+                    expected: "KEY=VALUE",
+                    backtrace: snafu::Backtrace::generate(),
+                });
+            }
+        };
+        // Build {"a": {"b": value}} out of a.b=value.
+        let patch_for = |value: serde_json::Value| {
+            key.rsplit('.').fold(value, |value, part| {
+                let mut object = serde_json::Map::new();
+                object.insert(part.to_string(), value);
+                serde_json::Value::Object(object)
+            })
+        };
+        let as_string = patch_for(serde_json::Value::String(value.to_string()));
+        if let Err(err) = nacp.apply_patch(&as_string) {
+            match serde_json::from_str(value) {
+                Ok(value) => nacp.apply_patch(&patch_for(value))?,
+                Err(_) => return Err(err),
+            }
+        }
+    }
+    nacp.validate()?;
+
+    nacp.write_to_buffer(&mut buf).with_path(input_file)?;
+    let output_file = output_file.unwrap_or(input_file);
+    write_output(output_file, &buf)
+}
+
+fn create_control(
+    input_file: &str,
+    output_directory: &Path,
+    icon: Option<&Path>,
+    lang_icons: &[String],
+    romfs: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    use linkle::format::nacp::Language;
+    use snafu::GenerateBacktrace;
+    use std::collections::BTreeMap;
+
+    let mut nacp = load_nacp_descriptor(input_file)?;
+    nacp.validate().with_path(input_file)?;
+
+    let mut icons = BTreeMap::new();
+    for lang_icon in lang_icons {
+        let mut parts = lang_icon.splitn(2, '=');
+        let lang = serde_json::from_value::<Language>(serde_json::Value::String(
+            parts.next().unwrap_or("").to_string(),
+        ));
+        match (lang, parts.next()) {
+            (Ok(lang), Some(path)) => {
+                icons.insert(lang as usize, PathBuf::from(path));
+            }
+            _ => {
+                use snafu::GenerateBacktrace;
+                return Err(linkle::error::Error::InvalidArgument {
+                    argument: format!("--lang-icon {}", lang_icon),
+                    expected: "LANGUAGE=PATH",
+                    backtrace: snafu::Backtrace::generate(),
+                });
+            }
+        }
+    }
+
+    let languages = match &nacp.supported_languages {
+        Some(languages) => languages.clone(),
+        None => Language::ALL.to_vec(),
+    };
+    // Check every language has an icon before writing anything.
+    let mut language_icons = Vec::new();
+    for lang in languages {
+        match icons.get(&(lang as usize)).map(|v| v.as_path()).or(icon) {
+            Some(path) => language_icons.push((lang, path)),
+            None => {
+                return Err(linkle::error::Error::MissingIcon {
+                    language: lang.name(),
+                    backtrace: snafu::Backtrace::generate(),
+                })
+            }
+        }
+    }
+
+    std::fs::create_dir_all(output_directory).map_err(|err| (err, output_directory))?;
+    // Languages sharing an icon only need it converted once.
+    let mut converted = BTreeMap::new();
+    for (lang, path) in language_icons {
+        if !converted.contains_key(path) {
+            converted.insert(path, linkle::format::icon::load_icon(path, true)?);
+        }
+        let icon_path = output_directory.join(format!("icon_{}.dat", lang.name()));
+        write_output(&icon_path, &converted[path])?;
+    }
+
+    let nacp_path = output_directory.join("control.nacp");
+    let mut nacp_file = Output::create(&nacp_path)?;
+    nacp.write(&mut nacp_file).with_path(&nacp_path)?;
+    nacp_file.finish()?;
+
+    if let Some(romfs) = romfs {
+        let control = linkle::format::romfs::RomFs::from_directory(output_directory)?;
+        create_romfs(&control, romfs, 1, None)?;
+    }
+    Ok(())
+}
+
+/// Loads the JSON descriptor at `path`. With `--prompt` and a terminal on
+/// stdin, the fields it misses are asked for instead of failing, along with
+/// the `wanted` ones, and can then be written back into the file.
+fn load_json_descriptor<T: serde::de::DeserializeOwned>(
+    path: &Path,
+    wanted: &[&str],
+) -> Result<T, linkle::error::Error> {
+    let data = std::fs::read_to_string(path).map_err(|err| (err, path))?;
+    if !PROMPT.load(Ordering::Relaxed) || !atty::is(atty::Stream::Stdin) {
+        return Ok(serde_json::from_str(&data)?);
+    }
+    let mut value: serde_json::Value = serde_json::from_str(&data)?;
+    let mut added = Vec::new();
+    for field in wanted {
+        if value.is_object() && value.get(field).is_none() {
+            prompt_field(&mut value, &mut added, field, path)?;
+        }
+    }
+    let descriptor = loop {
+        let err = match serde_json::from_value(value.clone()) {
+            Ok(descriptor) => break descriptor,
+            Err(err) => err,
+        };
+        // Fields missing from nested objects can't be fixed at the top level,
+        // which shows as the same field missing again.
+        let missing = err
+            .to_string()
+            .strip_prefix("missing field `")
+            .map(|rest| rest.trim_end_matches('`').to_string());
+        match missing {
+            Some(field) if value.is_object() && !added.iter().any(|(name, _)| *name == field) => {
+                prompt_field(&mut value, &mut added, &field, path)?
+            }
+            _ => return Err(err.into()),
+        }
+    };
+    if !added.is_empty() && confirm(&format!("Write them to {}?", path.display()))? {
+        write_back_fields(path, &data, &added)?;
+    }
+    Ok(descriptor)
+}
+
+/// Asks for the value of `field`, read as JSON if it is valid JSON and as a
+/// string otherwise, and adds it to the `value` object.
+fn prompt_field(
+    value: &mut serde_json::Value,
+    added: &mut Vec<(String, serde_json::Value)>,
+    field: &str,
+    path: &Path,
+) -> Result<(), linkle::error::Error> {
+    use snafu::GenerateBacktrace;
+
+    eprint!("{} has no {}, enter it: ", path.display(), field);
+    let mut line = String::new();
+    std::io::stdin()
+        .read_line(&mut line)
+        .map_err(|err| (err, path))?;
+    let line = line.trim();
+    if line.is_empty() {
+        return Err(linkle::error::Error::InvalidArgument {
+            argument: format!("{} of {}", field, path.display()),
+            expected: "a value",
+            backtrace: snafu::Backtrace::generate(),
+        });
+    }
+    let field_value =
+        serde_json::from_str(line).unwrap_or_else(|_| serde_json::Value::String(line.to_string()));
+    value[field] = field_value.clone();
+    added.push((field.to_string(), field_value));
+    Ok(())
+}
+
+/// Asks a yes or no `question` on stderr, no being the default.
+fn confirm(question: &str) -> Result<bool, linkle::error::Error> {
+    eprint!("{} [y/N] ", question);
+    let mut line = String::new();
+    std::io::stdin()
+        .read_line(&mut line)
+        .map_err(|err| (err, "<stdin>"))?;
+    Ok(matches!(line.trim(), "y" | "Y" | "yes"))
+}
+
+/// Adds the `fields` at the start of the JSON object in `data`, leaving the
+/// rest of the file as it was written, and saves it to `path`.
+fn write_back_fields(
+    path: &Path,
+    data: &str,
+    fields: &[(String, serde_json::Value)],
+) -> Result<(), linkle::error::Error> {
+    let start = data.find('{').map_or(0, |start| start + 1);
+    let empty = data[start..].trim_start().starts_with('}');
+    let mut inserted: String = fields
+        .iter()
+        .map(|(name, value)| {
+            format!(
+                "\n    {}: {},",
+                serde_json::Value::from(name.as_str()),
+                value
+            )
+        })
+        .collect();
+    if empty {
+        inserted.pop();
+        inserted.push('\n');
+    }
+    let updated = format!("{}{}{}", &data[..start], inserted, &data[start..]);
+    write_output(path, updated.as_bytes())?;
+    log::info!("Updated {}", path.display());
+    Ok(())
+}
+
+/// Loads an NACP descriptor. Its name and title ID aren't required, but
+/// `--prompt` asks for them when JSON descriptors leave them out, as every
+/// application needs them. Names set per language are enough.
+fn load_nacp_descriptor(
+    path: &str,
+) -> Result<linkle::format::nacp::NacpFile, linkle::error::Error> {
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+    if extension.as_deref() != Some("json") || !PROMPT.load(Ordering::Relaxed) {
+        return linkle::format::nacp::NacpFile::from_file(path).map_err(|err| (err, path).into());
+    }
+    let value: serde_json::Value = load_json_descriptor(Path::new(path), &[])?;
+    let mut wanted = vec!["title_id"];
+    if value.get("lang").is_none() {
+        wanted.insert(0, "name");
+    }
+    let mut nacp: linkle::format::nacp::NacpFile = load_json_descriptor(Path::new(path), &wanted)?;
+    nacp.apply_preset();
+    Ok(nacp)
+}
+
+/// Loads a binary NACP, or a descriptor if the file has a JSON, TOML or YAML
+/// extension.
+fn load_nacp(path: &str) -> Result<linkle::format::nacp::NacpFile, linkle::error::Error> {
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("json") | Some("toml") | Some("yaml") | Some("yml") => {
+            let nacp = load_nacp_descriptor(path)?;
+            nacp.validate().with_path(path)?;
+            Ok(nacp)
+        }
+        _ => {
+            let file = File::open(path).map_err(|err| (err, path))?;
+            linkle::format::nacp::NacpFile::from_reader(file).with_path(path)
+        }
+    }
+}
+
+fn diff_nacp(old_path: &str, new_path: &str, json: bool) -> Result<(), linkle::error::Error> {
+    let old = load_nacp(old_path)?;
+    let new = load_nacp(new_path)?;
+    let changes = linkle::format::nacp::diff(&old, &new)?;
+
+    if json {
+        serde_json::to_writer_pretty(std::io::stdout(), &changes)?;
+        println!();
+        return Ok(());
+    }
+
+    let show = |value: &Option<serde_json::Value>| match value {
+        Some(value) => value.to_string(),
+        None => "(default)".to_string(),
+    };
+    for change in changes.iter() {
+        println!(
+            "{}: {} -> {}",
+            change.field,
+            show(&change.old),
+            show(&change.new)
+        );
+    }
+    println!("{} field(s) changed", changes.len());
+    Ok(())
+}
+
+fn extract_cnmt(input_path: &Path, output_path: &Path) -> Result<(), linkle::error::Error> {
+    let input_file = open_input(input_path)?;
+    let cnmt = linkle::format::cnmt::Cnmt::from_reader_with_mode(input_file, parse_mode())
+        .with_path(input_path)?;
+    if output_path == Path::new("-") {
+        serde_json::to_writer_pretty(std::io::stdout(), &cnmt)?;
+        println!();
+    } else {
+        let mut output_file = Output::create(output_path)?;
+        serde_json::to_writer_pretty(&mut output_file, &cnmt)?;
+        output_file.finish()?;
+    }
+    Ok(())
+}
+
+/// Writes `nacp` to `output_file`, or as JSON to stdout if it is "-".
+fn write_nacp_descriptor(
+    nacp: &linkle::format::nacp::NacpFile,
+    output_file: &str,
+) -> Result<(), linkle::error::Error> {
+    if output_file == "-" {
+        serde_json::to_writer_pretty(std::io::stdout(), nacp)?;
+        println!();
+    } else {
+        nacp.to_file(output_file)
+            .map_err(|err| (err, output_file))?;
+    }
+    Ok(())
+}
+
+fn extract_nacp(input_file: &str, output_file: &str) -> Result<(), linkle::error::Error> {
+    let input = open_input(input_file)?;
+    let nacp = linkle::format::nacp::NacpFile::from_reader(input).with_path(input_file)?;
+    write_nacp_descriptor(&nacp, output_file)
+}
+
+fn canonicalize_nacp(input_file: &str, output_file: &str) -> Result<(), linkle::error::Error> {
+    let nacp = load_nacp(input_file)?.canonical()?;
+    write_nacp_descriptor(&nacp, output_file)
+}
+
+fn create_romfs(
+    romfs: &linkle::format::romfs::RomFs,
+    output_file: &Path,
+    jobs: usize,
+    manifest: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    let mut out_file = Output::create(output_file)?;
+    romfs
+        .write_with_jobs(&mut out_file, jobs)
+        .with_path(output_file)?;
+    if let Some(manifest) = manifest {
+        // Hash what actually landed in the image rather than the sources.
+        let mut romfs = linkle::format::romfs::RomFsReader::from_reader(&mut out_file)
+            .with_path(output_file)?;
+        write_romfs_manifest(&mut romfs, manifest)?;
+    }
+    out_file.finish()
+}
+
+fn print_romfs_layout(romfs: &linkle::format::romfs::RomFs) -> Result<(), linkle::error::Error> {
+    let layout = romfs.layout();
+    println!("Header:          {:#x}", layout.file_partition_ofs);
+    println!(
+        "File data:       {:#x} ({} files)",
+        layout.file_partition_size, layout.file_count
+    );
+    println!("Dir hash table:  {:#x}", layout.dir_hash_table_size);
+    println!(
+        "Dir table:       {:#x} ({} directories)",
+        layout.dir_table_size, layout.dir_count
+    );
+    println!("File hash table: {:#x}", layout.file_hash_table_size);
+    println!("File table:      {:#x}", layout.file_table_size);
+    println!(
+        "Total size:      {:#x} ({} bytes)",
+        layout.total_size, layout.total_size
+    );
+    println!();
+    println!("Data size per directory:");
+    for dir in layout.directories.iter() {
+        println!(
+            "  {} {:#x} ({} files, {:#x} directly)",
+            dir.path, dir.total_data_size, dir.file_count, dir.data_size
+        );
+    }
+    Ok(())
+}
+
+fn write_romfs_manifest<R: std::io::Read + std::io::Seek>(
+    romfs: &mut linkle::format::romfs::RomFsReader<R>,
+    manifest_path: &Path,
+) -> Result<(), linkle::error::Error> {
+    let manifest = linkle::format::romfs::RomFsManifest::from_romfs(romfs)?;
+    let mut manifest_file = Output::create(manifest_path)?;
+    manifest
+        .write(&mut manifest_file)
+        .map_err(|err| (err, manifest_path))?;
+    manifest_file.finish()
+}
+
+fn verify_romfs(
+    input_path: &Path,
+    manifest_path: &Path,
+    json: bool,
+) -> Result<(), linkle::error::Error> {
+    let manifest_file = File::open(manifest_path).map_err(|err| (err, manifest_path))?;
+    let expected =
+        linkle::format::romfs::RomFsManifest::from_reader(std::io::BufReader::new(manifest_file))
+            .with_path(manifest_path)?;
+    let input_file = open_input(input_path)?;
+    let mut romfs =
+        linkle::format::romfs::RomFsReader::from_reader_with_mode(input_file, parse_mode())
+            .with_path(input_path)?;
+    let actual = linkle::format::romfs::RomFsManifest::from_romfs(&mut romfs)?;
+    let mismatches = expected.verify(&actual);
+
+    if json {
+        serde_json::to_writer_pretty(std::io::stdout(), &mismatches)?;
+        println!();
+    } else {
+        for mismatch in mismatches.iter() {
+            match mismatch {
+                linkle::format::romfs::ManifestMismatch::Missing { path } => {
+                    println!("MISSING {}", path)
+                }
+                linkle::format::romfs::ManifestMismatch::Unexpected { path } => {
+                    println!("UNEXPECTED {}", path)
+                }
+                linkle::format::romfs::ManifestMismatch::Modified { path, .. } => {
+                    println!("MODIFIED {}", path)
+                }
+            }
+        }
+    }
+    if !mismatches.is_empty() {
+        return Err((
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{} file(s) don't match the manifest", mismatches.len()),
+            ),
+            input_path,
+        )
+            .into());
+    }
+    if !json {
+        println!("All files match the manifest");
+    }
+    Ok(())
+}
+
+fn list_romfs(
+    input_path: &Path,
+    depth: Option<usize>,
+    find: Option<&str>,
+    json: bool,
+) -> Result<(), linkle::error::Error> {
+    let input_file = open_input(input_path)?;
+    let romfs = linkle::format::romfs::RomFsReader::from_reader_with_mode(input_file, parse_mode())
+        .with_path(input_path)?;
+    let entries = match find {
+        Some(pattern) => romfs.find(pattern)?,
+        None => romfs.entries()?,
+    }
+    .into_iter()
+    .filter(|entry| depth.map_or(true, |depth| entry.depth() <= depth))
+    .collect::<Vec<_>>();
+
+    if json {
+        serde_json::to_writer_pretty(std::io::stdout(), &entries)?;
+        println!();
+        return Ok(());
+    }
+
+    if find.is_some() {
+        for entry in entries {
+            if let linkle::format::romfs::RomFsEntry::File { size, .. } = entry {
+                println!("{} (size: {:#x})", entry.path(), size);
+            }
+        }
+        return Ok(());
+    }
+
+    println!("/");
+    for entry in entries {
+        let indent = "  ".repeat(entry.depth());
+        match entry {
+            linkle::format::romfs::RomFsEntry::Dir { .. } => {
+                println!("{}{}/", indent, entry.name());
+            }
+            linkle::format::romfs::RomFsEntry::File { offset, size, .. } => {
+                println!(
+                    "{}{} (size: {:#x}, offset: {:#x})",
+                    indent,
+                    entry.name(),
+                    size,
+                    offset
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn extract_romfs(
+    input_path: &Path,
+    output_directory: &Path,
+    manifest: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    let input_file = open_input(input_path)?;
+    let mut romfs =
+        linkle::format::romfs::RomFsReader::from_reader_with_mode(input_file, parse_mode())
+            .with_path(input_path)?;
+    extract_romfs_entries(&mut romfs, input_path, output_directory)?;
+    if let Some(manifest) = manifest {
+        write_romfs_manifest(&mut romfs, manifest)?;
+    }
+    Ok(())
+}
+
+fn extract_romfs_entries<R: std::io::Read + std::io::Seek>(
+    romfs: &mut linkle::format::romfs::RomFsReader<R>,
+    input_path: &Path,
+    output_directory: &Path,
+) -> Result<(), linkle::error::Error> {
+    match std::fs::create_dir(output_directory) {
+        Ok(()) => (),
+        Err(ref err) if err.kind() == std::io::ErrorKind::AlreadyExists => (),
+        Err(err) => return Err((err, output_directory).into()),
+    }
+    for entry in romfs.entries()? {
+        let name = tree_entry_output_path(input_path, output_directory, entry.path())?;
+        match entry {
+            linkle::format::romfs::RomFsEntry::Dir { .. } => match std::fs::create_dir(&name) {
+                Ok(()) => (),
+                Err(ref err) if err.kind() == std::io::ErrorKind::AlreadyExists => (),
+                Err(err) => return Err((err, &name).into()),
+            },
+            linkle::format::romfs::RomFsEntry::File { offset, size, .. } => {
+                log::info!("Writing {}", entry.path());
+                let mut out_file = Output::create(&name)?;
+                let mut file = romfs
+                    .open_file(offset, size)
+                    .map_err(|err| (err, input_path))?;
+                std::io::copy(&mut file, &mut out_file).map_err(|err| (err, &name))?;
+                out_file.finish()?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Joins the name of an entry of `input_path` to `output_directory`,
+/// failing if it could escape it.
+fn entry_output_path<P: AsRef<Path>>(
+    input_path: P,
+    output_directory: &Path,
+    name: &str,
+) -> Result<PathBuf, linkle::error::Error> {
+    if !linkle::format::parse::is_valid_entry_name(name) {
+        let err = std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{:?} isn't a valid file name to extract an entry to", name),
+        );
+        return Err((err, input_path.as_ref()).into());
+    }
+    Ok(output_directory.join(name))
+}
+
+/// Same as `entry_output_path`, for the path of an entry in a directory tree.
+fn tree_entry_output_path(
+    input_path: &Path,
+    output_directory: &Path,
+    path: &str,
+) -> Result<PathBuf, linkle::error::Error> {
+    path.trim_start_matches('/')
+        .split('/')
+        .try_fold(output_directory.to_path_buf(), |directory, name| {
+            entry_output_path(input_path, &directory, name)
+        })
+}
+
+fn list_save(input_path: &Path, json: bool) -> Result<(), linkle::error::Error> {
+    let input_file = open_input(input_path)?;
+    let save = linkle::format::save::SaveFile::from_reader_with_mode(input_file, parse_mode())
+        .with_path(input_path)?;
+    let entries = save.entries()?;
+
+    if json {
+        serde_json::to_writer_pretty(std::io::stdout(), &entries)?;
+        println!();
+        return Ok(());
+    }
+
+    println!("/");
+    for entry in entries {
+        let indent = "  ".repeat(entry.depth());
+        match entry {
+            linkle::format::save::SaveEntry::Dir { .. } => {
+                println!("{}{}/", indent, entry.name());
+            }
+            linkle::format::save::SaveEntry::File { size, .. } => {
+                println!("{}{} (size: {:#x})", indent, entry.name(), size);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn extract_save(input_path: &Path, output_directory: &Path) -> Result<(), linkle::error::Error> {
+    let input_file = open_input(input_path)?;
+    let mut save = linkle::format::save::SaveFile::from_reader_with_mode(input_file, parse_mode())
+        .with_path(input_path)?;
+    match std::fs::create_dir(output_directory) {
+        Ok(()) => (),
+        Err(ref err) if err.kind() == std::io::ErrorKind::AlreadyExists => (),
+        Err(err) => return Err((err, output_directory).into()),
+    }
+    for entry in save.entries()? {
+        let name = tree_entry_output_path(input_path, output_directory, entry.path())?;
+        match entry {
+            linkle::format::save::SaveEntry::Dir { .. } => match std::fs::create_dir(&name) {
+                Ok(()) => (),
+                Err(ref err) if err.kind() == std::io::ErrorKind::AlreadyExists => (),
+                Err(err) => return Err((err, &name).into()),
+            },
+            linkle::format::save::SaveEntry::File {
+                start_block, size, ..
+            } => {
+                log::info!("Writing {}", entry.path());
+                let mut out_file = Output::create(&name)?;
+                let mut file = save.open_file(start_block, size).with_path(input_path)?;
+                std::io::copy(&mut file, &mut out_file).map_err(|err| (err, &name))?;
+                out_file.finish()?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn extract_romfs_file(
+    input_path: &Path,
+    output_directory: &Path,
+    path: &str,
+) -> Result<(), linkle::error::Error> {
+    let input_file = open_input(input_path)?;
+    let mut romfs =
+        linkle::format::romfs::RomFsReader::from_reader_with_mode(input_file, parse_mode())
+            .with_path(input_path)?;
+    let (offset, size) = match romfs.find_file(path)? {
+        Some(linkle::format::romfs::RomFsEntry::File { offset, size, .. }) => (offset, size),
+        _ => {
+            return Err((
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("No file at {} in the RomFS", path),
+                ),
+                input_path,
+            )
+                .into())
+        }
+    };
+    let mut file = romfs
+        .open_file(offset, size)
+        .map_err(|err| (err, input_path))?;
+
+    if output_directory == Path::new("-") {
+        let stdout = std::io::stdout();
+        std::io::copy(&mut file, &mut stdout.lock())?;
+        return Ok(());
+    }
+
+    match std::fs::create_dir(output_directory) {
+        Ok(()) => (),
+        Err(ref err) if err.kind() == std::io::ErrorKind::AlreadyExists => (),
+        Err(err) => return Err((err, output_directory).into()),
+    }
+    let name = output_directory.join(&path[path.rfind('/').map_or(0, |v| v + 1)..]);
+    log::info!("Writing {}", path);
+    let mut out_file = Output::create(&name)?;
+    std::io::copy(&mut file, &mut out_file).map_err(|err| (err, &name))?;
+    out_file.finish()
+}
+
+fn replace_romfs_file(
+    romfs_path: &Path,
+    path: &str,
+    input_path: &Path,
+) -> Result<(), linkle::error::Error> {
+    let romfs_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(romfs_path)
+        .map_err(|err| (err, romfs_path))?;
+    let mut romfs =
+        linkle::format::romfs::RomFsReader::from_reader_with_mode(romfs_file, parse_mode())
+            .with_path(romfs_path)?;
+    let mut input_file = open_input(input_path)?;
+    let size = input_file
+        .metadata()
+        .map_err(|err| (err, input_path))?
+        .len();
+    romfs
+        .replace_file(path, &mut input_file, size)
+        .with_path(romfs_path)?;
+    Ok(())
+}
+
+fn diff_romfs(old_path: &Path, new_path: &Path, json: bool) -> Result<(), linkle::error::Error> {
+    let old_file = File::open(old_path).map_err(|err| (err, old_path))?;
+    let mut old = linkle::format::romfs::RomFsReader::from_reader_with_mode(old_file, parse_mode())
+        .with_path(old_path)?;
+    let new_file = File::open(new_path).map_err(|err| (err, new_path))?;
+    let mut new = linkle::format::romfs::RomFsReader::from_reader_with_mode(new_file, parse_mode())
+        .with_path(new_path)?;
+    let changes = linkle::format::romfs::diff(&mut old, &mut new)?;
+
+    if json {
+        serde_json::to_writer_pretty(std::io::stdout(), &changes)?;
+        println!();
+        return Ok(());
+    }
+
+    for change in changes.iter() {
+        match change {
+            linkle::format::romfs::RomFsChange::Added { path, size, .. } => {
+                println!("+ {} (size: {:#x})", path, size);
+            }
+            linkle::format::romfs::RomFsChange::Removed { path, size, .. } => {
+                println!("- {} (size: {:#x})", path, size);
+            }
+            linkle::format::romfs::RomFsChange::Modified {
+                path,
+                old_size,
+                new_size,
+                ..
+            } => {
+                println!("M {} (size: {:#x} -> {:#x})", path, old_size, new_size);
+            }
+        }
+    }
+    println!("{} file(s) changed", changes.len());
+    Ok(())
+}
+
+lazy_static::lazy_static! {
+    /// The `--titlekeys` and `--console-keys` files, used by every command
+    /// loading keys.
+    static ref KEY_PATHS: std::sync::Mutex<linkle::pki::KeyPaths> = Default::default();
+}
+
+/// Loads the keys from the `--keyset` of the command, and the global
+/// `--titlekeys` and `--console-keys`.
+fn load_keys(
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<linkle::pki::Keys, linkle::error::Error> {
+    let mut paths = KEY_PATHS.lock().unwrap().clone();
+    paths.keyset = key_path.map(Path::to_path_buf);
+    if is_dev {
+        linkle::pki::Keys::dev_from_paths(&paths)
+    } else {
+        linkle::pki::Keys::retail_from_paths(&paths)
+    }
+}
+
+/// The files `load_keys` reads the keys from, wherever they were found.
+fn key_files(is_dev: bool, key_path: Option<&Path>) -> Result<Vec<PathBuf>, linkle::error::Error> {
+    let mut paths = KEY_PATHS.lock().unwrap().clone();
+    paths.keyset = key_path.map(Path::to_path_buf);
+    paths.files(is_dev)
+}
+
+/// Loads the keys like `load_keys`, along with the titlekeys of `tickets`.
+fn load_keys_with_tickets(
+    is_dev: bool,
+    key_path: Option<&Path>,
+    tickets: &[PathBuf],
+) -> Result<linkle::pki::Keys, linkle::error::Error> {
+    let mut keys = load_keys(is_dev, key_path)?;
+    for ticket in tickets {
+        keys.read_ticket(ticket)?;
+    }
+    Ok(keys)
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|v| format!("{:02X}", v)).collect()
+}
+
+fn print_nca_info(
+    input_file: &Path,
+    json: bool,
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    use linkle::format::nca::HashInfo;
+
+    let keys = load_keys(is_dev, key_path)?;
+    let mut file = open_input(input_file)?;
+    let header = linkle::format::nca::read_header(&mut file, &keys).with_path(input_file)?;
+
+    if json {
+        serde_json::to_writer_pretty(std::io::stdout(), &header)?;
+        println!();
+        return Ok(());
+    }
+
+    println!("Magic:                    NCA{}", header.version);
+    println!("Distribution type:        {}", header.distribution_type);
+    println!("Content type:             {}", header.content_type);
+    println!(
+        "Key generation:           {} (master key {:02x})",
+        header.key_generation(),
+        header.master_key_revision()
+    );
+    println!(
+        "Signature key generation: {}",
+        header.signature_key_generation
+    );
+    println!("Key area encryption key:  {:?}", header.key_area_key_index);
+    println!("Content size:             {:#x}", header.content_size);
+    println!("Program ID:               {:016x}", header.program_id);
+    println!("Content index:            {}", header.content_index);
+    let sdk = header.sdk_addon_version.to_le_bytes();
+    println!(
+        "SDK version:              {}.{}.{}.{}",
+        sdk[3], sdk[2], sdk[1], sdk[0]
+    );
+    let plaintext = header
+        .sections
+        .iter()
+        .all(|v| v.fs_header.encryption_type == linkle::format::nca::EncryptionType::None);
+    if plaintext {
+        println!("Crypto:                   None");
+    } else if header.has_rights_id() {
+        println!("Rights ID:                {}", hex(&header.rights_id));
+        println!("Crypto:                   Titlekey");
+    } else {
+        println!("Crypto:                   Standard (key area)");
+        for (i, key) in header.encrypted_key_area.iter().enumerate() {
+            println!("Encrypted key area {}:     {}", i, hex(key));
+        }
+    }
+
+    println!("Sections:");
+    for section in &header.sections {
+        let fs_header = &section.fs_header;
+        println!("  Section {}:", section.index);
+        println!(
+            "    Offset:               {:#x}-{:#x} ({:#x} bytes)",
+            section.offset,
+            section.offset + section.size,
+            section.size
+        );
+        println!("    Filesystem:           {}", fs_header.fs_type);
+        println!("    Fs header version:    {}", fs_header.version);
+        println!("    Fs header hash:       {}", hex(&section.fs_header_hash));
+        println!("    Encryption:           {}", fs_header.encryption_type);
+        println!("    Section CTR:          {:016X}", fs_header.ctr);
+        if let Some(table) = fs_header.sparse_table() {
+            println!(
+                "    Sparse table:         {:#x} ({:#x} bytes)",
+                table.offset, table.size
+            );
+        }
+        if let Some(table) = fs_header.compression_table() {
+            println!(
+                "    Compression table:    {:#x} ({:#x} bytes)",
+                table.offset, table.size
+            );
+        }
+        println!("    Hash type:            {}", fs_header.hash_type);
+        match &fs_header.hash_info {
+            HashInfo::HierarchicalSha256 {
+                master_hash,
+                block_size,
+                hash_table,
+                pfs0,
+            } => {
+                println!("    Master hash:          {}", hex(master_hash));
+                println!("    Hash block size:      {:#x}", block_size);
+                println!(
+                    "    Hash table:           {:#x} ({:#x} bytes)",
+                    hash_table.offset, hash_table.size
+                );
+                println!(
+                    "    PFS0:                 {:#x} ({:#x} bytes)",
+                    pfs0.offset, pfs0.size
+                );
+            }
+            HashInfo::HierarchicalIntegrity {
+                master_hash,
+                levels,
+            } => {
+                println!("    Master hash:          {}", hex(master_hash));
+                for (i, level) in levels.iter().enumerate() {
+                    println!(
+                        "    Level {}:              {:#x} ({:#x} bytes, block size {:#x})",
+                        i + 1,
+                        level.region.offset,
+                        level.region.size,
+                        1u64 << level.block_size_log2
+                    );
+                }
+            }
+            HashInfo::None => (),
+        }
+    }
+    Ok(())
+}
+
+fn parse_program_id(s: &str) -> Result<u64, std::num::ParseIntError> {
+    u64::from_str_radix(s.trim_start_matches("0x"), 16)
+}
+
+fn parse_hex_key(s: &str) -> Result<[u8; 0x10], String> {
+    let s = s.trim_start_matches("0x");
+    if s.len() != 0x20 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(format!("{} isn't 16 bytes of hexadecimal", s));
+    }
+    let mut key = [0; 0x10];
+    for (i, b) in key.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).unwrap();
+    }
+    Ok(key)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn create_nca(
+    output_file: &Path,
+    sections: &[String],
+    content_type: linkle::format::nca::ContentType,
+    program_id: u64,
+    key_generation: u8,
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    let keys = load_keys(is_dev, key_path)?;
+    let mut nca = linkle::format::nca::NcaBuilder::new(content_type, program_id);
+    nca.key_generation = key_generation;
+    for section in sections {
+        let (fs_type, path) = match section.find('=') {
+            Some(idx) => (&section[..idx], Path::new(&section[idx + 1..])),
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Invalid section {}, expected TYPE=FILE", section),
+                )
+                .into())
+            }
+        };
+        let fs_type = fs_type
+            .parse()
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+        let image = File::open(path).map_err(|err| (err, path))?;
+        nca.add_section(fs_type, image)?;
+    }
+
+    let mut out_file = Output::create(output_file)?;
+    nca.write(&mut out_file, &keys).with_path(output_file)?;
+    out_file.finish()
+}
+
+#[allow(clippy::too_many_arguments)]
+fn create_program_nca(
+    output_file: &Path,
+    exefs: &Path,
+    romfs: Option<&Path>,
+    logo: Option<&Path>,
+    base: Option<&Path>,
+    tickets: &[PathBuf],
+    program_id: Option<u64>,
+    program_index: u8,
+    npdm: Option<&Path>,
+    acid_signing_key: Option<&Path>,
+    key_generation: u8,
+    signing_key: Option<&Path>,
+    plaintext: bool,
+    compress: bool,
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    let keys = load_keys_with_tickets(is_dev, key_path, tickets)?;
+    let signing_key = match signing_key {
+        Some(path) => Some(linkle::pki::RsaPrivateKey::from_file(path)?),
+        None => None,
+    };
+    let acid_signing_key = match acid_signing_key {
+        Some(path) => Some(linkle::pki::RsaPrivateKey::from_file(path)?),
+        None => None,
+    };
+
+    // With --npdm, the ExeFS is copied so its main.npdm can be rebuilt.
+    let work_dir = tempfile::tempdir()?;
+    let exefs = match npdm {
+        Some(npdm) => {
+            let exefs_dir = work_dir.path().join("exefs");
+            copy_exefs(exefs, &exefs_dir)?;
+            write_npdm(
+                npdm,
+                &exefs_dir,
+                program_id,
+                signing_key.as_ref(),
+                acid_signing_key.as_ref(),
+            )?;
+            exefs_dir
+        }
+        None => exefs.to_path_buf(),
+    };
+
+    let mut nca = match (base, romfs) {
+        (Some(base_path), Some(romfs)) => {
+            let base_file = File::open(base_path).map_err(|err| (err, base_path))?;
+            let base =
+                linkle::format::nca::Nca::from_reader_with_mode(base_file, &keys, parse_mode())
+                    .with_path(base_path)?;
+            linkle::format::nca::NcaBuilder::patch_program(&exefs, romfs, &base)
+                .with_path(base_path)?
+        }
+        _ => linkle::format::nca::NcaBuilder::program(&exefs, romfs)?,
+    };
+    if let Some(logo) = logo {
+        nca.add_logo_section(logo).with_path(logo)?;
+    }
+    if let Some(program_id) = program_id {
+        nca.program_id = program_id;
+    }
+    nca.content_index = program_index as u32;
+    nca.key_generation = key_generation;
+    nca.compress = compress;
+    if plaintext {
+        nca.section_key = None;
+    }
+    if let Some(signing_key) = signing_key {
+        check_signing_key(&exefs, &signing_key)?;
+        nca.signing_key = Some(signing_key);
+    }
+
+    let mut out_file = Output::create(output_file)?;
+    nca.write(&mut out_file, &keys).with_path(output_file)?;
+    out_file.finish()
+}
+
+/// Copies the files of the ExeFS directory `exefs` to `exefs_dir`.
+fn copy_exefs(exefs: &Path, exefs_dir: &Path) -> Result<(), linkle::error::Error> {
+    std::fs::create_dir(exefs_dir).map_err(|err| (err, exefs_dir))?;
+    for entry in std::fs::read_dir(exefs).map_err(|err| (err, exefs))? {
+        let entry = entry.map_err(|err| (err, exefs))?;
+        if entry.path().is_file() {
+            let dest = exefs_dir.join(entry.file_name());
+            std::fs::copy(entry.path(), &dest).map_err(|err| (err, entry.path()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Builds the main.npdm of `exefs_dir` from the NPDM JSON at `npdm_path`.
+/// `program_id` replaces the one of the JSON, so that the ACI0 matches the
+/// NCA and the CNMT. The ACID holds the public key of `signing_key`, and is
+/// signed with `acid_signing_key`.
+fn write_npdm(
+    npdm_path: &Path,
+    exefs_dir: &Path,
+    program_id: Option<u64>,
+    signing_key: Option<&linkle::pki::RsaPrivateKey>,
+    acid_signing_key: Option<&linkle::pki::RsaPrivateKey>,
+) -> Result<(), linkle::error::Error> {
+    let mut npdm: linkle::format::npdm::Npdm =
+        load_json_descriptor(npdm_path, &[]).with_path(npdm_path)?;
+    if let Some(program_id) = program_id {
+        npdm.set_program_id(program_id).with_path(npdm_path)?;
+    }
+    let acid_public_key = match signing_key {
+        Some(signing_key) => *signing_key.modulus(),
+        None => [0; 0x100],
+    };
+    let mut data = npdm.to_bytes(&acid_public_key).with_path(npdm_path)?;
+    if let Some(acid_signing_key) = acid_signing_key {
+        linkle::format::npdm::sign_acid(&mut data, acid_signing_key)?;
+    }
+    write_output(exefs_dir.join("main.npdm"), &data)
+}
+
+/// Warns when `signing_key` isn't the one the ACID of the main.npdm in
+/// `exefs` expects the program NCA to be signed with.
+fn check_signing_key(
+    exefs: &Path,
+    signing_key: &linkle::pki::RsaPrivateKey,
+) -> Result<(), linkle::error::Error> {
+    let npdm_path = exefs.join("main.npdm");
+    let npdm = std::fs::read(&npdm_path).map_err(|err| (err, &npdm_path))?;
+    let npdm = linkle::format::npdm::NpdmInfo::parse_with_mode(&npdm, parse_mode())
+        .with_path(&npdm_path)?;
+    if npdm.acid_public_key[..] != signing_key.modulus()[..] {
+        log::warn!("the signing key doesn't match the ACID public key of main.npdm");
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn create_manual_nca(
+    output_file: &Path,
+    documents: &Path,
+    program_id: u64,
+    program_index: u8,
+    key_generation: u8,
+    plaintext: bool,
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    let keys = load_keys(is_dev, key_path)?;
+    let mut nca =
+        linkle::format::nca::NcaBuilder::manual(documents, program_id).with_path(documents)?;
+    nca.content_index = program_index as u32;
+    nca.key_generation = key_generation;
+    if plaintext {
+        nca.section_key = None;
+    }
+
+    let mut out_file = Output::create(output_file)?;
+    nca.write(&mut out_file, &keys).with_path(output_file)?;
+    out_file.finish()
+}
+
+#[allow(clippy::too_many_arguments)]
+fn create_control_nca(
+    output_file: &Path,
+    control: &Path,
+    program_id: Option<u64>,
+    program_index: u8,
+    key_generation: u8,
+    plaintext: bool,
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    let keys = load_keys(is_dev, key_path)?;
+    let mut nca = linkle::format::nca::NcaBuilder::control(control)?;
+    if let Some(program_id) = program_id {
+        nca.program_id = program_id;
+    }
+    nca.content_index = program_index as u32;
+    nca.key_generation = key_generation;
+    if plaintext {
+        nca.section_key = None;
+    }
+
+    let mut out_file = Output::create(output_file)?;
+    nca.write(&mut out_file, &keys).with_path(output_file)?;
+    out_file.finish()
+}
+
+#[allow(clippy::too_many_arguments)]
+fn create_meta_nca(
+    output_file: &Path,
+    contents: &[String],
+    meta_type: linkle::format::cnmt::ContentMetaType,
+    title_id: u64,
+    title_version: u32,
+    required_system_version: u32,
+    key_generation: u8,
+    plaintext: bool,
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    use linkle::format::cnmt::{Cnmt, ContentRecord, ContentType, ExtendedHeader};
+
+    let keys = load_keys(is_dev, key_path)?;
+    let mut cnmt = Cnmt::new(meta_type, title_id, title_version);
+    match cnmt.extended_header {
+        ExtendedHeader::Application {
+            required_system_version: ref mut version,
+            ..
+        }
+        | ExtendedHeader::Patch {
+            required_system_version: ref mut version,
+            ..
+        } => *version = required_system_version,
+        _ => (),
+    }
+    for content in contents {
+        let (content_type, path) = match content.find('=') {
+            Some(idx) => (&content[..idx], Path::new(&content[idx + 1..])),
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Invalid content {}, expected TYPE=FILE", content),
+                )
+                .into())
+            }
+        };
+        let content_type = content_type
+            .parse()
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+        let mut record = ContentRecord::from_file(path, content_type)?;
+        // The program of the title the NCA belongs to is given by its header.
+        let mut file = File::open(path).map_err(|err| (err, path))?;
+        let header = linkle::format::nca::read_header(&mut file, &keys).with_path(path)?;
+        record.id_offset = match u8::try_from(header.content_index) {
+            Ok(program_index) => program_index,
+            Err(_) => {
+                return Err((
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "the program index of the NCA doesn't fit in a CNMT",
+                    ),
+                    path,
+                )
+                    .into())
+            }
+        };
+        if content_type == ContentType::Program
+            && cnmt.program_id(record.id_offset) != Some(header.program_id)
+        {
+            log::warn!(
+                "{} has program ID {:016x}, expected the application ID plus its program index {}",
+                path.display(),
+                header.program_id,
+                record.id_offset
+            );
+        }
+        log::info!("{}: {}", path.display(), record.file_name());
+        cnmt.push_content(record).with_path(path)?;
+    }
+
+    let mut nca = linkle::format::nca::NcaBuilder::meta(&cnmt)?;
+    nca.key_generation = key_generation;
+    if plaintext {
+        nca.section_key = None;
+    }
+    let mut out_file = Output::create(output_file)?;
+    let hash = nca.write(&mut out_file, &keys).with_path(output_file)?;
+    out_file.finish()?;
+    log::info!(
+        "{}: {}.cnmt.nca",
+        output_file.display(),
+        hex(&hash[..0x10]).to_lowercase()
+    );
+    Ok(())
+}
+
+fn create_ticket(
+    output_file: &Path,
+    rights_id: [u8; 0x10],
+    titlekey: &[u8; 0x10],
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    use std::io::Write;
+
+    let keys = load_keys(is_dev, key_path)?;
+    let ticket = linkle::format::tik::Ticket::common(rights_id, titlekey, &keys)?;
+    let mut out_file = Output::create(output_file)?;
+    out_file
+        .write_all(&ticket.to_bytes())
+        .map_err(|err| (err, output_file))?;
+    out_file.finish()
+}
+
+fn depersonalize_ticket(
+    input_file: &Path,
+    output_file: &Path,
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    use std::io::Write;
+
+    let keys = load_keys(is_dev, key_path)?;
+    let file = open_input(input_file)?;
+    let ticket = linkle::format::tik::Ticket::from_reader_with_mode(file, parse_mode())
+        .with_path(input_file)?;
+    let common = ticket.to_common(&keys).with_path(input_file)?;
+    let mut out_file = Output::create(output_file)?;
+    out_file
+        .write_all(&common.to_bytes())
+        .map_err(|err| (err, output_file))?;
+    out_file.finish()?;
+    log::info!("Rights ID:       {}", hex(&common.rights_id));
+    Ok(())
+}
+
+/// A ticket along with its decrypted titlekey, as printed by `tik_info
+/// --json`.
+#[derive(serde_derive::Serialize)]
+struct TicketInfo<'a> {
+    #[serde(flatten)]
+    ticket: &'a linkle::format::tik::Ticket,
+    titlekey: Option<String>,
+}
+
+impl<'a> TicketInfo<'a> {
+    fn new(ticket: &'a linkle::format::tik::Ticket, keys: &linkle::pki::Keys) -> TicketInfo<'a> {
+        TicketInfo {
+            ticket,
+            titlekey: ticket
+                .titlekey(keys)
+                .ok()
+                .map(|titlekey| hex(titlekey.as_bytes())),
+        }
+    }
+}
+
+fn print_ticket_info(
+    input_file: &Path,
+    json: bool,
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    let keys = load_keys(is_dev, key_path)?;
+    let file = open_input(input_file)?;
+    let ticket = linkle::format::tik::Ticket::from_reader_with_mode(file, parse_mode())
+        .with_path(input_file)?;
+
+    if json {
+        serde_json::to_writer_pretty(std::io::stdout(), &TicketInfo::new(&ticket, &keys))?;
+        println!();
+        return Ok(());
+    }
+
+    println!("Signature type:  {}", ticket.signature_type);
+    println!("Issuer:          {}", ticket.issuer);
+    println!("Format version:  {}", ticket.format_version);
+    println!("Titlekey type:   {}", ticket.titlekey_type);
+    println!("Ticket version:  {}", ticket.ticket_version);
+    println!("License type:    {}", ticket.license_type);
+    println!("Key generation:  {:02x}", ticket.key_generation);
+    println!("Property mask:   {:#06x}", ticket.property_mask);
+    println!("Ticket ID:       {:016x}", ticket.ticket_id);
+    println!("Device ID:       {:016x}", ticket.device_id);
+    println!("Rights ID:       {}", hex(&ticket.rights_id));
+    println!("Account ID:      {:08x}", ticket.account_id);
+    match ticket.titlekey_type {
+        linkle::format::tik::TitlekeyType::Common => {
+            println!("Titlekey:        {}", hex(&ticket.titlekey_block[..0x10]));
+            if let Ok(titlekey) = ticket.titlekey(&keys) {
+                println!("Dec. titlekey:   {}", hex(titlekey.as_bytes()));
+            }
+        }
+        linkle::format::tik::TitlekeyType::Personalized => {
+            println!("Titlekey block:  {}", hex(&ticket.titlekey_block));
+            match ticket.titlekey(&keys) {
+                Ok(titlekey) => println!("Dec. titlekey:   {}", hex(titlekey.as_bytes())),
+                Err(err) => println!("Dec. titlekey:   {}", err),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print_cert_info(input_file: &Path, json: bool) -> Result<(), linkle::error::Error> {
+    let file = open_input(input_file)?;
+    let chain = linkle::format::cert::CertChain::from_reader_with_mode(file, parse_mode())
+        .with_path(input_file)?;
+    if json {
+        serde_json::to_writer_pretty(std::io::stdout(), &chain)?;
+        println!();
+        return chain.validate().with_path(input_file);
+    }
+    for cert in &chain.certs {
+        println!("{}:", cert.full_name());
+        println!("  Signature type: {}", cert.signature_type);
+        println!("  Issuer:         {}", cert.issuer);
+        println!("  Name:           {}", cert.name);
+        println!("  Key type:       {}", cert.public_key);
+        println!("  Key ID:         {:08x}", cert.key_id);
+    }
+    chain.validate().with_path(input_file)?;
+    println!("The chain is well-formed.");
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn create_nsp(
+    output_file: &Path,
+    elf: Option<&str>,
+    exefs: Option<&Path>,
+    npdm: Option<&Path>,
+    nacp: &str,
+    icon: Option<&Path>,
+    romfs: Option<&Path>,
+    logo: Option<&Path>,
+    html_document: Option<&Path>,
+    legal_information: Option<&Path>,
+    base: Option<&Path>,
+    tickets: &[PathBuf],
+    title_version: u32,
+    key_generation: u8,
+    compress: bool,
+    signing_key: Option<&Path>,
+    acid_signing_key: Option<&Path>,
+    titlekey_crypto: bool,
+    cert: Option<&Path>,
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    use linkle::format::cnmt::{Cnmt, ContentMetaType, ContentType};
+    use linkle::format::nca::{Nca, NcaBuilder};
+
+    let mut keys = load_keys_with_tickets(is_dev, key_path, tickets)?;
+    if let Some(cert) = cert {
+        keys.read_certs(cert)?;
+    }
+    let signing_key = match signing_key {
+        Some(path) => Some(linkle::pki::RsaPrivateKey::from_file(path)?),
+        None => None,
+    };
+    let acid_signing_key = match acid_signing_key {
+        Some(path) => Some(linkle::pki::RsaPrivateKey::from_file(path)?),
+        None => None,
+    };
+    let work_dir = tempfile::tempdir()?;
+
+    // Gather the ExeFS: the files of --exefs, then the NSO and NPDM built from
+    // --elf and --npdm.
+    let exefs_dir = work_dir.path().join("exefs");
+    match exefs {
+        Some(exefs) => copy_exefs(exefs, &exefs_dir)?,
+        None => std::fs::create_dir(&exefs_dir).map_err(|err| (err, &exefs_dir))?,
+    }
+    if let Some(elf) = elf {
+        let main_path = exefs_dir.join("main");
+        create_nxo(
+            "nso",
+            elf,
+            &main_path.to_string_lossy(),
+            None,
+            false,
+            None,
+            None,
+        )?;
+    }
+    match (npdm, &signing_key) {
+        (Some(npdm), _) => write_npdm(
+            npdm,
+            &exefs_dir,
+            None,
+            signing_key.as_ref(),
+            acid_signing_key.as_ref(),
+        )?,
+        (None, Some(signing_key)) => check_signing_key(&exefs_dir, signing_key)?,
+        (None, None) => (),
+    }
+
+    let mut program = match (base, romfs) {
+        (Some(base_path), Some(romfs)) => {
+            let base_file = File::open(base_path).map_err(|err| (err, base_path))?;
+            let base =
+                Nca::from_reader_with_mode(base_file, &keys, parse_mode()).with_path(base_path)?;
+            NcaBuilder::patch_program(&exefs_dir, romfs, &base).with_path(base_path)?
+        }
+        _ => NcaBuilder::program(&exefs_dir, romfs)?,
+    };
+    if let Some(logo) = logo {
+        program.add_logo_section(logo).with_path(logo)?;
+    }
+    program.signing_key = signing_key;
+    let title_id = program.program_id;
+    // Updates are a title of their own, the patch title of the application.
+    let (meta_type, meta_id) = match base {
+        Some(_) => (ContentMetaType::Patch, title_id | 0x800),
+        None => (ContentMetaType::Application, title_id),
+    };
+
+    let control_dir = work_dir.path().join("control");
+    create_control(nacp, &control_dir, icon, &[], None)?;
+    let mut control = NcaBuilder::control(&control_dir)?;
+    control.program_id = title_id;
+
+    let mut ncas = vec![
+        (program, ContentType::Program),
+        (control, ContentType::Control),
+    ];
+    if let Some(html_document) = html_document {
+        let nca = NcaBuilder::manual(html_document, title_id).with_path(html_document)?;
+        ncas.push((nca, ContentType::HtmlDocument));
+    }
+    if let Some(legal_information) = legal_information {
+        let nca = NcaBuilder::manual(legal_information, title_id).with_path(legal_information)?;
+        ncas.push((nca, ContentType::LegalInformation));
+    }
+
+    for (nca, _) in &mut ncas {
+        nca.compress = compress;
+    }
+    let cnmt = Cnmt::new(meta_type, meta_id, title_version);
+    pack_nsp(
+        output_file,
+        ncas,
+        cnmt,
+        key_generation,
+        titlekey_crypto,
+        &keys,
+    )
+}
+
+/// Builds an NSP the way hacbrewpack does, from its directories.
+/// Writes the templates of `linkle new`, refusing to replace existing files
+/// unless `--force` is given.
+fn create_templates(opt: &NewOpt) -> Result<(), linkle::error::Error> {
+    use linkle::template::{self, ProgramKind};
+
+    let (output, templates) = match opt {
+        NewOpt::Sysmodule { title_id, output } => {
+            let name = output.name.as_deref().unwrap_or("sysmodule");
+            let npdm = template::npdm(ProgramKind::Sysmodule, name, *title_id);
+            (output, vec![("npdm.json", npdm)])
+        }
+        NewOpt::App {
+            title_id,
+            author,
+            output,
+        } => {
+            let name = output.name.as_deref().unwrap_or("app");
+            let npdm = template::npdm(ProgramKind::Application, name, *title_id);
+            let nacp = template::nacp(name, author, *title_id);
+            (output, vec![("npdm.json", npdm), ("nacp.json", nacp)])
+        }
+    };
+
+    std::fs::create_dir_all(&output.output_dir).map_err(|err| (err, &output.output_dir))?;
+    let paths: Vec<PathBuf> = templates
+        .iter()
+        .map(|(file_name, _)| output.output_dir.join(file_name))
+        .collect();
+    if !FORCE.load(Ordering::Relaxed) {
+        // Check them all first, so that none is written when one exists.
+        if let Some(path) = paths.iter().find(|path| path.exists()) {
+            let err = std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                "file exists, use --force to overwrite it",
+            );
+            return Err((err, path).into());
+        }
+    }
+    for (path, (_, data)) in paths.iter().zip(&templates) {
+        write_output(path, data.as_bytes())?;
+        log::info!("Created {}", path.display());
+    }
+    Ok(())
+}
+
+fn create_hacbrewpack_nsp(opt: &HacbrewpackOpt) -> Result<(), linkle::error::Error> {
+    use linkle::format::cnmt::{Cnmt, ContentMetaType, ContentType};
+    use linkle::format::nca::NcaBuilder;
+    use snafu::GenerateBacktrace;
+
+    let default_keyfile = Path::new("keys.dat");
+    let key_path = match &opt.keyfile {
+        Some(keyfile) => Some(keyfile.as_path()),
+        None if default_keyfile.exists() => Some(default_keyfile),
+        None => None,
+    };
+    let keys = load_keys(opt.dev, key_path)?;
+
+    let romfs = if opt.noromfs {
+        None
+    } else {
+        Some(opt.romfsdir.as_path())
+    };
+    let mut program = NcaBuilder::program(&opt.exefsdir, romfs)?;
+    if !opt.nologo {
+        program
+            .add_logo_section(&opt.logodir)
+            .with_path(&opt.logodir)?;
+    }
+    let title_id = program.program_id;
+    if let Some(titleid) = opt.titleid {
+        if titleid != title_id {
+            return Err(linkle::error::Error::InvalidArgument {
+                argument: format!("--titleid {:016x}", titleid),
+                expected: "the program ID of main.npdm",
+                backtrace: snafu::Backtrace::generate(),
+            });
+        }
+    }
+    let mut control = NcaBuilder::control(&opt.controldir).with_path(&opt.controldir)?;
+    control.program_id = title_id;
+
+    let mut ncas = vec![
+        (program, ContentType::Program),
+        (control, ContentType::Control),
+    ];
+    if opt.htmldocdir.is_dir() {
+        let nca = NcaBuilder::manual(&opt.htmldocdir, title_id).with_path(&opt.htmldocdir)?;
+        ncas.push((nca, ContentType::HtmlDocument));
+    }
+    if opt.legalinfodir.is_dir() {
+        let nca = NcaBuilder::manual(&opt.legalinfodir, title_id).with_path(&opt.legalinfodir)?;
+        ncas.push((nca, ContentType::LegalInformation));
+    }
+
+    std::fs::create_dir_all(&opt.nspdir).map_err(|err| (err, &opt.nspdir))?;
+    let output_file = opt.nspdir.join(format!("{:016x}.nsp", title_id));
+    let cnmt = Cnmt::new(ContentMetaType::Application, title_id, 0);
+    pack_nsp(&output_file, ncas, cnmt, opt.keygeneration, false, &keys)?;
+    log::info!("Created {}", output_file.display());
+    Ok(())
+}
+
+/// Writes the NSP of the title described by `cnmt`, made of `ncas` and of the
+/// meta NCA listing them. With `titlekey_crypto`, the NCAs are encrypted with
+/// a random titlekey, given by a common ticket packed along them.
+fn pack_nsp(
+    output_file: &Path,
+    ncas: Vec<(
+        linkle::format::nca::NcaBuilder,
+        linkle::format::cnmt::ContentType,
+    )>,
+    mut cnmt: linkle::format::cnmt::Cnmt,
+    key_generation: u8,
+    titlekey_crypto: bool,
+    keys: &linkle::pki::Keys,
+) -> Result<(), linkle::error::Error> {
+    use linkle::format::cnmt::ContentRecord;
+    use linkle::format::nca::NcaBuilder;
+
+    let work_dir = tempfile::tempdir()?;
+    let mut nsp = linkle::format::pfs0::Pfs0::new();
+    let titlekey = if titlekey_crypto {
+        let rights_id =
+            linkle::format::tik::rights_id(cnmt.title_id, key_generation.saturating_sub(1));
+        let titlekey = rand::random();
+        let ticket = linkle::format::tik::Ticket::common(rights_id, &titlekey, keys)?;
+        match keys.certs().chain_for_ticket(&ticket) {
+            Ok(chain) => nsp.push_data(ticket.cert_file_name(), chain.to_bytes())?,
+            Err(_) => log::warn!(
+                "no certificate chain for {}, installers may refuse the ticket",
+                ticket.issuer
+            ),
+        }
+        nsp.push_data(ticket.file_name(), ticket.to_bytes())?;
+        Some((rights_id, titlekey))
+    } else {
+        None
+    };
+
+    // Write the NCAs under their final names, listing them in the CNMT.
+    let nca_path = work_dir.path().join("nca");
+    for (mut nca, content_type) in ncas {
+        nca.key_generation = key_generation;
+        if let Some((rights_id, titlekey)) = titlekey {
+            nca.rights_id = Some(rights_id);
+            nca.section_key = Some(titlekey);
+        }
+        let mut out_file = File::create(&nca_path).map_err(|err| (err, &nca_path))?;
+        let hash = nca.write(&mut out_file, keys).with_path(&nca_path)?;
+        let size = out_file.metadata().map_err(|err| (err, &nca_path))?.len();
+        let record = ContentRecord::new(hash, size, content_type);
+        let path = work_dir.path().join(record.file_name());
+        std::fs::rename(&nca_path, &path).map_err(|err| (err, &nca_path))?;
+        nsp.push_file(path)?;
+        cnmt.push_content(record)?;
+    }
+
+    let mut meta = NcaBuilder::meta(&cnmt)?;
+    meta.key_generation = key_generation;
+    let mut out_file = File::create(&nca_path).map_err(|err| (err, &nca_path))?;
+    let hash = meta.write(&mut out_file, keys).with_path(&nca_path)?;
+    let path = work_dir
+        .path()
+        .join(format!("{}.cnmt.nca", hex(&hash[..0x10]).to_lowercase()));
+    std::fs::rename(&nca_path, &path).map_err(|err| (err, &nca_path))?;
+    nsp.push_file(path)?;
+
+    let mut out_file = Output::create(output_file)?;
+    nsp.write_pfs0(&mut out_file)
+        .map_err(|err| (err, output_file))?;
+    out_file.finish()
+}
+
+#[allow(clippy::too_many_arguments)]
+fn convert_nsp(
+    input_path: &Path,
+    output_path: &Path,
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    let mut keys = load_keys(is_dev, key_path)?;
+    let input_file = open_input(input_path)?;
+    let mut output_file = Output::create(output_path)?;
+    let converted = linkle::format::nca::convert_nsp_to_standard_crypto(
+        input_file,
+        &mut output_file,
+        &mut keys,
+    )
+    .with_path(input_path)?;
+    output_file.finish()?;
+    if converted.is_empty() {
+        log::warn!("{} has no NCA using titlekey crypto", input_path.display());
+    }
+    Ok(())
+}
+
+fn extract_nsp(
+    input_path: &Path,
+    options: &NcaExtractOptions<'_>,
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    let mut keys = load_keys(is_dev, key_path)?;
+    let base = open_base_nca(options.base, &keys)?;
+    let input_file = open_input(input_path)?;
+    let (name, nca) =
+        linkle::format::nca::open_nsp_program(input_file, &mut keys).with_path(input_path)?;
+    log::info!("Extracting {}", name);
+    extract_nca_content(&nca, base.as_ref(), input_path, options)
+}
+
+fn create_add_on_content_nsp(
+    output_file: &Path,
+    romfs: &Path,
+    application_id: u64,
+    index: u16,
+    title_version: u32,
+    required_application_version: u32,
+    key_generation: u8,
+    titlekey_crypto: bool,
+    cert: Option<&Path>,
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    use linkle::format::cnmt::{Cnmt, ContentMetaType, ContentType, ExtendedHeader};
+    use linkle::format::nca::NcaBuilder;
+
+    let mut keys = load_keys(is_dev, key_path)?;
+    if let Some(cert) = cert {
+        keys.read_certs(cert)?;
+    }
+    let title_id = linkle::format::cnmt::add_on_content_id(application_id, index)?;
+    let nca = NcaBuilder::add_on_content(romfs, title_id)?;
+
+    let mut cnmt = Cnmt::new(ContentMetaType::AddOnContent, title_id, title_version);
+    if let ExtendedHeader::AddOnContent {
+        required_application_version: ref mut version,
+        ..
+    } = cnmt.extended_header
+    {
+        *version = required_application_version;
+    }
+    log::info!("Add-on content ID: {:016x}", title_id);
+    pack_nsp(
+        output_file,
+        vec![(nca, ContentType::Data)],
+        cnmt,
+        key_generation,
+        titlekey_crypto,
+        &keys,
+    )
+}
+
+fn reencrypt_nca(
+    input_path: &Path,
+    output_path: &Path,
+    key_generation: u8,
+    signing_key: Option<&Path>,
+    tickets: &[PathBuf],
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    let keys = load_keys_with_tickets(is_dev, key_path, tickets)?;
+    let signing_key = match signing_key {
+        Some(path) => Some(linkle::pki::RsaPrivateKey::from_file(path)?),
+        None => None,
+    };
+    let mut input_file = open_input(input_path)?;
+    let mut output_file = Output::create(output_path)?;
+    let hash = linkle::format::nca::reencrypt(
+        &mut input_file,
+        &mut output_file,
+        &keys,
+        key_generation,
+        signing_key.as_ref(),
+    )
+    .with_path(input_path)?;
+    output_file.finish()?;
+    log::info!(
+        "{}: {}.nca",
+        output_path.display(),
+        hex(&hash[..0x10]).to_lowercase()
+    );
+    Ok(())
+}
+
+fn resign_nca(
+    input_path: &Path,
+    output_path: &Path,
+    signing_key: &Path,
+    tickets: &[PathBuf],
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    let keys = load_keys_with_tickets(is_dev, key_path, tickets)?;
+    let signing_key = linkle::pki::RsaPrivateKey::from_file(signing_key)?;
+    let input_file = open_input(input_path)?;
+    let mut output_file = Output::create(output_path)?;
+    let hash = linkle::format::nca::resign(input_file, &mut output_file, &keys, &signing_key)
+        .with_path(input_path)?;
+    output_file.finish()?;
+    log::info!(
+        "{}: {}.nca",
+        output_path.display(),
+        hex(&hash[..0x10]).to_lowercase()
+    );
+    Ok(())
+}
+
+/// Result of checking the hashes of a section, as printed by `nca_verify
+/// --json`. `mismatches` is `None` for BKTR sections checked without their
+/// base NCA.
+#[derive(serde_derive::Serialize)]
+struct SectionStatus {
+    index: usize,
+    mismatches: Option<Vec<linkle::format::nca::HashMismatch>>,
+}
+
+fn verify_nca(
+    input_path: &Path,
+    base_path: Option<&Path>,
+    tickets: &[PathBuf],
+    json: bool,
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    use linkle::format::nca::{EncryptionType, SignatureStatus};
+
+    let keys = load_keys_with_tickets(is_dev, key_path, tickets)?;
+    let input_file = open_input(input_path)?;
+    let nca = linkle::format::nca::Nca::from_reader_with_mode(input_file, &keys, parse_mode())
+        .with_path(input_path)?;
+    let base = match base_path {
+        Some(base_path) => {
+            let base_file = File::open(base_path).map_err(|err| (err, base_path))?;
+            let base =
+                linkle::format::nca::Nca::from_reader_with_mode(base_file, &keys, parse_mode())
+                    .with_path(base_path)?;
+            Some(base)
+        }
+        None => None,
+    };
+    let signatures = nca.verify_signatures(&keys).with_path(input_path)?;
+
+    let mut sections = Vec::new();
+    for section in &nca.header().sections {
+        let is_bktr = section.fs_header.encryption_type == EncryptionType::AesCtrEx;
+        let mismatches = match &base {
+            Some(base) if is_bktr => Some(nca.verify_patched_section(section.index, base)),
+            None if is_bktr => None,
+            _ => Some(nca.verify_section(section.index)),
+        }
+        .transpose()
+        .with_path(input_path)?;
+        sections.push(SectionStatus {
+            index: section.index,
+            mismatches,
+        });
+    }
+    let hashes_valid = sections.iter().all(|section| match &section.mismatches {
+        Some(mismatches) => mismatches.is_empty(),
+        None => true,
+    });
+
+    if json {
+        let status = serde_json::json!({
+            "signatures": signatures,
+            "sections": sections,
+        });
+        serde_json::to_writer_pretty(std::io::stdout(), &status)?;
+        println!();
+    } else {
+        println!("Fixed key signature: {}", signatures.fixed_key);
+        println!("NPDM signature:      {}", signatures.npdm);
+        for section in &sections {
+            match &section.mismatches {
+                None => println!(
+                    "Section {}:           Unchecked, needs --base",
+                    section.index
+                ),
+                Some(mismatches) if mismatches.is_empty() => {
+                    println!("Section {}:           Valid", section.index)
+                }
+                Some(mismatches) => {
+                    println!("Section {}:           Invalid", section.index);
+                    for mismatch in mismatches {
+                        println!("    Hash mismatch at {}", mismatch);
+                    }
+                }
+            }
+        }
+    }
+
+    if signatures.fixed_key == SignatureStatus::Invalid
+        || signatures.npdm == SignatureStatus::Invalid
+    {
+        return Err((
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "the NCA header has an invalid signature",
+            ),
+            input_path,
+        )
+            .into());
+    }
+    if !hashes_valid {
+        return Err((
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "the NCA has sections with invalid hashes",
+            ),
+            input_path,
+        )
+            .into());
+    }
+    Ok(())
+}
+
+/// Where `extract_nca` writes the content of an NCA, along with the base NCA
+/// its BKTR sections patch.
+struct NcaExtractOptions<'a> {
+    exefs: Option<&'a Path>,
+    romfs: Option<&'a Path>,
+    sections: Option<&'a Path>,
+    base: Option<&'a Path>,
+}
+
+fn extract_nca(
+    input_path: &Path,
+    options: &NcaExtractOptions<'_>,
+    tickets: &[PathBuf],
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    let keys = load_keys_with_tickets(is_dev, key_path, tickets)?;
+    let input_file = open_input(input_path)?;
+    let nca = linkle::format::nca::Nca::from_reader_with_mode(input_file, &keys, parse_mode())
+        .with_path(input_path)?;
+    let base = open_base_nca(options.base, &keys)?;
+    extract_nca_content(&nca, base.as_ref(), input_path, options)
+}
+
+/// Opens the NCA patched by the one being extracted, if any.
+fn open_base_nca(
+    base_path: Option<&Path>,
+    keys: &linkle::pki::Keys,
+) -> Result<Option<linkle::format::nca::Nca<File>>, linkle::error::Error> {
+    match base_path {
+        Some(base_path) => {
+            let base_file = File::open(base_path).map_err(|err| (err, base_path))?;
+            let base =
+                linkle::format::nca::Nca::from_reader_with_mode(base_file, keys, parse_mode())
+                    .with_path(base_path)?;
+            Ok(Some(base))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Extracts the content of `nca`, read from `input_path`, as told by
+/// `options`, whose base NCA is already opened as `base`.
+fn extract_nca_content<R>(
+    nca: &linkle::format::nca::Nca<R>,
+    base: Option<&linkle::format::nca::Nca<File>>,
+    input_path: &Path,
+    options: &NcaExtractOptions<'_>,
+) -> Result<(), linkle::error::Error>
+where
+    R: std::io::Read + std::io::Seek + linkle::TryClone + 'static,
+{
+    use linkle::format::nca::{EncryptionType, FsType};
+    use snafu::GenerateBacktrace;
+
+    let NcaExtractOptions {
+        exefs,
+        romfs,
+        sections,
+        ..
+    } = *options;
+    // BKTR sections are read over the NCA they patch, if there is one.
+    let patch_base = |index| {
+        let section = nca.header().sections.iter().find(|v| v.index == index);
+        match (section, base) {
+            (Some(section), Some(base))
+                if section.fs_header.encryption_type == EncryptionType::AesCtrEx =>
+            {
+                Some(base)
+            }
+            _ => None,
+        }
+    };
+    let find_section = |fs_type| {
+        nca.header()
+            .sections
+            .iter()
+            .find(|v| v.fs_header.fs_type == fs_type)
+            .map(|v| v.index)
+            .ok_or_else(|| linkle::error::Error::InvalidNca {
+                error: match fs_type {
+                    FsType::PartitionFs => "there is no PFS0 section",
+                    FsType::RomFs => "there is no RomFS section",
+                },
+                backtrace: snafu::Backtrace::generate(),
+            })
+    };
+
+    if let Some(exefs) = exefs {
+        let pfs0 = nca.pfs0(find_section(FsType::PartitionFs)?)?;
+        match std::fs::create_dir(exefs) {
+            Ok(()) => (),
+            Err(ref err) if err.kind() == std::io::ErrorKind::AlreadyExists => (),
+            Err(err) => return Err((err, exefs).into()),
+        }
+        for file in pfs0.files() {
+            let mut file = file.map_err(|err| (err, input_path))?;
+            let name = entry_output_path(input_path, exefs, file.file_name())?;
+            log::info!("Writing {}", file.file_name());
+            let mut out_file = Output::create(&name)?;
+            std::io::copy(&mut file, &mut out_file).map_err(|err| (err, &name))?;
+            out_file.finish()?;
+        }
+    }
+
+    if let Some(romfs) = romfs {
+        let index = find_section(FsType::RomFs)?;
+        match patch_base(index) {
+            Some(base) => {
+                let mut reader = nca.patched_romfs(index, base)?;
+                extract_romfs_entries(&mut reader, input_path, romfs)?;
+            }
+            None => {
+                let mut reader = nca.romfs(index)?;
+                extract_romfs_entries(&mut reader, input_path, romfs)?;
+            }
+        }
+    }
+
+    if let Some(sections) = sections {
+        match std::fs::create_dir(sections) {
+            Ok(()) => (),
+            Err(ref err) if err.kind() == std::io::ErrorKind::AlreadyExists => (),
+            Err(err) => return Err((err, sections).into()),
+        }
+        for section in &nca.header().sections {
+            let name = sections.join(format!("section{}.bin", section.index));
+            log::info!("Writing section {}", section.index);
+            let mut out_file = Output::create(&name)?;
+            match patch_base(section.index) {
+                Some(base) => {
+                    let mut reader = nca.patched_section(section.index, base)?;
+                    std::io::copy(&mut reader, &mut out_file).map_err(|err| (err, &name))?;
+                }
+                None => {
+                    let mut reader = nca.section(section.index)?;
+                    std::io::copy(&mut reader, &mut out_file).map_err(|err| (err, &name))?;
+                }
+            }
+            out_file.finish()?;
+        }
+    }
+    Ok(())
+}
+
+fn print_package1_info(
+    input_path: &Path,
+    json: bool,
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    use linkle::format::package1::{Package1, Package1Section};
+
+    let keys = load_keys(is_dev, key_path)?;
+    let input_file = open_input(input_path)?;
+    let mut package1 =
+        Package1::from_reader_with_mode(input_file, parse_mode()).with_path(input_path)?;
+    let decrypted = package1.decrypt(&keys);
+
+    if json {
+        // The sections are only known once the PK11 is decrypted.
+        let mut sections = Vec::new();
+        let mut key_generation = None;
+        if decrypted.is_ok() {
+            key_generation = package1.key_generation();
+            for &section in &Package1Section::ALL {
+                sections.push(serde_json::json!({
+                    "name": section.to_string(),
+                    "size": package1.section(section)?.len(),
+                }));
+            }
+        }
+        let info = serde_json::json!({
+            "build_date": package1.build_date(),
+            "firmware": package1.era().to_string(),
+            "pk11_size": package1.pk11_size(),
+            "encrypted": decrypted.is_err(),
+            "key_generation": key_generation,
+            "sections": sections,
+        });
+        serde_json::to_writer_pretty(std::io::stdout(), &info)?;
+        println!();
+        return Ok(());
+    }
+
+    println!("Build date:     {}", package1.build_date());
+    println!("Firmware:       {}", package1.era());
+    println!("PK11 size:      {:#x}", package1.pk11_size());
+    if let Err(err) = decrypted {
+        println!("PK11:           encrypted ({})", err);
+        return Ok(());
+    }
+    match package1.key_generation() {
+        Some(generation) => println!("Package1 key:   {:02x}", generation),
+        None => println!("Package1 key:   none, PK11 is plaintext"),
+    }
+    for &section in &Package1Section::ALL {
+        println!(
+            "{:<16}{:#x}",
+            format!("{}:", section),
+            package1.section(section)?.len()
+        );
+    }
+    Ok(())
+}
+
+fn extract_package1(
+    input_path: &Path,
+    output_directory: &Path,
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    use linkle::format::package1::{Package1, Package1Section};
+
+    let keys = load_keys(is_dev, key_path)?;
+    let input_file = open_input(input_path)?;
+    let mut package1 =
+        Package1::from_reader_with_mode(input_file, parse_mode()).with_path(input_path)?;
+    package1.decrypt(&keys).with_path(input_path)?;
+
+    match std::fs::create_dir(output_directory) {
+        Ok(()) => (),
+        Err(ref err) if err.kind() == std::io::ErrorKind::AlreadyExists => (),
+        Err(err) => return Err((err, output_directory).into()),
+    }
+    let mut files = vec![("package1ldr.bin", package1.package1ldr())];
+    for &section in &Package1Section::ALL {
+        files.push((section.file_name(), package1.section(section)?));
+    }
+    for (file_name, data) in files {
+        let name = output_directory.join(file_name);
+        log::info!("Writing {}", file_name);
+        write_output(&name, data)?;
+    }
+    Ok(())
+}
+
+/// Summary of a KIP, as printed in JSON by `package2_info` and `info`.
+fn kip_info(kip: &linkle::format::ini1::Kip) -> serde_json::Value {
+    serde_json::json!({
+        "name": kip.name(),
+        "program_id": format!("{:016x}", kip.program_id()),
+        "version": kip.version(),
+    })
+}
+
+fn print_package2_info(
+    input_path: &Path,
+    json: bool,
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    use linkle::format::package2::{Package2, PACKAGE2_SECTION_COUNT};
+
+    let keys = load_keys(is_dev, key_path)?;
+    let input_file = open_input(input_path)?;
+    let package2 =
+        Package2::from_reader_with_mode(input_file, &keys, parse_mode()).with_path(input_path)?;
+
+    if json {
+        let sections = (0..PACKAGE2_SECTION_COUNT)
+            .filter(|&index| !package2.section(index).is_empty())
+            .map(|index| {
+                serde_json::json!({
+                    "index": index,
+                    "offset": package2.section_offset(index),
+                    "size": package2.section(index).len(),
+                    "hash_valid": package2.verify_section(index),
+                })
+            })
+            .collect::<Vec<_>>();
+        let info = serde_json::json!({
+            "key_generation": package2.key_generation(),
+            "header_version": package2.header_version(),
+            "version_min": package2.version_min(),
+            "version_max": package2.version_max(),
+            "size": package2.package_size(),
+            "entrypoint": package2.entrypoint(),
+            "signature": package2.verify_signature(&keys),
+            "sections": sections,
+            "embedded_ini1_offset": package2.embedded_ini1_offset(),
+            "kips": package2.ini1()?.kips.iter().map(kip_info).collect::<Vec<_>>(),
+        });
+        serde_json::to_writer_pretty(std::io::stdout(), &info)?;
+        println!();
+        return Ok(());
+    }
+
+    println!("Package2 key:    {:02x}", package2.key_generation());
+    println!("Header version:  {}", package2.header_version());
+    println!(
+        "Versions:        {}-{}",
+        package2.version_min(),
+        package2.version_max()
+    );
+    println!("Size:            {:#x}", package2.package_size());
+    println!("Entrypoint:      {:#x}", package2.entrypoint());
+    println!("Signature:       {}", package2.verify_signature(&keys));
+    for index in 0..PACKAGE2_SECTION_COUNT {
+        if package2.section(index).is_empty() {
+            continue;
+        }
+        println!(
+            "Section {}:       offset {:#x}, size {:#x}, hash {}",
+            index,
+            package2.section_offset(index),
+            package2.section(index).len(),
+            if package2.verify_section(index) {
+                "valid"
+            } else {
+                "invalid"
+            }
+        );
+    }
+    if let Some(offset) = package2.embedded_ini1_offset() {
+        println!("INI1:            in the kernel, at {:#x}", offset);
+    }
+    for kip in package2.ini1()?.kips {
+        println!(
+            "KIP {:<12} program ID {:016x}, version {}",
+            kip.name(),
+            kip.program_id(),
+            kip.version()
+        );
+    }
+    Ok(())
+}
+
+fn extract_package2(
+    input_path: &Path,
+    output_directory: &Path,
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    let keys = load_keys(is_dev, key_path)?;
+    let input_file = open_input(input_path)?;
+    let package2 =
+        linkle::format::package2::Package2::from_reader_with_mode(input_file, &keys, parse_mode())
+            .with_path(input_path)?;
+    let ini1 = package2.ini1()?;
+
+    let kips_directory = output_directory.join("ini1");
+    for directory in &[output_directory, &kips_directory] {
+        match std::fs::create_dir(directory) {
+            Ok(()) => (),
+            Err(ref err) if err.kind() == std::io::ErrorKind::AlreadyExists => (),
+            Err(err) => return Err((err, *directory).into()),
+        }
+    }
+    let mut files = vec![
+        (
+            output_directory.join("kernel.bin"),
+            package2.kernel().to_vec(),
+        ),
+        (output_directory.join("ini1.bin"), ini1.to_bytes()?),
+    ];
+    for kip in &ini1.kips {
+        files.push((
+            entry_output_path(input_path, &kips_directory, &format!("{}.kip", kip.name()))?,
+            kip.as_bytes().to_vec(),
+        ));
+    }
+    for (name, data) in files {
+        log::info!("Writing {}", name.display());
+        write_output(&name, &data)?;
+    }
+    Ok(())
+}
+
+fn repack_package2(
+    input_path: &Path,
+    output_path: &Path,
+    ini1_path: Option<&Path>,
+    kip_paths: &[PathBuf],
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    use linkle::format::ini1::{Ini1, Kip};
+
+    let keys = load_keys(is_dev, key_path)?;
+    let input_file = open_input(input_path)?;
+    let mut package2 =
+        linkle::format::package2::Package2::from_reader_with_mode(input_file, &keys, parse_mode())
+            .with_path(input_path)?;
+    let mut ini1 = match ini1_path {
+        Some(ini1_path) => {
+            let data = std::fs::read(ini1_path).map_err(|err| (err, ini1_path))?;
+            Ini1::from_bytes_with_mode(&data, parse_mode()).with_path(ini1_path)?
+        }
+        None => package2.ini1()?,
+    };
+    for kip_path in kip_paths {
+        let data = std::fs::read(kip_path).map_err(|err| (err, kip_path))?;
+        let kip = Kip::from_bytes_with_mode(data, parse_mode()).with_path(kip_path)?;
+        match ini1.set_kip(kip) {
+            Some(old) => log::info!("Replacing KIP {}", old.name()),
+            None => println!("Adding KIP {}", kip_path.display()),
+        }
+    }
+    package2.set_ini1(&ini1)?;
+
+    let mut output_file = Output::create(output_path)?;
+    package2
+        .write(&mut output_file, &keys)
+        .with_path(output_path)?;
+    output_file.finish()
+}
+
+/// Writes the first valid copy of a package, and warns if the copies differ.
+fn write_package_copy(
+    name: &str,
+    copies: Vec<(String, Result<Vec<u8>, linkle::error::Error>)>,
+    output_path: &Path,
+) -> Result<(), linkle::error::Error> {
+    let mut valid = Vec::new();
+    let mut first_err = None;
+    for (copy, data) in copies {
+        match data {
+            Ok(data) => valid.push((copy, data)),
+            Err(err) => {
+                println!("{} {} copy is invalid: {}", name, copy, err);
+                first_err.get_or_insert(err);
+            }
+        }
+    }
+    let (copy, data) = match valid.first() {
+        Some(valid) => valid,
+        None => return Err(first_err.expect("there is at least one copy")),
+    };
+    if valid.iter().any(|(_, other)| other != data) {
+        log::warn!("the copies of {} differ", name);
+    }
+    log::info!("Writing the {} copy of {}", copy, name);
+    write_output(output_path, data)
+}
+
+fn extract_boot0(input_path: &Path, output_path: &Path) -> Result<(), linkle::error::Error> {
+    use linkle::format::boot::{boot0_package1, BootCopy};
+    use linkle::format::package1::Package1;
+    use std::io::{Read, Seek, SeekFrom};
+
+    let mut copies = Vec::new();
+    for &copy in &BootCopy::ALL {
+        let input_file = open_input(input_path)?;
+        let mut reader = boot0_package1(input_file, copy).map_err(|err| (err, input_path))?;
+        let data =
+            Package1::from_reader_with_mode(&mut reader, parse_mode()).and_then(|package1| {
+                let mut data = vec![0; package1.size()];
+                reader.seek(SeekFrom::Start(0))?;
+                reader.read_exact(&mut data)?;
+                Ok(data)
+            });
+        copies.push((copy.to_string(), data.with_path(input_path)));
+    }
+    write_package_copy("Package1", copies, output_path)
+}
+
+fn extract_bcpkg2(
+    input_path: &Path,
+    sub_path: Option<&Path>,
+    output_path: &Path,
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    use linkle::format::boot::bcpkg2_package2;
+    use linkle::format::package2::Package2;
+    use std::io::{Read, Seek, SeekFrom};
+
+    let keys = load_keys(is_dev, key_path)?;
+    let mut copies = Vec::new();
+    let partitions =
+        std::iter::once(("main", input_path)).chain(sub_path.map(|path| ("sub", path)));
+    for (copy, path) in partitions {
+        let input_file = File::open(path).map_err(|err| (err, path))?;
+        let mut reader = bcpkg2_package2(input_file).map_err(|err| (err, path))?;
+        let data = Package2::from_reader_with_mode(&mut reader, &keys, parse_mode()).and_then(
+            |package2| {
+                let mut data = vec![0; package2.package_size()];
+                reader.seek(SeekFrom::Start(0))?;
+                reader.read_exact(&mut data)?;
+                Ok(data)
+            },
+        );
+        copies.push((copy.to_string(), data.with_path(path)));
+    }
+    write_package_copy("Package2", copies, output_path)
+}
+
+fn verify_keys(
+    is_dev: bool,
+    key_path: Option<&Path>,
+    hashes_path: Option<&Path>,
+    json: bool,
+) -> Result<(), linkle::error::Error> {
+    let hashes = match hashes_path {
+        Some(path) => linkle::pki::read_key_hashes(path).with_path(path)?,
+        None => Default::default(),
+    };
+    let (path, problems) = linkle::pki::verify_keyfile(key_path, is_dev, &hashes)?;
+    if json {
+        let status = serde_json::json!({
+            "keyfile": path,
+            "key_hashes": hashes.len(),
+            "problems": problems,
+        });
+        serde_json::to_writer_pretty(std::io::stdout(), &status)?;
+        println!();
+    } else {
+        println!("Keyfile: {}", path.display());
+        if hashes_path.is_some() {
+            println!("Checked against {} key hashes", hashes.len());
+        }
+        for problem in &problems {
+            println!("{}", problem);
+        }
+    }
+    if !problems.is_empty() {
+        return Err((
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("the keyfile has {} problem(s)", problems.len()),
+            ),
+            path,
+        )
+            .into());
+    }
+    if !json {
+        println!("No problem found.");
+    }
+    Ok(())
+}
+
+fn decrypt_bis(
+    partition: linkle::format::bis::BisPartition,
+    input_path: &Path,
+    output_path: &Path,
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    let keys = load_keys(is_dev, key_path)?;
+    let input_file = open_input(input_path)?;
+    let mut output_file = Output::create(output_path)?;
+    let size = linkle::format::bis::decrypt(
+        &keys,
+        partition,
+        std::io::BufReader::new(input_file),
+        std::io::BufWriter::new(&mut output_file),
+    )
+    .with_path(input_path)?;
+    output_file.finish()?;
+    log::info!("Decrypted {} ({:#x} bytes)", partition, size);
+    Ok(())
+}
+
+/// Guesses the path NAX0 keys are derived from, the one from the
+/// Nintendo/Contents or Nintendo/save directory of the SD card.
+fn guess_sd_path(input_path: &Path) -> Option<String> {
+    let components = input_path
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    let start = components.windows(2).rposition(|pair| {
+        pair[0].eq_ignore_ascii_case("Nintendo")
+            && (pair[1].eq_ignore_ascii_case("Contents") || pair[1].eq_ignore_ascii_case("save"))
+    })?;
+    let rest = &components[start + 2..];
+    if rest.is_empty() {
+        return None;
+    }
+    Some(format!("/{}", rest.join("/")))
+}
+
+fn decrypt_sd(
+    input_path: &Path,
+    output_path: &Path,
+    sd_path: Option<&str>,
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    use std::io::Read;
+
+    let sd_path = match sd_path {
+        Some(sd_path) => sd_path.to_string(),
+        None => guess_sd_path(input_path).ok_or_else(|| {
+            (
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "not in Nintendo/Contents or Nintendo/save, give its SD card path with --path",
+                ),
+                input_path,
+            )
+        })?,
+    };
+    let keys = load_keys(is_dev, key_path)?;
+
+    // Split NCAs are directories of 00, 01... parts.
+    let input: Box<dyn Read> = if input_path.is_dir() {
+        let mut parts = std::fs::read_dir(input_path)
+            .map_err(|err| (err, input_path))?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| (err, input_path))?;
+        parts.sort();
+        let mut input: Box<dyn Read> = Box::new(std::io::empty());
+        for part in parts {
+            let file = File::open(&part).map_err(|err| (err, &part))?;
+            input = Box::new(input.chain(file));
+        }
+        input
+    } else {
+        Box::new(open_input(input_path)?)
+    };
+    let mut output_file = Output::create(output_path)?;
+    let header = linkle::format::nax0::decrypt(
+        &keys,
+        &sd_path,
+        std::io::BufReader::new(input),
+        std::io::BufWriter::new(&mut output_file),
+    )
+    .with_path(input_path)?;
+    output_file.finish()?;
+    log::info!(
+        "Decrypted {} with the SD card {} key ({:#x} bytes)",
+        sd_path,
+        if header.is_save { "save" } else { "NCA" },
+        header.size
+    );
+    Ok(())
+}
+
+/// The keys a format needs.
+#[derive(Clone, Copy, PartialEq, Eq, serde_derive::Serialize)]
+#[serde(rename_all = "snake_case")]
+enum FormatKeys {
+    /// The header key, deriving from the keyset.
+    Header,
+    /// The package1 keys.
+    Package1,
+    /// The package2 keys.
+    Package2,
+    /// The BIS keys of the console.
+    Bis,
+    /// The SD card keys of the console.
+    SdCard,
+}
+
+/// A format, the keys it needs, and its operations, as in `create`, `info`
+/// or `extract`, with the commands doing them.
+type FormatEntry = (
+    &'static str,
+    Option<FormatKeys>,
+    &'static [(&'static str, &'static str)],
+);
+
+const FORMATS: &[FormatEntry] = &[
+    ("nro", None, &[("create", "nro"), ("info", "info")]),
+    ("nso", None, &[("create", "nso"), ("info", "info")]),
+    ("kip1", None, &[("create", "kip"), ("info", "info")]),
+    ("ini1", None, &[("info", "info")]),
+    ("npdm", None, &[("create", "npdm"), ("info", "info")]),
+    (
+        "nacp",
+        None,
+        &[
+            ("create", "nacp"),
+            ("create", "control"),
+            ("extract", "nacp_extract"),
+            ("canonicalize", "nacp_canonicalize"),
+            ("patch", "nacp_patch"),
+            ("diff", "nacp_diff"),
+        ],
+    ),
+    (
+        "pfs0",
+        None,
+        &[
+            ("create", "pfs0"),
+            ("extract", "pfs0_extract"),
+            ("append", "pfs0_append"),
+            ("info", "info"),
+        ],
+    ),
+    ("hfs0", None, &[("info", "info")]),
+    ("xci", None, &[("info", "info")]),
+    (
+        "romfs",
+        None,
+        &[
+            ("create", "romfs"),
+            ("list", "romfs_list"),
+            ("extract", "romfs_extract"),
+            ("replace", "romfs_replace"),
+            ("verify", "romfs_verify"),
+            ("diff", "romfs_diff"),
+            ("info", "info"),
+        ],
+    ),
+    (
+        "save",
+        None,
+        &[
+            ("list", "save_list"),
+            ("extract", "save_extract"),
+            ("info", "info"),
+        ],
+    ),
+    (
+        "nca",
+        Some(FormatKeys::Header),
+        &[
+            ("create", "nca_program"),
+            ("create", "nca_control"),
+            ("create", "nca_manual"),
+            ("create", "nca_meta"),
+            ("info", "nca_info"),
+            ("extract", "nca_extract"),
+            ("verify", "nca_verify"),
+            ("reencrypt", "nca_reencrypt"),
+            ("resign", "nca_resign"),
+        ],
+    ),
+    (
+        "cnmt",
+        Some(FormatKeys::Header),
+        &[("extract", "cnmt_extract")],
+    ),
+    (
+        "nsp",
+        Some(FormatKeys::Header),
+        &[
+            ("create", "nsp"),
+            ("create", "nsp_add_on_content"),
+            ("create", "hacbrewpack"),
+            ("convert", "nsp_convert"),
+            ("extract", "pfs0_extract"),
+            ("extract", "nsp_extract"),
+            ("info", "info"),
+        ],
+    ),
+    (
+        "ticket",
+        None,
+        &[
+            ("create", "tik"),
+            ("info", "tik_info"),
+            ("depersonalize", "tik_depersonalize"),
+        ],
+    ),
+    ("cert", None, &[("info", "cert_info")]),
+    (
+        "package1",
+        Some(FormatKeys::Package1),
+        &[
+            ("info", "package1_info"),
+            ("extract", "package1_extract"),
+            ("extract", "boot0_extract"),
+        ],
+    ),
+    (
+        "package2",
+        Some(FormatKeys::Package2),
+        &[
+            ("info", "package2_info"),
+            ("extract", "package2_extract"),
+            ("extract", "bcpkg2_extract"),
+            ("repack", "package2_repack"),
+        ],
+    ),
+    ("bis", Some(FormatKeys::Bis), &[("decrypt", "bis_decrypt")]),
+    (
+        "nax0",
+        Some(FormatKeys::SdCard),
+        &[("decrypt", "sd_decrypt")],
+    ),
+];
+
+#[derive(serde_derive::Serialize)]
+struct FormatOperation {
+    operation: &'static str,
+    command: &'static str,
+}
+
+/// A format, as printed by `formats --json`.
+#[derive(serde_derive::Serialize)]
+struct FormatSupport {
+    name: &'static str,
+    keys: Option<FormatKeys>,
+    /// Whether the keys it needs, if any, are there.
+    available: bool,
+    operations: Vec<FormatOperation>,
+}
+
+/// The keys found, as printed by `formats --json`.
+#[derive(serde_derive::Serialize)]
+struct KeySupport {
+    /// Why the keyset couldn't be loaded, if it couldn't.
+    error: Option<String>,
+    master_key_revisions: Vec<usize>,
+    titlekeys: usize,
+    certificates: usize,
+    bis_keys: bool,
+    sd_card_keys: bool,
+    eticket_rsa_key: bool,
+}
+
+fn print_formats(
+    json: bool,
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    let keys = load_keys(is_dev, key_path);
+    let has_key = |format_keys: FormatKeys| {
+        let keys = match &keys {
+            Ok(keys) => keys,
+            Err(_) => return false,
+        };
+        match format_keys {
+            FormatKeys::Header => keys.header_key().is_ok(),
+            FormatKeys::Package1 => (0..0x20).any(|rev| keys.package1_key(rev).is_ok()),
+            FormatKeys::Package2 => (0..0x20).any(|rev| keys.package2_key(rev).is_ok()),
+            FormatKeys::Bis => keys.bis_key(0).is_ok(),
+            FormatKeys::SdCard => keys.sd_card_nca_key().is_ok(),
+        }
+    };
+    let formats: Vec<FormatSupport> = FORMATS
+        .iter()
+        .map(|&(name, format_keys, operations)| FormatSupport {
+            name,
+            keys: format_keys,
+            available: match format_keys {
+                Some(format_keys) => has_key(format_keys),
+                None => true,
+            },
+            operations: operations
+                .iter()
+                .map(|&(operation, command)| FormatOperation { operation, command })
+                .collect(),
+        })
+        .collect();
+    let key_support = match &keys {
+        Ok(keys) => KeySupport {
+            error: None,
+            master_key_revisions: (0..0x20)
+                .filter(|&rev| keys.master_key(rev).is_ok())
+                .collect(),
+            titlekeys: keys.titlekey_count(),
+            certificates: keys.certs().certs.len(),
+            bis_keys: keys.bis_key(0).is_ok(),
+            sd_card_keys: keys.sd_card_nca_key().is_ok(),
+            eticket_rsa_key: keys.eticket_rsa_key().is_ok(),
+        },
+        Err(err) => KeySupport {
+            error: Some(err.to_string()),
+            master_key_revisions: Vec::new(),
+            titlekeys: 0,
+            certificates: 0,
+            bis_keys: false,
+            sd_card_keys: false,
+            eticket_rsa_key: false,
+        },
+    };
+
+    if json {
+        let support = serde_json::json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "features": { "crypto": cfg!(feature = "crypto") },
+            "formats": formats,
+            "keys": key_support,
+        });
+        serde_json::to_writer_pretty(std::io::stdout(), &support)?;
+        println!();
+        return Ok(());
+    }
+
+    println!("linkle {}", env!("CARGO_PKG_VERSION"));
+    for format in &formats {
+        let mut operations: Vec<&str> = Vec::new();
+        for operation in &format.operations {
+            if !operations.contains(&operation.operation) {
+                operations.push(operation.operation);
+            }
+        }
+        let missing = if format.available {
+            ""
+        } else {
+            " (missing keys)"
+        };
+        println!("{:<10}{}{}", format.name, operations.join(", "), missing);
+    }
+    match &key_support.error {
+        Some(err) => println!("Keys: {}", err),
+        None => {
+            let revisions = &key_support.master_key_revisions;
+            let master_keys = if revisions.is_empty() {
+                "none".to_string()
+            } else {
+                let revisions: Vec<String> =
+                    revisions.iter().map(|rev| format!("{:02x}", rev)).collect();
+                revisions.join(" ")
+            };
+            println!(
+                "Keys: master keys {}, {} titlekey(s), {} certificate(s)",
+                master_keys, key_support.titlekeys, key_support.certificates
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Prints the headers of the file at `input_path` as stored in it, as JSON.
+fn print_raw_headers(input_path: &Path) -> Result<(), linkle::error::Error> {
+    use linkle::format::detect::FileFormat;
+    use snafu::GenerateBacktrace;
+    use std::io::Read;
+
+    let mut data = Vec::new();
+    open_input(input_path)?
+        .read_to_end(&mut data)
+        .map_err(|err| (err, input_path))?;
+    let format = FileFormat::detect(&data);
+    let headers = match format {
+        Some(FileFormat::Npdm) => {
+            let headers = linkle::format::npdm::NpdmHeaders::parse_with_mode(&data, parse_mode());
+            serde_json::to_value(headers.with_path(input_path)?)?
+        }
+        Some(FileFormat::Nso) => {
+            let header = linkle::format::nxo::NsoHeader::parse(&data);
+            serde_json::to_value(header.with_path(input_path)?)?
+        }
+        Some(FileFormat::Nro) => {
+            let header = linkle::format::nxo::NroHeader::parse(&data);
+            serde_json::to_value(header.with_path(input_path)?)?
+        }
+        Some(FileFormat::Pfs0) | Some(FileFormat::Hfs0) => {
+            let mut reader = std::io::Cursor::new(&data);
+            let entries = linkle::format::pfs0::read_entries(&mut reader, parse_mode());
+            serde_json::to_value(entries.with_path(input_path)?)?
+        }
+        _ => {
+            return Err(linkle::error::Error::InvalidArgument {
+                argument: "--raw".to_string(),
+                expected: "an NPDM, NSO, NRO, PFS0 or HFS0 file",
+                backtrace: snafu::Backtrace::generate(),
+            })
+        }
+    };
+    let info = serde_json::json!({ "format": format, "headers": headers });
+    serde_json::to_writer_pretty(std::io::stdout(), &info)?;
+    println!();
+    Ok(())
+}
+
+fn print_info(
+    input_path: &Path,
+    json: bool,
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    use linkle::format::container::SwitchFormat;
+    use linkle::format::detect::{FileFormat, DETECT_SIZE};
+    use linkle::format::nxo::NxoHeader;
+    use serde_json::Value;
+    use snafu::GenerateBacktrace;
+    use std::io::{Read, Seek, SeekFrom};
+
+    let mut file = open_input(input_path)?;
+    let mut header = Vec::with_capacity(DETECT_SIZE);
+    (&mut file)
+        .take(DETECT_SIZE as u64)
+        .read_to_end(&mut header)
+        .map_err(|err| (err, input_path))?;
+    let format = match FileFormat::detect(&header) {
+        Some(format) => format,
+        None => {
+            // NCA headers are encrypted, so only the keys tell them apart.
+            let header = match load_keys(is_dev, key_path) {
+                Ok(keys) => linkle::format::nca::read_header(&mut file, &keys).ok(),
+                Err(_) => None,
+            };
+            let header = header.ok_or_else(|| linkle::error::Error::UnknownFormat {
+                path: input_path.to_path_buf(),
+                backtrace: snafu::Backtrace::generate(),
+            })?;
+            if json {
+                let info = serde_json::json!({ "format": "nca", "info": header });
+                serde_json::to_writer_pretty(std::io::stdout(), &info)?;
+                println!();
+                return Ok(());
+            }
+            println!("Format: NCA");
+            return print_nca_info(input_path, false, is_dev, key_path);
+        }
+    };
+    if !json {
+        println!("Format: {}", format);
+    }
+
+    file.seek(SeekFrom::Start(0))
+        .map_err(|err| (err, input_path))?;
+    let read_all = |mut file: File| -> Result<Vec<u8>, linkle::error::Error> {
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)
+            .map_err(|err| (err, input_path))?;
+        Ok(data)
+    };
+    // The summary printed in JSON mode, the text is printed along the way.
+    let info = match format {
+        FileFormat::Npdm => {
+            let npdm =
+                linkle::format::npdm::NpdmInfo::parse_with_mode(&read_all(file)?, parse_mode())
+                    .with_path(input_path)?;
+            if !json {
+                println!("Name:       {}", npdm.name);
+                println!("Program ID: {:016x}", npdm.program_id);
+            }
+            serde_json::to_value(&npdm)?
+        }
+        FileFormat::Nso | FileFormat::Nro => {
+            let data = read_all(file)?;
+            let header = if format == FileFormat::Nso {
+                NxoHeader::parse_nso_with_mode(&data, parse_mode())
+            } else {
+                NxoHeader::parse_nro_with_mode(&data, parse_mode())
+            }
+            .with_path(input_path)?;
+            if !json {
+                println!("Build ID:   {}", hex(&header.build_id));
+                for (name, segment) in ["text", "rodata", "data"].iter().zip(&header.segments) {
+                    println!(
+                        "{:<11} {:#010x} ({:#x} bytes, {:#x} in the file at {:#x})",
+                        format!("{}:", name),
+                        segment.memory_offset,
+                        segment.size,
+                        segment.file_size,
+                        segment.file_offset
+                    );
+                }
+                println!("bss:        {:#x} bytes", header.bss_size);
+            }
+            serde_json::to_value(&header)?
+        }
+        FileFormat::Kip => {
+            let kip =
+                linkle::format::ini1::Kip::from_bytes_with_mode(read_all(file)?, parse_mode())
+                    .with_path(input_path)?;
+            if !json {
+                println!("Name:       {}", kip.name());
+                println!("Program ID: {:016x}", kip.program_id());
+                println!("Version:    {}", kip.version());
+            }
+            kip_info(&kip)
+        }
+        FileFormat::Ini1 => {
+            let ini1 =
+                linkle::format::ini1::Ini1::from_bytes_with_mode(&read_all(file)?, parse_mode())
+                    .with_path(input_path)?;
+            if !json {
+                for kip in &ini1.kips {
+                    println!(
+                        "{:<12} {:016x} (version {})",
+                        kip.name(),
+                        kip.program_id(),
+                        kip.version()
+                    );
+                }
+            }
+            Value::from(ini1.kips.iter().map(kip_info).collect::<Vec<_>>())
+        }
+        FileFormat::Pfs0 | FileFormat::Hfs0 => {
+            let pfs0 = linkle::format::pfs0::Pfs0::from_reader_with_mode(file, parse_mode())
+                .with_path(input_path)?;
+            let mut files = pfs0.list_entries().with_path(input_path)?;
+            files.sort_by(|a, b| a.name.cmp(&b.name));
+            if !json {
+                for file in &files {
+                    println!("{} (size: {:#x})", file.name, file.size);
+                }
+            }
+            serde_json::to_value(&files)?
+        }
+        FileFormat::Xci => {
+            let xci = linkle::format::xci::Xci::from_reader_with_mode(file, parse_mode())
+                .with_path(input_path)?;
+            let files = xci.list_entries().with_path(input_path)?;
+            if !json {
+                println!("Package ID: {:016x}", xci.header().package_id);
+                for file in &files {
+                    println!("{} (size: {:#x})", file.name, file.size);
+                }
+            }
+            let mut info = xci.metadata()?;
+            info["files"] = serde_json::to_value(&files)?;
+            info
+        }
+        FileFormat::RomFs if !json => {
+            list_romfs(input_path, Some(1), None, false)?;
+            Value::Null
+        }
+        FileFormat::RomFs => {
+            let romfs =
+                linkle::format::romfs::RomFsReader::from_reader_with_mode(file, parse_mode())
+                    .with_path(input_path)?;
+            let entries = romfs
+                .entries()?
+                .into_iter()
+                .filter(|entry| entry.depth() <= 1)
+                .collect::<Vec<_>>();
+            serde_json::to_value(&entries)?
+        }
+        FileFormat::Save if !json => {
+            list_save(input_path, false)?;
+            Value::Null
+        }
+        FileFormat::Save => {
+            let save = linkle::format::save::SaveFile::from_reader_with_mode(file, parse_mode())
+                .with_path(input_path)?;
+            serde_json::to_value(&save.entries()?)?
+        }
+        FileFormat::Nax0 => {
+            if !json {
+                println!("Decrypt it with sd_decrypt to see its content.");
+            }
+            Value::Null
+        }
+        FileFormat::Ticket if !json => {
+            print_ticket_info(input_path, false, is_dev, key_path)?;
+            Value::Null
+        }
+        FileFormat::Ticket => {
+            let keys = load_keys(is_dev, key_path)?;
+            let ticket = linkle::format::tik::Ticket::from_reader_with_mode(file, parse_mode())
+                .with_path(input_path)?;
+            serde_json::to_value(TicketInfo::new(&ticket, &keys))?
+        }
+        FileFormat::CertChain if !json => {
+            print_cert_info(input_path, false)?;
+            Value::Null
+        }
+        FileFormat::CertChain => {
+            let chain = linkle::format::cert::CertChain::from_reader_with_mode(file, parse_mode())
+                .with_path(input_path)?;
+            serde_json::to_value(&chain)?
+        }
+    };
+    if json {
+        let info = serde_json::json!({ "format": format, "info": info });
+        serde_json::to_writer_pretty(std::io::stdout(), &info)?;
+        println!();
+    }
+    Ok(())
+}
+
+fn print_keys(
+    is_dev: bool,
+    key_path: Option<&Path>,
+    console_unique: bool,
+    minimal: bool,
+) -> Result<(), linkle::error::Error> {
+    let keys = load_keys(is_dev, key_path)?;
+
+    keys.write(&mut std::io::stdout(), console_unique, minimal)
+        .unwrap();
+    Ok(())
+}
+
+/// A build pipeline, as declared in a linkle.toml. Paths are relative to the
+/// directory of the file.
+#[derive(serde_derive::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Pipeline {
+    elf: Option<PathBuf>,
+    npdm: Option<PathBuf>,
+    nacp: Option<PathBuf>,
+    icon: Option<PathBuf>,
+    #[serde(default)]
+    reencode_icon: bool,
+    romfs: Option<PathBuf>,
+    /// Commands run before building the outputs, e.g. the one building the
+    /// ELF.
+    #[serde(default)]
+    run: Vec<PipelineCommand>,
+    nso: Option<PipelineOutput>,
+    nro: Option<PipelineOutput>,
+    nsp: Option<PipelineNsp>,
+}
+
+#[derive(serde_derive::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PipelineCommand {
+    /// The program and its arguments, run from the directory of the file.
+    command: Vec<String>,
+    /// Files and directories the command reads. With its outputs, they
+    /// order it before the steps using them and skip it when it is up to date.
+    #[serde(default)]
+    inputs: Vec<PathBuf>,
+    #[serde(default)]
+    outputs: Vec<PathBuf>,
+}
+
+#[derive(serde_derive::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PipelineOutput {
+    output: PathBuf,
+}
+
+/// The options of the `nsp` command.
+#[derive(serde_derive::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PipelineNsp {
+    output: PathBuf,
+    #[serde(default)]
+    title_version: u32,
+    #[serde(default)]
+    key_generation: u8,
+    #[serde(default)]
+    compress: bool,
+    signing_key: Option<PathBuf>,
+    acid_signing_key: Option<PathBuf>,
+    #[serde(default)]
+    titlekey_crypto: bool,
+    cert: Option<PathBuf>,
+}
+
+enum PipelineAction<'a> {
+    Run(&'a [String]),
+    Nso(&'a Path),
+    Nro(&'a Path),
+    Nsp(&'a PipelineNsp),
+}
+
+struct PipelineStep<'a> {
+    action: PipelineAction<'a>,
+    inputs: Vec<PathBuf>,
+    outputs: Vec<PathBuf>,
+}
+
+impl Pipeline {
+    fn resolve_paths(&mut self, base: &Path) {
+        let paths = vec![
+            &mut self.elf,
+            &mut self.npdm,
+            &mut self.nacp,
+            &mut self.icon,
+            &mut self.romfs,
+        ]
+        .into_iter()
+        .flatten()
+        .chain(
+            self.run
+                .iter_mut()
+                .flat_map(|command| command.inputs.iter_mut().chain(command.outputs.iter_mut())),
+        )
+        .chain(self.nso.iter_mut().map(|nso| &mut nso.output))
+        .chain(self.nro.iter_mut().map(|nro| &mut nro.output))
+        .chain(self.nsp.iter_mut().flat_map(|nsp| {
+            Some(&mut nsp.output)
+                .into_iter()
+                .chain(nsp.signing_key.as_mut())
+                .chain(nsp.acid_signing_key.as_mut())
+                .chain(nsp.cert.as_mut())
+        }));
+        for path in paths {
+            *path = base.join(&*path);
+        }
+    }
+
+    /// The steps of the pipeline, with what they read and write.
+    fn steps<'a>(&'a self, config_path: &Path) -> Result<Vec<PipelineStep<'a>>, String> {
+        let mut steps = Vec::new();
+        for command in &self.run {
+            if command.command.is_empty() {
+                return Err("a run command is empty".to_string());
+            }
+            steps.push(PipelineStep {
+                action: PipelineAction::Run(&command.command),
+                inputs: command.inputs.clone(),
+                outputs: command.outputs.clone(),
+            });
+        }
+        // Changing the pipeline rebuilds the outputs.
+        let mut inputs = vec![config_path.to_path_buf()];
+        if self.nso.is_some() || self.nro.is_some() || self.nsp.is_some() {
+            match &self.elf {
+                Some(elf) => inputs.push(elf.clone()),
+                None => return Err("building an NSO, NRO or NSP needs an elf".to_string()),
+            }
+        }
+        if let Some(nso) = &self.nso {
+            steps.push(PipelineStep {
+                action: PipelineAction::Nso(&nso.output),
+                inputs: inputs.clone(),
+                outputs: vec![nso.output.clone()],
+            });
+        }
+        inputs.extend(
+            self.icon
+                .iter()
+                .chain(&self.romfs)
+                .chain(&self.nacp)
+                .cloned(),
+        );
+        if let Some(nro) = &self.nro {
+            steps.push(PipelineStep {
+                action: PipelineAction::Nro(&nro.output),
+                inputs: inputs.clone(),
+                outputs: vec![nro.output.clone()],
+            });
+        }
+        if let Some(nsp) = &self.nsp {
+            if self.nacp.is_none() {
+                return Err("building an NSP needs a nacp".to_string());
+            }
+            let keys = nsp.signing_key.iter().chain(&nsp.acid_signing_key);
+            inputs.extend(self.npdm.iter().chain(keys).chain(&nsp.cert).cloned());
+            steps.push(PipelineStep {
+                action: PipelineAction::Nsp(nsp),
+                inputs,
+                outputs: vec![nsp.output.clone()],
+            });
+        }
+        Ok(steps)
+    }
+}
+
+/// Orders `steps` so that the ones writing a file run before the ones reading
+/// it, keeping the declaration order otherwise.
+fn order_steps<'a>(mut steps: Vec<PipelineStep<'a>>) -> Result<Vec<PipelineStep<'a>>, String> {
+    let overlaps = |a: &Path, b: &Path| a.starts_with(b) || b.starts_with(a);
+    let mut ordered = Vec::new();
+    while !steps.is_empty() {
+        let ready = (0..steps.len()).find(|&i| {
+            !steps.iter().enumerate().any(|(j, other)| {
+                j != i
+                    && steps[i]
+                        .inputs
+                        .iter()
+                        .any(|input| other.outputs.iter().any(|output| overlaps(input, output)))
+            })
+        });
+        match ready {
+            Some(i) => ordered.push(steps.remove(i)),
+            None => return Err("the steps depend on each other in a cycle".to_string()),
+        }
+    }
+    Ok(ordered)
+}
+
+fn newest_modification(path: &Path) -> Option<std::time::SystemTime> {
+    let mut newest = None;
+    for entry in ignore::WalkBuilder::new(path)
+        .standard_filters(false)
+        .follow_links(true)
+        .build()
+    {
+        let modified = entry.ok()?.metadata().ok()?.modified().ok()?;
+        newest = newest.max(Some(modified));
+    }
+    newest
+}
+
+/// Returns true if every output of `step` exists and is newer than all of its
+/// inputs. Steps without inputs always run.
+fn is_up_to_date(step: &PipelineStep) -> bool {
+    let newest_input = step
+        .inputs
+        .iter()
+        .map(|input| newest_modification(input))
+        .collect::<Option<Vec<_>>>()
+        .and_then(|times| times.into_iter().max());
+    let oldest_output = step
+        .outputs
+        .iter()
+        .map(|output| std::fs::metadata(output).and_then(|v| v.modified()).ok())
+        .collect::<Option<Vec<_>>>()
+        .and_then(|times| times.into_iter().min());
+    match (newest_input, oldest_output) {
+        (Some(input), Some(output)) => output >= input,
+        _ => false,
+    }
+}
+
+/// Runs what the hactool command line `opt` would, with the linkle command
+/// doing the same thing for each file type.
+fn run_hactool(opt: &HactoolOpt) -> Result<(), linkle::error::Error> {
+    use snafu::GenerateBacktrace;
+
+    let input = opt.input_file.as_path();
+    let key_path = to_opt_ref(&opt.keyfile);
+    let default_action = !opt.info && !opt.extract && !opt.verify;
+    let info = opt.info || default_action;
+    let extract = opt.extract || default_action;
+    let outdir = opt.outdir.as_deref().filter(|_| extract);
+    let unsupported = |expected| {
+        Err(linkle::error::Error::InvalidArgument {
+            argument: format!("--intype {}", opt.intype),
+            expected,
+            backtrace: snafu::Backtrace::generate(),
+        })
+    };
+
+    match opt.intype.as_str() {
+        "nca" => {
+            if info {
+                print_nca_info(input, false, opt.dev, key_path)?;
+            }
+            if opt.verify {
+                verify_nca(
+                    input,
+                    to_opt_ref(&opt.basenca),
+                    &[],
+                    false,
+                    opt.dev,
+                    key_path,
+                )?;
+            }
+            if extract && (opt.exefsdir.is_some() || opt.romfsdir.is_some()) {
+                let options = NcaExtractOptions {
+                    exefs: to_opt_ref(&opt.exefsdir),
+                    romfs: to_opt_ref(&opt.romfsdir),
+                    sections: None,
+                    base: to_opt_ref(&opt.basenca),
+                };
+                extract_nca(input, &options, &[], opt.dev, key_path)?;
+            }
+            return Ok(());
+        }
+        "npdm" | "nso" | "kip1" | "ini1" | "hfs0" if outdir.is_none() => {}
+        "npdm" | "nso" | "kip1" | "ini1" | "hfs0" => {
+            return unsupported("a type linkle can extract with --outdir")
+        }
+        "pfs0" | "exefs" | "nsp" | "romfs" | "save" | "package1" | "pk11" | "package2" | "pk21" => {
+        }
+        _ => return unsupported("a hactool type linkle supports"),
+    }
+
+    if info {
+        match opt.intype.as_str() {
+            "package1" | "pk11" => print_package1_info(input, false, opt.dev, key_path)?,
+            "package2" | "pk21" => print_package2_info(input, false, opt.dev, key_path)?,
+            _ => print_info(input, false, opt.dev, key_path)?,
+        }
+    }
+    if let Some(outdir) = outdir {
+        match opt.intype.as_str() {
+            "pfs0" | "exefs" | "nsp" => {
+                extract_pfs0(&input.to_string_lossy(), &outdir.to_string_lossy())?
+            }
+            "romfs" => extract_romfs(input, outdir, None)?,
+            "save" => extract_save(input, outdir)?,
+            "package1" | "pk11" => extract_package1(input, outdir, opt.dev, key_path)?,
+            _ => extract_package2(input, outdir, opt.dev, key_path)?,
+        }
+    }
+    if extract && opt.intype == "nsp" && (opt.exefsdir.is_some() || opt.romfsdir.is_some()) {
+        let options = NcaExtractOptions {
+            exefs: to_opt_ref(&opt.exefsdir),
+            romfs: to_opt_ref(&opt.romfsdir),
+            sections: None,
+            base: to_opt_ref(&opt.basenca),
+        };
+        extract_nsp(input, &options, opt.dev, key_path)?;
+    }
+    Ok(())
+}
+
+fn run_pipeline(
+    config_path: &Path,
+    force: bool,
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    use snafu::GenerateBacktrace;
+
+    let invalid = |error| linkle::error::Error::InvalidPipeline {
+        path: config_path.to_path_buf(),
+        error,
+        backtrace: snafu::Backtrace::generate(),
+    };
+    let data = std::fs::read_to_string(config_path).map_err(|err| (err, config_path))?;
+    let mut pipeline: Pipeline = toml::from_str(&data).map_err(|err| invalid(err.to_string()))?;
+    let base = match config_path.parent() {
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new("."),
+    };
+    pipeline.resolve_paths(base);
+    let steps = pipeline
+        .steps(config_path)
+        .and_then(order_steps)
+        .map_err(invalid)?;
+
+    let elf = pipeline.elf.as_ref().map(|elf| elf.to_string_lossy());
+    let nacp = pipeline.nacp.as_ref().map(|nacp| nacp.to_string_lossy());
+    for step in steps {
+        let name = match step.action {
+            PipelineAction::Run(command) => command.join(" "),
+            _ => step.outputs[0].display().to_string(),
+        };
+        if !force && is_up_to_date(&step) {
+            log::info!("{} is up to date", name);
+            if !matches!(step.action, PipelineAction::Run(_)) {
+                queue_checksums(&step.outputs);
+            }
+            continue;
+        }
+        for output in &step.outputs {
+            if let Some(parent) = output.parent() {
+                std::fs::create_dir_all(parent).map_err(|err| (err, parent))?;
+            }
+        }
+        match step.action {
+            PipelineAction::Run(command) => {
+                log::info!("Running {}", name);
+                let status = process::Command::new(&command[0])
+                    .args(&command[1..])
+                    .current_dir(base)
+                    .status()
+                    .map_err(|err| (err, &command[0]))?;
+                if !status.success() {
+                    return Err(linkle::error::Error::CommandFailed {
+                        command: name,
+                        status,
+                        backtrace: snafu::Backtrace::generate(),
+                    });
+                }
+            }
+            PipelineAction::Nso(output) => {
+                log::info!("Building {}", name);
+                let elf = elf.as_deref().expect("checked by Pipeline::steps");
+                create_nxo(
+                    "nso",
+                    elf,
+                    &output.to_string_lossy(),
+                    None,
+                    false,
+                    None,
+                    None,
+                )?;
+            }
+            PipelineAction::Nro(output) => {
+                log::info!("Building {}", name);
+                let elf = elf.as_deref().expect("checked by Pipeline::steps");
+                let icon = pipeline.icon.as_ref().map(|v| v.to_string_lossy());
+                let romfs = pipeline.romfs.as_ref().map(|v| v.to_string_lossy());
+                create_nxo(
+                    "nro",
+                    elf,
+                    &output.to_string_lossy(),
+                    icon.as_deref(),
+                    pipeline.reencode_icon,
+                    romfs.as_deref(),
+                    nacp.as_deref(),
+                )?;
+            }
+            PipelineAction::Nsp(nsp) => {
+                log::info!("Building {}", name);
+                create_nsp(
+                    &nsp.output,
+                    elf.as_deref(),
+                    None,
+                    to_opt_ref(&pipeline.npdm),
+                    nacp.as_deref().expect("checked by Pipeline::steps"),
+                    to_opt_ref(&pipeline.icon),
+                    to_opt_ref(&pipeline.romfs),
+                    None,
+                    None,
+                    None,
+                    None,
+                    &[],
+                    nsp.title_version,
+                    nsp.key_generation,
+                    nsp.compress,
+                    to_opt_ref(&nsp.signing_key),
+                    to_opt_ref(&nsp.acid_signing_key),
+                    nsp.titlekey_crypto,
+                    to_opt_ref(&nsp.cert),
+                    is_dev,
+                    key_path,
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn to_opt_ref<U: ?Sized, T: AsRef<U>>(s: &Option<T>) -> Option<&U> {
+    s.as_ref().map(AsRef::as_ref)
+}
+
+thread_local! {
+    /// Copy of stdin, read by `open_input`.
+    static STDIN: std::cell::RefCell<Option<File>> = std::cell::RefCell::new(None);
+}
+
+/// Opens `path` for reading, or stdin if it is "-". Stdin is first copied
+/// to a temporary file, as most formats need to seek, and can be opened
+/// again afterwards.
+fn open_input<P: AsRef<Path>>(path: P) -> Result<File, linkle::error::Error> {
+    use std::io::{Seek, SeekFrom};
+
+    let path = path.as_ref();
+    if path != Path::new("-") {
+        return File::open(path).map_err(|err| (err, path).into());
+    }
+    STDIN.with(|stdin_copy| {
+        let mut stdin_copy = stdin_copy.borrow_mut();
+        let file = match &*stdin_copy {
+            Some(file) => file.try_clone(),
+            None => tempfile::tempfile().and_then(|mut file| {
+                let stdin = std::io::stdin();
+                std::io::copy(&mut stdin.lock(), &mut file)?;
+                Ok(file)
+            }),
+        }
+        .and_then(|mut file| file.seek(SeekFrom::Start(0)).map(|_| file))
+        .map_err(|err| (err, path))?;
+        if stdin_copy.is_none() {
+            *stdin_copy = Some(file.try_clone().map_err(|err| (err, path))?);
+        }
+        Ok(file)
+    })
+}
+
+/// The files built by the command, whose checksums `--checksums` and
+/// `--checksum-manifest` ask for.
+#[derive(Default)]
+struct Checksums {
+    sidecars: bool,
+    manifest: Option<PathBuf>,
+    /// Files built since the checksums were last written.
+    queued: Vec<PathBuf>,
+    /// Checksums of the manifest, by path relative to it.
+    entries: std::collections::BTreeMap<PathBuf, String>,
+}
+
+lazy_static::lazy_static! {
+    static ref CHECKSUMS: std::sync::Mutex<Checksums> = Default::default();
+}
+
+/// Queues the checksums of files that were built, or are up to date. They
+/// are only written once the command succeeds, so that intermediate files
+/// built in a temporary directory are gone by then and left out.
+fn queue_checksums<P: AsRef<Path>>(paths: &[P]) {
+    let mut checksums = CHECKSUMS.lock().unwrap();
+    if checksums.sidecars {
+        checksums
+            .queued
+            .extend(paths.iter().map(|path| path.as_ref().to_path_buf()));
+    }
+}
+
+/// Writes the `.sha256` files of the queued files that still exist, and the
+/// manifest with them.
+fn write_checksums() -> Result<(), linkle::error::Error> {
+    let mut checksums = CHECKSUMS.lock().unwrap();
+    let queued = std::mem::take(&mut checksums.queued);
+    let mut written = std::collections::HashSet::new();
+    for path in queued {
+        if !path.is_file() || !written.insert(path.clone()) {
+            continue;
+        }
+        let hash = hash_file(&path).map_err(|err| (err, &path))?.to_lowercase();
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let mut sidecar = path.clone().into_os_string();
+        sidecar.push(".sha256");
+        let sidecar = PathBuf::from(sidecar);
+        std::fs::write(&sidecar, format!("{}  {}\n", hash, file_name))
+            .map_err(|err| (err, &sidecar))?;
+        log::debug!("Wrote {}", sidecar.display());
+        if let Some(manifest) = &checksums.manifest {
+            let base = manifest.parent().unwrap_or_else(|| Path::new(""));
+            let name = path.strip_prefix(base).unwrap_or(&path).to_path_buf();
+            checksums.entries.insert(name, hash);
+        }
+    }
+    if let Some(manifest) = &checksums.manifest {
+        if !written.is_empty() {
+            let data: String = checksums
+                .entries
+                .iter()
+                .map(|(path, hash)| format!("{}  {}\n", hash, path.display()))
+                .collect();
+            std::fs::write(manifest, data).map_err(|err| (err, manifest))?;
+            log::info!("Wrote the checksums to {}", manifest.display());
+        }
+    }
+    Ok(())
+}
+
+/// Set by `--no-clobber`: outputs can't replace existing files.
+static NO_CLOBBER: AtomicBool = AtomicBool::new(false);
+/// Set by `--force`, overriding `--no-clobber`.
+static FORCE: AtomicBool = AtomicBool::new(false);
+/// Set by `--prompt`: missing descriptor fields are asked for.
+static PROMPT: AtomicBool = AtomicBool::new(false);
+/// Set by `--strict`: inputs are parsed in strict mode.
+static STRICT: AtomicBool = AtomicBool::new(false);
+
+fn parse_mode() -> linkle::format::parse::ParseMode {
+    if STRICT.load(Ordering::Relaxed) {
+        linkle::format::parse::ParseMode::Strict
+    } else {
+        linkle::format::parse::ParseMode::Lenient
+    }
+}
+
+/// A file being written, or stdout if its path is "-". Files are written to
+/// a temporary file next to them, only renamed to their path once `finish`
+/// is called, so that failed or interrupted builds don't leave truncated
+/// outputs behind. Stdout only gets the data once `finish` is called too, as
+/// most formats need to seek while being written.
+struct Output {
+    file: File,
+    path: PathBuf,
+    /// The temporary file, removed if the output is never finished.
+    temp_path: Option<PathBuf>,
+}
+
+impl Output {
+    fn create<P: AsRef<Path>>(path: P) -> Result<Output, linkle::error::Error> {
+        let path = path.as_ref();
+        if path == Path::new("-") {
+            return Ok(Output {
+                file: tempfile::tempfile().map_err(|err| (err, path))?,
+                path: path.to_path_buf(),
+                temp_path: None,
+            });
+        }
+        if NO_CLOBBER.load(Ordering::Relaxed) && !FORCE.load(Ordering::Relaxed) && path.exists() {
+            let err = std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                "file exists, use --force to overwrite it",
+            );
+            return Err((err, path).into());
+        }
+        // Created by hand rather than with tempfile, whose files are only
+        // readable by their owner.
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let temp_path =
+            path.with_file_name(format!(".{}.{:08x}.tmp", file_name, rand::random::<u32>()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)
+            .map_err(|err| (err, path))?;
+        Ok(Output {
+            file,
+            path: path.to_path_buf(),
+            temp_path: Some(temp_path),
+        })
+    }
+
+    fn finish(mut self) -> Result<(), linkle::error::Error> {
+        use std::io::{Seek, SeekFrom};
+
+        match self.temp_path.take() {
+            Some(temp_path) => {
+                // Synced first, so that a crash can't leave the final name
+                // pointing to a file whose data was never written.
+                let renamed = self
+                    .file
+                    .sync_all()
+                    .and_then(|()| std::fs::rename(&temp_path, &self.path));
+                if let Err(err) = renamed {
+                    let _ = std::fs::remove_file(&temp_path);
+                    return Err((err, &self.path).into());
+                }
+                queue_checksums(&[&self.path]);
+            }
+            None => {
+                let stdout = std::io::stdout();
+                self.file
+                    .seek(SeekFrom::Start(0))
+                    .and_then(|_| std::io::copy(&mut self.file, &mut stdout.lock()))
+                    .map_err(|err| (err, "-"))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes `data` to `path` through an `Output`.
+fn write_output<P: AsRef<Path>>(path: P, data: &[u8]) -> Result<(), linkle::error::Error> {
+    use std::io::Write;
+
+    let path = path.as_ref();
+    let mut output = Output::create(path)?;
+    output.write_all(data).map_err(|err| (err, path))?;
+    output.finish()
+}
+
+impl Drop for Output {
+    fn drop(&mut self) {
+        if let Some(temp_path) = &self.temp_path {
+            let _ = std::fs::remove_file(temp_path);
+        }
+    }
+}
+
+impl std::io::Read for Output {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl std::io::Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl std::io::Seek for Output {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+fn process_args(app: &Opt) -> Result<(), linkle::error::Error> {
+    match app {
+        Opt::Nro {
+            ref input_files,
+            ref output_file,
+            jobs,
+            watch,
+            ref icon,
+            reencode_icon,
+            ref romfs,
+            ref nacp,
+        } => {
+            let build = || {
+                let (icon, romfs, nacp) = (icon.clone(), romfs.clone(), nacp.clone());
+                let reencode_icon = *reencode_icon;
+                convert_files(
+                    input_files,
+                    output_file,
+                    "nro",
+                    *jobs,
+                    move |input, output| {
+                        create_nxo(
+                            "nro",
+                            input,
+                            output,
+                            to_opt_ref(&icon),
+                            reencode_icon,
+                            to_opt_ref(&romfs),
+                            to_opt_ref(&nacp),
+                        )
+                    },
+                )
+            };
+            if *watch {
+                let inputs = || {
+                    let mut inputs = watched_files(input_files);
+                    let extra = icon.iter().chain(romfs).chain(nacp);
+                    inputs.extend(extra.map(PathBuf::from));
+                    inputs
+                };
+                watch_inputs(inputs, Some(Path::new(output_file)), build)
+            } else {
+                build()
+            }
+        }
+        Opt::Nso {
+            ref input_files,
+            ref output_file,
+            jobs,
+            watch,
+        } => {
+            let build = || {
+                convert_files(input_files, output_file, "nso", *jobs, |input, output| {
+                    create_nxo("nso", input, output, None, false, None, None)
+                })
+            };
+            if *watch {
+                let inputs = || watched_files(input_files);
+                watch_inputs(inputs, Some(Path::new(output_file)), build)
+            } else {
+                build()
+            }
+        }
+        Opt::Kip {
+            ref input_file,
+            ref npdm_file,
+            ref output_file,
+            jobs,
+        } => {
+            let npdm_file = npdm_file.clone();
+            convert_files(
+                std::slice::from_ref(input_file),
+                output_file,
+                "kip",
+                *jobs,
+                move |input, output| {
+                    let npdm_path = Path::new(&npdm_file);
+                    if npdm_path.is_dir() {
+                        let name = Path::new(input).with_extension("json");
+                        let npdm_path = npdm_path.join(name.file_name().unwrap_or_default());
+                        create_kip(input, &npdm_path.to_string_lossy(), output)
+                    } else {
+                        create_kip(input, &npdm_file, output)
+                    }
+                },
+            )
+        }
+        Opt::Npdm {
+            ref input_file,
+            ref output_file,
+        } => create_npdm(input_file, output_file),
+        Opt::Pfs0 {
+            ref input_directory,
+            ref output_file,
+            ref exclude,
+            ref include,
+            symlinks,
+            dry_run,
+        } => linkle::filter::PathFilter::new(include, exclude).and_then(|filter| {
+            let output_file = match output_file {
+                Some(output_file) if !*dry_run => Some(output_file.as_str()),
+                _ => None,
+            };
+            create_pfs0(input_directory, output_file, &filter, *symlinks)
+        }),
+        Opt::Pfs0Extract {
+            ref input_file,
+            ref output_directory,
+            file: Some(ref file),
+        } => extract_pfs0_file(input_file, output_directory, file),
+        Opt::Pfs0Extract {
+            ref input_file,
+            ref output_directory,
+            file: None,
+        } => extract_pfs0(input_file, output_directory),
+        Opt::Pfs0Append {
+            ref input_file,
+            ref output_file,
+            ref files,
+        } => append_pfs0(input_file, output_file, files),
+        Opt::Nacp {
+            ref input_file,
+            ref output_file,
+        } => create_nacp(input_file, output_file),
+        Opt::NacpPatch {
+            ref input_file,
+            ref output_file,
+            ref set,
+            ref patch,
+        } => patch_nacp(input_file, to_opt_ref(output_file), set, to_opt_ref(patch)),
+        Opt::Control {
+            ref input_file,
+            ref output_directory,
+            ref icon,
+            ref lang_icons,
+            ref romfs,
+        } => create_control(
+            input_file,
+            output_directory,
+            to_opt_ref(icon),
+            lang_icons,
+            to_opt_ref(romfs),
+        ),
+        Opt::NacpDiff {
+            ref old_file,
+            ref new_file,
+            json,
+        } => diff_nacp(old_file, new_file, *json),
+        Opt::NacpCanonicalize {
+            ref input_file,
+            ref output_file,
+        } => canonicalize_nacp(input_file, output_file),
+        Opt::NacpExtract {
+            ref input_file,
+            ref output_file,
+        } => extract_nacp(input_file, output_file),
+        Opt::CnmtExtract {
+            ref input_file,
+            ref output_file,
+        } => extract_cnmt(input_file, output_file),
+        Opt::Romfs {
+            ref input_directory,
+            ref output_file,
+            ref overlay,
+            ref exclude,
+            ref include,
+            path_encoding,
+            symlinks,
+            jobs,
+            ref manifest,
+            dir_hash_buckets,
+            file_hash_buckets,
+            dry_run,
+            cache,
+            watch,
+        } => {
+            let mut input_directories = vec![input_directory.as_path()];
+            input_directories.extend(overlay.iter().map(PathBuf::as_path));
+            let inputs =
+                || -> Vec<PathBuf> { input_directories.iter().map(|v| v.to_path_buf()).collect() };
+            let build_romfs = || {
+                linkle::filter::PathFilter::new(include, exclude).and_then(|filter| {
+                    let options = linkle::format::romfs::RomFsSourceOptions {
+                        filter,
+                        path_encoding: *path_encoding,
+                        symlinks: *symlinks,
+                    };
+                    let mut romfs = linkle::format::romfs::RomFs::from_directories(
+                        &input_directories,
+                        &options,
+                    )?;
+                    romfs.set_hash_table_sizes(*dir_hash_buckets, *file_hash_buckets);
+                    let output_file = match output_file {
+                        Some(output_file) if !*dry_run => output_file,
+                        _ => return print_romfs_layout(&romfs),
+                    };
+                    let jobs = jobs.unwrap_or_else(num_cpus::get);
+                    create_romfs(&romfs, output_file, jobs, to_opt_ref(manifest))
+                })
+            };
+            let build = || match output_file {
+                Some(output_file) if *cache && !*dry_run => {
+                    let mut outputs = vec![output_file.as_path()];
+                    outputs.extend(manifest.as_deref());
+                    cached_build(&inputs(), &outputs, build_romfs)
+                }
+                _ => build_romfs(),
+            };
+            if *watch {
+                watch_inputs(inputs, to_opt_ref(output_file), build)
+            } else {
+                build()
+            }
+        }
+        Opt::RomfsList {
+            ref input_file,
+            depth,
+            ref find,
+            json,
+        } => list_romfs(input_file, *depth, to_opt_ref(find), *json),
+        Opt::RomfsExtract {
+            ref input_file,
+            ref output_directory,
+            file: Some(ref file),
+            ..
+        } => extract_romfs_file(input_file, output_directory, file),
+        Opt::RomfsExtract {
+            ref input_file,
+            ref output_directory,
+            file: None,
+            ref manifest,
+        } => extract_romfs(input_file, output_directory, to_opt_ref(manifest)),
+        Opt::RomfsVerify {
+            ref input_file,
+            ref manifest,
+            json,
+        } => verify_romfs(input_file, manifest, *json),
+        Opt::RomfsReplace {
+            ref romfs_file,
+            ref path,
+            ref input_file,
+        } => replace_romfs_file(romfs_file, path, input_file),
+        Opt::RomfsDiff {
+            ref old_file,
+            ref new_file,
+            json,
+        } => diff_romfs(old_file, new_file, *json),
+        Opt::SaveList {
+            ref input_file,
+            json,
+        } => list_save(input_file, *json),
+        Opt::SaveExtract {
+            ref input_file,
+            ref output_directory,
+        } => extract_save(input_file, output_directory),
+        Opt::Nca {
+            ref output_file,
+            ref sections,
+            content_type,
+            program_id,
+            key_generation,
+            dev,
+            ref keyfile,
+        } => create_nca(
+            output_file,
+            sections,
+            *content_type,
+            *program_id,
+            *key_generation,
+            *dev,
+            to_opt_ref(keyfile),
+        ),
+        Opt::NcaProgram {
+            ref output_file,
+            ref exefs,
+            ref romfs,
+            ref logo,
+            ref base,
+            ref tickets,
+            program_id,
+            program_index,
+            ref npdm,
+            ref acid_signing_key,
+            key_generation,
+            ref signing_key,
+            plaintext,
+            compress,
+            dev,
+            ref keyfile,
+        } => create_program_nca(
+            output_file,
+            exefs,
+            to_opt_ref(romfs),
+            to_opt_ref(logo),
+            to_opt_ref(base),
+            tickets,
+            *program_id,
+            *program_index,
+            to_opt_ref(npdm),
+            to_opt_ref(acid_signing_key),
+            *key_generation,
+            to_opt_ref(signing_key),
+            *plaintext,
+            *compress,
+            *dev,
+            to_opt_ref(keyfile),
+        ),
+        Opt::NcaManual {
+            ref output_file,
+            ref documents,
+            program_id,
+            program_index,
+            key_generation,
+            plaintext,
+            dev,
+            ref keyfile,
+        } => create_manual_nca(
+            output_file,
+            documents,
+            *program_id,
+            *program_index,
+            *key_generation,
+            *plaintext,
+            *dev,
+            to_opt_ref(keyfile),
+        ),
+        Opt::NcaControl {
+            ref output_file,
+            ref control,
+            program_id,
+            program_index,
+            key_generation,
+            plaintext,
+            dev,
+            ref keyfile,
+        } => create_control_nca(
+            output_file,
+            control,
+            *program_id,
+            *program_index,
+            *key_generation,
+            *plaintext,
+            *dev,
+            to_opt_ref(keyfile),
+        ),
+        Opt::NcaMeta {
+            ref output_file,
+            ref contents,
+            meta_type,
+            title_id,
+            title_version,
+            required_system_version,
+            key_generation,
+            plaintext,
+            dev,
+            ref keyfile,
+        } => create_meta_nca(
+            output_file,
+            contents,
+            *meta_type,
+            *title_id,
+            *title_version,
+            *required_system_version,
+            *key_generation,
+            *plaintext,
+            *dev,
+            to_opt_ref(keyfile),
+        ),
+        Opt::NcaResign {
+            ref input_file,
+            ref output_file,
+            ref signing_key,
+            ref tickets,
+            dev,
+            ref keyfile,
+        } => resign_nca(
+            input_file,
+            output_file,
+            signing_key,
+            tickets,
+            *dev,
+            to_opt_ref(keyfile),
+        ),
+        Opt::NcaReencrypt {
+            ref input_file,
+            ref output_file,
+            key_generation,
+            ref signing_key,
+            ref tickets,
+            dev,
+            ref keyfile,
+        } => reencrypt_nca(
+            input_file,
+            output_file,
+            *key_generation,
+            to_opt_ref(signing_key),
+            tickets,
+            *dev,
+            to_opt_ref(keyfile),
+        ),
+        Opt::NcaVerify {
+            ref input_file,
+            ref base,
+            ref tickets,
+            json,
+            dev,
+            ref keyfile,
+        } => verify_nca(
+            input_file,
+            to_opt_ref(base),
+            tickets,
+            *json,
+            *dev,
+            to_opt_ref(keyfile),
+        ),
+        Opt::NcaInfo {
+            ref input_file,
+            json,
+            dev,
+            ref keyfile,
+        } => print_nca_info(input_file, *json, *dev, to_opt_ref(keyfile)),
+        Opt::NcaExtract {
+            ref input_file,
+            ref exefs,
+            ref romfs,
+            ref sections,
+            ref base,
+            ref tickets,
+            dev,
+            ref keyfile,
+        } => {
+            let options = NcaExtractOptions {
+                exefs: to_opt_ref(exefs),
+                romfs: to_opt_ref(romfs),
+                sections: to_opt_ref(sections),
+                base: to_opt_ref(base),
+            };
+            extract_nca(input_file, &options, tickets, *dev, to_opt_ref(keyfile))
+        }
+        Opt::Package1Info {
+            ref input_file,
+            json,
+            dev,
+            ref keyfile,
+        } => print_package1_info(input_file, *json, *dev, to_opt_ref(keyfile)),
+        Opt::Package1Extract {
+            ref input_file,
+            ref output_directory,
+            dev,
+            ref keyfile,
+        } => extract_package1(input_file, output_directory, *dev, to_opt_ref(keyfile)),
+        Opt::Package2Info {
+            ref input_file,
+            json,
+            dev,
+            ref keyfile,
+        } => print_package2_info(input_file, *json, *dev, to_opt_ref(keyfile)),
+        Opt::Package2Extract {
+            ref input_file,
+            ref output_directory,
+            dev,
+            ref keyfile,
+        } => extract_package2(input_file, output_directory, *dev, to_opt_ref(keyfile)),
+        Opt::Boot0Extract {
+            ref input_file,
+            ref output_file,
+        } => extract_boot0(input_file, output_file),
+        Opt::Bcpkg2Extract {
+            ref input_file,
+            ref output_file,
+            ref sub,
+            dev,
+            ref keyfile,
+        } => extract_bcpkg2(
+            input_file,
+            to_opt_ref(sub),
+            output_file,
+            *dev,
+            to_opt_ref(keyfile),
+        ),
+        Opt::Package2Repack {
+            ref input_file,
+            ref output_file,
+            ref ini1,
+            ref kips,
+            dev,
+            ref keyfile,
+        } => repack_package2(
+            input_file,
+            output_file,
+            to_opt_ref(ini1),
+            kips,
+            *dev,
+            to_opt_ref(keyfile),
+        ),
+        Opt::Tik {
+            ref output_file,
+            rights_id,
+            ref titlekey,
+            dev,
+            ref keyfile,
+        } => create_ticket(output_file, *rights_id, titlekey, *dev, to_opt_ref(keyfile)),
+        Opt::TikInfo {
+            ref input_file,
+            json,
+            dev,
+            ref keyfile,
+        } => print_ticket_info(input_file, *json, *dev, to_opt_ref(keyfile)),
+        Opt::TikDepersonalize {
+            ref input_file,
+            ref output_file,
+            dev,
+            ref keyfile,
+        } => depersonalize_ticket(input_file, output_file, *dev, to_opt_ref(keyfile)),
+        Opt::CertInfo {
+            ref input_file,
+            json,
+        } => print_cert_info(input_file, *json),
+        Opt::Nsp {
+            ref output_file,
+            ref elf,
+            ref exefs,
+            ref npdm,
+            ref nacp,
+            ref icon,
+            ref romfs,
+            ref logo,
+            ref html_document,
+            ref legal_information,
+            ref base,
+            ref tickets,
+            title_version,
+            key_generation,
+            compress,
+            ref signing_key,
+            ref acid_signing_key,
+            titlekey_crypto,
+            ref cert,
+            cache,
+            watch,
+            dev,
+            ref keyfile,
+        } => {
+            let inputs = || {
+                let paths = [
+                    exefs,
+                    npdm,
+                    icon,
+                    romfs,
+                    logo,
+                    html_document,
+                    legal_information,
+                    base,
+                    signing_key,
+                    acid_signing_key,
+                    cert,
+                ];
+                let mut inputs = paths.iter().filter_map(|&v| v.clone()).collect::<Vec<_>>();
+                inputs.extend(elf.iter().chain(Some(nacp)).map(PathBuf::from));
+                inputs.extend(tickets.iter().cloned());
+                // Without a keyset the build fails anyway.
+                inputs.extend(key_files(*dev, to_opt_ref(keyfile)).unwrap_or_default());
+                inputs
+            };
+            let build_nsp = || {
+                create_nsp(
+                    output_file,
+                    to_opt_ref(elf),
+                    to_opt_ref(exefs),
+                    to_opt_ref(npdm),
+                    nacp,
+                    to_opt_ref(icon),
+                    to_opt_ref(romfs),
+                    to_opt_ref(logo),
+                    to_opt_ref(html_document),
+                    to_opt_ref(legal_information),
+                    to_opt_ref(base),
+                    tickets,
+                    *title_version,
+                    *key_generation,
+                    *compress,
+                    to_opt_ref(signing_key),
+                    to_opt_ref(acid_signing_key),
+                    *titlekey_crypto,
+                    to_opt_ref(cert),
+                    *dev,
+                    to_opt_ref(keyfile),
+                )
+            };
+            let build = || {
+                if *cache {
+                    cached_build(&inputs(), &[output_file], build_nsp)
+                } else {
+                    build_nsp()
+                }
+            };
+            if *watch {
+                watch_inputs(inputs, Some(output_file), build)
+            } else {
+                build()
+            }
+        }
+        Opt::NspAddOnContent {
+            ref output_file,
+            ref romfs,
+            application_id,
+            index,
+            title_version,
+            required_application_version,
+            key_generation,
+            titlekey_crypto,
+            ref cert,
+            dev,
+            ref keyfile,
+        } => create_add_on_content_nsp(
+            output_file,
+            romfs,
+            *application_id,
+            *index,
+            *title_version,
+            *required_application_version,
+            *key_generation,
+            *titlekey_crypto,
+            to_opt_ref(cert),
+            *dev,
+            to_opt_ref(keyfile),
+        ),
+        Opt::NspConvert {
+            ref input_file,
+            ref output_file,
+            dev,
+            ref keyfile,
+        } => convert_nsp(input_file, output_file, *dev, to_opt_ref(keyfile)),
+        Opt::NspExtract {
+            ref input_file,
+            ref exefs,
+            ref romfs,
+            ref base,
+            dev,
+            ref keyfile,
+        } => {
+            let options = NcaExtractOptions {
+                exefs: to_opt_ref(exefs),
+                romfs: to_opt_ref(romfs),
+                sections: None,
+                base: to_opt_ref(base),
+            };
+            extract_nsp(input_file, &options, *dev, to_opt_ref(keyfile))
+        }
+        Opt::Keygen {
+            dev,
+            ref keyfile,
+            show_console_unique,
+            minimal,
+        } => print_keys(*dev, to_opt_ref(keyfile), *show_console_unique, *minimal),
+        Opt::KeysVerify {
+            dev,
+            ref keyfile,
+            ref hashes,
+            json,
+        } => verify_keys(*dev, to_opt_ref(keyfile), to_opt_ref(hashes), *json),
+        Opt::BisDecrypt {
+            partition,
+            ref input_file,
+            ref output_file,
+            dev,
+            ref keyfile,
+        } => decrypt_bis(
+            *partition,
+            input_file,
+            output_file,
+            *dev,
+            to_opt_ref(keyfile),
+        ),
+        Opt::SdDecrypt {
+            ref input_file,
+            ref output_file,
+            ref sd_path,
+            dev,
+            ref keyfile,
+        } => decrypt_sd(
+            input_file,
+            output_file,
+            to_opt_ref(sd_path),
+            *dev,
+            to_opt_ref(keyfile),
+        ),
+        Opt::Info {
+            ref input_file,
+            raw: true,
+            ..
+        } => print_raw_headers(input_file),
+        Opt::Info {
+            ref input_file,
+            json,
+            dev,
+            ref keyfile,
+            ..
+        } => print_info(input_file, *json, *dev, to_opt_ref(keyfile)),
+        Opt::Formats {
+            json,
+            dev,
+            ref keyfile,
+        } => print_formats(*json, *dev, to_opt_ref(keyfile)),
+        Opt::Build {
+            ref config,
+            dev,
+            ref keyfile,
+        } => run_pipeline(
+            config,
+            FORCE.load(Ordering::Relaxed),
+            *dev,
+            to_opt_ref(keyfile),
+        ),
+        Opt::Hactool(ref opt) => run_hactool(opt),
+        Opt::Hacbrewpack(ref opt) => create_hacbrewpack_nsp(opt),
+        Opt::New(ref opt) => create_templates(opt),
+    }
+}
+
+fn main() {
+    let args = Args::from_args();
+    linkle::logger::init(&["linkle"], args.verbose, args.quiet);
+    {
+        let mut checksums = CHECKSUMS.lock().unwrap();
+        checksums.sidecars = args.checksums || args.checksum_manifest.is_some();
+        checksums.manifest = args.checksum_manifest.clone();
+    }
+    {
+        let mut key_paths = KEY_PATHS.lock().unwrap();
+        key_paths.titlekeys = args.titlekeys.clone();
+        key_paths.console_keys = args.console_keys.clone();
+    }
+    NO_CLOBBER.store(args.no_clobber, Ordering::Relaxed);
+    FORCE.store(args.force, Ordering::Relaxed);
+    PROMPT.store(args.prompt, Ordering::Relaxed);
+    STRICT.store(args.strict, Ordering::Relaxed);
+    if let Err(e) = process_args(&args.command).and_then(|()| write_checksums()) {
+        if args.error_format == "json" {
+            let error = serde_json::json!({
+                "class": e.class(),
+                "exit_code": e.class().exit_code(),
+                "message": e.to_string(),
+                "path": e.path().map(|path| path.display().to_string()),
+                "field": e.field(),
+                "offset": e.offset(),
+            });
+            eprintln!("{}", error);
+        } else {
+            eprintln!("Error: {}", e);
+        }
+        process::exit(e.class().exit_code())
+    }
 }
@@ -0,0 +1,317 @@
+//! AES modes used for the content of NCAs, and anything else encrypted by
+//! position: AES-CTR, whose counter is taken from the offset of the data, and
+//! AES-XTS with the tweak Nintendo uses.
+
+use crate::error::Error;
+use crate::pki::{Aes128Key, AesXtsKey};
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::BlockCipher;
+use aes::Aes128;
+use aes::NewBlockCipher;
+use block_modes::cipher::SyncStreamCipher;
+use ctr::cipher::stream::NewStreamCipher;
+use ctr::Ctr128;
+use snafu::Backtrace;
+use snafu::GenerateBacktrace;
+
+/// Encrypts data in place depending on its offset, so any part of a stream
+/// can be read or written on its own.
+pub trait Crypter {
+    /// Size, in bytes, that the data and its offset must be aligned to.
+    fn block_size(&self) -> usize;
+
+    /// Encrypts `data`, found at `offset` in the stream.
+    fn encrypt(&self, data: &mut [u8], offset: u64) -> Result<(), Error>;
+
+    /// Decrypts `data`, found at `offset` in the stream.
+    fn decrypt(&self, data: &mut [u8], offset: u64) -> Result<(), Error>;
+}
+
+fn check_alignment<C: Crypter + ?Sized>(
+    crypter: &C,
+    data: &[u8],
+    offset: u64,
+) -> Result<(), Error> {
+    let block_size = crypter.block_size();
+    if data.len() % block_size != 0 || offset % block_size as u64 != 0 {
+        return Err(Error::Crypto {
+            error: format!(
+                "Length and offset must be a multiple of the block size {:#x}",
+                block_size
+            ),
+            backtrace: Backtrace::generate(),
+        });
+    }
+    Ok(())
+}
+
+/// AES-CTR whose counter is incremented by the offset of the data, in blocks.
+/// NCA sections give the upper half of the counter in their fs header, the
+/// lower half being the offset alone.
+#[derive(Debug, Clone)]
+pub struct AesCtr {
+    key: Aes128Key,
+    ctr: u128,
+}
+
+impl AesCtr {
+    pub fn new(key: Aes128Key, ctr: u64) -> AesCtr {
+        AesCtr {
+            key,
+            ctr: (ctr as u128) << 64,
+        }
+    }
+
+    /// Uses the whole counter of the data at offset 0, as stored in front of
+    /// the encrypted data of packages.
+    pub fn with_counter(key: Aes128Key, ctr: [u8; 0x10]) -> AesCtr {
+        AesCtr {
+            key,
+            ctr: u128::from_be_bytes(ctr),
+        }
+    }
+
+    /// Upper half of the counter.
+    pub fn ctr(&self) -> u64 {
+        (self.ctr >> 64) as u64
+    }
+
+    /// Encrypts or decrypts `data`, found at `offset` in the stream. Both are
+    /// the same operation with AES-CTR, and can't fail.
+    pub fn apply_keystream(&self, data: &mut [u8], offset: u64) {
+        let ctr = self.ctr.wrapping_add((offset >> 4) as u128).to_be_bytes();
+        let mut crypter = Ctr128::<Aes128>::new(
+            GenericArray::from_slice(self.key.as_bytes()),
+            GenericArray::from_slice(&ctr),
+        );
+        // Skip the part of the keystream used by the data before `offset`
+        // in its block.
+        crypter.apply_keystream(&mut [0; 0x10][..(offset % 0x10) as usize]);
+        crypter.apply_keystream(data);
+    }
+}
+
+impl Crypter for AesCtr {
+    /// AES-CTR is a stream cipher, it works at any offset.
+    fn block_size(&self) -> usize {
+        1
+    }
+
+    fn encrypt(&self, data: &mut [u8], offset: u64) -> Result<(), Error> {
+        self.apply_keystream(data, offset);
+        Ok(())
+    }
+
+    fn decrypt(&self, data: &mut [u8], offset: u64) -> Result<(), Error> {
+        self.apply_keystream(data, offset);
+        Ok(())
+    }
+}
+
+/// AES-XTS over sectors of `sector_size` bytes, the sector number being the
+/// offset of the data divided by the sector size. Nintendo stores the sector
+/// number in the tweak as big endian, unlike standard XTS.
+#[derive(Debug, Clone)]
+pub struct AesXts {
+    key: AesXtsKey,
+    sector_size: usize,
+}
+
+impl AesXts {
+    /// Fails if `sector_size` isn't a non-zero multiple of the AES block size.
+    pub fn new(key: AesXtsKey, sector_size: usize) -> Result<AesXts, Error> {
+        if sector_size == 0 || sector_size % 0x10 != 0 {
+            return Err(Error::Crypto {
+                error: format!("Invalid AES-XTS sector size {:#x}", sector_size),
+                backtrace: Backtrace::generate(),
+            });
+        }
+        Ok(AesXts { key, sector_size })
+    }
+
+    pub fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+}
+
+impl Crypter for AesXts {
+    fn block_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn encrypt(&self, data: &mut [u8], offset: u64) -> Result<(), Error> {
+        check_alignment(self, data, offset)?;
+        xts(
+            self.key.as_bytes(),
+            data,
+            offset / self.sector_size as u64,
+            self.sector_size,
+            true,
+        );
+        Ok(())
+    }
+
+    fn decrypt(&self, data: &mut [u8], offset: u64) -> Result<(), Error> {
+        check_alignment(self, data, offset)?;
+        xts(
+            self.key.as_bytes(),
+            data,
+            offset / self.sector_size as u64,
+            self.sector_size,
+            false,
+        );
+        Ok(())
+    }
+}
+
+/// Runs AES-XTS over `data`, starting at sector `sector`. `data` must be made
+/// of whole sectors.
+fn xts(key: &[u8; 0x20], data: &mut [u8], sector: u64, sector_size: usize, encrypt: bool) {
+    let crypter = Aes128::new(GenericArray::from_slice(&key[..0x10]));
+    let tweak_crypter = Aes128::new(GenericArray::from_slice(&key[0x10..]));
+    for (idx, sector_data) in data.chunks_mut(sector_size).enumerate() {
+        let mut tweak = [0; 0x10];
+        tweak[8..].copy_from_slice(&(sector + idx as u64).to_be_bytes());
+        tweak_crypter.encrypt_block(GenericArray::from_mut_slice(&mut tweak));
+
+        for block in sector_data.chunks_mut(0x10) {
+            for (byte, tweak) in block.iter_mut().zip(&tweak) {
+                *byte ^= tweak;
+            }
+            if encrypt {
+                crypter.encrypt_block(GenericArray::from_mut_slice(block));
+            } else {
+                crypter.decrypt_block(GenericArray::from_mut_slice(block));
+            }
+            for (byte, tweak) in block.iter_mut().zip(&tweak) {
+                *byte ^= tweak;
+            }
+
+            // Multiply the tweak by x in GF(2^128).
+            let carry = tweak[0xF] >> 7;
+            for i in (1..0x10).rev() {
+                tweak[i] = (tweak[i] << 1) | (tweak[i - 1] >> 7);
+            }
+            tweak[0] = (tweak[0] << 1) ^ if carry != 0 { 0x87 } else { 0 };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn hex(data: &[u8]) -> String {
+        data.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn ctr_at_any_offset() {
+        let crypter = AesCtr::new([0x42; 0x10].into(), 0x0000_0001_0000_0000);
+        let mut whole = [0x5A; 0x40];
+        crypter.encrypt(&mut whole, 0x1200).unwrap();
+
+        let mut parts = [0x5A; 0x40];
+        let (first, second) = parts.split_at_mut(0x13);
+        crypter.encrypt(first, 0x1200).unwrap();
+        crypter.encrypt(second, 0x1213).unwrap();
+        assert_eq!(&whole[..], &parts[..]);
+
+        crypter.decrypt(&mut whole[0x13..], 0x1213).unwrap();
+        assert_eq!(&whole[0x13..], &[0x5A; 0x2D][..]);
+    }
+
+    #[test]
+    fn xts_round_trip() {
+        let mut key = [0; 0x20];
+        key[..0x10].copy_from_slice(&[0x11; 0x10]);
+        key[0x10..].copy_from_slice(&[0x22; 0x10]);
+        let crypter = AesXts::new(AesXtsKey::from(key), 0x200).unwrap();
+
+        let mut data = [0x5A; 0x400];
+        crypter.encrypt(&mut data, 0x400).unwrap();
+        assert_ne!(&data[..0x200], &data[0x200..]);
+        crypter.decrypt(&mut data, 0x400).unwrap();
+        assert_eq!(&data[..], &[0x5A; 0x400][..]);
+
+        assert!(crypter.decrypt(&mut data[..0x200], 0x100).is_err());
+        assert!(crypter.decrypt(&mut data[..0x100], 0).is_err());
+    }
+
+    #[test]
+    fn ctr_matches_known_answers() {
+        // NIST SP 800-38A, F.5.1 CTR-AES128.Encrypt. The counter wraps from
+        // ...feff to ...ff00 at the second block, as a big endian number.
+        let mut key = [0; 0x10];
+        key.copy_from_slice(&from_hex("2b7e151628aed2a6abf7158809cf4f3c"));
+        let mut ctr = [0; 0x10];
+        ctr.copy_from_slice(&from_hex("f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff"));
+        let crypter = AesCtr::with_counter(key.into(), ctr);
+        let mut data = from_hex(concat!(
+            "6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51",
+            "30c81c46a35ce411e5fbc1191a0a52eff69f2445df4f9b17ad2b417be66c3710",
+        ));
+        crypter.encrypt(&mut data, 0).unwrap();
+        assert_eq!(
+            hex(&data),
+            concat!(
+                "874d6191b620e3261bef6864990db6ce9806f66b7970fdff8617187bb9fffdff",
+                "5ae4df3edbd5d35e5b4f09020db03eab1e031dda2fbe03d1792170a0f3009cee",
+            )
+        );
+        crypter.decrypt(&mut data[0x20..0x30], 0x20).unwrap();
+        assert_eq!(hex(&data[0x20..0x30]), "30c81c46a35ce411e5fbc1191a0a52ef");
+
+        // The counter of NCA sections: the upper half from the fs header,
+        // the lower half the offset in blocks, computed with OpenSSL.
+        let crypter = AesCtr::new([0x42; 0x10].into(), 0x0000_0001_0000_0000);
+        let mut data = [0x5A; 0x20];
+        crypter.encrypt(&mut data, 0x1200).unwrap();
+        assert_eq!(
+            hex(&data),
+            "8ae2a97ec53687b5f31b7675654472b33986b07f3b360222a1b8b9c1bb10a65a"
+        );
+    }
+
+    #[test]
+    fn xts_matches_known_answers() {
+        // IEEE 1619 vector 1. At sector 0, the tweak is the same whatever the
+        // endianness.
+        let crypter = AesXts::new(AesXtsKey::from([0; 0x20]), 0x20).unwrap();
+        let mut data = [0; 0x20];
+        crypter.encrypt(&mut data, 0).unwrap();
+        assert_eq!(
+            hex(&data),
+            "917cf69ebd68b2ec9b9fe9a3eadda692cd43d2f59598ed858c02c2652fbf922e"
+        );
+
+        // The keys and data of IEEE 1619 vector 2, over two sectors starting
+        // at sector 0x3333333333 stored as big endian in the tweak, computed
+        // with OpenSSL.
+        let mut key = [0; 0x20];
+        key[..0x10].copy_from_slice(&[0x11; 0x10]);
+        key[0x10..].copy_from_slice(&[0x22; 0x10]);
+        let crypter = AesXts::new(AesXtsKey::from(key), 0x200).unwrap();
+        let mut data = [0x44; 0x400];
+        let offset = 0x33_3333_3333 * 0x200;
+        crypter.encrypt(&mut data, offset).unwrap();
+        assert_eq!(
+            hex(&data[..0x20]),
+            "44bec82ffb76aefdfbc96dfe61e192ccfa2213677c8f4fd6e4f18f7ebb69382f"
+        );
+        assert_eq!(hex(&data[0x1F0..0x200]), "c55c0e0c4e7e807470d7b3e37f47bc61");
+        assert_eq!(
+            hex(&data[0x200..0x220]),
+            "5a2cf8ce76a49709468e07cd8f05b5fb296e36d00e5cd93bc4583f3d2cc05ae5"
+        );
+        crypter.decrypt(&mut data[0x200..], offset + 0x200).unwrap();
+        assert_eq!(&data[0x200..], &[0x44; 0x200][..]);
+    }
+}
@@ -1,9 +1,12 @@
+#[cfg(feature = "crypto")]
 use block_modes::BlockModeError;
+use serde_derive::Serialize;
 use snafu::Backtrace;
 use snafu::GenerateBacktrace;
 use snafu::Snafu;
 use std::io;
 use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
 use std::str::Utf8Error;
 use std::string::FromUtf8Error;
 
@@ -25,11 +28,13 @@ pub enum Error {
         error: io::Error,
         backtrace: Backtrace,
     },
+    #[cfg(feature = "crypto")]
     #[snafu(display("Decryption failed"))]
     BlockMode {
         error: BlockModeError,
         backtrace: Backtrace,
     },
+    #[cfg(feature = "crypto")]
     #[snafu(display("Error parsing the INI file: {}", error))]
     Ini {
         error: ini::Error,
@@ -37,6 +42,24 @@ pub enum Error {
     },
     #[snafu(display("Key derivation error: {}", error))]
     Crypto { error: String, backtrace: Backtrace },
+    #[snafu(display("Missing key {}. Add it to your keyset.", key_name))]
+    MissingKey {
+        key_name: String,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("Keyfile not found: {}.", path.display()))]
+    MissingKeyfile { path: PathBuf, backtrace: Backtrace },
+    #[snafu(display(
+        "Missing console unique key {}. It differs on every console: {}.",
+        key_name,
+        hint
+    ))]
+    MissingConsoleKey {
+        key_name: String,
+        hint: &'static str,
+        backtrace: Backtrace,
+    },
+    #[cfg(feature = "crypto")]
     #[snafu(display("Invalid keyblob {}: {}.", id, error))]
     MacError {
         error: cmac::crypto_mac::MacError,
@@ -48,6 +71,136 @@ pub enum Error {
         error: &'static str,
         backtrace: Backtrace,
     },
+    #[snafu(display("Invalid CNMT: {}.", error))]
+    InvalidCnmt {
+        error: &'static str,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("Invalid certificate: {}.", error))]
+    InvalidCert {
+        error: &'static str,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("Invalid ticket: {}.", error))]
+    InvalidTicket {
+        error: &'static str,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("Invalid NCA: {}.", error))]
+    InvalidNca {
+        error: &'static str,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("Invalid NPDM: {}.", error))]
+    InvalidNpdm {
+        error: &'static str,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("Invalid NSO or NRO: {}.", error))]
+    InvalidNxo {
+        error: &'static str,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("Invalid RomFS: {}.", error))]
+    InvalidRomFs {
+        error: &'static str,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("Invalid XCI: {}.", error))]
+    InvalidXci {
+        error: &'static str,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("{} at {:#x}..{:#x} {}.", region, start, end, error))]
+    InvalidRegion {
+        /// What the region holds, e.g. `string table`.
+        region: String,
+        start: u64,
+        end: u64,
+        error: String,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("Invalid PRODINFO: {}.", error))]
+    InvalidProdinfo {
+        error: &'static str,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("Invalid NAX0: {}.", error))]
+    InvalidNax0 {
+        error: &'static str,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("Invalid Package1: {}.", error))]
+    InvalidPackage1 {
+        error: &'static str,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("Invalid Package2: {}.", error))]
+    InvalidPackage2 {
+        error: &'static str,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("Invalid INI1: {}.", error))]
+    InvalidIni1 {
+        error: &'static str,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("Invalid save file: {}.", error))]
+    InvalidSave {
+        error: &'static str,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("{}: {}", path.display(), error))]
+    InvalidImage {
+        error: image::ImageError,
+        path: PathBuf,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("Invalid icon {}: {}.", path.display(), error))]
+    InvalidIcon {
+        path: PathBuf,
+        error: &'static str,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("No icon given for {}", language))]
+    MissingIcon {
+        language: &'static str,
+        backtrace: Backtrace,
+    },
+    #[snafu(display(
+        "{}: unknown file format, or an NCA the keys can't decrypt",
+        path.display()
+    ))]
+    UnknownFormat { path: PathBuf, backtrace: Backtrace },
+    #[snafu(display("Invalid NACP: {}.", error))]
+    InvalidNacp {
+        error: &'static str,
+        /// The descriptor field the error is about, `a.b` for nested fields.
+        field: Option<&'static str>,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("Unsupported file name {}: {}.", path.display(), error))]
+    RomFsPathEncoding {
+        path: PathBuf,
+        error: &'static str,
+        backtrace: Backtrace,
+    },
+    #[snafu(display(
+        "Can't replace {} in place: {:#x} bytes given but only {:#x} available",
+        path,
+        size,
+        available
+    ))]
+    RomFsReplaceTooBig {
+        path: String,
+        size: u64,
+        available: u64,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("Invalid manifest: line {} is not in the sha256sum format", line))]
+    InvalidManifest { line: usize, backtrace: Backtrace },
+    #[snafu(display("Invalid path in RomFS: {}", path))]
+    InvalidRomFsPath { path: String, backtrace: Backtrace },
     #[snafu(display("Failed to convert filename to UTF8: {}.", filename))]
     Utf8Conversion {
         filename: String,
@@ -59,35 +212,268 @@ pub enum Error {
         error: PathBuf,
         backtrace: Backtrace,
     },
+    #[snafu(display("Symlink {} points to one of its parent directories", path.display()))]
+    RomFsSymlinkLoop { path: PathBuf, backtrace: Backtrace },
     #[snafu(display("Unknown file type at {}", error.display()))]
     RomFsFiletype {
         error: PathBuf,
         backtrace: Backtrace,
     },
+    #[snafu(display("Invalid glob pattern: {}", error))]
+    InvalidGlob {
+        error: globset::Error,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("Invalid {}, expected {}", argument, expected))]
+    InvalidArgument {
+        argument: String,
+        expected: &'static str,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("Invalid build pipeline {}: {}.", path.display(), error))]
+    InvalidPipeline {
+        path: PathBuf,
+        error: String,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("{} failed with {}", command, status))]
+    CommandFailed {
+        command: String,
+        status: ExitStatus,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("No file matches {}", pattern))]
+    NoGlobMatch {
+        pattern: String,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("{} of {} file(s) failed", failed, total))]
+    BatchFailed {
+        failed: usize,
+        total: usize,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("Invalid ZIP archive: {}", error))]
+    InvalidZip {
+        error: zip::result::ZipError,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("Invalid ignore file: {}", error))]
+    InvalidIgnoreFile {
+        error: ignore::Error,
+        backtrace: Backtrace,
+    },
+    /// Another error, with where it happened: in which file, field of the
+    /// input, or offset of the file.
+    #[snafu(display("{}{}", context_prefix(path, field, offset), source))]
+    WithContext {
+        path: Option<PathBuf>,
+        field: Option<String>,
+        offset: Option<u64>,
+        source: Box<Error>,
+    },
+}
+
+fn context_prefix(path: &Option<PathBuf>, field: &Option<String>, offset: &Option<u64>) -> String {
+    let mut prefix = String::new();
+    if let Some(path) = path {
+        prefix += &format!("{}: ", path.display());
+    }
+    if let Some(field) = field {
+        prefix += &format!("{}: ", field);
+    }
+    if let Some(offset) = offset {
+        prefix += &format!("at offset {:#x}: ", offset);
+    }
+    prefix
+}
+
+/// The kind of failure an `Error` is, each exiting linkle with its own code so
+/// scripts can tell them apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorClass {
+    /// A file or argument isn't in the expected format.
+    Parse,
+    /// The input parsed but isn't valid, like a NACP with inconsistent fields.
+    Validation,
+    /// A key is missing from the keyset, or doesn't decrypt the data.
+    MissingKeys,
+    /// Reading or writing a file failed.
+    Io,
+    /// Anything else, like a build command failing.
+    Other,
+}
+
+impl ErrorClass {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorClass::Other => 1,
+            ErrorClass::Parse => 2,
+            ErrorClass::Validation => 3,
+            ErrorClass::MissingKeys => 4,
+            ErrorClass::Io => 5,
+        }
+    }
 }
 
 impl Error {
-    fn with_path<T: AsRef<Path>>(self, path: T) -> Error {
-        if let Error::IoInternal { error, backtrace } = self {
-            Error::Io {
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            Error::Io { error, .. } | Error::IoInternal { error, .. } => {
+                // Serde errors and truncated files come as IO errors.
+                match error.kind() {
+                    io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => ErrorClass::Parse,
+                    _ => ErrorClass::Io,
+                }
+            }
+            Error::NoGlobMatch { .. }
+            | Error::RomFsSymlink { .. }
+            | Error::RomFsSymlinkLoop { .. }
+            | Error::RomFsFiletype { .. } => ErrorClass::Io,
+            Error::MissingKey { .. }
+            | Error::MissingKeyfile { .. }
+            | Error::MissingConsoleKey { .. }
+            | Error::Crypto { .. } => ErrorClass::MissingKeys,
+            #[cfg(feature = "crypto")]
+            Error::BlockMode { .. } | Error::MacError { .. } => ErrorClass::MissingKeys,
+            Error::InvalidNacp { .. }
+            | Error::InvalidIcon { .. }
+            | Error::MissingIcon { .. }
+            | Error::RomFsPathEncoding { .. }
+            | Error::RomFsReplaceTooBig { .. }
+            | Error::InvalidRomFsPath { .. } => ErrorClass::Validation,
+            Error::CommandFailed { .. } | Error::BatchFailed { .. } => ErrorClass::Other,
+            Error::WithContext { source, .. } => source.class(),
+            _ => ErrorClass::Parse,
+        }
+    }
+
+    /// The file this error is about, if known.
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Error::Io { path, .. }
+            | Error::InvalidImage { path, .. }
+            | Error::InvalidIcon { path, .. }
+            | Error::UnknownFormat { path, .. }
+            | Error::MissingKeyfile { path, .. }
+            | Error::RomFsPathEncoding { path, .. }
+            | Error::RomFsSymlinkLoop { path, .. }
+            | Error::InvalidPipeline { path, .. } => Some(path),
+            Error::RomFsSymlink { error, .. } | Error::RomFsFiletype { error, .. } => Some(error),
+            Error::WithContext { path, source, .. } => path.as_deref().or_else(|| source.path()),
+            _ => None,
+        }
+    }
+
+    /// The field of the input this error is about, if known. Nested fields
+    /// are named `a.b`, and array elements `a[0]`.
+    pub fn field(&self) -> Option<&str> {
+        match self {
+            Error::InvalidNacp { field, .. } => *field,
+            Error::WithContext { field, source, .. } => field.as_deref().or_else(|| source.field()),
+            _ => None,
+        }
+    }
+
+    /// The offset in the file this error is about, if known.
+    pub fn offset(&self) -> Option<u64> {
+        match self {
+            Error::InvalidRegion { start, .. } => Some(*start),
+            Error::WithContext { offset, source, .. } => offset.or_else(|| source.offset()),
+            _ => None,
+        }
+    }
+
+    /// Records that this error is about the file `path`, unless it already
+    /// names a file.
+    pub fn with_path<T: AsRef<Path>>(self, path: T) -> Error {
+        match self {
+            Error::IoInternal { error, backtrace } => Error::Io {
                 error,
                 path: path.as_ref().to_owned(),
                 backtrace,
-            }
-        } else {
-            self
+            },
+            // IO errors get their path themselves, so they aren't reported
+            // as internal ones.
+            Error::WithContext {
+                path: None,
+                field,
+                offset,
+                source,
+            } if matches!(*source, Error::IoInternal { .. }) => Error::WithContext {
+                path: None,
+                field,
+                offset,
+                source: Box::new(source.with_path(path)),
+            },
+            err if err.path().is_some() => err,
+            err => err.context(|context| context.0 = Some(path.as_ref().to_owned())),
+        }
+    }
+
+    /// Records that this error is about the field `field` of the input,
+    /// unless it already names one.
+    pub fn with_field<T: Into<String>>(self, field: T) -> Error {
+        if self.field().is_some() {
+            return self;
+        }
+        self.context(|context| context.1 = Some(field.into()))
+    }
+
+    /// Records that this error is about the data at `offset` in the file,
+    /// unless it already has an offset.
+    pub fn with_offset(self, offset: u64) -> Error {
+        if self.offset().is_some() {
+            return self;
+        }
+        self.context(|context| context.2 = Some(offset))
+    }
+
+    /// Sets part of the context of this error, wrapping it in `WithContext`
+    /// if it isn't already.
+    fn context<F>(self, f: F) -> Error
+    where
+        F: FnOnce(&mut (Option<PathBuf>, Option<String>, Option<u64>)),
+    {
+        let (mut context, source) = match self {
+            Error::WithContext {
+                path,
+                field,
+                offset,
+                source,
+            } => ((path, field, offset), source),
+            err => ((None, None, None), Box::new(err)),
+        };
+        f(&mut context);
+        let (path, field, offset) = context;
+        Error::WithContext {
+            path,
+            field,
+            offset,
+            source,
         }
     }
 }
 
 pub trait ResultExt {
     fn with_path<T: AsRef<Path>>(self, path: T) -> Self;
+    fn with_field<T: Into<String>>(self, field: T) -> Self;
+    fn with_offset(self, offset: u64) -> Self;
 }
 
 impl<T> ResultExt for Result<T, Error> {
     fn with_path<U: AsRef<Path>>(self, path: U) -> Result<T, Error> {
         self.map_err(|err| err.with_path(path))
     }
+
+    fn with_field<U: Into<String>>(self, field: U) -> Result<T, Error> {
+        self.map_err(|err| err.with_field(field))
+    }
+
+    fn with_offset(self, offset: u64) -> Result<T, Error> {
+        self.map_err(|err| err.with_offset(offset))
+    }
 }
 
 impl From<io::Error> for Error {
@@ -109,6 +495,7 @@ impl<T: AsRef<Path>> From<(io::Error, T)> for Error {
     }
 }
 
+#[cfg(feature = "crypto")]
 impl From<ini::Error> for Error {
     fn from(error: ini::Error) -> Error {
         Error::Ini {
@@ -118,6 +505,7 @@ impl From<ini::Error> for Error {
     }
 }
 
+#[cfg(feature = "crypto")]
 impl From<BlockModeError> for Error {
     fn from(error: BlockModeError) -> Error {
         Error::BlockMode {
@@ -144,6 +532,7 @@ impl From<FromUtf8Error> for Error {
     }
 }
 
+#[cfg(feature = "crypto")]
 impl From<(usize, cmac::crypto_mac::MacError)> for Error {
     fn from((id, error): (usize, cmac::crypto_mac::MacError)) -> Error {
         Error::MacError {
@@ -153,3 +542,31 @@ impl From<(usize, cmac::crypto_mac::MacError)> for Error {
         }
     }
 }
+
+impl From<zip::result::ZipError> for Error {
+    fn from(error: zip::result::ZipError) -> Error {
+        Error::InvalidZip {
+            error,
+            backtrace: Backtrace::generate(),
+        }
+    }
+}
+
+impl From<globset::Error> for Error {
+    fn from(error: globset::Error) -> Error {
+        Error::InvalidGlob {
+            error,
+            backtrace: Backtrace::generate(),
+        }
+    }
+}
+
+impl<T: AsRef<Path>> From<(image::ImageError, T)> for Error {
+    fn from((error, path): (image::ImageError, T)) -> Error {
+        Error::InvalidImage {
+            error,
+            path: path.as_ref().to_owned(),
+            backtrace: Backtrace::generate(),
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use crate::error::Error;
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::WalkBuilder;
+use snafu::Backtrace;
+use snafu::GenerateBacktrace;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+/// Glob-based filter used to decide which entries of an input directory end
+/// up in a built archive.
+///
+/// Patterns are matched against the path of the entry relative to the root of
+/// the input directory, using `/` as the separator (e.g. `*.pdb`, `.git/**`).
+/// Excluding a directory excludes everything below it. When include patterns
+/// are given, only files matching at least one of them are kept.
+#[derive(Debug, Default, Clone)]
+pub struct PathFilter {
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+}
+
+fn build_globset(patterns: &[String]) -> Result<Option<GlobSet>, Error> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern)?);
+    }
+    Ok(Some(builder.build()?))
+}
+
+impl PathFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<PathFilter, Error> {
+        Ok(PathFilter {
+            include: build_globset(include)?,
+            exclude: build_globset(exclude)?,
+        })
+    }
+
+    /// Returns true if the directory at `path` (relative to the input root)
+    /// should be skipped along with all of its content.
+    pub fn is_dir_excluded(&self, path: &Path) -> bool {
+        let path = match to_internal_path(path) {
+            Some(path) => path,
+            None => return false,
+        };
+        if let Some(exclude) = &self.exclude {
+            // Patterns like `.git/**` only match the directory's content, so
+            // also check with a trailing slash to prune the directory itself.
+            exclude.is_match(&path) || exclude.is_match(path + "/")
+        } else {
+            false
+        }
+    }
+
+    /// Returns true if the file at `path` (relative to the input root) should
+    /// be skipped.
+    pub fn is_file_excluded(&self, path: &Path) -> bool {
+        let path = match to_internal_path(path) {
+            Some(path) => path,
+            None => return false,
+        };
+        if let Some(exclude) = &self.exclude {
+            if exclude.is_match(&path) {
+                return true;
+            }
+        }
+        if let Some(include) = &self.include {
+            !include.is_match(&path)
+        } else {
+            false
+        }
+    }
+}
+
+/// What to do with symbolic links found in an input directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Uses the file or directory the link points to. Links pointing to one of
+    /// their parent directories are rejected.
+    Follow,
+    /// Prints a warning and leaves the link out.
+    Skip,
+    /// Fails on the first link found.
+    Error,
+}
+
+impl Default for SymlinkPolicy {
+    fn default() -> SymlinkPolicy {
+        SymlinkPolicy::Error
+    }
+}
+
+impl FromStr for SymlinkPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SymlinkPolicy, String> {
+        match s {
+            "follow" => Ok(SymlinkPolicy::Follow),
+            "skip" => Ok(SymlinkPolicy::Skip),
+            "error" => Ok(SymlinkPolicy::Error),
+            _ => Err(format!(
+                "Unknown symlink policy {}, expected one of follow, skip, error",
+                s
+            )),
+        }
+    }
+}
+
+/// Converts a host relative path to a `/`-separated path, the way patterns
+/// are written by the user regardless of the host platform. Names that aren't
+/// valid UTF-8 are converted lossily, so that `*` patterns still match them.
+fn to_internal_path(path: &Path) -> Option<String> {
+    let mut components = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => components.push(name.to_string_lossy()),
+            Component::CurDir => (),
+            _ => return None,
+        }
+    }
+    Some(components.join("/"))
+}
+
+/// Name of the ignore file looked up at the root of RomFS source directories.
+pub const ROMFS_IGNORE_FILE: &str = ".romfsignore";
+
+/// Patterns read from an ignore file using the gitignore syntax, applied to
+/// paths relative to the directory containing it.
+#[derive(Debug)]
+pub struct IgnoreFile {
+    matcher: Gitignore,
+}
+
+impl IgnoreFile {
+    /// Loads the ignore file named `name` at the root of `dir`. Returns `None`
+    /// if the directory has no such file.
+    pub fn from_directory(dir: &Path, name: &str) -> Result<Option<IgnoreFile>, Error> {
+        let path = dir.join(name);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let mut builder = GitignoreBuilder::new(dir);
+        if let Some(error) = builder.add(&path) {
+            return Err(Error::InvalidIgnoreFile {
+                error,
+                backtrace: Backtrace::generate(),
+            });
+        }
+        let matcher = builder.build().map_err(|error| Error::InvalidIgnoreFile {
+            error,
+            backtrace: Backtrace::generate(),
+        })?;
+        Ok(Some(IgnoreFile { matcher }))
+    }
+
+    /// Returns true if the entry at `path` (relative to the directory holding
+    /// the ignore file) is ignored.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        self.matcher.matched(path, is_dir).is_ignore()
+    }
+}
+
+/// Returns true if `pattern` has glob metacharacters, rather than being the
+/// path of a single file.
+pub fn is_glob(pattern: &str) -> bool {
+    pattern.contains(&['*', '?', '[', '{'][..])
+}
+
+/// Lists the files matching the glob pattern `pattern`, e.g. `build/*.elf` or
+/// `elfs/**/*.elf`, sorted by path. Unlike in `PathFilter`, `*` doesn't match
+/// `/`. Fails if no file matches.
+pub fn expand_glob(pattern: &str) -> Result<Vec<PathBuf>, Error> {
+    let matcher = GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()?
+        .compile_matcher();
+
+    // Only walk from the last directory before the first glob, and no deeper
+    // than the pattern goes.
+    let components = Path::new(pattern).components().collect::<Vec<_>>();
+    let literal = components
+        .iter()
+        .take_while(|component| !is_glob(&component.as_os_str().to_string_lossy()))
+        .count();
+    let walk_root = if literal == 0 {
+        PathBuf::from(".")
+    } else {
+        components[..literal].iter().collect()
+    };
+    let max_depth = if pattern.contains("**") {
+        None
+    } else {
+        Some(components.len() - literal)
+    };
+
+    let no_match = || Error::NoGlobMatch {
+        pattern: pattern.to_string(),
+        backtrace: Backtrace::generate(),
+    };
+    if !walk_root.is_dir() {
+        return Err(no_match());
+    }
+
+    let mut paths = Vec::new();
+    let walker = WalkBuilder::new(&walk_root)
+        .standard_filters(false)
+        .max_depth(max_depth)
+        .build();
+    for entry in walker {
+        let entry = entry.map_err(|err| (io::Error::new(io::ErrorKind::Other, err), &walk_root))?;
+        if !entry.path().is_file() {
+            continue;
+        }
+        // Relative patterns don't start with the ./ the walk adds.
+        let path = match entry.path().strip_prefix(&walk_root) {
+            Ok(relative) if literal == 0 => relative.to_path_buf(),
+            _ => entry.path().to_path_buf(),
+        };
+        if matcher.is_match(&path) {
+            paths.push(path);
+        }
+    }
+    if paths.is_empty() {
+        return Err(no_match());
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn names_that_arent_utf8_can_be_excluded() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let filter = PathFilter::new(&[], &["*.pdb".to_string(), "tmp/**".to_string()]).unwrap();
+        let name = OsStr::from_bytes(b"caf\xe9.pdb");
+        assert!(filter.is_file_excluded(Path::new(name)));
+        assert!(filter.is_dir_excluded(&Path::new("tmp").join(name)));
+        assert!(!filter.is_file_excluded(&Path::new(name).with_extension("bin")));
+
+        let filter = PathFilter::new(&["*.bin".to_string()], &[]).unwrap();
+        assert!(filter.is_file_excluded(Path::new(name)));
+    }
+}
@@ -0,0 +1,99 @@
+//! The BIS partitions of the eMMC, encrypted with AES-XTS by the console
+//! unique BIS keys. PRODINFO holds the calibration data of the console, SAFE,
+//! SYSTEM and USER are FAT32 filesystems.
+
+use crate::error::Error;
+use crate::pki::Keys;
+use crate::utils::read_full;
+use snafu::Backtrace;
+use snafu::GenerateBacktrace;
+use std::fmt;
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+/// Size of the AES-XTS sectors of the BIS partitions.
+pub const BIS_SECTOR_SIZE: usize = 0x4000;
+
+/// An encrypted partition of the eMMC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BisPartition {
+    Prodinfo,
+    ProdinfoF,
+    Safe,
+    System,
+    User,
+}
+
+impl BisPartition {
+    /// Index of the BIS key decrypting the partition.
+    pub fn key_index(self) -> usize {
+        match self {
+            BisPartition::Prodinfo | BisPartition::ProdinfoF => 0,
+            BisPartition::Safe => 1,
+            BisPartition::System => 2,
+            BisPartition::User => 3,
+        }
+    }
+}
+
+impl FromStr for BisPartition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<BisPartition, String> {
+        match s {
+            "prodinfo" => Ok(BisPartition::Prodinfo),
+            "prodinfof" => Ok(BisPartition::ProdinfoF),
+            "safe" => Ok(BisPartition::Safe),
+            "system" => Ok(BisPartition::System),
+            "user" => Ok(BisPartition::User),
+            _ => Err(format!(
+                "Unknown BIS partition {}, expected one of prodinfo, prodinfof, safe, system, user",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for BisPartition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            BisPartition::Prodinfo => "PRODINFO",
+            BisPartition::ProdinfoF => "PRODINFOF",
+            BisPartition::Safe => "SAFE",
+            BisPartition::System => "SYSTEM",
+            BisPartition::User => "USER",
+        })
+    }
+}
+
+/// Decrypts the raw dump of `partition` from `input` to `output`, returning
+/// the size of the partition.
+pub fn decrypt<R: Read, W: Write>(
+    keys: &Keys,
+    partition: BisPartition,
+    mut input: R,
+    mut output: W,
+) -> Result<u64, Error> {
+    let key = keys.bis_key(partition.key_index())?;
+    let mut buf = vec![0; BIS_SECTOR_SIZE * 0x40];
+    let mut sector = 0;
+    loop {
+        let read = read_full(&mut input, &mut buf)?;
+        if read % BIS_SECTOR_SIZE != 0 {
+            return Err(Error::Crypto {
+                error: format!(
+                    "{} is not made of whole {:#x} bytes sectors",
+                    partition, BIS_SECTOR_SIZE
+                ),
+                backtrace: Backtrace::generate(),
+            });
+        }
+        key.decrypt(&mut buf[..read], sector, BIS_SECTOR_SIZE)?;
+        output.write_all(&buf[..read])?;
+        sector += (read / BIS_SECTOR_SIZE) as u64;
+        if read < buf.len() {
+            output.flush()?;
+            return Ok(sector * BIS_SECTOR_SIZE as u64);
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! Raw dumps of the boot partitions, to get at the packages they hold.
+//!
+//! BOOT0 holds Package1 twice, a main copy and a backup one. Each BCPKG2
+//! partition holds a Package2 after its boot config, the Sub partitions being
+//! copies of the Main ones.
+
+use crate::utils::ReadRange;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// Space reserved for each copy of Package1 in BOOT0.
+pub const BOOT0_PACKAGE1_SIZE: u64 = 0x4_0000;
+
+/// Offset of Package2 in a BCPKG2 partition, after the boot config.
+pub const BCPKG2_PACKAGE2_OFFSET: u64 = 0x4000;
+
+/// Space reserved for Package2 in a BCPKG2 partition.
+pub const BCPKG2_PACKAGE2_SIZE: u64 = 0x7F_C000;
+
+/// Copy of a package in the boot partitions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootCopy {
+    Main,
+    Backup,
+}
+
+impl BootCopy {
+    pub const ALL: [BootCopy; 2] = [BootCopy::Main, BootCopy::Backup];
+
+    fn boot0_package1_offset(self) -> u64 {
+        match self {
+            BootCopy::Main => 0x10_0000,
+            BootCopy::Backup => 0x14_0000,
+        }
+    }
+}
+
+impl fmt::Display for BootCopy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BootCopy::Main => write!(f, "main"),
+            BootCopy::Backup => write!(f, "backup"),
+        }
+    }
+}
+
+/// Returns a reader over a copy of Package1 in a BOOT0 dump. It spans the
+/// whole space reserved for the copy, which is padded after the package.
+pub fn boot0_package1<R: Read + Seek>(
+    mut boot0: R,
+    copy: BootCopy,
+) -> io::Result<impl Read + Seek> {
+    let offset = copy.boot0_package1_offset();
+    boot0.seek(SeekFrom::Start(offset))?;
+    Ok(ReadRange::new(boot0, offset, BOOT0_PACKAGE1_SIZE))
+}
+
+/// Returns a reader over the Package2 of a BCPKG2 partition dump. It spans
+/// the whole space reserved for the package, which is padded after it.
+pub fn bcpkg2_package2<R: Read + Seek>(mut bcpkg2: R) -> io::Result<impl Read + Seek> {
+    bcpkg2.seek(SeekFrom::Start(BCPKG2_PACKAGE2_OFFSET))?;
+    Ok(ReadRange::new(
+        bcpkg2,
+        BCPKG2_PACKAGE2_OFFSET,
+        BCPKG2_PACKAGE2_SIZE,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::format::package1::Package1;
+    use byteorder::{ByteOrder, LittleEndian};
+
+    /// A Package1 of 4.0.0 whose PK11, already decrypted, holds a 0x10 bytes
+    /// NX bootloader.
+    fn package1() -> Vec<u8> {
+        let mut data = vec![0; 0x4000 + 0x30];
+        data[0x10..0x1E].copy_from_slice(b"20170921172629");
+        LittleEndian::write_u32(&mut data[0x3FE0..], 0x30);
+        data[0x4000..0x4004].copy_from_slice(b"PK11");
+        LittleEndian::write_u32(&mut data[0x4010..], 0x10);
+        data
+    }
+
+    #[test]
+    fn package1_copies_are_found_in_boot0() {
+        let mut boot0 = vec![0; 0x18_0000];
+        let package1 = package1();
+        boot0[0x14_0000..0x14_0000 + package1.len()].copy_from_slice(&package1);
+        // The main copy is corrupted.
+        boot0[0x10_0000..0x10_0000 + package1.len()].copy_from_slice(&package1);
+        boot0[0x10_0010] = b'X';
+
+        let main = boot0_package1(io::Cursor::new(boot0.clone()), BootCopy::Main).unwrap();
+        assert!(Package1::from_reader(main).is_err());
+        let mut backup = boot0_package1(io::Cursor::new(boot0), BootCopy::Backup).unwrap();
+        assert_eq!(
+            Package1::from_reader(&mut backup).unwrap().size(),
+            package1.len()
+        );
+
+        // The reader spans the space of the copy, starting with the package.
+        let mut data = Vec::new();
+        backup.seek(SeekFrom::Start(0)).unwrap();
+        backup.read_to_end(&mut data).unwrap();
+        assert_eq!(data.len() as u64, BOOT0_PACKAGE1_SIZE);
+        assert_eq!(data[..package1.len()], package1[..]);
+    }
+
+    #[test]
+    fn package2_is_found_after_the_boot_config() {
+        let mut bcpkg2 = vec![0; 0x20_0000];
+        bcpkg2[..0x10].copy_from_slice(&[0xBC; 0x10]);
+        bcpkg2[0x4000..0x4010].copy_from_slice(&[0x52; 0x10]);
+
+        let mut package2 = bcpkg2_package2(io::Cursor::new(bcpkg2)).unwrap();
+        let mut data = [0; 0x10];
+        package2.read_exact(&mut data).unwrap();
+        assert_eq!(data, [0x52; 0x10]);
+        assert_eq!(
+            package2.seek(SeekFrom::End(0)).unwrap(),
+            BCPKG2_PACKAGE2_SIZE
+        );
+    }
+}
@@ -0,0 +1,314 @@
+use crate::error::Error;
+use crate::format::parse::ParseMode;
+use crate::format::tik::{SignatureType, Ticket};
+use crate::format::utils::serialize_hex_bytes;
+use byteorder::{BigEndian, ByteOrder};
+use serde_derive::Serialize;
+use snafu::Backtrace;
+use snafu::GenerateBacktrace;
+use std::collections::HashSet;
+use std::fmt;
+use std::io::Read;
+
+fn invalid_cert(error: &'static str) -> Error {
+    Error::InvalidCert {
+        error,
+        backtrace: Backtrace::generate(),
+    }
+}
+
+/// Issuer of the certificates signed by the root key.
+pub const ROOT_ISSUER: &str = "Root";
+
+/// Public key of a certificate.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PublicKey {
+    Rsa4096 {
+        #[serde(serialize_with = "serialize_hex_bytes")]
+        modulus: Vec<u8>,
+        exponent: u32,
+    },
+    Rsa2048 {
+        #[serde(serialize_with = "serialize_hex_bytes")]
+        modulus: Vec<u8>,
+        exponent: u32,
+    },
+    Ecc(#[serde(serialize_with = "serialize_hex_bytes")] Vec<u8>),
+}
+
+impl PublicKey {
+    fn key_type(&self) -> u32 {
+        match self {
+            PublicKey::Rsa4096 { .. } => 0,
+            PublicKey::Rsa2048 { .. } => 1,
+            PublicKey::Ecc(_) => 2,
+        }
+    }
+
+    /// Size of the public key, padding included.
+    fn size(key_type: u32) -> Option<usize> {
+        match key_type {
+            0 => Some(0x238),
+            1 => Some(0x138),
+            2 => Some(0x78),
+            _ => None,
+        }
+    }
+
+    /// Size of this public key, padding included.
+    fn padded_size(&self) -> usize {
+        match self {
+            PublicKey::Rsa4096 { .. } => 0x238,
+            PublicKey::Rsa2048 { .. } => 0x138,
+            PublicKey::Ecc(_) => 0x78,
+        }
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PublicKey::Rsa4096 { .. } => "RSA-4096",
+            PublicKey::Rsa2048 { .. } => "RSA-2048",
+            PublicKey::Ecc(_) => "ECC",
+        })
+    }
+}
+
+/// A certificate, giving the public key of `name` signed by `issuer`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Certificate {
+    pub signature_type: SignatureType,
+    #[serde(serialize_with = "serialize_hex_bytes")]
+    pub signature: Vec<u8>,
+    pub issuer: String,
+    pub name: String,
+    pub key_id: u32,
+    pub public_key: PublicKey,
+}
+
+fn read_name(data: &[u8]) -> String {
+    let len = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..len]).into_owned()
+}
+
+fn write_name(data: &mut [u8], name: &str) {
+    let name = name.as_bytes();
+    let len = name.len().min(data.len());
+    data[..len].copy_from_slice(&name[..len]);
+}
+
+impl Certificate {
+    /// Parses the certificate at the start of `data`. Returns it along with
+    /// its size.
+    pub fn from_bytes(data: &[u8]) -> Result<(Certificate, usize), Error> {
+        if data.len() < 4 {
+            return Err(invalid_cert("the certificate is truncated"));
+        }
+        let signature_type = SignatureType::from_u32(BigEndian::read_u32(data))
+            .ok_or_else(|| invalid_cert("unknown signature type"))?;
+        let offset = signature_type.padded_size();
+        let header = data
+            .get(offset..offset + 0x88)
+            .ok_or_else(|| invalid_cert("the certificate is truncated"))?;
+        let key_type = BigEndian::read_u32(&header[0x40..]);
+        let key_size =
+            PublicKey::size(key_type).ok_or_else(|| invalid_cert("unknown public key type"))?;
+        let key = data
+            .get(offset + 0x88..offset + 0x88 + key_size)
+            .ok_or_else(|| invalid_cert("the certificate is truncated"))?;
+        let public_key = match key_type {
+            0 => PublicKey::Rsa4096 {
+                modulus: key[..0x200].to_vec(),
+                exponent: BigEndian::read_u32(&key[0x200..]),
+            },
+            1 => PublicKey::Rsa2048 {
+                modulus: key[..0x100].to_vec(),
+                exponent: BigEndian::read_u32(&key[0x100..]),
+            },
+            _ => PublicKey::Ecc(key[..0x3C].to_vec()),
+        };
+
+        let cert = Certificate {
+            signature_type,
+            signature: data[4..4 + signature_type.size()].to_vec(),
+            issuer: read_name(&header[..0x40]),
+            name: read_name(&header[0x44..0x84]),
+            key_id: BigEndian::read_u32(&header[0x84..]),
+            public_key,
+        };
+        Ok((cert, offset + 0x88 + key_size))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let offset = self.signature_type.padded_size();
+        let key_type = self.public_key.key_type();
+        let mut data = vec![0; offset + 0x88 + self.public_key.padded_size()];
+        BigEndian::write_u32(&mut data, self.signature_type as u32);
+        let signature_len = self.signature.len().min(self.signature_type.size());
+        data[4..4 + signature_len].copy_from_slice(&self.signature[..signature_len]);
+
+        let cert = &mut data[offset..];
+        write_name(&mut cert[..0x40], &self.issuer);
+        BigEndian::write_u32(&mut cert[0x40..], key_type);
+        write_name(&mut cert[0x44..0x84], &self.name);
+        BigEndian::write_u32(&mut cert[0x84..], self.key_id);
+        let key = &mut cert[0x88..];
+        match &self.public_key {
+            PublicKey::Rsa4096 { modulus, exponent } => {
+                key[..0x200].copy_from_slice(modulus);
+                BigEndian::write_u32(&mut key[0x200..], *exponent);
+            }
+            PublicKey::Rsa2048 { modulus, exponent } => {
+                key[..0x100].copy_from_slice(modulus);
+                BigEndian::write_u32(&mut key[0x100..], *exponent);
+            }
+            PublicKey::Ecc(point) => key[..0x3C].copy_from_slice(point),
+        }
+        data
+    }
+
+    /// Full name of the certificate, which the certificates and tickets it
+    /// signs give as their issuer.
+    pub fn full_name(&self) -> String {
+        format!("{}-{}", self.issuer, self.name)
+    }
+}
+
+/// A chain of certificates, as found in `.cert` files.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CertChain {
+    pub certs: Vec<Certificate>,
+}
+
+impl CertChain {
+    pub fn from_reader<R: Read>(reader: R) -> Result<CertChain, Error> {
+        CertChain::from_reader_with_mode(reader, ParseMode::Lenient)
+    }
+
+    /// Same as `from_reader`, checking the file as told by `mode`.
+    pub fn from_reader_with_mode<R: Read>(
+        mut reader: R,
+        mode: ParseMode,
+    ) -> Result<CertChain, Error> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        CertChain::from_bytes_with_mode(&data, mode)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<CertChain, Error> {
+        CertChain::from_bytes_with_mode(data, ParseMode::Lenient)
+    }
+
+    /// Same as `from_bytes`, checking the file as told by `mode`. The sizes
+    /// of certificates only depend on their types, so strict mode only
+    /// rejects chains with several certificates of the same name, of which
+    /// `get` would silently pick the first one.
+    pub fn from_bytes_with_mode(mut data: &[u8], mode: ParseMode) -> Result<CertChain, Error> {
+        let mut certs = Vec::new();
+        let mut names = HashSet::new();
+        while !data.is_empty() {
+            let (cert, size) = Certificate::from_bytes(data)?;
+            if mode.is_strict() && !names.insert(cert.full_name()) {
+                return Err(invalid_cert("certificate names must be unique"));
+            }
+            certs.push(cert);
+            data = &data[size..];
+        }
+        Ok(CertChain { certs })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.certs.iter().flat_map(|cert| cert.to_bytes()).collect()
+    }
+
+    /// Finds the certificate with the given full name.
+    pub fn get(&self, full_name: &str) -> Option<&Certificate> {
+        self.certs.iter().find(|cert| cert.full_name() == full_name)
+    }
+
+    /// Checks that every certificate is issued by the root or by a
+    /// certificate found before it in the chain, and that its signature type
+    /// matches the public key of its issuer.
+    pub fn validate(&self) -> Result<(), Error> {
+        for (i, cert) in self.certs.iter().enumerate() {
+            if cert.issuer == ROOT_ISSUER {
+                continue;
+            }
+            let issuer = self.certs[..i]
+                .iter()
+                .find(|v| v.full_name() == cert.issuer)
+                .ok_or_else(|| invalid_cert("a certificate comes before its issuer"))?;
+            let matches = matches!(
+                (&issuer.public_key, cert.signature_type),
+                (PublicKey::Rsa4096 { .. }, SignatureType::Rsa4096Sha1)
+                    | (PublicKey::Rsa4096 { .. }, SignatureType::Rsa4096Sha256)
+                    | (PublicKey::Rsa2048 { .. }, SignatureType::Rsa2048Sha1)
+                    | (PublicKey::Rsa2048 { .. }, SignatureType::Rsa2048Sha256)
+                    | (PublicKey::Ecc(_), SignatureType::EcdsaSha1)
+                    | (PublicKey::Ecc(_), SignatureType::EcdsaSha256)
+            );
+            if !matches {
+                return Err(invalid_cert(
+                    "a signature type doesn't match the key of its issuer",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Extracts the chain of certificates needed to check a signature by
+    /// `issuer`, e.g. `Root-CA00000003-XS00000020`, ordered from the one
+    /// signed by the root.
+    pub fn chain_for(&self, issuer: &str) -> Result<CertChain, Error> {
+        let mut certs = Vec::new();
+        let mut name = issuer;
+        while name != ROOT_ISSUER {
+            let cert = self
+                .get(name)
+                .ok_or_else(|| invalid_cert("a certificate of the chain is missing"))?;
+            certs.insert(0, cert.clone());
+            name = &cert.issuer;
+        }
+        Ok(CertChain { certs })
+    }
+
+    /// Extracts the chain of certificates checking `ticket`, to be bundled
+    /// with it in NSPs under the same name with a `.cert` extension.
+    pub fn chain_for_ticket(&self, ticket: &Ticket) -> Result<CertChain, Error> {
+        self.chain_for(&ticket.issuer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn strict_mode_rejects_duplicate_certificates() {
+        let cert = Certificate {
+            signature_type: SignatureType::Rsa4096Sha256,
+            signature: vec![0xFF; SignatureType::Rsa4096Sha256.size()],
+            issuer: ROOT_ISSUER.to_string(),
+            name: "CA00000003".to_string(),
+            key_id: 0,
+            public_key: PublicKey::Rsa2048 {
+                modulus: vec![0xFF; 0x100],
+                exponent: 0x10001,
+            },
+        };
+        let chain = CertChain {
+            certs: vec![cert.clone()],
+        };
+        let data = chain.to_bytes();
+        CertChain::from_bytes_with_mode(&data, ParseMode::Strict).unwrap();
+
+        let chain = CertChain {
+            certs: vec![cert.clone(), cert],
+        };
+        let data = chain.to_bytes();
+        assert_eq!(CertChain::from_bytes(&data).unwrap().certs.len(), 2);
+        assert!(CertChain::from_bytes_with_mode(&data, ParseMode::Strict).is_err());
+    }
+}
@@ -0,0 +1,656 @@
+use crate::error::Error;
+use crate::format::parse::{ParseMode, Regions};
+use crate::format::utils::{serialize_hex_bytes, serialize_title_id};
+use byteorder::{ByteOrder, LittleEndian};
+use serde_derive::Serialize;
+use sha2::{Digest, Sha256};
+use snafu::Backtrace;
+use snafu::GenerateBacktrace;
+use std::fmt;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+
+fn invalid_cnmt(error: &'static str) -> Error {
+    Error::InvalidCnmt {
+        error,
+        backtrace: Backtrace::generate(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContentMetaType {
+    SystemProgram = 0x01,
+    SystemData = 0x02,
+    SystemUpdate = 0x03,
+    BootImagePackage = 0x04,
+    BootImagePackageSafe = 0x05,
+    Application = 0x80,
+    Patch = 0x81,
+    AddOnContent = 0x82,
+    Delta = 0x83,
+}
+
+impl ContentMetaType {
+    pub fn from_u8(value: u8) -> Option<ContentMetaType> {
+        match value {
+            0x01 => Some(ContentMetaType::SystemProgram),
+            0x02 => Some(ContentMetaType::SystemData),
+            0x03 => Some(ContentMetaType::SystemUpdate),
+            0x04 => Some(ContentMetaType::BootImagePackage),
+            0x05 => Some(ContentMetaType::BootImagePackageSafe),
+            0x80 => Some(ContentMetaType::Application),
+            0x81 => Some(ContentMetaType::Patch),
+            0x82 => Some(ContentMetaType::AddOnContent),
+            0x83 => Some(ContentMetaType::Delta),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ContentMetaType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ContentMetaType::SystemProgram => "SystemProgram",
+            ContentMetaType::SystemData => "SystemData",
+            ContentMetaType::SystemUpdate => "SystemUpdate",
+            ContentMetaType::BootImagePackage => "BootImagePackage",
+            ContentMetaType::BootImagePackageSafe => "BootImagePackageSafe",
+            ContentMetaType::Application => "Application",
+            ContentMetaType::Patch => "Patch",
+            ContentMetaType::AddOnContent => "AddOnContent",
+            ContentMetaType::Delta => "Delta",
+        })
+    }
+}
+
+impl FromStr for ContentMetaType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ContentMetaType, String> {
+        match s {
+            "application" => Ok(ContentMetaType::Application),
+            "patch" => Ok(ContentMetaType::Patch),
+            "add_on_content" => Ok(ContentMetaType::AddOnContent),
+            _ => Err(format!(
+                "Unknown content meta type {}, expected one of application, patch, add_on_content",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContentType {
+    Meta = 0,
+    Program = 1,
+    Data = 2,
+    Control = 3,
+    HtmlDocument = 4,
+    LegalInformation = 5,
+    DeltaFragment = 6,
+}
+
+impl ContentType {
+    pub fn from_u8(value: u8) -> Option<ContentType> {
+        match value {
+            0 => Some(ContentType::Meta),
+            1 => Some(ContentType::Program),
+            2 => Some(ContentType::Data),
+            3 => Some(ContentType::Control),
+            4 => Some(ContentType::HtmlDocument),
+            5 => Some(ContentType::LegalInformation),
+            6 => Some(ContentType::DeltaFragment),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for ContentType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ContentType, String> {
+        match s {
+            "program" => Ok(ContentType::Program),
+            "data" => Ok(ContentType::Data),
+            "control" => Ok(ContentType::Control),
+            "html_document" => Ok(ContentType::HtmlDocument),
+            "legal_information" => Ok(ContentType::LegalInformation),
+            "delta_fragment" => Ok(ContentType::DeltaFragment),
+            _ => Err(format!(
+                "Unknown content type {}, expected one of program, data, control, html_document, legal_information, delta_fragment",
+                s
+            )),
+        }
+    }
+}
+
+/// An NCA of a title.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ContentRecord {
+    /// SHA-256 of the NCA.
+    #[serde(serialize_with = "serialize_hex_bytes")]
+    pub hash: [u8; 0x20],
+    /// ID of the NCA, its file name in NSPs and on the console.
+    #[serde(serialize_with = "serialize_hex_bytes")]
+    pub nca_id: [u8; 0x10],
+    pub size: u64,
+    pub content_type: ContentType,
+    pub id_offset: u8,
+}
+
+impl ContentRecord {
+    /// Makes the record of an NCA of `size` bytes hashing to `hash`. Its ID
+    /// is the first half of the hash.
+    pub fn new(hash: [u8; 0x20], size: u64, content_type: ContentType) -> ContentRecord {
+        let mut nca_id = [0; 0x10];
+        nca_id.copy_from_slice(&hash[..0x10]);
+        ContentRecord {
+            hash,
+            nca_id,
+            size,
+            content_type,
+            id_offset: 0,
+        }
+    }
+
+    /// Makes the record of the NCA at `path`, hashing it.
+    pub fn from_file(path: &Path, content_type: ContentType) -> Result<ContentRecord, Error> {
+        let file = std::fs::File::open(path).map_err(|err| (err, path))?;
+        ContentRecord::from_reader(file, content_type).map_err(|err| (err, path).into())
+    }
+
+    /// Makes the record of the NCA read from `reader`, hashing it.
+    pub fn from_reader<R: Read>(
+        mut reader: R,
+        content_type: ContentType,
+    ) -> std::io::Result<ContentRecord> {
+        let mut hasher = Sha256::default();
+        let mut buf = vec![0; 0x10000];
+        let mut size = 0;
+        loop {
+            let read = reader.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buf[..read]);
+            size += read as u64;
+        }
+        Ok(ContentRecord::new(
+            hasher.finalize().into(),
+            size,
+            content_type,
+        ))
+    }
+
+    /// File name of the NCA: its ID in lowercase hexadecimal.
+    pub fn file_name(&self) -> String {
+        let id: String = self.nca_id.iter().map(|v| format!("{:02x}", v)).collect();
+        format!("{}.nca", id)
+    }
+}
+
+/// A title listed by a CNMT, e.g. by a system update.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ContentMetaRecord {
+    #[serde(serialize_with = "serialize_title_id")]
+    pub title_id: u64,
+    pub title_version: u32,
+    pub meta_type: ContentMetaType,
+    pub attributes: u8,
+}
+
+/// Highest index of the add-on content of an application.
+pub const MAX_ADD_ON_CONTENT_INDEX: u16 = 2000;
+
+/// Title ID of the add-on content `index` of `application_id`, from 1 to
+/// `MAX_ADD_ON_CONTENT_INDEX`. They follow the ID of the application plus
+/// 0x1000.
+pub fn add_on_content_id(application_id: u64, index: u16) -> Result<u64, Error> {
+    if application_id & 0xFFF != 0 {
+        return Err(invalid_cnmt(
+            "add-on content needs the ID of an application",
+        ));
+    }
+    if index == 0 || index > MAX_ADD_ON_CONTENT_INDEX {
+        return Err(invalid_cnmt(
+            "the index of add-on content must be between 1 and 2000",
+        ));
+    }
+    Ok(application_id + 0x1000 + index as u64)
+}
+
+/// The extended header of a CNMT, depending on its type.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ExtendedHeader {
+    None,
+    Application {
+        #[serde(serialize_with = "serialize_title_id")]
+        patch_id: u64,
+        required_system_version: u32,
+        required_application_version: u32,
+    },
+    Patch {
+        #[serde(serialize_with = "serialize_title_id")]
+        application_id: u64,
+        required_system_version: u32,
+    },
+    AddOnContent {
+        #[serde(serialize_with = "serialize_title_id")]
+        application_id: u64,
+        required_application_version: u32,
+    },
+    Delta {
+        #[serde(serialize_with = "serialize_title_id")]
+        application_id: u64,
+    },
+}
+
+/// The content meta of a title, listing its NCAs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Cnmt {
+    #[serde(serialize_with = "serialize_title_id")]
+    pub title_id: u64,
+    pub title_version: u32,
+    pub meta_type: ContentMetaType,
+    pub attributes: u8,
+    pub required_download_system_version: u32,
+    pub extended_header: ExtendedHeader,
+    pub contents: Vec<ContentRecord>,
+    /// Titles this one is made of, for system updates.
+    pub content_metas: Vec<ContentMetaRecord>,
+}
+
+impl Cnmt {
+    /// Makes the content meta of a title of type `meta_type`, filling the
+    /// extended header with the IDs derived from `title_id`.
+    pub fn new(meta_type: ContentMetaType, title_id: u64, title_version: u32) -> Cnmt {
+        let extended_header = match meta_type {
+            ContentMetaType::Application => ExtendedHeader::Application {
+                patch_id: title_id | 0x800,
+                required_system_version: 0,
+                required_application_version: 0,
+            },
+            ContentMetaType::Patch => ExtendedHeader::Patch {
+                application_id: title_id & !0x800,
+                required_system_version: 0,
+            },
+            ContentMetaType::AddOnContent => ExtendedHeader::AddOnContent {
+                application_id: (title_id - 0x1000) & !0xFFF,
+                required_application_version: 0,
+            },
+            _ => ExtendedHeader::None,
+        };
+        Cnmt {
+            title_id,
+            title_version,
+            meta_type,
+            attributes: 0,
+            required_download_system_version: 0,
+            extended_header,
+            contents: Vec::new(),
+            content_metas: Vec::new(),
+        }
+    }
+
+    /// ID of the application the title belongs to, the program ID of its
+    /// first program. `None` for titles that aren't part of an application.
+    pub fn application_id(&self) -> Option<u64> {
+        match self.extended_header {
+            ExtendedHeader::Application { .. } => Some(self.title_id),
+            ExtendedHeader::Patch { application_id, .. }
+            | ExtendedHeader::Delta { application_id } => Some(application_id),
+            _ => None,
+        }
+    }
+
+    /// Program ID of the program `program_index` of the application of a
+    /// multi-program title, as found in the header of its program NCA.
+    pub fn program_id(&self, program_index: u8) -> Option<u64> {
+        self.application_id()
+            .map(|application_id| application_id + program_index as u64)
+    }
+
+    /// Adds `content` to the title. Each program of the title, given by the
+    /// ID offset of the records, has at most one content of each type, delta
+    /// fragments aside.
+    pub fn push_content(&mut self, content: ContentRecord) -> Result<(), Error> {
+        let is_duplicate = content.content_type != ContentType::DeltaFragment
+            && self.contents.iter().any(|other| {
+                other.content_type == content.content_type && other.id_offset == content.id_offset
+            });
+        if is_duplicate {
+            return Err(invalid_cnmt(
+                "a program of the title has two contents of the same type",
+            ));
+        }
+        self.contents.push(content);
+        Ok(())
+    }
+
+    /// Name of the CNMT file inside the meta NCA.
+    pub fn file_name(&self) -> String {
+        format!("{}_{:016x}.cnmt", self.meta_type, self.title_id)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let extended_header_size = match self.extended_header {
+            ExtendedHeader::None => 0,
+            ExtendedHeader::Application { .. }
+            | ExtendedHeader::AddOnContent { .. }
+            | ExtendedHeader::Delta { .. } => 0x10,
+            ExtendedHeader::Patch { .. } => 0x18,
+        };
+        let content_metas_offset = 0x20 + extended_header_size + self.contents.len() * 0x38;
+        let mut data = vec![0; content_metas_offset + self.content_metas.len() * 0x10 + 0x20];
+
+        LittleEndian::write_u64(&mut data[0x0..], self.title_id);
+        LittleEndian::write_u32(&mut data[0x8..], self.title_version);
+        data[0xC] = self.meta_type as u8;
+        LittleEndian::write_u16(&mut data[0xE..], extended_header_size as u16);
+        LittleEndian::write_u16(&mut data[0x10..], self.contents.len() as u16);
+        LittleEndian::write_u16(&mut data[0x12..], self.content_metas.len() as u16);
+        data[0x14] = self.attributes;
+        LittleEndian::write_u32(&mut data[0x18..], self.required_download_system_version);
+
+        let extended_header = &mut data[0x20..];
+        match self.extended_header {
+            ExtendedHeader::None => (),
+            ExtendedHeader::Application {
+                patch_id,
+                required_system_version,
+                required_application_version,
+            } => {
+                LittleEndian::write_u64(&mut extended_header[0x0..], patch_id);
+                LittleEndian::write_u32(&mut extended_header[0x8..], required_system_version);
+                LittleEndian::write_u32(&mut extended_header[0xC..], required_application_version);
+            }
+            ExtendedHeader::Patch {
+                application_id,
+                required_system_version,
+            } => {
+                LittleEndian::write_u64(&mut extended_header[0x0..], application_id);
+                LittleEndian::write_u32(&mut extended_header[0x8..], required_system_version);
+            }
+            ExtendedHeader::AddOnContent {
+                application_id,
+                required_application_version,
+            } => {
+                LittleEndian::write_u64(&mut extended_header[0x0..], application_id);
+                LittleEndian::write_u32(&mut extended_header[0x8..], required_application_version);
+            }
+            ExtendedHeader::Delta { application_id } => {
+                LittleEndian::write_u64(&mut extended_header[0x0..], application_id);
+            }
+        }
+
+        for (i, content) in self.contents.iter().enumerate() {
+            let record = &mut data[0x20 + extended_header_size + i * 0x38..];
+            record[0x0..0x20].copy_from_slice(&content.hash);
+            record[0x20..0x30].copy_from_slice(&content.nca_id);
+            LittleEndian::write_u48(&mut record[0x30..], content.size);
+            record[0x36] = content.content_type as u8;
+            record[0x37] = content.id_offset;
+        }
+        for (i, content_meta) in self.content_metas.iter().enumerate() {
+            let record = &mut data[content_metas_offset + i * 0x10..];
+            LittleEndian::write_u64(&mut record[0x0..], content_meta.title_id);
+            LittleEndian::write_u32(&mut record[0x8..], content_meta.title_version);
+            record[0xC] = content_meta.meta_type as u8;
+            record[0xD] = content_meta.attributes;
+        }
+        data
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Cnmt, Error> {
+        Cnmt::from_bytes_with_mode(data, ParseMode::Lenient)
+    }
+
+    /// Same as `from_bytes`, checking the file as told by `mode`. Strict mode
+    /// rejects extended headers too small for the meta type, which are
+    /// otherwise ignored, and checks that the extended data and the digest
+    /// follow the records up to the end of the file.
+    pub fn from_bytes_with_mode(data: &[u8], mode: ParseMode) -> Result<Cnmt, Error> {
+        if data.len() < 0x20 {
+            return Err(invalid_cnmt("header is truncated"));
+        }
+        let meta_type = ContentMetaType::from_u8(data[0xC])
+            .ok_or_else(|| invalid_cnmt("unknown content meta type"))?;
+        let extended_header_size = LittleEndian::read_u16(&data[0xE..]) as usize;
+        let content_count = LittleEndian::read_u16(&data[0x10..]) as usize;
+        let content_meta_count = LittleEndian::read_u16(&data[0x12..]) as usize;
+        let records_offset = 0x20 + extended_header_size;
+        let content_metas_offset = records_offset + content_count * 0x38;
+        if data.len() < content_metas_offset + content_meta_count * 0x10 {
+            return Err(invalid_cnmt("content records are truncated"));
+        }
+
+        let extended_header = &data[0x20..records_offset];
+        let extended_header = match meta_type {
+            ContentMetaType::Application if extended_header.len() >= 0x10 => {
+                ExtendedHeader::Application {
+                    patch_id: LittleEndian::read_u64(&extended_header[0x0..]),
+                    required_system_version: LittleEndian::read_u32(&extended_header[0x8..]),
+                    required_application_version: LittleEndian::read_u32(&extended_header[0xC..]),
+                }
+            }
+            ContentMetaType::Patch if extended_header.len() >= 0x10 => ExtendedHeader::Patch {
+                application_id: LittleEndian::read_u64(&extended_header[0x0..]),
+                required_system_version: LittleEndian::read_u32(&extended_header[0x8..]),
+            },
+            ContentMetaType::AddOnContent if extended_header.len() >= 0x10 => {
+                ExtendedHeader::AddOnContent {
+                    application_id: LittleEndian::read_u64(&extended_header[0x0..]),
+                    required_application_version: LittleEndian::read_u32(&extended_header[0x8..]),
+                }
+            }
+            ContentMetaType::Delta if extended_header.len() >= 0x10 => ExtendedHeader::Delta {
+                application_id: LittleEndian::read_u64(&extended_header[0x0..]),
+            },
+            _ => ExtendedHeader::None,
+        };
+        if mode.is_strict() {
+            check_layout(data, meta_type, records_offset, content_metas_offset)?;
+        }
+
+        let mut contents = Vec::with_capacity(content_count);
+        for i in 0..content_count {
+            let record = &data[records_offset + i * 0x38..];
+            let mut hash = [0; 0x20];
+            hash.copy_from_slice(&record[0x0..0x20]);
+            let mut nca_id = [0; 0x10];
+            nca_id.copy_from_slice(&record[0x20..0x30]);
+            contents.push(ContentRecord {
+                hash,
+                nca_id,
+                size: LittleEndian::read_u48(&record[0x30..]),
+                content_type: ContentType::from_u8(record[0x36])
+                    .ok_or_else(|| invalid_cnmt("unknown content type"))?,
+                id_offset: record[0x37],
+            });
+        }
+
+        let mut content_metas = Vec::with_capacity(content_meta_count);
+        for i in 0..content_meta_count {
+            let record = &data[content_metas_offset + i * 0x10..];
+            content_metas.push(ContentMetaRecord {
+                title_id: LittleEndian::read_u64(&record[0x0..]),
+                title_version: LittleEndian::read_u32(&record[0x8..]),
+                meta_type: ContentMetaType::from_u8(record[0xC])
+                    .ok_or_else(|| invalid_cnmt("unknown content meta type"))?,
+                attributes: record[0xD],
+            });
+        }
+
+        Ok(Cnmt {
+            title_id: LittleEndian::read_u64(&data[0x0..]),
+            title_version: LittleEndian::read_u32(&data[0x8..]),
+            meta_type,
+            attributes: data[0x14],
+            required_download_system_version: LittleEndian::read_u32(&data[0x18..]),
+            extended_header,
+            contents,
+            content_metas,
+        })
+    }
+
+    pub fn from_reader<R: Read>(reader: R) -> Result<Cnmt, Error> {
+        Cnmt::from_reader_with_mode(reader, ParseMode::Lenient)
+    }
+
+    /// Same as `from_reader`, checking the file as told by `mode`.
+    pub fn from_reader_with_mode<R: Read>(mut reader: R, mode: ParseMode) -> Result<Cnmt, Error> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        Cnmt::from_bytes_with_mode(&data, mode)
+    }
+}
+
+/// Checks the layout of a CNMT in strict mode: the extended header must be
+/// big enough for the meta type, and the extended data its size is given by
+/// and the digest must follow the content meta records, up to the end of
+/// the file.
+fn check_layout(
+    data: &[u8],
+    meta_type: ContentMetaType,
+    records_offset: usize,
+    content_metas_offset: usize,
+) -> Result<(), Error> {
+    let extended_header = &data[0x20..records_offset];
+    // The size of the extended data is in the extended header of the types
+    // having some.
+    let (header_size, extended_data_size) = match meta_type {
+        ContentMetaType::Application | ContentMetaType::AddOnContent => (0x10, None),
+        ContentMetaType::Patch => (0x18, Some(0xC)),
+        ContentMetaType::Delta => (0x10, Some(0x8)),
+        _ => (0, None),
+    };
+    if extended_header.len() < header_size {
+        return Err(invalid_cnmt(
+            "extended header is too small for the meta type",
+        ));
+    }
+    let extended_data_size = extended_data_size.map_or(0, |offset| {
+        LittleEndian::read_u32(&extended_header[offset..])
+    });
+
+    let content_count = LittleEndian::read_u16(&data[0x10..]) as u64;
+    let content_meta_count = LittleEndian::read_u16(&data[0x12..]) as u64;
+    let extended_data_offset = content_metas_offset as u64 + content_meta_count * 0x10;
+    let digest_offset = extended_data_offset + u64::from(extended_data_size);
+    let mut regions = Regions::new(data.len() as u64);
+    regions.add("header", 0, 0x20)?;
+    regions.add("extended header", 0x20, extended_header.len() as u64)?;
+    regions.add(
+        "content records",
+        records_offset as u64,
+        content_count * 0x38,
+    )?;
+    regions.add(
+        "content meta records",
+        content_metas_offset as u64,
+        content_meta_count * 0x10,
+    )?;
+    regions.add(
+        "extended data",
+        extended_data_offset,
+        extended_data_size.into(),
+    )?;
+    regions.add("digest", digest_offset, 0x20)?;
+    if digest_offset + 0x20 != data.len() as u64 {
+        return Err(invalid_cnmt("data follows the digest"));
+    }
+    regions.check_overlaps()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cnmt_round_trips() {
+        let mut cnmt = Cnmt::new(ContentMetaType::Patch, 0x0100_0000_0000_1800, 0x10000);
+        cnmt.contents.push(ContentRecord::new(
+            [0x42; 0x20],
+            0x1234,
+            ContentType::Program,
+        ));
+        let data = cnmt.to_bytes();
+        assert_eq!(data.len(), 0x20 + 0x18 + 0x38 + 0x20);
+        assert_eq!(Cnmt::from_bytes(&data).unwrap(), cnmt);
+        assert_eq!(
+            cnmt.extended_header,
+            ExtendedHeader::Patch {
+                application_id: 0x0100_0000_0000_1000,
+                required_system_version: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn contents_are_unique_per_program() {
+        let mut cnmt = Cnmt::new(ContentMetaType::Application, 0x0100_0000_0000_1000, 0);
+        let program = |id_offset| ContentRecord {
+            id_offset,
+            ..ContentRecord::new([0x42; 0x20], 0x1234, ContentType::Program)
+        };
+        cnmt.push_content(program(0)).unwrap();
+        cnmt.push_content(program(1)).unwrap();
+        assert!(cnmt.push_content(program(1)).is_err());
+        assert_eq!(cnmt.program_id(1), Some(0x0100_0000_0000_1001));
+
+        let data = cnmt.to_bytes();
+        assert_eq!(Cnmt::from_bytes(&data).unwrap().contents[1].id_offset, 1);
+    }
+
+    #[test]
+    fn add_on_content_ids() {
+        let id = add_on_content_id(0x0100_0000_0000_A000, 3).unwrap();
+        assert_eq!(id, 0x0100_0000_0000_B003);
+        assert_eq!(
+            Cnmt::new(ContentMetaType::AddOnContent, id, 0).extended_header,
+            ExtendedHeader::AddOnContent {
+                application_id: 0x0100_0000_0000_A000,
+                required_application_version: 0,
+            }
+        );
+        assert!(add_on_content_id(0x0100_0000_0000_A800, 1).is_err());
+        assert!(add_on_content_id(0x0100_0000_0000_A000, 0).is_err());
+    }
+
+    #[test]
+    fn system_update_round_trips() {
+        let mut cnmt = Cnmt::new(ContentMetaType::SystemUpdate, 0x0100_0000_0000_0816, 0x100);
+        cnmt.content_metas.push(ContentMetaRecord {
+            title_id: 0x0100_0000_0000_0809,
+            title_version: 0x100,
+            meta_type: ContentMetaType::SystemData,
+            attributes: 0,
+        });
+        let data = cnmt.to_bytes();
+        assert_eq!(data.len(), 0x20 + 0x10 + 0x20);
+        assert_eq!(Cnmt::from_bytes(&data).unwrap(), cnmt);
+    }
+
+    #[test]
+    fn strict_mode_checks_the_layout() {
+        let cnmt = Cnmt::new(ContentMetaType::Patch, 0x0100_0000_0000_1800, 0x10000);
+        let mut data = cnmt.to_bytes();
+        assert_eq!(
+            Cnmt::from_bytes_with_mode(&data, ParseMode::Strict).unwrap(),
+            cnmt
+        );
+        data.push(0);
+        Cnmt::from_bytes(&data).unwrap();
+        assert!(Cnmt::from_bytes_with_mode(&data, ParseMode::Strict).is_err());
+
+        // The extended header of an application is too small for a patch.
+        let mut data = Cnmt::new(ContentMetaType::Application, 0x0100_0000_0000_1000, 0).to_bytes();
+        data[0xC] = ContentMetaType::Patch as u8;
+        Cnmt::from_bytes(&data).unwrap();
+        assert!(Cnmt::from_bytes_with_mode(&data, ParseMode::Strict).is_err());
+    }
+}
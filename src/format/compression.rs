@@ -0,0 +1,87 @@
+//! Compression of the segments of NSOs and KIPs.
+
+use std::io;
+
+/// Compresses the segments of NSOs and KIPs.
+///
+/// The writers use `Lz4` for NSOs and `Blz` for KIPs, but any implementation
+/// producing the same format, like a faster encoder or one offloading the work
+/// to hardware, can be given to `NxoFile::write_nso_with`,
+/// `NxoFile::write_kip1_with` and `NsoBuilder::build_with` instead.
+pub trait Compressor {
+    /// Compresses a segment, or returns `None` to store it uncompressed. The
+    /// writers set the compression flags of the header accordingly.
+    fn compress(&self, data: &[u8]) -> io::Result<Option<Vec<u8>>>;
+}
+
+/// Stores segments uncompressed, which the loader accepts too.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stored;
+
+impl Compressor for Stored {
+    fn compress(&self, _data: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+}
+
+/// LZ4 block compression, the one of NSOs. LZ4 is a C library, so it needs
+/// the `native` feature.
+#[cfg(feature = "native")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lz4 {
+    Default,
+    /// Faster, but compresses less the higher the acceleration is.
+    Fast(i32),
+    /// LZ4-HC, which compresses more but slower, at a level from 1 to 12.
+    HighCompression(i32),
+}
+
+#[cfg(feature = "native")]
+impl Default for Lz4 {
+    fn default() -> Lz4 {
+        Lz4::Default
+    }
+}
+
+#[cfg(feature = "native")]
+impl Compressor for Lz4 {
+    fn compress(&self, data: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        use lz4::block::CompressionMode;
+
+        let mode = match *self {
+            Lz4::Default => CompressionMode::DEFAULT,
+            Lz4::Fast(acceleration) => CompressionMode::FAST(acceleration),
+            Lz4::HighCompression(level) => CompressionMode::HIGHCOMPRESSION(level),
+        };
+        lz4::block::compress(data, Some(mode), false).map(Some)
+    }
+}
+
+/// Backwards LZ, the compression of KIPs.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Blz;
+
+impl Compressor for Blz {
+    fn compress(&self, data: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        // The compressor works in place on its input.
+        let mut data = data.to_vec();
+        let mut compressed = vec![0; blz_nx::get_worst_compression_buffer_size(data.len())];
+        let size = blz_nx::compress_raw(&mut data, &mut compressed)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "BLZ compression failed"))?;
+        compressed.truncate(size);
+        Ok(Some(compressed))
+    }
+}
+
+/// The compressor NSOs are written with by default: `Lz4` with the `native`
+/// feature, `Stored` without it.
+pub fn default_nso_compressor() -> &'static dyn Compressor {
+    #[cfg(feature = "native")]
+    {
+        &Lz4::Default
+    }
+    #[cfg(not(feature = "native"))]
+    {
+        &Stored
+    }
+}
@@ -0,0 +1,93 @@
+//! A common interface over the formats holding files, for tools working on
+//! any of them, like `linkle info`.
+
+use crate::error::Error;
+use crate::format::detect::DETECT_SIZE;
+#[cfg(feature = "crypto")]
+use crate::format::nca::Nca;
+use crate::format::parse::ParseMode;
+use crate::format::pfs0::{Pfs0, ReadSeek};
+use crate::format::romfs::RomFsReader;
+use crate::format::xci::Xci;
+#[cfg(feature = "crypto")]
+use crate::pki::Keys;
+use crate::utils::TryClone;
+use serde_derive::Serialize;
+use std::io::{Read, Seek, SeekFrom};
+
+/// A file of a container.
+#[derive(Debug, Clone, Serialize)]
+pub struct ContainerEntry {
+    pub name: String,
+    pub size: u64,
+}
+
+/// A format holding files: PFS0s and HFS0s, RomFSs, NCAs and XCIs.
+pub trait SwitchFormat {
+    /// Whether `header`, the first `DETECT_SIZE` bytes of a file, is in this
+    /// format.
+    fn detect(header: &[u8]) -> bool
+    where
+        Self: Sized;
+
+    /// The files of the container, in the order they are stored.
+    fn list_entries(&self) -> Result<Vec<ContainerEntry>, Error>;
+
+    /// Opens the file `name`, as given by `list_entries`.
+    fn extract_entry(&mut self, name: &str) -> Result<Box<dyn ReadSeek + '_>, Error>;
+
+    /// What the format knows about the container besides its files, as
+    /// printed by `linkle info --json`.
+    fn metadata(&self) -> Result<serde_json::Value, Error>;
+}
+
+/// Opens the container in `reader`, or returns `None` if it isn't in a
+/// format recognizable without keys.
+pub fn detect<R>(reader: R) -> Result<Option<Box<dyn SwitchFormat>>, Error>
+where
+    R: Read + Seek + TryClone + 'static,
+{
+    detect_with_mode(reader, ParseMode::Lenient)
+}
+
+/// Same as `detect`, opening the container with the checks of `mode`.
+pub fn detect_with_mode<R>(
+    mut reader: R,
+    mode: ParseMode,
+) -> Result<Option<Box<dyn SwitchFormat>>, Error>
+where
+    R: Read + Seek + TryClone + 'static,
+{
+    let mut header = Vec::with_capacity(DETECT_SIZE);
+    reader.seek(SeekFrom::Start(0))?;
+    (&mut reader)
+        .take(DETECT_SIZE as u64)
+        .read_to_end(&mut header)?;
+    reader.seek(SeekFrom::Start(0))?;
+
+    let container: Box<dyn SwitchFormat> = if Pfs0::detect(&header) {
+        Box::new(Pfs0::from_reader_with_mode(reader, mode)?)
+    } else if RomFsReader::<R>::detect(&header) {
+        Box::new(RomFsReader::from_reader_with_mode(reader, mode)?)
+    } else if Xci::<R>::detect(&header) {
+        Box::new(Xci::from_reader_with_mode(reader, mode)?)
+    } else {
+        return Ok(None);
+    };
+    Ok(Some(container))
+}
+
+/// Same as `detect`, but also recognizes NCAs, whose headers are encrypted.
+#[cfg(feature = "crypto")]
+pub fn detect_with_keys<R>(reader: R, keys: &Keys) -> Result<Option<Box<dyn SwitchFormat>>, Error>
+where
+    R: Read + Seek + TryClone + 'static,
+{
+    if let Some(container) = detect(reader.try_clone()?)? {
+        return Ok(Some(container));
+    }
+    match Nca::from_reader(reader, keys) {
+        Ok(nca) => Ok(Some(Box::new(nca))),
+        Err(_) => Ok(None),
+    }
+}
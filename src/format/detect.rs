@@ -0,0 +1,101 @@
+//! Guessing the format of a file from the magic numbers of its header, for
+//! the tools taking any kind of file.
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use serde_derive::Serialize;
+use std::fmt;
+
+/// Bytes from the start of a file `FileFormat::detect` looks at. Shorter
+/// files are fine, the formats needing more are just not detected.
+pub const DETECT_SIZE: usize = 0x400;
+
+/// The formats recognizable by their header alone. NCAs and Package2s have
+/// encrypted headers and need keys to be recognized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileFormat {
+    Npdm,
+    Nso,
+    Nro,
+    Kip,
+    Ini1,
+    Pfs0,
+    Hfs0,
+    RomFs,
+    Save,
+    Nax0,
+    Ticket,
+    CertChain,
+    Xci,
+}
+
+impl FileFormat {
+    /// Guesses the format of the file starting with `header`, which should
+    /// hold its first `DETECT_SIZE` bytes.
+    pub fn detect(header: &[u8]) -> Option<FileFormat> {
+        let magic_at =
+            |offset: usize, magic: &[u8; 4]| header.get(offset..offset + 4) == Some(&magic[..]);
+
+        if magic_at(0, b"META") {
+            Some(FileFormat::Npdm)
+        } else if magic_at(0, b"NSO0") {
+            Some(FileFormat::Nso)
+        } else if magic_at(0x10, b"NRO0") {
+            Some(FileFormat::Nro)
+        } else if magic_at(0, b"KIP1") {
+            Some(FileFormat::Kip)
+        } else if magic_at(0, b"INI1") {
+            Some(FileFormat::Ini1)
+        } else if magic_at(0, b"PFS0") {
+            Some(FileFormat::Pfs0)
+        } else if magic_at(0, b"HFS0") {
+            Some(FileFormat::Hfs0)
+        } else if magic_at(0x100, b"DISF") {
+            Some(FileFormat::Save)
+        } else if magic_at(0x20, b"NAX0") {
+            Some(FileFormat::Nax0)
+        } else if magic_at(0x100, b"HEAD") {
+            Some(FileFormat::Xci)
+        } else if header.len() >= 0x50 && LittleEndian::read_u64(header) == 0x50 {
+            // The RomFS header only starts with its own size.
+            Some(FileFormat::RomFs)
+        } else {
+            detect_signed(header)
+        }
+    }
+}
+
+/// Tickets and certificates both start with their signature type, little
+/// endian in tickets and big endian in certificates.
+fn detect_signed(header: &[u8]) -> Option<FileFormat> {
+    let is_signature_type = |value| (0x10000..=0x10005).contains(&value);
+    if header.len() < 4 {
+        None
+    } else if is_signature_type(LittleEndian::read_u32(header)) {
+        Some(FileFormat::Ticket)
+    } else if is_signature_type(BigEndian::read_u32(header)) {
+        Some(FileFormat::CertChain)
+    } else {
+        None
+    }
+}
+
+impl fmt::Display for FileFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FileFormat::Npdm => "NPDM",
+            FileFormat::Nso => "NSO",
+            FileFormat::Nro => "NRO",
+            FileFormat::Kip => "KIP1",
+            FileFormat::Ini1 => "INI1",
+            FileFormat::Pfs0 => "PFS0",
+            FileFormat::Hfs0 => "HFS0",
+            FileFormat::RomFs => "RomFS",
+            FileFormat::Save => "Save file",
+            FileFormat::Nax0 => "NAX0",
+            FileFormat::Ticket => "Ticket",
+            FileFormat::CertChain => "Certificate chain",
+            FileFormat::Xci => "XCI",
+        })
+    }
+}
@@ -0,0 +1,115 @@
+use crate::error::Error;
+use byteorder::{BigEndian, ByteOrder};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, ImageError};
+use snafu::Backtrace;
+use snafu::GenerateBacktrace;
+use std::path::Path;
+
+/// Width and height of the icons shown by the home menu.
+pub const ICON_SIZE: u32 = 256;
+
+/// Biggest icon the home menu accepts, in bytes.
+pub const ICON_MAX_SIZE: usize = 0x20000;
+
+/// Checks that `data` is an icon the home menu can draw: a baseline JPEG of
+/// 256x256 pixels, without EXIF data, and no bigger than `ICON_MAX_SIZE`.
+/// Returns what is wrong with it otherwise.
+pub fn check_icon(data: &[u8]) -> Result<(), &'static str> {
+    if data.len() > ICON_MAX_SIZE {
+        return Err("the icon is bigger than 128KiB");
+    }
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return Err("the icon isn't a JPEG");
+    }
+
+    // Walk the segments up to the start of the image data.
+    let mut offset = 2;
+    let mut dimensions = None;
+    loop {
+        if offset + 4 > data.len() || data[offset] != 0xFF {
+            return Err("the icon is a truncated or invalid JPEG");
+        }
+        let marker = data[offset + 1];
+        let len = BigEndian::read_u16(&data[offset + 2..]) as usize;
+        let segment = match data.get(offset + 4..offset + 2 + len) {
+            Some(segment) if len >= 2 => segment,
+            _ => return Err("the icon is a truncated or invalid JPEG"),
+        };
+        match marker {
+            // APP1, holding EXIF data.
+            0xE1 if segment.starts_with(b"Exif\0") => {
+                return Err("the icon has EXIF data");
+            }
+            // Baseline DCT.
+            0xC0 => {
+                if segment.len() < 5 {
+                    return Err("the icon is a truncated or invalid JPEG");
+                }
+                let height = BigEndian::read_u16(&segment[1..]) as u32;
+                let width = BigEndian::read_u16(&segment[3..]) as u32;
+                dimensions = Some((width, height));
+            }
+            // Every other start of frame: progressive, lossless, ...
+            0xC1..=0xC3 | 0xC5..=0xC7 | 0xC9..=0xCB | 0xCD..=0xCF => {
+                return Err("the icon isn't a baseline JPEG");
+            }
+            // Start of scan, the headers are over.
+            0xDA => break,
+            _ => (),
+        }
+        offset += 2 + len;
+    }
+
+    match dimensions {
+        Some((ICON_SIZE, ICON_SIZE)) => Ok(()),
+        Some(_) => Err("the icon isn't 256x256"),
+        None => Err("the icon is a truncated or invalid JPEG"),
+    }
+}
+
+/// Loads the icon at `path`, checking it with `check_icon`. Icons failing the
+/// check are converted with `convert_icon` if `reencode` is set, and rejected
+/// otherwise.
+pub fn load_icon(path: &Path, reencode: bool) -> Result<Vec<u8>, Error> {
+    let invalid_icon = |error| Error::InvalidIcon {
+        path: path.to_owned(),
+        error,
+        backtrace: Backtrace::generate(),
+    };
+    let data = std::fs::read(path).map_err(|err| (err, path))?;
+    let data = match check_icon(&data) {
+        Ok(()) => return Ok(data),
+        Err(_) if reencode => convert_icon(path)?,
+        Err(error) => return Err(invalid_icon(error)),
+    };
+    check_icon(&data).map_err(invalid_icon)?;
+    Ok(data)
+}
+
+/// Loads the image at `path`, in any format the `image` crate knows about, and
+/// converts it to the baseline 256x256 JPEG expected by the home menu.
+pub fn convert_icon(path: &Path) -> Result<Vec<u8>, Error> {
+    let image = image::open(path).map_err(|err| (err, path))?;
+    encode_icon(image).map_err(|err| (err, path).into())
+}
+
+/// Converts an image held in memory, like `convert_icon`.
+pub fn convert_icon_data(data: &[u8]) -> Result<Vec<u8>, ImageError> {
+    encode_icon(image::load_from_memory(data)?)
+}
+
+fn encode_icon(image: DynamicImage) -> Result<Vec<u8>, ImageError> {
+    let image = if image.dimensions() != (ICON_SIZE, ICON_SIZE) {
+        image.resize_exact(ICON_SIZE, ICON_SIZE, FilterType::Lanczos3)
+    } else {
+        image
+    };
+    // The home menu doesn't draw icons with an alpha channel.
+    let image = image.to_rgb8();
+
+    let mut data = Vec::new();
+    JpegEncoder::new_with_quality(&mut data, 90).encode_image(&image)?;
+    Ok(data)
+}
@@ -0,0 +1,275 @@
+//! INI1, the bundle of KIP1 processes launched by the kernel at boot. It is
+//! stored in Package2, either as its own section or at the end of the kernel
+//! since 8.0.0.
+
+use crate::error::Error;
+use crate::format::parse::{ParseMode, Regions};
+use crate::utils::align_up;
+use byteorder::{ByteOrder, LittleEndian};
+use snafu::Backtrace;
+use snafu::GenerateBacktrace;
+use std::convert::TryFrom;
+use std::io::{self, Write};
+
+/// Size of the INI1 header.
+pub const INI1_HEADER_SIZE: usize = 0x10;
+
+/// Most KIPs an INI1 can hold.
+pub const INI1_MAX_KIPS: usize = 0x50;
+
+/// Size of the KIP1 header, before the segments.
+const KIP1_HEADER_SIZE: usize = 0x100;
+
+fn invalid_ini1(error: &'static str) -> Error {
+    Error::InvalidIni1 {
+        error,
+        backtrace: Backtrace::generate(),
+    }
+}
+
+/// A KIP1 of an INI1, as is. The segments stay compressed.
+#[derive(Debug, Clone)]
+pub struct Kip {
+    data: Vec<u8>,
+}
+
+impl Kip {
+    /// Checks the header of a KIP1 and takes it along with its segments,
+    /// which must end at the end of `data`.
+    pub fn from_bytes(data: Vec<u8>) -> Result<Kip, Error> {
+        Kip::from_bytes_with_mode(data, ParseMode::Lenient)
+    }
+
+    /// Same as `from_bytes`, checking the KIP1 as told by `mode`. Strict mode
+    /// checks its segments don't overlap once loaded.
+    pub fn from_bytes_with_mode(data: Vec<u8>, mode: ParseMode) -> Result<Kip, Error> {
+        if kip_size(&data)? != data.len() {
+            return Err(invalid_ini1("KIP1 size doesn't match its segments"));
+        }
+        let kip = Kip { data };
+        if mode.is_strict() {
+            kip.check_segments()?;
+        }
+        Ok(kip)
+    }
+
+    /// Checks the segments of the KIP1 don't overlap once loaded, in strict
+    /// mode. Each one is given by its offset in memory and its size there.
+    fn check_segments(&self) -> Result<(), Error> {
+        let name = self.name();
+        let mut regions = Regions::within(format!("the memory of {}", name), 1 << 32);
+        let segments = [
+            (".text", 0x20),
+            (".rodata", 0x30),
+            (".data", 0x40),
+            (".bss", 0x50),
+        ];
+        for &(segment, header_offset) in &segments {
+            regions.add(
+                format!("{} of {}", segment, name),
+                LittleEndian::read_u32(&self.data[header_offset..]).into(),
+                LittleEndian::read_u32(&self.data[header_offset + 0x4..]).into(),
+            )?;
+        }
+        regions.check_overlaps()
+    }
+
+    pub fn name(&self) -> String {
+        let name = &self.data[0x4..0x10];
+        let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+        String::from_utf8_lossy(&name[..len]).into_owned()
+    }
+
+    pub fn program_id(&self) -> u64 {
+        LittleEndian::read_u64(&self.data[0x10..])
+    }
+
+    pub fn version(&self) -> u32 {
+        LittleEndian::read_u32(&self.data[0x18..])
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+/// Returns the size of the KIP1 at the start of `data`, header included.
+fn kip_size(data: &[u8]) -> Result<usize, Error> {
+    if data.len() < KIP1_HEADER_SIZE {
+        return Err(invalid_ini1("KIP1 header is truncated"));
+    }
+    if &data[..4] != b"KIP1" {
+        return Err(invalid_ini1("KIP1 magic is wrong"));
+    }
+    // Only the text, rodata and data segments are stored, bss isn't.
+    let segments_size: u64 = (0..3)
+        .map(|i| u64::from(LittleEndian::read_u32(&data[0x20 + i * 0x10 + 0x8..])))
+        .sum();
+    usize::try_from(KIP1_HEADER_SIZE as u64 + segments_size)
+        .map_err(|_| invalid_ini1("KIP1 is out of bounds"))
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Ini1 {
+    pub kips: Vec<Kip>,
+}
+
+impl Ini1 {
+    /// Parses the INI1 at the start of `data`, which may be followed by
+    /// anything, e.g. the padding of its Package2 section.
+    pub fn from_bytes(data: &[u8]) -> Result<Ini1, Error> {
+        Ini1::from_bytes_with_mode(data, ParseMode::Lenient)
+    }
+
+    /// Same as `from_bytes`, checking the INI1 as told by `mode`. Strict mode
+    /// checks the KIPs fill the INI1 up to its size, and their segments as
+    /// `Kip::from_bytes_with_mode` does.
+    pub fn from_bytes_with_mode(data: &[u8], mode: ParseMode) -> Result<Ini1, Error> {
+        if data.len() < INI1_HEADER_SIZE {
+            return Err(invalid_ini1("header is truncated"));
+        }
+        if &data[..4] != b"INI1" {
+            return Err(invalid_ini1("magic is wrong"));
+        }
+        let size = LittleEndian::read_u32(&data[0x4..]) as usize;
+        let count = LittleEndian::read_u32(&data[0x8..]) as usize;
+        if size < INI1_HEADER_SIZE || size > data.len() {
+            return Err(invalid_ini1("size is out of bounds"));
+        }
+        if count > INI1_MAX_KIPS {
+            return Err(invalid_ini1("too many KIPs"));
+        }
+
+        let mut kips = Vec::with_capacity(count);
+        let mut offset = INI1_HEADER_SIZE;
+        for _ in 0..count {
+            let kip_size = kip_size(&data[offset..size])?;
+            if offset.checked_add(kip_size).map_or(true, |end| end > size) {
+                return Err(invalid_ini1("KIP1 is out of bounds"));
+            }
+            let kip = Kip {
+                data: data[offset..offset + kip_size].to_vec(),
+            };
+            if mode.is_strict() {
+                kip.check_segments()?;
+            }
+            kips.push(kip);
+            offset += kip_size;
+        }
+        // The KIPs are padded to 4 bytes.
+        if mode.is_strict() && size != offset && size != align_up(offset, 4) {
+            return Err(invalid_ini1("data follows the KIPs"));
+        }
+        Ok(Ini1 { kips })
+    }
+
+    /// Size of the INI1 once written.
+    pub fn len(&self) -> usize {
+        align_up(
+            INI1_HEADER_SIZE + self.kips.iter().map(|kip| kip.data.len()).sum::<usize>(),
+            4,
+        )
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.kips.is_empty()
+    }
+
+    /// Replaces the KIP with the same name as `kip`, or adds it if there is
+    /// none. Returns the KIP replaced.
+    pub fn set_kip(&mut self, kip: Kip) -> Option<Kip> {
+        let name = kip.name();
+        match self.kips.iter_mut().find(|v| v.name() == name) {
+            Some(old) => Some(std::mem::replace(old, kip)),
+            None => {
+                self.kips.push(kip);
+                None
+            }
+        }
+    }
+
+    pub fn write<W: Write>(&self, output: &mut W) -> io::Result<()> {
+        if self.kips.len() > INI1_MAX_KIPS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("An INI1 holds at most {} KIPs", INI1_MAX_KIPS),
+            ));
+        }
+        let mut header = [0; INI1_HEADER_SIZE];
+        header[..4].copy_from_slice(b"INI1");
+        LittleEndian::write_u32(&mut header[0x4..], self.len() as u32);
+        LittleEndian::write_u32(&mut header[0x8..], self.kips.len() as u32);
+        output.write_all(&header)?;
+        let mut written = INI1_HEADER_SIZE;
+        for kip in &self.kips {
+            output.write_all(&kip.data)?;
+            written += kip.data.len();
+        }
+        output.write_all(&[0; 4][..self.len() - written])
+    }
+
+    pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(self.len());
+        self.write(&mut data)?;
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A KIP1 with no data, whose segments are loaded at the given offsets
+    /// and sizes.
+    fn kip(segments: [(u32, u32); 4]) -> Vec<u8> {
+        let mut data = vec![0; KIP1_HEADER_SIZE];
+        data[..4].copy_from_slice(b"KIP1");
+        data[0x4..0x8].copy_from_slice(b"Test");
+        for (i, &(offset, size)) in segments.iter().enumerate() {
+            LittleEndian::write_u32(&mut data[0x20 + i * 0x10..], offset);
+            LittleEndian::write_u32(&mut data[0x24 + i * 0x10..], size);
+        }
+        data
+    }
+
+    #[test]
+    fn strict_mode_rejects_overlapping_segments() {
+        let layout = [
+            (0, 0x1000),
+            (0x1000, 0x1000),
+            (0x2000, 0x1000),
+            (0x3000, 0x1000),
+        ];
+        Kip::from_bytes_with_mode(kip(layout), ParseMode::Strict).unwrap();
+
+        let layout = [
+            (0, 0x1000),
+            (0x800, 0x1000),
+            (0x2000, 0x1000),
+            (0x3000, 0x1000),
+        ];
+        Kip::from_bytes(kip(layout)).unwrap();
+        assert!(Kip::from_bytes_with_mode(kip(layout), ParseMode::Strict).is_err());
+    }
+
+    #[test]
+    fn strict_mode_rejects_data_after_the_kips() {
+        let layout = [
+            (0, 0x1000),
+            (0x1000, 0x1000),
+            (0x2000, 0x1000),
+            (0x3000, 0x1000),
+        ];
+        let ini1 = Ini1 {
+            kips: vec![Kip::from_bytes(kip(layout)).unwrap()],
+        };
+        let mut data = ini1.to_bytes().unwrap();
+        Ini1::from_bytes_with_mode(&data, ParseMode::Strict).unwrap();
+
+        data.extend_from_slice(&[0; 0x10]);
+        let size = data.len() as u32;
+        LittleEndian::write_u32(&mut data[0x4..], size);
+        assert_eq!(Ini1::from_bytes(&data).unwrap().kips.len(), 1);
+        assert!(Ini1::from_bytes_with_mode(&data, ParseMode::Strict).is_err());
+    }
+}
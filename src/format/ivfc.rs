@@ -0,0 +1,264 @@
+//! IVFC hash trees, guarding the integrity of RomFS sections and savefiles.
+//!
+//! Each level of the tree holds the SHA-256 of every block of the level after
+//! it, the last level being the data itself. The first level is hashed in the
+//! master hash, which the superblock stores along with the position of every
+//! level.
+
+use crate::utils::align_up;
+use byteorder::{ByteOrder, LittleEndian};
+use serde_derive::Serialize;
+use sha2::{Digest, Sha256};
+use std::io::{self, Read};
+
+/// Size of the superblock: the levels and the master hash.
+pub const IVFC_SUPERBLOCK_SIZE: usize = 0xE0;
+
+/// Highest number of levels in a tree, the master hash included.
+pub const IVFC_MAX_LEVEL_COUNT: usize = 7;
+
+/// Block size used by RomFS sections, as a power of two.
+pub const IVFC_BLOCK_SIZE_LOG2: u32 = 14;
+
+/// Number of levels of RomFS sections, the data included but not the master
+/// hash.
+pub const IVFC_LEVEL_COUNT: usize = 6;
+
+/// A part of a section or file, relative to its start.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Region {
+    pub offset: u64,
+    pub size: u64,
+}
+
+impl Region {
+    pub(crate) fn read(data: &[u8]) -> Region {
+        Region {
+            offset: LittleEndian::read_u64(&data[0x0..]),
+            size: LittleEndian::read_u64(&data[0x8..]),
+        }
+    }
+
+    pub(crate) fn write(&self, data: &mut [u8]) {
+        LittleEndian::write_u64(&mut data[0x0..], self.offset);
+        LittleEndian::write_u64(&mut data[0x8..], self.size);
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct IvfcLevel {
+    pub region: Region,
+    pub block_size_log2: u32,
+}
+
+/// Returns the SHA-256 of every `block_size` bytes of `data`. When
+/// `full_block` is set, the last block is padded with zeroes before being
+/// hashed.
+pub(crate) fn hash_blocks<R: Read>(
+    mut data: R,
+    block_size: usize,
+    full_block: bool,
+) -> io::Result<Vec<u8>> {
+    let mut hashes = Vec::new();
+    let mut block = vec![0; block_size];
+    loop {
+        let mut read = 0;
+        while read < block_size {
+            match data.read(&mut block[read..])? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        if read == 0 {
+            break;
+        }
+        let len = if full_block {
+            for byte in &mut block[read..] {
+                *byte = 0;
+            }
+            block_size
+        } else {
+            read
+        };
+        hashes.extend_from_slice(&Sha256::digest(&block[..len]));
+        if read < block_size {
+            break;
+        }
+    }
+    Ok(hashes)
+}
+
+/// Builds the IVFC tree of some data.
+#[derive(Debug, Clone)]
+pub struct IvfcBuilder {
+    /// Block size of every level, as a power of two.
+    pub block_size_log2: u32,
+    /// Number of levels, the data included but not the master hash.
+    pub level_count: usize,
+}
+
+impl Default for IvfcBuilder {
+    fn default() -> IvfcBuilder {
+        IvfcBuilder::new()
+    }
+}
+
+impl IvfcBuilder {
+    /// Prepares a tree laid out like the ones of RomFS sections.
+    pub fn new() -> IvfcBuilder {
+        IvfcBuilder {
+            block_size_log2: IVFC_BLOCK_SIZE_LOG2,
+            level_count: IVFC_LEVEL_COUNT,
+        }
+    }
+
+    /// Hashes the `size` bytes of `data`, the last level of the tree.
+    pub fn build<R: Read>(&self, data: R, size: u64) -> io::Result<IvfcTree> {
+        let invalid_input = |error| io::Error::new(io::ErrorKind::InvalidInput, error);
+        if self.level_count < 2 || self.level_count >= IVFC_MAX_LEVEL_COUNT {
+            return Err(invalid_input("IVFC trees have between 2 and 6 levels"));
+        }
+        if !(9..=24).contains(&self.block_size_log2) {
+            return Err(invalid_input(
+                "IVFC block size must be between 0x200 and 0x1000000",
+            ));
+        }
+        let block_size = 1usize << self.block_size_log2;
+
+        // Each level hashes the one after it.
+        let data_hashes = hash_blocks(data.take(size), block_size, true)?;
+        if data_hashes.len() as u64 / 0x20 != (size + block_size as u64 - 1) / block_size as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "IVFC data is smaller than its size",
+            ));
+        }
+        let mut hash_levels = vec![data_hashes];
+        while hash_levels.len() < self.level_count - 1 {
+            let level = hash_blocks(&hash_levels[0][..], block_size, true)?;
+            hash_levels.insert(0, level);
+        }
+        if hash_levels[0].len() > block_size {
+            return Err(invalid_input("too few IVFC levels for this much data"));
+        }
+        let mut master_hash = [0; 0x20];
+        master_hash.copy_from_slice(&hash_blocks(&hash_levels[0][..], block_size, true)?[..0x20]);
+
+        let mut hash_data = Vec::new();
+        let mut levels = Vec::new();
+        for level in &hash_levels {
+            levels.push(IvfcLevel {
+                region: Region {
+                    offset: hash_data.len() as u64,
+                    size: level.len() as u64,
+                },
+                block_size_log2: self.block_size_log2,
+            });
+            hash_data.extend_from_slice(level);
+            hash_data.resize(align_up(hash_data.len(), block_size), 0);
+        }
+        levels.push(IvfcLevel {
+            region: Region {
+                offset: hash_data.len() as u64,
+                size,
+            },
+            block_size_log2: self.block_size_log2,
+        });
+
+        Ok(IvfcTree {
+            master_hash,
+            levels,
+            hash_data,
+        })
+    }
+}
+
+/// An IVFC tree, as built by `IvfcBuilder`.
+#[derive(Debug, Clone)]
+pub struct IvfcTree {
+    pub master_hash: [u8; 0x20],
+    /// Position of every level, the data included, relative to the start of
+    /// `hash_data`.
+    pub levels: Vec<IvfcLevel>,
+    /// Every hash level, each padded to the block size. The data comes right
+    /// after it.
+    pub hash_data: Vec<u8>,
+}
+
+impl IvfcTree {
+    pub fn superblock(&self) -> [u8; IVFC_SUPERBLOCK_SIZE] {
+        write_superblock(&self.master_hash, &self.levels)
+    }
+}
+
+/// Reads an IVFC superblock, returning the master hash and the levels.
+pub fn read_superblock(data: &[u8]) -> Result<([u8; 0x20], Vec<IvfcLevel>), &'static str> {
+    if data.len() < IVFC_SUPERBLOCK_SIZE {
+        return Err("IVFC superblock is truncated");
+    }
+    if &data[..4] != b"IVFC" {
+        return Err("IVFC magic is wrong");
+    }
+    let level_count = LittleEndian::read_u32(&data[0xC..]) as usize;
+    if level_count == 0 || level_count > IVFC_MAX_LEVEL_COUNT {
+        return Err("invalid IVFC level count");
+    }
+    // The master hash counts as the first level.
+    let levels = (0..level_count - 1)
+        .map(|i| {
+            let level = &data[0x10 + i * 0x18..];
+            IvfcLevel {
+                region: Region::read(level),
+                block_size_log2: LittleEndian::read_u32(&level[0x10..]),
+            }
+        })
+        .collect();
+    let mut master_hash = [0; 0x20];
+    master_hash.copy_from_slice(&data[0xC0..0xE0]);
+    Ok((master_hash, levels))
+}
+
+/// Writes an IVFC superblock, the way `read_superblock` reads it.
+pub fn write_superblock(
+    master_hash: &[u8; 0x20],
+    levels: &[IvfcLevel],
+) -> [u8; IVFC_SUPERBLOCK_SIZE] {
+    let mut data = [0; IVFC_SUPERBLOCK_SIZE];
+    data[..4].copy_from_slice(b"IVFC");
+    LittleEndian::write_u32(&mut data[0x4..], 0x20000);
+    LittleEndian::write_u32(&mut data[0x8..], 0x20);
+    LittleEndian::write_u32(&mut data[0xC..], levels.len() as u32 + 1);
+    for (i, level) in levels.iter().enumerate() {
+        let level_data = &mut data[0x10 + i * 0x18..];
+        level.region.write(level_data);
+        LittleEndian::write_u32(&mut level_data[0x10..], level.block_size_log2);
+    }
+    data[0xC0..0xE0].copy_from_slice(master_hash);
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tree_round_trip() {
+        let data = vec![0x5A; 0x2345];
+        let builder = IvfcBuilder {
+            block_size_log2: 9,
+            level_count: 4,
+        };
+        let tree = builder.build(&data[..], data.len() as u64).unwrap();
+        assert_eq!(tree.levels.len(), 4);
+        assert_eq!(tree.levels[2].region.size, 0x12 * 0x20);
+        assert_eq!(tree.levels[3].region.size, data.len() as u64);
+        assert_eq!(tree.hash_data.len() as u64, tree.levels[3].region.offset);
+
+        let (master_hash, levels) = read_superblock(&tree.superblock()).unwrap();
+        assert_eq!(master_hash, tree.master_hash);
+        assert_eq!(levels, tree.levels);
+
+        let truncated = builder.build(&data[..0x100], data.len() as u64);
+        assert!(truncated.is_err());
+    }
+}
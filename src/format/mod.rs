@@ -1,6 +1,32 @@
+#[cfg(feature = "crypto")]
+pub mod bis;
+pub mod boot;
+#[cfg(feature = "crypto")]
+pub mod cert;
+pub mod cnmt;
+pub mod compression;
+pub mod container;
+pub mod detect;
+pub mod icon;
+pub mod ini1;
+pub mod ivfc;
 pub mod nacp;
-mod npdm;
+#[cfg(feature = "crypto")]
+pub mod nax0;
+#[cfg(feature = "crypto")]
+pub mod nca;
+pub mod npdm;
 pub mod nxo;
+#[cfg(feature = "crypto")]
+pub mod package1;
+#[cfg(feature = "crypto")]
+pub mod package2;
+pub mod parse;
 pub mod pfs0;
 pub mod romfs;
+pub mod save;
+#[cfg(feature = "crypto")]
+pub mod tik;
 mod utils;
+pub mod vfs;
+pub mod xci;
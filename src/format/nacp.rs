@@ -1,289 +1,1806 @@
-use crate::format::utils;
-use byteorder::{LittleEndian, WriteBytesExt};
+use crate::error::Error;
+use crate::format::utils::{self, HexOrNum};
+use byteorder::{ByteOrder, LittleEndian};
+use serde::de::DeserializeOwned;
+use serde::de::Error as _;
+use serde::Deserializer;
 use serde_derive::{Deserialize, Serialize};
+use snafu::Backtrace;
+use snafu::GenerateBacktrace;
+use std::collections::BTreeMap;
 use std::fs::File;
-use std::io::Write;
+use std::io::{Read, Write};
+use std::path::Path;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct NacpLangEntry {
     pub name: String,
     pub author: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Name and author of the application for each language. Languages can be
+/// given either by their code (`en-US`) or their name (`AmericanEnglish`).
+/// Missing languages use the top-level name and author.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct NacpLangEntries {
-    #[serde(rename = "en-US")]
+    #[serde(
+        rename = "en-US",
+        alias = "AmericanEnglish",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub en_us: Option<NacpLangEntry>,
-
-    #[serde(rename = "en-GB")]
+    #[serde(
+        rename = "en-GB",
+        alias = "BritishEnglish",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub en_gb: Option<NacpLangEntry>,
-
+    #[serde(
+        rename = "ja",
+        alias = "Japanese",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub ja: Option<NacpLangEntry>,
+    #[serde(
+        rename = "fr",
+        alias = "French",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub fr: Option<NacpLangEntry>,
+    #[serde(
+        rename = "de",
+        alias = "German",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub de: Option<NacpLangEntry>,
-
-    #[serde(rename = "es-419")]
+    #[serde(
+        rename = "es-419",
+        alias = "LatinAmericanSpanish",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub es_419: Option<NacpLangEntry>,
-
+    #[serde(
+        rename = "es",
+        alias = "Spanish",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub es: Option<NacpLangEntry>,
+    #[serde(
+        rename = "it",
+        alias = "Italian",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub it: Option<NacpLangEntry>,
+    #[serde(
+        rename = "nl",
+        alias = "Dutch",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub nl: Option<NacpLangEntry>,
-
-    #[serde(rename = "fr-CA")]
+    #[serde(
+        rename = "fr-CA",
+        alias = "CanadianFrench",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub fr_ca: Option<NacpLangEntry>,
+    #[serde(
+        rename = "pt",
+        alias = "Portuguese",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub pt: Option<NacpLangEntry>,
-
+    #[serde(
+        rename = "ru",
+        alias = "Russian",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub ru: Option<NacpLangEntry>,
+    #[serde(
+        rename = "ko",
+        alias = "Korean",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub ko: Option<NacpLangEntry>,
-
-    #[serde(rename = "zh-TW")]
+    #[serde(
+        rename = "zh-TW",
+        alias = "TraditionalChinese",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub zh_tw: Option<NacpLangEntry>,
-
-    #[serde(rename = "zh-CN")]
+    #[serde(
+        rename = "zh-CN",
+        alias = "SimplifiedChinese",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub zh_cn: Option<NacpLangEntry>,
+    #[serde(
+        rename = "pt-BR",
+        alias = "BrazilianPortuguese",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub pt_br: Option<NacpLangEntry>,
+}
+
+impl NacpLangEntries {
+    /// The entries, in the order they are stored in the NACP.
+    fn entries(&self) -> [&Option<NacpLangEntry>; 16] {
+        [
+            &self.en_us,
+            &self.en_gb,
+            &self.ja,
+            &self.fr,
+            &self.de,
+            &self.es_419,
+            &self.es,
+            &self.it,
+            &self.nl,
+            &self.fr_ca,
+            &self.pt,
+            &self.ru,
+            &self.ko,
+            &self.zh_tw,
+            &self.zh_cn,
+            &self.pt_br,
+        ]
+    }
+
+    fn entries_mut(&mut self) -> [&mut Option<NacpLangEntry>; 16] {
+        [
+            &mut self.en_us,
+            &mut self.en_gb,
+            &mut self.ja,
+            &mut self.fr,
+            &mut self.de,
+            &mut self.es_419,
+            &mut self.es,
+            &mut self.it,
+            &mut self.nl,
+            &mut self.fr_ca,
+            &mut self.pt,
+            &mut self.ru,
+            &mut self.ko,
+            &mut self.zh_tw,
+            &mut self.zh_cn,
+            &mut self.pt_br,
+        ]
+    }
+}
+
+/// Declares an enum stored as a single byte in the NACP.
+macro_rules! nacp_enum {
+    ($(#[$meta:meta])* $name:ident { $($variant:ident = $value:literal),* $(,)? }) => {
+        $(#[$meta])*
+        #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum $name {
+            $($variant = $value),*
+        }
+
+        impl $name {
+            fn from_u8(value: u8) -> Option<$name> {
+                match value {
+                    $($value => Some($name::$variant),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+nacp_enum!(StartupUserAccount {
+    None = 0,
+    Required = 1,
+    RequiredWithNetworkServiceAccountAvailable = 2,
+});
+
+nacp_enum!(UserAccountSwitchLock {
+    Disable = 0,
+    Enable = 1,
+});
+
+nacp_enum!(AddOnContentRegistrationType {
+    AllOnLaunch = 0,
+    OnDemand = 1,
+});
+
+nacp_enum!(Screenshot {
+    Allow = 0,
+    Deny = 1,
+});
+
+nacp_enum!(VideoCapture {
+    Disable = 0,
+    Manual = 1,
+    Enable = 2,
+});
+
+nacp_enum!(DataLossConfirmation {
+    None = 0,
+    Required = 1,
+});
+
+nacp_enum!(PlayLogPolicy {
+    All = 0,
+    LogOnly = 1,
+    None = 2,
+    Closed = 3,
+});
+
+nacp_enum!(LogoType {
+    LicensedByNintendo = 0,
+    DistributedByNintendo = 1,
+    Nintendo = 2,
+});
+
+nacp_enum!(LogoHandling {
+    Auto = 0,
+    Manual = 1,
+});
+
+nacp_enum!(RuntimeAddOnContentInstall {
+    Deny = 0,
+    AllowAppend = 1,
+    AllowAppendButDontDownloadWhenUsingNetwork = 2,
+});
+
+nacp_enum!(RuntimeParameterDelivery {
+    Always = 0,
+    AlwaysIfUserStateMatched = 1,
+    OnRestart = 2,
+});
+
+nacp_enum!(CrashReport {
+    Deny = 0,
+    Allow = 1,
+});
+
+nacp_enum!(Hdcp {
+    None = 0,
+    Required = 1,
+});
+
+/// A language, named the same way as the keys of `NacpLangEntries`. Used in
+/// the supported language bitmap, where the value is the bit index.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    #[serde(rename = "en-US", alias = "AmericanEnglish")]
+    AmericanEnglish = 0,
+    #[serde(rename = "en-GB", alias = "BritishEnglish")]
+    BritishEnglish = 1,
+    #[serde(rename = "ja", alias = "Japanese")]
+    Japanese = 2,
+    #[serde(rename = "fr", alias = "French")]
+    French = 3,
+    #[serde(rename = "de", alias = "German")]
+    German = 4,
+    #[serde(rename = "es-419", alias = "LatinAmericanSpanish")]
+    LatinAmericanSpanish = 5,
+    #[serde(rename = "es", alias = "Spanish")]
+    Spanish = 6,
+    #[serde(rename = "it", alias = "Italian")]
+    Italian = 7,
+    #[serde(rename = "nl", alias = "Dutch")]
+    Dutch = 8,
+    #[serde(rename = "fr-CA", alias = "CanadianFrench")]
+    CanadianFrench = 9,
+    #[serde(rename = "pt", alias = "Portuguese")]
+    Portuguese = 10,
+    #[serde(rename = "ru", alias = "Russian")]
+    Russian = 11,
+    #[serde(rename = "ko", alias = "Korean")]
+    Korean = 12,
+    #[serde(rename = "zh-TW", alias = "TraditionalChinese")]
+    TraditionalChinese = 13,
+    #[serde(rename = "zh-CN", alias = "SimplifiedChinese")]
+    SimplifiedChinese = 14,
+    #[serde(rename = "pt-BR", alias = "BrazilianPortuguese")]
+    BrazilianPortuguese = 15,
+}
+
+impl Language {
+    pub const ALL: [Language; 16] = [
+        Language::AmericanEnglish,
+        Language::BritishEnglish,
+        Language::Japanese,
+        Language::French,
+        Language::German,
+        Language::LatinAmericanSpanish,
+        Language::Spanish,
+        Language::Italian,
+        Language::Dutch,
+        Language::CanadianFrench,
+        Language::Portuguese,
+        Language::Russian,
+        Language::Korean,
+        Language::TraditionalChinese,
+        Language::SimplifiedChinese,
+        Language::BrazilianPortuguese,
+    ];
+
+    /// The name of the language, as used in the name of icon files.
+    pub fn name(self) -> &'static str {
+        match self {
+            Language::AmericanEnglish => "AmericanEnglish",
+            Language::BritishEnglish => "BritishEnglish",
+            Language::Japanese => "Japanese",
+            Language::French => "French",
+            Language::German => "German",
+            Language::LatinAmericanSpanish => "LatinAmericanSpanish",
+            Language::Spanish => "Spanish",
+            Language::Italian => "Italian",
+            Language::Dutch => "Dutch",
+            Language::CanadianFrench => "CanadianFrench",
+            Language::Portuguese => "Portuguese",
+            Language::Russian => "Russian",
+            Language::Korean => "Korean",
+            Language::TraditionalChinese => "TraditionalChinese",
+            Language::SimplifiedChinese => "SimplifiedChinese",
+            Language::BrazilianPortuguese => "BrazilianPortuguese",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttributeFlag {
+    Demo = 0,
+    RetailInteractiveDisplay = 1,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParentalControlFlag {
+    FreeCommunication = 0,
+}
+
+/// A rating organization. The value is the index of its entry in the rating
+/// age array.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RatingOrganization {
+    #[serde(rename = "CERO")]
+    Cero = 0,
+    #[serde(rename = "GRACGCRB")]
+    GracGcrb = 1,
+    #[serde(rename = "GSRMR")]
+    Gsrmr = 2,
+    #[serde(rename = "ESRB")]
+    Esrb = 3,
+    #[serde(rename = "ClassInd")]
+    ClassInd = 4,
+    #[serde(rename = "USK")]
+    Usk = 5,
+    #[serde(rename = "PEGI")]
+    Pegi = 6,
+    #[serde(rename = "PEGIPortugal")]
+    PegiPortugal = 7,
+    #[serde(rename = "PEGIBBFC")]
+    PegiBbfc = 8,
+    #[serde(rename = "Russian")]
+    Russian = 9,
+    #[serde(rename = "ACB")]
+    Acb = 10,
+    #[serde(rename = "OFLC")]
+    Oflc = 11,
+    #[serde(rename = "IARCGeneric")]
+    IarcGeneric = 12,
+}
+
+impl RatingOrganization {
+    const ALL: [RatingOrganization; 13] = [
+        RatingOrganization::Cero,
+        RatingOrganization::GracGcrb,
+        RatingOrganization::Gsrmr,
+        RatingOrganization::Esrb,
+        RatingOrganization::ClassInd,
+        RatingOrganization::Usk,
+        RatingOrganization::Pegi,
+        RatingOrganization::PegiPortugal,
+        RatingOrganization::PegiBbfc,
+        RatingOrganization::Russian,
+        RatingOrganization::Acb,
+        RatingOrganization::Oflc,
+        RatingOrganization::IarcGeneric,
+    ];
+
+    /// The name used for this organization in descriptors.
+    pub fn name(self) -> &'static str {
+        match self {
+            RatingOrganization::Cero => "CERO",
+            RatingOrganization::GracGcrb => "GRACGCRB",
+            RatingOrganization::Gsrmr => "GSRMR",
+            RatingOrganization::Esrb => "ESRB",
+            RatingOrganization::ClassInd => "ClassInd",
+            RatingOrganization::Usk => "USK",
+            RatingOrganization::Pegi => "PEGI",
+            RatingOrganization::PegiPortugal => "PEGIPortugal",
+            RatingOrganization::PegiBbfc => "PEGIBBFC",
+            RatingOrganization::Russian => "Russian",
+            RatingOrganization::Acb => "ACB",
+            RatingOrganization::Oflc => "OFLC",
+            RatingOrganization::IarcGeneric => "IARCGeneric",
+        }
+    }
+
+    /// The ages this organization rates software for.
+    pub fn allowed_ages(self) -> &'static [u8] {
+        match self {
+            RatingOrganization::Cero => &[0, 12, 15, 17, 18],
+            RatingOrganization::GracGcrb => &[0, 12, 15, 18],
+            RatingOrganization::Gsrmr => &[0, 6, 12, 15, 18],
+            RatingOrganization::Esrb => &[3, 6, 10, 13, 17, 18],
+            RatingOrganization::ClassInd => &[0, 10, 12, 14, 16, 18],
+            RatingOrganization::Usk => &[0, 6, 12, 16, 18],
+            RatingOrganization::Pegi => &[3, 7, 12, 16, 18],
+            RatingOrganization::PegiPortugal => &[4, 6, 12, 16, 18],
+            RatingOrganization::PegiBbfc => &[3, 4, 7, 8, 12, 15, 16, 18],
+            RatingOrganization::Russian => &[0, 6, 12, 16, 18],
+            RatingOrganization::Acb => &[0, 8, 15, 18],
+            RatingOrganization::Oflc => &[0, 13, 15, 16, 18],
+            RatingOrganization::IarcGeneric => &[3, 7, 12, 16, 18],
+        }
+    }
+}
+
+/// Minimum age required by each rating organization. Organizations that are
+/// left out are stored as unrated.
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct RatingAges(pub BTreeMap<RatingOrganization, u8>);
+
+impl<'de> serde::Deserialize<'de> for RatingAges {
+    fn deserialize<D>(deserializer: D) -> Result<RatingAges, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        // Keys are read as strings since not every format can deserialize
+        // map keys as enums.
+        let names = <BTreeMap<String, u8> as serde::Deserialize>::deserialize(deserializer)?;
+        let mut ages = BTreeMap::new();
+        for (name, age) in names {
+            let organization = RatingOrganization::ALL
+                .iter()
+                .cloned()
+                .find(|organization| organization.name() == name)
+                .ok_or_else(|| D::Error::custom(format!("unknown rating organization {}", name)))?;
+            if !organization.allowed_ages().contains(&age) {
+                return Err(D::Error::custom(format!(
+                    "invalid rating age {} for {}, expected one of {:?}",
+                    age,
+                    name,
+                    organization.allowed_ages()
+                )));
+            }
+            ages.insert(organization, age);
+        }
+        Ok(RatingAges(ages))
+    }
+}
+
+nacp_enum!(PlayLogQueryCapability {
+    None = 0,
+    WhiteList = 1,
+    All = 2,
+});
+
+nacp_enum!(RequiredNetworkServiceLicenseOnLaunch {
+    None = 0,
+    Common = 1,
+});
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepairFlag {
+    SuppressGameCardAccess = 0,
+}
+
+impl From<RepairFlag> for u32 {
+    fn from(flag: RepairFlag) -> u32 {
+        flag as u32
+    }
+}
+
+/// Lets the application use JIT compilation, with `memory_size` bytes set
+/// aside for the generated code.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JitConfiguration {
+    pub enabled: bool,
+    pub memory_size: HexOrNum,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct NeighborDetectionGroupConfiguration {
+    /// Hexadecimal 64-bit group ID.
+    pub group_id: String,
+    /// Hexadecimal 16 bytes key.
+    pub key: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct NeighborDetectionClientConfiguration {
+    pub send_group_configuration: NeighborDetectionGroupConfiguration,
+    /// Up to 16 groups.
+    #[serde(default)]
+    pub receivable_group_configurations: Vec<NeighborDetectionGroupConfiguration>,
+}
+
+/// Marker for fields set to `"auto"`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Auto {
+    Auto,
+}
+
+/// IDs of the applications this one can talk to over local wireless.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum LocalCommunicationIds {
+    /// Uses the title ID for all 8 entries, like official applications do.
+    Auto(Auto),
+    /// Up to 8 IDs, the remaining entries are left empty.
+    Ids(Vec<String>),
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+/// A set of defaults applied to the fields a descriptor leaves out.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NacpPreset {
+    /// Every language shows the top-level name and author, no user account is
+    /// needed to start the application and the logo is handled by the system.
+    Homebrew,
+}
+
+/// The languages descriptors can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl DescriptorFormat {
+    /// The format of the descriptor at `path`: TOML or YAML if it ends in
+    /// `.toml`, `.yaml` or `.yml`, and JSON otherwise.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> DescriptorFormat {
+        let extension = path
+            .as_ref()
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("toml") => DescriptorFormat::Toml,
+            Some("yaml") | Some("yml") => DescriptorFormat::Yaml,
+            _ => DescriptorFormat::Json,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct NacpFile {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset: Option<NacpPreset>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub author: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub title_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub dlc_base_title_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub lang: Option<NacpLangEntries>,
+
+    // The following fields default to the values homebrew usually ships with.
+    // IDs default to title_id.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub isbn: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub startup_user_account: Option<StartupUserAccount>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_account_switch_lock: Option<UserAccountSwitchLock>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub add_on_content_registration_type: Option<AddOnContentRegistrationType>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attribute_flags: Option<Vec<AttributeFlag>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub supported_languages: Option<Vec<Language>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parental_control_flags: Option<Vec<ParentalControlFlag>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub screenshot: Option<Screenshot>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub video_capture: Option<VideoCapture>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data_loss_confirmation: Option<DataLossConfirmation>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub play_log_policy: Option<PlayLogPolicy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_group_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rating_age: Option<RatingAges>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub save_data_owner_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_account_save_data_size: Option<HexOrNum>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_account_save_data_journal_size: Option<HexOrNum>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_save_data_size: Option<HexOrNum>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_save_data_journal_size: Option<HexOrNum>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bcat_delivery_cache_storage_size: Option<HexOrNum>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub application_error_code_category: Option<String>,
+    #[serde(
+        alias = "local_communication_ids",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub local_communication_id: Option<LocalCommunicationIds>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logo_type: Option<LogoType>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logo_handling: Option<LogoHandling>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub runtime_add_on_content_install: Option<RuntimeAddOnContentInstall>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub runtime_parameter_delivery: Option<RuntimeParameterDelivery>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crash_report: Option<CrashReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hdcp: Option<Hdcp>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed_for_pseudo_device_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bcat_passphrase: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub startup_user_account_optional: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_account_save_data_size_max: Option<HexOrNum>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_account_save_data_journal_size_max: Option<HexOrNum>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_save_data_size_max: Option<HexOrNum>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_save_data_journal_size_max: Option<HexOrNum>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temporary_storage_size: Option<HexOrNum>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_storage_size: Option<HexOrNum>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_storage_journal_size: Option<HexOrNum>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_storage_data_and_journal_size_max: Option<HexOrNum>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_storage_index_max: Option<u16>,
+    /// Applications whose play log this one can query, when the capability
+    /// is WhiteList. Up to 16 IDs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub play_log_queryable_application_id: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub play_log_query_capability: Option<PlayLogQueryCapability>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repair_flags: Option<Vec<RepairFlag>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub program_index: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub required_network_service_license_on_launch: Option<RequiredNetworkServiceLicenseOnLaunch>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub neighbor_detection_client_configuration: Option<NeighborDetectionClientConfiguration>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jit_configuration: Option<JitConfiguration>,
+}
+
+fn load_descriptor<T: DeserializeOwned>(input: &str) -> std::io::Result<T> {
+    read_descriptor(File::open(input)?, DescriptorFormat::from_path(input))
+}
+
+fn read_descriptor<T: DeserializeOwned, R: Read>(
+    mut reader: R,
+    format: DescriptorFormat,
+) -> std::io::Result<T> {
+    match format {
+        DescriptorFormat::Toml => {
+            let mut data = String::new();
+            reader.read_to_string(&mut data)?;
+            toml::from_str(&data)
+                .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error))
+        }
+        DescriptorFormat::Yaml => serde_yaml::from_reader(reader)
+            .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error)),
+        DescriptorFormat::Json => serde_json::from_reader(reader).map_err(std::io::Error::from),
+    }
+}
+
+/// Applies `patch` to `target` as a JSON merge patch (RFC 7386).
+fn merge_patch(target: &mut serde_json::Value, patch: &serde_json::Value) {
+    let patch = match patch.as_object() {
+        Some(patch) => patch,
+        None => {
+            *target = patch.clone();
+            return;
+        }
+    };
+    if !target.is_object() {
+        *target = serde_json::Value::Object(Default::default());
+    }
+    if let Some(target) = target.as_object_mut() {
+        for (key, value) in patch {
+            if value.is_null() {
+                target.remove(key);
+            } else {
+                merge_patch(
+                    target
+                        .entry(key.as_str())
+                        .or_insert(serde_json::Value::Null),
+                    value,
+                );
+            }
+        }
+    }
+}
+
+/// Parses the hexadecimal ID in the descriptor field `field`.
+fn parse_title_id(title_id: &Option<String>, field: &'static str) -> Result<Option<u64>, Error> {
+    match title_id {
+        Some(title_id) => u64::from_str_radix(title_id, 16)
+            .map(Some)
+            .map_err(|_| invalid_field(field, "ids must be hexadecimal 64-bit numbers")),
+        None => Ok(None),
+    }
+}
+
+fn format_title_id(title_id: u64) -> String {
+    format!("{:016x}", title_id)
+}
+
+/// Copies `string` in `buf`, truncating it if needed so it stays NUL-terminated.
+fn write_string(buf: &mut [u8], string: &str, name: &str) {
+    let mut string = string.to_string();
+    utils::check_string_or_truncate(&mut string, name, buf.len());
+    let len = std::cmp::min(string.len(), buf.len());
+    buf[..len].copy_from_slice(&string.as_bytes()[..len]);
+    for c in &mut buf[len..] {
+        *c = 0;
+    }
+}
+
+/// Parses a string of hexadecimal digits into `buf`, which it must exactly fill.
+fn parse_hex_bytes(hex: &str, buf: &mut [u8]) -> Option<()> {
+    if hex.len() != buf.len() * 2 || !hex.is_ascii() {
+        return None;
+    }
+    for (idx, byte) in buf.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[idx * 2..idx * 2 + 2], 16).ok()?;
+    }
+    Some(())
+}
+
+fn format_hex_bytes(buf: &[u8]) -> String {
+    buf.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn read_group_configuration(buf: &[u8]) -> NeighborDetectionGroupConfiguration {
+    NeighborDetectionGroupConfiguration {
+        group_id: format_title_id(LittleEndian::read_u64(buf)),
+        key: format_hex_bytes(&buf[8..0x18]),
+    }
+}
+
+fn write_group_configuration(
+    buf: &mut [u8],
+    config: &NeighborDetectionGroupConfiguration,
+) -> Result<(), Error> {
+    let invalid = || {
+        invalid_field(
+            "neighbor_detection_client_configuration",
+            "neighbor detection groups need a 64-bit hexadecimal group_id and a 16 bytes hexadecimal key",
+        )
+    };
+    let group_id = u64::from_str_radix(&config.group_id, 16).map_err(|_| invalid())?;
+    LittleEndian::write_u64(buf, group_id);
+    parse_hex_bytes(&config.key, &mut buf[8..0x18]).ok_or_else(invalid)
+}
+
+/// Reads a NUL-terminated string from `buf`.
+fn read_string(buf: &[u8]) -> Result<String, Error> {
+    let len = buf
+        .iter()
+        .position(|&c| c == 0)
+        .unwrap_or_else(|| buf.len());
+    Ok(String::from_utf8(buf[..len].to_vec())?)
+}
+
+fn flags_to_bits<T: Copy + Into<u32>>(flags: &[T]) -> u32 {
+    flags.iter().fold(0, |bits, &flag| bits | 1 << flag.into())
+}
+
+impl From<Language> for u32 {
+    fn from(lang: Language) -> u32 {
+        lang as u32
+    }
+}
+
+impl From<AttributeFlag> for u32 {
+    fn from(flag: AttributeFlag) -> u32 {
+        flag as u32
+    }
+}
+
+impl From<ParentalControlFlag> for u32 {
+    fn from(flag: ParentalControlFlag) -> u32 {
+        flag as u32
+    }
+}
+
+fn invalid_nacp(error: &'static str) -> Error {
+    Error::InvalidNacp {
+        error,
+        field: None,
+        backtrace: Backtrace::generate(),
+    }
+}
+
+/// An `InvalidNacp` error about the descriptor field `field`.
+fn invalid_field(field: &'static str, error: &'static str) -> Error {
+    Error::InvalidNacp {
+        error,
+        field: Some(field),
+        backtrace: Backtrace::generate(),
+    }
+}
+
+fn read_enum<T>(buf: &[u8], offset: usize, from_u8: fn(u8) -> Option<T>) -> Result<T, Error> {
+    from_u8(buf[offset]).ok_or_else(|| invalid_nacp("unknown enum value"))
+}
+
+/// Returns `Some(value)` if `value` differs from what the writer would use
+/// when the field is left out.
+fn non_default<T: PartialEq>(value: T, default: T) -> Option<T> {
+    if value == default {
+        None
+    } else {
+        Some(value)
+    }
 }
 
 #[allow(clippy::len_without_is_empty)]
 impl NacpFile {
+    /// Loads a descriptor. Files ending in `.toml`, `.yaml` or `.yml` are
+    /// parsed as TOML or YAML, anything else as JSON.
     pub fn from_file(input: &str) -> std::io::Result<Self> {
-        let file = File::open(input)?;
-        match serde_json::from_reader(file) {
-            Ok(res) => Ok(res),
-            Err(error) => Err(std::io::Error::from(error)),
-        }
+        NacpFile::from_descriptor(File::open(input)?, DescriptorFormat::from_path(input))
+    }
+
+    /// Loads a descriptor written in `format` from `reader`.
+    pub fn from_descriptor<R: Read>(reader: R, format: DescriptorFormat) -> std::io::Result<Self> {
+        let mut res: NacpFile = read_descriptor(reader, format)?;
+        res.apply_preset();
+        Ok(res)
+    }
+
+    /// Saves this descriptor, as TOML or YAML if `output` ends in `.toml`,
+    /// `.yaml` or `.yml` and as pretty-printed JSON otherwise.
+    pub fn to_file(&self, output: &str) -> std::io::Result<()> {
+        let mut data = Vec::new();
+        self.to_descriptor(&mut data, DescriptorFormat::from_path(output))?;
+        std::fs::write(output, data)
     }
 
-    fn write_lang_entry<T>(
+    /// Writes this descriptor to `writer` in `format`. JSON is pretty-printed.
+    pub fn to_descriptor<W: Write>(
         &self,
-        output_writter: &mut T,
-        lang_entry: &NacpLangEntry,
-    ) -> std::io::Result<()>
-    where
-        T: Write,
-    {
-        let name = &lang_entry.name;
-        let name_padding = 0x200 - name.len();
-        output_writter.write_all(name.as_bytes())?;
-        output_writter.write_all(&vec![0; name_padding])?;
-
-        let author = &lang_entry.author;
-        let author_padding = 0x100 - author.len();
-        output_writter.write_all(author.as_bytes())?;
-        output_writter.write_all(&vec![0; author_padding])?;
+        mut writer: W,
+        format: DescriptorFormat,
+    ) -> std::io::Result<()> {
+        let invalid_data = |error| std::io::Error::new(std::io::ErrorKind::InvalidData, error);
+        let data = match format {
+            // Going through a toml::Value puts the tables after the plain
+            // values, as TOML requires.
+            DescriptorFormat::Toml => toml::Value::try_from(self)
+                .and_then(|value| toml::to_string_pretty(&value))
+                .map_err(|error| invalid_data(error.to_string()))?,
+            DescriptorFormat::Yaml => {
+                serde_yaml::to_string(self).map_err(|error| invalid_data(error.to_string()))?
+            }
+            DescriptorFormat::Json => serde_json::to_string_pretty(self)? + "\n",
+        };
+        writer.write_all(data.as_bytes())
+    }
+
+    /// Loads a patch for `apply_patch`, in any of the formats accepted by
+    /// `from_file`.
+    pub fn load_patch(input: &str) -> std::io::Result<serde_json::Value> {
+        load_descriptor(input)
+    }
+
+    /// Overrides the fields set in `patch`, an object using the same fields as
+    /// descriptors. Fields set to `null` go back to their default value. On
+    /// error, `self` is left untouched.
+    pub fn apply_patch(&mut self, patch: &serde_json::Value) -> Result<(), Error> {
+        let mut value = serde_json::to_value(&*self)?;
+        merge_patch(&mut value, patch);
+        *self = serde_json::from_value(value)?;
+        Ok(())
+    }
+
+    /// Fills the fields left out of the descriptor with the values of its
+    /// preset, if it has one.
+    pub fn apply_preset(&mut self) {
+        match self.preset {
+            Some(NacpPreset::Homebrew) => {
+                self.supported_languages
+                    .get_or_insert_with(|| Language::ALL.to_vec());
+                self.startup_user_account
+                    .get_or_insert(StartupUserAccount::None);
+                self.user_account_switch_lock
+                    .get_or_insert(UserAccountSwitchLock::Disable);
+                self.logo_type.get_or_insert(LogoType::LicensedByNintendo);
+                self.logo_handling.get_or_insert(LogoHandling::Auto);
+                let video_capture = match self.screenshot.get_or_insert(Screenshot::Allow) {
+                    Screenshot::Allow => VideoCapture::Enable,
+                    Screenshot::Deny => VideoCapture::Disable,
+                };
+                self.video_capture.get_or_insert(video_capture);
+            }
+            None => (),
+        }
+    }
+
+    /// Parses a binary NACP. The result only sets the fields that differ from
+    /// what `write` uses by default, so it can be saved as a descriptor and
+    /// turned back into the same NACP.
+    pub fn from_reader<R: Read>(mut reader: R) -> Result<Self, Error> {
+        let mut buf = vec![0; 0x4000];
+        reader.read_exact(&mut buf)?;
+
+        // Lang entries. The default entry is the American English one, the
+        // others are only kept if they differ from it.
+        let mut entries = Vec::with_capacity(16);
+        for idx in 0..16 {
+            let entry = &buf[idx * 0x300..(idx + 1) * 0x300];
+            entries.push(NacpLangEntry {
+                name: read_string(&entry[..0x200])?,
+                author: read_string(&entry[0x200..])?,
+            });
+        }
+        let default_lang_entry = entries[0].clone();
+        let mut lang = NacpLangEntries::default();
+        let mut has_lang = false;
+        for (slot, entry) in lang.entries_mut().iter_mut().zip(entries.into_iter()) {
+            if entry != default_lang_entry {
+                **slot = Some(entry);
+                has_lang = true;
+            }
+        }
+
+        let title_id = LittleEndian::read_u64(&buf[0x3078..]);
+        let bits_to_langs = |bits: u32| {
+            Language::ALL
+                .iter()
+                .cloned()
+                .filter(|&lang| bits & (1 << lang as u32) != 0)
+                .collect::<Vec<_>>()
+        };
+        let attribute_flags = LittleEndian::read_u32(&buf[0x3028..]);
+        if attribute_flags & !0b11 != 0 {
+            return Err(invalid_nacp("unknown attribute flag"));
+        }
+        let parental_control_flags = LittleEndian::read_u32(&buf[0x3030..]);
+        if parental_control_flags & !0b1 != 0 {
+            return Err(invalid_nacp("unknown parental control flag"));
+        }
+        let mut rating_age = BTreeMap::new();
+        for (idx, &age) in buf[0x3040..0x3060].iter().enumerate() {
+            if age == 0xFF {
+                continue;
+            }
+            match RatingOrganization::ALL.get(idx) {
+                Some(&organization) => {
+                    rating_age.insert(organization, age);
+                }
+                None => return Err(invalid_nacp("rating age set for an unknown organization")),
+            }
+        }
+        let local_communication_id = (0..8)
+            .map(|idx| LittleEndian::read_u64(&buf[0x30B0 + idx * 8..]))
+            .collect::<Vec<_>>();
+        let size = |offset: usize| {
+            let size = LittleEndian::read_u64(&buf[offset..]);
+            non_default(size, 0).map(HexOrNum)
+        };
+        let play_log_queryable_application_id = buf[0x3190..0x3210]
+            .chunks(8)
+            .map(LittleEndian::read_u64)
+            .filter(|&id| id != 0)
+            .map(format_title_id)
+            .collect::<Vec<_>>();
+        let repair_flags = buf[0x3211];
+        if repair_flags & !0b1 != 0 {
+            return Err(invalid_nacp("unknown repair flag"));
+        }
+        // Unused group configurations are all zeroes.
+        let neighbor_detection_client_configuration = if buf[0x3218..0x33B0].iter().all(|&b| b == 0)
+        {
+            None
+        } else {
+            let receivable_group_configurations = buf[0x3230..0x33B0]
+                .chunks(0x18)
+                .filter(|config| config.iter().any(|&b| b != 0))
+                .map(read_group_configuration)
+                .collect();
+            Some(NeighborDetectionClientConfiguration {
+                send_group_configuration: read_group_configuration(&buf[0x3218..0x3230]),
+                receivable_group_configurations,
+            })
+        };
+        let jit_flags = LittleEndian::read_u64(&buf[0x33B0..]);
+        if jit_flags & !0b1 != 0 {
+            return Err(invalid_nacp("unknown JIT configuration flag"));
+        }
+        let jit_memory_size = LittleEndian::read_u64(&buf[0x33B8..]);
+        let jit_configuration = if jit_flags == 0 && jit_memory_size == 0 {
+            None
+        } else {
+            Some(JitConfiguration {
+                enabled: jit_flags & 1 != 0,
+                memory_size: HexOrNum(jit_memory_size),
+            })
+        };
+
+        Ok(NacpFile {
+            preset: None,
+            name: Some(default_lang_entry.name),
+            author: Some(default_lang_entry.author),
+            version: Some(read_string(&buf[0x3060..0x3070])?),
+            title_id: Some(format_title_id(title_id)),
+            dlc_base_title_id: non_default(
+                LittleEndian::read_u64(&buf[0x3070..]),
+                title_id.wrapping_add(0x1000),
+            )
+            .map(format_title_id),
+            lang: if has_lang { Some(lang) } else { None },
+            isbn: non_default(read_string(&buf[0x3000..0x3025])?, String::new()),
+            startup_user_account: non_default(
+                read_enum(&buf, 0x3025, StartupUserAccount::from_u8)?,
+                StartupUserAccount::None,
+            ),
+            user_account_switch_lock: non_default(
+                read_enum(&buf, 0x3026, UserAccountSwitchLock::from_u8)?,
+                UserAccountSwitchLock::Disable,
+            ),
+            add_on_content_registration_type: non_default(
+                read_enum(&buf, 0x3027, AddOnContentRegistrationType::from_u8)?,
+                AddOnContentRegistrationType::AllOnLaunch,
+            ),
+            attribute_flags: non_default(attribute_flags, 0).map(|bits| {
+                [AttributeFlag::Demo, AttributeFlag::RetailInteractiveDisplay]
+                    .iter()
+                    .cloned()
+                    .filter(|&flag| bits & (1 << flag as u32) != 0)
+                    .collect()
+            }),
+            supported_languages: non_default(LittleEndian::read_u32(&buf[0x302C..]), 0)
+                .map(bits_to_langs),
+            parental_control_flags: non_default(parental_control_flags, 0)
+                .map(|_| vec![ParentalControlFlag::FreeCommunication]),
+            screenshot: non_default(
+                read_enum(&buf, 0x3034, Screenshot::from_u8)?,
+                Screenshot::Allow,
+            ),
+            video_capture: non_default(
+                read_enum(&buf, 0x3035, VideoCapture::from_u8)?,
+                VideoCapture::Disable,
+            ),
+            data_loss_confirmation: non_default(
+                read_enum(&buf, 0x3036, DataLossConfirmation::from_u8)?,
+                DataLossConfirmation::None,
+            ),
+            play_log_policy: non_default(
+                read_enum(&buf, 0x3037, PlayLogPolicy::from_u8)?,
+                PlayLogPolicy::All,
+            ),
+            presence_group_id: non_default(LittleEndian::read_u64(&buf[0x3038..]), title_id)
+                .map(format_title_id),
+            rating_age: non_default(RatingAges(rating_age), RatingAges::default()),
+            save_data_owner_id: None,
+            user_account_save_data_size: size(0x3080),
+            user_account_save_data_journal_size: size(0x3088),
+            device_save_data_size: size(0x3090),
+            device_save_data_journal_size: size(0x3098),
+            bcat_delivery_cache_storage_size: size(0x30A0),
+            application_error_code_category: non_default(
+                read_string(&buf[0x30A8..0x30B0])?,
+                String::new(),
+            ),
+            local_communication_id: if local_communication_id
+                == NacpFile::default_local_communication_id(title_id)
+            {
+                None
+            } else if local_communication_id.iter().all(|&id| id == title_id) {
+                Some(LocalCommunicationIds::Auto(Auto::Auto))
+            } else {
+                // Empty entries at the end are filled back in when writing.
+                let len = local_communication_id
+                    .iter()
+                    .rposition(|&id| id != 0)
+                    .map_or(0, |idx| idx + 1);
+                Some(LocalCommunicationIds::Ids(
+                    local_communication_id[..len]
+                        .iter()
+                        .cloned()
+                        .map(format_title_id)
+                        .collect(),
+                ))
+            },
+            logo_type: non_default(
+                read_enum(&buf, 0x30F0, LogoType::from_u8)?,
+                LogoType::LicensedByNintendo,
+            ),
+            logo_handling: non_default(
+                read_enum(&buf, 0x30F1, LogoHandling::from_u8)?,
+                LogoHandling::Auto,
+            ),
+            runtime_add_on_content_install: non_default(
+                read_enum(&buf, 0x30F2, RuntimeAddOnContentInstall::from_u8)?,
+                RuntimeAddOnContentInstall::Deny,
+            ),
+            runtime_parameter_delivery: non_default(
+                read_enum(&buf, 0x30F3, RuntimeParameterDelivery::from_u8)?,
+                RuntimeParameterDelivery::Always,
+            ),
+            crash_report: non_default(
+                read_enum(&buf, 0x30F6, CrashReport::from_u8)?,
+                CrashReport::Deny,
+            ),
+            hdcp: non_default(read_enum(&buf, 0x30F7, Hdcp::from_u8)?, Hdcp::None),
+            seed_for_pseudo_device_id: non_default(
+                LittleEndian::read_u64(&buf[0x30F8..]),
+                title_id,
+            )
+            .map(format_title_id),
+            bcat_passphrase: non_default(read_string(&buf[0x3100..0x3141])?, String::new()),
+            startup_user_account_optional: non_default(buf[0x3141] & 1 != 0, false),
+            user_account_save_data_size_max: size(0x3148),
+            user_account_save_data_journal_size_max: size(0x3150),
+            device_save_data_size_max: size(0x3158),
+            device_save_data_journal_size_max: size(0x3160),
+            temporary_storage_size: size(0x3168),
+            cache_storage_size: size(0x3170),
+            cache_storage_journal_size: size(0x3178),
+            cache_storage_data_and_journal_size_max: size(0x3180),
+            cache_storage_index_max: non_default(LittleEndian::read_u16(&buf[0x3188..]), 0),
+            play_log_queryable_application_id: non_default(
+                play_log_queryable_application_id,
+                Vec::new(),
+            ),
+            play_log_query_capability: non_default(
+                read_enum(&buf, 0x3210, PlayLogQueryCapability::from_u8)?,
+                PlayLogQueryCapability::None,
+            ),
+            repair_flags: non_default(repair_flags, 0)
+                .map(|_| vec![RepairFlag::SuppressGameCardAccess]),
+            program_index: non_default(buf[0x3212], 0),
+            required_network_service_license_on_launch: non_default(
+                read_enum(&buf, 0x3213, RequiredNetworkServiceLicenseOnLaunch::from_u8)?,
+                RequiredNetworkServiceLicenseOnLaunch::None,
+            ),
+            neighbor_detection_client_configuration,
+            jit_configuration,
+        })
+    }
+
+    /// Checks for settings that are each valid on their own but that the
+    /// system rejects when combined, so they are caught before writing.
+    pub fn validate(&self) -> Result<(), Error> {
+        let ids = [
+            ("title_id", &self.title_id),
+            ("dlc_base_title_id", &self.dlc_base_title_id),
+            ("presence_group_id", &self.presence_group_id),
+            ("save_data_owner_id", &self.save_data_owner_id),
+            ("seed_for_pseudo_device_id", &self.seed_for_pseudo_device_id),
+        ];
+        let local_communication_id = match &self.local_communication_id {
+            Some(LocalCommunicationIds::Ids(ids)) => &ids[..],
+            _ => &[],
+        };
+        let ids = ids
+            .iter()
+            .filter_map(|&(field, id)| id.as_ref().map(|id| (field, id)))
+            .chain(
+                local_communication_id
+                    .iter()
+                    .map(|id| ("local_communication_id", id)),
+            );
+        for (field, id) in ids {
+            if u64::from_str_radix(id, 16).is_err() {
+                return Err(invalid_field(
+                    field,
+                    "ids must be hexadecimal 64-bit numbers",
+                ));
+            }
+        }
+        if local_communication_id.len() > 8 {
+            return Err(invalid_field(
+                "local_communication_id",
+                "there can't be more than 8 local communication ids",
+            ));
+        }
+
+        if self.screenshot == Some(Screenshot::Deny)
+            && self
+                .video_capture
+                .map_or(false, |v| v != VideoCapture::Disable)
+        {
+            return Err(invalid_field(
+                "video_capture",
+                "video capture must be disabled when screenshots are denied",
+            ));
+        }
+        let requires_account = self
+            .startup_user_account
+            .map_or(false, |v| v != StartupUserAccount::None);
+        if !requires_account && self.user_account_switch_lock == Some(UserAccountSwitchLock::Enable)
+        {
+            return Err(invalid_field(
+                "user_account_switch_lock",
+                "the user account switch lock requires a startup user account",
+            ));
+        }
+        if !requires_account && self.startup_user_account_optional == Some(true) {
+            return Err(invalid_field(
+                "startup_user_account_optional",
+                "an optional startup user account requires a startup user account",
+            ));
+        }
+
+        let size = |size: &Option<HexOrNum>| size.as_ref().map_or(0, |v| v.0);
+        let sizes = [
+            (
+                "user_account_save_data_size",
+                &self.user_account_save_data_size,
+            ),
+            (
+                "user_account_save_data_journal_size",
+                &self.user_account_save_data_journal_size,
+            ),
+            ("device_save_data_size", &self.device_save_data_size),
+            (
+                "device_save_data_journal_size",
+                &self.device_save_data_journal_size,
+            ),
+            (
+                "bcat_delivery_cache_storage_size",
+                &self.bcat_delivery_cache_storage_size,
+            ),
+            (
+                "user_account_save_data_size_max",
+                &self.user_account_save_data_size_max,
+            ),
+            (
+                "user_account_save_data_journal_size_max",
+                &self.user_account_save_data_journal_size_max,
+            ),
+            ("device_save_data_size_max", &self.device_save_data_size_max),
+            (
+                "device_save_data_journal_size_max",
+                &self.device_save_data_journal_size_max,
+            ),
+            ("temporary_storage_size", &self.temporary_storage_size),
+            ("cache_storage_size", &self.cache_storage_size),
+            (
+                "cache_storage_journal_size",
+                &self.cache_storage_journal_size,
+            ),
+            (
+                "cache_storage_data_and_journal_size_max",
+                &self.cache_storage_data_and_journal_size_max,
+            ),
+        ];
+        if let Some(&(field, _)) = sizes.iter().find(|(_, v)| size(v) % 0x4000 != 0) {
+            return Err(invalid_field(
+                field,
+                "storage sizes must be multiples of 0x4000",
+            ));
+        }
+        let pairs = [
+            (sizes[0], sizes[1]),
+            (sizes[2], sizes[3]),
+            (sizes[10], sizes[11]),
+        ];
+        for &((data_field, data), (journal_field, journal)) in pairs.iter() {
+            if (size(data) == 0) != (size(journal) == 0) {
+                let field = if size(data) == 0 {
+                    data_field
+                } else {
+                    journal_field
+                };
+                return Err(invalid_field(
+                    field,
+                    "save data and journal sizes must be set together",
+                ));
+            }
+        }
+        let maximums = [
+            (sizes[0], sizes[5]),
+            (sizes[1], sizes[6]),
+            (sizes[2], sizes[7]),
+            (sizes[3], sizes[8]),
+        ];
+        for &((field, value), (_, max)) in maximums.iter() {
+            if max.is_some() && size(value) > size(max) {
+                return Err(invalid_field(
+                    field,
+                    "save data sizes can't be bigger than their maximum",
+                ));
+            }
+        }
+        if self.cache_storage_index_max.unwrap_or(0) != 0 && size(&self.cache_storage_size) == 0 {
+            return Err(invalid_field(
+                "cache_storage_index_max",
+                "cache_storage_index_max requires a cache storage size",
+            ));
+        }
+
+        if let Some(ids) = &self.play_log_queryable_application_id {
+            if ids.len() > 16 {
+                return Err(invalid_field(
+                    "play_log_queryable_application_id",
+                    "there can't be more than 16 play log queryable application ids",
+                ));
+            }
+            if ids.iter().any(|id| u64::from_str_radix(id, 16).is_err()) {
+                return Err(invalid_field(
+                    "play_log_queryable_application_id",
+                    "ids must be hexadecimal 64-bit numbers",
+                ));
+            }
+            if !ids.is_empty()
+                && self.play_log_query_capability != Some(PlayLogQueryCapability::WhiteList)
+            {
+                return Err(invalid_field(
+                    "play_log_query_capability",
+                    "play log queryable application ids need the WhiteList query capability",
+                ));
+            }
+        }
+        if let Some(config) = &self.neighbor_detection_client_configuration {
+            if config.receivable_group_configurations.len() > 16 {
+                return Err(invalid_field(
+                    "neighbor_detection_client_configuration.receivable_group_configurations",
+                    "there can't be more than 16 receivable neighbor detection groups",
+                ));
+            }
+            let groups = std::iter::once(&config.send_group_configuration)
+                .chain(config.receivable_group_configurations.iter());
+            for group in groups {
+                if u64::from_str_radix(&group.group_id, 16).is_err()
+                    || parse_hex_bytes(&group.key, &mut [0; 0x10]).is_none()
+                {
+                    return Err(invalid_field(
+                        "neighbor_detection_client_configuration",
+                        "neighbor detection groups need a 64-bit hexadecimal group_id and a 16 bytes hexadecimal key",
+                    ));
+                }
+            }
+        }
+        if let Some(jit) = &self.jit_configuration {
+            if jit.enabled && jit.memory_size.0 == 0 {
+                return Err(invalid_field(
+                    "jit_configuration.memory_size",
+                    "JIT needs a memory size",
+                ));
+            }
+        }
+
+        // Supported languages without their own entry use the top-level name,
+        // so one of them has to be there.
+        if let Some(languages) = &self.supported_languages {
+            let entries = self.lang.as_ref().map(NacpLangEntries::entries);
+            let missing = languages.iter().any(|&lang| {
+                entries
+                    .as_ref()
+                    .map_or(true, |entries| entries[lang as usize].is_none())
+            });
+            if missing && self.name.is_none() {
+                return Err(invalid_field(
+                    "supported_languages",
+                    "a supported language has neither a lang entry nor a default name",
+                ));
+            }
+        }
         Ok(())
     }
 
+    /// Returns the descriptor `from_reader` gives for the NACP this one
+    /// writes: defaults are left out and presets are applied.
+    pub fn canonical(&self) -> Result<NacpFile, Error> {
+        let mut buf = Vec::new();
+        self.clone().write(&mut buf)?;
+        NacpFile::from_reader(&buf[..])
+    }
+
+    fn default_local_communication_id(title_id: u64) -> Vec<u64> {
+        vec![title_id, title_id, 0, 0, 0, 0, 0, 0]
+    }
+
+    fn write_lang_entry(buf: &mut [u8], lang_entry: &NacpLangEntry) {
+        write_string(&mut buf[..0x200], &lang_entry.name, "name");
+        write_string(&mut buf[0x200..0x300], &lang_entry.author, "author");
+    }
+
     /// The size in bytes of this entry once serialized.
     pub fn len(&self) -> usize {
         0x4000
     }
 
-    pub fn write<T>(&mut self, output_writter: &mut T) -> std::io::Result<()>
+    pub fn write<T>(&mut self, output_writter: &mut T) -> Result<(), Error>
     where
         T: Write,
     {
-        let mut name = self
+        let mut buf = vec![0; 0x4000];
+        self.write_to_buffer(&mut buf)?;
+        output_writter.write_all(&buf)?;
+        Ok(())
+    }
+
+    /// Writes the fields of this NACP over `buf`, an existing 0x4000 bytes NACP.
+    /// Reserved bytes, and fields linkle doesn't know about, are left as is.
+    ///
+    /// Fails with the field at fault if an ID or key isn't valid hexadecimal,
+    /// which `validate` also checks.
+    pub fn write_to_buffer(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        self.apply_preset();
+
+        let name = self
             .name
             .clone()
             .unwrap_or_else(|| "Unknown Application".to_string());
-        let mut version = self.version.clone().unwrap_or_else(|| "1.0.0".to_string());
-        let mut author = self
+        let version = self.version.clone().unwrap_or_else(|| "1.0.0".to_string());
+        let author = self
             .author
             .clone()
             .unwrap_or_else(|| "Unknown Author".to_string());
 
-        let title_id = match &self.title_id {
-            None => 0,
-            Some(title_string) => {
-                u64::from_str_radix(title_string.as_str(), 16).expect("Invalid title_id provided!")
-            }
-        };
+        let title_id = parse_title_id(&self.title_id, "title_id")?.unwrap_or(0);
+        let dlc_base_title_id = parse_title_id(&self.dlc_base_title_id, "dlc_base_title_id")?
+            .unwrap_or(title_id + 0x1000);
+
+        // fallback entry if lang entry isn't defined
+        let default_lang_entry = NacpLangEntry { name, author };
+        let lang_entries = self.lang.as_ref().map(NacpLangEntries::entries);
+        for idx in 0..16 {
+            let entry = lang_entries
+                .as_ref()
+                .and_then(|entries| entries.get(idx))
+                .and_then(|entry| entry.as_ref())
+                .unwrap_or(&default_lang_entry);
+            NacpFile::write_lang_entry(&mut buf[idx * 0x300..(idx + 1) * 0x300], entry);
+        }
+
+        write_string(
+            &mut buf[0x3000..0x3025],
+            self.isbn.as_deref().unwrap_or(""),
+            "isbn",
+        );
+        buf[0x3025] = self.startup_user_account.map_or(0, |v| v as u8);
+        buf[0x3026] = self.user_account_switch_lock.map_or(0, |v| v as u8);
+        buf[0x3027] = self.add_on_content_registration_type.map_or(0, |v| v as u8);
+        LittleEndian::write_u32(
+            &mut buf[0x3028..],
+            self.attribute_flags
+                .as_ref()
+                .map_or(0, |v| flags_to_bits(v)),
+        );
+        LittleEndian::write_u32(
+            &mut buf[0x302C..],
+            self.supported_languages
+                .as_ref()
+                .map_or(0, |v| flags_to_bits(v)),
+        );
+        LittleEndian::write_u32(
+            &mut buf[0x3030..],
+            self.parental_control_flags
+                .as_ref()
+                .map_or(0, |v| flags_to_bits(v)),
+        );
+        buf[0x3034] = self.screenshot.map_or(0, |v| v as u8);
+        buf[0x3035] = self.video_capture.map_or(0, |v| v as u8);
+        buf[0x3036] = self.data_loss_confirmation.map_or(0, |v| v as u8);
+        buf[0x3037] = self.play_log_policy.map_or(0, |v| v as u8);
+        LittleEndian::write_u64(
+            &mut buf[0x3038..],
+            parse_title_id(&self.presence_group_id, "presence_group_id")?.unwrap_or(title_id),
+        );
 
-        let dlc_base_title_id = match &self.dlc_base_title_id {
-            None => title_id + 0x1000,
-            Some(title_string) => {
-                u64::from_str_radix(title_string.as_str(), 16).expect("Invalid title_id provided!")
+        // Unrated for every organization by default.
+        for age in &mut buf[0x3040..0x3060] {
+            *age = 0xFF;
+        }
+        if let Some(rating_age) = &self.rating_age {
+            for (&organization, &age) in &rating_age.0 {
+                buf[0x3040 + organization as usize] = age;
             }
-        };
+        }
 
-        let lang_entries = &self.lang;
+        write_string(&mut buf[0x3060..0x3070], &version, "version");
+        LittleEndian::write_u64(&mut buf[0x3070..], dlc_base_title_id);
+        LittleEndian::write_u64(
+            &mut buf[0x3078..],
+            parse_title_id(&self.save_data_owner_id, "save_data_owner_id")?.unwrap_or(title_id),
+        );
 
-        // Truncate names if needed
-        utils::check_string_or_truncate(&mut name, "name", 0x200);
-        utils::check_string_or_truncate(&mut version, "version", 0x10);
-        utils::check_string_or_truncate(&mut author, "author", 0x100);
+        let sizes = [
+            (0x3080, &self.user_account_save_data_size),
+            (0x3088, &self.user_account_save_data_journal_size),
+            (0x3090, &self.device_save_data_size),
+            (0x3098, &self.device_save_data_journal_size),
+            (0x30A0, &self.bcat_delivery_cache_storage_size),
+            (0x3148, &self.user_account_save_data_size_max),
+            (0x3150, &self.user_account_save_data_journal_size_max),
+            (0x3158, &self.device_save_data_size_max),
+            (0x3160, &self.device_save_data_journal_size_max),
+            (0x3168, &self.temporary_storage_size),
+            (0x3170, &self.cache_storage_size),
+            (0x3178, &self.cache_storage_journal_size),
+            (0x3180, &self.cache_storage_data_and_journal_size_max),
+        ];
+        for (offset, size) in sizes.iter() {
+            LittleEndian::write_u64(&mut buf[*offset..], size.as_ref().map_or(0, |v| v.0));
+        }
 
-        // fallback entry if lang entry isn't defined
-        let default_lang_entry = NacpLangEntry { name, author };
-        match lang_entries {
-            None => {
-                for _ in 0..16 {
-                    self.write_lang_entry(output_writter, &default_lang_entry)?;
-                }
-            }
-            Some(data) => {
-                let lang_entries = data;
-
-                // Write every langs in order
-                self.write_lang_entry(
-                    output_writter,
-                    &lang_entries
-                        .en_us
-                        .clone()
-                        .unwrap_or_else(|| default_lang_entry.clone()),
-                )?;
-                self.write_lang_entry(
-                    output_writter,
-                    &lang_entries
-                        .en_gb
-                        .clone()
-                        .unwrap_or_else(|| default_lang_entry.clone()),
-                )?;
-                self.write_lang_entry(
-                    output_writter,
-                    &lang_entries
-                        .ja
-                        .clone()
-                        .unwrap_or_else(|| default_lang_entry.clone()),
-                )?;
-                self.write_lang_entry(
-                    output_writter,
-                    &lang_entries
-                        .fr
-                        .clone()
-                        .unwrap_or_else(|| default_lang_entry.clone()),
-                )?;
-                self.write_lang_entry(
-                    output_writter,
-                    &lang_entries
-                        .de
-                        .clone()
-                        .unwrap_or_else(|| default_lang_entry.clone()),
-                )?;
-                self.write_lang_entry(
-                    output_writter,
-                    &lang_entries
-                        .es_419
-                        .clone()
-                        .unwrap_or_else(|| default_lang_entry.clone()),
-                )?;
-                self.write_lang_entry(
-                    output_writter,
-                    &lang_entries
-                        .es
-                        .clone()
-                        .unwrap_or_else(|| default_lang_entry.clone()),
-                )?;
-                self.write_lang_entry(
-                    output_writter,
-                    &lang_entries
-                        .it
-                        .clone()
-                        .unwrap_or_else(|| default_lang_entry.clone()),
-                )?;
-                self.write_lang_entry(
-                    output_writter,
-                    &lang_entries
-                        .nl
-                        .clone()
-                        .unwrap_or_else(|| default_lang_entry.clone()),
-                )?;
-                self.write_lang_entry(
-                    output_writter,
-                    &lang_entries
-                        .fr_ca
-                        .clone()
-                        .unwrap_or_else(|| default_lang_entry.clone()),
-                )?;
-                self.write_lang_entry(
-                    output_writter,
-                    &lang_entries
-                        .pt
-                        .clone()
-                        .unwrap_or_else(|| default_lang_entry.clone()),
-                )?;
-                self.write_lang_entry(
-                    output_writter,
-                    &lang_entries
-                        .ru
-                        .clone()
-                        .unwrap_or_else(|| default_lang_entry.clone()),
-                )?;
-                self.write_lang_entry(
-                    output_writter,
-                    &lang_entries
-                        .ko
-                        .clone()
-                        .unwrap_or_else(|| default_lang_entry.clone()),
-                )?;
-                self.write_lang_entry(
-                    output_writter,
-                    &lang_entries
-                        .zh_tw
-                        .clone()
-                        .unwrap_or_else(|| default_lang_entry.clone()),
-                )?;
-                self.write_lang_entry(
-                    output_writter,
-                    &lang_entries
-                        .zh_cn
-                        .clone()
-                        .unwrap_or_else(|| default_lang_entry.clone()),
-                )?;
-                // There are 16 entries. One is missing :eyes:
-                self.write_lang_entry(output_writter, &default_lang_entry)?;
+        write_string(
+            &mut buf[0x30A8..0x30B0],
+            self.application_error_code_category
+                .as_deref()
+                .unwrap_or(""),
+            "application_error_code_category",
+        );
+
+        let local_communication_id = match &self.local_communication_id {
+            Some(LocalCommunicationIds::Ids(ids)) => ids
+                .iter()
+                .map(|id| {
+                    parse_title_id(&Some(id.clone()), "local_communication_id")
+                        .map(Option::unwrap_or_default)
+                })
+                .collect::<Result<_, _>>()?,
+            Some(LocalCommunicationIds::Auto(_)) => vec![title_id; 8],
+            None => NacpFile::default_local_communication_id(title_id),
+        };
+        for idx in 0..8 {
+            let id = local_communication_id.get(idx).cloned().unwrap_or(0);
+            LittleEndian::write_u64(&mut buf[0x30B0 + idx * 8..], id);
+        }
+
+        buf[0x30F0] = self.logo_type.map_or(0, |v| v as u8);
+        buf[0x30F1] = self.logo_handling.map_or(0, |v| v as u8);
+        buf[0x30F2] = self.runtime_add_on_content_install.map_or(0, |v| v as u8);
+        buf[0x30F3] = self.runtime_parameter_delivery.map_or(0, |v| v as u8);
+        buf[0x30F6] = self.crash_report.map_or(0, |v| v as u8);
+        buf[0x30F7] = self.hdcp.map_or(0, |v| v as u8);
+        LittleEndian::write_u64(
+            &mut buf[0x30F8..],
+            parse_title_id(&self.seed_for_pseudo_device_id, "seed_for_pseudo_device_id")?
+                .unwrap_or(title_id),
+        );
+        write_string(
+            &mut buf[0x3100..0x3141],
+            self.bcat_passphrase.as_deref().unwrap_or(""),
+            "bcat_passphrase",
+        );
+        buf[0x3141] = self.startup_user_account_optional.unwrap_or(false) as u8;
+        LittleEndian::write_u16(
+            &mut buf[0x3188..],
+            self.cache_storage_index_max.unwrap_or(0),
+        );
+
+        let queryable_ids = self
+            .play_log_queryable_application_id
+            .as_ref()
+            .map_or(&[][..], |ids| &ids[..]);
+        for (idx, slot) in buf[0x3190..0x3210].chunks_mut(8).enumerate() {
+            let id = parse_title_id(
+                &queryable_ids.get(idx).cloned(),
+                "play_log_queryable_application_id",
+            )?;
+            LittleEndian::write_u64(slot, id.unwrap_or(0));
+        }
+        buf[0x3210] = self.play_log_query_capability.map_or(0, |v| v as u8);
+        buf[0x3211] = self.repair_flags.as_ref().map_or(0, |v| flags_to_bits(v)) as u8;
+        buf[0x3212] = self.program_index.unwrap_or(0);
+        buf[0x3213] = self
+            .required_network_service_license_on_launch
+            .map_or(0, |v| v as u8);
+        for b in &mut buf[0x3218..0x33B0] {
+            *b = 0;
+        }
+        if let Some(config) = &self.neighbor_detection_client_configuration {
+            write_group_configuration(&mut buf[0x3218..0x3230], &config.send_group_configuration)?;
+            let receivable = buf[0x3230..0x33B0].chunks_mut(0x18);
+            for (buf, config) in receivable.zip(config.receivable_group_configurations.iter()) {
+                write_group_configuration(buf, config)?;
             }
         }
+        let jit = self.jit_configuration.unwrap_or(JitConfiguration {
+            enabled: false,
+            memory_size: HexOrNum(0),
+        });
+        LittleEndian::write_u64(&mut buf[0x33B0..], jit.enabled as u64);
+        LittleEndian::write_u64(&mut buf[0x33B8..], jit.memory_size.0);
+        Ok(())
+    }
+}
+
+/// Builds a NACP without going through a descriptor. Fields left unset get
+/// the same defaults as in descriptors.
+#[derive(Debug, Default, Clone)]
+pub struct NacpBuilder {
+    nacp: NacpFile,
+}
+
+impl NacpBuilder {
+    pub fn new() -> NacpBuilder {
+        NacpBuilder::default()
+    }
+
+    /// Fills the fields left unset with the values of `preset` when building.
+    pub fn preset(&mut self, preset: NacpPreset) -> &mut NacpBuilder {
+        self.nacp.preset = Some(preset);
+        self
+    }
+
+    /// Name of the application, for the languages without their own.
+    pub fn name(&mut self, name: &str) -> &mut NacpBuilder {
+        self.nacp.name = Some(name.to_string());
+        self
+    }
+
+    /// Author of the application, for the languages without their own.
+    pub fn author(&mut self, author: &str) -> &mut NacpBuilder {
+        self.nacp.author = Some(author.to_string());
+        self
+    }
 
-        // 0x3000 - 0x3038: Unknown
-        output_writter.write_all(&[0; 0x38])?;
+    /// The version shown to users, like `1.0.0`.
+    pub fn version(&mut self, version: &str) -> &mut NacpBuilder {
+        self.nacp.version = Some(version.to_string());
+        self
+    }
 
-        output_writter.write_u64::<LittleEndian>(title_id)?;
+    /// The program ID, which the other IDs default to.
+    pub fn program_id(&mut self, program_id: u64) -> &mut NacpBuilder {
+        self.nacp.title_id = Some(format_title_id(program_id));
+        self
+    }
 
-        // Unknown 0x20 bytes
-        let mut unknown = Vec::new();
-        unknown.resize(0x20, 0xFF);
-        output_writter.write_all(&unknown)?;
+    /// Name and author of the application in `language`.
+    pub fn lang(&mut self, language: Language, name: &str, author: &str) -> &mut NacpBuilder {
+        let entries = self.nacp.lang.get_or_insert_with(Default::default);
+        *entries.entries_mut()[language as usize] = Some(NacpLangEntry {
+            name: name.to_string(),
+            author: author.to_string(),
+        });
+        self
+    }
 
-        // Version string part (probably UTF8)
-        let version_padding = 0x10 - version.len();
-        output_writter.write_all(version.as_bytes())?;
-        output_writter.write_all(&vec![0; version_padding])?;
+    pub fn supported_languages(&mut self, languages: &[Language]) -> &mut NacpBuilder {
+        self.nacp.supported_languages = Some(languages.to_vec());
+        self
+    }
 
-        output_writter.write_u64::<LittleEndian>(dlc_base_title_id)?;
-        output_writter.write_u64::<LittleEndian>(title_id)?;
+    pub fn startup_user_account(&mut self, value: StartupUserAccount) -> &mut NacpBuilder {
+        self.nacp.startup_user_account = Some(value);
+        self
+    }
 
-        //  0x3080 - 0x30B0: Unknown
-        output_writter.write_all(&[0; 0x30])?;
+    pub fn screenshot(&mut self, value: Screenshot) -> &mut NacpBuilder {
+        self.nacp.screenshot = Some(value);
+        self
+    }
 
-        output_writter.write_u64::<LittleEndian>(title_id)?;
+    pub fn video_capture(&mut self, value: VideoCapture) -> &mut NacpBuilder {
+        self.nacp.video_capture = Some(value);
+        self
+    }
 
-        // title id array (0x7 entries), only write the base, other entries seems to be for update titles
-        output_writter.write_u64::<LittleEndian>(title_id)?;
-        output_writter.write_all(&[0; 0x30])?;
+    pub fn logo_type(&mut self, value: LogoType) -> &mut NacpBuilder {
+        self.nacp.logo_type = Some(value);
+        self
+    }
 
-        // 0x30F0 - 0x30F8: Unknown
-        output_writter.write_u64::<LittleEndian>(0)?;
-        output_writter.write_u64::<LittleEndian>(title_id)?;
+    /// Size of the save data of each user account, and of its journal.
+    pub fn user_account_save_data_size(
+        &mut self,
+        size: u64,
+        journal_size: u64,
+    ) -> &mut NacpBuilder {
+        self.nacp.user_account_save_data_size = Some(HexOrNum(size));
+        self.nacp.user_account_save_data_journal_size = Some(HexOrNum(journal_size));
+        self
+    }
 
-        let mut end_of_file = Vec::new();
-        end_of_file.resize(0xF00, 0);
-        output_writter.write_all(&end_of_file)?;
+    /// Size of the save data shared by every user account, and of its journal.
+    pub fn device_save_data_size(&mut self, size: u64, journal_size: u64) -> &mut NacpBuilder {
+        self.nacp.device_save_data_size = Some(HexOrNum(size));
+        self.nacp.device_save_data_journal_size = Some(HexOrNum(journal_size));
+        self
+    }
+
+    /// The descriptor built so far, to set the fields without a setter.
+    pub fn nacp_mut(&mut self) -> &mut NacpFile {
+        &mut self.nacp
+    }
 
+    /// Validates the fields, then writes the NACP.
+    pub fn build<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        self.nacp.validate()?;
+        self.nacp.clone().write(writer)?;
         Ok(())
     }
 }
 
+/// A field that differs between two NACPs, as returned by `diff`. Fields of
+/// nested objects are named with their path, e.g. `lang.ja.name`. Values are
+/// `None` when the field has its default value.
+#[derive(Debug, Clone, Serialize)]
+pub struct NacpChange {
+    pub field: String,
+    pub old: Option<serde_json::Value>,
+    pub new: Option<serde_json::Value>,
+}
+
+fn flatten_fields(
+    prefix: &str,
+    value: serde_json::Value,
+    fields: &mut BTreeMap<String, serde_json::Value>,
+) {
+    match value {
+        serde_json::Value::Object(object) => {
+            for (key, value) in object {
+                let name = if prefix.is_empty() {
+                    key
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten_fields(&name, value, fields);
+            }
+        }
+        value => {
+            fields.insert(prefix.to_string(), value);
+        }
+    }
+}
+
+/// Compares the fields of two NACPs, once written. Descriptors setting a
+/// field to its default value are the same as descriptors leaving it out.
+/// The changes are sorted by field name.
+pub fn diff(old: &NacpFile, new: &NacpFile) -> Result<Vec<NacpChange>, Error> {
+    let mut old_fields = BTreeMap::new();
+    flatten_fields("", serde_json::to_value(old.canonical()?)?, &mut old_fields);
+    let mut new_fields = BTreeMap::new();
+    flatten_fields("", serde_json::to_value(new.canonical()?)?, &mut new_fields);
+
+    let mut changes = Vec::new();
+    for (field, old_value) in old_fields.iter() {
+        if new_fields.get(field) != Some(old_value) {
+            changes.push(NacpChange {
+                field: field.clone(),
+                old: Some(old_value.clone()),
+                new: new_fields.get(field).cloned(),
+            });
+        }
+    }
+    for (field, new_value) in new_fields {
+        if !old_fields.contains_key(&field) {
+            changes.push(NacpChange {
+                field,
+                old: None,
+                new: Some(new_value),
+            });
+        }
+    }
+    changes.sort_by(|a, b| a.field.cmp(&b.field));
+    Ok(changes)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -294,4 +1811,38 @@ mod test {
         NacpFile::default().write(&mut buf).unwrap();
         assert_eq!(buf.len(), 0x4000, "Nacp length is wrong");
     }
+
+    #[test]
+    fn nacp_round_trips() {
+        let mut nacp: NacpFile = serde_json::from_str(
+            r#"{
+                "name": "Test",
+                "author": "Someone",
+                "title_id": "0100000000001000",
+                "lang": { "ja": { "name": "テスト", "author": "誰か" } },
+                "supported_languages": ["en-US", "ja"],
+                "screenshot": "Deny",
+                "user_account_save_data_size": "0x400000",
+                "startup_user_account": "Required"
+            }"#,
+        )
+        .unwrap();
+        let mut buf = Vec::new();
+        nacp.write(&mut buf).unwrap();
+
+        let mut parsed = NacpFile::from_reader(&buf[..]).unwrap();
+        let mut rewritten = Vec::new();
+        parsed.write(&mut rewritten).unwrap();
+        assert!(buf == rewritten, "Nacp changed after a round trip");
+    }
+
+    #[test]
+    fn invalid_ids_fail_with_their_field() {
+        let mut nacp = NacpFile {
+            presence_group_id: Some("not hex".to_string()),
+            ..NacpFile::default()
+        };
+        let err = nacp.write(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.field(), Some("presence_group_id"));
+    }
 }
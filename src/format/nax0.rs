@@ -0,0 +1,148 @@
+//! NAX0, the encryption of the NCAs and saves installed to the SD card.
+//!
+//! The file starts with a 0x4000 bytes header holding its AES-XTS keys,
+//! encrypted with keys derived from the console unique SD card keys and from
+//! the path of the file on the SD card. The data follows, in 0x4000 bytes
+//! sectors numbered from the end of the header.
+
+use crate::error::Error;
+use crate::pki::{Aes128Key, AesXtsKey, Keys};
+use crate::utils::read_full;
+use byteorder::{ByteOrder, LittleEndian};
+use sha2::{Digest, Sha256};
+use snafu::Backtrace;
+use snafu::GenerateBacktrace;
+use std::io::{Read, Write};
+
+/// Size of the header, and of the AES-XTS sectors of the data.
+pub const NAX0_SECTOR_SIZE: usize = 0x4000;
+
+fn invalid_nax0(error: &'static str) -> Error {
+    Error::InvalidNax0 {
+        error,
+        backtrace: Backtrace::generate(),
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 0x20] {
+    let mut inner_pad = [0x36; 0x40];
+    let mut outer_pad = [0x5C; 0x40];
+    for (i, byte) in key.iter().enumerate() {
+        inner_pad[i] ^= byte;
+        outer_pad[i] ^= byte;
+    }
+    let mut inner = Sha256::default();
+    inner.update(inner_pad);
+    inner.update(data);
+    let mut outer = Sha256::default();
+    outer.update(outer_pad);
+    outer.update(inner.finalize());
+    let mut mac = [0; 0x20];
+    mac.copy_from_slice(&outer.finalize());
+    mac
+}
+
+/// Header of a NAX0 file.
+#[derive(Debug)]
+pub struct Nax0Header {
+    /// Decrypted AES-XTS key of the data.
+    pub key: AesXtsKey,
+    /// Size of the decrypted data.
+    pub size: u64,
+    /// Whether the file was decrypted with the SD card save key rather than
+    /// the NCA one.
+    pub is_save: bool,
+}
+
+impl Nax0Header {
+    /// Decrypts the keys of `header` with the SD card key matching its MAC.
+    /// `path` is the path of the file from the `Nintendo/Contents` or
+    /// `Nintendo/save` directory of the SD card, e.g.
+    /// `/registered/000000ab/0123456789abcdef0123456789abcdef.nca`.
+    pub fn from_bytes(keys: &Keys, header: &[u8], path: &str) -> Result<Nax0Header, Error> {
+        if header.len() < 0x60 || &header[0x20..0x24] != b"NAX0" {
+            return Err(invalid_nax0("bad magic"));
+        }
+
+        let mut first_error = None;
+        for &is_save in &[false, true] {
+            let sd_key = if is_save {
+                keys.sd_card_save_key()
+            } else {
+                keys.sd_card_nca_key()
+            };
+            let sd_key = match sd_key {
+                Ok(sd_key) => sd_key.as_bytes(),
+                Err(err) => {
+                    first_error.get_or_insert(err);
+                    continue;
+                }
+            };
+
+            let keks = hmac_sha256(&sd_key[..0x10], path.as_bytes());
+            let mut decrypted = [0; 0x40];
+            decrypted.copy_from_slice(&header[0x20..0x60]);
+            for (idx, kek) in keks.chunks(0x10).enumerate() {
+                let mut kek_bytes = [0; 0x10];
+                kek_bytes.copy_from_slice(kek);
+                let mut encrypted_key = [0; 0x10];
+                encrypted_key.copy_from_slice(&header[0x28 + idx * 0x10..0x38 + idx * 0x10]);
+                let key = Aes128Key::from(kek_bytes).derive_key(&encrypted_key)?;
+                decrypted[0x8 + idx * 0x10..0x18 + idx * 0x10].copy_from_slice(key.as_bytes());
+            }
+            if hmac_sha256(&sd_key[0x10..], &decrypted)[..] != header[..0x20] {
+                continue;
+            }
+
+            let mut key = [0; 0x20];
+            key.copy_from_slice(&decrypted[0x8..0x28]);
+            return Ok(Nax0Header {
+                key: AesXtsKey::from(key),
+                size: LittleEndian::read_u64(&header[0x48..]),
+                is_save,
+            });
+        }
+        Err(first_error.unwrap_or_else(|| {
+            invalid_nax0(
+                "no SD card key matches the header MAC, check the SD card path and sd_seed",
+            )
+        }))
+    }
+}
+
+/// Decrypts the NAX0 file read from `input` to `output`, returning its
+/// header. See `Nax0Header::from_bytes` for `path`.
+pub fn decrypt<R: Read, W: Write>(
+    keys: &Keys,
+    path: &str,
+    mut input: R,
+    mut output: W,
+) -> Result<Nax0Header, Error> {
+    let mut header = vec![0; NAX0_SECTOR_SIZE];
+    if read_full(&mut input, &mut header)? != NAX0_SECTOR_SIZE {
+        return Err(invalid_nax0("truncated header"));
+    }
+    let header = Nax0Header::from_bytes(keys, &header, path)?;
+
+    let mut buf = vec![0; NAX0_SECTOR_SIZE * 0x40];
+    let mut sector = 0;
+    let mut remaining = header.size;
+    while remaining > 0 {
+        let read = read_full(&mut input, &mut buf)?;
+        if read == 0 {
+            return Err(invalid_nax0("data is smaller than the size in the header"));
+        }
+        if read % NAX0_SECTOR_SIZE != 0 {
+            return Err(invalid_nax0("data is not made of whole sectors"));
+        }
+        header
+            .key
+            .decrypt(&mut buf[..read], sector, NAX0_SECTOR_SIZE)?;
+        let write = std::cmp::min(read as u64, remaining) as usize;
+        output.write_all(&buf[..write])?;
+        remaining -= write as u64;
+        sector += (read / NAX0_SECTOR_SIZE) as u64;
+    }
+    output.flush()?;
+    Ok(header)
+}
@@ -0,0 +1,2964 @@
+use crate::crypto::AesCtr;
+use crate::error::{Error, ResultExt};
+use crate::format::cnmt::Cnmt;
+use crate::format::container::{ContainerEntry, SwitchFormat};
+use crate::format::ivfc::{self, hash_blocks, IvfcBuilder};
+pub use crate::format::ivfc::{IvfcLevel, Region};
+use crate::format::npdm::NpdmInfo;
+use crate::format::parse::{ParseMode, Regions};
+use crate::format::pfs0::{read_entries, Pfs0, Pfs0Entry, ReadSeek};
+use crate::format::romfs::{RomFs, RomFsReader};
+use crate::format::utils::{serialize_hex_bytes, serialize_title_id};
+use crate::pki::{rsa2048_pss_verify, Aes128Key, KeyAreaKeyIndex, Keys, RsaPrivateKey};
+use crate::utils::{add_offset, align_up, ReadRange, TryClone};
+use byteorder::{ByteOrder, LittleEndian};
+use serde::Serializer;
+use serde_derive::Serialize;
+use sha2::{Digest, Sha256};
+use snafu::Backtrace;
+use snafu::GenerateBacktrace;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Size of the NCA header, fs headers included.
+pub const NCA_HEADER_SIZE: usize = 0xC00;
+
+/// Size of the sectors the header is encrypted with, and of the media units
+/// the section table is expressed in.
+pub const MEDIA_SIZE: u64 = 0x200;
+
+fn invalid_nca(error: &'static str) -> Error {
+    Error::InvalidNca {
+        error,
+        backtrace: Backtrace::generate(),
+    }
+}
+
+macro_rules! nca_enum {
+    ($(#[$meta:meta])* pub enum $name:ident { $($variant:ident = $value:expr => $display:expr,)* }) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+        #[serde(rename_all = "snake_case")]
+        pub enum $name {
+            $($variant = $value,)*
+        }
+
+        impl $name {
+            pub fn from_u8(value: u8) -> Option<$name> {
+                match value {
+                    $($value => Some($name::$variant),)*
+                    _ => None,
+                }
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(match self {
+                    $($name::$variant => $display,)*
+                })
+            }
+        }
+    };
+}
+
+nca_enum! {
+    pub enum DistributionType {
+        Download = 0 => "Download",
+        GameCard = 1 => "Gamecard",
+    }
+}
+
+nca_enum! {
+    pub enum ContentType {
+        Program = 0 => "Program",
+        Meta = 1 => "Meta",
+        Control = 2 => "Control",
+        Manual = 3 => "Manual",
+        Data = 4 => "Data",
+        PublicData = 5 => "PublicData",
+    }
+}
+
+impl FromStr for ContentType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ContentType, String> {
+        match s {
+            "program" => Ok(ContentType::Program),
+            "meta" => Ok(ContentType::Meta),
+            "control" => Ok(ContentType::Control),
+            "manual" => Ok(ContentType::Manual),
+            "data" => Ok(ContentType::Data),
+            "public_data" => Ok(ContentType::PublicData),
+            _ => Err(format!(
+                "Unknown content type {}, expected one of program, meta, control, manual, data, public_data",
+                s
+            )),
+        }
+    }
+}
+
+nca_enum! {
+    pub enum FsType {
+        RomFs = 0 => "RomFS",
+        PartitionFs = 1 => "PFS0",
+    }
+}
+
+impl FromStr for FsType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<FsType, String> {
+        match s {
+            "pfs0" => Ok(FsType::PartitionFs),
+            "romfs" => Ok(FsType::RomFs),
+            _ => Err(format!(
+                "Unknown section type {}, expected pfs0 or romfs",
+                s
+            )),
+        }
+    }
+}
+
+nca_enum! {
+    pub enum HashType {
+        Auto = 0 => "Auto",
+        None = 1 => "None",
+        HierarchicalSha256 = 2 => "Hierarchical SHA-256",
+        HierarchicalIntegrity = 3 => "Hierarchical integrity (IVFC)",
+    }
+}
+
+nca_enum! {
+    pub enum EncryptionType {
+        Auto = 0 => "Auto",
+        None = 1 => "None",
+        AesXts = 2 => "AES-XTS",
+        AesCtr = 3 => "AES-CTR",
+        AesCtrEx = 4 => "AES-CTR (BKTR)",
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HashInfo {
+    None,
+    /// Used by PFS0 sections: a table of SHA-256 of every block of the PFS0,
+    /// itself hashed in the master hash.
+    HierarchicalSha256 {
+        #[serde(serialize_with = "serialize_hex_bytes")]
+        master_hash: [u8; 0x20],
+        block_size: u32,
+        hash_table: Region,
+        pfs0: Region,
+    },
+    /// Used by RomFS sections: an IVFC tree, whose last level is the RomFS.
+    HierarchicalIntegrity {
+        #[serde(serialize_with = "serialize_hex_bytes")]
+        master_hash: [u8; 0x20],
+        levels: Vec<IvfcLevel>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FsHeader {
+    pub version: u16,
+    pub fs_type: FsType,
+    pub hash_type: HashType,
+    pub encryption_type: EncryptionType,
+    pub hash_info: HashInfo,
+    #[serde(serialize_with = "serialize_hex_bytes")]
+    pub patch_info: [u8; 0x40],
+    /// Upper half of the AES-CTR counter of the section.
+    pub ctr: u64,
+    /// Offset, size and BKTR header of the table of sparse sections, whose
+    /// data is partly left out of the NCA. Zeroed for other sections.
+    #[serde(serialize_with = "serialize_hex_bytes")]
+    pub sparse_info: [u8; 0x30],
+    /// Offset, size and BKTR header of the table of compressed sections,
+    /// mapping the decompressed section to the stored data. Zeroed for other
+    /// sections.
+    #[serde(serialize_with = "serialize_hex_bytes")]
+    pub compression_info: [u8; 0x28],
+}
+
+impl FsHeader {
+    fn parse(data: &[u8]) -> Result<FsHeader, Error> {
+        let fs_type = FsType::from_u8(data[0x2]).ok_or_else(|| invalid_nca("unknown fs type"))?;
+        let hash_type =
+            HashType::from_u8(data[0x3]).ok_or_else(|| invalid_nca("unknown hash type"))?;
+        let encryption_type = EncryptionType::from_u8(data[0x4])
+            .ok_or_else(|| invalid_nca("unknown encryption type"))?;
+
+        let hash_data = &data[0x8..0x100];
+        let hash_info = match hash_type {
+            HashType::HierarchicalSha256 => {
+                let mut master_hash = [0; 0x20];
+                master_hash.copy_from_slice(&hash_data[..0x20]);
+                HashInfo::HierarchicalSha256 {
+                    master_hash,
+                    block_size: LittleEndian::read_u32(&hash_data[0x20..]),
+                    hash_table: Region::read(&hash_data[0x28..]),
+                    pfs0: Region::read(&hash_data[0x38..]),
+                }
+            }
+            HashType::HierarchicalIntegrity => {
+                let (master_hash, levels) =
+                    ivfc::read_superblock(hash_data).map_err(invalid_nca)?;
+                HashInfo::HierarchicalIntegrity {
+                    master_hash,
+                    levels,
+                }
+            }
+            _ => HashInfo::None,
+        };
+
+        let mut patch_info = [0; 0x40];
+        patch_info.copy_from_slice(&data[0x100..0x140]);
+        let mut sparse_info = [0; 0x30];
+        sparse_info.copy_from_slice(&data[0x148..0x178]);
+        let mut compression_info = [0; 0x28];
+        compression_info.copy_from_slice(&data[0x178..0x1A0]);
+
+        Ok(FsHeader {
+            version: LittleEndian::read_u16(&data[0x0..]),
+            fs_type,
+            hash_type,
+            encryption_type,
+            hash_info,
+            patch_info,
+            ctr: LittleEndian::read_u64(&data[0x140..]),
+            sparse_info,
+            compression_info,
+        })
+    }
+
+    /// Serializes the fs header, the way `parse` reads it.
+    pub fn to_bytes(&self) -> [u8; 0x200] {
+        let mut data = [0; 0x200];
+        LittleEndian::write_u16(&mut data[0x0..], self.version);
+        data[0x2] = self.fs_type as u8;
+        data[0x3] = self.hash_type as u8;
+        data[0x4] = self.encryption_type as u8;
+
+        let hash_data = &mut data[0x8..0x100];
+        match &self.hash_info {
+            HashInfo::HierarchicalSha256 {
+                master_hash,
+                block_size,
+                hash_table,
+                pfs0,
+            } => {
+                hash_data[..0x20].copy_from_slice(master_hash);
+                LittleEndian::write_u32(&mut hash_data[0x20..], *block_size);
+                LittleEndian::write_u32(&mut hash_data[0x24..], 2);
+                hash_table.write(&mut hash_data[0x28..]);
+                pfs0.write(&mut hash_data[0x38..]);
+            }
+            HashInfo::HierarchicalIntegrity {
+                master_hash,
+                levels,
+            } => {
+                hash_data[..ivfc::IVFC_SUPERBLOCK_SIZE]
+                    .copy_from_slice(&ivfc::write_superblock(master_hash, levels));
+            }
+            HashInfo::None => (),
+        }
+
+        data[0x100..0x140].copy_from_slice(&self.patch_info);
+        LittleEndian::write_u64(&mut data[0x140..], self.ctr);
+        data[0x148..0x178].copy_from_slice(&self.sparse_info);
+        data[0x178..0x1A0].copy_from_slice(&self.compression_info);
+        data
+    }
+
+    /// Region of the sparse table in the section, if the data of the section
+    /// is partly left out of the NCA.
+    pub fn sparse_table(&self) -> Option<Region> {
+        Some(Region::read(&self.sparse_info)).filter(|table| table.size != 0)
+    }
+
+    /// Region of the compression table in the section, if it is stored
+    /// compressed.
+    pub fn compression_table(&self) -> Option<Region> {
+        Some(Region::read(&self.compression_info)).filter(|table| table.size != 0)
+    }
+
+    /// Region of the section holding the filesystem itself, the PFS0 or the
+    /// RomFS.
+    pub fn data_region(&self) -> Option<Region> {
+        match &self.hash_info {
+            HashInfo::HierarchicalSha256 { pfs0, .. } => Some(*pfs0),
+            HashInfo::HierarchicalIntegrity { levels, .. } => levels.last().map(|v| v.region),
+            HashInfo::None => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NcaSection {
+    pub index: usize,
+    /// Offset of the section in the NCA, in bytes.
+    pub offset: u64,
+    /// Size of the section, in bytes.
+    pub size: u64,
+    /// SHA-256 of the fs header, as stored in the NCA header.
+    #[serde(serialize_with = "serialize_hex_bytes")]
+    pub fs_header_hash: [u8; 0x20],
+    pub fs_header: FsHeader,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NcaHeader {
+    #[serde(serialize_with = "serialize_hex_bytes")]
+    pub fixed_key_signature: [u8; 0x100],
+    #[serde(serialize_with = "serialize_hex_bytes")]
+    pub npdm_signature: [u8; 0x100],
+    /// 2 for NCA2, 3 for NCA3.
+    pub version: u8,
+    pub distribution_type: DistributionType,
+    pub content_type: ContentType,
+    pub key_area_key_index: KeyAreaKeyIndex,
+    /// Key generation, as stored in the two key generation fields of the
+    /// header. Use `key_generation` to get the effective one.
+    pub key_generation_old: u8,
+    pub key_generation_new: u8,
+    pub signature_key_generation: u8,
+    pub content_size: u64,
+    #[serde(serialize_with = "serialize_title_id")]
+    pub program_id: u64,
+    pub content_index: u32,
+    pub sdk_addon_version: u32,
+    #[serde(serialize_with = "serialize_hex_bytes")]
+    pub rights_id: [u8; 0x10],
+    pub sections: Vec<NcaSection>,
+    #[serde(serialize_with = "serialize_key_area")]
+    pub encrypted_key_area: [[u8; 0x10]; 4],
+}
+
+/// Serializes the key area as the hexadecimal strings of its keys.
+fn serialize_key_area<S>(key_area: &[[u8; 0x10]; 4], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.collect_seq(key_area.iter().map(|key| {
+        key.iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>()
+    }))
+}
+
+impl NcaHeader {
+    /// Parses a decrypted NCA header.
+    pub fn parse(data: &[u8; NCA_HEADER_SIZE]) -> Result<NcaHeader, Error> {
+        let version = match &data[0x200..0x204] {
+            b"NCA3" => 3,
+            b"NCA2" => 2,
+            b"NCA0" => return Err(invalid_nca("NCA0 is not supported")),
+            _ => return Err(invalid_nca("magic is wrong")),
+        };
+
+        let mut fixed_key_signature = [0; 0x100];
+        fixed_key_signature.copy_from_slice(&data[0x0..0x100]);
+        let mut npdm_signature = [0; 0x100];
+        npdm_signature.copy_from_slice(&data[0x100..0x200]);
+        let mut rights_id = [0; 0x10];
+        rights_id.copy_from_slice(&data[0x230..0x240]);
+        let mut encrypted_key_area = [[0; 0x10]; 4];
+        for (i, key) in encrypted_key_area.iter_mut().enumerate() {
+            key.copy_from_slice(&data[0x300 + i * 0x10..0x310 + i * 0x10]);
+        }
+
+        let mut sections = Vec::new();
+        for index in 0..4 {
+            let entry = &data[0x240 + index * 0x10..];
+            let start = LittleEndian::read_u32(&entry[0x0..]) as u64 * MEDIA_SIZE;
+            let end = LittleEndian::read_u32(&entry[0x4..]) as u64 * MEDIA_SIZE;
+            if start == 0 && end == 0 {
+                continue;
+            }
+            if end < start {
+                return Err(invalid_nca("section ends before it starts"));
+            }
+            let mut fs_header_hash = [0; 0x20];
+            fs_header_hash.copy_from_slice(&data[0x280 + index * 0x20..0x2A0 + index * 0x20]);
+            let fs_header = &data[0x400 + index * 0x200..0x600 + index * 0x200];
+            sections.push(NcaSection {
+                index,
+                offset: start,
+                size: end - start,
+                fs_header_hash,
+                fs_header: FsHeader::parse(fs_header)?,
+            });
+        }
+
+        Ok(NcaHeader {
+            fixed_key_signature,
+            npdm_signature,
+            version,
+            distribution_type: DistributionType::from_u8(data[0x204])
+                .ok_or_else(|| invalid_nca("unknown distribution type"))?,
+            content_type: ContentType::from_u8(data[0x205])
+                .ok_or_else(|| invalid_nca("unknown content type"))?,
+            key_generation_old: data[0x206],
+            key_area_key_index: KeyAreaKeyIndex::from_u8(data[0x207])
+                .ok_or_else(|| invalid_nca("unknown key area encryption key index"))?,
+            content_size: LittleEndian::read_u64(&data[0x208..]),
+            program_id: LittleEndian::read_u64(&data[0x210..]),
+            content_index: LittleEndian::read_u32(&data[0x218..]),
+            sdk_addon_version: LittleEndian::read_u32(&data[0x21C..]),
+            key_generation_new: data[0x220],
+            signature_key_generation: data[0x221],
+            rights_id,
+            sections,
+            encrypted_key_area,
+        })
+    }
+
+    /// Serializes the header, the way `parse` reads it. The fs header hashes
+    /// are computed from the fs headers.
+    pub fn to_bytes(&self) -> [u8; NCA_HEADER_SIZE] {
+        let mut data = [0; NCA_HEADER_SIZE];
+        data[0x0..0x100].copy_from_slice(&self.fixed_key_signature);
+        data[0x100..0x200].copy_from_slice(&self.npdm_signature);
+        data[0x200..0x203].copy_from_slice(b"NCA");
+        data[0x203] = b'0' + self.version;
+        data[0x204] = self.distribution_type as u8;
+        data[0x205] = self.content_type as u8;
+        data[0x206] = self.key_generation_old;
+        data[0x207] = self.key_area_key_index as u8;
+        LittleEndian::write_u64(&mut data[0x208..], self.content_size);
+        LittleEndian::write_u64(&mut data[0x210..], self.program_id);
+        LittleEndian::write_u32(&mut data[0x218..], self.content_index);
+        LittleEndian::write_u32(&mut data[0x21C..], self.sdk_addon_version);
+        data[0x220] = self.key_generation_new;
+        data[0x221] = self.signature_key_generation;
+        data[0x230..0x240].copy_from_slice(&self.rights_id);
+
+        for section in &self.sections {
+            let index = section.index;
+            let entry = &mut data[0x240 + index * 0x10..0x250 + index * 0x10];
+            LittleEndian::write_u32(&mut entry[0x0..], (section.offset / MEDIA_SIZE) as u32);
+            LittleEndian::write_u32(
+                &mut entry[0x4..],
+                ((section.offset + section.size) / MEDIA_SIZE) as u32,
+            );
+            // Marks the section as used.
+            entry[0x8] = 1;
+
+            let fs_header = section.fs_header.to_bytes();
+            data[0x280 + index * 0x20..0x2A0 + index * 0x20]
+                .copy_from_slice(&Sha256::digest(&fs_header));
+            data[0x400 + index * 0x200..0x600 + index * 0x200].copy_from_slice(&fs_header);
+        }
+
+        for (i, key) in self.encrypted_key_area.iter().enumerate() {
+            data[0x300 + i * 0x10..0x310 + i * 0x10].copy_from_slice(key);
+        }
+        data
+    }
+
+    /// Sets both key generation fields from the effective key generation.
+    pub fn set_key_generation(&mut self, key_generation: u8) {
+        if key_generation > 2 {
+            self.key_generation_old = 2;
+            self.key_generation_new = key_generation;
+        } else {
+            self.key_generation_old = key_generation;
+            self.key_generation_new = 0;
+        }
+    }
+
+    /// Key generation of the NCA: 0 and 1 both mean the first one.
+    pub fn key_generation(&self) -> u8 {
+        std::cmp::max(self.key_generation_old, self.key_generation_new)
+    }
+
+    /// Index of the master key the key area is encrypted with.
+    pub fn master_key_revision(&self) -> usize {
+        self.key_generation().saturating_sub(1) as usize
+    }
+
+    /// Whether the sections are encrypted with a titlekey instead of the keys
+    /// of the key area.
+    pub fn has_rights_id(&self) -> bool {
+        self.rights_id.iter().any(|&v| v != 0)
+    }
+}
+
+/// Reads and decrypts the header of the NCA in `file`, which may also be a
+/// plaintext header.
+pub fn read_header<R: Read + Seek>(file: &mut R, keys: &Keys) -> Result<NcaHeader, Error> {
+    NcaHeader::parse(&decrypt_header(file, keys)?)
+}
+
+/// Reads and decrypts the raw header of the NCA in `file`, as covered by its
+/// signatures.
+pub fn decrypt_header<R: Read + Seek>(
+    file: &mut R,
+    keys: &Keys,
+) -> Result<[u8; NCA_HEADER_SIZE], Error> {
+    let mut data = [0; NCA_HEADER_SIZE];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut data)?;
+
+    if &data[0x200..0x203] != b"NCA" {
+        let header_key = keys.header_key()?;
+        let mut header = data;
+        header_key.decrypt(&mut header[..0x400], 0, MEDIA_SIZE as usize)?;
+        match &header[0x200..0x204] {
+            b"NCA3" => header_key.decrypt(&mut header[0x400..], 2, MEDIA_SIZE as usize)?,
+            // NCA2 fs headers are each encrypted as sector 0.
+            b"NCA2" => {
+                for fs_header in header[0x400..].chunks_mut(MEDIA_SIZE as usize) {
+                    header_key.decrypt(fs_header, 0, MEDIA_SIZE as usize)?;
+                }
+            }
+            _ => {
+                return Err(invalid_nca(
+                    "failed to decrypt the header, is the header key right?",
+                ))
+            }
+        }
+        data = header;
+    }
+    Ok(data)
+}
+
+/// Encrypts a serialized NCA3 header with the header key.
+pub fn encrypt_header(data: &mut [u8; NCA_HEADER_SIZE], keys: &Keys) -> Result<(), Error> {
+    keys.header_key()?.encrypt(data, 0, MEDIA_SIZE as usize)
+}
+
+/// Block size of the hash table of PFS0 sections.
+pub const PFS0_HASH_BLOCK_SIZE: u32 = 0x10000;
+
+struct SectionLayout {
+    image: Box<dyn ReadSeek>,
+    image_size: u64,
+    /// Everything coming before the image in the section: hash tables and
+    /// their padding.
+    hash_data: Vec<u8>,
+    size: u64,
+    fs_header: FsHeader,
+    /// Size of the patch data of BKTR sections, coming before the tables and
+    /// encrypted with the counter of its subsection.
+    patch_data_size: Option<u64>,
+}
+
+impl SectionLayout {
+    fn new(
+        fs_type: FsType,
+        mut image: Box<dyn ReadSeek>,
+        ivfc: &IvfcBuilder,
+    ) -> io::Result<SectionLayout> {
+        let image_size = image.seek(SeekFrom::End(0))?;
+        image.seek(SeekFrom::Start(0))?;
+
+        let (hash_type, hash_info, hash_data) = match fs_type {
+            FsType::PartitionFs => {
+                let hash_table = hash_blocks(&mut image, PFS0_HASH_BLOCK_SIZE as usize, false)?;
+                let pfs0_offset = align_up(hash_table.len() as u64, MEDIA_SIZE);
+                let hash_info = HashInfo::HierarchicalSha256 {
+                    master_hash: Sha256::digest(&hash_table).into(),
+                    block_size: PFS0_HASH_BLOCK_SIZE,
+                    hash_table: Region {
+                        offset: 0,
+                        size: hash_table.len() as u64,
+                    },
+                    pfs0: Region {
+                        offset: pfs0_offset,
+                        size: image_size,
+                    },
+                };
+                let mut hash_data = hash_table;
+                hash_data.resize(pfs0_offset as usize, 0);
+                (HashType::HierarchicalSha256, hash_info, hash_data)
+            }
+            FsType::RomFs => {
+                let tree = ivfc.build(&mut image, image_size)?;
+                let hash_info = HashInfo::HierarchicalIntegrity {
+                    master_hash: tree.master_hash,
+                    levels: tree.levels,
+                };
+                let hash_data = tree.hash_data;
+                (HashType::HierarchicalIntegrity, hash_info, hash_data)
+            }
+        };
+
+        Ok(SectionLayout {
+            size: align_up(hash_data.len() as u64 + image_size, MEDIA_SIZE),
+            image,
+            image_size,
+            hash_data,
+            fs_header: FsHeader {
+                version: 2,
+                fs_type,
+                hash_type,
+                encryption_type: EncryptionType::None,
+                hash_info,
+                patch_info: [0; 0x40],
+                ctr: 0,
+                sparse_info: [0; 0x30],
+                compression_info: [0; 0x28],
+            },
+            patch_data_size: None,
+        })
+    }
+
+    /// Turns the layout into the one of a compressed section. The section holds
+    /// each block of the original one compressed with LZ4, or as is when it
+    /// doesn't compress, blocks of zeros taking no space. The compression
+    /// table follows.
+    fn into_compressed(self) -> Result<SectionLayout, Error> {
+        let mut image = self.image;
+        image.seek(SeekFrom::Start(0))?;
+        let padding = self.size - self.hash_data.len() as u64 - self.image_size;
+        let mut section = io::Cursor::new(self.hash_data)
+            .chain(image.take(self.image_size))
+            .chain(io::repeat(0).take(padding));
+
+        let mut data = tempfile::tempfile()?;
+        let mut data_size = 0;
+        let mut entries: Vec<CompressionEntry> = Vec::new();
+        let mut block = vec![0; COMPRESSION_BLOCK_SIZE];
+        let mut offset = 0;
+        while offset < self.size {
+            let len = std::cmp::min(COMPRESSION_BLOCK_SIZE as u64, self.size - offset) as usize;
+            let block = &mut block[..len];
+            section.read_exact(block)?;
+
+            let compressed = lz4::block::compress(block, None, false)?;
+            let (compression_type, stored) = if block.iter().all(|&b| b == 0) {
+                (CompressionType::Zeros, &[][..])
+            } else if compressed.len() < len {
+                (CompressionType::Lz4, &compressed[..])
+            } else {
+                (CompressionType::None, &block[..])
+            };
+            // Uncompressed blocks following each other share an entry.
+            let extends_last = match entries.last() {
+                Some(last) => {
+                    compression_type != CompressionType::Lz4
+                        && last.compression_type == Some(compression_type)
+                }
+                None => false,
+            };
+            if !extends_last {
+                entries.push(CompressionEntry {
+                    virtual_offset: offset,
+                    physical_offset: data_size,
+                    compression_type: Some(compression_type),
+                    physical_size: 0,
+                });
+            }
+            if let Some(last) = entries.last_mut() {
+                last.physical_size += stored.len() as u32;
+            }
+            data.write_all(stored)?;
+            data_size += stored.len() as u64;
+
+            // Each entry starts on an AES block.
+            let aligned_size = align_up(data_size, 0x10);
+            data.write_all(&[0; 0x10][..(aligned_size - data_size) as usize])?;
+            data_size = aligned_size;
+            offset += len as u64;
+        }
+
+        let entries = entries
+            .iter()
+            .map(|entry| {
+                let mut data = vec![0; 0x18];
+                LittleEndian::write_u64(&mut data[0x0..], entry.virtual_offset);
+                LittleEndian::write_u64(&mut data[0x8..], entry.physical_offset);
+                data[0x10] = entry.compression_type.map_or(0, |v| v as u8);
+                LittleEndian::write_u32(&mut data[0x14..], entry.physical_size);
+                data
+            })
+            .collect::<Vec<_>>();
+        let table = build_bucket_tree(&entries, 0x18, self.size)?;
+        data.write_all(&table)?;
+
+        let mut fs_header = self.fs_header;
+        fs_header.compression_info[..0x20].copy_from_slice(&bktr_header(
+            data_size,
+            table.len() as u64,
+            entries.len(),
+        ));
+        let image_size = data_size + table.len() as u64;
+        Ok(SectionLayout {
+            image: Box::new(data),
+            image_size,
+            hash_data: Vec::new(),
+            size: align_up(image_size, MEDIA_SIZE),
+            fs_header,
+            patch_data_size: None,
+        })
+    }
+
+    /// Turns the layout into the one of a BKTR section patching `base`, the
+    /// whole RomFS section of the patched NCA. The section holds the parts of
+    /// the patched section that aren't found in `base`, followed by the
+    /// relocation and subsection tables.
+    fn into_patch(self, mut base: Box<dyn ReadSeek>) -> Result<SectionLayout, Error> {
+        let block_size = PATCH_BLOCK_SIZE as u64;
+        let mut block = vec![0; PATCH_BLOCK_SIZE];
+
+        // Index the blocks of the base by their checksum, to find them at any
+        // offset of the patched section, like rsync does.
+        let base_size = base.seek(SeekFrom::End(0))?;
+        base.seek(SeekFrom::Start(0))?;
+        let mut base_hashes = Vec::new();
+        let mut base_blocks: HashMap<u32, Vec<([u8; 0x20], u64)>> = HashMap::new();
+        for offset in (0..base_size / block_size).map(|idx| idx * block_size) {
+            base.read_exact(&mut block)?;
+            let hash: [u8; 0x20] = Sha256::digest(&block).into();
+            let candidates = base_blocks
+                .entry(RollingChecksum::new(&block).digest())
+                .or_default();
+            if !candidates.iter().any(|(other, _)| *other == hash) {
+                candidates.push((hash, offset));
+            }
+            base_hashes.push(hash);
+        }
+        let find_base_block = |checksum: RollingChecksum, window: &[u8], expected: u64| {
+            let candidates = base_blocks.get(&checksum.digest())?;
+            let hash: [u8; 0x20] = Sha256::digest(window).into();
+            if expected % block_size == 0
+                && base_hashes.get((expected / block_size) as usize) == Some(&hash)
+            {
+                return Some(expected);
+            }
+            candidates
+                .iter()
+                .find(|(other, _)| *other == hash)
+                .map(|(_, offset)| *offset)
+        };
+
+        let mut image = self.image;
+        image.seek(SeekFrom::Start(0))?;
+        let padding = self.size - self.hash_data.len() as u64 - self.image_size;
+        let mut patched = io::Cursor::new(self.hash_data)
+            .chain(image.take(self.image_size))
+            .chain(io::repeat(0).take(padding));
+
+        // Slide a window over the patched section, moving past the blocks
+        // found in the base, and one byte at a time otherwise. The bytes left
+        // behind are the patch data.
+        let mut patch = PatchData {
+            file: tempfile::tempfile()?,
+            size: 0,
+            relocations: Vec::new(),
+        };
+        let mut buf = Vec::new();
+        let mut buf_offset = 0;
+        let mut patch_start = 0;
+        let mut pos = 0;
+        let mut checksum = None;
+        while pos + block_size <= self.size {
+            // Keep the window and the byte following it in the buffer.
+            let buf_end = buf_offset + buf.len() as u64;
+            if buf_end < std::cmp::min(pos + block_size + 1, self.size) {
+                if (&mut patched).take(PATCH_READ_SIZE).read_to_end(&mut buf)? == 0 {
+                    return Err(invalid_nca("section image changed while writing it"));
+                }
+                continue;
+            }
+
+            let start = (pos - buf_offset) as usize;
+            let window = &buf[start..start + PATCH_BLOCK_SIZE];
+            let sum = checksum.unwrap_or_else(|| RollingChecksum::new(window));
+            let expected = patch.expected_base_offset(pos);
+            if let Some(physical_offset) = find_base_block(sum, window, expected) {
+                let patch_data = &buf[(patch_start - buf_offset) as usize..start];
+                patch.push_data(patch_start, patch_data)?;
+                patch.push(RelocationEntry {
+                    virtual_offset: pos,
+                    physical_offset,
+                    is_patch: false,
+                });
+                pos += block_size;
+                patch_start = pos;
+                checksum = None;
+            } else {
+                if pos + block_size < self.size {
+                    checksum = Some(sum.roll(buf[start], buf[start + PATCH_BLOCK_SIZE]));
+                }
+                pos += 1;
+                // Long runs of new data are written out as they come.
+                if pos - patch_start >= PATCH_READ_SIZE {
+                    let patch_data = &buf[(patch_start - buf_offset) as usize..start + 1];
+                    patch.push_data(patch_start, patch_data)?;
+                    patch_start = pos;
+                }
+            }
+            if patch_start - buf_offset >= PATCH_READ_SIZE {
+                buf.drain(..(patch_start - buf_offset) as usize);
+                buf_offset = patch_start;
+            }
+        }
+        patched.read_to_end(&mut buf)?;
+        if buf_offset + buf.len() as u64 != self.size {
+            return Err(invalid_nca("section image changed while writing it"));
+        }
+        patch.push_data(patch_start, &buf[(patch_start - buf_offset) as usize..])?;
+        let PatchData {
+            file: mut data,
+            size: data_size,
+            relocations,
+        } = patch;
+        // The tables start on an AES block, with the counter of the section.
+        let data_size = {
+            let aligned_size = align_up(data_size, 0x10);
+            data.write_all(&vec![0; (aligned_size - data_size) as usize])?;
+            aligned_size
+        };
+
+        let relocation_entries = relocations
+            .iter()
+            .map(|entry| {
+                let mut data = vec![0; 0x14];
+                LittleEndian::write_u64(&mut data[0x0..], entry.virtual_offset);
+                LittleEndian::write_u64(&mut data[0x8..], entry.physical_offset);
+                LittleEndian::write_u32(&mut data[0x10..], entry.is_patch as u32);
+                data
+            })
+            .collect::<Vec<_>>();
+        let relocation_table = build_bucket_tree(&relocation_entries, 0x14, self.size)?;
+        // All the patch data is in a single subsection.
+        let mut subsection_entry = vec![0; 0x10];
+        LittleEndian::write_u32(&mut subsection_entry[0xC..], PATCH_CTR_VAL);
+        let subsection_table = build_bucket_tree(&[subsection_entry], 0x10, data_size)?;
+
+        let mut fs_header = self.fs_header;
+        let relocation_offset = data_size;
+        let subsection_offset = relocation_offset + relocation_table.len() as u64;
+        fs_header.patch_info[..0x20].copy_from_slice(&bktr_header(
+            relocation_offset,
+            relocation_table.len() as u64,
+            relocation_entries.len(),
+        ));
+        fs_header.patch_info[0x20..].copy_from_slice(&bktr_header(
+            subsection_offset,
+            subsection_table.len() as u64,
+            1,
+        ));
+        data.write_all(&relocation_table)?;
+        data.write_all(&subsection_table)?;
+
+        let image_size = subsection_offset + subsection_table.len() as u64;
+        Ok(SectionLayout {
+            image: Box::new(data),
+            image_size,
+            hash_data: Vec::new(),
+            size: align_up(image_size, MEDIA_SIZE),
+            fs_header,
+            patch_data_size: Some(data_size),
+        })
+    }
+}
+
+/// Size of the blocks compressed sections are compressed in.
+const COMPRESSION_BLOCK_SIZE: usize = 0x1_0000;
+
+/// Size of the reads of the patched section while building a BKTR section,
+/// and of the runs of patch data written at once.
+const PATCH_READ_SIZE: u64 = 0x10_0000;
+
+/// Weak checksum of the blocks of the base of a BKTR section, the one of
+/// rsync, which can be rolled one byte forward.
+#[derive(Clone, Copy)]
+struct RollingChecksum {
+    a: u32,
+    b: u32,
+}
+
+impl RollingChecksum {
+    fn new(block: &[u8]) -> RollingChecksum {
+        let mut checksum = RollingChecksum { a: 0, b: 0 };
+        for (idx, &byte) in block.iter().enumerate() {
+            checksum.a = checksum.a.wrapping_add(byte as u32);
+            checksum.b = checksum
+                .b
+                .wrapping_add((block.len() - idx) as u32 * byte as u32);
+        }
+        checksum
+    }
+
+    /// Checksum of the block one byte further, without `old`, its first
+    /// byte, and with `new`, the byte following it.
+    fn roll(self, old: u8, new: u8) -> RollingChecksum {
+        let a = self.a.wrapping_sub(old as u32).wrapping_add(new as u32);
+        let b = self
+            .b
+            .wrapping_sub((PATCH_BLOCK_SIZE as u32).wrapping_mul(old as u32))
+            .wrapping_add(a);
+        RollingChecksum { a, b }
+    }
+
+    fn digest(self) -> u32 {
+        (self.a & 0xFFFF) | (self.b << 16)
+    }
+}
+
+/// Patch data of a BKTR section being built, and the relocation entries
+/// mapping the patched section to it and to the base.
+struct PatchData {
+    file: std::fs::File,
+    size: u64,
+    relocations: Vec<RelocationEntry>,
+}
+
+impl PatchData {
+    /// Offset of the base the patched section at `virtual_offset` most likely
+    /// comes from: following the last part found in the base, or at the same
+    /// offset.
+    fn expected_base_offset(&self, virtual_offset: u64) -> u64 {
+        match self.relocations.last() {
+            Some(last) if !last.is_patch => {
+                last.physical_offset + (virtual_offset - last.virtual_offset)
+            }
+            _ => virtual_offset,
+        }
+    }
+
+    /// Adds `entry`, merging it with the last one when they follow each other
+    /// on both sides.
+    fn push(&mut self, entry: RelocationEntry) {
+        if let Some(last) = self.relocations.last() {
+            if last.is_patch == entry.is_patch
+                && last.physical_offset + (entry.virtual_offset - last.virtual_offset)
+                    == entry.physical_offset
+            {
+                return;
+            }
+        }
+        self.relocations.push(entry);
+    }
+
+    /// Adds `data`, found at `virtual_offset` in the patched section, to the
+    /// patch data.
+    fn push_data(&mut self, virtual_offset: u64, data: &[u8]) -> io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.file.write_all(data)?;
+        self.push(RelocationEntry {
+            virtual_offset,
+            physical_offset: self.size,
+            is_patch: true,
+        });
+        self.size += data.len() as u64;
+        Ok(())
+    }
+}
+
+/// Builds a BKTR table out of its entries, each starting with the offset it
+/// applies from. The first bucket lists the offsets of the next ones, each
+/// holding a part of the entries.
+fn build_bucket_tree(
+    entries: &[Vec<u8>],
+    entry_size: usize,
+    total_size: u64,
+) -> Result<Vec<u8>, Error> {
+    let buckets = entries
+        .chunks((BKTR_BUCKET_SIZE - 0x10) / entry_size)
+        .collect::<Vec<_>>();
+    if 0x10 + buckets.len() * 8 > BKTR_BUCKET_SIZE {
+        return Err(invalid_nca("too many entries for a BKTR table"));
+    }
+    let mut data = vec![0; (buckets.len() + 1) * BKTR_BUCKET_SIZE];
+    LittleEndian::write_u32(&mut data[0x4..], buckets.len() as u32);
+    LittleEndian::write_u64(&mut data[0x8..], total_size);
+    for (idx, entries) in buckets.iter().enumerate() {
+        let start = LittleEndian::read_u64(&entries[0]);
+        let end = match buckets.get(idx + 1) {
+            Some(next) => LittleEndian::read_u64(&next[0]),
+            None => total_size,
+        };
+        LittleEndian::write_u64(&mut data[0x10 + idx * 8..], start);
+        let bucket = &mut data[(idx + 1) * BKTR_BUCKET_SIZE..(idx + 2) * BKTR_BUCKET_SIZE];
+        LittleEndian::write_u32(&mut bucket[0x0..], idx as u32);
+        LittleEndian::write_u32(&mut bucket[0x4..], entries.len() as u32);
+        LittleEndian::write_u64(&mut bucket[0x8..], end);
+        for (entry, dest) in entries
+            .iter()
+            .zip(bucket[0x10..].chunks_exact_mut(entry_size))
+        {
+            dest.copy_from_slice(entry);
+        }
+    }
+    Ok(data)
+}
+
+/// Header of a BKTR table, in the patch info of the fs header.
+fn bktr_header(offset: u64, size: u64, entry_count: usize) -> [u8; 0x20] {
+    let mut header = [0; 0x20];
+    LittleEndian::write_u64(&mut header[0x0..], offset);
+    LittleEndian::write_u64(&mut header[0x8..], size);
+    header[0x10..0x14].copy_from_slice(b"BKTR");
+    LittleEndian::write_u32(&mut header[0x14..], 1);
+    LittleEndian::write_u32(&mut header[0x18..], entry_count as u32);
+    header
+}
+
+/// Writes everything to `inner`, keeping track of its SHA-256.
+struct HashingWriter<'a, W> {
+    inner: &'a mut W,
+    hasher: Sha256,
+}
+
+impl<'a, W: Write> Write for HashingWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Encrypts everything written through it with AES-CTR before passing it to
+/// `inner`.
+struct CtrWriter<'a, W> {
+    inner: &'a mut W,
+    crypter: AesCtr,
+    /// Offset of the next write in the NCA.
+    offset: u64,
+    buf: Vec<u8>,
+}
+
+impl<'a, W: Write> Write for CtrWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.clear();
+        self.buf.extend_from_slice(buf);
+        self.crypter.apply_keystream(&mut self.buf, self.offset);
+        self.inner.write_all(&self.buf)?;
+        self.offset += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Files of the logo section of program NCAs, shown while the program starts,
+/// along with the magic of their format.
+pub const LOGO_FILES: [(&str, &[u8]); 2] = [
+    ("NintendoLogo.png", b"\x89PNG\r\n\x1a\n"),
+    ("StartupMovie.gif", b"GIF8"),
+];
+
+/// Index of the logo section in program NCAs, after the ExeFS and the RomFS.
+const LOGO_SECTION_INDEX: usize = 2;
+
+/// A section of an NCA being built.
+struct BuilderSection {
+    index: usize,
+    fs_type: FsType,
+    /// Whether the section is left in plaintext even when `section_key` is
+    /// set, like the logo section.
+    plaintext: bool,
+    image: Box<dyn ReadSeek>,
+    /// Whole RomFS section of the NCA patched by this one, making this a
+    /// BKTR section storing the differences with it.
+    base: Option<Box<dyn ReadSeek>>,
+}
+
+/// Size of the blocks of the base of patch NCAs looked for in the patched
+/// section. Changes smaller than it are stored in the patch as whole blocks.
+const PATCH_BLOCK_SIZE: usize = 0x4000;
+
+/// Low bits of the counter of the patch data of built BKTR sections. The
+/// tables use the counter of the section, whose low bits are 0.
+const PATCH_CTR_VAL: u32 = 1;
+
+/// Builds an NCA from the images of its sections.
+pub struct NcaBuilder {
+    pub distribution_type: DistributionType,
+    pub content_type: ContentType,
+    pub program_id: u64,
+    pub content_index: u32,
+    pub sdk_addon_version: u32,
+    pub key_generation: u8,
+    /// Key the sections are encrypted with, using AES-CTR. It is stored in the
+    /// key area, encrypted with the application key area key of
+    /// `key_generation`. Sections are left in plaintext when `None`.
+    pub section_key: Option<[u8; 0x10]>,
+    /// Rights ID of the NCA. When set, `section_key` is the titlekey given
+    /// by the ticket of this rights ID and the key area is left empty.
+    pub rights_id: Option<[u8; 0x10]>,
+    /// Key signing the header with its second signature, which consoles
+    /// check against the ACID public key of the NPDM of program NCAs.
+    pub signing_key: Option<RsaPrivateKey>,
+    /// Layout of the IVFC trees of RomFS sections.
+    pub ivfc: IvfcBuilder,
+    /// Compresses the sections with LZ4, as newer titles do, which older
+    /// firmwares can't read. BKTR sections are left uncompressed.
+    pub compress: bool,
+    sections: Vec<BuilderSection>,
+}
+
+impl NcaBuilder {
+    pub fn new(content_type: ContentType, program_id: u64) -> NcaBuilder {
+        NcaBuilder {
+            distribution_type: DistributionType::Download,
+            content_type,
+            program_id,
+            content_index: 0,
+            sdk_addon_version: 0x000C_1100,
+            key_generation: 0,
+            section_key: None,
+            rights_id: None,
+            signing_key: None,
+            ivfc: IvfcBuilder::new(),
+            compress: false,
+            sections: Vec::new(),
+        }
+    }
+
+    /// Prepares a program NCA, holding the ExeFS built from `exefs_dir` and,
+    /// if given, the RomFS built from `romfs_dir`. The program ID is taken
+    /// from the `main.npdm` of the ExeFS. Sections are encrypted with a random
+    /// key.
+    pub fn program(exefs_dir: &Path, romfs_dir: Option<&Path>) -> Result<NcaBuilder, Error> {
+        let npdm_path = exefs_dir.join("main.npdm");
+        let npdm = std::fs::read(&npdm_path).map_err(|err| (err, &npdm_path))?;
+        let npdm = NpdmInfo::parse(&npdm)?;
+
+        let mut nca = NcaBuilder::new(ContentType::Program, npdm.program_id);
+        nca.section_key = Some(rand::random());
+
+        let mut exefs = tempfile::tempfile()?;
+        Pfs0::from_directory(&exefs_dir.to_string_lossy())
+            .and_then(|mut pfs0| pfs0.write_pfs0(&mut exefs))
+            .map_err(|err| (err, exefs_dir))?;
+        nca.add_section(FsType::PartitionFs, exefs)?;
+
+        if let Some(romfs_dir) = romfs_dir {
+            let mut romfs = tempfile::tempfile()?;
+            RomFs::from_directory(romfs_dir)?
+                .write(&mut romfs)
+                .with_path(romfs_dir)?;
+            nca.add_section(FsType::RomFs, romfs)?;
+        }
+        Ok(nca)
+    }
+
+    /// Prepares the program NCA of an update of the title of `base`, its
+    /// program NCA. It holds the ExeFS built from `exefs_dir`, whose program ID
+    /// must be the one of `base`, and a BKTR section patching the RomFS of
+    /// `base` into the one built from `romfs_dir`. Sections are encrypted with
+    /// a random key.
+    pub fn patch_program<B: Read + Seek + TryClone + 'static>(
+        exefs_dir: &Path,
+        romfs_dir: &Path,
+        base: &Nca<B>,
+    ) -> Result<NcaBuilder, Error> {
+        let mut nca = NcaBuilder::program(exefs_dir, None)?;
+        if nca.program_id != base.header.program_id {
+            return Err(invalid_nca(
+                "the program ID of the ExeFS isn't the one of the base NCA",
+            ));
+        }
+
+        let mut romfs = tempfile::tempfile()?;
+        RomFs::from_directory(romfs_dir)?
+            .write(&mut romfs)
+            .with_path(romfs_dir)?;
+        nca.add_patch_section(romfs, base)?;
+        Ok(nca)
+    }
+
+    /// Prepares a control NCA, holding the RomFS built from `control_dir`, a
+    /// directory with a `control.nacp` and the icons, as written by the
+    /// `control` command. The program ID is taken from the NACP. Sections are
+    /// encrypted with a random key.
+    pub fn control(control_dir: &Path) -> Result<NcaBuilder, Error> {
+        let nacp_path = control_dir.join("control.nacp");
+        let nacp = std::fs::read(&nacp_path).map_err(|err| (err, &nacp_path))?;
+        if nacp.len() != 0x4000 {
+            return Err(invalid_nca("control.nacp is not a NACP"));
+        }
+        // The save data owner ID is the ID of the application.
+        let program_id = LittleEndian::read_u64(&nacp[0x3078..]);
+
+        let mut nca = NcaBuilder::new(ContentType::Control, program_id);
+        nca.section_key = Some(rand::random());
+
+        let mut romfs = tempfile::tempfile()?;
+        RomFs::from_directory(control_dir)?
+            .write(&mut romfs)
+            .with_path(control_dir)?;
+        nca.add_section(FsType::RomFs, romfs)?;
+        Ok(nca)
+    }
+
+    /// Prepares a manual NCA for `program_id`, holding the RomFS built from
+    /// `documents_dir`, a directory of HTML documents. It is listed in the
+    /// CNMT as the HtmlDocument or the LegalInformation of the title. Sections
+    /// are encrypted with a random key.
+    pub fn manual(documents_dir: &Path, program_id: u64) -> Result<NcaBuilder, Error> {
+        fn has_html(dir: &Path) -> io::Result<bool> {
+            for entry in std::fs::read_dir(dir)? {
+                let path = entry?.path();
+                let is_html = match path.extension() {
+                    Some(ext) => {
+                        ext.eq_ignore_ascii_case("html") || ext.eq_ignore_ascii_case("htm")
+                    }
+                    None => false,
+                };
+                if is_html || (path.is_dir() && has_html(&path)?) {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        }
+        if !has_html(documents_dir).map_err(|err| (err, documents_dir))? {
+            return Err(invalid_nca("the manual directory holds no HTML document"));
+        }
+
+        let mut nca = NcaBuilder::new(ContentType::Manual, program_id);
+        nca.section_key = Some(rand::random());
+
+        let mut romfs = tempfile::tempfile()?;
+        RomFs::from_directory(documents_dir)?
+            .write(&mut romfs)
+            .with_path(documents_dir)?;
+        nca.add_section(FsType::RomFs, romfs)?;
+        Ok(nca)
+    }
+
+    /// Prepares the data NCA of add-on content `add_on_id`, holding the RomFS
+    /// built from `romfs_dir`. Sections are encrypted with a random key.
+    pub fn add_on_content(romfs_dir: &Path, add_on_id: u64) -> Result<NcaBuilder, Error> {
+        let mut nca = NcaBuilder::new(ContentType::PublicData, add_on_id);
+        nca.section_key = Some(rand::random());
+
+        let mut romfs = tempfile::tempfile()?;
+        RomFs::from_directory(romfs_dir)?
+            .write(&mut romfs)
+            .with_path(romfs_dir)?;
+        nca.add_section(FsType::RomFs, romfs)?;
+        Ok(nca)
+    }
+
+    /// Prepares the meta NCA of a title, holding `cnmt` in its PFS0 section.
+    pub fn meta(cnmt: &Cnmt) -> Result<NcaBuilder, Error> {
+        let mut nca = NcaBuilder::new(ContentType::Meta, cnmt.title_id);
+        nca.section_key = Some(rand::random());
+
+        let mut pfs0 = Pfs0::new();
+        pfs0.push_data(cnmt.file_name(), cnmt.to_bytes())?;
+        let mut image = io::Cursor::new(Vec::new());
+        pfs0.write_pfs0(&mut image)?;
+        nca.add_section(FsType::PartitionFs, image)?;
+        Ok(nca)
+    }
+
+    /// Adds a section holding `image`, a PFS0 or a RomFS according to
+    /// `fs_type`. Sections are numbered in the order they are added.
+    pub fn add_section<R: Read + Seek + 'static>(
+        &mut self,
+        fs_type: FsType,
+        image: R,
+    ) -> Result<&mut NcaBuilder, Error> {
+        let index = self.sections.last().map_or(0, |section| section.index + 1);
+        if index == 4 {
+            return Err(invalid_nca("an NCA can't have more than 4 sections"));
+        }
+        self.sections.push(BuilderSection {
+            index,
+            fs_type,
+            plaintext: false,
+            image: Box::new(image),
+            base: None,
+        });
+        Ok(self)
+    }
+
+    /// Adds a BKTR section patching the RomFS section of `base` into the RomFS
+    /// `image`. Only the parts of the section that aren't found in `base` are
+    /// stored in the NCA, which needs `section_key` to be set.
+    pub fn add_patch_section<R: Read + Seek + 'static, B: Read + Seek + TryClone + 'static>(
+        &mut self,
+        image: R,
+        base: &Nca<B>,
+    ) -> Result<&mut NcaBuilder, Error> {
+        let base_section = base.section(base.romfs_section_index()?)?;
+        self.add_section(FsType::RomFs, image)?;
+        if let Some(section) = self.sections.last_mut() {
+            section.base = Some(Box::new(base_section));
+        }
+        Ok(self)
+    }
+
+    /// Adds the logo section of a program NCA, the PFS0 built from `logo_dir`,
+    /// which must hold `NintendoLogo.png` and `StartupMovie.gif` and nothing
+    /// else. It comes third, after the ExeFS and the RomFS, even without a
+    /// RomFS, and is left in plaintext like in retail NCAs.
+    pub fn add_logo_section(&mut self, logo_dir: &Path) -> Result<&mut NcaBuilder, Error> {
+        if self.content_type != ContentType::Program {
+            return Err(invalid_nca("only program NCAs have a logo section"));
+        }
+        if self.sections.is_empty() || self.sections.len() > LOGO_SECTION_INDEX {
+            return Err(invalid_nca(
+                "the logo section must come after the ExeFS and the RomFS",
+            ));
+        }
+
+        for entry in std::fs::read_dir(logo_dir).map_err(|err| (err, logo_dir))? {
+            let name = entry.map_err(|err| (err, logo_dir))?.file_name();
+            if !LOGO_FILES.iter().any(|(logo_name, _)| name == *logo_name) {
+                return Err(invalid_nca(
+                    "the logo section must only hold NintendoLogo.png and StartupMovie.gif",
+                ));
+            }
+        }
+        let mut pfs0 = Pfs0::new();
+        for (name, magic) in &LOGO_FILES {
+            let path = logo_dir.join(name);
+            let data = std::fs::read(&path).map_err(|err| (err, &path))?;
+            if !data.starts_with(magic) {
+                return Err(invalid_nca(if name.ends_with(".png") {
+                    "NintendoLogo.png is not a PNG"
+                } else {
+                    "StartupMovie.gif is not a GIF"
+                }));
+            }
+            pfs0.push_data(name.to_string(), data)?;
+        }
+        let mut image = io::Cursor::new(Vec::new());
+        pfs0.write_pfs0(&mut image)?;
+
+        self.sections.push(BuilderSection {
+            index: LOGO_SECTION_INDEX,
+            fs_type: FsType::PartitionFs,
+            plaintext: true,
+            image: Box::new(image),
+            base: None,
+        });
+        Ok(self)
+    }
+
+    /// Writes the NCA to `output`, encrypting its header with the header key
+    /// of `keys`. Returns the SHA-256 of the NCA.
+    pub fn write<W: Write>(self, output: &mut W, keys: &Keys) -> Result<[u8; 0x20], Error> {
+        let mut layouts = Vec::with_capacity(self.sections.len());
+        for section in self.sections {
+            let mut layout = SectionLayout::new(section.fs_type, section.image, &self.ivfc)?;
+            if let Some(base) = section.base {
+                if self.section_key.is_none() {
+                    return Err(invalid_nca("BKTR sections can't be left in plaintext"));
+                }
+                layout = layout.into_patch(base)?;
+            } else if self.compress {
+                layout = layout.into_compressed()?;
+            }
+            layouts.push((section.index, section.plaintext, layout));
+        }
+
+        let mut encrypted_key_area = [[0; 0x10]; 4];
+        if let (Some(section_key), None) = (&self.section_key, &self.rights_id) {
+            let key_area_key = keys.key_area_key(
+                KeyAreaKeyIndex::Application,
+                self.key_generation.saturating_sub(1) as usize,
+            )?;
+            encrypted_key_area[2] = *key_area_key.generate_kek(section_key)?.as_bytes();
+        }
+
+        let mut sections = Vec::with_capacity(layouts.len());
+        let mut offset = NCA_HEADER_SIZE as u64;
+        for (index, plaintext, layout) in layouts.iter_mut() {
+            let index = *index;
+            if self.section_key.is_some() && !*plaintext {
+                layout.fs_header.encryption_type = match layout.patch_data_size {
+                    Some(_) => EncryptionType::AesCtrEx,
+                    None => EncryptionType::AesCtr,
+                };
+                layout.fs_header.ctr = (index as u64 + 1) << 32;
+            }
+            sections.push(NcaSection {
+                index,
+                offset,
+                size: layout.size,
+                fs_header_hash: Sha256::digest(&layout.fs_header.to_bytes()).into(),
+                fs_header: layout.fs_header.clone(),
+            });
+            offset += layout.size;
+        }
+
+        let mut header = NcaHeader {
+            fixed_key_signature: [0; 0x100],
+            npdm_signature: [0; 0x100],
+            version: 3,
+            distribution_type: self.distribution_type,
+            content_type: self.content_type,
+            key_area_key_index: KeyAreaKeyIndex::Application,
+            key_generation_old: 0,
+            key_generation_new: 0,
+            signature_key_generation: 0,
+            content_size: offset,
+            program_id: self.program_id,
+            content_index: self.content_index,
+            sdk_addon_version: self.sdk_addon_version,
+            rights_id: self.rights_id.unwrap_or([0; 0x10]),
+            sections,
+            encrypted_key_area,
+        };
+        header.set_key_generation(self.key_generation);
+        let mut header_data = header.to_bytes();
+        if let Some(signing_key) = &self.signing_key {
+            let signature = signing_key.sign_pss(&header_data[0x200..0x400]);
+            header_data[0x100..0x200].copy_from_slice(&signature);
+        }
+        encrypt_header(&mut header_data, keys)?;
+
+        let mut output = HashingWriter {
+            inner: output,
+            hasher: Sha256::default(),
+        };
+        output.write_all(&header_data)?;
+        for ((_, plaintext, layout), section) in layouts.into_iter().zip(&header.sections) {
+            if let (Some(key), Some(_)) = (&self.section_key, layout.patch_data_size) {
+                write_patch_section(layout, (*key).into(), section, &mut output)?;
+                continue;
+            }
+            let mut section_output: Box<dyn Write> = match &self.section_key {
+                Some(key) if !plaintext => Box::new(CtrWriter {
+                    inner: &mut output,
+                    crypter: AesCtr::new((*key).into(), section.fs_header.ctr),
+                    offset: section.offset,
+                    buf: Vec::new(),
+                }),
+                _ => Box::new(&mut output),
+            };
+            write_section(layout, &mut section_output)?;
+        }
+        Ok(output.hasher.finalize().into())
+    }
+}
+
+fn write_section<W: Write>(mut layout: SectionLayout, output: &mut W) -> Result<(), Error> {
+    output.write_all(&layout.hash_data)?;
+    layout.image.seek(SeekFrom::Start(0))?;
+    let copied = io::copy(&mut layout.image, output)?;
+    if copied != layout.image_size {
+        return Err(invalid_nca("section image changed while writing it"));
+    }
+    let padding = layout.size - layout.hash_data.len() as u64 - layout.image_size;
+    io::copy(&mut io::repeat(0).take(padding), output)?;
+    Ok(())
+}
+
+/// Writes a BKTR section, its patch data being encrypted with the counter of
+/// its subsection and its tables with the one of the section.
+fn write_patch_section<W: Write>(
+    mut layout: SectionLayout,
+    key: Aes128Key,
+    section: &NcaSection,
+    output: &mut W,
+) -> Result<(), Error> {
+    let data_size = layout.patch_data_size.unwrap_or(0);
+    let data_ctr = (section.fs_header.ctr & !0xFFFF_FFFF) | PATCH_CTR_VAL as u64;
+    layout.image.seek(SeekFrom::Start(0))?;
+    let mut copied = io::copy(
+        &mut (&mut layout.image).take(data_size),
+        &mut CtrWriter {
+            inner: output,
+            crypter: AesCtr::new(key.clone(), data_ctr),
+            offset: section.offset,
+            buf: Vec::new(),
+        },
+    )?;
+    let mut table_output = CtrWriter {
+        inner: output,
+        crypter: AesCtr::new(key, section.fs_header.ctr),
+        offset: section.offset + data_size,
+        buf: Vec::new(),
+    };
+    copied += io::copy(&mut layout.image, &mut table_output)?;
+    if copied != layout.image_size {
+        return Err(invalid_nca("section image changed while writing it"));
+    }
+    io::copy(
+        &mut io::repeat(0).take(layout.size - layout.image_size),
+        &mut table_output,
+    )?;
+    Ok(())
+}
+
+/// Copies the NCA in `input` to `output`, re-encrypting it for
+/// `key_generation`: the key area is decrypted with the key area key of the
+/// current generation and encrypted again with the one of the new generation.
+/// NCAs using titlekey crypto are converted to standard crypto, storing the
+/// titlekey from `keys` in the key area. Only the header changes, the sections
+/// are copied as is.
+///
+/// This invalidates the first signature of the header, and the second one
+/// unless `signing_key` is given to sign the header again. Returns the SHA-256
+/// of the new NCA.
+pub fn reencrypt<R: Read + Seek, W: Write>(
+    input: &mut R,
+    output: &mut W,
+    keys: &Keys,
+    key_generation: u8,
+    signing_key: Option<&RsaPrivateKey>,
+) -> Result<[u8; 0x20], Error> {
+    let mut data = decrypt_header(input, keys)?;
+    let mut header = NcaHeader::parse(&data)?;
+    if header.version != 3 {
+        return Err(invalid_nca("only NCA3 headers can be re-encrypted"));
+    }
+
+    let new_key_area_key = || {
+        keys.key_area_key(
+            header.key_area_key_index,
+            key_generation.saturating_sub(1) as usize,
+        )
+    };
+    if header.has_rights_id() {
+        let titlekey = keys.titlekey(&header.rights_id, header.master_key_revision())?;
+        // The titlekey is the AES-CTR key, the third one of the key area.
+        let mut key_area = [[0; 0x10]; 4];
+        key_area[2] = *new_key_area_key()?
+            .generate_kek(titlekey.as_bytes())?
+            .as_bytes();
+        for (i, key) in key_area.iter().enumerate() {
+            data[0x300 + i * 0x10..0x310 + i * 0x10].copy_from_slice(key);
+        }
+        data[0x230..0x240].copy_from_slice(&[0; 0x10]);
+    } else {
+        for (i, key) in header.encrypted_key_area.iter().enumerate() {
+            // Unused keys, like the whole key area of plaintext NCAs, are left
+            // empty.
+            if key.iter().all(|&b| b == 0) {
+                continue;
+            }
+            let key = keys
+                .key_area_key(header.key_area_key_index, header.master_key_revision())?
+                .derive_key(key)?;
+            data[0x300 + i * 0x10..0x310 + i * 0x10]
+                .copy_from_slice(new_key_area_key()?.generate_kek(key.as_bytes())?.as_bytes());
+        }
+    }
+
+    header.set_key_generation(key_generation);
+    data[0x206] = header.key_generation_old;
+    data[0x220] = header.key_generation_new;
+    if let Some(signing_key) = signing_key {
+        let signature = signing_key.sign_pss(&data[0x200..0x400]);
+        data[0x100..0x200].copy_from_slice(&signature);
+    }
+    encrypt_header(&mut data, keys)?;
+
+    let mut output = HashingWriter {
+        inner: output,
+        hasher: Sha256::default(),
+    };
+    output.write_all(&data)?;
+    input.seek(SeekFrom::Start(NCA_HEADER_SIZE as u64))?;
+    io::copy(input, &mut output)?;
+    Ok(output.hasher.finalize().into())
+}
+
+/// Copies the NSP in `input` to `output`, converting its NCAs using titlekey
+/// crypto to standard crypto with `reencrypt`, at the same key generation,
+/// and dropping its tickets and certificates. The titlekeys come from `keys`,
+/// or from the tickets of the NSP, which are read into it. The other entries
+/// are copied as is. Returns the names of the converted NCAs.
+///
+/// As with the other tools doing this conversion, the NCAs keep their names
+/// and the CNMT isn't updated, so the hashes it records no longer match the
+/// converted NCAs.
+pub fn convert_nsp_to_standard_crypto<R, W>(
+    input: R,
+    output: &mut W,
+    keys: &mut Keys,
+) -> Result<Vec<String>, Error>
+where
+    R: Read + Seek + TryClone + 'static,
+    W: Write + Seek,
+{
+    // Tickets may come after the NCAs they decrypt, so they are all read
+    // before converting anything.
+    let mut entries = Vec::new();
+    for file in Pfs0::from_reader(input)?.files() {
+        let file = file?;
+        let name = file.file_name().to_string();
+        if name.ends_with(".tik") {
+            // The titlekey may be in `keys` already, e.g. for personalized
+            // tickets of another console.
+            if let Err(err) = keys.read_ticket_from(file) {
+                log::warn!("Failed to read {}: {}", name, err);
+            }
+        } else if !name.ends_with(".cert") {
+            entries.push((name, file));
+        }
+    }
+
+    let mut nsp = Pfs0::new();
+    let mut converted = Vec::new();
+    for (name, mut file) in entries {
+        if name.ends_with(".nca") {
+            let header = read_header(&mut file, keys).with_field(name.as_str())?;
+            if header.has_rights_id() {
+                log::info!("Converting {} to standard crypto", name);
+                let mut nca = tempfile::tempfile()?;
+                reencrypt(&mut file, &mut nca, keys, header.key_generation(), None)
+                    .with_field(name.as_str())?;
+                nsp.push_reader(name.clone(), nca)?;
+                converted.push(name);
+                continue;
+            }
+        }
+        nsp.push_reader(name, file)?;
+    }
+    nsp.write_pfs0(output)?;
+    Ok(converted)
+}
+
+/// Opens the program NCA of the NSP in `input`, the one holding its ExeFS
+/// and RomFS, returning its name along with it. The tickets of the NSP are
+/// read into `keys` first, so that NCAs using titlekey crypto can be opened.
+/// Applications with several programs have one NCA for each: the first one,
+/// of content index 0, is opened.
+pub fn open_nsp_program<R: Read + Seek + TryClone>(
+    input: R,
+    keys: &mut Keys,
+) -> Result<(String, Nca<ReadRange<R>>), Error> {
+    let entries = read_entries(
+        &mut io::BufReader::new(input.try_clone()?),
+        ParseMode::Lenient,
+    )?;
+    let open_entry = |entry: &Pfs0Entry| -> Result<ReadRange<R>, Error> {
+        let mut file = ReadRange::new(input.try_clone()?, entry.offset, entry.size);
+        file.seek(SeekFrom::Start(0))?;
+        Ok(file)
+    };
+    for entry in entries.iter().filter(|v| v.name.ends_with(".tik")) {
+        let ticket = open_entry(entry)?;
+        // The titlekey may be in `keys` already, e.g. for personalized
+        // tickets of another console.
+        if let Err(err) = keys.read_ticket_from(ticket) {
+            log::warn!("Failed to read {}: {}", entry.name, err);
+        }
+    }
+
+    let mut program = None;
+    for entry in entries.iter().filter(|v| v.name.ends_with(".nca")) {
+        let mut file = open_entry(entry)?;
+        let header = read_header(&mut file, keys).with_field(entry.name.as_str())?;
+        if header.content_type != ContentType::Program {
+            continue;
+        }
+        match program {
+            Some((content_index, _)) if content_index <= header.content_index => (),
+            _ => program = Some((header.content_index, entry)),
+        }
+    }
+    let entry = match program {
+        Some((_, entry)) => entry,
+        None => return Err(invalid_nca("the NSP has no program NCA")),
+    };
+    let nca = Nca::from_reader(open_entry(entry)?, keys).with_field(entry.name.as_str())?;
+    Ok((entry.name.clone(), nca))
+}
+
+/// Copies the NCA in `input` to `output`, replacing the second signature of
+/// its header with one made by `signing_key`, over the signed area of the
+/// header as it is. The key of program NCAs must match the ACID public key of
+/// their NPDM, e.g. one set with `nca_program --npdm`. Returns the SHA-256 of
+/// the new NCA.
+pub fn resign<R: Read + Seek + TryClone + 'static, W: Write>(
+    input: R,
+    output: &mut W,
+    keys: &Keys,
+    signing_key: &RsaPrivateKey,
+) -> Result<[u8; 0x20], Error> {
+    let nca = Nca::from_reader(input, keys)?;
+    if nca.header.version != 3 {
+        return Err(invalid_nca("only NCA3 headers can be signed again"));
+    }
+    if let Some(acid_public_key) = nca.acid_public_key()? {
+        if acid_public_key != *signing_key.modulus() {
+            return Err(invalid_nca(
+                "the signing key doesn't match the ACID public key of the NPDM",
+            ));
+        }
+    }
+
+    let mut data = *nca.raw_header;
+    let signature = signing_key.sign_pss(&data[0x200..0x400]);
+    data[0x100..0x200].copy_from_slice(&signature);
+    encrypt_header(&mut data, keys)?;
+
+    let mut output = HashingWriter {
+        inner: output,
+        hasher: Sha256::default(),
+    };
+    output.write_all(&data)?;
+    let mut input = nca.into_inner();
+    input.seek(SeekFrom::Start(NCA_HEADER_SIZE as u64))?;
+    io::copy(&mut input, &mut output)?;
+    Ok(output.hasher.finalize().into())
+}
+
+/// Result of checking a signature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SignatureStatus {
+    Valid,
+    Invalid,
+    /// There is no key to check the signature against.
+    Unchecked,
+}
+
+impl fmt::Display for SignatureStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SignatureStatus::Valid => "Valid",
+            SignatureStatus::Invalid => "Invalid",
+            SignatureStatus::Unchecked => "Unchecked",
+        })
+    }
+}
+
+/// Status of the two signatures of an NCA header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct NcaSignatures {
+    /// First signature, by the fixed key of the signature key generation.
+    pub fixed_key: SignatureStatus,
+    /// Second signature, by the key whose public key is in the ACID of the
+    /// NPDM. Only program NCAs have one.
+    pub npdm: SignatureStatus,
+}
+
+/// A block of a section whose hash doesn't match the one stored in the level
+/// before it, or in the master hash of the fs header for the first level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct HashMismatch {
+    /// Level of the block in the hash tree, from 0 for the one hashed in the
+    /// master hash to the one holding the filesystem.
+    pub level: usize,
+    /// Index of the block in its level.
+    pub block: u64,
+    /// Offset of the block in the section.
+    pub offset: u64,
+}
+
+impl fmt::Display for HashMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "level {} block {} (section offset {:#x})",
+            self.level, self.block, self.offset
+        )
+    }
+}
+
+/// Checks every level of the hash tree described by `hash_info` against the
+/// level before it, in `section`, the whole section. Returns the blocks whose
+/// hash doesn't match.
+fn verify_hashes<S: Read + Seek>(
+    hash_info: &HashInfo,
+    mut section: S,
+) -> Result<Vec<HashMismatch>, Error> {
+    // The levels, along with their block size and whether their last block
+    // is padded before being hashed.
+    let (master_hash, levels) = match hash_info {
+        HashInfo::HierarchicalSha256 {
+            master_hash,
+            block_size,
+            hash_table,
+            pfs0,
+        } => {
+            // The master hash is the hash of the whole hash table.
+            let table_block_size = std::cmp::max(hash_table.size, 1) as usize;
+            let levels = vec![
+                (*hash_table, table_block_size, false),
+                (*pfs0, *block_size as usize, false),
+            ];
+            (master_hash, levels)
+        }
+        HashInfo::HierarchicalIntegrity {
+            master_hash,
+            levels,
+        } => {
+            if levels.iter().any(|v| v.block_size_log2 > 30) {
+                return Err(invalid_nca("invalid IVFC block size"));
+            }
+            let levels = levels
+                .iter()
+                .map(|v| (v.region, 1usize << v.block_size_log2, true))
+                .collect();
+            (master_hash, levels)
+        }
+        HashInfo::None => return Ok(Vec::new()),
+    };
+
+    let section_size = section.seek(SeekFrom::End(0))?;
+    let mut hashes = master_hash.to_vec();
+    let mut mismatches = Vec::new();
+    for (level, &(region, block_size, full_block)) in levels.iter().enumerate() {
+        if block_size == 0 {
+            return Err(invalid_nca("invalid hash block size"));
+        }
+        if region
+            .offset
+            .checked_add(region.size)
+            .map_or(true, |end| end > section_size)
+        {
+            return Err(invalid_nca("section data is out of bounds"));
+        }
+        section.seek(SeekFrom::Start(region.offset))?;
+        // Hash levels are small enough to be kept for checking the next one.
+        let (computed, data) = if level + 1 < levels.len() {
+            let mut data = vec![0; region.size as usize];
+            section.read_exact(&mut data)?;
+            (hash_blocks(&data[..], block_size, full_block)?, data)
+        } else {
+            let computed = hash_blocks((&mut section).take(region.size), block_size, full_block)?;
+            (computed, Vec::new())
+        };
+        for (block, hash) in computed.chunks(0x20).enumerate() {
+            if hashes.get(block * 0x20..(block + 1) * 0x20) != Some(hash) {
+                mismatches.push(HashMismatch {
+                    level,
+                    block: block as u64,
+                    offset: region.offset + (block * block_size) as u64,
+                });
+            }
+        }
+        hashes = data;
+    }
+    Ok(mismatches)
+}
+
+/// Checks the regions of an NCA in strict mode: the sections must be in the
+/// file, without overlapping each other or the header. The hash levels of
+/// sections stored as is must be in their section, without overlapping each
+/// other. Those of compressed and BKTR sections are in the section they
+/// decompress to, so they are only checked when read.
+fn check_regions(header: &NcaHeader, file_size: u64) -> Result<(), Error> {
+    let mut regions = Regions::new(file_size);
+    regions.add("header", 0, NCA_HEADER_SIZE as u64)?;
+    for section in &header.sections {
+        regions.add(
+            format!("section {}", section.index),
+            section.offset,
+            section.size,
+        )?;
+        let fs_header = &section.fs_header;
+        if fs_header.encryption_type == EncryptionType::AesCtrEx
+            || fs_header.compression_table().is_some()
+            || fs_header.sparse_table().is_some()
+        {
+            continue;
+        }
+        let mut levels = Regions::within(format!("section {}", section.index), section.size);
+        let name = |level: &str| format!("{} of section {}", level, section.index);
+        match &fs_header.hash_info {
+            HashInfo::HierarchicalSha256 {
+                hash_table, pfs0, ..
+            } => {
+                levels.add(name("hash table"), hash_table.offset, hash_table.size)?;
+                levels.add(name("PFS0"), pfs0.offset, pfs0.size)?;
+            }
+            HashInfo::HierarchicalIntegrity { levels: ivfc, .. } => {
+                for (i, level) in ivfc.iter().enumerate() {
+                    let level_name = name(&format!("IVFC level {}", i + 1));
+                    levels.add(level_name, level.region.offset, level.region.size)?;
+                }
+            }
+            HashInfo::None => (),
+        }
+        levels.check_overlaps()?;
+    }
+    regions.check_overlaps()
+}
+
+/// Checks the entries of a table are sorted by `offset`, as `find_entry`
+/// expects.
+fn check_sorted<T>(
+    entries: &[T],
+    offset: impl Fn(&T) -> u64,
+    error: &'static str,
+) -> Result<(), Error> {
+    if entries
+        .windows(2)
+        .any(|pair| offset(&pair[0]) > offset(&pair[1]))
+    {
+        return Err(invalid_nca(error));
+    }
+    Ok(())
+}
+
+pub struct Nca<R> {
+    file: R,
+    header: NcaHeader,
+    raw_header: Box<[u8; NCA_HEADER_SIZE]>,
+    key: Option<Aes128Key>,
+    mode: ParseMode,
+}
+
+impl<R: Read + Seek + TryClone> Nca<R> {
+    /// Opens the NCA in `file`, decrypting its header and the key used by its
+    /// sections.
+    pub fn from_reader(file: R, keys: &Keys) -> Result<Nca<R>, Error> {
+        Nca::from_reader_with_mode(file, keys, ParseMode::Lenient)
+    }
+
+    /// Same as `from_reader`, checking the file as told by `mode`. In strict
+    /// mode, the sections and their hash levels are checked before anything
+    /// is read from them, and so are the compression tables of the sections
+    /// when opening them.
+    pub fn from_reader_with_mode(
+        mut file: R,
+        keys: &Keys,
+        mode: ParseMode,
+    ) -> Result<Nca<R>, Error> {
+        let raw_header = Box::new(decrypt_header(&mut file, keys)?);
+        let header = NcaHeader::parse(&raw_header)?;
+        if mode.is_strict() {
+            check_regions(&header, file.seek(SeekFrom::End(0))?)?;
+        }
+        let needs_key = header
+            .sections
+            .iter()
+            .any(|v| v.fs_header.encryption_type != EncryptionType::None);
+        let key = if !needs_key {
+            None
+        } else if header.has_rights_id() {
+            // Titlekey crypto: the sections use the titlekey of the ticket
+            // with this rights ID, from `title.keys` or `Keys::read_ticket`.
+            Some(keys.titlekey(&header.rights_id, header.master_key_revision())?)
+        } else {
+            let key_area_key =
+                keys.key_area_key(header.key_area_key_index, header.master_key_revision())?;
+            // The AES-CTR key is the third one of the key area.
+            Some(key_area_key.derive_key(&header.encrypted_key_area[2])?)
+        };
+        Ok(Nca {
+            file,
+            header,
+            raw_header,
+            key,
+            mode,
+        })
+    }
+
+    pub fn header(&self) -> &NcaHeader {
+        &self.header
+    }
+
+    fn find_section(&self, index: usize) -> Result<&NcaSection, Error> {
+        self.header
+            .sections
+            .iter()
+            .find(|v| v.index == index)
+            .ok_or_else(|| invalid_nca("no such section"))
+    }
+
+    /// Opens `size` bytes at `offset` in the decrypted and decompressed
+    /// section, up to its end if `size` is `None`.
+    fn open_region(
+        &self,
+        section: &NcaSection,
+        offset: u64,
+        size: Option<u64>,
+    ) -> Result<NcaSectionReader<R>, Error> {
+        let key = match section.fs_header.encryption_type {
+            EncryptionType::None => None,
+            EncryptionType::AesCtr => self.key.clone(),
+            EncryptionType::AesXts => {
+                return Err(invalid_nca("AES-XTS sections are not supported"))
+            }
+            EncryptionType::AesCtrEx => {
+                return Err(invalid_nca(
+                    "BKTR sections can only be read over the NCA they patch",
+                ))
+            }
+            EncryptionType::Auto => return Err(invalid_nca("unknown section encryption")),
+        };
+        if section.fs_header.sparse_table().is_some() {
+            return Err(invalid_nca("sparse sections are not supported"));
+        }
+
+        let compression = if section.fs_header.compression_table().is_some() {
+            let (entries, size) = self.read_bucket_tree(
+                section,
+                &section.fs_header.compression_info[..0x20],
+                0x18,
+                |data| CompressionEntry {
+                    virtual_offset: LittleEndian::read_u64(&data[0x0..]),
+                    physical_offset: LittleEndian::read_u64(&data[0x8..]),
+                    compression_type: CompressionType::from_u8(data[0x10]),
+                    physical_size: LittleEndian::read_u32(&data[0x14..]),
+                },
+            )?;
+            if entries.first().map(|v| v.virtual_offset) != Some(0) {
+                return Err(invalid_nca("compression table doesn't start at 0"));
+            }
+            check_sorted(
+                &entries,
+                |v| v.virtual_offset,
+                "compression table isn't sorted",
+            )?;
+            // The stored data is read relative to the section, whose offset
+            // stays in range as long as the entries are in it.
+            let mut regions = Regions::within(format!("section {}", section.index), section.size);
+            for (i, entry) in entries.iter().enumerate() {
+                match entry.compression_type {
+                    Some(CompressionType::None) | Some(CompressionType::Lz4) => (),
+                    _ => continue,
+                }
+                let region = format!("compressed entry {}", i);
+                regions.add(region, entry.physical_offset, entry.physical_size.into())?;
+            }
+            if self.mode.is_strict() {
+                regions.check_overlaps()?;
+            }
+            Some(CompressedSection {
+                section_offset: section.offset,
+                size,
+                entries: Arc::new(entries),
+                cache: None,
+            })
+        } else {
+            None
+        };
+
+        let section_size = match &compression {
+            Some(compression) => compression.size,
+            None => section.size,
+        };
+        let size = size.unwrap_or_else(|| section_size.saturating_sub(offset));
+        if offset
+            .checked_add(size)
+            .map_or(true, |end| end > section_size)
+        {
+            return Err(invalid_nca("section data is out of bounds"));
+        }
+        Ok(NcaSectionReader {
+            file: self.file.try_clone()?,
+            start: section.offset + offset,
+            size,
+            pos: 0,
+            crypter: key.map(|key| AesCtr::new(key, section.fs_header.ctr)),
+            compression,
+        })
+    }
+
+    /// Reads one of the two bucket trees of a BKTR section, whose header is
+    /// in `header`, the first or second half of the patch info.
+    fn read_bucket_tree<T>(
+        &self,
+        section: &NcaSection,
+        header: &[u8],
+        entry_size: usize,
+        parse: impl Fn(&[u8]) -> T,
+    ) -> Result<(Vec<T>, u64), Error> {
+        let offset = LittleEndian::read_u64(&header[0x0..]);
+        let size = LittleEndian::read_u64(&header[0x8..]);
+        if &header[0x10..0x14] != b"BKTR" {
+            return Err(invalid_nca("invalid BKTR header magic"));
+        }
+        if offset
+            .checked_add(size)
+            .map_or(true, |end| end > section.size)
+            || size < BKTR_BUCKET_SIZE as u64
+        {
+            return Err(invalid_nca("BKTR table is out of bounds"));
+        }
+
+        // The tables are encrypted like the rest of the section.
+        let key = match section.fs_header.encryption_type {
+            EncryptionType::None => None,
+            _ => self.key.clone(),
+        };
+        let mut table = NcaSectionReader {
+            file: self.file.try_clone()?,
+            start: section.offset + offset,
+            size,
+            pos: 0,
+            crypter: key.map(|key| AesCtr::new(key, section.fs_header.ctr)),
+            compression: None,
+        };
+
+        // A first bucket lists the offsets of the next ones, each holding a
+        // part of the entries. They are read one at a time, so that nothing
+        // is allocated for buckets that aren't there.
+        let mut bucket = vec![0; BKTR_BUCKET_SIZE];
+        table.read_exact(&mut bucket)?;
+        let bucket_count = LittleEndian::read_u32(&bucket[0x4..]) as u64;
+        let total_size = LittleEndian::read_u64(&bucket[0x8..]);
+        if (bucket_count + 1) * BKTR_BUCKET_SIZE as u64 > size {
+            return Err(invalid_nca("BKTR table is out of bounds"));
+        }
+        let mut entries = Vec::new();
+        for _ in 0..bucket_count {
+            table.read_exact(&mut bucket)?;
+            let entry_count = LittleEndian::read_u32(&bucket[0x4..]) as usize;
+            if entry_count > (BKTR_BUCKET_SIZE - 0x10) / entry_size {
+                return Err(invalid_nca("BKTR bucket has too many entries"));
+            }
+            entries.extend(
+                bucket[0x10..0x10 + entry_count * entry_size]
+                    .chunks_exact(entry_size)
+                    .map(&parse),
+            );
+        }
+        Ok((entries, total_size))
+    }
+
+    /// Index of the RomFS section, the one patched by update NCAs.
+    fn romfs_section_index(&self) -> Result<usize, Error> {
+        self.header
+            .sections
+            .iter()
+            .find(|v| v.fs_header.fs_type == FsType::RomFs)
+            .map(|v| v.index)
+            .ok_or_else(|| invalid_nca("the base NCA has no RomFS section"))
+    }
+
+    /// Opens the whole section `index` of this patch NCA, a BKTR section
+    /// patching the RomFS section of `base`, the NCA of the patched title.
+    pub fn patched_section<B: Read + Seek + TryClone>(
+        &self,
+        index: usize,
+        base: &Nca<B>,
+    ) -> Result<BktrReader<R, B>, Error> {
+        let section = self.find_section(index)?;
+        if section.fs_header.encryption_type != EncryptionType::AesCtrEx {
+            return Err(invalid_nca("section is not a BKTR section"));
+        }
+        let base_index = base.romfs_section_index()?;
+
+        let patch_info = &section.fs_header.patch_info;
+        let (relocations, size) =
+            self.read_bucket_tree(section, &patch_info[..0x20], 0x14, |data| RelocationEntry {
+                virtual_offset: LittleEndian::read_u64(&data[0x0..]),
+                physical_offset: LittleEndian::read_u64(&data[0x8..]),
+                is_patch: LittleEndian::read_u32(&data[0x10..]) != 0,
+            })?;
+        let (mut subsections, _) =
+            self.read_bucket_tree(section, &patch_info[0x20..], 0x10, |data| SubsectionEntry {
+                offset: LittleEndian::read_u64(&data[0x0..]),
+                ctr_val: LittleEndian::read_u32(&data[0xC..]),
+            })?;
+        if relocations.first().map(|v| v.virtual_offset) != Some(0) {
+            return Err(invalid_nca("BKTR relocation table doesn't start at 0"));
+        }
+        // The tables themselves follow the last subsection, and use the
+        // counter of the section.
+        subsections.push(SubsectionEntry {
+            offset: LittleEndian::read_u64(&patch_info[0x0..]),
+            ctr_val: section.fs_header.ctr as u32,
+        });
+        check_sorted(
+            &relocations,
+            |v| v.virtual_offset,
+            "BKTR relocation table isn't sorted",
+        )?;
+        check_sorted(
+            &subsections,
+            |v| v.offset,
+            "BKTR subsection table isn't sorted",
+        )?;
+
+        Ok(BktrReader {
+            base: base.section(base_index)?,
+            patch: self.file.try_clone()?,
+            section_offset: section.offset,
+            key: self.key.clone(),
+            ctr: section.fs_header.ctr,
+            relocations,
+            subsections,
+            start: 0,
+            size,
+            pos: 0,
+        })
+    }
+
+    /// Opens the RomFS of section `index` of this patch NCA, as patched over
+    /// the RomFS of `base`.
+    pub fn patched_romfs<B: Read + Seek + TryClone>(
+        &self,
+        index: usize,
+        base: &Nca<B>,
+    ) -> Result<RomFsReader<BktrReader<R, B>>, Error> {
+        let section = self.find_section(index)?;
+        if section.fs_header.fs_type != FsType::RomFs {
+            return Err(invalid_nca("section is not a RomFS"));
+        }
+        let region = section
+            .fs_header
+            .data_region()
+            .ok_or_else(|| invalid_nca("section has no hash info"))?;
+        let mut reader = self.patched_section(index, base)?;
+        if region
+            .offset
+            .checked_add(region.size)
+            .map_or(true, |end| end > reader.size)
+        {
+            return Err(invalid_nca("section data is out of bounds"));
+        }
+        reader.start = region.offset;
+        reader.size = region.size;
+        RomFsReader::from_reader(reader)
+    }
+
+    /// Opens the decrypted content of the whole section `index`, hash data
+    /// included.
+    pub fn section(&self, index: usize) -> Result<NcaSectionReader<R>, Error> {
+        let section = self.find_section(index)?;
+        self.open_region(section, 0, None)
+    }
+
+    /// Opens the filesystem of section `index`, without its hash data.
+    pub fn section_data(&self, index: usize) -> Result<NcaSectionReader<R>, Error> {
+        let section = self.find_section(index)?;
+        let region = section
+            .fs_header
+            .data_region()
+            .ok_or_else(|| invalid_nca("section has no hash info"))?;
+        self.open_region(section, region.offset, Some(region.size))
+    }
+
+    /// Opens the PFS0 of section `index`, e.g. the ExeFS of a program NCA.
+    pub fn pfs0(&self, index: usize) -> Result<Pfs0, Error>
+    where
+        R: 'static,
+    {
+        if self.find_section(index)?.fs_header.fs_type != FsType::PartitionFs {
+            return Err(invalid_nca("section is not a PFS0"));
+        }
+        Pfs0::from_reader(self.section_data(index)?)
+    }
+
+    /// Opens the RomFS of section `index`.
+    pub fn romfs(&self, index: usize) -> Result<RomFsReader<NcaSectionReader<R>>, Error> {
+        if self.find_section(index)?.fs_header.fs_type != FsType::RomFs {
+            return Err(invalid_nca("section is not a RomFS"));
+        }
+        RomFsReader::from_reader(self.section_data(index)?)
+    }
+
+    /// Checks the signatures of the header. The second one is checked against
+    /// the ACID public key of the main.npdm in the ExeFS of program NCAs.
+    pub fn verify_signatures(&self, keys: &Keys) -> Result<NcaSignatures, Error>
+    where
+        R: 'static,
+    {
+        const EXPONENT: [u8; 3] = [0x01, 0x00, 0x01];
+        let signed_data = &self.raw_header[0x200..0x400];
+        let check = |modulus: &[u8; 0x100], signature: &[u8; 0x100]| {
+            if rsa2048_pss_verify(modulus, &EXPONENT, signed_data, signature) {
+                SignatureStatus::Valid
+            } else {
+                SignatureStatus::Invalid
+            }
+        };
+
+        let fixed_key =
+            match keys.nca_hdr_fixed_key_modulus(self.header.signature_key_generation as usize) {
+                Ok(modulus) => check(modulus, &self.header.fixed_key_signature),
+                Err(_) => SignatureStatus::Unchecked,
+            };
+
+        let npdm = match self.acid_public_key()? {
+            Some(acid_public_key) => check(&acid_public_key, &self.header.npdm_signature),
+            None => SignatureStatus::Unchecked,
+        };
+
+        Ok(NcaSignatures { fixed_key, npdm })
+    }
+
+    /// ACID public key of the main.npdm in the ExeFS of program NCAs, which
+    /// checks the second signature of the header. Other NCAs have none.
+    pub fn acid_public_key(&self) -> Result<Option<[u8; 0x100]>, Error>
+    where
+        R: 'static,
+    {
+        if self.header.content_type != ContentType::Program {
+            return Ok(None);
+        }
+        let mut npdm = Vec::new();
+        self.pfs0(0)?
+            .into_file("main.npdm")
+            .ok_or_else(|| invalid_nca("the ExeFS has no main.npdm"))??
+            .read_to_end(&mut npdm)?;
+        Ok(Some(NpdmInfo::parse(&npdm)?.acid_public_key))
+    }
+
+    /// Checks the hashes of section `index`, from the master hash in its fs
+    /// header down to the filesystem. Returns the blocks whose hash doesn't
+    /// match.
+    pub fn verify_section(&self, index: usize) -> Result<Vec<HashMismatch>, Error> {
+        let section = self.find_section(index)?;
+        verify_hashes(&section.fs_header.hash_info, self.section(index)?)
+    }
+
+    /// Checks the hashes of section `index` of this patch NCA, a BKTR section
+    /// patching `base`, like `verify_section`.
+    pub fn verify_patched_section<B: Read + Seek + TryClone>(
+        &self,
+        index: usize,
+        base: &Nca<B>,
+    ) -> Result<Vec<HashMismatch>, Error> {
+        let section = self.find_section(index)?;
+        verify_hashes(
+            &section.fs_header.hash_info,
+            self.patched_section(index, base)?,
+        )
+    }
+
+    pub fn into_inner(self) -> R {
+        self.file
+    }
+}
+
+/// Entries are the decrypted sections, named `section<index>.bin` as by
+/// `linkle nca_extract`. NCA headers are encrypted, so `detect` never
+/// recognizes them: open them with `Nca::from_reader` or
+/// `container::detect_with_keys`.
+impl<R: Read + Seek + TryClone> SwitchFormat for Nca<R> {
+    fn detect(_header: &[u8]) -> bool {
+        false
+    }
+
+    fn list_entries(&self) -> Result<Vec<ContainerEntry>, Error> {
+        let mut entries = Vec::with_capacity(self.header.sections.len());
+        for section in &self.header.sections {
+            entries.push(ContainerEntry {
+                name: format!("section{}.bin", section.index),
+                size: self.section(section.index)?.seek(SeekFrom::End(0))?,
+            });
+        }
+        Ok(entries)
+    }
+
+    fn extract_entry(&mut self, name: &str) -> Result<Box<dyn ReadSeek + '_>, Error> {
+        let section = self
+            .header
+            .sections
+            .iter()
+            .find(|section| format!("section{}.bin", section.index) == name)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("No entry named {} in the NCA", name),
+                )
+            })?;
+        Ok(Box::new(self.section(section.index)?))
+    }
+
+    fn metadata(&self) -> Result<serde_json::Value, Error> {
+        Ok(serde_json::to_value(&self.header)?)
+    }
+}
+
+/// Reads a part of an NCA section, decrypting and decompressing it on the
+/// fly.
+pub struct NcaSectionReader<R> {
+    file: R,
+    /// Offset of the readable data in the NCA. For compressed sections, the
+    /// decompressed section is taken to start at the offset of the section.
+    start: u64,
+    size: u64,
+    pos: u64,
+    crypter: Option<AesCtr>,
+    compression: Option<CompressedSection>,
+}
+
+impl<R: Read + Seek> Read for NcaSectionReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = std::cmp::min(buf.len() as u64, self.size.saturating_sub(self.pos)) as usize;
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let len = match &mut self.compression {
+            Some(compression) => compression.read(
+                &mut self.file,
+                self.start + self.pos - compression.section_offset,
+                &mut buf[..len],
+                self.crypter.as_ref(),
+            )?,
+            None => {
+                read_decrypted(
+                    &mut self.file,
+                    self.start + self.pos,
+                    &mut buf[..len],
+                    self.crypter.as_ref(),
+                )?;
+                len
+            }
+        };
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+nca_enum! {
+    /// How a part of a compressed section is stored.
+    pub enum CompressionType {
+        None = 0 => "None",
+        Zeros = 1 => "Zeros",
+        Lz4 = 3 => "LZ4",
+    }
+}
+
+/// Maps a part of a compressed section, starting at `virtual_offset` in the
+/// decompressed section, to the stored data. The type is `None` for the ones
+/// this doesn't know of.
+#[derive(Debug, Clone, Copy)]
+struct CompressionEntry {
+    virtual_offset: u64,
+    physical_offset: u64,
+    compression_type: Option<CompressionType>,
+    physical_size: u32,
+}
+
+/// Decompresses the data of a compressed section for `NcaSectionReader`.
+#[derive(Clone)]
+struct CompressedSection {
+    /// Offset of the section in the NCA, the physical offsets being relative
+    /// to it.
+    section_offset: u64,
+    /// Size of the decompressed section.
+    size: u64,
+    entries: Arc<Vec<CompressionEntry>>,
+    /// Index and content of the last LZ4 entry decompressed.
+    cache: Option<(usize, Arc<Vec<u8>>)>,
+}
+
+impl CompressedSection {
+    /// Reads up to `buf.len()` bytes at `offset` in the decompressed section,
+    /// stopping at the end of the entry holding `offset`.
+    fn read<R: Read + Seek>(
+        &mut self,
+        file: &mut R,
+        offset: u64,
+        buf: &mut [u8],
+        crypter: Option<&AesCtr>,
+    ) -> io::Result<usize> {
+        let invalid_data = |error| io::Error::new(io::ErrorKind::InvalidData, error);
+
+        let idx = find_entry(&self.entries, offset, |v| v.virtual_offset)
+            .ok_or_else(|| invalid_data("no compression entry for offset"))?;
+        let entry = self.entries[idx];
+        let end = self
+            .entries
+            .get(idx + 1)
+            .map_or(self.size, |v| v.virtual_offset);
+        let len = std::cmp::min(buf.len() as u64, end - offset) as usize;
+        let offset_in_entry = (offset - entry.virtual_offset) as usize;
+        let buf = &mut buf[..len];
+
+        match entry.compression_type {
+            Some(CompressionType::None) => {
+                let offset = (entry.physical_offset + self.section_offset)
+                    .checked_add(offset_in_entry as u64)
+                    .ok_or_else(|| invalid_data("compressed entry is out of bounds"))?;
+                read_decrypted(file, offset, buf, crypter)?
+            }
+            Some(CompressionType::Zeros) => {
+                for byte in buf.iter_mut() {
+                    *byte = 0;
+                }
+            }
+            Some(CompressionType::Lz4) => {
+                let data = match &self.cache {
+                    Some((cached_idx, data)) if *cached_idx == idx => data.clone(),
+                    _ => {
+                        let size = end - entry.virtual_offset;
+                        if size > i32::MAX as u64 {
+                            return Err(invalid_data("compressed entry is too big"));
+                        }
+                        // LZ4 never makes data bigger than this, so the
+                        // stored size of a well-formed entry is in its
+                        // bounds, whatever the size the section claims.
+                        if u64::from(entry.physical_size) > size + size / 255 + 16 {
+                            return Err(invalid_data("compressed entry is too big"));
+                        }
+                        let mut compressed = vec![0; entry.physical_size as usize];
+                        read_decrypted(
+                            file,
+                            self.section_offset + entry.physical_offset,
+                            &mut compressed,
+                            crypter,
+                        )?;
+                        let data =
+                            Arc::new(lz4::block::decompress(&compressed, Some(size as i32))?);
+                        self.cache = Some((idx, data.clone()));
+                        data
+                    }
+                };
+                if data.len() < offset_in_entry + len {
+                    return Err(invalid_data("compressed entry is truncated"));
+                }
+                buf.copy_from_slice(&data[offset_in_entry..offset_in_entry + len]);
+            }
+            None => return Err(invalid_data("unknown compression type")),
+        }
+        Ok(len)
+    }
+}
+
+/// Reads `buf.len()` bytes at `offset` in `file`, decrypting them with
+/// `crypter` if there is one.
+fn read_decrypted<R: Read + Seek>(
+    file: &mut R,
+    offset: u64,
+    buf: &mut [u8],
+    crypter: Option<&AesCtr>,
+) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)?;
+    if let Some(crypter) = crypter {
+        crypter.apply_keystream(buf, offset);
+    }
+    Ok(())
+}
+
+impl<R> Seek for NcaSectionReader<R> {
+    fn seek(&mut self, from: SeekFrom) -> io::Result<u64> {
+        let pos = match from {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::Current(offset) => add_offset(self.pos, offset),
+            SeekFrom::End(offset) => add_offset(self.size, offset),
+        };
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seek before position 0",
+            )),
+        }
+    }
+}
+
+/// Size of the buckets of the BKTR tables.
+const BKTR_BUCKET_SIZE: usize = 0x4000;
+
+/// Maps a part of a patched section, starting at `virtual_offset`, to data
+/// in the base section or in the patch.
+#[derive(Debug, Clone, Copy)]
+struct RelocationEntry {
+    virtual_offset: u64,
+    physical_offset: u64,
+    is_patch: bool,
+}
+
+/// Part of the patch data, starting at `offset`, encrypted with a counter
+/// using `ctr_val` as its low bits.
+#[derive(Debug, Clone, Copy)]
+struct SubsectionEntry {
+    offset: u64,
+    ctr_val: u32,
+}
+
+/// Finds the entry containing `offset`, in entries sorted by their start.
+fn find_entry<T>(entries: &[T], offset: u64, start: impl Fn(&T) -> u64) -> Option<usize> {
+    match entries.binary_search_by_key(&offset, start) {
+        Ok(idx) => Some(idx),
+        Err(0) => None,
+        Err(idx) => Some(idx - 1),
+    }
+}
+
+/// Reads a BKTR section, taking the data that didn't change from the section
+/// of the base NCA, and the rest from the patch NCA.
+pub struct BktrReader<R, B> {
+    base: NcaSectionReader<B>,
+    patch: R,
+    /// Offset of the BKTR section in the patch NCA.
+    section_offset: u64,
+    key: Option<Aes128Key>,
+    ctr: u64,
+    relocations: Vec<RelocationEntry>,
+    subsections: Vec<SubsectionEntry>,
+    /// Offset of the readable data in the patched section.
+    start: u64,
+    size: u64,
+    pos: u64,
+}
+
+impl<R: Read + Seek, B: Read + Seek> Read for BktrReader<R, B> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = std::cmp::min(buf.len() as u64, self.size.saturating_sub(self.pos));
+        if len == 0 {
+            return Ok(0);
+        }
+        let invalid_data = |error| io::Error::new(io::ErrorKind::InvalidData, error);
+
+        let offset = self.start + self.pos;
+        let idx = find_entry(&self.relocations, offset, |v| v.virtual_offset)
+            .ok_or_else(|| invalid_data("no BKTR relocation entry for offset"))?;
+        let entry = self.relocations[idx];
+        let end = self
+            .relocations
+            .get(idx + 1)
+            .map_or(self.start + self.size, |v| v.virtual_offset);
+        let len = std::cmp::min(len, end - offset);
+        let physical_offset = entry
+            .physical_offset
+            .checked_add(offset - entry.virtual_offset)
+            .ok_or_else(|| invalid_data("BKTR relocation entry is out of bounds"))?;
+
+        let len = if entry.is_patch {
+            let idx = find_entry(&self.subsections, physical_offset, |v| v.offset)
+                .ok_or_else(|| invalid_data("no BKTR subsection entry for offset"))?;
+            let subsection = self.subsections[idx];
+            let len = match self.subsections.get(idx + 1) {
+                Some(next) => std::cmp::min(len, next.offset - physical_offset),
+                None => len,
+            } as usize;
+            let ctr = (self.ctr & !0xFFFF_FFFF) | subsection.ctr_val as u64;
+            let crypter = self.key.clone().map(|key| AesCtr::new(key, ctr));
+            let offset = self
+                .section_offset
+                .checked_add(physical_offset)
+                .ok_or_else(|| invalid_data("BKTR subsection entry is out of bounds"))?;
+            read_decrypted(&mut self.patch, offset, &mut buf[..len], crypter.as_ref())?;
+            len
+        } else {
+            let len = len as usize;
+            self.base.seek(SeekFrom::Start(physical_offset))?;
+            self.base.read_exact(&mut buf[..len])?;
+            len
+        };
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<R, B> Seek for BktrReader<R, B> {
+    fn seek(&mut self, from: SeekFrom) -> io::Result<u64> {
+        let pos = match from {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::Current(offset) => add_offset(self.pos, offset),
+            SeekFrom::End(offset) => add_offset(self.size, offset),
+        };
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seek before position 0",
+            )),
+        }
+    }
+}
+
+impl<R: TryClone> TryClone for NcaSectionReader<R> {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(NcaSectionReader {
+            file: self.file.try_clone()?,
+            start: self.start,
+            size: self.size,
+            pos: self.pos,
+            crypter: self.crypter.clone(),
+            compression: self.compression.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::format::romfs::{RomFsBuilder, RomFsEntry};
+    use crate::format::tik::{self, Ticket};
+
+    /// Keys made up for the tests, enough to encrypt and decrypt NCAs of the
+    /// first key generation.
+    fn test_keys() -> Keys {
+        let keyfile = format!(
+            "header_key = {}{}\nkey_area_key_application_00 = {}\ntitlekek_00 = {}\n",
+            "01".repeat(0x10),
+            "02".repeat(0x10),
+            "03".repeat(0x10),
+            "04".repeat(0x10)
+        );
+        Keys::retail_from_reader(keyfile.as_bytes()).unwrap()
+    }
+
+    fn read_pfs0_files(pfs0: Pfs0) -> Vec<(String, Vec<u8>)> {
+        pfs0.files()
+            .map(|file| {
+                let mut file = file.unwrap();
+                let mut data = Vec::new();
+                file.read_to_end(&mut data).unwrap();
+                (file.file_name().to_string(), data)
+            })
+            .collect()
+    }
+
+    /// Builds an NCA with a single PFS0 section holding `files`, encrypted
+    /// with `titlekey` under the rights ID of `program_id`.
+    fn ticketed_nca(
+        content_type: ContentType,
+        program_id: u64,
+        titlekey: [u8; 0x10],
+        files: &[(&str, Vec<u8>)],
+    ) -> Vec<u8> {
+        let mut pfs0 = Pfs0::new();
+        for (name, data) in files {
+            pfs0.push_data(name.to_string(), data.clone()).unwrap();
+        }
+        let mut pfs0_data = io::Cursor::new(Vec::new());
+        pfs0.write_pfs0(&mut pfs0_data).unwrap();
+        let mut nca = NcaBuilder::new(content_type, program_id);
+        nca.rights_id = Some(tik::rights_id(program_id, 0));
+        nca.section_key = Some(titlekey);
+        nca.add_section(FsType::PartitionFs, pfs0_data).unwrap();
+        let mut nca_data = Vec::new();
+        nca.write(&mut nca_data, &test_keys()).unwrap();
+        nca_data
+    }
+
+    #[test]
+    fn nsp_converts_to_standard_crypto() {
+        let keys = test_keys();
+        let program_id = 0x0100_0000_0000_1000;
+        let rights_id = tik::rights_id(program_id, 0);
+        let titlekey = [0x42; 0x10];
+        let nca_data = ticketed_nca(
+            ContentType::Program,
+            program_id,
+            titlekey,
+            &[("main", vec![0x55; 0x100])],
+        );
+
+        // The ticket comes after the NCA it decrypts.
+        let ticket = Ticket::common(rights_id, &titlekey, &keys).unwrap();
+        let mut nsp = Pfs0::new();
+        nsp.push_data("program.nca".to_string(), nca_data).unwrap();
+        nsp.push_data(ticket.file_name(), ticket.to_bytes())
+            .unwrap();
+        nsp.push_data(ticket.cert_file_name(), vec![0; 0x10])
+            .unwrap();
+        let mut nsp_data = io::Cursor::new(Vec::new());
+        nsp.write_pfs0(&mut nsp_data).unwrap();
+
+        let mut keys = test_keys();
+        let mut output = io::Cursor::new(Vec::new());
+        let converted = convert_nsp_to_standard_crypto(nsp_data, &mut output, &mut keys).unwrap();
+        assert_eq!(converted, ["program.nca"]);
+
+        // Without the titlekey, the NCA is still readable.
+        let keys = test_keys();
+        output.set_position(0);
+        let files = read_pfs0_files(Pfs0::from_reader(output).unwrap());
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].0, "program.nca");
+        let nca = Nca::from_reader(io::Cursor::new(files[0].1.clone()), &keys).unwrap();
+        assert!(!nca.header().has_rights_id());
+        assert_eq!(
+            read_pfs0_files(nca.pfs0(0).unwrap()),
+            [("main".to_string(), vec![0x55; 0x100])]
+        );
+    }
+
+    #[test]
+    fn nsp_program_is_found_among_its_contents() {
+        let keys = test_keys();
+        let program_id = 0x0100_0000_0000_1000;
+        let titlekey = [0x42; 0x10];
+        let control = ticketed_nca(
+            ContentType::Control,
+            program_id,
+            titlekey,
+            &[("control.nacp", vec![0x11; 0x40])],
+        );
+        let program = ticketed_nca(
+            ContentType::Program,
+            program_id,
+            titlekey,
+            &[("main", vec![0x55; 0x100])],
+        );
+        let ticket = Ticket::common(tik::rights_id(program_id, 0), &titlekey, &keys).unwrap();
+        let mut nsp = Pfs0::new();
+        nsp.push_data("control.nca".to_string(), control).unwrap();
+        nsp.push_data("program.nca".to_string(), program).unwrap();
+        nsp.push_data(ticket.file_name(), ticket.to_bytes())
+            .unwrap();
+        let mut nsp_data = io::Cursor::new(Vec::new());
+        nsp.write_pfs0(&mut nsp_data).unwrap();
+
+        let mut keys = test_keys();
+        let (name, nca) = open_nsp_program(nsp_data, &mut keys).unwrap();
+        assert_eq!(name, "program.nca");
+        assert_eq!(
+            read_pfs0_files(nca.pfs0(0).unwrap()),
+            [("main".to_string(), vec![0x55; 0x100])]
+        );
+    }
+
+    #[test]
+    fn nsp_without_program_is_rejected() {
+        let mut nsp = Pfs0::new();
+        nsp.push_data("control.nca".to_string(), Vec::new())
+            .unwrap();
+        let mut nsp_data = io::Cursor::new(Vec::new());
+        nsp.write_pfs0(&mut nsp_data).unwrap();
+        assert!(open_nsp_program(nsp_data, &mut test_keys()).is_err());
+    }
+
+    #[test]
+    fn strict_mode_checks_the_sections() {
+        let keys = test_keys();
+        let mut pfs0 = Pfs0::new();
+        pfs0.push_data("main".to_string(), vec![0x55; 0x100])
+            .unwrap();
+        let mut pfs0_data = io::Cursor::new(Vec::new());
+        pfs0.write_pfs0(&mut pfs0_data).unwrap();
+        let mut nca = NcaBuilder::new(ContentType::Program, 0x0100_0000_0000_1000);
+        nca.add_section(FsType::PartitionFs, pfs0_data).unwrap();
+        let mut data = Vec::new();
+        nca.write(&mut data, &keys).unwrap();
+
+        let nca =
+            Nca::from_reader_with_mode(io::Cursor::new(data.clone()), &keys, ParseMode::Strict)
+                .unwrap();
+        assert_eq!(
+            read_pfs0_files(nca.pfs0(0).unwrap()),
+            [("main".to_string(), vec![0x55; 0x100])]
+        );
+
+        // The section goes past the end of the file.
+        data.truncate(data.len() - MEDIA_SIZE as usize);
+        assert!(Nca::from_reader(io::Cursor::new(data.clone()), &keys).is_ok());
+        assert!(
+            Nca::from_reader_with_mode(io::Cursor::new(data), &keys, ParseMode::Strict).is_err()
+        );
+    }
+
+    fn romfs_image(files: &[(&str, Vec<u8>)]) -> io::Cursor<Vec<u8>> {
+        let mut builder = RomFsBuilder::new();
+        for (path, data) in files {
+            builder
+                .add_file(path, io::Cursor::new(data.clone()), data.len() as u64)
+                .unwrap();
+        }
+        let mut data = Vec::new();
+        builder.build().write(&mut data).unwrap();
+        io::Cursor::new(data)
+    }
+
+    fn read_romfs_files<R: Read + Seek>(mut romfs: RomFsReader<R>) -> Vec<(String, Vec<u8>)> {
+        let mut files = Vec::new();
+        for entry in romfs.entries().unwrap() {
+            if let RomFsEntry::File { path, offset, size } = entry {
+                let mut data = Vec::new();
+                romfs
+                    .open_file(offset, size)
+                    .unwrap()
+                    .read_to_end(&mut data)
+                    .unwrap();
+                files.push((path, data));
+            }
+        }
+        files
+    }
+
+    #[test]
+    fn patch_romfs_is_read_over_the_base_nca() {
+        let keys = test_keys();
+        let program_id = 0x0100_0000_0000_1000;
+        let kept = vec![0x11; 0x20000];
+        let changed: Vec<u8> = (0..0x20000u32).map(|v| v as u8).collect();
+        let mut patched = changed.clone();
+        patched[0x10000..0x10100].copy_from_slice(&[0xEE; 0x100]);
+
+        let mut base = NcaBuilder::new(ContentType::Program, program_id);
+        base.section_key = Some([0x21; 0x10]);
+        base.add_section(
+            FsType::RomFs,
+            romfs_image(&[("kept.bin", kept.clone()), ("changed.bin", changed)]),
+        )
+        .unwrap();
+        let mut base_data = Vec::new();
+        base.write(&mut base_data, &keys).unwrap();
+        let base = Nca::from_reader(io::Cursor::new(base_data.clone()), &keys).unwrap();
+
+        let mut patch = NcaBuilder::new(ContentType::Program, program_id);
+        patch.section_key = Some([0x22; 0x10]);
+        patch
+            .add_patch_section(
+                romfs_image(&[
+                    ("kept.bin", kept.clone()),
+                    ("changed.bin", patched.clone()),
+                    ("new.bin", vec![0x33; 0x100]),
+                ]),
+                &base,
+            )
+            .unwrap();
+        let mut patch_data = Vec::new();
+        patch.write(&mut patch_data, &keys).unwrap();
+        // Only what isn't in the base is stored in the patch.
+        assert!(patch_data.len() < base_data.len());
+        let patch = Nca::from_reader(io::Cursor::new(patch_data), &keys).unwrap();
+
+        assert_eq!(
+            read_romfs_files(patch.patched_romfs(0, &base).unwrap()),
+            [
+                ("/changed.bin".to_string(), patched),
+                ("/kept.bin".to_string(), kept),
+                ("/new.bin".to_string(), vec![0x33; 0x100]),
+            ]
+        );
+        assert!(patch.verify_patched_section(0, &base).unwrap().is_empty());
+        assert!(patch.romfs(0).is_err());
+        assert!(patch.patched_romfs(0, &patch).is_err());
+    }
+
+    #[test]
+    fn lz4_entries_bigger_than_their_data_could_compress_to_are_rejected() {
+        let data = vec![0x42; 0x1000];
+        let compressed = lz4::block::compress(&data, None, false).unwrap();
+        let mut section = CompressedSection {
+            section_offset: 0,
+            size: data.len() as u64,
+            entries: Arc::new(vec![CompressionEntry {
+                virtual_offset: 0,
+                physical_offset: 0,
+                compression_type: Some(CompressionType::Lz4),
+                physical_size: compressed.len() as u32,
+            }]),
+            cache: None,
+        };
+        let mut file = io::Cursor::new(compressed);
+        let mut buf = vec![0; data.len()];
+        assert_eq!(
+            section.read(&mut file, 0, &mut buf, None).unwrap(),
+            data.len()
+        );
+        assert_eq!(buf, data);
+
+        // Rejected before the stored data is allocated, so that a crafted
+        // table can't make the reader allocate 4 GiB.
+        section.entries = Arc::new(vec![CompressionEntry {
+            physical_size: u32::MAX,
+            ..section.entries[0]
+        }]);
+        section.cache = None;
+        let err = section.read(&mut file, 0, &mut buf, None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -1,13 +1,26 @@
-use crate::format::utils::HexOrNum;
+use crate::error::{Error, ResultExt};
+use crate::format::parse::{ParseMode, Regions};
+use crate::format::utils::{serialize_hex_bytes, serialize_title_id, HexOrNum};
+#[cfg(feature = "crypto")]
+use crate::pki::RsaPrivateKey;
 use bit_field::BitField;
+use byteorder::{ByteOrder, LittleEndian};
+use serde::de::{Error as _, MapAccess, SeqAccess, Visitor};
+use serde::{Deserializer, Serializer};
 use serde_derive::{Deserialize, Serialize};
+use snafu::Backtrace;
+use snafu::GenerateBacktrace;
 use std::collections::HashMap;
-use std::convert::TryFrom;
+use std::fmt;
+use std::io::{Read, Write};
+use std::path::Path;
 
+/// A kernel capability, encoded the way npdmtool and elf2kip do.
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type", content = "value")]
 #[serde(rename_all = "snake_case")]
 pub enum KernelCapability {
+    /// The thread priorities can be given in either order.
     KernelFlags {
         highest_thread_priority: u8,
         lowest_thread_priority: u8,
@@ -15,14 +28,21 @@ pub enum KernelCapability {
         lowest_cpu_id: u8,
     },
     Syscalls(HashMap<String, HexOrNum>),
+    /// Maps `size` bytes of IO or static memory at `address`. The kernel
+    /// maps static memory when the top bit of the size word is set (the
+    /// `Normal` bit of `MapRangeSize` in Atmosphère's kernel), so it holds
+    /// `!is_io`, as switch-tools' npdmtool writes it. linkle 0.2.10 and
+    /// earlier stored `is_io` as is, giving the opposite mapping.
     Map {
         address: HexOrNum,
         size: HexOrNum,
         is_ro: bool,
         is_io: bool,
     },
+    /// Maps the IO page at this address.
     MapPage(HexOrNum),
-    IrqPair([u16; 2]),
+    /// Two interrupts, null standing for none.
+    IrqPair([Option<u16>; 2]),
     ApplicationType(u16),
     MinKernelVersion(HexOrNum),
     HandleTableSize(u16),
@@ -32,36 +52,48 @@ pub enum KernelCapability {
     },
 }
 
+fn invalid_npdm(error: &'static str) -> Error {
+    Error::InvalidNpdm {
+        error,
+        backtrace: Backtrace::generate(),
+    }
+}
+
 impl KernelCapability {
-    pub fn encode(&self) -> Vec<u32> {
-        match self {
+    pub fn encode(&self) -> Result<Vec<u32>, Error> {
+        let page = |address: u64| (address >> 12) as u32 & 0xFF_FFFF;
+        let caps = match self {
             KernelCapability::KernelFlags {
                 highest_thread_priority,
                 lowest_thread_priority,
                 highest_cpu_id,
                 lowest_cpu_id,
-            } => vec![*0b111u32
-                .set_bits(4..10, u32::from(*lowest_thread_priority))
-                .set_bits(10..16, u32::from(*highest_thread_priority))
-                .set_bits(16..24, u32::from(*lowest_cpu_id))
-                .set_bits(24..32, u32::from(*highest_cpu_id))],
+            } => {
+                // The highest priority is the lowest number.
+                let highest = highest_thread_priority.min(lowest_thread_priority) & 0x3F;
+                let lowest = highest_thread_priority.max(lowest_thread_priority) & 0x3F;
+                vec![*0b111u32
+                    .set_bits(4..10, u32::from(lowest))
+                    .set_bits(10..16, u32::from(highest))
+                    .set_bits(16..24, u32::from(*lowest_cpu_id))
+                    .set_bits(24..32, u32::from(*highest_cpu_id))]
+            }
             KernelCapability::Syscalls(syscalls) => {
-                let mut masks = vec![0b1111u32; 6];
-                let mut used = [false; 6];
-                for (idx, mask) in masks.iter_mut().enumerate() {
-                    mask.set_bits(29..32, idx as u32);
-                }
+                let mut masks = [0u32; 8];
                 for syscall_val in syscalls.values() {
-                    masks[syscall_val.0 as usize / 24]
-                        .set_bit(usize::try_from((syscall_val.0 % 24) + 5).unwrap(), true);
-                    used[syscall_val.0 as usize / 24] = true;
-                }
-                for (idx, used) in used.iter().enumerate().rev() {
-                    if !used {
-                        masks.remove(idx);
+                    if syscall_val.0 >= 0xC0 {
+                        return Err(invalid_npdm("syscall numbers must be below 0xC0"));
                     }
+                    masks[syscall_val.0 as usize / 24].set_bit(syscall_val.0 as usize % 24, true);
                 }
                 masks
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, &mask)| mask != 0)
+                    .map(|(idx, &mask)| {
+                        *0b1111u32.set_bits(5..29, mask).set_bits(29..32, idx as u32)
+                    })
+                    .collect()
             }
             KernelCapability::Map {
                 address,
@@ -69,29 +101,32 @@ impl KernelCapability {
                 is_ro,
                 is_io,
             } => {
+                // The size takes 20 bits, and the top bit is set for static
+                // memory, the kernel mapping IO registers otherwise.
                 let mut val = vec![0b11_1111u32, 0b11_1111u32];
-                val[0]
-                    .set_bits(7..31, u32::try_from(address.0).unwrap())
-                    .set_bit(31, *is_ro);
+                val[0].set_bits(7..31, page(address.0)).set_bit(31, *is_ro);
                 val[1]
-                    .set_bits(7..31, u32::try_from(size.0).unwrap())
-                    .set_bit(31, *is_io);
+                    .set_bits(7..27, page(size.0) & 0xF_FFFF)
+                    .set_bit(31, !*is_io);
                 val
             }
-            KernelCapability::MapPage(page) => {
-                vec![*0b111_1111u32.set_bits(8..32, u32::try_from(page.0).unwrap())]
+            KernelCapability::MapPage(address) => {
+                vec![*0b111_1111u32.set_bits(8..32, page(address.0))]
+            }
+            KernelCapability::IrqPair(irq_pair) => {
+                let irq = |irq: Option<u16>| u32::from(irq.unwrap_or(0x3FF) & 0x3FF);
+                vec![*0b111_1111_1111u32
+                    .set_bits(12..22, irq(irq_pair[0]))
+                    .set_bits(22..32, irq(irq_pair[1]))]
             }
-            KernelCapability::IrqPair(irq_pair) => vec![*0b111_1111_1111u32
-                .set_bits(12..22, u32::from(irq_pair[0]))
-                .set_bits(22..32, u32::from(irq_pair[1]))],
             KernelCapability::ApplicationType(app_type) => {
-                vec![*0b1_1111_1111_1111u32.set_bits(14..17, u32::from(*app_type))]
+                vec![*0b1_1111_1111_1111u32.set_bits(14..17, u32::from(*app_type & 7))]
             }
             KernelCapability::MinKernelVersion(min_kernel) => {
-                vec![*0b11_1111_1111_1111u32.set_bits(15..32, u32::try_from(min_kernel.0).unwrap())]
+                vec![*0b11_1111_1111_1111u32.set_bits(15..32, min_kernel.0 as u32 & 0xFFFF)]
             }
             KernelCapability::HandleTableSize(handle_table_size) => {
-                vec![*0b111_1111_1111_1111u32.set_bits(16..26, u32::from(*handle_table_size))]
+                vec![*0b111_1111_1111_1111u32.set_bits(16..32, u32::from(*handle_table_size))]
             }
             KernelCapability::DebugFlags {
                 allow_debug,
@@ -99,6 +134,980 @@ impl KernelCapability {
             } => vec![*0b1111_1111_1111_1111u32
                 .set_bit(17, *allow_debug)
                 .set_bit(18, *force_debug)],
+        };
+        Ok(caps)
+    }
+}
+
+/// Encodes `caps`, which can't take more than the 0x20 entries of a KIP or
+/// of the kernel capabilities npdmtool builds.
+pub fn encode_capabilities(caps: &[KernelCapability]) -> Result<Vec<u32>, Error> {
+    let mut encoded = Vec::new();
+    for (idx, cap) in caps.iter().enumerate() {
+        encoded.extend(
+            cap.encode()
+                .with_field(format!("kernel_capabilities[{}]", idx))?,
+        );
+    }
+    if encoded.len() > 0x20 {
+        return Err(invalid_npdm(
+            "there can't be more than 0x20 kernel capability entries",
+        ));
+    }
+    Ok(encoded)
+}
+
+/// Deserializes kernel capabilities from a list of `type` and `value`
+/// objects or, as npdmtool also accepts, from an object mapping types to
+/// values, in the order they are written.
+pub(crate) fn deserialize_capabilities<'de, D>(
+    deserializer: D,
+) -> Result<Vec<KernelCapability>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct CapabilitiesVisitor;
+
+    impl<'de> Visitor<'de> for CapabilitiesVisitor {
+        type Value = Vec<KernelCapability>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            write!(
+                formatter,
+                "a list of kernel capabilities or an object mapping their types to their values"
+            )
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut caps = Vec::new();
+            while let Some(cap) = seq.next_element()? {
+                caps.push(cap);
+            }
+            Ok(caps)
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut caps = Vec::new();
+            while let Some((kind, value)) = map.next_entry::<String, serde_json::Value>()? {
+                let cap = serde_json::json!({ "type": kind, "value": value });
+                caps.push(serde_json::from_value(cap).map_err(A::Error::custom)?);
+            }
+            Ok(caps)
+        }
+    }
+
+    deserializer.deserialize_any(CapabilitiesVisitor)
+}
+
+/// A service a program accesses, or hosts.
+#[derive(Debug, Clone)]
+pub struct ServiceAccess {
+    pub name: String,
+    pub is_host: bool,
+}
+
+/// Deserializes `service_access` from a list of names or, as npdmtool also
+/// accepts, from an object mapping names to whether they are hosted.
+fn deserialize_services<'de, D>(deserializer: D) -> Result<Vec<ServiceAccess>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct ServicesVisitor;
+
+    impl<'de> Visitor<'de> for ServicesVisitor {
+        type Value = Vec<ServiceAccess>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            write!(
+                formatter,
+                "a list of service names or an object mapping them to whether they are hosted"
+            )
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut services = Vec::new();
+            while let Some(name) = seq.next_element()? {
+                services.push(ServiceAccess {
+                    name,
+                    is_host: false,
+                });
+            }
+            Ok(services)
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut services = Vec::new();
+            while let Some((name, is_host)) = map.next_entry()? {
+                services.push(ServiceAccess { name, is_host });
+            }
+            Ok(services)
+        }
+    }
+
+    deserializer.deserialize_any(ServicesVisitor)
+}
+
+fn serialize_services<S>(services: &[ServiceAccess], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.collect_map(
+        services
+            .iter()
+            .map(|service| (&service.name, service.is_host)),
+    )
+}
+
+/// Filesystem permissions of a program.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FsAccess {
+    pub permissions: HexOrNum,
+}
+
+/// An NPDM descriptor, in the JSON format of switch-tools' npdmtool, built
+/// into the same bytes it does.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Npdm {
+    pub name: String,
+    #[serde(alias = "program_id")]
+    pub title_id: HexOrNum,
+    /// Range of program IDs the ACID allows. Defaults to `title_id` alone.
+    #[serde(alias = "program_id_range_min")]
+    pub title_id_range_min: Option<HexOrNum>,
+    #[serde(alias = "program_id_range_max")]
+    pub title_id_range_max: Option<HexOrNum>,
+    pub main_thread_stack_size: HexOrNum,
+    pub main_thread_priority: u8,
+    pub default_cpu_id: u8,
+    pub version: Option<u32>,
+    pub system_resource_size: Option<HexOrNum>,
+    pub is_retail: bool,
+    pub pool_partition: u8,
+    pub is_64_bit: bool,
+    pub address_space_type: u8,
+    pub filesystem_access: FsAccess,
+    /// Services the program uses. Hosted ones can be given here too, as an
+    /// object mapping names to whether they are hosted.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_services",
+        serialize_with = "serialize_services"
+    )]
+    pub service_access: Vec<ServiceAccess>,
+    #[serde(default)]
+    pub service_host: Vec<String>,
+    #[serde(deserialize_with = "deserialize_capabilities")]
+    pub kernel_capabilities: Vec<KernelCapability>,
+}
+
+fn align16(offset: usize) -> usize {
+    (offset + 0xF) & !0xF
+}
+
+impl Npdm {
+    pub fn from_file(path: &Path) -> Result<Npdm, Error> {
+        let file = std::fs::File::open(path).map_err(|err| (err, path))?;
+        Npdm::from_reader(file).with_path(path)
+    }
+
+    /// Parses the JSON of an NPDM, in the format of npdmtool.
+    pub fn from_reader<R: Read>(reader: R) -> Result<Npdm, Error> {
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    /// Sets the program ID of the ACI0. It must be in the range the ACID
+    /// allows, when one is given.
+    pub fn set_program_id(&mut self, program_id: u64) -> Result<(), Error> {
+        let min = self.title_id_range_min.map_or(program_id, |v| v.0);
+        let max = self.title_id_range_max.map_or(program_id, |v| v.0);
+        if program_id < min || program_id > max {
+            return Err(invalid_npdm(
+                "the program ID is out of the range allowed by the ACID",
+            ));
+        }
+        self.title_id.0 = program_id;
+        Ok(())
+    }
+
+    /// Encodes the services to the service access control format: a control
+    /// byte holding the length of the name and whether it is hosted,
+    /// followed by the name. Like npdmtool, `service_access` comes first.
+    fn service_access_control(&self) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::new();
+        let services = self
+            .service_access
+            .iter()
+            .map(|service| (&service.name, service.is_host))
+            .chain(self.service_host.iter().map(|name| (name, true)));
+        for (name, is_host) in services {
+            if name.is_empty() || name.len() > 8 {
+                return Err(invalid_npdm("service names must be 1 to 8 characters long"));
+            }
+            data.push((name.len() - 1) as u8 | if is_host { 0x80 } else { 0 });
+            data.extend_from_slice(name.as_bytes());
+        }
+        Ok(data)
+    }
+
+    /// Builds the NPDM file. The ACID is left unsigned, with
+    /// `acid_public_key` as the key checking the second signature of the NCA
+    /// header.
+    pub fn to_bytes(&self, acid_public_key: &[u8; 0x100]) -> Result<Vec<u8>, Error> {
+        if self.main_thread_stack_size.0 >> 32 != 0 {
+            return Err(invalid_npdm(
+                "the main thread stack size must be below 4 GiB",
+            ));
+        }
+        if self.address_space_type > 3 {
+            return Err(invalid_npdm("the address space type must be 0 to 3"));
+        }
+        let sac = self.service_access_control()?;
+        let kac: Vec<u8> = encode_capabilities(&self.kernel_capabilities)?
+            .iter()
+            .flat_map(|v| v.to_le_bytes().to_vec())
+            .collect();
+        let permissions = self.filesystem_access.permissions.0;
+        let title_id = self.title_id.0;
+
+        // The ACID, describing the permissions the program may ask for.
+        let acid_fac_offset = 0x240;
+        let acid_sac_offset = align16(acid_fac_offset + 0x2C);
+        let acid_kac_offset = align16(acid_sac_offset + sac.len());
+        let mut acid = vec![0; acid_kac_offset + kac.len()];
+        acid[0x100..0x200].copy_from_slice(acid_public_key);
+        acid[0x200..0x204].copy_from_slice(b"ACID");
+        let acid_len = acid.len();
+        LittleEndian::write_u32(&mut acid[0x204..], (acid_len - 0x100) as u32);
+        LittleEndian::write_u32(
+            &mut acid[0x20C..],
+            self.is_retail as u32 | ((u32::from(self.pool_partition) & 0x3) << 2),
+        );
+        LittleEndian::write_u64(
+            &mut acid[0x210..],
+            self.title_id_range_min.unwrap_or(self.title_id).0,
+        );
+        LittleEndian::write_u64(
+            &mut acid[0x218..],
+            self.title_id_range_max.unwrap_or(self.title_id).0,
+        );
+        LittleEndian::write_u32(&mut acid[0x220..], acid_fac_offset as u32);
+        LittleEndian::write_u32(&mut acid[0x224..], 0x2C);
+        LittleEndian::write_u32(&mut acid[0x228..], acid_sac_offset as u32);
+        LittleEndian::write_u32(&mut acid[0x22C..], sac.len() as u32);
+        LittleEndian::write_u32(&mut acid[0x230..], acid_kac_offset as u32);
+        LittleEndian::write_u32(&mut acid[0x234..], kac.len() as u32);
+        // FsAccessControl descriptor, without content or save data owners.
+        acid[acid_fac_offset] = 1;
+        LittleEndian::write_u64(&mut acid[acid_fac_offset + 0x4..], permissions);
+        acid[acid_sac_offset..acid_sac_offset + sac.len()].copy_from_slice(&sac);
+        acid[acid_kac_offset..].copy_from_slice(&kac);
+
+        // The ACI0, describing the permissions the program asks for.
+        let aci0_fah_offset = 0x40;
+        let aci0_sac_offset = align16(aci0_fah_offset + 0x1C);
+        let aci0_kac_offset = align16(aci0_sac_offset + sac.len());
+        let mut aci0 = vec![0; aci0_kac_offset + kac.len()];
+        aci0[0x0..0x4].copy_from_slice(b"ACI0");
+        LittleEndian::write_u64(&mut aci0[0x10..], title_id);
+        LittleEndian::write_u32(&mut aci0[0x20..], aci0_fah_offset as u32);
+        LittleEndian::write_u32(&mut aci0[0x24..], 0x1C);
+        LittleEndian::write_u32(&mut aci0[0x28..], aci0_sac_offset as u32);
+        LittleEndian::write_u32(&mut aci0[0x2C..], sac.len() as u32);
+        LittleEndian::write_u32(&mut aci0[0x30..], aci0_kac_offset as u32);
+        LittleEndian::write_u32(&mut aci0[0x34..], kac.len() as u32);
+        // FsAccessHeader, with empty content and save data owner infos.
+        aci0[aci0_fah_offset] = 1;
+        LittleEndian::write_u64(&mut aci0[aci0_fah_offset + 0x4..], permissions);
+        LittleEndian::write_u32(&mut aci0[aci0_fah_offset + 0xC..], 0x1C);
+        LittleEndian::write_u32(&mut aci0[aci0_fah_offset + 0x14..], 0x1C);
+        aci0[aci0_sac_offset..aci0_sac_offset + sac.len()].copy_from_slice(&sac);
+        aci0[aci0_kac_offset..].copy_from_slice(&kac);
+
+        let acid_offset = 0x80;
+        let aci0_offset = align16(acid_offset + acid.len());
+        let mut meta = vec![0; 0x80];
+        meta[0x0..0x4].copy_from_slice(b"META");
+        meta[0xC] = self.is_64_bit as u8 | (self.address_space_type << 1);
+        meta[0xE] = self.main_thread_priority;
+        meta[0xF] = self.default_cpu_id;
+        LittleEndian::write_u32(
+            &mut meta[0x14..],
+            self.system_resource_size.unwrap_or_default().0 as u32,
+        );
+        LittleEndian::write_u32(&mut meta[0x18..], self.version.unwrap_or(0));
+        LittleEndian::write_u32(&mut meta[0x1C..], self.main_thread_stack_size.0 as u32);
+        let name = self.name.as_bytes();
+        let name_len = name.len().min(0xF);
+        meta[0x20..0x20 + name_len].copy_from_slice(&name[..name_len]);
+        LittleEndian::write_u32(&mut meta[0x70..], aci0_offset as u32);
+        LittleEndian::write_u32(&mut meta[0x74..], aci0.len() as u32);
+        LittleEndian::write_u32(&mut meta[0x78..], acid_offset as u32);
+        LittleEndian::write_u32(&mut meta[0x7C..], acid.len() as u32);
+
+        meta.extend_from_slice(&acid);
+        meta.resize(aci0_offset, 0);
+        meta.extend_from_slice(&aci0);
+        Ok(meta)
+    }
+}
+
+/// Builds an NPDM without going through its JSON descriptor. It starts out
+/// like the application template: a 64-bit program in the application pool,
+/// with every filesystem permission and no services or kernel capabilities.
+pub struct NpdmBuilder {
+    npdm: Npdm,
+    acid_public_key: [u8; 0x100],
+}
+
+impl NpdmBuilder {
+    pub fn new(name: &str, program_id: u64) -> NpdmBuilder {
+        NpdmBuilder {
+            npdm: Npdm {
+                name: name.to_string(),
+                title_id: HexOrNum(program_id),
+                title_id_range_min: None,
+                title_id_range_max: None,
+                main_thread_stack_size: HexOrNum(0x10_0000),
+                main_thread_priority: 44,
+                default_cpu_id: 0,
+                version: None,
+                system_resource_size: None,
+                is_retail: true,
+                pool_partition: 0,
+                is_64_bit: true,
+                address_space_type: 3,
+                filesystem_access: FsAccess {
+                    permissions: HexOrNum(0xFFFF_FFFF_FFFF_FFFF),
+                },
+                service_access: Vec::new(),
+                service_host: Vec::new(),
+                kernel_capabilities: Vec::new(),
+            },
+            acid_public_key: [0; 0x100],
         }
     }
+
+    /// Range of program IDs the ACID allows.
+    pub fn program_id_range(&mut self, min: u64, max: u64) -> &mut NpdmBuilder {
+        self.npdm.title_id_range_min = Some(HexOrNum(min));
+        self.npdm.title_id_range_max = Some(HexOrNum(max));
+        self
+    }
+
+    pub fn main_thread(&mut self, stack_size: u32, priority: u8, cpu_id: u8) -> &mut NpdmBuilder {
+        self.npdm.main_thread_stack_size = HexOrNum(stack_size.into());
+        self.npdm.main_thread_priority = priority;
+        self.npdm.default_cpu_id = cpu_id;
+        self
+    }
+
+    pub fn version(&mut self, version: u32) -> &mut NpdmBuilder {
+        self.npdm.version = Some(version);
+        self
+    }
+
+    pub fn system_resource_size(&mut self, size: u32) -> &mut NpdmBuilder {
+        self.npdm.system_resource_size = Some(HexOrNum(size.into()));
+        self
+    }
+
+    pub fn retail(&mut self, is_retail: bool) -> &mut NpdmBuilder {
+        self.npdm.is_retail = is_retail;
+        self
+    }
+
+    /// 0 is the application pool, 1 the applet pool, 2 the system pool and 3
+    /// the non-secure system pool.
+    pub fn pool_partition(&mut self, pool_partition: u8) -> &mut NpdmBuilder {
+        self.npdm.pool_partition = pool_partition;
+        self
+    }
+
+    /// Whether the program is 64-bit, and its address space type, from 0 to 3.
+    pub fn address_space(&mut self, is_64_bit: bool, address_space_type: u8) -> &mut NpdmBuilder {
+        self.npdm.is_64_bit = is_64_bit;
+        self.npdm.address_space_type = address_space_type;
+        self
+    }
+
+    pub fn filesystem_permissions(&mut self, permissions: u64) -> &mut NpdmBuilder {
+        self.npdm.filesystem_access.permissions = HexOrNum(permissions);
+        self
+    }
+
+    /// Allows using the service `name`, or hosting it if `is_host` is set.
+    /// `*` stands for any service.
+    pub fn service(&mut self, name: &str, is_host: bool) -> &mut NpdmBuilder {
+        self.npdm.service_access.push(ServiceAccess {
+            name: name.to_string(),
+            is_host,
+        });
+        self
+    }
+
+    pub fn kernel_flags(
+        &mut self,
+        highest_thread_priority: u8,
+        lowest_thread_priority: u8,
+        highest_cpu_id: u8,
+        lowest_cpu_id: u8,
+    ) -> &mut NpdmBuilder {
+        self.capability(KernelCapability::KernelFlags {
+            highest_thread_priority,
+            lowest_thread_priority,
+            highest_cpu_id,
+            lowest_cpu_id,
+        })
+    }
+
+    /// Allows the syscalls in `syscalls`, by name and number. They are added
+    /// to the ones already allowed.
+    pub fn syscalls<'a, I>(&mut self, syscalls: I) -> &mut NpdmBuilder
+    where
+        I: IntoIterator<Item = (&'a str, u8)>,
+    {
+        let caps = &mut self.npdm.kernel_capabilities;
+        let index = match caps
+            .iter()
+            .position(|cap| matches!(cap, KernelCapability::Syscalls(_)))
+        {
+            Some(index) => index,
+            None => {
+                caps.push(KernelCapability::Syscalls(HashMap::new()));
+                caps.len() - 1
+            }
+        };
+        if let KernelCapability::Syscalls(allowed) = &mut caps[index] {
+            for (name, number) in syscalls {
+                allowed.insert(name.to_string(), HexOrNum(number.into()));
+            }
+        }
+        self
+    }
+
+    /// Maps `size` bytes of IO or static memory at `address`.
+    pub fn map(&mut self, address: u64, size: u64, is_ro: bool, is_io: bool) -> &mut NpdmBuilder {
+        self.capability(KernelCapability::Map {
+            address: HexOrNum(address),
+            size: HexOrNum(size),
+            is_ro,
+            is_io,
+        })
+    }
+
+    pub fn map_page(&mut self, address: u64) -> &mut NpdmBuilder {
+        self.capability(KernelCapability::MapPage(HexOrNum(address)))
+    }
+
+    pub fn irq_pair(&mut self, first: Option<u16>, second: Option<u16>) -> &mut NpdmBuilder {
+        self.capability(KernelCapability::IrqPair([first, second]))
+    }
+
+    /// 0 for a sysmodule, 1 for an application and 2 for an applet.
+    pub fn application_type(&mut self, application_type: u16) -> &mut NpdmBuilder {
+        self.capability(KernelCapability::ApplicationType(application_type))
+    }
+
+    pub fn min_kernel_version(&mut self, version: u16) -> &mut NpdmBuilder {
+        self.capability(KernelCapability::MinKernelVersion(HexOrNum(version.into())))
+    }
+
+    pub fn handle_table_size(&mut self, size: u16) -> &mut NpdmBuilder {
+        self.capability(KernelCapability::HandleTableSize(size))
+    }
+
+    pub fn debug_flags(&mut self, allow_debug: bool, force_debug: bool) -> &mut NpdmBuilder {
+        self.capability(KernelCapability::DebugFlags {
+            allow_debug,
+            force_debug,
+        })
+    }
+
+    fn capability(&mut self, capability: KernelCapability) -> &mut NpdmBuilder {
+        self.npdm.kernel_capabilities.push(capability);
+        self
+    }
+
+    /// Key checking the second signature of the NCA header. Zeroed by default.
+    pub fn acid_public_key(&mut self, acid_public_key: [u8; 0x100]) -> &mut NpdmBuilder {
+        self.acid_public_key = acid_public_key;
+        self
+    }
+
+    /// The descriptor built so far, e.g. to save it as JSON.
+    pub fn npdm(&self) -> &Npdm {
+        &self.npdm
+    }
+
+    /// Writes the NPDM, with its ACID unsigned.
+    pub fn build<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        writer.write_all(&self.npdm.to_bytes(&self.acid_public_key)?)?;
+        Ok(())
+    }
+}
+
+/// Signs the ACID of the NPDM file `data` with `key`, which must be the
+/// private key of the ACID fixed key the loader checks it with.
+#[cfg(feature = "crypto")]
+pub fn sign_acid(data: &mut [u8], key: &RsaPrivateKey) -> Result<(), Error> {
+    let invalid = |error| Error::InvalidNpdm {
+        error,
+        backtrace: Backtrace::generate(),
+    };
+    if data.len() < 0x80 || &data[..4] != b"META" {
+        return Err(invalid("magic is wrong"));
+    }
+    let acid_offset = LittleEndian::read_u32(&data[0x78..]) as usize;
+    let acid_size = LittleEndian::read_u32(&data[0x7C..]) as usize;
+    let acid = data
+        .get_mut(acid_offset..acid_offset + acid_size)
+        .filter(|acid| acid.len() >= 0x208 && &acid[0x200..0x204] == b"ACID")
+        .ok_or_else(|| invalid("ACID is out of bounds"))?;
+    // The signature covers the public key and everything after it.
+    let signed_size = LittleEndian::read_u32(&acid[0x204..]) as usize;
+    let signed = acid
+        .get(0x100..0x100 + signed_size)
+        .ok_or_else(|| invalid("ACID size is out of bounds"))?;
+    let signature = key.sign_pss(signed);
+    acid[..0x100].copy_from_slice(&signature);
+    Ok(())
+}
+
+/// The parts of an NPDM file needed to build the NCA holding it, along
+/// with its name.
+#[derive(Serialize)]
+pub struct NpdmInfo {
+    /// Name of the program, e.g. `Application`.
+    pub name: String,
+    /// Program ID of the ACI0.
+    #[serde(serialize_with = "serialize_title_id")]
+    pub program_id: u64,
+    /// Public key of the ACID, checking the second signature of the NCA
+    /// header.
+    #[serde(serialize_with = "serialize_hex_bytes")]
+    pub acid_public_key: [u8; 0x100],
+}
+
+impl NpdmInfo {
+    pub fn parse(data: &[u8]) -> Result<NpdmInfo, Error> {
+        NpdmInfo::parse_with_mode(data, ParseMode::Lenient)
+    }
+
+    /// Same as `parse`, checking the file as told by `mode`. Strict mode
+    /// checks it like `NpdmHeaders::parse_with_mode` does.
+    pub fn parse_with_mode(data: &[u8], mode: ParseMode) -> Result<NpdmInfo, Error> {
+        let invalid = |error| Error::InvalidNpdm {
+            error,
+            backtrace: Backtrace::generate(),
+        };
+        if data.len() < 0x80 || &data[..4] != b"META" {
+            return Err(invalid("magic is wrong"));
+        }
+        if mode.is_strict() {
+            NpdmHeaders::parse_with_mode(data, mode)?;
+        }
+        let aci0_offset = LittleEndian::read_u32(&data[0x70..]) as usize;
+        let acid_offset = LittleEndian::read_u32(&data[0x78..]) as usize;
+        let aci0 = data
+            .get(aci0_offset..)
+            .and_then(|aci0| aci0.get(..0x18))
+            .ok_or_else(|| invalid("ACI0 is out of bounds"))?;
+        if &aci0[..4] != b"ACI0" {
+            return Err(invalid("ACI0 magic is wrong"));
+        }
+        let acid = data
+            .get(acid_offset..)
+            .and_then(|acid| acid.get(..0x204))
+            .ok_or_else(|| invalid("ACID is out of bounds"))?;
+        if &acid[0x200..] != b"ACID" {
+            return Err(invalid("ACID magic is wrong"));
+        }
+
+        let mut acid_public_key = [0; 0x100];
+        acid_public_key.copy_from_slice(&acid[0x100..0x200]);
+        let name = &data[0x20..0x30];
+        let name_len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+        Ok(NpdmInfo {
+            name: String::from_utf8_lossy(&name[..name_len]).into_owned(),
+            program_id: LittleEndian::read_u64(&aci0[0x10..]),
+            acid_public_key,
+        })
+    }
+}
+
+/// The offset and size of a part of an NPDM, relative to the start of the
+/// structure holding them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct NpdmRange {
+    pub offset: u32,
+    pub size: u32,
+}
+
+impl NpdmRange {
+    fn read(data: &[u8]) -> NpdmRange {
+        NpdmRange {
+            offset: LittleEndian::read_u32(data),
+            size: LittleEndian::read_u32(&data[4..]),
+        }
+    }
+}
+
+/// The META header at the start of an NPDM, as stored in it.
+#[derive(Debug, Clone, Serialize)]
+pub struct NpdmMeta {
+    pub signature_key_generation: u32,
+    /// Bit 0 tells whether the program is 64-bit, bits 1-3 its address
+    /// space type.
+    pub flags: u8,
+    pub main_thread_priority: u8,
+    pub main_thread_core_number: u8,
+    pub system_resource_size: u32,
+    pub version: u32,
+    pub main_thread_stack_size: u32,
+    pub name: String,
+    pub product_code: String,
+    pub aci0: NpdmRange,
+    pub acid: NpdmRange,
+}
+
+/// The ACID of an NPDM, describing the permissions the program may ask for.
+#[derive(Debug, Clone, Serialize)]
+pub struct NpdmAcid {
+    #[serde(serialize_with = "serialize_hex_bytes")]
+    pub signature: [u8; 0x100],
+    #[serde(serialize_with = "serialize_hex_bytes")]
+    pub public_key: [u8; 0x100],
+    /// Size of the ACID from the public key on, which the signature covers.
+    pub signed_size: u32,
+    pub version: u8,
+    /// Bit 0 tells whether the program is for retail consoles, bits 2-5
+    /// its pool partition.
+    pub flags: u32,
+    #[serde(serialize_with = "serialize_title_id")]
+    pub program_id_min: u64,
+    #[serde(serialize_with = "serialize_title_id")]
+    pub program_id_max: u64,
+    pub fs_access_control: NpdmRange,
+    pub service_access_control: NpdmRange,
+    pub kernel_access_control: NpdmRange,
+}
+
+/// The ACI0 of an NPDM, describing the permissions the program asks for.
+#[derive(Debug, Clone, Serialize)]
+pub struct NpdmAci0 {
+    #[serde(serialize_with = "serialize_title_id")]
+    pub program_id: u64,
+    pub fs_access_header: NpdmRange,
+    pub service_access_control: NpdmRange,
+    pub kernel_access_control: NpdmRange,
+}
+
+/// The headers of the three parts of an NPDM file, as stored in it. Unlike
+/// `Npdm`, nothing gets decoded, which makes them suited to debugging files
+/// other tools made.
+#[derive(Debug, Clone, Serialize)]
+pub struct NpdmHeaders {
+    pub meta: NpdmMeta,
+    pub acid: NpdmAcid,
+    pub aci0: NpdmAci0,
+}
+
+/// Checks the parts of the ACID or ACI0 of an NPDM in strict mode: they must
+/// be in it after its header, without overlapping each other.
+fn check_parts(
+    name: &str,
+    range: NpdmRange,
+    header_size: u32,
+    parts: &[(&str, NpdmRange)],
+) -> Result<(), Error> {
+    let mut regions = Regions::within(format!("the {}", name), range.size.into());
+    regions.add(format!("{} header", name), 0, header_size.into())?;
+    for (part, part_range) in parts {
+        regions.add(
+            format!("{} of the {}", part, name),
+            part_range.offset.into(),
+            part_range.size.into(),
+        )?;
+    }
+    regions.check_overlaps()
+}
+
+impl NpdmHeaders {
+    pub fn parse(data: &[u8]) -> Result<NpdmHeaders, Error> {
+        NpdmHeaders::parse_with_mode(data, ParseMode::Lenient)
+    }
+
+    /// Same as `parse`, checking the file as told by `mode`. Strict mode
+    /// checks the ACI0 and ACID against their size in the META header, and
+    /// the parts of each against their size, none overlapping another.
+    pub fn parse_with_mode(data: &[u8], mode: ParseMode) -> Result<NpdmHeaders, Error> {
+        if data.len() < 0x80 || &data[..4] != b"META" {
+            return Err(invalid_npdm("magic is wrong"));
+        }
+        let string = |bytes: &[u8]| {
+            let len = bytes.iter().position(|&c| c == 0).unwrap_or(bytes.len());
+            String::from_utf8_lossy(&bytes[..len]).into_owned()
+        };
+        let meta = NpdmMeta {
+            signature_key_generation: LittleEndian::read_u32(&data[0x4..]),
+            flags: data[0xC],
+            main_thread_priority: data[0xE],
+            main_thread_core_number: data[0xF],
+            system_resource_size: LittleEndian::read_u32(&data[0x14..]),
+            version: LittleEndian::read_u32(&data[0x18..]),
+            main_thread_stack_size: LittleEndian::read_u32(&data[0x1C..]),
+            name: string(&data[0x20..0x30]),
+            product_code: string(&data[0x30..0x40]),
+            aci0: NpdmRange::read(&data[0x70..]),
+            acid: NpdmRange::read(&data[0x78..]),
+        };
+
+        let acid = data
+            .get(meta.acid.offset as usize..)
+            .and_then(|acid| acid.get(..0x240))
+            .ok_or_else(|| invalid_npdm("ACID is out of bounds"))
+            .with_offset(0x78)?;
+        if &acid[0x200..0x204] != b"ACID" {
+            return Err(invalid_npdm("ACID magic is wrong")).with_offset(meta.acid.offset.into());
+        }
+        let mut signature = [0; 0x100];
+        signature.copy_from_slice(&acid[..0x100]);
+        let mut public_key = [0; 0x100];
+        public_key.copy_from_slice(&acid[0x100..0x200]);
+        let acid = NpdmAcid {
+            signature,
+            public_key,
+            signed_size: LittleEndian::read_u32(&acid[0x204..]),
+            version: acid[0x208],
+            flags: LittleEndian::read_u32(&acid[0x20C..]),
+            program_id_min: LittleEndian::read_u64(&acid[0x210..]),
+            program_id_max: LittleEndian::read_u64(&acid[0x218..]),
+            fs_access_control: NpdmRange::read(&acid[0x220..]),
+            service_access_control: NpdmRange::read(&acid[0x228..]),
+            kernel_access_control: NpdmRange::read(&acid[0x230..]),
+        };
+
+        let aci0 = data
+            .get(meta.aci0.offset as usize..)
+            .and_then(|aci0| aci0.get(..0x40))
+            .ok_or_else(|| invalid_npdm("ACI0 is out of bounds"))
+            .with_offset(0x70)?;
+        if &aci0[..4] != b"ACI0" {
+            return Err(invalid_npdm("ACI0 magic is wrong")).with_offset(meta.aci0.offset.into());
+        }
+        let aci0 = NpdmAci0 {
+            program_id: LittleEndian::read_u64(&aci0[0x10..]),
+            fs_access_header: NpdmRange::read(&aci0[0x20..]),
+            service_access_control: NpdmRange::read(&aci0[0x28..]),
+            kernel_access_control: NpdmRange::read(&aci0[0x30..]),
+        };
+
+        if mode.is_strict() {
+            let mut regions = Regions::new(data.len() as u64);
+            regions.add("META header", 0, 0x80)?;
+            regions.add("ACI0", meta.aci0.offset.into(), meta.aci0.size.into())?;
+            regions.add("ACID", meta.acid.offset.into(), meta.acid.size.into())?;
+            regions.check_overlaps()?;
+            // The signature covers the ACID from its public key on.
+            Regions::within("the ACID", meta.acid.size.into()).add(
+                "signed part of the ACID",
+                0x100,
+                acid.signed_size.into(),
+            )?;
+            check_parts(
+                "ACID",
+                meta.acid,
+                0x240,
+                &[
+                    ("FS access control", acid.fs_access_control),
+                    ("service access control", acid.service_access_control),
+                    ("kernel access control", acid.kernel_access_control),
+                ],
+            )?;
+            check_parts(
+                "ACI0",
+                meta.aci0,
+                0x40,
+                &[
+                    ("FS access header", aci0.fs_access_header),
+                    ("service access control", aci0.service_access_control),
+                    ("kernel access control", aci0.kernel_access_control),
+                ],
+            )?;
+        }
+        Ok(NpdmHeaders { meta, acid, aci0 })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const NPDM_JSON: &str = r#"{
+        "name": "Test",
+        "program_id": "0x0100000000ABC000",
+        "main_thread_stack_size": "0x100000",
+        "main_thread_priority": 44,
+        "default_cpu_id": 0,
+        "is_retail": true,
+        "pool_partition": 0,
+        "is_64_bit": true,
+        "address_space_type": 3,
+        "filesystem_access": { "permissions": "0xFFFFFFFFFFFFFFFF" },
+        "service_access": { "fsp-srv": false, "sm:": true },
+        "service_host": ["*"],
+        "kernel_capabilities": {
+            "kernel_flags": {
+                "highest_thread_priority": 59,
+                "lowest_thread_priority": 28,
+                "highest_cpu_id": 3,
+                "lowest_cpu_id": 0
+            },
+            "syscalls": { "svcSetHeapSize": "0x01", "svcExitProcess": "0x07" },
+            "min_kernel_version": "0x0030",
+            "handle_table_size": 1023,
+            "debug_flags": { "allow_debug": true, "force_debug": false }
+        }
+    }"#;
+
+    fn encode(cap: &str) -> Result<Vec<u32>, Error> {
+        serde_json::from_str::<KernelCapability>(cap)
+            .unwrap()
+            .encode()
+    }
+
+    #[test]
+    fn kernel_capabilities_encode_like_npdmtool() {
+        let npdm: Npdm = serde_json::from_str(NPDM_JSON).unwrap();
+        assert_eq!(
+            encode_capabilities(&npdm.kernel_capabilities).unwrap(),
+            [0x0300_73B7, 0x104F, 0x18_3FFF, 0x03FF_7FFF, 0x2_FFFF]
+        );
+        assert_eq!(
+            encode(r#"{"type": "syscalls", "value": {"svcCallSecureMonitor": "0xBF"}}"#).unwrap(),
+            [0xF000_000F]
+        );
+        assert!(encode(r#"{"type": "syscalls", "value": {"svcBad": "0xC0"}}"#).is_err());
+        // Page 0x12345 and 1 page from bit 7, after the 0b11_1111 type
+        // bits, bit 31 of the address being is_ro and the one of the size
+        // !is_io.
+        assert_eq!(
+            encode(
+                r#"{"type": "map", "value": {
+                    "address": "0x12345000", "size": "0x1000", "is_ro": true, "is_io": false
+                }}"#
+            )
+            .unwrap(),
+            [0x8091_A2BF, 0x8000_00BF]
+        );
+        assert_eq!(
+            encode(
+                r#"{"type": "map", "value": {
+                    "address": "0x12345000", "size": "0x1000", "is_ro": false, "is_io": true
+                }}"#
+            )
+            .unwrap(),
+            [0x0091_A2BF, 0x0000_00BF]
+        );
+        assert_eq!(
+            encode(r#"{"type": "map_page", "value": "0x12345000"}"#).unwrap(),
+            [0x0123_457F]
+        );
+        assert_eq!(
+            encode(r#"{"type": "irq_pair", "value": [5, null]}"#).unwrap(),
+            [0xFFC0_57FF]
+        );
+        assert_eq!(
+            encode(r#"{"type": "application_type", "value": 1}"#).unwrap(),
+            [0x5FFF]
+        );
+    }
+
+    #[test]
+    fn npdm_layout_matches_npdmtool() {
+        let npdm: Npdm = serde_json::from_str(NPDM_JSON).unwrap();
+        let data = npdm.to_bytes(&[0; 0x100]).unwrap();
+        let u32_at = |offset: usize| LittleEndian::read_u32(&data[offset..]);
+        assert_eq!(data.len(), 0x3A4);
+        assert_eq!(data[0xC], 0x7);
+        assert_eq!(&data[0x20..0x24], b"Test");
+        // ACI0 and ACID offsets and sizes.
+        assert_eq!(
+            [u32_at(0x70), u32_at(0x74), u32_at(0x78), u32_at(0x7C)],
+            [0x320, 0x84, 0x80, 0x294]
+        );
+        // Service access comes before service host.
+        let sac = b"\x06fsp-srv\x82sm:\x80*";
+        assert_eq!(&data[0x80 + 0x270..0x80 + 0x270 + sac.len()], sac);
+        assert_eq!(&data[0x320 + 0x60..0x320 + 0x60 + sac.len()], sac);
+        assert_eq!(u32_at(0x80 + 0x280), 0x0300_73B7);
+        assert_eq!(u32_at(0x320 + 0x70), 0x0300_73B7);
+    }
+
+    #[test]
+    fn headers_serialize_like_they_were_built() {
+        let npdm: Npdm = serde_json::from_str(NPDM_JSON).unwrap();
+        let data = npdm.to_bytes(&[0; 0x100]).unwrap();
+        let headers = serde_json::to_value(NpdmHeaders::parse(&data).unwrap()).unwrap();
+        assert_eq!(headers["meta"]["name"], "Test");
+        assert_eq!(headers["meta"]["flags"], 7);
+        assert_eq!(
+            headers["meta"]["acid"],
+            serde_json::json!({ "offset": 0x80, "size": 0x294 })
+        );
+        assert_eq!(headers["acid"]["signed_size"], 0x194);
+        assert_eq!(headers["acid"]["program_id_max"], "0100000000abc000");
+        assert_eq!(headers["aci0"]["program_id"], "0100000000abc000");
+        assert_eq!(headers["aci0"]["kernel_access_control"]["size"], 0x14);
+    }
+
+    #[test]
+    fn builder_matches_json() {
+        let mut builder = NpdmBuilder::new("Test", 0x0100_0000_00AB_C000);
+        builder
+            .service("fsp-srv", false)
+            .service("sm:", true)
+            .service("*", true)
+            .kernel_flags(59, 28, 3, 0)
+            .syscalls(vec![("svcSetHeapSize", 0x01)])
+            .syscalls(vec![("svcExitProcess", 0x07)])
+            .min_kernel_version(0x30)
+            .handle_table_size(1023)
+            .debug_flags(true, false);
+        let mut data = Vec::new();
+        builder.build(&mut data).unwrap();
+
+        let npdm: Npdm = serde_json::from_str(NPDM_JSON).unwrap();
+        assert_eq!(data, npdm.to_bytes(&[0; 0x100]).unwrap());
+    }
+
+    #[test]
+    fn too_many_capabilities_are_refused() {
+        let caps: Vec<KernelCapability> = (0..0x21)
+            .map(|_| KernelCapability::HandleTableSize(0))
+            .collect();
+        assert!(encode_capabilities(&caps).is_err());
+        assert!(encode_capabilities(&caps[..0x20]).is_ok());
+    }
+
+    #[test]
+    fn strict_mode_checks_the_parts_of_the_npdm() {
+        let npdm: Npdm = serde_json::from_str(NPDM_JSON).unwrap();
+        let data = npdm.to_bytes(&[0; 0x100]).unwrap();
+        NpdmHeaders::parse_with_mode(&data, ParseMode::Strict).unwrap();
+        NpdmInfo::parse_with_mode(&data, ParseMode::Strict).unwrap();
+
+        // The ACID running into the ACI0.
+        let mut overlapping = data.clone();
+        LittleEndian::write_u32(&mut overlapping[0x7C..], 0x2B0);
+        NpdmHeaders::parse(&overlapping).unwrap();
+        assert!(NpdmHeaders::parse_with_mode(&overlapping, ParseMode::Strict).is_err());
+        assert!(NpdmInfo::parse_with_mode(&overlapping, ParseMode::Strict).is_err());
+
+        // The kernel access control of the ACI0 going past its end.
+        let mut out_of_bounds = data;
+        LittleEndian::write_u32(&mut out_of_bounds[0x320 + 0x34..], 0x1000);
+        NpdmHeaders::parse(&out_of_bounds).unwrap();
+        assert!(NpdmHeaders::parse_with_mode(&out_of_bounds, ParseMode::Strict).is_err());
+    }
 }
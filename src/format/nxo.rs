@@ -1,18 +1,24 @@
-use crate::format::utils::HexOrNum;
-use crate::format::{nacp::NacpFile, npdm::KernelCapability, romfs::RomFs, utils};
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crate::error::{Error, ResultExt};
+use crate::format::compression::{self, Blz, Compressor};
+use crate::format::npdm::{encode_capabilities, KernelCapability};
+use crate::format::parse::{ParseMode, Regions};
+use crate::format::utils::{serialize_hex_bytes, HexOrNum};
+use crate::format::{nacp::NacpFile, romfs::RomFs, utils};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
 use elf::types::{Machine, ProgramHeader, SectionHeader, EM_AARCH64, EM_ARM, PT_LOAD, SHT_NOTE};
 use serde_derive::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::convert::{TryFrom, TryInto};
+use snafu::Backtrace;
+use snafu::GenerateBacktrace;
+use std::convert::TryFrom;
 use std::fs::File;
-use std::io::{self, Cursor, Seek, SeekFrom, Write};
-use std::path::{Path, PathBuf};
-use std::process;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
 
 // TODO: Support switchbrew's embedded files for NRO
-pub struct NxoFile {
-    file: File,
+/// An ELF being converted to an NRO, NSO or KIP1. The segments are read from
+/// `R` when writing them.
+pub struct NxoFile<R = File> {
+    file: R,
     machine: Machine,
     text_segment: ProgramHeader,
     rodata_segment: ProgramHeader,
@@ -34,6 +40,7 @@ pub struct KipNpdm {
     default_cpu_id: u8,
     process_category: u8,
     flags: Option<u8>,
+    #[serde(deserialize_with = "crate::format::npdm::deserialize_capabilities")]
     kernel_capabilities: Vec<KernelCapability>,
 }
 
@@ -61,8 +68,8 @@ where
         Some(build_id) => {
             let mut build_id_data = build_id.clone();
             if build_id_data.len() > 0x30 {
-                println!(
-                    "Warning: build-id is too big (0x{:x} > 0x30), the content will be shrink.",
+                log::warn!(
+                    "build-id is too big (0x{:x} > 0x30), the content will be shrink.",
                     build_id_data.len()
                 );
             }
@@ -82,8 +89,28 @@ where
     Ok(())
 }
 
-fn write_mod0<T>(
-    nxo_file: &NxoFile,
+/// Compresses the three segments of an NSO or KIP with `compressor`,
+/// returning the data to write and the compression flags of the header.
+fn compress_segments(
+    compressor: &dyn Compressor,
+    segments: [&[u8]; 3],
+) -> io::Result<([Vec<u8>; 3], u8)> {
+    let mut flags = 0;
+    let mut compress = |index: usize| -> io::Result<Vec<u8>> {
+        match compressor.compress(segments[index])? {
+            Some(compressed) => {
+                flags |= 1 << index;
+                Ok(compressed)
+            }
+            None => Ok(segments[index].to_vec()),
+        }
+    };
+    let compressed = [compress(0)?, compress(1)?, compress(2)?];
+    Ok((compressed, flags))
+}
+
+fn write_mod0<R, T>(
+    nxo_file: &NxoFile<R>,
     offset: u32,
     output_writter: &mut T,
     bss_addr: u32,
@@ -126,33 +153,35 @@ where
 }
 
 impl NxoFile {
-    pub fn from_elf(input: &str) -> std::io::Result<Self> {
-        let path = PathBuf::from(input);
-        let mut file = File::open(path)?;
+    pub fn from_elf(input: &str) -> io::Result<Self> {
+        NxoFile::from_reader(File::open(input)?)
+    }
+}
 
-        let elf_file = elf::File::open_stream(&mut file).unwrap();
+impl<R: Read + Seek> NxoFile<R> {
+    /// Parses the ELF in `file`, which must be an ARM or AArch64 one with the
+    /// .text, .rodata and .data segments first.
+    pub fn from_reader(mut file: R) -> io::Result<Self> {
+        let invalid_elf = |error: String| io::Error::new(io::ErrorKind::InvalidData, error);
+        let elf_file = elf::File::open_stream(&mut file)
+            .map_err(|err| invalid_elf(format!("invalid ELF file: {:?}", err)))?;
 
         if elf_file.ehdr.machine != EM_AARCH64 && elf_file.ehdr.machine != EM_ARM {
-            println!("Error: Invalid ELF file (expected ARM or AArch64 machine)");
-            process::exit(1)
+            return Err(invalid_elf(
+                "invalid ELF file (expected ARM or AArch64 machine)".to_string(),
+            ));
         }
 
         let sections = &elf_file.sections;
         let phdrs: Vec<ProgramHeader> = elf_file.phdrs.to_vec();
-        let text_segment = phdrs.get(0).unwrap_or_else(|| {
-            println!("Error: .text not found in ELF file");
-            process::exit(1)
-        });
-
-        let rodata_segment = phdrs.get(1).unwrap_or_else(|| {
-            println!("Error: .rodata not found in ELF file");
-            process::exit(1)
-        });
-
-        let data_segment = phdrs.get(2).unwrap_or_else(|| {
-            println!("Error: .data not found in ELF file");
-            process::exit(1)
-        });
+        let segment = |index: usize, name: &str| {
+            phdrs
+                .get(index)
+                .ok_or_else(|| invalid_elf(format!("{} not found in ELF file", name)))
+        };
+        let text_segment = segment(0, ".text")?;
+        let rodata_segment = segment(1, ".rodata")?;
+        let data_segment = segment(2, ".data")?;
 
         let bss_segment = match phdrs.get(3) {
             Some(s) => {
@@ -175,8 +204,8 @@ impl NxoFile {
             if section.shdr.shtype == SHT_NOTE {
                 let mut data = Cursor::new(section.data.clone());
                 // Ignore the two first offset of nhdr32
-                data.seek(SeekFrom::Start(0x8)).unwrap();
-                let n_type = data.read_u32::<LittleEndian>().unwrap();
+                data.seek(SeekFrom::Start(0x8))?;
+                let n_type = data.read_u32::<LittleEndian>()?;
 
                 // BUILD_ID
                 if n_type == 0x3 {
@@ -211,9 +240,9 @@ impl NxoFile {
         &mut self,
         output_writter: &mut T,
         romfs: Option<RomFs>,
-        icon: Option<&str>,
+        icon: Option<&[u8]>,
         nacp: Option<NacpFile>,
-    ) -> std::io::Result<()>
+    ) -> Result<(), Error>
     where
         T: Write,
     {
@@ -239,6 +268,12 @@ impl NxoFile {
             pad_segment(&mut data, code.len() + rodata.len(), &segment);
         }
 
+        if code.len() < 0x80 {
+            return Err(
+                invalid_nxo("the .text segment can't hold the NRO header").with_field(".text")
+            );
+        }
+
         let total_len: u32 = (code.len() + rodata.len() + data.len()) as u32;
 
         // Write the first branching and mod0 offset
@@ -280,9 +315,11 @@ impl NxoFile {
         let (bss_start, bss_size) = match self.bss_segment {
             Some(segment) => {
                 if segment.vaddr != u64::from(file_offset) {
-                    println!(
-                    "Warning: possible misalign bss\n.bss addr: 0x{:x}\nexpected offset: 0x{:x}",
-                    segment.vaddr, file_offset);
+                    log::warn!(
+                        "possible misalign bss\n.bss addr: 0x{:x}\nexpected offset: 0x{:x}",
+                        segment.vaddr,
+                        file_offset
+                    );
                 }
                 output_writter
                     .write_u32::<LittleEndian>(((segment.memsz + 0xFFF) & !0xFFF) as u32)?;
@@ -324,37 +361,34 @@ impl NxoFile {
         output_writter.write_u32::<LittleEndian>(
             self.dynstr_section
                 .as_ref()
-                .map(|v| u32::try_from(v.addr).unwrap())
-                .unwrap_or(0),
+                .map_or(Ok(0), |v| to_u32(v.addr, ".dynstr address"))?,
         )?;
         output_writter.write_u32::<LittleEndian>(
             self.dynstr_section
                 .as_ref()
-                .map(|v| u32::try_from(v.size).unwrap())
-                .unwrap_or(0),
+                .map_or(Ok(0), |v| to_u32(v.size, ".dynstr size"))?,
         )?;
 
         // .dynsym section info
         output_writter.write_u32::<LittleEndian>(
             self.dynsym_section
                 .as_ref()
-                .map(|v| u32::try_from(v.addr).unwrap())
-                .unwrap_or(0),
+                .map_or(Ok(0), |v| to_u32(v.addr, ".dynsym address"))?,
         )?;
         output_writter.write_u32::<LittleEndian>(
             self.dynsym_section
                 .as_ref()
-                .map(|v| u32::try_from(v.size).unwrap())
-                .unwrap_or(0),
+                .map_or(Ok(0), |v| to_u32(v.size, ".dynsym size"))?,
         )?;
 
-        let module_offset = u32::from_le_bytes(code[4..8].try_into().unwrap()) as usize;
+        let module_offset = LittleEndian::read_u32(&code[4..8]) as usize;
         if module_offset != 0
-            && !((0x80..code_size).contains(&(module_offset as u32))
+            && !((0x80..code_size - 0x1C).contains(&(module_offset as u32))
                 || (rodata_offset..data_offset).contains(&(module_offset as u32))
                 || (data_offset..file_offset).contains(&(module_offset as u32)))
         {
-            panic!("Invalid module offset {}", module_offset)
+            return Err(invalid_nxo("the module offset is out of the segments")
+                .with_offset(module_offset as u64));
         }
 
         if (0x80..code_size).contains(&(module_offset as u32))
@@ -423,8 +457,7 @@ impl NxoFile {
         let mut offset = 8 + 16 + 16 + 16;
 
         let icon_len = if let Some(icon) = &icon {
-            // TODO: Check if icon is a 256x256 JPEG. Convert it if it isn't?
-            let icon_len = Path::new(icon).metadata()?.len();
+            let icon_len = icon.len() as u64;
             output_writter.write_u64::<LittleEndian>(offset)?;
             output_writter.write_u64::<LittleEndian>(icon_len)?;
             icon_len
@@ -458,11 +491,7 @@ impl NxoFile {
         };
 
         if let Some(icon) = icon {
-            assert_eq!(
-                io::copy(&mut File::open(icon)?, output_writter)?,
-                icon_len,
-                "Icon changed while building."
-            );
+            output_writter.write_all(icon)?;
         }
 
         if let Some(mut nacp) = nacp {
@@ -475,7 +504,19 @@ impl NxoFile {
         Ok(())
     }
 
-    pub fn write_nso<T>(&mut self, output_writter: &mut T) -> std::io::Result<()>
+    pub fn write_nso<T>(&mut self, output_writter: &mut T) -> Result<(), Error>
+    where
+        T: Write,
+    {
+        self.write_nso_with(output_writter, compression::default_nso_compressor())
+    }
+
+    /// Same as `write_nso`, compressing the segments with `compressor`.
+    pub fn write_nso_with<T>(
+        &mut self,
+        output_writter: &mut T,
+        compressor: &dyn Compressor,
+    ) -> Result<(), Error>
     where
         T: Write,
     {
@@ -497,136 +538,58 @@ impl NxoFile {
             pad_segment(&mut data, data_segment.vaddr as usize, &segment);
         }
 
-        // NSO magic
-        output_writter.write_all(b"NSO0")?;
-        // Version
-        output_writter.write_u32::<LittleEndian>(0)?;
-        // Reserved
-        output_writter.write_u32::<LittleEndian>(0)?;
-
-        // Flags, set compression + sum check
-        output_writter.write_u32::<LittleEndian>(0x3F)?;
-
-        // Segment Header (3 entries)
-        let mut file_offset = 0x100;
-
-        // .text segment
-        let code_size = code.len() as u32;
-        let compressed_code = utils::compress_lz4(&mut code)?;
-        let compressed_code_size = compressed_code.len() as u32;
-        output_writter.write_u32::<LittleEndian>(file_offset as u32)?;
-        output_writter.write_u32::<LittleEndian>(text_segment.vaddr as u32)?;
-        output_writter.write_u32::<LittleEndian>(code_size as u32)?;
-
-        // TODO: Module Name Offset
-        output_writter.write_u32::<LittleEndian>(0)?;
-
-        file_offset += compressed_code_size;
-
-        // .rodata segment
-        let rodata_size = rodata.len() as u32;
-        let compressed_rodata = utils::compress_lz4(&mut rodata)?;
-        let compressed_rodata_size = compressed_rodata.len() as u32;
-        output_writter.write_u32::<LittleEndian>(file_offset as u32)?;
-        output_writter.write_u32::<LittleEndian>(rodata_segment.vaddr as u32)?;
-        output_writter.write_u32::<LittleEndian>(rodata_size as u32)?;
-
-        // TODO: Module Name Size
-        output_writter.write_u32::<LittleEndian>(0)?;
-
-        file_offset += compressed_rodata_size;
-
-        // .data segment
-        let data_size = data.len() as u32;
-        let compressed_data = utils::compress_lz4(&mut data)?;
-        let compressed_data_size = compressed_data.len() as u32;
-        let uncompressed_data_size = data.len() as u64;
-        output_writter.write_u32::<LittleEndian>(file_offset as u32)?;
-        output_writter.write_u32::<LittleEndian>(data_segment.vaddr as u32)?;
-        output_writter.write_u32::<LittleEndian>(data_size as u32)?;
-
-        // BSS size
-        match self.bss_segment {
+        let bss_size = match self.bss_segment {
             Some(segment) => {
-                let memory_offset = data_segment.vaddr + uncompressed_data_size;
+                let memory_offset = data_segment.vaddr + data.len() as u64;
                 if segment.vaddr != memory_offset {
-                    println!(
-                    "Warning: possible misalign bss\n.bss addr: 0x{:x}\nexpected offset: 0x{:x}",
-                    segment.vaddr, memory_offset);
+                    log::warn!(
+                        "possible misalign bss\n.bss addr: 0x{:x}\nexpected offset: 0x{:x}",
+                        segment.vaddr,
+                        memory_offset
+                    );
                 }
                 // (bss_segment['p_memsz'] + 0xFFF) & ~0xFFF
-                output_writter
-                    .write_u32::<LittleEndian>(((segment.memsz + 0xFFF) & !0xFFF) as u32)?;
+                ((segment.memsz + 0xFFF) & !0xFFF) as u32
             }
-            _ => {
-                // in this case the bss is missing or is embedeed in .data. libnx does that, let's support it
-                output_writter
-                    .write_u32::<LittleEndian>((data_segment.memsz - data_segment.filesz) as u32)?;
-            }
-        }
-
-        write_build_id(&self.build_id, output_writter, &code, &rodata, &data)?;
-
-        // Compressed size
-        output_writter.write_u32::<LittleEndian>(compressed_code_size)?;
-        output_writter.write_u32::<LittleEndian>(compressed_rodata_size)?;
-        output_writter.write_u32::<LittleEndian>(compressed_data_size)?;
-
-        // Reserved (0x1C)
-        output_writter.write_u32::<LittleEndian>(0)?;
-        output_writter.write_u64::<LittleEndian>(0)?;
-        output_writter.write_u64::<LittleEndian>(0)?;
-        output_writter.write_u64::<LittleEndian>(0)?;
+            // in this case the bss is missing or is embedeed in .data. libnx does that, let's support it
+            _ => (data_segment.memsz - data_segment.filesz) as u32,
+        };
 
-        // TODO: SegmentHeaderRelative for .api_info
-        output_writter.write_u64::<LittleEndian>(0)?;
-        // SegmentHeaderRelative for .dynstr
-        output_writter.write_u32::<LittleEndian>(
-            self.dynstr_section
+        let section = |section: &Option<SectionHeader>, name: &'static str| {
+            section
                 .as_ref()
-                .map(|v| u32::try_from(v.addr).unwrap())
-                .unwrap_or(0),
-        )?;
-        output_writter.write_u32::<LittleEndian>(
-            self.dynstr_section
-                .as_ref()
-                .map(|v| u32::try_from(v.size).unwrap())
-                .unwrap_or(0),
-        )?;
-        // SegmentHeaderRelative for .dynsym
-        output_writter.write_u32::<LittleEndian>(
-            self.dynsym_section
-                .as_ref()
-                .map(|v| u32::try_from(v.addr).unwrap())
-                .unwrap_or(0),
-        )?;
-        output_writter.write_u32::<LittleEndian>(
-            self.dynsym_section
-                .as_ref()
-                .map(|v| u32::try_from(v.size).unwrap())
-                .unwrap_or(0),
-        )?;
-
-        // .text sha256
-        let text_sum = utils::calculate_sha256(&code)?;
-        output_writter.write_all(&text_sum)?;
-
-        // .rodata sha256
-        let rodata_sum = utils::calculate_sha256(&rodata)?;
-        output_writter.write_all(&rodata_sum)?;
-
-        // .data sha256
-        let data_sum = utils::calculate_sha256(&data)?;
-        output_writter.write_all(&data_sum)?;
-
-        // compressed data
-        output_writter.write_all(&compressed_code)?;
-        output_writter.write_all(&compressed_rodata)?;
-        output_writter.write_all(&compressed_data)?;
+                .map(|v| Ok::<_, Error>((to_u32(v.addr, name)?, to_u32(v.size, name)?)))
+                .transpose()
+        };
+        NsoBuilder {
+            text: (code, text_segment.vaddr as u32),
+            rodata: (rodata, rodata_segment.vaddr as u32),
+            data: (data, data_segment.vaddr as u32),
+            bss_size,
+            build_id: self.build_id.clone(),
+            dynstr: section(&self.dynstr_section, ".dynstr")?,
+            dynsym: section(&self.dynsym_section, ".dynsym")?,
+        }
+        .write(output_writter, compressor)?;
         Ok(())
     }
 
-    pub fn write_kip1<T>(&mut self, output_writer: &mut T, npdm: &KipNpdm) -> std::io::Result<()>
+    pub fn write_kip1<T>(&mut self, output_writer: &mut T, npdm: &KipNpdm) -> Result<(), Error>
+    where
+        T: Write,
+    {
+        self.write_kip1_with(output_writer, npdm, &Blz)
+    }
+
+    /// Same as `write_kip1`, compressing the segments with `compressor`.
+    /// The compression flags of the NPDM are replaced by the ones matching
+    /// what `compressor` did.
+    pub fn write_kip1_with<T>(
+        &mut self,
+        output_writer: &mut T,
+        npdm: &KipNpdm,
+        compressor: &dyn Compressor,
+    ) -> Result<(), Error>
     where
         T: Write,
     {
@@ -639,42 +602,40 @@ impl NxoFile {
         output_writer.write_u8(npdm.main_thread_priority)?;
         output_writer.write_u8(npdm.default_cpu_id)?;
         output_writer.write_u8(0)?; // Reserved
-        if let Some(flags) = npdm.flags {
-            output_writer.write_u8(flags)?;
+        let flags = if let Some(flags) = npdm.flags {
+            flags
         } else if self.machine == EM_AARCH64 {
             // Compression enable, Is64Bit, IsAddrSpace32Bit, UseSystemPoolPartition
-            output_writer.write_u8(0b0011_1111)?;
+            0b0011_1111
         } else if self.machine == EM_ARM {
             // Compression enable, UseSystemPoolPartition
-            output_writer.write_u8(0b0010_0111)?;
+            0b0010_0111
         } else {
-            unimplemented!("Unknown machine type");
-        }
+            return Err(invalid_nxo("only ARM and AArch64 ELFs are supported"));
+        };
 
-        let mut segment_data = utils::get_segment_data(&mut self.file, &self.text_segment)?;
-        let text_data = utils::compress_blz(&mut segment_data).unwrap();
-        let mut segment_data = utils::get_segment_data(&mut self.file, &self.rodata_segment)?;
-        let rodata_data = utils::compress_blz(&mut segment_data).unwrap();
-        let mut segment_data = utils::get_segment_data(&mut self.file, &self.data_segment)?;
-        let data_data = utils::compress_blz(&mut segment_data).unwrap();
+        let text = utils::get_segment_data(&mut self.file, &self.text_segment)?;
+        let rodata = utils::get_segment_data(&mut self.file, &self.rodata_segment)?;
+        let data = utils::get_segment_data(&mut self.file, &self.data_segment)?;
+        let ([text_data, rodata_data, data_data], compressed_flags) =
+            compress_segments(compressor, [&text, &rodata, &data])?;
+        output_writer.write_u8((flags & !0b111) | compressed_flags)?;
 
-        write_kip_segment_header(output_writer, &self.text_segment, 0, text_data.len() as u32)?;
+        write_kip_segment_header(output_writer, &self.text_segment, 0, text_data.len() as u32)
+            .with_field(".text")?;
         write_kip_segment_header(
             output_writer,
             &self.rodata_segment,
-            u32::try_from(npdm.main_thread_stack_size.0)
-                .expect("Exected main_thread_stack_size to be an u32"),
+            to_u32(npdm.main_thread_stack_size.0, "main_thread_stack_size")?,
             rodata_data.len() as u32,
-        )?;
-        write_kip_segment_header(output_writer, &self.data_segment, 0, data_data.len() as u32)?;
+        )
+        .with_field(".rodata")?;
+        write_kip_segment_header(output_writer, &self.data_segment, 0, data_data.len() as u32)
+            .with_field(".data")?;
 
         if let Some(segment) = self.bss_segment {
-            output_writer.write_u32::<LittleEndian>(
-                u32::try_from(segment.vaddr).expect("BSS vaddr too big"),
-            )?;
-            output_writer.write_u32::<LittleEndian>(
-                u32::try_from(segment.memsz).expect("BSS memsize too big"),
-            )?;
+            output_writer.write_u32::<LittleEndian>(to_u32(segment.vaddr, ".bss")?)?;
+            output_writer.write_u32::<LittleEndian>(to_u32(segment.memsz, ".bss")?)?;
         } else {
             // in this case the bss is missing or is embedeed in .data. libnx does that, let's support it
             let data_segment_size = (self.data_segment.filesz + 0xFFF) & !0xFFF;
@@ -683,9 +644,10 @@ impl NxoFile {
             } else {
                 0
             };
-            output_writer.write_u32::<LittleEndian>(
-                u32::try_from(self.data_segment.vaddr + data_segment_size).unwrap(),
-            )?;
+            output_writer.write_u32::<LittleEndian>(to_u32(
+                self.data_segment.vaddr + data_segment_size,
+                ".bss",
+            )?)?;
             output_writer.write_u32::<LittleEndian>(bss_size)?;
         }
         output_writer.write_u32::<LittleEndian>(0)?;
@@ -700,16 +662,8 @@ impl NxoFile {
         }
 
         // Kernel caps:
-        let caps = npdm
-            .kernel_capabilities
-            .iter()
-            .map(|v| v.encode())
-            .flatten()
-            .collect::<Vec<u32>>();
-        assert!(
-            caps.len() < 0x20,
-            "kernel_capabilities should have less than 0x20 entries!"
-        );
+        let caps =
+            encode_capabilities(&npdm.kernel_capabilities).with_field("kernel_capabilities")?;
 
         unsafe {
             // Safety: This is safe. I'm just casting a slice of u32 to a slice of u8
@@ -731,21 +685,532 @@ impl NxoFile {
     }
 }
 
+/// Builds an NSO out of the contents of its segments, for code that doesn't
+/// come from an ELF. Segments are given with their offset in memory, and
+/// padded to whole pages.
+#[derive(Debug, Default, Clone)]
+pub struct NsoBuilder {
+    text: (Vec<u8>, u32),
+    rodata: (Vec<u8>, u32),
+    data: (Vec<u8>, u32),
+    bss_size: u32,
+    /// A GNU build-id note, like the ones of ELFs.
+    build_id: Option<Vec<u8>>,
+    dynstr: Option<(u32, u32)>,
+    dynsym: Option<(u32, u32)>,
+}
+
+impl NsoBuilder {
+    pub fn new() -> NsoBuilder {
+        NsoBuilder::default()
+    }
+
+    pub fn text(&mut self, memory_offset: u32, data: Vec<u8>) -> &mut NsoBuilder {
+        self.text = (data, memory_offset);
+        self
+    }
+
+    pub fn rodata(&mut self, memory_offset: u32, data: Vec<u8>) -> &mut NsoBuilder {
+        self.rodata = (data, memory_offset);
+        self
+    }
+
+    pub fn data(&mut self, memory_offset: u32, data: Vec<u8>) -> &mut NsoBuilder {
+        self.data = (data, memory_offset);
+        self
+    }
+
+    /// Size of the zeroed memory following .data.
+    pub fn bss_size(&mut self, bss_size: u32) -> &mut NsoBuilder {
+        self.bss_size = bss_size;
+        self
+    }
+
+    /// The module ID, up to 0x20 bytes. Defaults to the SHA256 of the segments.
+    pub fn build_id(&mut self, build_id: &[u8]) -> &mut NsoBuilder {
+        let mut note = Vec::with_capacity(0x10 + build_id.len());
+        note.extend_from_slice(&4u32.to_le_bytes());
+        note.extend_from_slice(&(build_id.len() as u32).to_le_bytes());
+        note.extend_from_slice(&3u32.to_le_bytes());
+        note.extend_from_slice(b"GNU\0");
+        note.extend_from_slice(build_id);
+        self.build_id = Some(note);
+        self
+    }
+
+    /// Offset and size of the .dynstr section.
+    pub fn dynstr(&mut self, offset: u32, size: u32) -> &mut NsoBuilder {
+        self.dynstr = Some((offset, size));
+        self
+    }
+
+    /// Offset and size of the .dynsym section.
+    pub fn dynsym(&mut self, offset: u32, size: u32) -> &mut NsoBuilder {
+        self.dynsym = Some((offset, size));
+        self
+    }
+
+    pub fn build<W: Write>(self, writer: &mut W) -> Result<(), Error> {
+        self.build_with(writer, compression::default_nso_compressor())
+    }
+
+    /// Same as `build`, compressing the segments with `compressor`.
+    pub fn build_with<W: Write>(
+        mut self,
+        writer: &mut W,
+        compressor: &dyn Compressor,
+    ) -> Result<(), Error> {
+        utils::add_padding(&mut self.text.0, 0xFFF);
+        utils::add_padding(&mut self.rodata.0, 0xFFF);
+        utils::add_padding(&mut self.data.0, 0xFFF);
+        self.write(writer, compressor)?;
+        Ok(())
+    }
+
+    fn write<T: Write>(
+        self,
+        output_writter: &mut T,
+        compressor: &dyn Compressor,
+    ) -> std::io::Result<()> {
+        let (code, text_offset) = self.text;
+        let (rodata, rodata_offset) = self.rodata;
+        let (data, data_offset) = self.data;
+
+        // NSO magic
+        output_writter.write_all(b"NSO0")?;
+        // Version
+        output_writter.write_u32::<LittleEndian>(0)?;
+        // Reserved
+        output_writter.write_u32::<LittleEndian>(0)?;
+
+        let ([compressed_code, compressed_rodata, compressed_data], compressed_flags) =
+            compress_segments(compressor, [&code, &rodata, &data])?;
+
+        // Flags, set compression + sum check
+        output_writter.write_u32::<LittleEndian>(u32::from(compressed_flags) | 0x38)?;
+
+        // Segment Header (3 entries)
+        let mut file_offset = 0x100;
+
+        // .text segment
+        let code_size = code.len() as u32;
+        let compressed_code_size = compressed_code.len() as u32;
+        output_writter.write_u32::<LittleEndian>(file_offset)?;
+        output_writter.write_u32::<LittleEndian>(text_offset)?;
+        output_writter.write_u32::<LittleEndian>(code_size)?;
+
+        // TODO: Module Name Offset
+        output_writter.write_u32::<LittleEndian>(0)?;
+
+        file_offset += compressed_code_size;
+
+        // .rodata segment
+        let rodata_size = rodata.len() as u32;
+        let compressed_rodata_size = compressed_rodata.len() as u32;
+        output_writter.write_u32::<LittleEndian>(file_offset)?;
+        output_writter.write_u32::<LittleEndian>(rodata_offset)?;
+        output_writter.write_u32::<LittleEndian>(rodata_size)?;
+
+        // TODO: Module Name Size
+        output_writter.write_u32::<LittleEndian>(0)?;
+
+        file_offset += compressed_rodata_size;
+
+        // .data segment
+        let data_size = data.len() as u32;
+        let compressed_data_size = compressed_data.len() as u32;
+        output_writter.write_u32::<LittleEndian>(file_offset)?;
+        output_writter.write_u32::<LittleEndian>(data_offset)?;
+        output_writter.write_u32::<LittleEndian>(data_size)?;
+
+        // BSS size
+        output_writter.write_u32::<LittleEndian>(self.bss_size)?;
+
+        write_build_id(&self.build_id, output_writter, &code, &rodata, &data)?;
+
+        // Compressed size
+        output_writter.write_u32::<LittleEndian>(compressed_code_size)?;
+        output_writter.write_u32::<LittleEndian>(compressed_rodata_size)?;
+        output_writter.write_u32::<LittleEndian>(compressed_data_size)?;
+
+        // Reserved (0x1C)
+        output_writter.write_u32::<LittleEndian>(0)?;
+        output_writter.write_u64::<LittleEndian>(0)?;
+        output_writter.write_u64::<LittleEndian>(0)?;
+        output_writter.write_u64::<LittleEndian>(0)?;
+
+        // TODO: SegmentHeaderRelative for .api_info
+        output_writter.write_u64::<LittleEndian>(0)?;
+        // SegmentHeaderRelative for .dynstr and .dynsym
+        for section in &[self.dynstr, self.dynsym] {
+            let (offset, size) = section.unwrap_or((0, 0));
+            output_writter.write_u32::<LittleEndian>(offset)?;
+            output_writter.write_u32::<LittleEndian>(size)?;
+        }
+
+        // .text sha256
+        let text_sum = utils::calculate_sha256(&code)?;
+        output_writter.write_all(&text_sum)?;
+
+        // .rodata sha256
+        let rodata_sum = utils::calculate_sha256(&rodata)?;
+        output_writter.write_all(&rodata_sum)?;
+
+        // .data sha256
+        let data_sum = utils::calculate_sha256(&data)?;
+        output_writter.write_all(&data_sum)?;
+
+        // compressed data
+        output_writter.write_all(&compressed_code)?;
+        output_writter.write_all(&compressed_rodata)?;
+        output_writter.write_all(&compressed_data)?;
+        Ok(())
+    }
+}
+
 pub fn write_kip_segment_header<T>(
     output_writer: &mut T,
     segment: &ProgramHeader,
     attributes: u32,
     compressed_size: u32,
-) -> std::io::Result<()>
+) -> Result<(), Error>
 where
     T: Write,
 {
-    output_writer
-        .write_u32::<LittleEndian>(u32::try_from(segment.vaddr).expect("vaddr too big"))?;
-    output_writer
-        .write_u32::<LittleEndian>(u32::try_from(segment.filesz).expect("memsz too big"))?;
+    output_writer.write_u32::<LittleEndian>(to_u32(segment.vaddr, "vaddr")?)?;
+    output_writer.write_u32::<LittleEndian>(to_u32(segment.filesz, "filesz")?)?;
     output_writer.write_u32::<LittleEndian>(compressed_size)?;
     output_writer.write_u32::<LittleEndian>(attributes)?;
 
     Ok(())
 }
+
+/// A segment of an NSO or NRO, as told by its header.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct NxoSegment {
+    /// Offset of the segment in the file.
+    pub file_offset: u32,
+    /// Offset of the segment from the base of the module once loaded.
+    pub memory_offset: u32,
+    /// Size of the segment once loaded.
+    pub size: u32,
+    /// Size of the segment in the file, smaller than `size` if compressed.
+    pub file_size: u32,
+}
+
+/// The header of an NSO or NRO, describing its text, rodata and data
+/// segments.
+#[derive(Debug, Clone, Serialize)]
+pub struct NxoHeader {
+    pub segments: [NxoSegment; 3],
+    pub bss_size: u32,
+    #[serde(serialize_with = "serialize_hex_bytes")]
+    pub build_id: [u8; 0x20],
+}
+
+/// The offset and size of a region, as stored in NSO and NRO headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct NxoRange {
+    pub offset: u32,
+    pub size: u32,
+}
+
+/// A segment header of an NSO.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct NsoSegmentHeader {
+    pub file_offset: u32,
+    pub memory_offset: u32,
+    pub size: u32,
+}
+
+/// The header of an NSO, as stored in it.
+#[derive(Debug, Clone, Serialize)]
+pub struct NsoHeader {
+    pub version: u32,
+    /// Bits 0-2 tell which segments are compressed, bits 3-5 which ones are
+    /// checked against their hash.
+    pub flags: u32,
+    pub text: NsoSegmentHeader,
+    pub module_name_offset: u32,
+    pub rodata: NsoSegmentHeader,
+    pub module_name_size: u32,
+    pub data: NsoSegmentHeader,
+    pub bss_size: u32,
+    #[serde(serialize_with = "serialize_hex_bytes")]
+    pub build_id: [u8; 0x20],
+    pub text_file_size: u32,
+    pub rodata_file_size: u32,
+    pub data_file_size: u32,
+    /// Regions of .rodata, relative to its start.
+    pub api_info: NxoRange,
+    pub dynstr: NxoRange,
+    pub dynsym: NxoRange,
+    #[serde(serialize_with = "serialize_hex_bytes")]
+    pub text_hash: [u8; 0x20],
+    #[serde(serialize_with = "serialize_hex_bytes")]
+    pub rodata_hash: [u8; 0x20],
+    #[serde(serialize_with = "serialize_hex_bytes")]
+    pub data_hash: [u8; 0x20],
+}
+
+/// The header of an NRO, along with the start of the file before it, as
+/// stored in it.
+#[derive(Debug, Clone, Serialize)]
+pub struct NroHeader {
+    /// Offset of the MOD0 header, relative to the start of the file.
+    pub mod0_offset: u32,
+    pub version: u32,
+    /// Size of the NRO, without its assets.
+    pub size: u32,
+    pub flags: u32,
+    pub text: NxoRange,
+    pub rodata: NxoRange,
+    pub data: NxoRange,
+    pub bss_size: u32,
+    #[serde(serialize_with = "serialize_hex_bytes")]
+    pub build_id: [u8; 0x20],
+    pub dso_handle_offset: u32,
+    /// Regions of .rodata, relative to its start.
+    pub api_info: NxoRange,
+    pub dynstr: NxoRange,
+    pub dynsym: NxoRange,
+}
+
+fn read_range(data: &[u8]) -> NxoRange {
+    NxoRange {
+        offset: LittleEndian::read_u32(data),
+        size: LittleEndian::read_u32(&data[4..]),
+    }
+}
+
+fn read_hash(data: &[u8]) -> [u8; 0x20] {
+    let mut hash = [0; 0x20];
+    hash.copy_from_slice(&data[..0x20]);
+    hash
+}
+
+impl NsoHeader {
+    pub fn parse(data: &[u8]) -> Result<NsoHeader, Error> {
+        if data.len() < 0x100 || &data[..4] != b"NSO0" {
+            return Err(invalid_nxo("NSO magic is wrong"));
+        }
+        let u32_at = |offset: usize| LittleEndian::read_u32(&data[offset..]);
+        let segment = |offset: usize| NsoSegmentHeader {
+            file_offset: u32_at(offset),
+            memory_offset: u32_at(offset + 4),
+            size: u32_at(offset + 8),
+        };
+        Ok(NsoHeader {
+            version: u32_at(0x4),
+            flags: u32_at(0xC),
+            text: segment(0x10),
+            module_name_offset: u32_at(0x1C),
+            rodata: segment(0x20),
+            module_name_size: u32_at(0x2C),
+            data: segment(0x30),
+            bss_size: u32_at(0x3C),
+            build_id: read_hash(&data[0x40..]),
+            text_file_size: u32_at(0x60),
+            rodata_file_size: u32_at(0x64),
+            data_file_size: u32_at(0x68),
+            api_info: read_range(&data[0x88..]),
+            dynstr: read_range(&data[0x90..]),
+            dynsym: read_range(&data[0x98..]),
+            text_hash: read_hash(&data[0xA0..]),
+            rodata_hash: read_hash(&data[0xC0..]),
+            data_hash: read_hash(&data[0xE0..]),
+        })
+    }
+}
+
+impl NroHeader {
+    pub fn parse(data: &[u8]) -> Result<NroHeader, Error> {
+        if data.len() < 0x80 || &data[0x10..0x14] != b"NRO0" {
+            return Err(invalid_nxo("NRO magic is wrong"));
+        }
+        let u32_at = |offset: usize| LittleEndian::read_u32(&data[offset..]);
+        Ok(NroHeader {
+            mod0_offset: u32_at(0x4),
+            version: u32_at(0x14),
+            size: u32_at(0x18),
+            flags: u32_at(0x1C),
+            text: read_range(&data[0x20..]),
+            rodata: read_range(&data[0x28..]),
+            data: read_range(&data[0x30..]),
+            bss_size: u32_at(0x38),
+            build_id: read_hash(&data[0x40..]),
+            dso_handle_offset: u32_at(0x60),
+            api_info: read_range(&data[0x68..]),
+            dynstr: read_range(&data[0x70..]),
+            dynsym: read_range(&data[0x78..]),
+        })
+    }
+}
+
+fn invalid_nxo(error: &'static str) -> Error {
+    Error::InvalidNxo {
+        error,
+        backtrace: Backtrace::generate(),
+    }
+}
+
+/// Converts an address or size of the ELF to the 32 bits NXOs store them on.
+fn to_u32(value: u64, field: &'static str) -> Result<u32, Error> {
+    u32::try_from(value)
+        .map_err(|_| invalid_nxo("addresses and sizes must fit in 32 bits").with_field(field))
+}
+
+impl NxoHeader {
+    pub fn parse_nso(data: &[u8]) -> Result<NxoHeader, Error> {
+        NxoHeader::parse_nso_with_mode(data, ParseMode::Lenient)
+    }
+
+    /// Same as `parse_nso`. In strict mode, `data` must be the whole file, the
+    /// segments being checked to be in it after the header.
+    pub fn parse_nso_with_mode(data: &[u8], mode: ParseMode) -> Result<NxoHeader, Error> {
+        let raw = NsoHeader::parse(data)?;
+        let segment = |segment: &NsoSegmentHeader, file_size| NxoSegment {
+            file_offset: segment.file_offset,
+            memory_offset: segment.memory_offset,
+            size: segment.size,
+            file_size,
+        };
+        let header = NxoHeader {
+            segments: [
+                segment(&raw.text, raw.text_file_size),
+                segment(&raw.rodata, raw.rodata_file_size),
+                segment(&raw.data, raw.data_file_size),
+            ],
+            bss_size: raw.bss_size,
+            build_id: raw.build_id,
+        };
+        if mode.is_strict() {
+            let mut regions = Regions::new(data.len() as u64);
+            regions.add("header", 0, 0x100)?;
+            header.check_segments(regions)?;
+        }
+        Ok(header)
+    }
+
+    pub fn parse_nro(data: &[u8]) -> Result<NxoHeader, Error> {
+        NxoHeader::parse_nro_with_mode(data, ParseMode::Lenient)
+    }
+
+    /// Same as `parse_nro`. In strict mode, `data` must be the whole file, the
+    /// segments being checked to be in it.
+    pub fn parse_nro_with_mode(data: &[u8], mode: ParseMode) -> Result<NxoHeader, Error> {
+        let raw = NroHeader::parse(data)?;
+        // NROs are loaded as is, segments are neither moved nor compressed.
+        let segment = |range: &NxoRange| NxoSegment {
+            file_offset: range.offset,
+            memory_offset: range.offset,
+            size: range.size,
+            file_size: range.size,
+        };
+        let header = NxoHeader {
+            segments: [segment(&raw.text), segment(&raw.rodata), segment(&raw.data)],
+            bss_size: raw.bss_size,
+            build_id: raw.build_id,
+        };
+        if mode.is_strict() {
+            // The header is part of .text.
+            header.check_segments(Regions::new(data.len() as u64))?;
+        }
+        Ok(header)
+    }
+
+    fn check_segments(&self, mut regions: Regions) -> Result<(), Error> {
+        for (name, segment) in [".text", ".rodata", ".data"].iter().zip(&self.segments) {
+            regions.add(*name, segment.file_offset.into(), segment.file_size.into())?;
+        }
+        regions.check_overlaps()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn invalid_elves_are_errors() {
+        let err = NxoFile::from_reader(Cursor::new(vec![0; 0x40]))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn nso_builder_lays_out_segments() {
+        let mut builder = NsoBuilder::new();
+        builder
+            .text(0, vec![0x1F; 0x1800])
+            .rodata(0x2000, vec![1; 0x10])
+            .data(0x3000, vec![2; 0x10])
+            .bss_size(0x4000)
+            .build_id(&[0xAB; 0x14]);
+        let mut data = Vec::new();
+        builder.build(&mut data).unwrap();
+
+        let header = NxoHeader::parse_nso(&data).unwrap();
+        let layout: Vec<_> = header
+            .segments
+            .iter()
+            .map(|segment| (segment.memory_offset, segment.size))
+            .collect();
+        assert_eq!(layout, [(0, 0x2000), (0x2000, 0x1000), (0x3000, 0x1000)]);
+        assert_eq!(header.bss_size, 0x4000);
+        assert_eq!(&header.build_id[..0x14], &[0xAB; 0x14]);
+        assert_eq!(&header.build_id[0x14..], &[0; 0xC]);
+    }
+
+    #[test]
+    fn compressor_decides_the_compression_flags() {
+        /// Only compresses .text, into a marker.
+        struct TextOnly;
+
+        impl Compressor for TextOnly {
+            fn compress(&self, data: &[u8]) -> io::Result<Option<Vec<u8>>> {
+                Ok(if data[0] == 0x1F {
+                    Some(vec![0xAA; 4])
+                } else {
+                    None
+                })
+            }
+        }
+
+        let mut builder = NsoBuilder::new();
+        builder
+            .text(0, vec![0x1F; 0x10])
+            .rodata(0x1000, vec![1; 0x10])
+            .data(0x2000, vec![2; 0x10]);
+        let mut data = Vec::new();
+        builder.build_with(&mut data, &TextOnly).unwrap();
+
+        let header = NsoHeader::parse(&data).unwrap();
+        assert_eq!(header.flags, 0x39);
+        assert_eq!(header.text_file_size, 4);
+        assert_eq!(header.rodata_file_size, 0x1000);
+        assert_eq!(&data[header.text.file_offset as usize..][..4], &[0xAA; 4]);
+    }
+
+    #[test]
+    fn nso_header_serializes_every_field() {
+        let mut builder = NsoBuilder::new();
+        builder
+            .text(0, vec![0x1F; 0x10])
+            .rodata(0x1000, vec![1; 0x40])
+            .data(0x2000, vec![2; 0x10])
+            .dynstr(0x10, 0x20);
+        let mut data = Vec::new();
+        builder.build(&mut data).unwrap();
+
+        let header = serde_json::to_value(NsoHeader::parse(&data).unwrap()).unwrap();
+        assert_eq!(header["rodata"]["memory_offset"], 0x1000);
+        assert_eq!(header["rodata"]["size"], 0x1000);
+        assert_eq!(
+            header["dynstr"],
+            serde_json::json!({ "offset": 0x10, "size": 0x20 })
+        );
+        assert_eq!(header["text_hash"].as_str().unwrap().len(), 0x40);
+    }
+}
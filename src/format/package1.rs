@@ -0,0 +1,486 @@
+//! Package1, the boot stage stored in BOOT0 and run by the boot ROM. Its
+//! plaintext part, package1ldr, decrypts PK11 and runs the NX bootloader it
+//! holds. PK11 also holds the secure monitor, and the warmboot firmware used
+//! to resume from sleep.
+//!
+//! Nothing in the image tells which firmware it comes from, where PK11 is or
+//! in which order its sections are. All of this is guessed from the build
+//! date of package1ldr.
+
+use crate::crypto::AesCtr;
+use crate::error::Error;
+use crate::format::parse::{ParseMode, Regions};
+use crate::pki::Keys;
+use byteorder::{ByteOrder, LittleEndian};
+use snafu::Backtrace;
+use snafu::GenerateBacktrace;
+use std::fmt;
+use std::io::{Read, Seek, SeekFrom};
+use std::iter;
+
+/// Offset of the build date of package1ldr, as `YYYYMMDDhhmmss`.
+const PACKAGE1_BUILD_DATE_OFFSET: u64 = 0x10;
+
+/// Size of the PK11 header, giving the size of every section.
+const PK11_HEADER_SIZE: usize = 0x20;
+
+/// Biggest PK11 accepted, well over the size of BOOT0.
+const PK11_MAX_SIZE: u32 = 0x40_0000;
+
+fn invalid_package1(error: &'static str) -> Error {
+    Error::InvalidPackage1 {
+        error,
+        backtrace: Backtrace::generate(),
+    }
+}
+
+/// A section of PK11.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Package1Section {
+    Warmboot,
+    NxBootloader,
+    SecureMonitor,
+}
+
+impl Package1Section {
+    pub const ALL: [Package1Section; 3] = [
+        Package1Section::Warmboot,
+        Package1Section::NxBootloader,
+        Package1Section::SecureMonitor,
+    ];
+
+    /// Name of the file the section is extracted to.
+    pub fn file_name(self) -> &'static str {
+        match self {
+            Package1Section::Warmboot => "warmboot.bin",
+            Package1Section::NxBootloader => "nx_bootloader.bin",
+            Package1Section::SecureMonitor => "secmon.bin",
+        }
+    }
+
+    /// Offset of the size of the section in the PK11 header.
+    fn size_offset(self) -> usize {
+        match self {
+            Package1Section::Warmboot => 0x4,
+            Package1Section::NxBootloader => 0x10,
+            Package1Section::SecureMonitor => 0x18,
+        }
+    }
+}
+
+impl fmt::Display for Package1Section {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Package1Section::Warmboot => write!(f, "Warmboot"),
+            Package1Section::NxBootloader => write!(f, "NX bootloader"),
+            Package1Section::SecureMonitor => write!(f, "Secure monitor"),
+        }
+    }
+}
+
+/// A retail build of Package1.
+#[derive(Debug)]
+pub struct Package1Build {
+    pub build_date: &'static str,
+    /// Firmware versions shipping this build.
+    pub firmware: &'static str,
+    /// Master key revision of the package1 key encrypting PK11.
+    pub key_generation: usize,
+    /// Offset of the size and counter of PK11, which follows them.
+    pub pk11_header_offset: u64,
+    /// Order of the sections in PK11.
+    pub section_order: [Package1Section; 3],
+}
+
+const SM_LDR_WB: [Package1Section; 3] = [
+    Package1Section::SecureMonitor,
+    Package1Section::NxBootloader,
+    Package1Section::Warmboot,
+];
+const WB_LDR_SM: [Package1Section; 3] = [
+    Package1Section::Warmboot,
+    Package1Section::NxBootloader,
+    Package1Section::SecureMonitor,
+];
+const LDR_SM_WB: [Package1Section; 3] = [
+    Package1Section::NxBootloader,
+    Package1Section::SecureMonitor,
+    Package1Section::Warmboot,
+];
+
+macro_rules! package1_builds {
+    ($($date:expr, $firmware:expr, $key_generation:expr, $offset:expr, $order:expr;)*) => {
+        /// Known builds, oldest first.
+        pub const PACKAGE1_BUILDS: &[Package1Build] = &[
+            $(Package1Build {
+                build_date: $date,
+                firmware: $firmware,
+                key_generation: $key_generation,
+                pk11_header_offset: $offset,
+                section_order: $order,
+            },)*
+        ];
+    };
+}
+
+package1_builds! {
+    "20161121183008", "1.0.0", 0, 0x3FE0, SM_LDR_WB;
+    "20170210155124", "2.0.0-2.3.0", 0, 0x3FE0, WB_LDR_SM;
+    "20170519101410", "3.0.0", 1, 0x3FE0, WB_LDR_SM;
+    "20170710161758", "3.0.1-3.0.2", 2, 0x3FE0, WB_LDR_SM;
+    "20170921172629", "4.0.0-4.1.0", 3, 0x3FE0, LDR_SM_WB;
+    "20180220163747", "5.0.0-5.1.0", 4, 0x3FE0, LDR_SM_WB;
+    "20180802162753", "6.0.0-6.1.0", 5, 0x3FE0, LDR_SM_WB;
+    "20181107105733", "6.2.0", 6, 0x6FE0, LDR_SM_WB;
+    "20181218175730", "7.0.0", 7, 0x6FE0, LDR_SM_WB;
+    "20190208150037", "7.0.1", 7, 0x6FE0, LDR_SM_WB;
+    "20190314172056", "8.0.0-8.0.1", 7, 0x6FE0, WB_LDR_SM;
+    "20190531152432", "8.1.0", 8, 0x6FE0, LDR_SM_WB;
+    "20190809135709", "9.0.0-9.0.1", 9, 0x6FE0, LDR_SM_WB;
+    "20191021113848", "9.1.0-9.2.0", 10, 0x6FE0, LDR_SM_WB;
+}
+
+/// Firmware a Package1 comes from, guessed from its build date. Unknown builds
+/// are assumed to be laid out like the closest known one.
+#[derive(Debug, Clone, Copy)]
+pub enum Package1Era {
+    Known(&'static Package1Build),
+    /// Built before the first retail firmware, e.g. for development units.
+    Before(&'static Package1Build),
+    /// Built after the given build, the newest one with an older date.
+    After(&'static Package1Build),
+}
+
+impl Package1Era {
+    pub fn from_build_date(build_date: &str) -> Package1Era {
+        // Dates of the same length compare like strings.
+        match PACKAGE1_BUILDS
+            .iter()
+            .rev()
+            .find(|build| build.build_date <= build_date)
+        {
+            Some(build) if build.build_date == build_date => Package1Era::Known(build),
+            Some(build) => Package1Era::After(build),
+            None => Package1Era::Before(&PACKAGE1_BUILDS[0]),
+        }
+    }
+
+    /// The build whose layout is used.
+    pub fn build(self) -> &'static Package1Build {
+        match self {
+            Package1Era::Known(build) | Package1Era::Before(build) | Package1Era::After(build) => {
+                build
+            }
+        }
+    }
+}
+
+impl fmt::Display for Package1Era {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Package1Era::Known(build) => write!(f, "{}", build.firmware),
+            Package1Era::Before(build) => write!(f, "unknown, older than {}", build.firmware),
+            Package1Era::After(build) => write!(f, "unknown, newer than {}", build.firmware),
+        }
+    }
+}
+
+/// A Package1 image, as dumped from BOOT0.
+#[derive(Debug)]
+pub struct Package1 {
+    build_date: String,
+    era: Package1Era,
+    package1ldr: Vec<u8>,
+    ctr: [u8; 0x10],
+    pk11: Vec<u8>,
+    key_generation: Option<usize>,
+    decrypted: bool,
+    mode: ParseMode,
+}
+
+impl Package1 {
+    /// Reads a Package1 without decrypting it. PK11 is found already
+    /// decrypted in some dumps, which is kept as is.
+    pub fn from_reader<R: Read + Seek>(file: R) -> Result<Package1, Error> {
+        Package1::from_reader_with_mode(file, ParseMode::Lenient)
+    }
+
+    /// Same as `from_reader`, checking the file as told by `mode`. Strict mode
+    /// checks PK11 is in the file before reading it, and that its sections
+    /// are in it once decrypted.
+    pub fn from_reader_with_mode<R: Read + Seek>(
+        mut file: R,
+        mode: ParseMode,
+    ) -> Result<Package1, Error> {
+        let mut build_date = [0; 14];
+        file.seek(SeekFrom::Start(PACKAGE1_BUILD_DATE_OFFSET))?;
+        file.read_exact(&mut build_date)?;
+        if !build_date.iter().all(u8::is_ascii_digit) {
+            return Err(invalid_package1("build date is invalid"));
+        }
+        let build_date = String::from_utf8(build_date.to_vec())?;
+        let era = Package1Era::from_build_date(&build_date);
+
+        let header_offset = era.build().pk11_header_offset;
+        let mut package1ldr = vec![0; header_offset as usize];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut package1ldr)?;
+
+        let mut header = [0; 0x20];
+        file.read_exact(&mut header)?;
+        let size = LittleEndian::read_u32(&header[0x0..]);
+        if size < PK11_HEADER_SIZE as u32 || size > PK11_MAX_SIZE {
+            return Err(invalid_package1("PK11 size is invalid"));
+        }
+        if mode.is_strict() {
+            let pk11_offset = file.seek(SeekFrom::Current(0))?;
+            let mut regions = Regions::new(file.seek(SeekFrom::End(0))?);
+            regions.add("package1ldr", 0, header_offset)?;
+            regions.add("PK11 header", header_offset, 0x20)?;
+            regions.add("PK11", pk11_offset, size.into())?;
+            regions.check_overlaps()?;
+            file.seek(SeekFrom::Start(pk11_offset))?;
+        }
+        let mut ctr = [0; 0x10];
+        ctr.copy_from_slice(&header[0x10..0x20]);
+        let mut pk11 = vec![0; size as usize];
+        file.read_exact(&mut pk11)?;
+        let decrypted = &pk11[..4] == b"PK11";
+
+        let package1 = Package1 {
+            build_date,
+            era,
+            package1ldr,
+            ctr,
+            pk11,
+            key_generation: None,
+            decrypted,
+            mode,
+        };
+        if decrypted && mode.is_strict() {
+            package1.check_sections()?;
+        }
+        Ok(package1)
+    }
+
+    /// Checks the sections of the decrypted PK11 are in it, in strict mode.
+    fn check_sections(&self) -> Result<(), Error> {
+        let mut regions = Regions::within("PK11", self.pk11.len() as u64);
+        regions.add("PK11 header", 0, PK11_HEADER_SIZE as u64)?;
+        let mut offset = PK11_HEADER_SIZE as u64;
+        for &section in &self.era.build().section_order {
+            let size = LittleEndian::read_u32(&self.pk11[section.size_offset()..]).into();
+            regions.add(format!("{} section", section), offset, size)?;
+            offset += size;
+        }
+        regions.check_overlaps()
+    }
+
+    pub fn build_date(&self) -> &str {
+        &self.build_date
+    }
+
+    pub fn era(&self) -> Package1Era {
+        self.era
+    }
+
+    /// Plaintext part of the image, before PK11.
+    pub fn package1ldr(&self) -> &[u8] {
+        &self.package1ldr
+    }
+
+    /// Size of the whole image, package1ldr and PK11 included.
+    pub fn size(&self) -> usize {
+        self.package1ldr.len() + 0x20 + self.pk11.len()
+    }
+
+    pub fn pk11_size(&self) -> usize {
+        self.pk11.len()
+    }
+
+    pub fn is_decrypted(&self) -> bool {
+        self.decrypted
+    }
+
+    /// Master key revision of the key PK11 was decrypted with.
+    pub fn key_generation(&self) -> Option<usize> {
+        self.key_generation
+    }
+
+    /// Decrypts PK11 with the package1 key expected for its era, then with
+    /// any other package1 key of `keys`. Does nothing if PK11 is already
+    /// decrypted.
+    pub fn decrypt(&mut self, keys: &Keys) -> Result<(), Error> {
+        if self.decrypted {
+            return Ok(());
+        }
+        let expected = self.era.build().key_generation;
+        for generation in iter::once(expected).chain((0..0x20).filter(|&i| i != expected)) {
+            let key = match keys.package1_key(generation) {
+                Ok(key) => key,
+                Err(_) => continue,
+            };
+            let crypter = AesCtr::with_counter(key.clone(), self.ctr);
+            let mut magic = [0; 4];
+            magic.copy_from_slice(&self.pk11[..4]);
+            crypter.apply_keystream(&mut magic, 0);
+            if &magic == b"PK11" {
+                crypter.apply_keystream(&mut self.pk11, 0);
+                self.key_generation = Some(generation);
+                self.decrypted = true;
+                if self.mode.is_strict() {
+                    self.check_sections()?;
+                }
+                return Ok(());
+            }
+        }
+        keys.package1_key(expected)?;
+        Err(invalid_package1("no package1 key decrypts PK11"))
+    }
+
+    /// Returns the data of a section of PK11, which must be decrypted.
+    pub fn section(&self, section: Package1Section) -> Result<&[u8], Error> {
+        if !self.decrypted {
+            return Err(invalid_package1("PK11 is still encrypted"));
+        }
+        let mut offset = PK11_HEADER_SIZE;
+        for &current in &self.era.build().section_order {
+            let size = LittleEndian::read_u32(&self.pk11[current.size_offset()..]) as usize;
+            if current == section {
+                return self
+                    .pk11
+                    .get(offset..)
+                    .and_then(|data| data.get(..size))
+                    .ok_or_else(|| invalid_package1("section is out of bounds"));
+            }
+            offset = offset
+                .checked_add(size)
+                .ok_or_else(|| invalid_package1("section is out of bounds"))?;
+        }
+        Err(invalid_package1("section isn't in the layout of this era"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pki::Aes128Key;
+    use std::io::Cursor;
+
+    fn keys(generation: usize) -> Keys {
+        let keyfile = format!("package1_key_{:02x} = {}\n", generation, "42".repeat(0x10));
+        Keys::retail_from_reader(keyfile.as_bytes()).unwrap()
+    }
+
+    /// Builds the Package1 of 4.0.0, its PK11 holding the NX bootloader, the
+    /// secure monitor and warmboot, in this order. PK11 is encrypted with
+    /// `key` when given.
+    fn package1_image(key: Option<&Aes128Key>) -> Vec<u8> {
+        let mut pk11 = vec![0; PK11_HEADER_SIZE];
+        pk11[..4].copy_from_slice(b"PK11");
+        for &(section, size, byte) in &[
+            (Package1Section::NxBootloader, 0x30, 0xB1),
+            (Package1Section::SecureMonitor, 0x20, 0x5E),
+            (Package1Section::Warmboot, 0x10, 0x3B),
+        ] {
+            LittleEndian::write_u32(&mut pk11[section.size_offset()..], size);
+            pk11.extend(iter::repeat(byte).take(size as usize));
+        }
+        let ctr = [0xC7; 0x10];
+        if let Some(key) = key {
+            AesCtr::with_counter(key.clone(), ctr).apply_keystream(&mut pk11, 0);
+        }
+
+        let mut data = vec![0; 0x3FE0];
+        data[0x10..0x1E].copy_from_slice(b"20170921172629");
+        let mut header = [0; 0x20];
+        LittleEndian::write_u32(&mut header, pk11.len() as u32);
+        header[0x10..].copy_from_slice(&ctr);
+        data.extend_from_slice(&header);
+        data.extend_from_slice(&pk11);
+        data
+    }
+
+    #[test]
+    fn era_is_guessed_from_the_build_date() {
+        let firmware = |date: &str| Package1Era::from_build_date(date).to_string();
+        assert_eq!(firmware("20170921172629"), "4.0.0-4.1.0");
+        assert_eq!(
+            firmware("20181001000000"),
+            "unknown, newer than 6.0.0-6.1.0"
+        );
+        assert_eq!(firmware("20160101000000"), "unknown, older than 1.0.0");
+        assert_eq!(
+            Package1Era::from_build_date("20991231235959")
+                .build()
+                .pk11_header_offset,
+            0x6FE0
+        );
+    }
+
+    #[test]
+    fn pk11_is_decrypted_and_split_into_its_sections() {
+        let keys = keys(3);
+        let mut package1 = Package1::from_reader(Cursor::new(package1_image(Some(
+            keys.package1_key(3).unwrap(),
+        ))))
+        .unwrap();
+        assert_eq!(package1.build_date(), "20170921172629");
+        assert_eq!(package1.size(), 0x3FE0 + 0x20 + 0x80);
+        assert!(!package1.is_decrypted());
+        assert!(package1.section(Package1Section::Warmboot).is_err());
+
+        package1.decrypt(&keys).unwrap();
+        assert_eq!(package1.key_generation(), Some(3));
+        assert_eq!(
+            package1.section(Package1Section::NxBootloader).unwrap(),
+            &[0xB1; 0x30][..]
+        );
+        assert_eq!(
+            package1.section(Package1Section::SecureMonitor).unwrap(),
+            &[0x5E; 0x20][..]
+        );
+        assert_eq!(
+            package1.section(Package1Section::Warmboot).unwrap(),
+            &[0x3B; 0x10][..]
+        );
+    }
+
+    #[test]
+    fn pk11_is_decrypted_with_the_key_of_another_generation() {
+        // Only the key of the expected generation is missing.
+        let keys = keys(5);
+        let data = package1_image(Some(keys.package1_key(5).unwrap()));
+        let mut package1 = Package1::from_reader(Cursor::new(data.clone())).unwrap();
+        package1.decrypt(&keys).unwrap();
+        assert_eq!(package1.key_generation(), Some(5));
+
+        let mut package1 = Package1::from_reader(Cursor::new(data)).unwrap();
+        assert!(package1.decrypt(&Keys::default()).is_err());
+    }
+
+    #[test]
+    fn decrypted_pk11_is_kept() {
+        let package1 = Package1::from_reader(Cursor::new(package1_image(None))).unwrap();
+        assert!(package1.is_decrypted());
+        assert_eq!(package1.key_generation(), None);
+        assert_eq!(
+            package1.section(Package1Section::Warmboot).unwrap(),
+            &[0x3B; 0x10][..]
+        );
+    }
+
+    #[test]
+    fn strict_mode_checks_the_sections() {
+        let mut data = package1_image(None);
+        // The warmboot section goes past the end of PK11.
+        LittleEndian::write_u32(&mut data[0x4000 + 0x4..], 0x11);
+        let package1 = Package1::from_reader(Cursor::new(data.clone())).unwrap();
+        assert!(package1.section(Package1Section::Warmboot).is_err());
+        assert!(Package1::from_reader_with_mode(Cursor::new(data), ParseMode::Strict).is_err());
+
+        let mut data = package1_image(None);
+        data.truncate(data.len() - 1);
+        assert!(Package1::from_reader_with_mode(Cursor::new(data), ParseMode::Strict).is_err());
+    }
+}
@@ -0,0 +1,496 @@
+//! Package2, holding the kernel and the INI1 of the processes it launches at
+//! boot. It is signed and encrypted with the package2 key of a master key
+//! revision.
+//!
+//! The header is made of a signature and of metadata encrypted with AES-CTR,
+//! whose counter is the first 0x10 bytes of the encrypted metadata. The size
+//! of the package is stored XORed in that counter. Up to four sections follow
+//! the header, each encrypted with its own counter.
+
+use crate::crypto::AesCtr;
+use crate::error::Error;
+use crate::format::ini1::{Ini1, INI1_HEADER_SIZE};
+use crate::format::nca::SignatureStatus;
+use crate::format::parse::{ParseMode, Regions};
+use crate::pki::{rsa2048_pss_verify, Keys};
+use crate::utils::align_up;
+use byteorder::{ByteOrder, LittleEndian};
+use sha2::{Digest, Sha256};
+use snafu::Backtrace;
+use snafu::GenerateBacktrace;
+use std::io::{Read, Write};
+
+/// Size of the header: the signature and the metadata.
+pub const PACKAGE2_HEADER_SIZE: usize = 0x200;
+
+/// Biggest package accepted by the secure monitor.
+pub const PACKAGE2_SIZE_MAX: usize = 0x7F_C000;
+
+pub const PACKAGE2_SECTION_COUNT: usize = 4;
+
+/// Index of the kernel section.
+pub const PACKAGE2_SECTION_KERNEL: usize = 0;
+
+/// Index of the INI1 section, empty since 8.0.0.
+pub const PACKAGE2_SECTION_INI1: usize = 1;
+
+fn invalid_package2(error: &'static str) -> Error {
+    Error::InvalidPackage2 {
+        error,
+        backtrace: Backtrace::generate(),
+    }
+}
+
+/// Finds the INI1 embedded in the kernel since 8.0.0. The kernel map giving
+/// its offset is somewhere in the first page of the kernel, so every word is
+/// tried until one looks like a valid map.
+fn find_embedded_ini1(kernel: &[u8]) -> Option<usize> {
+    const KERNEL_MAP_SIZE: usize = 0x30;
+    let end = std::cmp::min(0x1000, kernel.len().saturating_sub(KERNEL_MAP_SIZE));
+    (0..end).step_by(4).find_map(|offset| {
+        let map = |i: usize| LittleEndian::read_u32(&kernel[offset + i * 4..]) as usize;
+        let (text_start, text_end, rodata_start, rodata_end) = (map(0), map(1), map(2), map(3));
+        let (data_start, data_end, bss_start, bss_end) = (map(4), map(5), map(6), map(7));
+        let ini1 = map(8);
+        let aligned = |v: usize| v & 0xFFF == 0;
+        let valid = text_start == 0
+            && text_start < text_end
+            && aligned(text_end)
+            && text_end <= rodata_start
+            && aligned(rodata_start)
+            && rodata_start < rodata_end
+            && aligned(rodata_end)
+            && rodata_end <= data_start
+            && aligned(data_start)
+            && data_start < data_end
+            && data_end <= bss_start
+            && bss_start <= bss_end
+            && bss_end <= ini1
+            && ini1
+                .checked_add(INI1_HEADER_SIZE)
+                .map_or(false, |end| end <= kernel.len())
+            && &kernel[ini1..ini1 + 4] == b"INI1";
+        if valid {
+            Some(ini1)
+        } else {
+            None
+        }
+    })
+}
+
+/// A decrypted Package2.
+#[derive(Debug, Clone)]
+pub struct Package2 {
+    signature: [u8; 0x100],
+    /// Metadata as stored, which is what the signature covers.
+    raw_meta: [u8; 0x100],
+    /// Decrypted metadata, its first 0x10 bytes being the counter.
+    meta: [u8; 0x100],
+    key_generation: usize,
+    sections: [Vec<u8>; PACKAGE2_SECTION_COUNT],
+    mode: ParseMode,
+}
+
+impl Package2 {
+    /// Reads a Package2, decrypting it with the first package2 key of `keys`
+    /// that gives valid metadata.
+    pub fn from_reader<R: Read>(file: R, keys: &Keys) -> Result<Package2, Error> {
+        Package2::from_reader_with_mode(file, keys, ParseMode::Lenient)
+    }
+
+    /// Same as `from_reader`, checking the package as told by `mode`. Strict
+    /// mode checks the metadata like the secure monitor does before reading
+    /// the sections: the entrypoint must be in the kernel, and the sections
+    /// can't overlap once loaded. The INI1 is then parsed in strict mode too.
+    pub fn from_reader_with_mode<R: Read>(
+        mut file: R,
+        keys: &Keys,
+        mode: ParseMode,
+    ) -> Result<Package2, Error> {
+        let mut header = [0; PACKAGE2_HEADER_SIZE];
+        file.read_exact(&mut header)?;
+        let mut signature = [0; 0x100];
+        signature.copy_from_slice(&header[..0x100]);
+        let mut raw_meta = [0; 0x100];
+        raw_meta.copy_from_slice(&header[0x100..]);
+        let mut ctr = [0; 0x10];
+        ctr.copy_from_slice(&raw_meta[..0x10]);
+
+        let mut found = None;
+        let mut tried = false;
+        for generation in 0..0x20 {
+            let key = match keys.package2_key(generation) {
+                Ok(key) => key,
+                Err(_) => continue,
+            };
+            tried = true;
+            let mut meta = raw_meta;
+            AesCtr::with_counter(key.clone(), ctr).apply_keystream(&mut meta, 0);
+            if &meta[0x50..0x54] == b"PK21" {
+                // The counter stays as is.
+                meta[..0x10].copy_from_slice(&ctr);
+                found = Some((generation, meta));
+                break;
+            }
+        }
+        let (key_generation, meta) = match found {
+            Some(found) => found,
+            None if !tried => return Err(keys.package2_key(0).unwrap_err()),
+            None => return Err(invalid_package2("no package2 key decrypts the header")),
+        };
+        let mut package2 = Package2 {
+            signature,
+            raw_meta,
+            meta,
+            key_generation,
+            sections: Default::default(),
+            mode,
+        };
+
+        let package_size = package2.package_size();
+        let sections_size: usize = (0..PACKAGE2_SECTION_COUNT)
+            .map(|i| package2.section_size(i))
+            .sum();
+        if package_size <= PACKAGE2_HEADER_SIZE
+            || package_size > PACKAGE2_SIZE_MAX
+            || PACKAGE2_HEADER_SIZE + sections_size > package_size
+        {
+            return Err(invalid_package2(
+                "section sizes don't match the package size",
+            ));
+        }
+        if mode.is_strict() {
+            package2.check_layout()?;
+        }
+
+        let key = keys.package2_key(key_generation)?;
+        for i in 0..PACKAGE2_SECTION_COUNT {
+            let mut section = vec![0; package2.section_size(i)];
+            file.read_exact(&mut section)?;
+            AesCtr::with_counter(key.clone(), package2.section_ctr(i))
+                .apply_keystream(&mut section, 0);
+            package2.sections[i] = section;
+        }
+        Ok(package2)
+    }
+
+    /// Checks the layout of the sections once loaded, in strict mode.
+    fn check_layout(&self) -> Result<(), Error> {
+        let kernel_offset = u64::from(self.section_offset(PACKAGE2_SECTION_KERNEL));
+        let kernel_size = self.section_size(PACKAGE2_SECTION_KERNEL) as u64;
+        let entrypoint = u64::from(self.entrypoint());
+        if entrypoint < kernel_offset || entrypoint >= kernel_offset + kernel_size {
+            return Err(invalid_package2("the entrypoint isn't in the kernel"));
+        }
+        let mut regions = Regions::within("the address space", 1 << 32);
+        for i in 0..PACKAGE2_SECTION_COUNT {
+            regions.add(
+                format!("section {}", i),
+                self.section_offset(i).into(),
+                self.section_size(i) as u64,
+            )?;
+        }
+        regions.check_overlaps()
+    }
+
+    /// Master key revision of the package2 key the package is encrypted with.
+    pub fn key_generation(&self) -> usize {
+        self.key_generation
+    }
+
+    fn ctr_word(&self, index: usize) -> u32 {
+        LittleEndian::read_u32(&self.meta[index * 4..])
+    }
+
+    /// Size of the package, header included.
+    pub fn package_size(&self) -> usize {
+        (self.ctr_word(0) ^ self.ctr_word(2) ^ self.ctr_word(3)) as usize
+    }
+
+    pub fn header_version(&self) -> u8 {
+        let word = self.ctr_word(1);
+        (word ^ (word >> 16) ^ (word >> 24)) as u8
+    }
+
+    /// Offset of the kernel entrypoint.
+    pub fn entrypoint(&self) -> u32 {
+        LittleEndian::read_u32(&self.meta[0x54..])
+    }
+
+    /// Highest version of the secure monitor able to boot the package.
+    pub fn version_max(&self) -> u8 {
+        self.meta[0x5C]
+    }
+
+    /// Lowest version of the secure monitor able to boot the package.
+    pub fn version_min(&self) -> u8 {
+        self.meta[0x5D]
+    }
+
+    fn section_ctr(&self, index: usize) -> [u8; 0x10] {
+        let mut ctr = [0; 0x10];
+        ctr.copy_from_slice(&self.meta[0x10 + index * 0x10..0x20 + index * 0x10]);
+        ctr
+    }
+
+    fn section_size(&self, index: usize) -> usize {
+        LittleEndian::read_u32(&self.meta[0x60 + index * 4..]) as usize
+    }
+
+    /// Offset the section is loaded at.
+    pub fn section_offset(&self, index: usize) -> u32 {
+        LittleEndian::read_u32(&self.meta[0x70 + index * 4..])
+    }
+
+    pub fn section_hash(&self, index: usize) -> &[u8] {
+        &self.meta[0x80 + index * 0x20..0xA0 + index * 0x20]
+    }
+
+    /// Decrypted data of section `index`.
+    pub fn section(&self, index: usize) -> &[u8] {
+        &self.sections[index]
+    }
+
+    pub fn kernel(&self) -> &[u8] {
+        &self.sections[PACKAGE2_SECTION_KERNEL]
+    }
+
+    /// Checks the SHA-256 of section `index` against the metadata.
+    pub fn verify_section(&self, index: usize) -> bool {
+        Sha256::digest(&self.sections[index])[..] == *self.section_hash(index)
+    }
+
+    /// Checks the signature of the header against the package2 fixed key.
+    pub fn verify_signature(&self, keys: &Keys) -> SignatureStatus {
+        const EXPONENT: [u8; 3] = [0x01, 0x00, 0x01];
+        match keys.package2_fixed_key_modulus() {
+            Ok(modulus)
+                if rsa2048_pss_verify(modulus, &EXPONENT, &self.raw_meta, &self.signature) =>
+            {
+                SignatureStatus::Valid
+            }
+            Ok(_) => SignatureStatus::Invalid,
+            Err(_) => SignatureStatus::Unchecked,
+        }
+    }
+
+    /// Offset of the INI1 in the kernel, when it's embedded in it rather than
+    /// in its own section.
+    pub fn embedded_ini1_offset(&self) -> Option<usize> {
+        if self.sections[PACKAGE2_SECTION_INI1].is_empty() {
+            find_embedded_ini1(self.kernel())
+        } else {
+            None
+        }
+    }
+
+    pub fn ini1(&self) -> Result<Ini1, Error> {
+        if !self.sections[PACKAGE2_SECTION_INI1].is_empty() {
+            return Ini1::from_bytes_with_mode(&self.sections[PACKAGE2_SECTION_INI1], self.mode);
+        }
+        match self.embedded_ini1_offset() {
+            Some(offset) => Ini1::from_bytes_with_mode(&self.kernel()[offset..], self.mode),
+            None => Err(invalid_package2("there is no INI1")),
+        }
+    }
+
+    /// Replaces the INI1, in its section or at the end of the kernel.
+    pub fn set_ini1(&mut self, ini1: &Ini1) -> Result<(), Error> {
+        let mut data = ini1.to_bytes()?;
+        data.resize(align_up(data.len(), 0x10), 0);
+        if !self.sections[PACKAGE2_SECTION_INI1].is_empty() {
+            self.sections[PACKAGE2_SECTION_INI1] = data;
+            return Ok(());
+        }
+        let offset = self
+            .embedded_ini1_offset()
+            .ok_or_else(|| invalid_package2("there is no INI1"))?;
+        let old_size = Ini1::from_bytes(&self.kernel()[offset..])?.len();
+        // The INI1 can only grow or shrink if nothing follows it.
+        let rest = self.kernel().get(offset + old_size..).unwrap_or(&[]);
+        if rest.iter().any(|&v| v != 0) {
+            return Err(invalid_package2(
+                "the INI1 embedded in the kernel isn't at its end",
+            ));
+        }
+        let kernel = &mut self.sections[PACKAGE2_SECTION_KERNEL];
+        kernel.truncate(offset);
+        kernel.extend_from_slice(&data);
+        Ok(())
+    }
+
+    /// Encrypts the package again with the package2 key it was decrypted
+    /// with, updating the sizes and hashes of the sections. The signature is
+    /// kept as is, so it won't match anymore if a section was changed.
+    pub fn write<W: Write>(&self, output: &mut W, keys: &Keys) -> Result<(), Error> {
+        let key = keys.package2_key(self.key_generation)?;
+        let mut meta = self.meta;
+        let mut package_size = PACKAGE2_HEADER_SIZE;
+        for (i, section) in self.sections.iter().enumerate() {
+            LittleEndian::write_u32(&mut meta[0x60 + i * 4..], section.len() as u32);
+            meta[0x80 + i * 0x20..0xA0 + i * 0x20].copy_from_slice(&Sha256::digest(section));
+            package_size += section.len();
+        }
+        if package_size > PACKAGE2_SIZE_MAX {
+            return Err(invalid_package2("package is too big"));
+        }
+        let size_word = package_size as u32 ^ self.ctr_word(2) ^ self.ctr_word(3);
+        LittleEndian::write_u32(&mut meta[0x0..], size_word);
+        let mut ctr = [0; 0x10];
+        ctr.copy_from_slice(&meta[..0x10]);
+        AesCtr::with_counter(key.clone(), ctr).apply_keystream(&mut meta, 0);
+        meta[..0x10].copy_from_slice(&ctr);
+
+        output.write_all(&self.signature)?;
+        output.write_all(&meta)?;
+        for (i, section) in self.sections.iter().enumerate() {
+            let mut section = section.clone();
+            AesCtr::with_counter(key.clone(), self.section_ctr(i)).apply_keystream(&mut section, 0);
+            output.write_all(&section)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::format::ini1::Kip;
+
+    /// Keys holding only the package2 key of master key revision 5, made of
+    /// `byte`.
+    fn keys(byte: u8) -> Keys {
+        let key = format!("{:02x}", byte).repeat(0x10);
+        Keys::retail_from_reader(format!("package2_key_05 = {}\n", key).as_bytes()).unwrap()
+    }
+
+    /// A KIP1 named `name`, holding 0x10 bytes of .text.
+    fn kip(name: &str) -> Kip {
+        let mut data = vec![0; 0x100];
+        data[..4].copy_from_slice(b"KIP1");
+        data[0x4..0x4 + name.len()].copy_from_slice(name.as_bytes());
+        LittleEndian::write_u32(&mut data[0x24..], 0x10);
+        LittleEndian::write_u32(&mut data[0x28..], 0x10);
+        data.extend_from_slice(&[0xAA; 0x10]);
+        Kip::from_bytes(data).unwrap()
+    }
+
+    fn kip_names(ini1: &Ini1) -> Vec<String> {
+        ini1.kips.iter().map(Kip::name).collect()
+    }
+
+    /// A Package2 of 8.0.0 or later, the INI1 holding `kips` being embedded
+    /// at the end of the kernel, after the kernel map.
+    fn package2(kips: Vec<Kip>) -> Package2 {
+        let mut kernel = vec![0; 0x3000];
+        let map = [
+            0, 0x1000, 0x1000, 0x2000, 0x2000, 0x3000, 0x3000, 0x3000, 0x3000,
+        ];
+        for (i, &value) in map.iter().enumerate() {
+            LittleEndian::write_u32(&mut kernel[0x8 + i * 4..], value);
+        }
+        kernel.extend(Ini1 { kips }.to_bytes().unwrap());
+
+        let mut meta = [0; 0x100];
+        LittleEndian::write_u32(&mut meta[0x8..], 0x1234_5678);
+        LittleEndian::write_u32(&mut meta[0xC..], 0x9ABC_DEF0);
+        for (i, byte) in meta[0x10..0x50].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        meta[0x50..0x54].copy_from_slice(b"PK21");
+        LittleEndian::write_u32(&mut meta[0x54..], 0x8_0000);
+        LittleEndian::write_u32(&mut meta[0x70..], 0x8_0000);
+        Package2 {
+            signature: [0x5A; 0x100],
+            raw_meta: [0; 0x100],
+            meta,
+            key_generation: 5,
+            sections: [kernel, Vec::new(), Vec::new(), Vec::new()],
+            mode: ParseMode::Lenient,
+        }
+    }
+
+    fn write(package2: &Package2, keys: &Keys) -> Vec<u8> {
+        let mut data = Vec::new();
+        package2.write(&mut data, keys).unwrap();
+        data
+    }
+
+    #[test]
+    fn embedded_ini1_is_repacked() {
+        let keys = keys(0x42);
+        let built = package2(vec![kip("FS"), kip("Loader")]);
+        let data = write(&built, &keys);
+        assert_eq!(data.len(), PACKAGE2_HEADER_SIZE + built.kernel().len());
+        // Everything but the signature and the counter is encrypted.
+        assert_eq!(data[..0x100], [0x5A; 0x100][..]);
+        assert_ne!(data[0x150..0x154], b"PK21"[..]);
+        assert_ne!(data[PACKAGE2_HEADER_SIZE..], built.kernel()[..]);
+
+        let mut package2 = Package2::from_reader(&data[..], &keys).unwrap();
+        assert_eq!(package2.key_generation(), 5);
+        assert_eq!(package2.package_size(), data.len());
+        assert_eq!(package2.entrypoint(), 0x8_0000);
+        assert_eq!(package2.kernel(), built.kernel());
+        assert!((0..PACKAGE2_SECTION_COUNT).all(|i| package2.verify_section(i)));
+        assert_eq!(package2.embedded_ini1_offset(), Some(0x3000));
+        let mut ini1 = package2.ini1().unwrap();
+        assert_eq!(kip_names(&ini1), ["FS", "Loader"]);
+
+        ini1.set_kip(kip("sm"));
+        package2.set_ini1(&ini1).unwrap();
+        let data = write(&package2, &keys);
+        let package2 = Package2::from_reader(&data[..], &keys).unwrap();
+        assert_eq!(package2.package_size(), data.len());
+        assert!((0..PACKAGE2_SECTION_COUNT).all(|i| package2.verify_section(i)));
+        assert_eq!(kip_names(&package2.ini1().unwrap()), ["FS", "Loader", "sm"]);
+    }
+
+    #[test]
+    fn ini1_section_is_repacked() {
+        let keys = keys(0x42);
+        let mut built = package2(Vec::new());
+        built.sections[PACKAGE2_SECTION_KERNEL] = vec![0x4B; 0x1000];
+        built.sections[PACKAGE2_SECTION_INI1] = Ini1 {
+            kips: vec![kip("FS")],
+        }
+        .to_bytes()
+        .unwrap();
+        built
+            .set_ini1(&Ini1 {
+                kips: vec![kip("FS"), kip("sm")],
+            })
+            .unwrap();
+        assert_eq!(built.section(PACKAGE2_SECTION_INI1).len() % 0x10, 0);
+
+        let data = write(&built, &keys);
+        let package2 = Package2::from_reader(&data[..], &keys).unwrap();
+        assert_eq!(package2.embedded_ini1_offset(), None);
+        assert_eq!(package2.kernel(), &[0x4B; 0x1000][..]);
+        assert_eq!(kip_names(&package2.ini1().unwrap()), ["FS", "sm"]);
+    }
+
+    #[test]
+    fn embedded_ini1_must_end_the_kernel() {
+        let mut built = package2(vec![kip("FS")]);
+        built.sections[PACKAGE2_SECTION_KERNEL].extend_from_slice(&[1; 0x10]);
+        assert!(built.set_ini1(&Ini1::default()).is_err());
+    }
+
+    #[test]
+    fn packages_are_only_read_with_their_key() {
+        let data = write(&package2(vec![kip("FS")]), &keys(0x42));
+        assert!(Package2::from_reader(&data[..], &keys(0x43)).is_err());
+        assert!(Package2::from_reader(&data[..], &Keys::default()).is_err());
+    }
+
+    #[test]
+    fn strict_mode_checks_the_entrypoint() {
+        let keys = keys(0x42);
+        let mut built = package2(vec![kip("FS")]);
+        LittleEndian::write_u32(&mut built.meta[0x54..], 0x7_0000);
+        let data = write(&built, &keys);
+        Package2::from_reader(&data[..], &keys).unwrap();
+        assert!(Package2::from_reader_with_mode(&data[..], &keys, ParseMode::Strict).is_err());
+    }
+}
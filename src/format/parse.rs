@@ -0,0 +1,158 @@
+//! How much the parsers trust the files they read.
+
+use crate::error::Error;
+use snafu::Backtrace;
+use snafu::GenerateBacktrace;
+
+/// How the parsers of the container formats check the files they read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseMode {
+    /// Accepts any file the data can be read from, like the console does.
+    /// Offsets and sizes are only checked when the data is read.
+    Lenient,
+    /// For untrusted files, like ones downloaded from the internet. Every
+    /// count, offset and size is checked against the size of the file before
+    /// anything is allocated for it, and regions of the file overlapping each
+    /// other are rejected.
+    Strict,
+}
+
+impl Default for ParseMode {
+    fn default() -> ParseMode {
+        ParseMode::Lenient
+    }
+}
+
+impl ParseMode {
+    pub fn is_strict(self) -> bool {
+        self == ParseMode::Strict
+    }
+}
+
+/// Whether `name`, the name of an entry of an image, can be used as a file
+/// name when extracting it: it isn't empty, `.` or `..`, and has no path
+/// separator or NUL in it, so it can't escape the directory it is written to.
+pub fn is_valid_entry_name(name: &str) -> bool {
+    !matches!(name, "" | "." | "..") && !name.contains(&['/', '\\', '\0'][..])
+}
+
+/// The regions of a file found by a strict parser, checked to be in the file
+/// and not to overlap each other.
+pub(crate) struct Regions {
+    /// What the regions are in, `the file` unless given to `within`.
+    container: String,
+    file_size: u64,
+    /// The start, end and name of the regions, and whether they can be
+    /// shared with regions of the same bounds.
+    regions: Vec<(u64, u64, String, bool)>,
+}
+
+fn invalid_region(region: &str, start: u64, end: u64, error: String) -> Error {
+    Error::InvalidRegion {
+        region: region.to_string(),
+        start,
+        end,
+        error,
+        backtrace: Backtrace::generate(),
+    }
+}
+
+impl Regions {
+    pub fn new(file_size: u64) -> Regions {
+        Regions::within("the file", file_size)
+    }
+
+    /// Same as `new`, for regions in a part of the file, e.g. `section 0`.
+    pub fn within<S: Into<String>>(container: S, size: u64) -> Regions {
+        Regions {
+            container: container.into(),
+            file_size: size,
+            regions: Vec::new(),
+        }
+    }
+
+    /// Records the region named `region`, failing if it isn't in the file.
+    pub fn add<S: Into<String>>(&mut self, region: S, offset: u64, size: u64) -> Result<(), Error> {
+        self.push(region.into(), offset, size, false)
+    }
+
+    /// Same as `add`, for regions other shared regions may have the exact
+    /// same bounds as, like the data of deduplicated files.
+    pub fn add_shared<S: Into<String>>(
+        &mut self,
+        region: S,
+        offset: u64,
+        size: u64,
+    ) -> Result<(), Error> {
+        self.push(region.into(), offset, size, true)
+    }
+
+    fn push(&mut self, region: String, offset: u64, size: u64, shared: bool) -> Result<(), Error> {
+        match offset.checked_add(size) {
+            Some(end) if end <= self.file_size => {
+                self.regions.push((offset, end, region, shared));
+                Ok(())
+            }
+            end => Err(invalid_region(
+                &region,
+                offset,
+                end.unwrap_or(u64::MAX),
+                format!(
+                    "is out of {}, which is {:#x} bytes",
+                    self.container, self.file_size
+                ),
+            )),
+        }
+    }
+
+    /// Fails if two of the regions overlap. Empty regions never do.
+    pub fn check_overlaps(&mut self) -> Result<(), Error> {
+        self.regions.retain(|(start, end, _, _)| start != end);
+        self.regions.sort_by_key(|&(start, end, _, _)| (start, end));
+        self.regions
+            .dedup_by(|a, b| (a.0, a.1) == (b.0, b.1) && a.3 && b.3);
+        for pair in self.regions.windows(2) {
+            let (start, end, region, _) = &pair[1];
+            if *start < pair[0].1 {
+                return Err(invalid_region(
+                    region,
+                    *start,
+                    *end,
+                    format!("overlaps {}", pair[0].2),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn regions_must_be_in_the_file_without_overlapping() {
+        let mut regions = Regions::new(0x100);
+        regions.add("header", 0, 0x10).unwrap();
+        assert!(regions.add("table", 0xF0, 0x20).is_err());
+        assert!(regions.add("table", u64::MAX, 2).is_err());
+        regions.add("table", 0x10, 0x20).unwrap();
+        regions.add("empty", 0x8, 0).unwrap();
+        regions.add_shared("file a", 0x40, 0x10).unwrap();
+        regions.add_shared("file b", 0x40, 0x10).unwrap();
+        regions.check_overlaps().unwrap();
+
+        regions.add("file c", 0x20, 0x10).unwrap();
+        let err = regions.check_overlaps().unwrap_err();
+        assert_eq!(err.offset(), Some(0x20));
+    }
+
+    #[test]
+    fn entry_names_cant_be_paths() {
+        assert!(is_valid_entry_name("main.npdm"));
+        assert!(is_valid_entry_name("..data"));
+        for name in &["", ".", "..", "a/b", "/etc", "..\\x", "a\0b"] {
+            assert!(!is_valid_entry_name(name), "{:?}", name);
+        }
+    }
+}
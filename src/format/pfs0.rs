@@ -1,176 +1,354 @@
-use crate::error::Error;
-use crate::format::utils;
+use crate::error::{Error, ResultExt};
+use crate::filter::{PathFilter, SymlinkPolicy};
+use crate::format::container::{ContainerEntry, SwitchFormat};
+use crate::format::detect::FileFormat;
+use crate::format::parse::{is_valid_entry_name, ParseMode, Regions};
+use crate::format::utils::serialize_hex_bytes;
+use crate::format::vfs::Vfs;
 use crate::utils::{align_up, ReadRange, TryClone};
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use serde_derive::Serialize;
 use snafu::Backtrace;
 use snafu::GenerateBacktrace;
+use std::collections::HashSet;
 use std::fs::File;
 use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
 pub trait ReadSeek: Read + Seek {}
 
 impl<T: Read + Seek> ReadSeek for T {}
 
 enum Pfs0Meta {
-    HostPath(PathBuf),
+    HostPath {
+        path: PathBuf,
+        name: String,
+    },
     SubFile {
         file: Box<dyn ReadSeek>,
         name: String,
         size: u64,
     },
+    /// A file of a `Vfs`, opened when needed.
+    Vfs {
+        vfs: Rc<dyn Vfs>,
+        path: String,
+        name: String,
+        size: u64,
+    },
 }
 
 impl Pfs0Meta {
     fn file_name(&self) -> &str {
         match self {
-            Pfs0Meta::HostPath(path) => path.file_name().unwrap().to_str().unwrap(),
-            Pfs0Meta::SubFile { ref name, .. } => name,
+            Pfs0Meta::HostPath { name, .. }
+            | Pfs0Meta::SubFile { name, .. }
+            | Pfs0Meta::Vfs { name, .. } => name,
         }
     }
+
+    fn open(self) -> io::Result<Pfs0File> {
+        let name = self.file_name().into();
+        let file = match self {
+            Pfs0Meta::HostPath { path, .. } => {
+                File::open(path).map(|v| Box::new(v) as Box<dyn ReadSeek>)
+            }
+            Pfs0Meta::SubFile { mut file, .. } => file.seek(SeekFrom::Start(0)).map(|_| file),
+            Pfs0Meta::Vfs { vfs, path, .. } => vfs.open(&path),
+        };
+        file.map(|file| Pfs0File { name, file })
+    }
 }
 
+#[derive(Default)]
 pub struct Pfs0 {
     files: Vec<Pfs0Meta>,
 }
 
 impl Pfs0 {
+    /// Creates an empty PFS0.
+    pub fn new() -> Self {
+        Pfs0::default()
+    }
+
     pub fn from_directory(input: &str) -> std::io::Result<Self> {
+        Pfs0::from_directory_with_filter(input, &PathFilter::default())
+    }
+
+    /// Same as `from_directory`, but skips the files rejected by `filter`.
+    pub fn from_directory_with_filter(input: &str, filter: &PathFilter) -> std::io::Result<Self> {
+        Pfs0::from_directory_with_options(input, filter, SymlinkPolicy::Follow)
+    }
+
+    /// Same as `from_directory_with_filter`, handling the symbolic links found
+    /// in `input` according to `symlinks`.
+    pub fn from_directory_with_options(
+        input: &str,
+        filter: &PathFilter,
+        symlinks: SymlinkPolicy,
+    ) -> std::io::Result<Self> {
         let path = PathBuf::from(input);
         let mut files = Vec::new();
         for entry_res in std::fs::read_dir(path)? {
             let entry = entry_res?;
             let entry_path = &entry.path();
+            if entry.file_type()?.is_symlink() {
+                match symlinks {
+                    SymlinkPolicy::Follow => (),
+                    SymlinkPolicy::Skip => {
+                        log::info!("Skipping symlink \"{}\"", entry_path.display());
+                        continue;
+                    }
+                    SymlinkPolicy::Error => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("Can't handle symlink \"{}\"", entry_path.display()),
+                        ))
+                    }
+                }
+            }
             if entry_path.is_dir() {
-                println!("Ignoring directory \"{}\"", entry_path.display());
+                log::info!("Ignoring directory \"{}\"", entry_path.display());
+            } else if filter.is_file_excluded(Path::new(&entry.file_name())) {
+                log::info!("Excluding \"{}\"", entry_path.display());
             } else {
-                files.push(Pfs0Meta::HostPath(entry_path.clone()));
+                let name = entry.file_name().into_string().map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{} has no valid file name", entry_path.display()),
+                    )
+                })?;
+                files.push(Pfs0Meta::HostPath {
+                    path: entry_path.clone(),
+                    name,
+                });
             }
         }
         Ok(Pfs0 { files })
     }
 
+    /// Creates a PFS0 holding the files at the root of `vfs`. Directories are
+    /// ignored, as PFS0s can't hold any.
+    pub fn from_vfs(vfs: Rc<dyn Vfs>) -> std::io::Result<Self> {
+        Pfs0::from_vfs_with_filter(vfs, &PathFilter::default())
+    }
+
+    /// Same as `from_vfs`, but skips the files rejected by `filter`.
+    pub fn from_vfs_with_filter(vfs: Rc<dyn Vfs>, filter: &PathFilter) -> std::io::Result<Self> {
+        let mut pfs0 = Pfs0::new();
+        pfs0.push_vfs_with_filter(vfs, filter)?;
+        Ok(pfs0)
+    }
+
+    /// Opens a PFS0, or an HFS0, the PFS0 of gamecards whose entries also
+    /// hash the start of their file. Those hashes are ignored.
     pub fn from_reader<R: Read + Seek + TryClone + 'static>(f: R) -> Result<Self, Error> {
-        // Header
-        let mut f = std::io::BufReader::new(f);
-        let mut magic = [0; 4];
-        f.read_exact(&mut magic)?;
-        if &magic != b"PFS0" {
-            return Err(Error::InvalidPfs0 {
-                error: "magic is wrong",
-                backtrace: Backtrace::generate(),
+        Pfs0::from_reader_with_mode(f, ParseMode::Lenient)
+    }
+
+    /// Same as `from_reader`, checking the file as told by `mode`. Strict mode
+    /// also rejects empty and duplicate file names.
+    pub fn from_reader_with_mode<R: Read + Seek + TryClone + 'static>(
+        f: R,
+        mode: ParseMode,
+    ) -> Result<Self, Error> {
+        let entries = read_entries(&mut std::io::BufReader::new(f.try_clone()?), mode)?;
+        let mut files = Vec::with_capacity(entries.len());
+        for entry in entries {
+            files.push(Pfs0Meta::SubFile {
+                file: Box::new(ReadRange::new(f.try_clone()?, entry.offset, entry.size)),
+                name: entry.name,
+                size: entry.size,
             });
         }
+        Ok(Pfs0 { files })
+    }
+
+    /// Adds a file from the host filesystem to this PFS0. Its content is only
+    /// read when writing the PFS0, so this is cheap on PFS0s opened with
+    /// `from_reader`: existing entries are copied straight from the source.
+    pub fn push_file(&mut self, path: PathBuf) -> std::io::Result<()> {
+        let name = match path.file_name().and_then(|v| v.to_str()) {
+            Some(name) => name.to_string(),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} has no valid file name", path.display()),
+                ))
+            }
+        };
+        self.check_new_name(&name)?;
+        self.files.push(Pfs0Meta::HostPath { path, name });
+        Ok(())
+    }
+
+    /// Adds a file named `name` holding `data` to this PFS0.
+    pub fn push_data(&mut self, name: String, data: Vec<u8>) -> std::io::Result<()> {
+        self.check_new_name(&name)?;
+        self.files.push(Pfs0Meta::SubFile {
+            size: data.len() as u64,
+            file: Box::new(io::Cursor::new(data)),
+            name,
+        });
+        Ok(())
+    }
+
+    /// Adds a file named `name` whose content is read from `reader` when
+    /// writing the PFS0.
+    pub fn push_reader<R: Read + Seek + 'static>(
+        &mut self,
+        name: String,
+        mut reader: R,
+    ) -> std::io::Result<()> {
+        self.check_new_name(&name)?;
+        let size = reader.seek(SeekFrom::End(0))?;
+        self.files.push(Pfs0Meta::SubFile {
+            file: Box::new(reader),
+            name,
+            size,
+        });
+        Ok(())
+    }
 
-        let filecount = f.read_u32::<LittleEndian>()?;
-        let string_table_size = f.read_u32::<LittleEndian>()?;
-        let _zero = f.read_u32::<LittleEndian>()?;
-        let mut files = Vec::with_capacity(filecount as usize);
+    /// Adds the files at the root of `vfs` to this PFS0, ignoring its
+    /// directories. Their content is only read when writing the PFS0.
+    pub fn push_vfs(&mut self, vfs: Rc<dyn Vfs>) -> std::io::Result<()> {
+        self.push_vfs_with_filter(vfs, &PathFilter::default())
+    }
 
-        let string_table_offset = 0x10 + filecount as u64 * 0x18;
-        let data_offset = string_table_offset + string_table_size as u64;
+    fn push_vfs_with_filter(&mut self, vfs: Rc<dyn Vfs>, filter: &PathFilter) -> io::Result<()> {
+        for entry in vfs.read_dir("")? {
+            if entry.metadata.is_dir {
+                log::info!("Ignoring directory \"{}\"", entry.name);
+                continue;
+            }
+            if filter.is_file_excluded(Path::new(&entry.name)) {
+                log::info!("Excluding \"{}\"", entry.name);
+                continue;
+            }
+            self.check_new_name(&entry.name)?;
+            self.files.push(Pfs0Meta::Vfs {
+                vfs: vfs.clone(),
+                path: entry.name.clone(),
+                name: entry.name,
+                size: entry.metadata.size,
+            });
+        }
+        Ok(())
+    }
 
-        for _ in 0..filecount {
-            let offset = data_offset + f.read_u64::<LittleEndian>()?;
-            let size = f.read_u64::<LittleEndian>()?;
-            let filename_offset = string_table_offset + f.read_u32::<LittleEndian>()? as u64;
-            let _zero = f.read_u32::<LittleEndian>()?;
-            files.push((offset, size, filename_offset));
+    fn check_new_name(&self, name: &str) -> std::io::Result<()> {
+        if self.files.iter().any(|v| v.file_name() == name) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("An entry named {} already exists", name),
+            ));
         }
+        Ok(())
+    }
 
-        let mut finalfiles = Vec::with_capacity(filecount as usize);
-        for (offset, size, filename_offset) in files {
-            f.seek(SeekFrom::Start(filename_offset as u64))?;
-            let mut filename = Vec::new();
-            f.read_until(b'\0', &mut filename)?;
-            filename.pop();
-            let filename = String::from_utf8(filename)?;
-            finalfiles.push(Pfs0Meta::SubFile {
-                file: Box::new(ReadRange::new(f.get_ref().try_clone()?, offset, size)),
-                name: filename,
+    /// Computes where every file will end up in the PFS0, without reading
+    /// any of them. Files are sorted by name, the way `write_pfs0` writes
+    /// them.
+    pub fn layout(&self) -> std::io::Result<Pfs0Layout> {
+        let mut files = self.files.iter().collect::<Vec<_>>();
+        files.sort_by_key(|v| v.file_name());
+
+        let header_size = 0x10 + 0x18 * files.len() as u64;
+        let names_size: u64 = files.iter().map(|x| x.file_name().len() as u64 + 1).sum();
+        // The Nintendo tools pad the string table so the data starts aligned
+        // to 0x20. Readers expect the string table right after the file table.
+        let data_offset = align_up(header_size + names_size, 0x20);
+
+        let mut entries = Vec::with_capacity(files.len());
+        let mut offset = data_offset;
+        let mut name_offset = 0;
+        for file in files {
+            let size = match file {
+                Pfs0Meta::HostPath { path, .. } => std::fs::metadata(path)?.len(),
+                Pfs0Meta::SubFile { size, .. } | Pfs0Meta::Vfs { size, .. } => *size,
+            };
+            entries.push(Pfs0Entry {
+                name: file.file_name().to_string(),
+                offset,
                 size,
+                name_offset,
+                hash: None,
             });
+            offset += size;
+            name_offset += file.file_name().len() as u32 + 1;
         }
-        Ok(Pfs0 { files: finalfiles })
+
+        Ok(Pfs0Layout {
+            header_size,
+            string_table_size: data_offset - header_size,
+            string_table_padding: data_offset - header_size - names_size,
+            data_offset,
+            total_size: offset,
+            files: entries,
+        })
     }
 
     pub fn write_pfs0<T>(&mut self, output_writter: &mut T) -> std::io::Result<()>
     where
         T: Write + Seek,
     {
-        let files = &mut self.files;
-        files.sort_by_key(|v| v.file_name().to_string());
-        let file_count = files.len() as u32;
+        self.files.sort_by_key(|v| v.file_name().to_string());
+        let layout = self.layout()?;
+        let file_count = layout.files.len();
 
         // Header
         output_writter.write_all(b"PFS0")?;
-        output_writter.write_u32::<LittleEndian>(file_count)?;
-        let string_table_size =
-            utils::align(files.iter().map(|x| x.file_name().len() + 1).sum(), 0x1F);
-        output_writter.write_u32::<LittleEndian>(string_table_size as u32)?;
+        output_writter.write_u32::<LittleEndian>(file_count as u32)?;
+        output_writter.write_u32::<LittleEndian>(layout.string_table_size as u32)?;
         output_writter.write_u32::<LittleEndian>(0)?;
 
-        let file_table_size = 0x18 * files.len() as u64;
-        // The Nintendo tools align the string table to 0x20.
-        let string_table_pos: u64 = align_up(0x10 + file_table_size, 0x20);
-        let data_pos: u64 = string_table_pos + (string_table_size as u64);
-
-        // Create empty tabes
-        let mut empty_tables = Vec::new();
-        empty_tables.resize(data_pos as usize - 0x10, 0);
-        output_writter.write_all(&empty_tables)?;
-
-        let mut string_offset = 0;
-        let mut data_offset = 0;
+        // File entry table, with offsets relative to the data.
+        for entry in &layout.files {
+            output_writter.write_u64::<LittleEndian>(entry.offset - layout.data_offset)?;
+            output_writter.write_u64::<LittleEndian>(entry.size)?;
+            output_writter.write_u32::<LittleEndian>(entry.name_offset)?;
+            output_writter.write_u32::<LittleEndian>(0)?;
+        }
 
-        for (file_index, file) in files
-            .iter_mut()
-            .enumerate()
-            .map(|(idx, path)| (idx as u64, path))
-        {
-            // Seek and write file name to string table
-            output_writter.seek(SeekFrom::Start(string_table_pos + string_offset))?;
+        // String table
+        for entry in &layout.files {
+            output_writter.write_all(entry.name.as_bytes())?;
+            output_writter.write_all(b"\0")?;
+        }
+        output_writter.write_all(&vec![0; layout.string_table_padding as usize])?;
 
-            println!(
+        for (file_index, (file, entry)) in self.files.iter_mut().zip(&layout.files).enumerate() {
+            log::debug!(
                 "Writing {}... [{}/{}]",
-                file.file_name(),
+                entry.name,
                 file_index + 1,
                 file_count
             );
-            output_writter.write_all(file.file_name().as_bytes())?;
-            output_writter.write_all(b"\0")?;
 
             let mut host_file;
-
-            let (file, file_size, file_name) = match file {
-                Pfs0Meta::HostPath(path) => {
-                    // Open the file and retrieve the size of it
+            let mut vfs_file;
+            let file = match file {
+                Pfs0Meta::HostPath { path, .. } => {
                     host_file = File::open(&path)?;
-                    let file_size = host_file.metadata()?.len();
-                    let name = path.file_name().unwrap().to_str().unwrap();
-                    (&mut host_file as &mut dyn ReadSeek, file_size, name)
+                    &mut host_file as &mut dyn ReadSeek
+                }
+                Pfs0Meta::SubFile { file, .. } => file as &mut dyn ReadSeek,
+                Pfs0Meta::Vfs { vfs, path, .. } => {
+                    vfs_file = vfs.open(path)?;
+                    &mut *vfs_file as &mut dyn ReadSeek
                 }
-                Pfs0Meta::SubFile {
-                    file, name, size, ..
-                } => (file as &mut dyn ReadSeek, *size, &**name),
             };
 
-            // Write file entry to the file entry table
-            output_writter.seek(SeekFrom::Start(0x10 + (file_index * 0x18)))?;
-
-            output_writter.write_u64::<LittleEndian>(data_offset)?;
-            output_writter.write_u64::<LittleEndian>(file_size)?;
-            output_writter.write_u64::<LittleEndian>(string_offset)?;
-
-            // Write the actual file content
-            output_writter.seek(SeekFrom::Start(data_pos + data_offset))?;
-
             file.seek(SeekFrom::Start(0))?;
             let size = io::copy(file, output_writter)?;
-            assert_eq!(size, file_size);
-
-            data_offset += file_size;
-            string_offset += file_name.len() as u64 + 1;
+            if size != entry.size {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("{} changed while building the PFS0", entry.name),
+                ));
+            }
         }
 
         Ok(())
@@ -179,6 +357,228 @@ impl Pfs0 {
     pub fn files(self) -> impl Iterator<Item = io::Result<Pfs0File>> + 'static {
         Pfs0FileIterator { pfs0: self }
     }
+
+    /// Opens the entry named `name`, without reading any of the other entries.
+    /// Returns `None` if there is no such entry.
+    pub fn into_file(mut self, name: &str) -> Option<io::Result<Pfs0File>> {
+        let idx = self.files.iter().position(|v| v.file_name() == name)?;
+        Some(self.files.swap_remove(idx).open())
+    }
+}
+
+/// Entries are named by their file name.
+impl SwitchFormat for Pfs0 {
+    fn detect(header: &[u8]) -> bool {
+        matches!(
+            FileFormat::detect(header),
+            Some(FileFormat::Pfs0) | Some(FileFormat::Hfs0)
+        )
+    }
+
+    fn list_entries(&self) -> Result<Vec<ContainerEntry>, Error> {
+        let mut entries = Vec::with_capacity(self.files.len());
+        for file in &self.files {
+            let size = match file {
+                Pfs0Meta::HostPath { path, .. } => std::fs::metadata(path)?.len(),
+                Pfs0Meta::SubFile { size, .. } | Pfs0Meta::Vfs { size, .. } => *size,
+            };
+            entries.push(ContainerEntry {
+                name: file.file_name().to_string(),
+                size,
+            });
+        }
+        Ok(entries)
+    }
+
+    fn extract_entry(&mut self, name: &str) -> Result<Box<dyn ReadSeek + '_>, Error> {
+        let file = match self.files.iter_mut().find(|v| v.file_name() == name) {
+            Some(file) => file,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("No entry named {} in the PFS0", name),
+                )
+                .into())
+            }
+        };
+        match file {
+            Pfs0Meta::HostPath { path, .. } => Ok(Box::new(File::open(path)?)),
+            Pfs0Meta::SubFile { file, .. } => {
+                file.seek(SeekFrom::Start(0))?;
+                Ok(Box::new(&mut **file))
+            }
+            Pfs0Meta::Vfs { vfs, path, .. } => Ok(vfs.open(path)?),
+        }
+    }
+
+    fn metadata(&self) -> Result<serde_json::Value, Error> {
+        Ok(serde_json::json!({ "files": self.files.len() }))
+    }
+}
+
+/// An entry of the file table of a PFS0 or HFS0.
+#[derive(Debug, Clone, Serialize)]
+pub struct Pfs0Entry {
+    pub name: String,
+    /// Offset of the file from the start of the PFS0, rather than from the
+    /// start of the data like in the entry.
+    pub offset: u64,
+    pub size: u64,
+    /// Offset of the name in the string table.
+    pub name_offset: u32,
+    /// The hash of HFS0 entries.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<Hfs0Hash>,
+}
+
+/// Where everything ends up in a PFS0, as returned by `Pfs0::layout`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Pfs0Layout {
+    /// Size of the header and of the file entry table.
+    pub header_size: u64,
+    pub string_table_size: u64,
+    /// Zeroes at the end of the string table, aligning the data to 0x20.
+    pub string_table_padding: u64,
+    pub data_offset: u64,
+    pub total_size: u64,
+    /// Every file, sorted by name.
+    pub files: Vec<Pfs0Entry>,
+}
+
+/// The hash of the start of a file, found in the entries of HFS0s.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Hfs0Hash {
+    /// Size of the hashed region at the start of the file.
+    pub hashed_size: u32,
+    #[serde(serialize_with = "serialize_hex_bytes")]
+    pub sha256: [u8; 0x20],
+}
+
+/// Reads the file table of the PFS0 or HFS0 in `f`, checking it as told by
+/// `mode`.
+pub fn read_entries<R: BufRead + Seek>(
+    f: &mut R,
+    mode: ParseMode,
+) -> Result<Vec<Pfs0Entry>, Error> {
+    // Header
+    f.seek(SeekFrom::Start(0))?;
+    let mut magic = [0; 4];
+    f.read_exact(&mut magic)?;
+    let entry_size = match &magic {
+        b"PFS0" => 0x18,
+        b"HFS0" => 0x40,
+        _ => return Err(invalid_pfs0("magic is wrong")),
+    };
+
+    let filecount = f.read_u32::<LittleEndian>()?;
+    let string_table_size = f.read_u32::<LittleEndian>()?;
+    let _zero = f.read_u32::<LittleEndian>()?;
+    // The count isn't trusted for allocations, it may come from a corrupted file.
+    let mut files = Vec::new();
+
+    let string_table_offset = 0x10 + filecount as u64 * entry_size;
+    let data_offset = string_table_offset + string_table_size as u64;
+
+    // In strict mode the tables are checked against the size of the file
+    // before reading them, and the string table is read in memory to find the
+    // names in it.
+    let mut regions = None;
+    let mut string_table = None;
+    if mode.is_strict() {
+        let mut strict_regions = Regions::new(f.seek(SeekFrom::End(0))?);
+        strict_regions.add("header", 0, 0x10)?;
+        strict_regions.add("entry table", 0x10, string_table_offset - 0x10)?;
+        strict_regions.add(
+            "string table",
+            string_table_offset,
+            string_table_size.into(),
+        )?;
+        let mut table = vec![0; string_table_size as usize];
+        f.seek(SeekFrom::Start(string_table_offset))?;
+        f.read_exact(&mut table)?;
+        f.seek(SeekFrom::Start(0x10))?;
+        regions = Some(strict_regions);
+        string_table = Some(table);
+    }
+
+    let mut entry = vec![0; entry_size as usize];
+    for idx in 0..filecount as u64 {
+        f.read_exact(&mut entry)
+            .map_err(Error::from)
+            .with_offset(0x10 + idx * entry_size)?;
+        let offset = data_offset
+            .checked_add(LittleEndian::read_u64(&entry[0x0..]))
+            .ok_or_else(|| invalid_pfs0("file offset is out of bounds"))
+            .with_offset(0x10 + idx * entry_size)?;
+        let size = LittleEndian::read_u64(&entry[0x8..]);
+        let filename_offset = LittleEndian::read_u32(&entry[0x10..]);
+        let hash = if entry_size == 0x40 {
+            let mut sha256 = [0; 0x20];
+            sha256.copy_from_slice(&entry[0x20..0x40]);
+            Some(Hfs0Hash {
+                hashed_size: LittleEndian::read_u32(&entry[0x14..]),
+                sha256,
+            })
+        } else {
+            None
+        };
+        files.push((offset, size, filename_offset, hash));
+    }
+
+    let mut entries = Vec::with_capacity(files.len());
+    let mut names = HashSet::new();
+    for (offset, size, name_offset, hash) in files {
+        let filename_offset = u64::from(name_offset);
+        let filename = match &string_table {
+            Some(table) => {
+                let name = table
+                    .get(filename_offset as usize..)
+                    .and_then(|name| name.iter().position(|&c| c == 0).map(|len| &name[..len]))
+                    .filter(|name| !name.is_empty())
+                    .ok_or_else(|| invalid_pfs0("file name isn't in the string table"))
+                    .with_offset(string_table_offset + filename_offset)?;
+                name.to_vec()
+            }
+            None => {
+                f.seek(SeekFrom::Start(string_table_offset + filename_offset))?;
+                let mut filename = Vec::new();
+                f.read_until(b'\0', &mut filename)?;
+                filename.pop();
+                filename
+            }
+        };
+        let filename = String::from_utf8(filename)
+            .map_err(Error::from)
+            .with_offset(string_table_offset + filename_offset)?;
+        if let Some(regions) = &mut regions {
+            if !is_valid_entry_name(&filename) {
+                return Err(invalid_pfs0("file names can't be paths"))
+                    .with_offset(string_table_offset + filename_offset);
+            }
+            if !names.insert(filename.clone()) {
+                return Err(invalid_pfs0("file names must be unique"));
+            }
+            regions.add(format!("file {}", filename), offset, size)?;
+        }
+        entries.push(Pfs0Entry {
+            name: filename,
+            offset,
+            size,
+            name_offset,
+            hash,
+        });
+    }
+    if let Some(regions) = &mut regions {
+        regions.check_overlaps()?;
+    }
+    Ok(entries)
+}
+
+fn invalid_pfs0(error: &'static str) -> Error {
+    Error::InvalidPfs0 {
+        error,
+        backtrace: Backtrace::generate(),
+    }
 }
 
 pub struct Pfs0File {
@@ -212,17 +612,209 @@ impl Iterator for Pfs0FileIterator {
     type Item = io::Result<Pfs0File>;
 
     fn next(&mut self) -> Option<io::Result<Pfs0File>> {
-        if let Some(meta) = self.pfs0.files.pop() {
-            let name = meta.file_name().into();
-            let file = match meta {
-                Pfs0Meta::HostPath(path) => {
-                    File::open(path).map(|v| Box::new(v) as Box<dyn ReadSeek>)
-                }
-                Pfs0Meta::SubFile { mut file, .. } => file.seek(SeekFrom::Start(0)).map(|_| file),
-            };
-            Some(file.map(|file| Pfs0File { name, file }))
-        } else {
-            None
+        self.pfs0.files.pop().map(Pfs0Meta::open)
+    }
+}
+
+/// Builds a PFS0, like the ExeFS of a program or an NSP. Files are sorted by
+/// name when writing it, and their names must be unique.
+#[derive(Default)]
+pub struct Pfs0Builder {
+    pfs0: Pfs0,
+}
+
+impl Pfs0Builder {
+    pub fn new() -> Pfs0Builder {
+        Pfs0Builder::default()
+    }
+
+    /// Adds a file named `name` holding `data`.
+    pub fn add_data(&mut self, name: &str, data: Vec<u8>) -> Result<&mut Pfs0Builder, Error> {
+        self.pfs0.push_data(name.to_string(), data)?;
+        Ok(self)
+    }
+
+    /// Adds a file named `name` whose content will be read from `reader` when
+    /// the PFS0 gets written.
+    pub fn add_file<R: Read + Seek + 'static>(
+        &mut self,
+        name: &str,
+        reader: R,
+    ) -> Result<&mut Pfs0Builder, Error> {
+        self.pfs0.push_reader(name.to_string(), reader)?;
+        Ok(self)
+    }
+
+    /// Adds a file from the host filesystem, named after its file name.
+    pub fn add_host_file<P: AsRef<Path>>(&mut self, path: P) -> Result<&mut Pfs0Builder, Error> {
+        self.pfs0.push_file(path.as_ref().to_path_buf())?;
+        Ok(self)
+    }
+
+    /// Adds the files at the root of `vfs`, named after their file name.
+    pub fn add_vfs(&mut self, vfs: Rc<dyn Vfs>) -> Result<&mut Pfs0Builder, Error> {
+        self.pfs0.push_vfs(vfs)?;
+        Ok(self)
+    }
+
+    /// Computes the layout of the PFS0 before writing it, e.g. to check its
+    /// size against a budget.
+    pub fn plan(&self) -> Result<Pfs0Layout, Error> {
+        Ok(self.pfs0.layout()?)
+    }
+
+    pub fn build<W: Write + Seek>(mut self, writer: &mut W) -> Result<(), Error> {
+        self.pfs0.write_pfs0(writer)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pfs0_round_trips_in_memory() {
+        let mut pfs0 = Pfs0::new();
+        pfs0.push_data("main".to_string(), vec![0x42; 0x30])
+            .unwrap();
+        pfs0.push_data("main.npdm".to_string(), b"META".to_vec())
+            .unwrap();
+        let mut data = io::Cursor::new(Vec::new());
+        pfs0.write_pfs0(&mut data).unwrap();
+        data.set_position(0);
+
+        let pfs0 = Pfs0::from_reader(data).unwrap();
+        let mut files = pfs0
+            .files()
+            .map(|file| {
+                let mut file = file.unwrap();
+                let mut content = Vec::new();
+                file.read_to_end(&mut content).unwrap();
+                (file.file_name().to_string(), content)
+            })
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(
+            files,
+            [
+                ("main".to_string(), vec![0x42; 0x30]),
+                ("main.npdm".to_string(), b"META".to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn plan_matches_written_pfs0() {
+        let mut builder = Pfs0Builder::new();
+        builder
+            .add_data("main.npdm", b"META".to_vec())
+            .unwrap()
+            .add_data("main", vec![0x42; 0x30])
+            .unwrap();
+        let layout = builder.plan().unwrap();
+        let mut data = io::Cursor::new(Vec::new());
+        builder.build(&mut data).unwrap();
+
+        assert_eq!(layout.total_size, data.get_ref().len() as u64);
+        assert_eq!(layout.data_offset % 0x20, 0);
+        let entries = read_entries(&mut data, ParseMode::Strict).unwrap();
+        let planned = layout
+            .files
+            .iter()
+            .map(|v| (v.name.as_str(), v.offset, v.size, v.name_offset))
+            .collect::<Vec<_>>();
+        let written = entries
+            .iter()
+            .map(|v| (v.name.as_str(), v.offset, v.size, v.name_offset))
+            .collect::<Vec<_>>();
+        assert_eq!(planned, written);
+        assert_eq!(planned[0], ("main", layout.data_offset, 0x30, 0));
+    }
+
+    #[test]
+    fn string_table_follows_file_table() {
+        // Readers find the string table right after the file table, and the
+        // data right after the string table, whatever the file count.
+        for count in 1..=5 {
+            let mut pfs0 = Pfs0::new();
+            for i in 0..count {
+                pfs0.push_data(format!("file{}", i), vec![i as u8; 3])
+                    .unwrap();
+            }
+            let mut data = io::Cursor::new(Vec::new());
+            pfs0.write_pfs0(&mut data).unwrap();
+            let data = data.into_inner();
+
+            let string_table_pos = 0x10 + 0x18 * count;
+            let string_table_size = LittleEndian::read_u32(&data[8..]) as usize;
+            let data_pos = string_table_pos + string_table_size;
+            assert_eq!(data_pos % 0x20, 0);
+            assert_eq!(&data[string_table_pos..string_table_pos + 6], b"file0\0");
+            assert_eq!(&data[data_pos..data_pos + 3], &[0; 3]);
+
+            let pfs0 = Pfs0::from_reader(io::Cursor::new(data)).unwrap();
+            assert_eq!(pfs0.files().count(), count);
+        }
+    }
+
+    #[test]
+    fn truncated_entry_table_reports_where() {
+        let mut data = b"PFS0".to_vec();
+        data.extend_from_slice(&[2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        data.extend_from_slice(&[0; 0x18]);
+        let err = Pfs0::from_reader(io::Cursor::new(data))
+            .map(|_| ())
+            .with_path("test.pfs0")
+            .unwrap_err();
+        assert_eq!(err.offset(), Some(0x28));
+        assert_eq!(err.path(), Some(Path::new("test.pfs0")));
+        assert_eq!(err.class(), crate::error::ErrorClass::Parse);
+        assert!(err.to_string().starts_with("at offset 0x28: test.pfs0: "));
+    }
+
+    #[test]
+    fn strict_mode_rejects_overlapping_files() {
+        let mut pfs0 = Pfs0::new();
+        pfs0.push_data("a".to_string(), vec![0x42; 0x20]).unwrap();
+        pfs0.push_data("b".to_string(), vec![0x43; 0x20]).unwrap();
+        let mut data = io::Cursor::new(Vec::new());
+        pfs0.write_pfs0(&mut data).unwrap();
+        let mut data = data.into_inner();
+        Pfs0::from_reader_with_mode(io::Cursor::new(data.clone()), ParseMode::Strict).unwrap();
+
+        // Makes b start in the middle of a.
+        LittleEndian::write_u64(&mut data[0x28..], 0x10);
+        Pfs0::from_reader(io::Cursor::new(data.clone())).unwrap();
+        let err = Pfs0::from_reader_with_mode(io::Cursor::new(data.clone()), ParseMode::Strict)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("overlaps file a"), "{}", err);
+
+        // A count way bigger than the file.
+        LittleEndian::write_u32(&mut data[4..], 0x1000_0000);
+        let err = Pfs0::from_reader_with_mode(io::Cursor::new(data), ParseMode::Strict)
+            .err()
+            .unwrap();
+        assert_eq!(err.offset(), Some(0x10));
+    }
+
+    #[test]
+    fn strict_mode_rejects_file_names_that_are_paths() {
+        let mut pfs0 = Pfs0::new();
+        pfs0.push_data("ab".to_string(), vec![0x42; 0x20]).unwrap();
+        let mut data = io::Cursor::new(Vec::new());
+        pfs0.write_pfs0(&mut data).unwrap();
+        let data = data.into_inner();
+
+        for name in &[b"..", b"/e", b"\\e"] {
+            let mut data = data.clone();
+            data[0x28..0x2A].copy_from_slice(*name);
+            Pfs0::from_reader(io::Cursor::new(data.clone())).unwrap();
+            let err = Pfs0::from_reader_with_mode(io::Cursor::new(data), ParseMode::Strict)
+                .err()
+                .unwrap();
+            assert_eq!(err.offset(), Some(0x28));
         }
     }
 }
@@ -1,53 +1,280 @@
 use crate::error::Error;
-use byteorder::{WriteBytesExt, LE};
+use crate::filter::{IgnoreFile, PathFilter, SymlinkPolicy, ROMFS_IGNORE_FILE};
+use crate::format::container::{ContainerEntry, SwitchFormat};
+use crate::format::detect::FileFormat;
+use crate::format::parse::{is_valid_entry_name, ParseMode, Regions};
+use crate::format::pfs0::ReadSeek;
+use crate::format::vfs::{self, Vfs};
+use crate::utils::ReadRange;
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+use globset::Glob;
+use serde_derive::Serialize;
+use sha2::{Digest, Sha256};
 use snafu::Backtrace;
 use snafu::GenerateBacktrace;
 use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::ffi::OsStr;
+use std::fmt;
 use std::fs::{self, File};
-use std::io::{self, Cursor, Write};
+use std::io::{self, BufRead, Cursor, Read, Seek, SeekFrom, Write};
 use std::mem;
 use std::path::{Path, PathBuf};
-use std::rc::{Rc, Weak};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use zip::ZipArchive;
+
+/// Biggest file read ahead of the writer by `RomFs::write_with_jobs`.
+pub const PREFETCH_MAX_SIZE: u64 = 8 * 1024 * 1024;
+
+type PrefetchTask = (PathBuf, mpsc::SyncSender<io::Result<Vec<u8>>>);
+
+/// Reads host files on worker threads, handing their content back in the
+/// order they were queued. At most `2 * jobs` files are held in memory.
+struct Prefetcher {
+    tasks: Option<mpsc::Sender<PrefetchTask>>,
+    pending: VecDeque<mpsc::Receiver<io::Result<Vec<u8>>>>,
+    queue: std::vec::IntoIter<PathBuf>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl Prefetcher {
+    fn new(paths: Vec<PathBuf>, jobs: usize) -> Prefetcher {
+        let (tasks, receiver) = mpsc::channel::<PrefetchTask>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..jobs)
+            .map(|_| {
+                let receiver = receiver.clone();
+                thread::spawn(move || loop {
+                    // The lock is only poisoned if another worker panicked.
+                    let task = match receiver.lock() {
+                        Ok(receiver) => receiver.recv(),
+                        Err(_) => break,
+                    };
+                    let (path, result) = match task {
+                        Ok(task) => task,
+                        Err(_) => break,
+                    };
+                    // The writer may have bailed out already.
+                    let _ = result.send(fs::read(&path));
+                })
+            })
+            .collect();
+
+        let mut prefetcher = Prefetcher {
+            tasks: Some(tasks),
+            pending: VecDeque::new(),
+            queue: paths.into_iter(),
+            workers,
+        };
+        for _ in 0..jobs * 2 {
+            prefetcher.queue_next();
+        }
+        prefetcher
+    }
+
+    fn queue_next(&mut self) {
+        if let (Some(path), Some(tasks)) = (self.queue.next(), &self.tasks) {
+            let (sender, receiver) = mpsc::sync_channel(1);
+            if tasks.send((path, sender)).is_ok() {
+                self.pending.push_back(receiver);
+            }
+        }
+    }
+
+    /// Returns the content of the next queued file.
+    fn next(&mut self) -> io::Result<Vec<u8>> {
+        let data = self
+            .pending
+            .pop_front()
+            .and_then(|receiver| receiver.recv().ok())
+            .unwrap_or_else(|| {
+                Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Prefetch worker stopped unexpectedly",
+                ))
+            });
+        self.queue_next();
+        data
+    }
+}
+
+impl Drop for Prefetcher {
+    fn drop(&mut self) {
+        // Closing the channel lets the workers exit once the queued tasks are
+        // done.
+        self.tasks = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// What to do with host file names that are not plain ASCII. The console's
+/// filesystem layer is picky about those, and games may fail to open such
+/// files at runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathEncodingPolicy {
+    /// Keeps valid UTF-8 names as they are, and fails on invalid UTF-8.
+    Allow,
+    /// Fails on any name that is not ASCII.
+    Error,
+    /// Prints a warning and leaves out entries whose name is not ASCII.
+    Skip,
+    /// Replaces the characters that are not ASCII with their closest ASCII
+    /// equivalent, or `_` when there is none.
+    Transliterate,
+}
+
+impl Default for PathEncodingPolicy {
+    fn default() -> PathEncodingPolicy {
+        PathEncodingPolicy::Allow
+    }
+}
+
+impl FromStr for PathEncodingPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<PathEncodingPolicy, String> {
+        match s {
+            "allow" => Ok(PathEncodingPolicy::Allow),
+            "error" => Ok(PathEncodingPolicy::Error),
+            "skip" => Ok(PathEncodingPolicy::Skip),
+            "transliterate" => Ok(PathEncodingPolicy::Transliterate),
+            _ => Err(format!(
+                "Unknown path encoding policy {}, expected one of allow, error, skip, transliterate",
+                s
+            )),
+        }
+    }
+}
+
+impl PathEncodingPolicy {
+    /// Returns the RomFS name to use for the host file `name`, or `None` if
+    /// the entry should be skipped.
+    fn convert_name(self, path: &Path, name: &OsStr) -> Result<Option<String>, Error> {
+        let error = |error| Error::RomFsPathEncoding {
+            path: path.to_owned(),
+            error,
+            backtrace: Backtrace::generate(),
+        };
+        match (self, name.to_str()) {
+            (PathEncodingPolicy::Allow, Some(name)) => Ok(Some(name.to_owned())),
+            (PathEncodingPolicy::Allow, None) => Err(error("name is not valid UTF-8")),
+            (_, Some(name)) if name.is_ascii() => Ok(Some(name.to_owned())),
+            (PathEncodingPolicy::Error, _) => Err(error("name is not ASCII")),
+            (PathEncodingPolicy::Skip, _) => Ok(None),
+            (PathEncodingPolicy::Transliterate, _) => {
+                let name = deunicode::deunicode_with_tofu(&name.to_string_lossy(), "_")
+                    .replace(|c| c == '/' || c == '\\' || c == '\0', "_");
+                Ok(Some(name))
+            }
+        }
+    }
+}
+
+/// Options controlling how host directories are turned into RomFS entries.
+#[derive(Debug, Default)]
+pub struct RomFsSourceOptions {
+    pub filter: PathFilter,
+    pub path_encoding: PathEncodingPolicy,
+    pub symlinks: SymlinkPolicy,
+}
 
 #[derive(Debug)]
 struct RomFsDirEntCtx {
-    // Only used in `RomFs::from_directory`
-    system_path: PathBuf,
     name: String,
+    /// Path of the directory, without leading slash, empty for the root.
+    path: String,
     entry_offset: u32,
-    parent: Weak<RefCell<RomFsDirEntCtx>>,
     child: Vec<Rc<RefCell<RomFsDirEntCtx>>>,
     file: Vec<Rc<RefCell<RomFsFileEntCtx>>>,
 }
 
 impl RomFsDirEntCtx {
     fn internal_path(&self) -> String {
-        let mut path = self.name.clone();
-        let mut cur = self.parent.upgrade().unwrap();
-        while cur.borrow().name != "" {
-            path = cur.borrow().name.clone() + "/" + &path;
-            let new_cur = cur.borrow().parent.upgrade().unwrap();
-            cur = new_cur;
+        self.path.clone()
+    }
+
+    /// Path of the directory named `name` in this one.
+    fn child_path(&self, name: &str) -> String {
+        if self.path.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}/{}", self.path, name)
+        }
+    }
+
+    /// Fails if this directory has a file named `name`, or a directory if
+    /// `dir` is set, as a file and a directory can't share a path.
+    fn check_name_is_free(&self, name: &str, dir: bool) -> Result<(), Error> {
+        let taken = if dir {
+            self.child.iter().any(|v| v.borrow().name == name)
+        } else {
+            self.file.iter().any(|v| v.borrow().name == name)
+        };
+        if taken {
+            return Err(Error::InvalidRomFsPath {
+                path: format!("/{}", self.child_path(name)),
+                backtrace: Backtrace::generate(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Where the content of a RomFS file comes from.
+enum RomFsFileSource {
+    HostPath(PathBuf),
+    Zip {
+        archive: Rc<RefCell<ZipArchive<File>>>,
+        index: usize,
+    },
+    /// A range of a file, such as the content of an entry in a tarball.
+    FileRange {
+        file: Rc<File>,
+        offset: u64,
+    },
+    /// Consumed when writing the RomFS, so it can only be written once.
+    Reader(Box<dyn Read>),
+    Vfs {
+        vfs: Rc<dyn Vfs>,
+        path: String,
+    },
+}
+
+impl fmt::Debug for RomFsFileSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomFsFileSource::HostPath(path) => f.debug_tuple("HostPath").field(path).finish(),
+            RomFsFileSource::Zip { index, .. } => {
+                f.debug_struct("Zip").field("index", index).finish()
+            }
+            RomFsFileSource::FileRange { offset, .. } => {
+                f.debug_struct("FileRange").field("offset", offset).finish()
+            }
+            RomFsFileSource::Reader(_) => f.write_str("Reader"),
+            RomFsFileSource::Vfs { path, .. } => f.debug_struct("Vfs").field("path", path).finish(),
         }
-        path
     }
 }
 
 #[derive(Debug)]
 struct RomFsFileEntCtx {
-    system_path: PathBuf,
+    source: RomFsFileSource,
     name: String,
     entry_offset: u32,
     offset: u64,
     size: u64,
-    parent: Weak<RefCell<RomFsDirEntCtx>>,
+    /// Path of the file, the one of its parent followed by `/` and its name.
+    path: String,
 }
 
 impl RomFsFileEntCtx {
     fn internal_path(&self) -> String {
-        let parent = self.parent.upgrade().unwrap();
-        let parent_borrow = parent.borrow();
-        parent_borrow.internal_path() + "/" + &self.name
+        self.path.clone()
     }
 }
 
@@ -75,38 +302,25 @@ struct RomFsFileEntryHdr {
 
 impl RomFsDirEntCtx {
     #[allow(clippy::new_ret_no_self)]
-    fn new(parent: Weak<RefCell<RomFsDirEntCtx>>, path: PathBuf) -> Rc<RefCell<RomFsDirEntCtx>> {
-        let filename = path
-            .file_name()
-            .expect("Path to terminate properly")
-            .to_str()
-            .expect("Path to contain non-unicode chars")
-            .into();
+    fn new(parent: &RomFsDirEntCtx, name: String) -> Rc<RefCell<RomFsDirEntCtx>> {
+        let path = parent.child_path(&name);
         Rc::new(RefCell::new(RomFsDirEntCtx {
-            system_path: path,
-            name: filename,
+            name,
+            path,
             entry_offset: 0,
-            parent,
             child: vec![],
             file: vec![],
         }))
     }
 
     fn new_root() -> Rc<RefCell<RomFsDirEntCtx>> {
-        // We'll want the parent to point to itself, so let's first
-        // set it to an unbound Weak, and set it to itself
-        // after creation.
-        let root = Rc::new(RefCell::new(RomFsDirEntCtx {
-            system_path: PathBuf::from(""),
+        Rc::new(RefCell::new(RomFsDirEntCtx {
             name: String::from(""),
+            path: String::new(),
             entry_offset: 0,
-            parent: Weak::new(),
             child: vec![],
             file: vec![],
-        }));
-        let weak = Rc::downgrade(&root);
-        root.borrow_mut().parent = weak;
-        root
+        }))
     }
 }
 
@@ -135,6 +349,56 @@ fn romfs_get_hash_table_count(mut num_entries: usize) -> usize {
     }
 }
 
+fn is_prime(n: usize) -> bool {
+    n >= 2 && (2..).take_while(|d| d * d <= n).all(|d| n % d != 0)
+}
+
+/// Number of buckets of a RomFS hash table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashTableSize {
+    /// About one bucket per entry, the way Nintendo's tools do it.
+    Default,
+    /// The smallest prime giving a load factor of at most 0.75, for a better
+    /// spread of the entries on large images.
+    Auto,
+    /// Exactly this many buckets.
+    Buckets(usize),
+}
+
+impl HashTableSize {
+    fn bucket_count(self, num_entries: usize) -> usize {
+        match self {
+            HashTableSize::Default => romfs_get_hash_table_count(num_entries),
+            HashTableSize::Auto => {
+                let mut count = std::cmp::max(3, (num_entries * 4 + 2) / 3);
+                while !is_prime(count) {
+                    count += 1;
+                }
+                count
+            }
+            HashTableSize::Buckets(count) => std::cmp::max(count, 1),
+        }
+    }
+}
+
+impl FromStr for HashTableSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<HashTableSize, String> {
+        match s {
+            "default" => Ok(HashTableSize::Default),
+            "auto" => Ok(HashTableSize::Auto),
+            _ => match s.parse::<usize>() {
+                Ok(count) if count > 0 => Ok(HashTableSize::Buckets(count)),
+                _ => Err(format!(
+                    "Invalid hash table size {}, expected default, auto or a bucket count",
+                    s
+                )),
+            },
+        }
+    }
+}
+
 fn align32(offset: u32, align: u32) -> u32 {
     let mask = !(align - 1);
     (offset + (align - 1)) & mask
@@ -158,6 +422,8 @@ fn calc_path_hash(parent: u32, path: &str) -> u32 {
 // TODO: why 0x200???
 const ROMFS_FILEPARTITION_OFS: u64 = 0x200;
 
+const ROMFS_ENTRY_EMPTY: u32 = 0xFF_FF_FF_FF;
+
 /// A graph of directories, and various metadata associated to it.
 #[derive(Debug)]
 pub struct RomFs {
@@ -166,77 +432,123 @@ pub struct RomFs {
     dir_table_size: u64,
     file_table_size: u64,
     file_partition_size: u64,
+    dir_hash_table_size: HashTableSize,
+    file_hash_table_size: HashTableSize,
 }
 
 #[allow(clippy::len_without_is_empty)]
 impl RomFs {
     // Internal path
     pub fn push_file(&mut self, file_path: &Path, internal_path: &str) -> io::Result<()> {
-        let mut parent = self.dirs[0].clone();
+        let size = file_path.metadata()?.len();
+        self.insert_file(
+            internal_path,
+            RomFsFileSource::HostPath(PathBuf::from(file_path)),
+            size,
+        )
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+        self.finalize();
+        Ok(())
+    }
 
-        let mut components = internal_path.split('/').peekable();
-        while let Some(component) = components.next() {
-            if components.peek().is_none() {
-                let metadata = file_path.metadata()?;
-                // Handling last component. Add the file.
-                let file_to_add = Rc::new(RefCell::new(RomFsFileEntCtx {
-                    system_path: PathBuf::from(file_path),
-                    name: String::from(component),
-                    entry_offset: 0,
-                    offset: 0,
-                    size: metadata.len(),
-                    parent: Rc::downgrade(&parent),
-                }));
-                self.files.push(file_to_add.clone());
-                parent.borrow_mut().file.push(file_to_add.clone());
-                parent
-                    .borrow_mut()
-                    .file
-                    .sort_by_key(|v| v.borrow().name.clone());
-
-                self.file_table_size += mem::size_of::<RomFsFileEntryHdr>() as u64
-                    + align64(file_to_add.borrow().name.len() as u64, 4);
+    /// Finds the directory at `internal_path`, creating it and its parents
+    /// if they don't exist. Fails if there is a file at one of their paths.
+    fn get_or_create_dir<'a, I>(
+        &mut self,
+        components: I,
+    ) -> Result<Rc<RefCell<RomFsDirEntCtx>>, Error>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let mut parent = self.dirs[0].clone();
+        for component in components {
+            if component.is_empty() {
+                continue;
+            }
+            let existing = parent
+                .borrow()
+                .child
+                .iter()
+                .find(|v| v.borrow().name == component)
+                .cloned();
+            let new_parent = if let Some(child) = existing {
+                child
             } else {
-                // Handling a parent component. Find the directory, create if it doesn't exist.
-                if component == "" {
-                    continue;
-                }
-                let new_parent = if let Some(child) = parent
-                    .borrow()
-                    .child
-                    .iter()
-                    .find(|v| v.borrow().name == component)
-                {
-                    child.clone()
-                } else {
-                    // system_path is not used outside from_directory. It's okay if it doesn't
-                    // point to something "safe" (or to anything at all)
-                    let child = Rc::new(RefCell::new(RomFsDirEntCtx {
-                        system_path: PathBuf::from(""),
-                        name: String::from(component),
-                        entry_offset: 0,
-                        parent: Rc::downgrade(&parent),
-                        child: vec![],
-                        file: vec![],
-                    }));
-                    self.dirs.push(child.clone());
-                    parent.borrow_mut().child.push(child.clone());
-                    parent
-                        .borrow_mut()
-                        .child
-                        .sort_by_key(|v| v.borrow().name.clone());
+                parent.borrow().check_name_is_free(component, false)?;
+                let child = RomFsDirEntCtx::new(&parent.borrow(), String::from(component));
+                self.dirs.push(child.clone());
+                parent.borrow_mut().child.push(child.clone());
+
+                self.dir_table_size += mem::size_of::<RomFsDirEntryHdr>() as u64
+                    + align64(child.borrow().name.len() as u64, 4);
+                child
+            };
+            parent = new_parent;
+        }
+        Ok(parent)
+    }
 
-                    self.dir_table_size += mem::size_of::<RomFsDirEntryHdr>() as u64
-                        + align64(child.borrow().name.len() as u64, 4);
-                    child
-                };
-                parent = new_parent;
-            }
+    /// Adds a file at `internal_path`, creating the missing parent
+    /// directories. If a file already exists there, its content is replaced.
+    /// The caller is responsible for calling `finalize` afterwards.
+    fn insert_file(
+        &mut self,
+        internal_path: &str,
+        source: RomFsFileSource,
+        size: u64,
+    ) -> Result<(), Error> {
+        let mut components = internal_path.rsplitn(2, '/');
+        let name = components.next().unwrap_or("");
+        if name.is_empty() || name == "." || name == ".." {
+            return Err(Error::InvalidRomFsPath {
+                path: internal_path.to_string(),
+                backtrace: Backtrace::generate(),
+            });
+        }
+        let parent_path = components.next().unwrap_or("");
+        let parent = self.get_or_create_dir(parent_path.split('/'))?;
+        parent.borrow().check_name_is_free(name, true)?;
+
+        let existing = parent
+            .borrow()
+            .file
+            .iter()
+            .find(|v| v.borrow().name == name)
+            .cloned();
+        if let Some(existing) = existing {
+            let mut existing = existing.borrow_mut();
+            existing.source = source;
+            existing.size = size;
+            return Ok(());
+        }
+
+        let file = Rc::new(RefCell::new(RomFsFileEntCtx {
+            source,
+            name: String::from(name),
+            entry_offset: 0,
+            offset: 0,
+            size,
+            path: format!("{}/{}", parent.borrow().path, name),
+        }));
+        self.files.push(file.clone());
+        parent.borrow_mut().file.push(file.clone());
+
+        self.file_table_size += mem::size_of::<RomFsFileEntryHdr>() as u64
+            + align64(file.borrow().name.len() as u64, 4);
+        Ok(())
+    }
+
+    /// Sorts the graph the way the RomFS tables expect it and computes the
+    /// final offsets of every entry.
+    fn finalize(&mut self) {
+        for dir in self.dirs.iter() {
+            let mut dir = dir.borrow_mut();
+            dir.child.sort_by_key(|v| v.borrow().name.clone());
+            dir.file.sort_by_key(|v| v.borrow().name.clone());
         }
         self.files.sort_by_key(|v| v.borrow().internal_path());
         self.dirs.sort_by_key(|v| v.borrow().internal_path());
         self.calculate_offsets();
-        Ok(())
     }
 
     pub fn empty() -> RomFs {
@@ -251,6 +563,8 @@ impl RomFs {
             dir_table_size: mem::size_of::<RomFsDirEntryHdr>() as u64, // Root Dir
             file_table_size: 0,
             file_partition_size: 0,
+            dir_hash_table_size: HashTableSize::Default,
+            file_hash_table_size: HashTableSize::Default,
         };
 
         ctx.calculate_offsets();
@@ -259,71 +573,465 @@ impl RomFs {
     }
 
     pub fn from_directory(path: &Path) -> Result<RomFs, Error> {
-        // Stack of directories to visit. We'll iterate over it. When finding
-        // new directories, we'll push them to this stack, so that iteration may
-        // continue. This avoids doing recursive functions (which runs the risk
-        // of stack overflowing).
-        let mut dirs = vec![];
+        RomFs::from_directory_with_filter(path, &PathFilter::default())
+    }
 
+    /// Same as `from_directory`, but skips the files and directories rejected
+    /// by `filter`.
+    pub fn from_directory_with_filter(path: &Path, filter: &PathFilter) -> Result<RomFs, Error> {
+        let options = RomFsSourceOptions {
+            filter: filter.clone(),
+            ..RomFsSourceOptions::default()
+        };
+        RomFs::from_directories(&[path], &options)
+    }
+
+    /// Merges several directories into a single RomFS. When the same path
+    /// exists in multiple sources, the file from the last source wins, which
+    /// allows layering overlays (localization, debug assets...) over a base
+    /// directory. A file and a directory at the same path are an error.
+    pub fn from_directories<P: AsRef<Path>>(
+        paths: &[P],
+        options: &RomFsSourceOptions,
+    ) -> Result<RomFs, Error> {
         // Let's build our context. This will be returned. It contains the graph
         // of files/directories, and some meta-information that will be used to
         // write the romfs file afterwards.
         let mut ctx = RomFs::empty();
 
-        // Set the system_path of the root directory.
-        ctx.dirs[0].borrow_mut().system_path = PathBuf::from(path);
+        for path in paths {
+            ctx.add_source(path.as_ref(), options)?;
+        }
+
+        ctx.finalize();
 
-        // Let's start iterating with the root directory.
-        dirs.push(ctx.dirs[0].clone());
+        Ok(ctx)
+    }
 
-        while let Some(parent_dir) = dirs.pop() {
-            let path = parent_dir.borrow().system_path.clone();
+    /// Creates a RomFS holding the content of `vfs`.
+    pub fn from_vfs(vfs: Rc<dyn Vfs>) -> Result<RomFs, Error> {
+        let mut ctx = RomFs::empty();
+        ctx.add_vfs("", vfs, "")?;
+        ctx.finalize();
+        Ok(ctx)
+    }
 
+    /// Adds the content of the directory at `path` in `vfs` at
+    /// `internal_path`, keeping empty directories.
+    fn add_vfs(&mut self, internal_path: &str, vfs: Rc<dyn Vfs>, path: &str) -> Result<(), Error> {
+        self.get_or_create_dir(internal_path.split('/'))?;
+        for entry in vfs.read_dir(path)? {
+            let entry_path = vfs::join(path, &entry.name);
+            let entry_internal_path = vfs::join(internal_path, &entry.name);
+            if entry.metadata.is_dir {
+                self.add_vfs(&entry_internal_path, vfs.clone(), &entry_path)?;
+            } else {
+                let source = RomFsFileSource::Vfs {
+                    vfs: vfs.clone(),
+                    path: entry_path,
+                };
+                self.insert_file(&entry_internal_path, source, entry.metadata.size)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the content of `path`, which is either a directory or an archive
+    /// recognized by its extension.
+    fn add_source(&mut self, path: &Path, options: &RomFsSourceOptions) -> Result<(), Error> {
+        let name = path
+            .file_name()
+            .and_then(|v| v.to_str())
+            .map(|v| v.to_ascii_lowercase())
+            .unwrap_or_default();
+        if !path.is_file() {
+            self.add_directory(path, options)
+        } else if name.ends_with(".zip") {
+            self.add_zip(path, options)
+        } else if name.ends_with(".tar") {
+            let file = File::open(path).map_err(|err| (err, path))?;
+            self.add_tar(path, file, options)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            self.add_tar_gz(path, options)
+        } else {
+            self.add_directory(path, options)
+        }
+    }
+
+    /// Adds the content of a gzipped tar archive. Compressed tarballs can't be
+    /// read out of order, so they get decompressed to an anonymous temporary
+    /// file first.
+    #[cfg(feature = "native")]
+    fn add_tar_gz(&mut self, path: &Path, options: &RomFsSourceOptions) -> Result<(), Error> {
+        use flate2::read::GzDecoder;
+
+        let mut decoder = GzDecoder::new(File::open(path).map_err(|err| (err, path))?);
+        let mut file = tempfile::tempfile()?;
+        io::copy(&mut decoder, &mut file).map_err(|err| (err, path))?;
+        file.seek(SeekFrom::Start(0))?;
+        self.add_tar(path, file, options)
+    }
+
+    #[cfg(not(feature = "native"))]
+    fn add_tar_gz(&mut self, path: &Path, _options: &RomFsSourceOptions) -> Result<(), Error> {
+        let error = io::Error::new(
+            io::ErrorKind::Other,
+            "compressed tarballs need the native feature",
+        );
+        Err((error, path).into())
+    }
+
+    /// Adds the content of a ZIP archive. Files are decompressed straight
+    /// into the image when writing it.
+    fn add_zip(&mut self, path: &Path, options: &RomFsSourceOptions) -> Result<(), Error> {
+        let file = File::open(path).map_err(|err| (err, path))?;
+        let archive = Rc::new(RefCell::new(ZipArchive::new(file)?));
+        let count = archive.borrow().len();
+        for index in 0..count {
+            let (name, is_dir, is_symlink, size) = {
+                let mut archive = archive.borrow_mut();
+                let entry = archive.by_index(index)?;
+                // S_IFLNK, for archives created on unix.
+                let is_symlink = entry
+                    .unix_mode()
+                    .map_or(false, |v| v & 0o170000 == 0o120000);
+                (
+                    entry.name().to_string(),
+                    entry.is_dir(),
+                    is_symlink,
+                    entry.size(),
+                )
+            };
+            let source = RomFsFileSource::Zip {
+                archive: archive.clone(),
+                index,
+            };
+            self.add_archive_entry(path, &name, is_dir, is_symlink, source, size, options)?;
+        }
+        Ok(())
+    }
+
+    /// Adds the content of the uncompressed tar archive in `file`. Files are
+    /// copied straight from the archive when writing the image.
+    fn add_tar(
+        &mut self,
+        path: &Path,
+        file: File,
+        options: &RomFsSourceOptions,
+    ) -> Result<(), Error> {
+        let file = Rc::new(file);
+        let mut archive = tar::Archive::new(&*file);
+        for entry in archive.entries().map_err(|err| (err, path))? {
+            let entry = entry.map_err(|err| (err, path))?;
+            let entry_type = entry.header().entry_type();
+            let name = match String::from_utf8(entry.path_bytes().into_owned()) {
+                Ok(name) => name,
+                Err(err) => match options.path_encoding {
+                    PathEncodingPolicy::Skip => {
+                        log::warn!("Skipping {}: unsupported file name", path.display());
+                        continue;
+                    }
+                    PathEncodingPolicy::Transliterate => {
+                        String::from_utf8_lossy(err.as_bytes()).replace('\u{FFFD}', "_")
+                    }
+                    _ => {
+                        return Err(Error::RomFsPathEncoding {
+                            path: path.join(String::from_utf8_lossy(err.as_bytes()).as_ref()),
+                            error: "name is not valid UTF-8",
+                            backtrace: Backtrace::generate(),
+                        })
+                    }
+                },
+            };
+            let is_dir = entry_type.is_dir();
+            let is_symlink = entry_type.is_symlink() || entry_type.is_hard_link();
+            if !is_dir && !is_symlink && !entry_type.is_file() {
+                return Err(Error::RomFsFiletype {
+                    error: path.join(name),
+                    backtrace: Backtrace::generate(),
+                });
+            }
+            let source = RomFsFileSource::FileRange {
+                file: file.clone(),
+                offset: entry.raw_file_position(),
+            };
+            let size = entry.size();
+            self.add_archive_entry(path, &name, is_dir, is_symlink, source, size, options)?;
+        }
+        Ok(())
+    }
+
+    /// Adds an entry of the archive at `archive_path`, applying the filter and
+    /// the path encoding policy. `name` is the `/`-separated path of the entry
+    /// in the archive.
+    #[allow(clippy::too_many_arguments)]
+    fn add_archive_entry(
+        &mut self,
+        archive_path: &Path,
+        name: &str,
+        is_dir: bool,
+        is_symlink: bool,
+        source: RomFsFileSource,
+        size: u64,
+        options: &RomFsSourceOptions,
+    ) -> Result<(), Error> {
+        let entry_path = archive_path.join(name);
+        let mut components = Vec::new();
+        for component in name.split('/') {
+            match component {
+                "" | "." => continue,
+                ".." => {
+                    return Err(Error::InvalidRomFsPath {
+                        path: name.to_string(),
+                        backtrace: Backtrace::generate(),
+                    })
+                }
+                _ => components.push(component),
+            }
+        }
+        if components.is_empty() || name == ROMFS_IGNORE_FILE {
+            return Ok(());
+        }
+
+        let relative_path = components.join("/");
+        let excluded = (1..components.len()).any(|len| {
+            options
+                .filter
+                .is_dir_excluded(Path::new(&components[..len].join("/")))
+        }) || if is_dir {
+            options.filter.is_dir_excluded(Path::new(&relative_path))
+        } else {
+            options.filter.is_file_excluded(Path::new(&relative_path))
+        };
+        if excluded {
+            log::info!("Excluding {}", entry_path.display());
+            return Ok(());
+        }
+
+        if is_symlink {
+            if options.symlinks == SymlinkPolicy::Skip {
+                log::info!("Skipping symlink {}", entry_path.display());
+                return Ok(());
+            }
+            // There is nothing to follow inside an archive.
+            return Err(Error::RomFsSymlink {
+                error: entry_path,
+                backtrace: Backtrace::generate(),
+            });
+        }
+
+        let mut internal_path = Vec::new();
+        for component in components {
+            match options
+                .path_encoding
+                .convert_name(&entry_path, OsStr::new(component))?
+            {
+                Some(component) => internal_path.push(component),
+                None => {
+                    log::warn!("Skipping {}: unsupported file name", entry_path.display());
+                    return Ok(());
+                }
+            }
+        }
+
+        if is_dir {
+            self.get_or_create_dir(internal_path.iter().map(String::as_str))?;
+            Ok(())
+        } else {
+            self.insert_file(&internal_path.join("/"), source, size)
+        }
+    }
+
+    /// Walks `root` and adds its content to the graph, replacing the files
+    /// that already exist at the same internal path. The caller is
+    /// responsible for calling `finalize` afterwards.
+    ///
+    /// If `root` contains a `.romfsignore` file, the entries it matches are
+    /// skipped as well.
+    fn add_directory(&mut self, root: &Path, options: &RomFsSourceOptions) -> Result<(), Error> {
+        let filter = &options.filter;
+        let ignore_file = IgnoreFile::from_directory(root, ROMFS_IGNORE_FILE)?;
+
+        // Stack of directories to visit. We'll iterate over it. When finding
+        // new directories, we'll push them to this stack, so that iteration may
+        // continue. This avoids doing recursive functions (which runs the risk
+        // of stack overflowing).
+        //
+        // Along with the directory, we keep track of whether it was created
+        // during this walk. If it was, it can't contain anything yet, and we
+        // can skip looking for conflicting entries in it.
+        //
+        // When following symlinks, we also keep the canonical paths of the
+        // directories leading to it, to detect links pointing to one of their
+        // ancestors.
+        let follow_symlinks = options.symlinks == SymlinkPolicy::Follow;
+        let root_ancestors = if follow_symlinks {
+            vec![fs::canonicalize(root).map_err(|err| (err, root))?]
+        } else {
+            Vec::new()
+        };
+        let mut dirs = vec![(
+            self.dirs[0].clone(),
+            PathBuf::from(root),
+            false,
+            root_ancestors,
+        )];
+
+        while let Some((parent_dir, path, is_new, ancestors)) = dirs.pop() {
+            // Names already used in this directory, to catch the ones that
+            // end up identical after being transliterated.
+            let mut names = HashSet::new();
             for entry in fs::read_dir(&path).map_err(|err| (err, &path))? {
                 let entry = entry.map_err(|err| (err, &path))?;
-                let file_type = entry.file_type().map_err(|err| (err, entry.path()))?;
+                let mut file_type = entry.file_type().map_err(|err| (err, entry.path()))?;
+                let entry_path = entry.path();
+                let relative_path = entry_path.strip_prefix(root).unwrap_or(&entry_path);
+
+                if file_type.is_symlink() {
+                    match options.symlinks {
+                        SymlinkPolicy::Follow => {
+                            file_type = fs::metadata(&entry_path)
+                                .map_err(|err| (err, &entry_path))?
+                                .file_type();
+                        }
+                        SymlinkPolicy::Skip => {
+                            log::info!("Skipping symlink {}", entry_path.display());
+                            continue;
+                        }
+                        SymlinkPolicy::Error => {
+                            return Err(Error::RomFsSymlink {
+                                error: entry_path,
+                                backtrace: Backtrace::generate(),
+                            });
+                        }
+                    }
+                }
+
+                if file_type.is_dir() && filter.is_dir_excluded(relative_path) {
+                    log::info!("Excluding {}", entry_path.display());
+                    continue;
+                }
+                if file_type.is_file() && filter.is_file_excluded(relative_path) {
+                    log::info!("Excluding {}", entry_path.display());
+                    continue;
+                }
+                if relative_path == Path::new(ROMFS_IGNORE_FILE)
+                    || ignore_file
+                        .as_ref()
+                        .map_or(false, |v| v.is_ignored(relative_path, file_type.is_dir()))
+                {
+                    continue;
+                }
+
+                let name = match options
+                    .path_encoding
+                    .convert_name(&entry_path, &entry.file_name())?
+                {
+                    Some(name) => name,
+                    None => {
+                        log::warn!("Skipping {}: unsupported file name", entry_path.display());
+                        continue;
+                    }
+                };
+                if !names.insert(name.clone()) {
+                    return Err(Error::RomFsPathEncoding {
+                        path: entry_path,
+                        error: "another entry has the same transliterated name",
+                        backtrace: Backtrace::generate(),
+                    });
+                }
 
                 if file_type.is_dir() {
-                    let new_dir = RomFsDirEntCtx::new(Rc::downgrade(&parent_dir), entry.path());
+                    let mut dir_ancestors = Vec::new();
+                    if follow_symlinks {
+                        let canonical_path =
+                            fs::canonicalize(&entry_path).map_err(|err| (err, &entry_path))?;
+                        if ancestors.contains(&canonical_path) {
+                            return Err(Error::RomFsSymlinkLoop {
+                                path: entry_path,
+                                backtrace: Backtrace::generate(),
+                            });
+                        }
+                        dir_ancestors = ancestors.clone();
+                        dir_ancestors.push(canonical_path);
+                    }
+
+                    let existing = if is_new {
+                        None
+                    } else {
+                        parent_dir
+                            .borrow()
+                            .child
+                            .iter()
+                            .find(|v| v.borrow().name == name)
+                            .cloned()
+                    };
+
+                    if let Some(existing) = existing {
+                        dirs.push((existing, entry_path, false, dir_ancestors));
+                        continue;
+                    }
+                    if !is_new {
+                        parent_dir.borrow().check_name_is_free(&name, false)?;
+                    }
+
+                    let new_dir = RomFsDirEntCtx::new(&parent_dir.borrow(), name);
 
                     // We want to push this directory to the list of directories to
                     // traverse/discover, and to the child list of the parent dir.
-                    ctx.dirs.push(new_dir.clone());
-                    dirs.push(new_dir.clone());
+                    self.dirs.push(new_dir.clone());
+                    dirs.push((new_dir.clone(), entry_path, true, dir_ancestors));
 
                     parent_dir.borrow_mut().child.push(new_dir.clone());
 
                     // Update the context. We want to keep track of the number of directories, and
                     // the size of the dir_table.
-                    ctx.dir_table_size += mem::size_of::<RomFsDirEntryHdr>() as u64
+                    self.dir_table_size += mem::size_of::<RomFsDirEntryHdr>() as u64
                         + align64(new_dir.borrow().name.len() as u64, 4);
                 } else if file_type.is_file() {
+                    let size = fs::metadata(&entry_path)
+                        .map_err(|err| (err, &entry_path))?
+                        .len();
+
+                    let existing = if is_new {
+                        None
+                    } else {
+                        parent_dir
+                            .borrow()
+                            .file
+                            .iter()
+                            .find(|v| v.borrow().name == name)
+                            .cloned()
+                    };
+
+                    if let Some(existing) = existing {
+                        // Overridden by a later source. Only the content
+                        // changes, the entry stays where it was.
+                        let mut existing = existing.borrow_mut();
+                        existing.source = RomFsFileSource::HostPath(entry_path);
+                        existing.size = size;
+                        continue;
+                    }
+                    if !is_new {
+                        parent_dir.borrow().check_name_is_free(&name, true)?;
+                    }
+
+                    let path = format!("{}/{}", parent_dir.borrow().path, name);
                     let file = Rc::new(RefCell::new(RomFsFileEntCtx {
-                        system_path: entry.path(),
-                        name: entry
-                            .path()
-                            .file_name()
-                            .expect("Path to terminate properly")
-                            .to_str()
-                            .expect("Path to contain non-unicode chars")
-                            .into(),
+                        source: RomFsFileSource::HostPath(entry_path),
+                        name,
                         entry_offset: 0,
                         offset: 0,
-                        size: entry.metadata().map_err(|err| (err, entry.path()))?.len(),
-                        parent: Rc::downgrade(&parent_dir),
+                        size,
+                        path,
                     }));
 
-                    ctx.files.push(file.clone());
+                    self.files.push(file.clone());
 
                     parent_dir.borrow_mut().file.push(file.clone());
 
-                    ctx.file_table_size += mem::size_of::<RomFsFileEntryHdr>() as u64
+                    self.file_table_size += mem::size_of::<RomFsFileEntryHdr>() as u64
                         + align64(file.borrow().name.len() as u64, 4);
-                } else if file_type.is_symlink() {
-                    return Err(Error::RomFsSymlink {
-                        error: entry.path(),
-                        backtrace: Backtrace::generate(),
-                    });
                 } else {
                     return Err(Error::RomFsFiletype {
                         error: entry.path(),
@@ -331,30 +1039,91 @@ impl RomFs {
                     });
                 }
             }
-            parent_dir
-                .borrow_mut()
-                .child
-                .sort_by_key(|v| v.borrow().name.clone());
-            parent_dir
-                .borrow_mut()
-                .file
-                .sort_by_key(|v| v.borrow().name.clone());
         }
 
-        ctx.files.sort_by_key(|v| v.borrow().internal_path());
-        ctx.dirs.sort_by_key(|v| v.borrow().internal_path());
-
-        ctx.calculate_offsets();
+        Ok(())
+    }
 
-        Ok(ctx)
+    /// Sets how many buckets the directory and file hash tables get. The
+    /// default matches Nintendo's tools.
+    pub fn set_hash_table_sizes(&mut self, dirs: HashTableSize, files: HashTableSize) {
+        self.dir_hash_table_size = dirs;
+        self.file_hash_table_size = files;
     }
 
     pub fn len(&self) -> usize {
-        (align64(ROMFS_FILEPARTITION_OFS + self.file_partition_size, 4)
-            + romfs_get_hash_table_count(self.dirs.len() * mem::size_of::<u32>()) as u64
-            + self.dir_table_size
-            + romfs_get_hash_table_count(self.files.len() * mem::size_of::<u32>()) as u64
-            + self.file_table_size) as usize
+        self.layout().total_size as usize
+    }
+
+    /// Computes where everything will end up in the image, without reading
+    /// any of the files.
+    pub fn layout(&self) -> RomFsLayout {
+        let dir_hash_table_size =
+            (self.dir_hash_table_size.bucket_count(self.dirs.len()) * mem::size_of::<u32>()) as u64;
+        let file_hash_table_size = (self.file_hash_table_size.bucket_count(self.files.len())
+            * mem::size_of::<u32>()) as u64;
+        let metadata_ofs = align64(ROMFS_FILEPARTITION_OFS + self.file_partition_size, 4);
+
+        // Data size of each directory, keyed by internal path.
+        let mut directories = self
+            .dirs
+            .iter()
+            .map(|dir| {
+                let dir = dir.borrow();
+                let layout = RomFsDirLayout {
+                    path: format!("/{}", dir.internal_path()),
+                    file_count: dir.file.len(),
+                    data_size: dir.file.iter().map(|file| file.borrow().size).sum(),
+                    total_data_size: 0,
+                };
+                (dir.internal_path(), layout)
+            })
+            .collect::<BTreeMap<_, _>>();
+        for file in self.files.iter() {
+            let file = file.borrow();
+            // Starting from the directory of the file.
+            let mut path = file.internal_path();
+            path.truncate(path.rfind('/').unwrap_or(0));
+            loop {
+                if let Some(dir) = directories.get_mut(&path) {
+                    dir.total_data_size += file.size;
+                }
+                match path.rfind('/') {
+                    Some(idx) => path.truncate(idx),
+                    None if !path.is_empty() => path.clear(),
+                    None => break,
+                }
+            }
+        }
+
+        RomFsLayout {
+            file_count: self.files.len(),
+            dir_count: self.dirs.len(),
+            file_partition_ofs: ROMFS_FILEPARTITION_OFS,
+            file_partition_size: self.file_partition_size,
+            dir_hash_table_size,
+            dir_table_size: self.dir_table_size,
+            file_hash_table_size,
+            file_table_size: self.file_table_size,
+            total_size: metadata_ofs
+                + dir_hash_table_size
+                + self.dir_table_size
+                + file_hash_table_size
+                + self.file_table_size,
+            directories: directories.into_iter().map(|(_, v)| v).collect(),
+            files: self
+                .files
+                .iter()
+                .map(|file| {
+                    let file = file.borrow();
+                    RomFsFileLayout {
+                        path: format!("/{}", file.internal_path()),
+                        offset: ROMFS_FILEPARTITION_OFS + file.offset,
+                        size: file.size,
+                    }
+                })
+                .collect(),
+        }
     }
 
     fn calculate_offsets(&mut self) {
@@ -386,32 +1155,72 @@ impl RomFs {
         }
     }
 
-    pub fn write(&self, to: &mut dyn Write) -> io::Result<()> {
-        const ROMFS_ENTRY_EMPTY: u32 = 0xFF_FF_FF_FF;
+    pub fn write(&self, to: &mut dyn Write) -> Result<(), Error> {
+        self.write_with_jobs(to, 1)
+    }
+
+    /// Same as `write`, but reads the host files on `jobs` threads ahead of
+    /// the writer. Files bigger than `PREFETCH_MAX_SIZE` are still streamed by
+    /// the writer to keep the memory usage bounded.
+    pub fn write_with_jobs(&self, to: &mut dyn Write, jobs: usize) -> Result<(), Error> {
+        // Without the native feature there may be no threads to spawn.
+        let mut prefetcher = if jobs > 1 && cfg!(feature = "native") {
+            let paths = self
+                .files
+                .iter()
+                .filter_map(|file| {
+                    let file = file.borrow();
+                    match &file.source {
+                        RomFsFileSource::HostPath(path) if file.size <= PREFETCH_MAX_SIZE => {
+                            Some(path.clone())
+                        }
+                        _ => None,
+                    }
+                })
+                .collect();
+            Some(Prefetcher::new(paths, jobs))
+        } else {
+            None
+        };
 
         let mut dir_hash_table =
-            vec![ROMFS_ENTRY_EMPTY; romfs_get_hash_table_count(self.dirs.len())];
+            vec![ROMFS_ENTRY_EMPTY; self.dir_hash_table_size.bucket_count(self.dirs.len())];
         let mut file_hash_table =
-            vec![ROMFS_ENTRY_EMPTY; romfs_get_hash_table_count(self.files.len())];
+            vec![ROMFS_ENTRY_EMPTY; self.file_hash_table_size.bucket_count(self.files.len())];
 
         let mut dir_table = vec![0u8; self.dir_table_size as usize];
         let mut file_table = vec![0u8; self.file_table_size as usize];
 
+        // The offsets of the parent and next sibling of each entry, found from
+        // the lists of the directories.
+        let mut file_links = HashMap::new();
+        let mut dir_links = HashMap::new();
+        for dir in self.dirs.iter() {
+            let dir = dir.borrow();
+            for (i, file) in dir.file.iter().enumerate() {
+                let sibling = dir.file.get(i + 1).map(|v| v.borrow().entry_offset);
+                file_links.insert(Rc::as_ptr(file), (dir.entry_offset, sibling));
+            }
+            for (i, child) in dir.child.iter().enumerate() {
+                let sibling = dir.child.get(i + 1).map(|v| v.borrow().entry_offset);
+                dir_links.insert(Rc::as_ptr(child), (dir.entry_offset, sibling));
+            }
+        }
+        // The root is the only entry that isn't in one of those lists. It is
+        // its own parent, and always first in the directory table.
+        let root_links = (0, None);
+
         // Populate file tables
         for file in self.files.iter() {
-            let orig_file = file;
+            let (parent_offset, sibling) = file_links
+                .get(&Rc::as_ptr(file))
+                .copied()
+                .unwrap_or(root_links);
             let file = file.borrow();
-            let parent = file.parent.upgrade().unwrap();
-            let parent = parent.borrow();
-            let sibling = parent
-                .file
-                .windows(2)
-                .find(|window| Rc::ptr_eq(&window[0], orig_file))
-                .map(|window| window[1].borrow().entry_offset);
-            let hash = calc_path_hash(parent.entry_offset, &file.name);
+            let hash = calc_path_hash(parent_offset, &file.name);
 
             let mut cursor = Cursor::new(&mut file_table[file.entry_offset as usize..]);
-            cursor.write_u32::<LE>(parent.entry_offset)?;
+            cursor.write_u32::<LE>(parent_offset)?;
             cursor.write_u32::<LE>(sibling.unwrap_or(ROMFS_ENTRY_EMPTY))?;
             cursor.write_u64::<LE>(file.offset)?;
             cursor.write_u64::<LE>(file.size)?;
@@ -425,18 +1234,15 @@ impl RomFs {
 
         // Populate dir tables
         for dir in self.dirs.iter() {
+            let (parent_offset, sibling) = dir_links
+                .get(&Rc::as_ptr(dir))
+                .copied()
+                .unwrap_or(root_links);
             let dir = dir.borrow();
-            let parent = dir.parent.upgrade().unwrap();
-            let parent = parent.borrow();
-            let sibling = parent
-                .child
-                .windows(2)
-                .find(|window| window[0].borrow().internal_path() == dir.internal_path())
-                .map(|window| window[1].borrow().entry_offset);
-            let hash = calc_path_hash(parent.entry_offset, &dir.name);
+            let hash = calc_path_hash(parent_offset, &dir.name);
 
             let mut cursor = Cursor::new(&mut dir_table[dir.entry_offset as usize..]);
-            cursor.write_u32::<LE>(parent.entry_offset)?;
+            cursor.write_u32::<LE>(parent_offset)?;
             cursor.write_u32::<LE>(sibling.unwrap_or(ROMFS_ENTRY_EMPTY))?;
             cursor.write_u32::<LE>(
                 dir.child
@@ -491,15 +1297,87 @@ impl RomFs {
             to.write_all(&vec![0; (new_cur_ofs - cur_ofs) as usize])?;
             cur_ofs = new_cur_ofs;
 
-            println!(
-                "Writing {} to RomFS image...",
-                file.borrow().system_path.to_string_lossy()
-            );
-            assert_eq!(file.borrow().offset, cur_ofs - 0x200, "Wrong offset");
+            let mut file = file.borrow_mut();
+            if file.offset != cur_ofs - 0x200 {
+                return Err(Error::InvalidRomFs {
+                    error: "file offsets don't match their layout, finalize wasn't called",
+                    backtrace: Backtrace::generate(),
+                });
+            }
 
-            let len = io::copy(&mut File::open(&file.borrow().system_path)?, to)?;
-            assert_eq!(len, file.borrow().size, "File changed while building romfs");
-            cur_ofs += file.borrow().size;
+            let size = file.size;
+            match &mut file.source {
+                RomFsFileSource::HostPath(path) => {
+                    log::debug!("Writing {} to RomFS image...", path.to_string_lossy());
+                    let len = match prefetcher.as_mut() {
+                        Some(prefetcher) if size <= PREFETCH_MAX_SIZE => {
+                            let data = prefetcher.next()?;
+                            to.write_all(&data)?;
+                            data.len() as u64
+                        }
+                        _ => io::copy(&mut File::open(&path)?, to)?,
+                    };
+                    if len != size {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            format!("{} changed while building the RomFS", path.display()),
+                        )
+                        .into());
+                    }
+                }
+                RomFsFileSource::Zip { archive, index } => {
+                    let mut archive = archive.borrow_mut();
+                    let mut entry = archive.by_index(*index)?;
+                    log::debug!("Writing {} to RomFS image...", entry.name());
+                    let len = io::copy(&mut entry, to)?;
+                    if len != size {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("Size of {} doesn't match its header", entry.name()),
+                        )
+                        .into());
+                    }
+                }
+                RomFsFileSource::FileRange { file, offset } => {
+                    let mut file = &**file;
+                    file.seek(SeekFrom::Start(*offset))?;
+                    let len = io::copy(&mut file.take(size), to)?;
+                    if len != size {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            format!("Archive is truncated at {:#x}", *offset + len),
+                        )
+                        .into());
+                    }
+                }
+                RomFsFileSource::Reader(reader) => {
+                    let len = io::copy(&mut reader.take(size), to)?;
+                    if len != size {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            format!(
+                                "Reader for {} provided {:#x} bytes instead of {:#x}",
+                                file.internal_path(),
+                                len,
+                                size
+                            ),
+                        )
+                        .into());
+                    }
+                }
+                RomFsFileSource::Vfs { vfs, path } => {
+                    log::debug!("Writing {} to RomFS image...", path);
+                    let len = io::copy(&mut vfs.open(path)?, to)?;
+                    if len != size {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            format!("{} changed while building the RomFS", path),
+                        )
+                        .into());
+                    }
+                }
+            }
+            cur_ofs += size;
         }
 
         // Pad to 4.
@@ -508,10 +1386,12 @@ impl RomFs {
         let cur_ofs = new_cur_ofs;
 
         // Write dir hash table
-        assert_eq!(
-            cur_ofs,
-            align64(ROMFS_FILEPARTITION_OFS + self.file_partition_size, 4)
-        );
+        if cur_ofs != align64(ROMFS_FILEPARTITION_OFS + self.file_partition_size, 4) {
+            return Err(Error::InvalidRomFs {
+                error: "file partition size doesn't match its content",
+                backtrace: Backtrace::generate(),
+            });
+        }
         for hash in dir_hash_table {
             to.write_u32::<LE>(hash)?;
         }
@@ -523,3 +1403,1076 @@ impl RomFs {
         Ok(())
     }
 }
+
+fn invalid_romfs(error: &'static str) -> Error {
+    Error::InvalidRomFs {
+        error,
+        backtrace: Backtrace::generate(),
+    }
+}
+
+#[derive(Debug)]
+struct RomFsHeader {
+    dir_hash_table_ofs: u64,
+    dir_hash_table_size: u64,
+    dir_table_ofs: u64,
+    dir_table_size: u64,
+    file_hash_table_ofs: u64,
+    file_hash_table_size: u64,
+    file_table_ofs: u64,
+    file_table_size: u64,
+    file_partition_ofs: u64,
+}
+
+#[derive(Debug)]
+struct RawDirEntry {
+    parent: u32,
+    sibling: u32,
+    child: u32,
+    file: u32,
+    hash_sibling: u32,
+    name: String,
+}
+
+#[derive(Debug)]
+struct RawFileEntry {
+    parent: u32,
+    sibling: u32,
+    offset: u64,
+    size: u64,
+    hash_sibling: u32,
+    name: String,
+}
+
+/// Sizes of the different parts of a RomFS image, as returned by
+/// `RomFs::layout`.
+#[derive(Debug, Clone, Serialize)]
+pub struct RomFsLayout {
+    pub file_count: usize,
+    pub dir_count: usize,
+    pub file_partition_ofs: u64,
+    pub file_partition_size: u64,
+    pub dir_hash_table_size: u64,
+    pub dir_table_size: u64,
+    pub file_hash_table_size: u64,
+    pub file_table_size: u64,
+    pub total_size: u64,
+    /// Every directory of the image, sorted by path.
+    pub directories: Vec<RomFsDirLayout>,
+    /// Every file of the image, in the order of their data.
+    pub files: Vec<RomFsFileLayout>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RomFsDirLayout {
+    pub path: String,
+    /// Number of files directly in this directory.
+    pub file_count: usize,
+    /// Size of the files directly in this directory.
+    pub data_size: u64,
+    /// Size of all the files below this directory, alignment excluded.
+    pub total_data_size: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RomFsFileLayout {
+    pub path: String,
+    /// Offset of the data of the file from the start of the image. Data is
+    /// aligned to 0x10, the padding coming before it.
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// Builds a RomFS from files that don't necessarily exist on disk.
+///
+/// ```no_run
+/// # use linkle::format::romfs::RomFsBuilder;
+/// let config = b"difficulty=hard";
+/// let mut builder = RomFsBuilder::new();
+/// builder.add_dir("saves")?;
+/// builder.add_file("data/config.ini", &config[..], config.len() as u64)?;
+/// builder.add_host_file("data/music.bfstm", "assets/music.bfstm")?;
+/// let romfs = builder.build();
+/// romfs.write(&mut std::fs::File::create("romfs.bin")?)?;
+/// # Ok::<(), linkle::error::Error>(())
+/// ```
+pub struct RomFsBuilder {
+    romfs: RomFs,
+}
+
+impl Default for RomFsBuilder {
+    fn default() -> RomFsBuilder {
+        RomFsBuilder::new()
+    }
+}
+
+impl RomFsBuilder {
+    pub fn new() -> RomFsBuilder {
+        RomFsBuilder {
+            romfs: RomFs::empty(),
+        }
+    }
+
+    /// Adds an empty directory at `internal_path`, along with its parents.
+    /// Directories holding files are created automatically.
+    pub fn add_dir(&mut self, internal_path: &str) -> Result<&mut RomFsBuilder, Error> {
+        self.romfs.get_or_create_dir(internal_path.split('/'))?;
+        Ok(self)
+    }
+
+    /// Adds a file whose `size` bytes of content will be read from `reader`
+    /// when the RomFS gets written. Replaces any file previously added at the
+    /// same path.
+    pub fn add_file<R: Read + 'static>(
+        &mut self,
+        internal_path: &str,
+        reader: R,
+        size: u64,
+    ) -> Result<&mut RomFsBuilder, Error> {
+        self.romfs.insert_file(
+            internal_path,
+            RomFsFileSource::Reader(Box::new(reader)),
+            size,
+        )?;
+        Ok(self)
+    }
+
+    /// Adds a file from the host filesystem. Replaces any file previously
+    /// added at the same path.
+    pub fn add_host_file<P: AsRef<Path>>(
+        &mut self,
+        internal_path: &str,
+        host_path: P,
+    ) -> Result<&mut RomFsBuilder, Error> {
+        let host_path = host_path.as_ref();
+        let size = host_path.metadata().map_err(|err| (err, host_path))?.len();
+        self.romfs.insert_file(
+            internal_path,
+            RomFsFileSource::HostPath(host_path.to_owned()),
+            size,
+        )?;
+        Ok(self)
+    }
+
+    /// Merges the content of a host directory, the same way
+    /// `RomFs::from_directories` does.
+    pub fn add_host_directory<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        options: &RomFsSourceOptions,
+    ) -> Result<&mut RomFsBuilder, Error> {
+        self.romfs.add_directory(path.as_ref(), options)?;
+        Ok(self)
+    }
+
+    /// Adds the content of `vfs` at `internal_path`, `""` being the root of
+    /// the RomFS. Files previously added at the same paths are replaced.
+    pub fn add_vfs(
+        &mut self,
+        internal_path: &str,
+        vfs: Rc<dyn Vfs>,
+    ) -> Result<&mut RomFsBuilder, Error> {
+        self.romfs
+            .add_vfs(internal_path.trim_matches('/'), vfs, "")?;
+        Ok(self)
+    }
+
+    /// Computes the layout of the RomFS with the files added so far, without
+    /// reading any of them.
+    pub fn plan(&mut self) -> RomFsLayout {
+        self.romfs.finalize();
+        self.romfs.layout()
+    }
+
+    pub fn build(mut self) -> RomFs {
+        self.romfs.finalize();
+        self.romfs
+    }
+}
+
+/// An entry of a RomFS image, as returned by `RomFsReader::entries`.
+///
+/// Paths are absolute within the image and `/`-separated (e.g. `/data/a.bin`).
+/// File offsets are relative to the start of the image.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RomFsEntry {
+    Dir {
+        path: String,
+    },
+    File {
+        path: String,
+        offset: u64,
+        size: u64,
+    },
+}
+
+impl RomFsEntry {
+    pub fn path(&self) -> &str {
+        match self {
+            RomFsEntry::Dir { path } => path,
+            RomFsEntry::File { path, .. } => path,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        let path = self.path();
+        &path[path.rfind('/').map(|v| v + 1).unwrap_or(0)..]
+    }
+
+    /// Number of directories between the root and this entry. Entries at the
+    /// root of the image have a depth of 1.
+    pub fn depth(&self) -> usize {
+        self.path().matches('/').count()
+    }
+}
+
+/// A RomFS image opened for reading. Only the metadata tables are loaded in
+/// memory, file data is read on demand.
+pub struct RomFsReader<R> {
+    inner: R,
+    header: RomFsHeader,
+    dir_hash_table: Vec<u32>,
+    dir_table: Vec<u8>,
+    file_hash_table: Vec<u32>,
+    file_table: Vec<u8>,
+    image_size: u64,
+    mode: ParseMode,
+}
+
+/// The header and metadata tables of a RomFS of `image_size` bytes.
+fn metadata_regions(header: &RomFsHeader, image_size: u64) -> Result<Regions, Error> {
+    let mut regions = Regions::new(image_size);
+    regions.add("header", 0, 0x50)?;
+    regions.add(
+        "directory hash table",
+        header.dir_hash_table_ofs,
+        header.dir_hash_table_size,
+    )?;
+    regions.add(
+        "directory table",
+        header.dir_table_ofs,
+        header.dir_table_size,
+    )?;
+    regions.add(
+        "file hash table",
+        header.file_hash_table_ofs,
+        header.file_hash_table_size,
+    )?;
+    regions.add("file table", header.file_table_ofs, header.file_table_size)?;
+    regions.add("file partition", header.file_partition_ofs, 0)?;
+    Ok(regions)
+}
+
+fn read_hash_table<R: Read + Seek>(inner: &mut R, ofs: u64, size: u64) -> io::Result<Vec<u32>> {
+    let mut table = vec![0; size as usize / mem::size_of::<u32>()];
+    inner.seek(SeekFrom::Start(ofs))?;
+    inner.read_u32_into::<LE>(&mut table)?;
+    Ok(table)
+}
+
+impl<R: Read + Seek> RomFsReader<R> {
+    pub fn from_reader(inner: R) -> Result<RomFsReader<R>, Error> {
+        RomFsReader::from_reader_with_mode(inner, ParseMode::Lenient)
+    }
+
+    /// Same as `from_reader`, checking the image as told by `mode`. Strict
+    /// mode also makes `entries` check that the data of the files is in the
+    /// image and that files don't overlap, unless they share the exact same
+    /// data.
+    pub fn from_reader_with_mode(mut inner: R, mode: ParseMode) -> Result<RomFsReader<R>, Error> {
+        inner.seek(SeekFrom::Start(0))?;
+        let header_size = inner.read_u64::<LE>()?;
+        if header_size != 80 {
+            return Err(invalid_romfs("header size is wrong"));
+        }
+        let header = RomFsHeader {
+            dir_hash_table_ofs: inner.read_u64::<LE>()?,
+            dir_hash_table_size: inner.read_u64::<LE>()?,
+            dir_table_ofs: inner.read_u64::<LE>()?,
+            dir_table_size: inner.read_u64::<LE>()?,
+            file_hash_table_ofs: inner.read_u64::<LE>()?,
+            file_hash_table_size: inner.read_u64::<LE>()?,
+            file_table_ofs: inner.read_u64::<LE>()?,
+            file_table_size: inner.read_u64::<LE>()?,
+            file_partition_ofs: inner.read_u64::<LE>()?,
+        };
+
+        let image_size = inner.seek(SeekFrom::End(0))?;
+        for (ofs, size) in &[
+            (header.dir_hash_table_ofs, header.dir_hash_table_size),
+            (header.dir_table_ofs, header.dir_table_size),
+            (header.file_hash_table_ofs, header.file_hash_table_size),
+            (header.file_table_ofs, header.file_table_size),
+        ] {
+            if ofs.checked_add(*size).map_or(true, |end| end > image_size) {
+                return Err(invalid_romfs("metadata table is out of bounds"));
+            }
+        }
+        if mode.is_strict() {
+            if header.dir_hash_table_size % 4 != 0 || header.file_hash_table_size % 4 != 0 {
+                return Err(invalid_romfs("hash table sizes must be multiples of 4"));
+            }
+            metadata_regions(&header, image_size)?.check_overlaps()?;
+        }
+
+        let dir_hash_table = read_hash_table(
+            &mut inner,
+            header.dir_hash_table_ofs,
+            header.dir_hash_table_size,
+        )?;
+        let file_hash_table = read_hash_table(
+            &mut inner,
+            header.file_hash_table_ofs,
+            header.file_hash_table_size,
+        )?;
+
+        let mut dir_table = vec![0; header.dir_table_size as usize];
+        inner.seek(SeekFrom::Start(header.dir_table_ofs))?;
+        inner.read_exact(&mut dir_table)?;
+
+        let mut file_table = vec![0; header.file_table_size as usize];
+        inner.seek(SeekFrom::Start(header.file_table_ofs))?;
+        inner.read_exact(&mut file_table)?;
+
+        Ok(RomFsReader {
+            inner,
+            header,
+            dir_hash_table,
+            dir_table,
+            file_hash_table,
+            file_table,
+            image_size,
+            mode,
+        })
+    }
+
+    fn dir_entry(&self, offset: u32) -> Result<RawDirEntry, Error> {
+        let offset = offset as usize;
+        if offset + mem::size_of::<RomFsDirEntryHdr>() > self.dir_table.len() {
+            return Err(invalid_romfs("directory entry is out of bounds"));
+        }
+        let mut cursor = Cursor::new(&self.dir_table[offset..]);
+        let parent = cursor.read_u32::<LE>()?;
+        let sibling = cursor.read_u32::<LE>()?;
+        let child = cursor.read_u32::<LE>()?;
+        let file = cursor.read_u32::<LE>()?;
+        let hash_sibling = cursor.read_u32::<LE>()?;
+        let name_size = cursor.read_u32::<LE>()? as usize;
+        let name_start = offset + mem::size_of::<RomFsDirEntryHdr>();
+        let name = self
+            .dir_table
+            .get(name_start..name_start + name_size)
+            .ok_or_else(|| invalid_romfs("directory name is out of bounds"))?;
+        Ok(RawDirEntry {
+            parent,
+            sibling,
+            child,
+            file,
+            hash_sibling,
+            name: String::from_utf8(name.to_vec())?,
+        })
+    }
+
+    fn file_entry(&self, offset: u32) -> Result<RawFileEntry, Error> {
+        let offset = offset as usize;
+        if offset + mem::size_of::<RomFsFileEntryHdr>() > self.file_table.len() {
+            return Err(invalid_romfs("file entry is out of bounds"));
+        }
+        let mut cursor = Cursor::new(&self.file_table[offset..]);
+        let parent = cursor.read_u32::<LE>()?;
+        let sibling = cursor.read_u32::<LE>()?;
+        let data_offset = cursor.read_u64::<LE>()?;
+        let size = cursor.read_u64::<LE>()?;
+        let hash_sibling = cursor.read_u32::<LE>()?;
+        let name_size = cursor.read_u32::<LE>()? as usize;
+        let name_start = offset + mem::size_of::<RomFsFileEntryHdr>();
+        let name = self
+            .file_table
+            .get(name_start..name_start + name_size)
+            .ok_or_else(|| invalid_romfs("file name is out of bounds"))?;
+        Ok(RawFileEntry {
+            parent,
+            sibling,
+            offset: data_offset,
+            size,
+            hash_sibling,
+            name: String::from_utf8(name.to_vec())?,
+        })
+    }
+
+    /// A well-formed image can't have more entries than this. Used to bail out
+    /// of sibling/child loops in corrupted images.
+    fn max_entries(&self) -> usize {
+        self.dir_table.len() / mem::size_of::<RomFsDirEntryHdr>()
+            + self.file_table.len() / mem::size_of::<RomFsFileEntryHdr>()
+    }
+
+    /// Looks up the directory named `name` in the directory at `parent` using
+    /// the hash table. Returns the offset of its entry in the directory table.
+    fn lookup_dir(&self, parent: u32, name: &str) -> Result<Option<u32>, Error> {
+        if self.dir_hash_table.is_empty() {
+            return Ok(None);
+        }
+        let hash = calc_path_hash(parent, name) as usize % self.dir_hash_table.len();
+        let mut offset = self.dir_hash_table[hash];
+        for _ in 0..=self.max_entries() {
+            if offset == ROMFS_ENTRY_EMPTY {
+                return Ok(None);
+            }
+            let dir = self.dir_entry(offset)?;
+            if dir.parent == parent && dir.name == name {
+                return Ok(Some(offset));
+            }
+            offset = dir.hash_sibling;
+        }
+        Err(invalid_romfs("directory hash table contains a loop"))
+    }
+
+    /// Looks up the file at `path` (e.g. `/data/a.bin`) using the hash
+    /// tables. Returns the offset of its entry in the file table along with
+    /// the entry itself.
+    fn lookup_file(&self, path: &str) -> Result<Option<(u32, RawFileEntry)>, Error> {
+        let mut components = path
+            .split('/')
+            .filter(|v| !v.is_empty())
+            .collect::<Vec<_>>();
+        let name = match components.pop() {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+        let mut parent = 0;
+        for component in components {
+            parent = match self.lookup_dir(parent, component)? {
+                Some(offset) => offset,
+                None => return Ok(None),
+            };
+        }
+
+        if self.file_hash_table.is_empty() {
+            return Ok(None);
+        }
+        let hash = calc_path_hash(parent, name) as usize % self.file_hash_table.len();
+        let mut offset = self.file_hash_table[hash];
+        for _ in 0..=self.max_entries() {
+            if offset == ROMFS_ENTRY_EMPTY {
+                return Ok(None);
+            }
+            let file = self.file_entry(offset)?;
+            if file.parent == parent && file.name == name {
+                return Ok(Some((offset, file)));
+            }
+            offset = file.hash_sibling;
+        }
+        Err(invalid_romfs("file hash table contains a loop"))
+    }
+
+    /// Returns the files whose path matches the glob `pattern`. Paths are
+    /// matched without their leading `/` (e.g. `**/*.bfres`, `data/*.bin`).
+    pub fn find(&self, pattern: &str) -> Result<Vec<RomFsEntry>, Error> {
+        let matcher = Glob::new(pattern)?.compile_matcher();
+        Ok(self
+            .entries()?
+            .into_iter()
+            .filter(|entry| match entry {
+                RomFsEntry::File { path, .. } => matcher.is_match(&path[1..]),
+                RomFsEntry::Dir { .. } => false,
+            })
+            .collect())
+    }
+
+    /// Looks up the file at `path` (e.g. `/data/a.bin`) without walking the
+    /// whole tree. Returns `None` if the image has no such file.
+    pub fn find_file(&self, path: &str) -> Result<Option<RomFsEntry>, Error> {
+        Ok(self.lookup_file(path)?.map(|(_, file)| RomFsEntry::File {
+            path: format!("/{}", path.trim_start_matches('/')),
+            offset: self.header.file_partition_ofs.saturating_add(file.offset),
+            size: file.size,
+        }))
+    }
+
+    /// Walks the metadata tables and returns every directory and file of the
+    /// image, depth first, each directory being followed by its content.
+    pub fn entries(&self) -> Result<Vec<RomFsEntry>, Error> {
+        let max_entries = self.max_entries();
+
+        let mut entries = Vec::new();
+        // Same as in `RomFs::from_directory`, we use a stack instead of
+        // recursing to avoid stack overflows on deep trees.
+        let mut dirs = vec![(0, String::new())];
+        while let Some((dir_offset, path)) = dirs.pop() {
+            let dir = self.dir_entry(dir_offset)?;
+            if dir_offset != 0 {
+                entries.push(RomFsEntry::Dir { path: path.clone() });
+            }
+
+            let mut file_offset = dir.file;
+            while file_offset != ROMFS_ENTRY_EMPTY {
+                let file = self.file_entry(file_offset)?;
+                if !is_valid_entry_name(&file.name) {
+                    return Err(invalid_romfs("a file name isn't a valid file name"));
+                }
+                entries.push(RomFsEntry::File {
+                    path: format!("{}/{}", path, file.name),
+                    offset: self.header.file_partition_ofs.saturating_add(file.offset),
+                    size: file.size,
+                });
+                if entries.len() > max_entries {
+                    return Err(invalid_romfs("file table contains a loop"));
+                }
+                file_offset = file.sibling;
+            }
+
+            let mut children = Vec::new();
+            let mut child_offset = dir.child;
+            while child_offset != ROMFS_ENTRY_EMPTY {
+                let child = self.dir_entry(child_offset)?;
+                if !is_valid_entry_name(&child.name) {
+                    return Err(invalid_romfs("a directory name isn't a valid file name"));
+                }
+                children.push((child_offset, format!("{}/{}", path, child.name)));
+                if entries.len() + children.len() + dirs.len() > max_entries {
+                    return Err(invalid_romfs("directory table contains a loop"));
+                }
+                child_offset = child.sibling;
+            }
+            // Pushed in reverse so they get popped in order.
+            dirs.extend(children.into_iter().rev());
+        }
+
+        if self.mode.is_strict() {
+            let mut regions = metadata_regions(&self.header, self.image_size)?;
+            for entry in &entries {
+                if let RomFsEntry::File { path, offset, size } = entry {
+                    regions.add_shared(path.as_str(), *offset, *size)?;
+                }
+            }
+            regions.check_overlaps()?;
+        }
+        Ok(entries)
+    }
+
+    /// Returns a reader over the data of a file, using the `offset` and
+    /// `size` given by `entries`.
+    pub fn open_file(&mut self, offset: u64, size: u64) -> io::Result<impl Read + Seek + '_> {
+        self.inner.seek(SeekFrom::Start(offset))?;
+        Ok(ReadRange::new(&mut self.inner, offset, size))
+    }
+
+    fn file_sha256(&mut self, offset: u64, size: u64) -> Result<String, Error> {
+        let mut file = self.open_file(offset, size)?;
+        let mut hasher = Sha256::default();
+        let mut buf = vec![0; 0x10000];
+        let mut remaining = size;
+        while remaining != 0 {
+            let read = file.read(&mut buf)?;
+            if read == 0 {
+                return Err(invalid_romfs("file data is out of bounds"));
+            }
+            hasher.update(&buf[..read]);
+            remaining -= read as u64;
+        }
+        Ok(hasher
+            .finalize()
+            .iter()
+            .map(|v| format!("{:02x}", v))
+            .collect())
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+/// Entries are the files of the image, named by their path, e.g.
+/// `/data/a.bin`.
+impl<R: Read + Seek> SwitchFormat for RomFsReader<R> {
+    fn detect(header: &[u8]) -> bool {
+        FileFormat::detect(header) == Some(FileFormat::RomFs)
+    }
+
+    fn list_entries(&self) -> Result<Vec<ContainerEntry>, Error> {
+        Ok(self
+            .entries()?
+            .into_iter()
+            .filter_map(|entry| match entry {
+                RomFsEntry::File { path, size, .. } => Some(ContainerEntry { name: path, size }),
+                RomFsEntry::Dir { .. } => None,
+            })
+            .collect())
+    }
+
+    fn extract_entry(&mut self, name: &str) -> Result<Box<dyn ReadSeek + '_>, Error> {
+        match self.find_file(name)? {
+            Some(RomFsEntry::File { offset, size, .. }) => {
+                Ok(Box::new(self.open_file(offset, size)?))
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No file at {} in the RomFS", name),
+            )
+            .into()),
+        }
+    }
+
+    fn metadata(&self) -> Result<serde_json::Value, Error> {
+        let entries = self.entries()?;
+        let files = entries
+            .iter()
+            .filter(|entry| matches!(entry, RomFsEntry::File { .. }))
+            .count();
+        Ok(serde_json::json!({
+            "files": files,
+            "directories": entries.len() - files,
+        }))
+    }
+}
+
+impl<R: Read + Write + Seek> RomFsReader<R> {
+    /// Overwrites the content of the file at `path` with `size` bytes read
+    /// from `data`, without rebuilding the image. Only the file data and the
+    /// size in its entry are rewritten, so the new content has to fit in the
+    /// space used by the old one, up to the start of the next file or table.
+    pub fn replace_file(
+        &mut self,
+        path: &str,
+        data: &mut dyn Read,
+        size: u64,
+    ) -> Result<(), Error> {
+        let (entry_offset, file) = match self.lookup_file(path)? {
+            Some(file) => file,
+            None => {
+                return Err(Error::InvalidRomFsPath {
+                    path: path.to_string(),
+                    backtrace: Backtrace::generate(),
+                })
+            }
+        };
+
+        // The space available ends where the next file or metadata table
+        // starts, or at the end of the image. Empty files get the offset of
+        // the file after them, so another file starting at the same offset
+        // leaves no space unless it is empty itself.
+        let image_size = self.inner.seek(SeekFrom::End(0))?;
+        let data_start = self.header.file_partition_ofs.saturating_add(file.offset);
+        let own_path = format!("/{}", path.trim_start_matches('/'));
+        let tables = [
+            self.header.dir_hash_table_ofs,
+            self.header.dir_table_ofs,
+            self.header.file_hash_table_ofs,
+            self.header.file_table_ofs,
+        ];
+        let data_end = self
+            .entries()?
+            .iter()
+            .filter_map(|entry| match entry {
+                RomFsEntry::File {
+                    path, offset, size, ..
+                } if *path != own_path => {
+                    if *offset > data_start || (*offset == data_start && *size != 0) {
+                        Some(*offset)
+                    } else {
+                        None
+                    }
+                }
+                _ => None,
+            })
+            .chain(
+                tables
+                    .iter()
+                    .cloned()
+                    .filter(|offset| *offset >= data_start),
+            )
+            .min()
+            .unwrap_or(image_size);
+        let available = data_end.saturating_sub(data_start);
+        if size > available {
+            return Err(Error::RomFsReplaceTooBig {
+                path: path.to_string(),
+                size,
+                available,
+                backtrace: Backtrace::generate(),
+            });
+        }
+
+        self.inner.seek(SeekFrom::Start(data_start))?;
+        let written = io::copy(&mut data.take(size), &mut self.inner)?;
+        if written != size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Replacement data is shorter than expected",
+            )
+            .into());
+        }
+        // Clear what's left of the old content, so the image doesn't leak it.
+        if file.size > size {
+            io::copy(&mut io::repeat(0).take(file.size - size), &mut self.inner)?;
+        }
+
+        // The size follows the parent, sibling and offset fields.
+        let size_offset = entry_offset as usize + 0x10;
+        self.inner.seek(SeekFrom::Start(
+            self.header.file_table_ofs + size_offset as u64,
+        ))?;
+        self.inner.write_u64::<LE>(size)?;
+        self.file_table[size_offset..size_offset + 8].copy_from_slice(&size.to_le_bytes());
+        self.inner.flush()?;
+        Ok(())
+    }
+}
+
+/// A difference between two RomFS images, as returned by `diff`. Hashes are
+/// hex-encoded SHA-256 of the file data.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum RomFsChange {
+    Added {
+        path: String,
+        size: u64,
+        sha256: String,
+    },
+    Removed {
+        path: String,
+        size: u64,
+        sha256: String,
+    },
+    Modified {
+        path: String,
+        old_size: u64,
+        new_size: u64,
+        old_sha256: String,
+        new_sha256: String,
+    },
+}
+
+impl RomFsChange {
+    pub fn path(&self) -> &str {
+        match self {
+            RomFsChange::Added { path, .. } => path,
+            RomFsChange::Removed { path, .. } => path,
+            RomFsChange::Modified { path, .. } => path,
+        }
+    }
+}
+
+fn file_map(entries: Vec<RomFsEntry>) -> BTreeMap<String, (u64, u64)> {
+    entries
+        .into_iter()
+        .filter_map(|entry| match entry {
+            RomFsEntry::File { path, offset, size } => Some((path, (offset, size))),
+            RomFsEntry::Dir { .. } => None,
+        })
+        .collect()
+}
+
+/// Compares the files of two RomFS images. Files present in both images are
+/// reported as modified if their size or content differ. The changes are
+/// sorted by path.
+pub fn diff<A: Read + Seek, B: Read + Seek>(
+    old: &mut RomFsReader<A>,
+    new: &mut RomFsReader<B>,
+) -> Result<Vec<RomFsChange>, Error> {
+    let old_files = file_map(old.entries()?);
+    let new_files = file_map(new.entries()?);
+
+    let mut changes = Vec::new();
+    for (path, &(offset, size)) in old_files.iter() {
+        let old_sha256 = old.file_sha256(offset, size)?;
+        match new_files.get(path) {
+            None => changes.push(RomFsChange::Removed {
+                path: path.clone(),
+                size,
+                sha256: old_sha256,
+            }),
+            Some(&(new_offset, new_size)) => {
+                let new_sha256 = new.file_sha256(new_offset, new_size)?;
+                if size != new_size || old_sha256 != new_sha256 {
+                    changes.push(RomFsChange::Modified {
+                        path: path.clone(),
+                        old_size: size,
+                        new_size,
+                        old_sha256,
+                        new_sha256,
+                    });
+                }
+            }
+        }
+    }
+    for (path, &(offset, size)) in new_files.iter() {
+        if !old_files.contains_key(path) {
+            changes.push(RomFsChange::Added {
+                path: path.clone(),
+                size,
+                sha256: new.file_sha256(offset, size)?,
+            });
+        }
+    }
+    changes.sort_by(|a, b| a.path().cmp(b.path()));
+    Ok(changes)
+}
+
+/// SHA-256 of every file of a RomFS image, keyed by path without the leading
+/// `/`. It is stored in the `sha256sum` format, so an extracted image can also
+/// be checked with `sha256sum -c`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RomFsManifest {
+    files: BTreeMap<String, String>,
+}
+
+/// A file whose hash doesn't match the manifest, as returned by
+/// `RomFsManifest::verify`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "problem", rename_all = "snake_case")]
+pub enum ManifestMismatch {
+    /// The file is listed in the manifest but is absent from the image.
+    Missing { path: String },
+    /// The file is present in the image but isn't listed in the manifest.
+    Unexpected { path: String },
+    Modified {
+        path: String,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl RomFsManifest {
+    /// Hashes every file of `romfs`.
+    pub fn from_romfs<R: Read + Seek>(romfs: &mut RomFsReader<R>) -> Result<RomFsManifest, Error> {
+        let mut files = BTreeMap::new();
+        for entry in romfs.entries()? {
+            if let RomFsEntry::File { path, offset, size } = entry {
+                let hash = romfs.file_sha256(offset, size)?;
+                files.insert(path[1..].to_string(), hash);
+            }
+        }
+        Ok(RomFsManifest { files })
+    }
+
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<RomFsManifest, Error> {
+        let mut files = BTreeMap::new();
+        for (idx, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = || Error::InvalidManifest {
+                line: idx + 1,
+                backtrace: Backtrace::generate(),
+            };
+            // `sha256sum` separates the hash and the path with a space and a
+            // mode character, either ' ' (text) or '*' (binary).
+            if line.len() < 66 || !line.is_char_boundary(64) {
+                return Err(invalid());
+            }
+            let (hash, rest) = line.split_at(64);
+            if !hash.bytes().all(|c| c.is_ascii_hexdigit())
+                || !(rest.starts_with("  ") || rest.starts_with(" *"))
+            {
+                return Err(invalid());
+            }
+            let path = rest[2..].trim_start_matches('/');
+            files.insert(path.to_string(), hash.to_ascii_lowercase());
+        }
+        Ok(RomFsManifest { files })
+    }
+
+    pub fn write(&self, to: &mut dyn Write) -> io::Result<()> {
+        for (path, hash) in self.files.iter() {
+            writeln!(to, "{}  {}", hash, path)?;
+        }
+        Ok(())
+    }
+
+    /// Compares the hashes of `actual` against this manifest. Returns the
+    /// files that differ, sorted by path.
+    pub fn verify(&self, actual: &RomFsManifest) -> Vec<ManifestMismatch> {
+        let mut mismatches = Vec::new();
+        for (path, expected) in self.files.iter() {
+            match actual.files.get(path) {
+                None => mismatches.push(ManifestMismatch::Missing { path: path.clone() }),
+                Some(hash) if hash != expected => mismatches.push(ManifestMismatch::Modified {
+                    path: path.clone(),
+                    expected: expected.clone(),
+                    actual: hash.clone(),
+                }),
+                Some(_) => (),
+            }
+        }
+        for path in actual.files.keys() {
+            if !self.files.contains_key(path) {
+                mismatches.push(ManifestMismatch::Unexpected { path: path.clone() });
+            }
+        }
+        mismatches.sort_by(|a, b| a.path().cmp(b.path()));
+        mismatches
+    }
+}
+
+impl ManifestMismatch {
+    pub fn path(&self) -> &str {
+        match self {
+            ManifestMismatch::Missing { path } => path,
+            ManifestMismatch::Unexpected { path } => path,
+            ManifestMismatch::Modified { path, .. } => path,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read_file(romfs: &mut RomFsReader<io::Cursor<Vec<u8>>>, path: &str) -> Vec<u8> {
+        let (offset, size) = match romfs.find_file(path).unwrap() {
+            Some(RomFsEntry::File { offset, size, .. }) => (offset, size),
+            _ => panic!("{} not found", path),
+        };
+        let mut data = Vec::new();
+        romfs
+            .open_file(offset, size)
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn replace_file_keeps_to_its_space() {
+        let mut builder = RomFsBuilder::new();
+        builder.add_file("a", &[][..], 0).unwrap();
+        builder.add_file("b", &[1; 0x10][..], 0x10).unwrap();
+        builder.add_file("c", &[2; 0x10][..], 0x10).unwrap();
+        let mut data = Vec::new();
+        builder.build().write(&mut data).unwrap();
+        let mut romfs = RomFsReader::from_reader(io::Cursor::new(data)).unwrap();
+
+        // The empty file shares its offset with the next one, but has no
+        // space of its own.
+        let err = romfs.replace_file("/a", &mut &[3; 4][..], 4).err().unwrap();
+        match err {
+            Error::RomFsReplaceTooBig { available, .. } => assert_eq!(available, 0),
+            err => panic!("unexpected error {}", err),
+        }
+        assert_eq!(read_file(&mut romfs, "/b"), [1; 0x10]);
+
+        // The file after an empty one still has its whole space.
+        romfs.replace_file("/b", &mut &[4; 0x10][..], 0x10).unwrap();
+        assert!(romfs.replace_file("/b", &mut &[4; 0x11][..], 0x11).is_err());
+        assert!(read_file(&mut romfs, "/a").is_empty());
+        assert_eq!(read_file(&mut romfs, "/b"), [4; 0x10]);
+        assert_eq!(read_file(&mut romfs, "/c"), [2; 0x10]);
+
+        // Shrinking a file clears the end of its old content.
+        romfs.replace_file("/c", &mut &[5; 2][..], 2).unwrap();
+        assert_eq!(read_file(&mut romfs, "/c"), [5; 2]);
+    }
+
+    #[test]
+    fn entries_list_the_tree_depth_first() {
+        let mut builder = RomFsBuilder::new();
+        builder.add_file("top.bin", &[1; 0x10][..], 0x10).unwrap();
+        builder.add_dir("empty").unwrap();
+        builder
+            .add_file("data/a.bin", &[2; 0x20][..], 0x20)
+            .unwrap();
+        builder
+            .add_file("data/sub/b.bfres", &[3; 0x30][..], 0x30)
+            .unwrap();
+        let mut data = Vec::new();
+        builder.build().write(&mut data).unwrap();
+        let mut romfs = RomFsReader::from_reader(io::Cursor::new(data)).unwrap();
+
+        let entries = romfs.entries().unwrap();
+        let listed: Vec<_> = entries
+            .iter()
+            .map(|entry| (entry.path(), entry.name(), entry.depth()))
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                ("/top.bin", "top.bin", 1),
+                ("/data", "data", 1),
+                ("/data/a.bin", "a.bin", 2),
+                ("/data/sub", "sub", 2),
+                ("/data/sub/b.bfres", "b.bfres", 3),
+                ("/empty", "empty", 1),
+            ]
+        );
+        for (entry, content) in entries
+            .iter()
+            .filter(|entry| matches!(entry, RomFsEntry::File { .. }))
+            .zip(&[[1; 0x10].to_vec(), [2; 0x20].to_vec(), [3; 0x30].to_vec()])
+        {
+            if let RomFsEntry::File { offset, size, .. } = *entry {
+                assert_eq!(size, content.len() as u64);
+                let mut data = Vec::new();
+                romfs
+                    .open_file(offset, size)
+                    .unwrap()
+                    .read_to_end(&mut data)
+                    .unwrap();
+                assert_eq!(&data, content);
+            }
+        }
+
+        let found = romfs.find("**/*.bfres").unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].path(), "/data/sub/b.bfres");
+        assert_eq!(
+            serde_json::to_value(&entries[1]).unwrap(),
+            serde_json::json!({ "type": "dir", "path": "/data" })
+        );
+        let json = serde_json::to_value(&found[0]).unwrap();
+        assert_eq!(json["type"], "file");
+        assert_eq!(json["size"], 0x30);
+    }
+
+    #[test]
+    fn overlays_cant_turn_files_into_directories() {
+        let base = tempfile::tempdir().unwrap();
+        let overlay = tempfile::tempdir().unwrap();
+        fs::write(base.path().join("a"), b"file").unwrap();
+        fs::create_dir(overlay.path().join("a")).unwrap();
+        fs::write(overlay.path().join("a").join("b"), b"file").unwrap();
+        fs::create_dir(base.path().join("c")).unwrap();
+        fs::write(overlay.path().join("c"), b"file").unwrap();
+
+        let options = RomFsSourceOptions::default();
+        for sources in &[[base.path(), overlay.path()], [overlay.path(), base.path()]] {
+            match RomFs::from_directories(sources, &options) {
+                Err(Error::InvalidRomFsPath { path, .. }) => assert!(path == "/a" || path == "/c"),
+                Err(err) => panic!("unexpected error {}", err),
+                Ok(_) => panic!("the conflict wasn't found"),
+            }
+        }
+
+        let mut builder = RomFsBuilder::new();
+        builder.add_file("a/b", &[][..], 0).unwrap();
+        assert!(builder.add_file("a", &[][..], 0).is_err());
+        assert!(builder.add_dir("a/b").is_err());
+        assert!(builder.add_file("a/b/c", &[][..], 0).is_err());
+        builder.add_file("a/b", &[1][..], 1).unwrap();
+    }
+
+    #[test]
+    fn entries_with_path_names_are_rejected() {
+        let mut builder = RomFsBuilder::new();
+        builder.add_file("xx/yy", &[1; 0x10][..], 0x10).unwrap();
+        let mut image = Vec::new();
+        builder.build().write(&mut image).unwrap();
+        RomFsReader::from_reader(io::Cursor::new(image.clone()))
+            .unwrap()
+            .entries()
+            .unwrap();
+
+        // Renames the directory or the file in the tables, as a crafted
+        // image would, so that extracting it would escape its directory.
+        for (name, renamed) in &[(b"xx", b".."), (b"yy", b"/a")] {
+            let mut data = image.clone();
+            let at = data.windows(2).position(|v| v == *name).unwrap();
+            data[at..at + 2].copy_from_slice(*renamed);
+            let romfs = RomFsReader::from_reader(io::Cursor::new(data)).unwrap();
+            assert!(romfs.entries().is_err());
+        }
+    }
+}
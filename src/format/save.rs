@@ -0,0 +1,953 @@
+//! Save files, as stored on the console and dumped by save managers.
+//!
+//! A save file stacks several storages on top of each other:
+//!
+//! - The data remap storage maps the image itself. It holds the duplex
+//!   storage and the journal data.
+//! - The duplex storage keeps two copies of every block, a bitmap telling
+//!   which one is current. The bitmap is itself a duplex storage, whose bitmap
+//!   is in the header.
+//! - The metadata remap storage maps the duplex storage. It holds the journal
+//!   map, the allocation table and the IVFC hash levels.
+//! - The journal storage maps the journal data block by block. The filesystem
+//!   lives in the data level of its IVFC tree.
+//!
+//! Files of the filesystem are chains of blocks in the allocation table. The
+//! directory and file tables are stored the same way.
+
+use crate::error::Error;
+use crate::format::parse::{is_valid_entry_name, ParseMode, Regions};
+use crate::utils::add_offset;
+use byteorder::{ByteOrder, LittleEndian};
+use serde_derive::Serialize;
+use sha2::{Digest, Sha256};
+use snafu::Backtrace;
+use snafu::GenerateBacktrace;
+use std::cmp;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// Size of each of the two copies of the header.
+const SAVE_HEADER_SIZE: usize = 0x4000;
+
+/// Size of an entry of the directory and file tables.
+const SAVE_TABLE_ENTRY_SIZE: usize = 0x60;
+
+/// Longest name of a directory or file.
+const SAVE_NAME_MAX_SIZE: usize = 0x40;
+
+/// Index of the entry heading the list of used entries in the tables.
+const SAVE_USED_LIST_HEAD: u32 = 1;
+
+fn invalid_save(error: &'static str) -> Error {
+    Error::InvalidSave {
+        error,
+        backtrace: Backtrace::generate(),
+    }
+}
+
+fn invalid_data(error: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/// Maps `size` bytes at `virtual_offset` of a remap storage to
+/// `physical_offset` of the storage it maps.
+#[derive(Debug, Clone, Copy)]
+struct RemapEntry {
+    virtual_offset: u64,
+    physical_offset: u64,
+    size: u64,
+}
+
+/// Reads the `count` entries of a remap storage at `offset`. They are read
+/// one at a time, the count not being trusted for allocations.
+fn read_remap_entries<R: Read + Seek>(
+    file: &mut R,
+    offset: u64,
+    count: u32,
+) -> Result<Vec<RemapEntry>, Error> {
+    let mut entries = Vec::new();
+    let mut entry = [0; 0x20];
+    file.seek(SeekFrom::Start(offset))?;
+    for _ in 0..count {
+        file.read_exact(&mut entry)?;
+        entries.push(RemapEntry {
+            virtual_offset: LittleEndian::read_u64(&entry[0x0..]),
+            physical_offset: LittleEndian::read_u64(&entry[0x8..]),
+            size: LittleEndian::read_u64(&entry[0x10..]),
+        });
+    }
+    Ok(entries)
+}
+
+/// Total size of the data mapped by a remap storage.
+fn mapped_size(map: &[RemapEntry]) -> u64 {
+    map.iter()
+        .fold(0u64, |size, entry| size.saturating_add(entry.size))
+}
+
+/// Checks the entries of a remap storage in strict mode: each one must map a
+/// part of the `base_size` bytes of the storage it maps, and neither their
+/// virtual nor their physical ranges can overlap.
+fn check_remap_entries(name: &str, map: &[RemapEntry], base_size: u64) -> Result<(), Error> {
+    let mut physical = Regions::within(format!("the storage mapped by the {}", name), base_size);
+    let mut virtual_ = Regions::within(format!("the {}", name), u64::MAX);
+    for (i, entry) in map.iter().enumerate() {
+        let region = format!("entry {} of the {}", i, name);
+        physical.add(region.clone(), entry.physical_offset, entry.size)?;
+        virtual_.add(region, entry.virtual_offset, entry.size)?;
+    }
+    physical.check_overlaps()?;
+    virtual_.check_overlaps()
+}
+
+/// Reads `buf` at `offset` of a remap storage, `read_base` reading the
+/// storage it maps.
+fn read_remapped<F>(
+    map: &[RemapEntry],
+    offset: u64,
+    buf: &mut [u8],
+    mut read_base: F,
+) -> io::Result<()>
+where
+    F: FnMut(u64, &mut [u8]) -> io::Result<()>,
+{
+    let mut done = 0;
+    while done < buf.len() {
+        let pos = offset
+            .checked_add(done as u64)
+            .ok_or_else(|| invalid_data("save remap storage read out of its entries"))?;
+        let entry = map
+            .iter()
+            .find(|entry| pos >= entry.virtual_offset && pos - entry.virtual_offset < entry.size)
+            .ok_or_else(|| invalid_data("save remap storage read out of its entries"))?;
+        let entry_pos = pos - entry.virtual_offset;
+        let len = cmp::min((buf.len() - done) as u64, entry.size - entry_pos) as usize;
+        let physical_offset = entry
+            .physical_offset
+            .checked_add(entry_pos)
+            .ok_or_else(|| invalid_data("save remap entry is out of bounds"))?;
+        read_base(physical_offset, &mut buf[done..done + len])?;
+        done += len;
+    }
+    Ok(())
+}
+
+/// Picks every block of a duplex storage from its copy `a` or `b`, depending
+/// on its bit in `bitmap`. Bits are read from the most significant one of
+/// little endian words.
+fn resolve_duplex(a: &[u8], b: &[u8], bitmap: &[u8], block_size: usize) -> Result<Vec<u8>, Error> {
+    let mut data = a.to_vec();
+    for (idx, block) in data.chunks_mut(block_size).enumerate() {
+        let word = bitmap
+            .get(idx / 32 * 4..idx / 32 * 4 + 4)
+            .ok_or_else(|| invalid_save("duplex bitmap is too small"))?;
+        if LittleEndian::read_u32(word) & (0x8000_0000 >> (idx % 32)) != 0 {
+            let start = idx * block_size;
+            block.copy_from_slice(&b[start..start + block.len()]);
+        }
+    }
+    Ok(data)
+}
+
+/// Returns the segments of the chain starting at `block` in the allocation
+/// table, as a first block and a block count.
+fn read_chain(fat: &[u8], block: u32) -> Result<Vec<(u32, u32)>, Error> {
+    // Entry 0 heads the list of free blocks, block N has entry N + 1.
+    let entry = |index: u32| {
+        let offset = index as usize * 8;
+        fat.get(offset..offset + 8)
+            .map(|entry| {
+                (
+                    LittleEndian::read_u32(&entry[0x0..]),
+                    LittleEndian::read_u32(&entry[0x4..]),
+                )
+            })
+            .ok_or_else(|| invalid_save("allocation table entry is out of bounds"))
+    };
+
+    let mut segments = Vec::new();
+    let mut block = Some(block);
+    while let Some(current) = block {
+        if segments.len() > fat.len() / 8 {
+            return Err(invalid_save("allocation table contains a loop"));
+        }
+        let index = current
+            .checked_add(1)
+            .ok_or_else(|| invalid_save("allocation table entry is out of bounds"))?;
+        let (prev, next) = entry(index)?;
+        if segments.is_empty() && prev != 0x8000_0000 {
+            return Err(invalid_save("chain doesn't start at a list head"));
+        }
+        // Segments of several blocks are followed by an entry giving the
+        // index of their last block.
+        let count = if next & 0x8000_0000 != 0 {
+            let (_, last) = entry(index + 1)?;
+            (last & 0x7FFF_FFFF)
+                .checked_sub(index)
+                .ok_or_else(|| invalid_save("allocation table segment is invalid"))?
+                + 1
+        } else {
+            1
+        };
+        segments.push((current, count));
+        block = match next & 0x7FFF_FFFF {
+            0 => None,
+            next => Some(next - 1),
+        };
+    }
+    Ok(segments)
+}
+
+/// An entry of a save file, as returned by `SaveFile::entries`.
+///
+/// Paths are absolute within the save and `/`-separated (e.g. `/data/a.bin`).
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SaveEntry {
+    Dir {
+        path: String,
+    },
+    File {
+        path: String,
+        start_block: u32,
+        size: u64,
+    },
+}
+
+impl SaveEntry {
+    pub fn path(&self) -> &str {
+        match self {
+            SaveEntry::Dir { path } => path,
+            SaveEntry::File { path, .. } => path,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        let path = self.path();
+        &path[path.rfind('/').map(|v| v + 1).unwrap_or(0)..]
+    }
+
+    /// Number of directories between the root and this entry. Entries at the
+    /// root of the save have a depth of 1.
+    pub fn depth(&self) -> usize {
+        self.path().matches('/').count()
+    }
+}
+
+/// An entry of the directory or file table.
+struct RawTableEntry {
+    parent: u32,
+    name: String,
+    next_sibling: u32,
+    /// First child directory of a directory, first block of a file.
+    first: u32,
+    /// First file of a directory, size of a file.
+    second: u64,
+    next: u32,
+}
+
+fn table_entry(table: &[u8], index: u32, is_dir: bool) -> Result<RawTableEntry, Error> {
+    let offset = index as usize * SAVE_TABLE_ENTRY_SIZE;
+    let entry = table
+        .get(offset..offset + SAVE_TABLE_ENTRY_SIZE)
+        .ok_or_else(|| invalid_save("table entry is out of bounds"))?;
+    let name = &entry[0x4..0x4 + SAVE_NAME_MAX_SIZE];
+    let name = &name[..name.iter().position(|&c| c == 0).unwrap_or(name.len())];
+    Ok(RawTableEntry {
+        parent: LittleEndian::read_u32(&entry[0x0..]),
+        name: String::from_utf8(name.to_vec())?,
+        next_sibling: LittleEndian::read_u32(&entry[0x44..]),
+        first: LittleEndian::read_u32(&entry[0x48..]),
+        second: if is_dir {
+            LittleEndian::read_u32(&entry[0x4C..]) as u64
+        } else {
+            LittleEndian::read_u64(&entry[0x4C..])
+        },
+        next: LittleEndian::read_u32(&entry[0x5C..]),
+    })
+}
+
+/// A save file opened for reading. The metadata, the duplex storage and the
+/// directory and file tables are loaded in memory, file data is read on
+/// demand.
+pub struct SaveFile<R> {
+    inner: R,
+    data_map: Vec<RemapEntry>,
+    journal_offset: u64,
+    journal_block_size: u64,
+    journal_map: Vec<u32>,
+    fs_offset: u64,
+    fs_size: u64,
+    block_size: u64,
+    fat: Vec<u8>,
+    dir_table: Vec<u8>,
+    file_table: Vec<u8>,
+}
+
+/// Reads the two copies of the header, returning the first one whose hash
+/// matches.
+fn read_header<R: Read + Seek>(file: &mut R) -> Result<Vec<u8>, Error> {
+    let mut header = vec![0; SAVE_HEADER_SIZE];
+    for copy in 0..2 {
+        file.seek(SeekFrom::Start((copy * SAVE_HEADER_SIZE) as u64))?;
+        file.read_exact(&mut header)?;
+        if &header[0x100..0x104] != b"DISF" {
+            continue;
+        }
+        if Sha256::digest(&header[0x300..])[..] == header[0x108..0x128] {
+            return Ok(header);
+        }
+    }
+    Err(invalid_save("no header has a valid hash"))
+}
+
+impl<R: Read + Seek> SaveFile<R> {
+    pub fn from_reader(inner: R) -> Result<SaveFile<R>, Error> {
+        SaveFile::from_reader_with_mode(inner, ParseMode::Lenient)
+    }
+
+    /// Same as `from_reader`, checking the file as told by `mode`. Strict mode
+    /// checks the remap tables and the data they map against the size of the
+    /// file, and the entries of both remap storages against the storage they
+    /// map, none overlapping another. Nothing is read from a storage in
+    /// memory if it's bigger than the data mapped, and the filesystem must be
+    /// in the journal storage.
+    pub fn from_reader_with_mode(mut inner: R, mode: ParseMode) -> Result<SaveFile<R>, Error> {
+        let header = read_header(&mut inner)?;
+        let u32_at = |offset: usize| LittleEndian::read_u32(&header[offset..]);
+        let u64_at = |offset: usize| LittleEndian::read_u64(&header[offset..]);
+        // Fields of the layout, at the start of the header.
+        let layout = |offset: usize| u64_at(0x100 + offset);
+        for &(offset, magic) in &[
+            (0x300, b"DPFS"),
+            (0x344, b"IVFC"),
+            (0x408, b"JNGL"),
+            (0x608, b"SAVE"),
+            (0x650, b"RMAP"),
+            (0x690, b"RMAP"),
+        ] {
+            if &header[offset..offset + 4] != magic {
+                return Err(invalid_save("header magic is wrong"));
+            }
+        }
+
+        if mode.is_strict() {
+            let mut regions = Regions::new(inner.seek(SeekFrom::End(0))?);
+            regions.add("headers", 0, 2 * SAVE_HEADER_SIZE as u64)?;
+            let table_size = |count: u32| u64::from(count) * 0x20;
+            regions.add("data remap table", layout(0x28), table_size(u32_at(0x658)))?;
+            regions.add(
+                "metadata remap table",
+                layout(0x38),
+                table_size(u32_at(0x698)),
+            )?;
+            regions.add("remapped data", layout(0x48), layout(0x50))?;
+            regions.check_overlaps()?;
+        }
+
+        let mut data_map = read_remap_entries(&mut inner, layout(0x28), u32_at(0x658))?;
+        if mode.is_strict() {
+            check_remap_entries("data remap storage", &data_map, layout(0x50))?;
+        }
+        for entry in &mut data_map {
+            entry.physical_offset = entry
+                .physical_offset
+                .checked_add(layout(0x48))
+                .ok_or_else(|| invalid_save("data remap entry is out of bounds"))?;
+        }
+        let data_size = mapped_size(&data_map);
+        let mut read_data = |offset: u64, size: u64| -> io::Result<Vec<u8>> {
+            if mode.is_strict() && size > data_size {
+                return Err(invalid_data("save data is bigger than its remap storage"));
+            }
+            let mut buf = vec![0; size as usize];
+            read_remapped(&data_map, offset, &mut buf, |offset, buf| {
+                inner.seek(SeekFrom::Start(offset))?;
+                inner.read_exact(buf)
+            })?;
+            Ok(buf)
+        };
+
+        // The duplex index picks the copy of the master bitmap in use.
+        let master_offset = if layout(0x158) == 1 {
+            layout(0xB0)
+        } else {
+            layout(0xA8)
+        } as usize;
+        let master_bitmap = header
+            .get(master_offset..)
+            .and_then(|bitmap| bitmap.get(..layout(0xB8) as usize))
+            .ok_or_else(|| invalid_save("duplex master bitmap is out of bounds"))?;
+        let duplex_block_size = |layer: usize| {
+            1usize
+                .checked_shl(u32_at(0x308 + layer * 0x14 + 0x10))
+                .ok_or_else(|| invalid_save("invalid duplex block size"))
+        };
+        let l1_bitmap = resolve_duplex(
+            &read_data(layout(0x58), layout(0x68))?,
+            &read_data(layout(0x60), layout(0x68))?,
+            master_bitmap,
+            duplex_block_size(1)?,
+        )?;
+        let duplex = resolve_duplex(
+            &read_data(layout(0x70), layout(0x80))?,
+            &read_data(layout(0x78), layout(0x80))?,
+            &l1_bitmap,
+            duplex_block_size(2)?,
+        )?;
+
+        let meta_map = read_remap_entries(&mut inner, layout(0x38), u32_at(0x698))?;
+        if mode.is_strict() {
+            check_remap_entries("metadata remap storage", &meta_map, duplex.len() as u64)?;
+        }
+        let meta_size = mapped_size(&meta_map);
+        let read_meta = |offset: u64, size: u64| -> io::Result<Vec<u8>> {
+            if mode.is_strict() && size > meta_size {
+                return Err(invalid_data(
+                    "save metadata is bigger than its remap storage",
+                ));
+            }
+            let mut buf = vec![0; size as usize];
+            read_remapped(&meta_map, offset, &mut buf, |offset, buf| {
+                let data = duplex
+                    .get(offset as usize..)
+                    .and_then(|data| data.get(..buf.len()))
+                    .ok_or_else(|| invalid_data("save metadata is out of bounds"))?;
+                buf.copy_from_slice(data);
+                Ok(())
+            })?;
+            Ok(buf)
+        };
+
+        let journal_block_count = u32_at(0x42C) as u64;
+        let journal_map = read_meta(layout(0xD8), journal_block_count * 8)?
+            .chunks(8)
+            .map(|entry| LittleEndian::read_u32(entry) & 0x7FFF_FFFF)
+            .collect();
+
+        // The filesystem is the data level of the IVFC tree, the master hash
+        // counting as a level.
+        let level_count = u32_at(0x350) as usize;
+        if !(2..=7).contains(&level_count) {
+            return Err(invalid_save("invalid IVFC level count"));
+        }
+        let data_level = 0x354 + (level_count - 2) * 0x18;
+
+        let fat = read_meta(layout(0x148), layout(0x150))?;
+
+        let mut save = SaveFile {
+            inner,
+            data_map,
+            journal_offset: layout(0x88),
+            journal_block_size: u64_at(0x420),
+            journal_map,
+            fs_offset: u64_at(data_level),
+            fs_size: u64_at(data_level + 0x8),
+            block_size: u64_at(0x618),
+            fat,
+            dir_table: Vec::new(),
+            file_table: Vec::new(),
+        };
+        if save.journal_block_size == 0 || save.block_size == 0 {
+            return Err(invalid_save("block size is zero"));
+        }
+        if mode.is_strict() {
+            // The journal storage maps its blocks to the data remap storage.
+            let journal_size = journal_block_count
+                .checked_mul(save.journal_block_size)
+                .filter(|&size| size <= data_size)
+                .ok_or_else(|| invalid_save("journal is bigger than its remap storage"))?;
+            Regions::within("the journal storage", journal_size).add(
+                "filesystem",
+                save.fs_offset,
+                save.fs_size,
+            )?;
+        }
+        save.dir_table = save.read_table(u32_at(0x648))?;
+        save.file_table = save.read_table(u32_at(0x64C))?;
+        Ok(save)
+    }
+
+    /// Reads `buf` at `offset` of the filesystem, going through the journal.
+    fn read_fs(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        if offset
+            .checked_add(buf.len() as u64)
+            .map_or(true, |end| end > self.fs_size)
+        {
+            return Err(invalid_data("save data is out of bounds"));
+        }
+        let inner = &mut self.inner;
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = (offset + done as u64)
+                .checked_add(self.fs_offset)
+                .ok_or_else(|| invalid_data("save data is out of bounds"))?;
+            let block_pos = pos % self.journal_block_size;
+            let len = cmp::min(
+                (buf.len() - done) as u64,
+                self.journal_block_size - block_pos,
+            ) as usize;
+            let block = *self
+                .journal_map
+                .get((pos / self.journal_block_size) as usize)
+                .ok_or_else(|| invalid_data("save journal block is out of bounds"))?;
+            let journal_offset = self.journal_offset;
+            let journal_pos = u64::from(block)
+                .checked_mul(self.journal_block_size)
+                .and_then(|pos| pos.checked_add(block_pos))
+                .and_then(|pos| pos.checked_add(journal_offset))
+                .ok_or_else(|| invalid_data("save journal block is out of bounds"))?;
+            read_remapped(
+                &self.data_map,
+                journal_pos,
+                &mut buf[done..done + len],
+                |offset, buf| {
+                    inner.seek(SeekFrom::Start(offset))?;
+                    inner.read_exact(buf)
+                },
+            )?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Reads the whole chain of blocks starting at `block`.
+    fn read_table(&mut self, block: u32) -> Result<Vec<u8>, Error> {
+        let mut table = Vec::new();
+        for (first, count) in read_chain(&self.fat, block)? {
+            // Segments are checked to be in the filesystem before making room
+            // for them.
+            let offset = u64::from(first).checked_mul(self.block_size);
+            let size = u64::from(count).checked_mul(self.block_size);
+            let (offset, size) = match (offset, size) {
+                (Some(offset), Some(size))
+                    if offset
+                        .checked_add(size)
+                        .map_or(false, |end| end <= self.fs_size) =>
+                {
+                    (offset, size)
+                }
+                _ => return Err(invalid_save("table is out of the filesystem")),
+            };
+            let start = table.len();
+            table.resize(start + size as usize, 0);
+            self.read_fs(offset, &mut table[start..])?;
+        }
+        Ok(table)
+    }
+
+    /// Looks up the root directory, the one with an empty name and no parent,
+    /// in the list of used entries of the directory table.
+    fn root_dir(&self) -> Result<RawTableEntry, Error> {
+        let max_entries = self.dir_table.len() / SAVE_TABLE_ENTRY_SIZE;
+        let mut index = table_entry(&self.dir_table, SAVE_USED_LIST_HEAD, true)?.next;
+        for _ in 0..max_entries {
+            if index == 0 {
+                break;
+            }
+            let dir = table_entry(&self.dir_table, index, true)?;
+            if dir.parent == 0 && dir.name.is_empty() {
+                return Ok(dir);
+            }
+            index = dir.next;
+        }
+        Err(invalid_save("root directory is missing"))
+    }
+
+    /// Walks the directory and file tables and returns every directory and
+    /// file of the save, depth first, each directory being followed by its
+    /// content.
+    pub fn entries(&self) -> Result<Vec<SaveEntry>, Error> {
+        let max_entries = (self.dir_table.len() + self.file_table.len()) / SAVE_TABLE_ENTRY_SIZE;
+
+        let mut entries = Vec::new();
+        let mut dirs = vec![(self.root_dir()?, String::new())];
+        while let Some((dir, path)) = dirs.pop() {
+            if !path.is_empty() {
+                entries.push(SaveEntry::Dir { path: path.clone() });
+            }
+
+            let mut file_index = dir.second as u32;
+            while file_index != 0 {
+                let file = table_entry(&self.file_table, file_index, false)?;
+                if !is_valid_entry_name(&file.name) {
+                    return Err(invalid_save("a file name isn't a valid file name"));
+                }
+                entries.push(SaveEntry::File {
+                    path: format!("{}/{}", path, file.name),
+                    start_block: file.first,
+                    size: file.second,
+                });
+                if entries.len() > max_entries {
+                    return Err(invalid_save("file table contains a loop"));
+                }
+                file_index = file.next_sibling;
+            }
+
+            let mut children = Vec::new();
+            let mut child_index = dir.first;
+            while child_index != 0 {
+                let child = table_entry(&self.dir_table, child_index, true)?;
+                if !is_valid_entry_name(&child.name) {
+                    return Err(invalid_save("a directory name isn't a valid file name"));
+                }
+                child_index = child.next_sibling;
+                let child_path = format!("{}/{}", path, child.name);
+                children.push((child, child_path));
+                if entries.len() + children.len() + dirs.len() > max_entries {
+                    return Err(invalid_save("directory table contains a loop"));
+                }
+            }
+            // Pushed in reverse so they get popped in order.
+            dirs.extend(children.into_iter().rev());
+        }
+        Ok(entries)
+    }
+
+    /// Returns a reader over the data of a file, using the `start_block` and
+    /// `size` given by `entries`.
+    pub fn open_file(
+        &mut self,
+        start_block: u32,
+        size: u64,
+    ) -> Result<SaveFileReader<'_, R>, Error> {
+        let segments = if size == 0 {
+            Vec::new()
+        } else {
+            read_chain(&self.fat, start_block)?
+        };
+        let chain_size = segments.iter().try_fold(0u64, |size, &(_, count)| {
+            u64::from(count)
+                .checked_mul(self.block_size)
+                .and_then(|segment_size| size.checked_add(segment_size))
+        });
+        if chain_size.map_or(true, |chain_size| chain_size < size) {
+            return Err(invalid_save("file is bigger than its chain of blocks"));
+        }
+        Ok(SaveFileReader {
+            save: self,
+            segments,
+            size,
+            pos: 0,
+        })
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+/// The data of a file of a save, as returned by `SaveFile::open_file`.
+pub struct SaveFileReader<'a, R> {
+    save: &'a mut SaveFile<R>,
+    segments: Vec<(u32, u32)>,
+    size: u64,
+    pos: u64,
+}
+
+impl<'a, R: Read + Seek> Read for SaveFileReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let block_size = self.save.block_size;
+        let mut segment_start = 0;
+        for &(first, count) in &self.segments {
+            let segment_size = count as u64 * block_size;
+            if self.pos < segment_start + segment_size {
+                let segment_pos = self.pos - segment_start;
+                let len = cmp::min(
+                    buf.len() as u64,
+                    cmp::min(self.size, segment_start + segment_size) - self.pos,
+                ) as usize;
+                let offset = u64::from(first)
+                    .checked_mul(block_size)
+                    .and_then(|offset| offset.checked_add(segment_pos))
+                    .ok_or_else(|| invalid_data("save data is out of bounds"))?;
+                self.save.read_fs(offset, &mut buf[..len])?;
+                self.pos += len as u64;
+                return Ok(len);
+            }
+            segment_start += segment_size;
+        }
+        Ok(0)
+    }
+}
+
+impl<'a, R> Seek for SaveFileReader<'a, R> {
+    fn seek(&mut self, from: SeekFrom) -> io::Result<u64> {
+        let pos = match from {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::Current(offset) => add_offset(self.pos, offset),
+            SeekFrom::End(offset) => add_offset(self.size, offset),
+        };
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seek before position 0",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(virtual_offset: u64, physical_offset: u64, size: u64) -> RemapEntry {
+        RemapEntry {
+            virtual_offset,
+            physical_offset,
+            size,
+        }
+    }
+
+    #[test]
+    fn strict_mode_checks_remap_entries() {
+        let map = [entry(0, 0x100, 0x100), entry(0x100, 0, 0x100)];
+        check_remap_entries("data remap storage", &map, 0x200).unwrap();
+        assert!(check_remap_entries("data remap storage", &map, 0x1FF).is_err());
+
+        let physical_overlap = [entry(0, 0, 0x100), entry(0x100, 0x80, 0x100)];
+        assert!(check_remap_entries("data remap storage", &physical_overlap, 0x200).is_err());
+        let virtual_overlap = [entry(0, 0, 0x100), entry(0x80, 0x100, 0x100)];
+        assert!(check_remap_entries("data remap storage", &virtual_overlap, 0x200).is_err());
+    }
+
+    #[test]
+    fn mapped_size_saturates() {
+        assert_eq!(
+            mapped_size(&[entry(0, 0, 0x100), entry(0x100, 0, 0x80)]),
+            0x180
+        );
+        assert_eq!(
+            mapped_size(&[entry(0, 0, u64::MAX), entry(0, 0, 1)]),
+            u64::MAX
+        );
+    }
+
+    /// An entry of the directory or file table.
+    fn write_table_entry(
+        table: &mut [u8],
+        index: usize,
+        parent: u32,
+        name: &str,
+        first: u32,
+        second: u64,
+        next: u32,
+    ) {
+        let entry = &mut table[index * SAVE_TABLE_ENTRY_SIZE..][..SAVE_TABLE_ENTRY_SIZE];
+        LittleEndian::write_u32(&mut entry[0x0..], parent);
+        entry[0x4..0x4 + name.len()].copy_from_slice(name.as_bytes());
+        LittleEndian::write_u32(&mut entry[0x48..], first);
+        LittleEndian::write_u64(&mut entry[0x4C..], second);
+        LittleEndian::write_u32(&mut entry[0x5C..], next);
+    }
+
+    fn a_bin() -> Vec<u8> {
+        (0..0x300u32).map(|v| v as u8).collect()
+    }
+
+    fn b_bin() -> Vec<u8> {
+        let mut data = vec![0xBB; 0x250];
+        data[0x200..].copy_from_slice(&[0xBC; 0x50]);
+        data
+    }
+
+    /// Builds a save holding `/a.bin` and `/dir/b.bin`. Its metadata comes
+    /// from both copies of the duplex storage, and its filesystem blocks are
+    /// stored in reverse order in the journal.
+    fn test_save() -> Vec<u8> {
+        let w32 = LittleEndian::write_u32;
+        let w64 = LittleEndian::write_u64;
+
+        // The filesystem: the directory table in block 0, the file table in
+        // block 1, a.bin in blocks 2 and 3, and b.bin in blocks 4 and 6.
+        let mut fs = vec![0; 0x1000];
+        write_table_entry(&mut fs, 1, 0, "", 0, 0, 2);
+        write_table_entry(&mut fs, 2, 0, "", 3, 2, 3);
+        write_table_entry(&mut fs, 3, 2, "dir", 0, 3, 0);
+        write_table_entry(&mut fs[0x200..], 2, 2, "a.bin", 2, 0x300, 0);
+        write_table_entry(&mut fs[0x200..], 3, 3, "b.bin", 4, 0x250, 0);
+        fs[0x400..0x700].copy_from_slice(&a_bin());
+        fs[0x800..0xA00].copy_from_slice(&b_bin()[..0x200]);
+        fs[0xC00..0xC50].copy_from_slice(&b_bin()[0x200..]);
+
+        // The metadata: the journal map, and the allocation table in the
+        // second block of the duplex storage, taken from its copy B.
+        let mut meta = vec![0; 0x1000];
+        for i in 0..8 {
+            w32(&mut meta[i * 8..], 0x8000_0000 | (7 - i as u32));
+        }
+        let fat = &mut meta[0x200..0x248];
+        for &(index, prev, next) in &[
+            (1, 0x8000_0000, 0),
+            (2, 0x8000_0000, 0),
+            (3, 0x8000_0000, 0x8000_0000),
+            (4, 0x8000_0003, 4),
+            (5, 0x8000_0000, 7),
+            (7, 5, 0),
+        ] {
+            w32(&mut fat[index * 8..], prev);
+            w32(&mut fat[index * 8 + 4..], next);
+        }
+
+        // The data remap storage: the bitmaps of the duplex storage at 0x0
+        // and 0x100, its copies at 0x1000 and 0x2000, and the journal at
+        // 0x4000.
+        let mut data = vec![0; 0x5000];
+        w32(&mut data[0x100..], 0x4000_0000);
+        data[0x1000..0x2000].copy_from_slice(&meta);
+        data[0x1200..0x1400].copy_from_slice(&[0xFF; 0x200]);
+        data[0x2200..0x2400].copy_from_slice(&meta[0x200..0x400]);
+        for (i, block) in fs.chunks(0x200).enumerate() {
+            let offset = 0x4000 + (7 - i) * 0x200;
+            data[offset..offset + 0x200].copy_from_slice(block);
+        }
+
+        let mut header = vec![0; SAVE_HEADER_SIZE];
+        for &(offset, magic) in &[
+            (0x100, b"DISF"),
+            (0x300, b"DPFS"),
+            (0x344, b"IVFC"),
+            (0x408, b"JNGL"),
+            (0x608, b"SAVE"),
+            (0x650, b"RMAP"),
+            (0x690, b"RMAP"),
+        ] {
+            header[offset..offset + 4].copy_from_slice(magic);
+        }
+        for &(offset, value) in &[
+            // Remap tables and remapped data.
+            (0x28, 0x8000),
+            (0x38, 0x8100),
+            (0x48, 0x9000),
+            (0x50, 0x5000),
+            // Duplex bitmaps and data, and journal.
+            (0x58, 0x0),
+            (0x60, 0x100),
+            (0x68, 0x10),
+            (0x70, 0x1000),
+            (0x78, 0x2000),
+            (0x80, 0x1000),
+            (0x88, 0x4000),
+            // Master bitmap, in the header, picking copy B of the first
+            // block of the bitmaps.
+            (0xA8, 0x3000),
+            (0xB0, 0x3010),
+            (0xB8, 4),
+            // Journal map and allocation table.
+            (0xD8, 0x0),
+            (0x148, 0x200),
+            (0x150, 0x48),
+        ] {
+            w64(&mut header[0x100 + offset..], value);
+        }
+        w32(&mut header[0x3000..], 0x8000_0000);
+        w32(&mut header[0x32C..], 4);
+        w32(&mut header[0x340..], 9);
+        w32(&mut header[0x350..], 2);
+        w64(&mut header[0x35C..], 0x1000);
+        w64(&mut header[0x420..], 0x200);
+        w32(&mut header[0x42C..], 8);
+        w64(&mut header[0x618..], 0x200);
+        w32(&mut header[0x64C..], 1);
+        w32(&mut header[0x658..], 1);
+        w32(&mut header[0x698..], 1);
+        let hash = Sha256::digest(&header[0x300..]);
+        header[0x108..0x128].copy_from_slice(&hash);
+
+        let mut file = vec![0; 0x9000];
+        file[..0x4000].copy_from_slice(&header);
+        file[0x4000..0x8000].copy_from_slice(&header);
+        w64(&mut file[0x8010..], 0x5000);
+        w64(&mut file[0x8110..], 0x1000);
+        file.extend_from_slice(&data);
+        file
+    }
+
+    fn read_save_files(save: &mut SaveFile<io::Cursor<Vec<u8>>>) -> Vec<(String, Vec<u8>)> {
+        let mut files = Vec::new();
+        for entry in save.entries().unwrap() {
+            if let SaveEntry::File {
+                path,
+                start_block,
+                size,
+            } = entry
+            {
+                let mut data = Vec::new();
+                save.open_file(start_block, size)
+                    .unwrap()
+                    .read_to_end(&mut data)
+                    .unwrap();
+                files.push((path, data));
+            }
+        }
+        files
+    }
+
+    #[test]
+    fn save_files_are_listed_and_read() {
+        let data = test_save();
+        let mut save = SaveFile::from_reader(io::Cursor::new(data.clone())).unwrap();
+        let entries = save.entries().unwrap();
+        let listed: Vec<_> = entries
+            .iter()
+            .map(|entry| (entry.path(), entry.name(), entry.depth()))
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                ("/a.bin", "a.bin", 1),
+                ("/dir", "dir", 1),
+                ("/dir/b.bin", "b.bin", 2),
+            ]
+        );
+        let files = [
+            ("/a.bin".to_string(), a_bin()),
+            ("/dir/b.bin".to_string(), b_bin()),
+        ];
+        assert_eq!(read_save_files(&mut save), files);
+
+        let mut save =
+            SaveFile::from_reader_with_mode(io::Cursor::new(data), ParseMode::Strict).unwrap();
+        assert_eq!(read_save_files(&mut save), files);
+    }
+
+    #[test]
+    fn entries_with_path_names_are_rejected() {
+        // Renames the directory or the file in the tables, as a crafted save
+        // would, so that extracting it would escape its directory.
+        let image = test_save();
+        for (name, renamed) in &[(&b"dir\0"[..], &b"..\0\0"[..]), (b"a.bin", b"../ab")] {
+            let mut data = image.clone();
+            let at = data.windows(name.len()).position(|v| v == *name).unwrap();
+            data[at..at + name.len()].copy_from_slice(renamed);
+            let save = SaveFile::from_reader(io::Cursor::new(data)).unwrap();
+            assert!(save.entries().is_err());
+        }
+    }
+
+    #[test]
+    fn second_header_is_used_when_the_first_is_corrupted() {
+        let mut data = test_save();
+        data[0x3FFF] ^= 1;
+        let mut save = SaveFile::from_reader(io::Cursor::new(data.clone())).unwrap();
+        assert_eq!(read_save_files(&mut save).len(), 2);
+
+        data[0x7FFF] ^= 1;
+        assert!(SaveFile::from_reader(io::Cursor::new(data)).is_err());
+    }
+
+    #[test]
+    fn strict_mode_rejects_remap_entries_out_of_the_file() {
+        let mut data = test_save();
+        LittleEndian::write_u64(&mut data[0x8010..], 0x6000);
+        SaveFile::from_reader(io::Cursor::new(data.clone())).unwrap();
+        assert!(SaveFile::from_reader_with_mode(io::Cursor::new(data), ParseMode::Strict).is_err());
+    }
+}
@@ -0,0 +1,358 @@
+use crate::error::Error;
+use crate::format::parse::{ParseMode, Regions};
+use crate::format::utils::serialize_hex_bytes;
+use crate::pki::{Aes128Key, Keys};
+use byteorder::{ByteOrder, LittleEndian};
+use serde_derive::Serialize;
+use snafu::Backtrace;
+use snafu::GenerateBacktrace;
+use std::fmt;
+use std::io::Read;
+
+fn invalid_ticket(error: &'static str) -> Error {
+    Error::InvalidTicket {
+        error,
+        backtrace: Backtrace::generate(),
+    }
+}
+
+/// Issuer of the common tickets of retail titles.
+pub const COMMON_TICKET_ISSUER: &str = "Root-CA00000003-XS00000020";
+
+/// Size of the ticket data following the signature.
+const TICKET_DATA_SIZE: usize = 0x180;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SignatureType {
+    Rsa4096Sha1 = 0x10000,
+    Rsa2048Sha1 = 0x10001,
+    EcdsaSha1 = 0x10002,
+    Rsa4096Sha256 = 0x10003,
+    Rsa2048Sha256 = 0x10004,
+    EcdsaSha256 = 0x10005,
+}
+
+impl SignatureType {
+    pub fn from_u32(value: u32) -> Option<SignatureType> {
+        match value {
+            0x10000 => Some(SignatureType::Rsa4096Sha1),
+            0x10001 => Some(SignatureType::Rsa2048Sha1),
+            0x10002 => Some(SignatureType::EcdsaSha1),
+            0x10003 => Some(SignatureType::Rsa4096Sha256),
+            0x10004 => Some(SignatureType::Rsa2048Sha256),
+            0x10005 => Some(SignatureType::EcdsaSha256),
+            _ => None,
+        }
+    }
+
+    /// Size of the signature alone.
+    pub fn size(self) -> usize {
+        match self {
+            SignatureType::Rsa4096Sha1 | SignatureType::Rsa4096Sha256 => 0x200,
+            SignatureType::Rsa2048Sha1 | SignatureType::Rsa2048Sha256 => 0x100,
+            SignatureType::EcdsaSha1 | SignatureType::EcdsaSha256 => 0x3C,
+        }
+    }
+
+    /// Size of the signature type, the signature and its padding, which
+    /// aligns what follows to 0x40 bytes.
+    pub fn padded_size(self) -> usize {
+        (4 + self.size() + 0x3F) & !0x3F
+    }
+}
+
+impl fmt::Display for SignatureType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SignatureType::Rsa4096Sha1 => "RSA-4096 SHA-1",
+            SignatureType::Rsa2048Sha1 => "RSA-2048 SHA-1",
+            SignatureType::EcdsaSha1 => "ECDSA SHA-1",
+            SignatureType::Rsa4096Sha256 => "RSA-4096 SHA-256",
+            SignatureType::Rsa2048Sha256 => "RSA-2048 SHA-256",
+            SignatureType::EcdsaSha256 => "ECDSA SHA-256",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TitlekeyType {
+    /// The titlekey block holds the titlekey encrypted with the titlekek.
+    Common = 0,
+    /// The titlekey block holds the titlekey encrypted with the RSA key of a
+    /// console.
+    Personalized = 1,
+}
+
+impl fmt::Display for TitlekeyType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TitlekeyType::Common => "Common",
+            TitlekeyType::Personalized => "Personalized",
+        })
+    }
+}
+
+/// A ticket, giving the titlekey of the NCAs with its rights ID.
+#[derive(Clone, Serialize)]
+pub struct Ticket {
+    pub signature_type: SignatureType,
+    #[serde(serialize_with = "serialize_hex_bytes")]
+    pub signature: Vec<u8>,
+    pub issuer: String,
+    #[serde(serialize_with = "serialize_hex_bytes")]
+    pub titlekey_block: [u8; 0x100],
+    pub format_version: u8,
+    pub titlekey_type: TitlekeyType,
+    pub ticket_version: u16,
+    pub license_type: u8,
+    /// Master key revision the titlekey is encrypted with.
+    pub key_generation: u8,
+    pub property_mask: u16,
+    pub ticket_id: u64,
+    pub device_id: u64,
+    #[serde(serialize_with = "serialize_hex_bytes")]
+    pub rights_id: [u8; 0x10],
+    pub account_id: u32,
+}
+
+impl Ticket {
+    /// Makes an unsigned common ticket for `rights_id`, holding `titlekey`
+    /// encrypted with the titlekek of the master key revision found in the
+    /// rights ID.
+    pub fn common(
+        rights_id: [u8; 0x10],
+        titlekey: &[u8; 0x10],
+        keys: &Keys,
+    ) -> Result<Ticket, Error> {
+        let key_generation = rights_id[0xF];
+        let encrypted_titlekey = keys
+            .titlekek(key_generation as usize)?
+            .generate_kek(titlekey)?;
+        let mut titlekey_block = [0; 0x100];
+        titlekey_block[..0x10].copy_from_slice(encrypted_titlekey.as_bytes());
+        Ok(Ticket {
+            signature_type: SignatureType::Rsa2048Sha256,
+            signature: vec![0xFF; SignatureType::Rsa2048Sha256.size()],
+            issuer: COMMON_TICKET_ISSUER.to_string(),
+            titlekey_block,
+            format_version: 2,
+            titlekey_type: TitlekeyType::Common,
+            ticket_version: 0,
+            license_type: 0,
+            key_generation,
+            property_mask: 0,
+            ticket_id: 0,
+            device_id: 0,
+            rights_id,
+            account_id: 0,
+        })
+    }
+
+    /// Makes an unsigned common ticket holding the titlekey of this one,
+    /// e.g. to use a personalized ticket on another console. The ticket ID,
+    /// version and properties are kept, the device and account IDs dropped.
+    pub fn to_common(&self, keys: &Keys) -> Result<Ticket, Error> {
+        let titlekey = self.titlekey(keys)?;
+        let encrypted_titlekey = keys
+            .titlekek(self.key_generation as usize)?
+            .generate_kek(titlekey.as_bytes())?;
+        let mut titlekey_block = [0; 0x100];
+        titlekey_block[..0x10].copy_from_slice(encrypted_titlekey.as_bytes());
+        Ok(Ticket {
+            signature_type: SignatureType::Rsa2048Sha256,
+            signature: vec![0xFF; SignatureType::Rsa2048Sha256.size()],
+            issuer: COMMON_TICKET_ISSUER.to_string(),
+            titlekey_block,
+            titlekey_type: TitlekeyType::Common,
+            device_id: 0,
+            account_id: 0,
+            ..self.clone()
+        })
+    }
+
+    pub fn from_reader<R: Read>(reader: R) -> Result<Ticket, Error> {
+        Ticket::from_reader_with_mode(reader, ParseMode::Lenient)
+    }
+
+    /// Same as `from_reader`, checking the file as told by `mode`.
+    pub fn from_reader_with_mode<R: Read>(mut reader: R, mode: ParseMode) -> Result<Ticket, Error> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        Ticket::from_bytes_with_mode(&data, mode)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Ticket, Error> {
+        Ticket::from_bytes_with_mode(data, ParseMode::Lenient)
+    }
+
+    /// Same as `from_bytes`, checking the file as told by `mode`. Strict mode
+    /// checks that the ticket is followed by the section records it gives
+    /// the size of, and by nothing else.
+    pub fn from_bytes_with_mode(data: &[u8], mode: ParseMode) -> Result<Ticket, Error> {
+        if data.len() < 4 {
+            return Err(invalid_ticket("the ticket is truncated"));
+        }
+        let signature_type = SignatureType::from_u32(LittleEndian::read_u32(data))
+            .ok_or_else(|| invalid_ticket("unknown signature type"))?;
+        let offset = signature_type.padded_size();
+        let ticket = data
+            .get(offset..offset + TICKET_DATA_SIZE)
+            .ok_or_else(|| invalid_ticket("the ticket is truncated"))?;
+
+        let issuer = &ticket[..0x40];
+        let issuer_len = issuer.iter().position(|&b| b == 0).unwrap_or(0x40);
+        let mut titlekey_block = [0; 0x100];
+        titlekey_block.copy_from_slice(&ticket[0x40..0x140]);
+        let titlekey_type = match ticket[0x141] {
+            0 => TitlekeyType::Common,
+            1 => TitlekeyType::Personalized,
+            _ => return Err(invalid_ticket("unknown titlekey type")),
+        };
+        let mut rights_id = [0; 0x10];
+        rights_id.copy_from_slice(&ticket[0x160..0x170]);
+        if mode.is_strict() {
+            let sections_offset = (offset + TICKET_DATA_SIZE) as u64;
+            let sections_size = LittleEndian::read_u32(&ticket[0x174..]).into();
+            let mut regions = Regions::new(data.len() as u64);
+            regions.add("signature", 0, offset as u64)?;
+            regions.add("ticket data", offset as u64, TICKET_DATA_SIZE as u64)?;
+            regions.add("section records", sections_offset, sections_size)?;
+            if sections_offset + sections_size != data.len() as u64 {
+                return Err(invalid_ticket("data follows the section records"));
+            }
+            regions.check_overlaps()?;
+        }
+
+        Ok(Ticket {
+            signature_type,
+            signature: data[4..4 + signature_type.size()].to_vec(),
+            issuer: String::from_utf8_lossy(&issuer[..issuer_len]).into_owned(),
+            titlekey_block,
+            format_version: ticket[0x140],
+            titlekey_type,
+            ticket_version: LittleEndian::read_u16(&ticket[0x142..]),
+            license_type: ticket[0x144],
+            key_generation: ticket[0x145],
+            property_mask: LittleEndian::read_u16(&ticket[0x146..]),
+            ticket_id: LittleEndian::read_u64(&ticket[0x150..]),
+            device_id: LittleEndian::read_u64(&ticket[0x158..]),
+            rights_id,
+            account_id: LittleEndian::read_u32(&ticket[0x170..]),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let offset = self.signature_type.padded_size();
+        let mut data = vec![0; offset + TICKET_DATA_SIZE];
+        LittleEndian::write_u32(&mut data, self.signature_type as u32);
+        let signature_len = self.signature.len().min(self.signature_type.size());
+        data[4..4 + signature_len].copy_from_slice(&self.signature[..signature_len]);
+
+        let ticket = &mut data[offset..];
+        let issuer = self.issuer.as_bytes();
+        let issuer_len = issuer.len().min(0x40);
+        ticket[..issuer_len].copy_from_slice(&issuer[..issuer_len]);
+        ticket[0x40..0x140].copy_from_slice(&self.titlekey_block);
+        ticket[0x140] = self.format_version;
+        ticket[0x141] = self.titlekey_type as u8;
+        LittleEndian::write_u16(&mut ticket[0x142..], self.ticket_version);
+        ticket[0x144] = self.license_type;
+        ticket[0x145] = self.key_generation;
+        LittleEndian::write_u16(&mut ticket[0x146..], self.property_mask);
+        LittleEndian::write_u64(&mut ticket[0x150..], self.ticket_id);
+        LittleEndian::write_u64(&mut ticket[0x158..], self.device_id);
+        ticket[0x160..0x170].copy_from_slice(&self.rights_id);
+        LittleEndian::write_u32(&mut ticket[0x170..], self.account_id);
+        data
+    }
+
+    /// Decrypts the titlekey of the ticket with the titlekek of its key
+    /// generation. The titlekey of personalized tickets is first decrypted
+    /// with the eticket RSA key of the console they were issued to.
+    pub fn titlekey(&self, keys: &Keys) -> Result<Aes128Key, Error> {
+        let mut encrypted_titlekey = [0; 0x10];
+        if self.titlekey_type == TitlekeyType::Common {
+            encrypted_titlekey.copy_from_slice(&self.titlekey_block[..0x10]);
+        } else {
+            let titlekey = keys
+                .eticket_rsa_key()?
+                .decrypt_oaep(&self.titlekey_block)
+                .map_err(|_| invalid_ticket("could not decrypt the titlekey, wrong console?"))?;
+            if titlekey.len() != 0x10 {
+                return Err(invalid_ticket("bad personalized titlekey size"));
+            }
+            encrypted_titlekey.copy_from_slice(&titlekey);
+        }
+        keys.titlekek(self.key_generation as usize)?
+            .derive_key(&encrypted_titlekey)
+    }
+
+    /// File name of the ticket in NSPs: its rights ID in lowercase
+    /// hexadecimal.
+    pub fn file_name(&self) -> String {
+        format!("{}.tik", self.rights_id_hex())
+    }
+
+    /// File name of the certificate chain checking the ticket in NSPs.
+    pub fn cert_file_name(&self) -> String {
+        format!("{}.cert", self.rights_id_hex())
+    }
+
+    fn rights_id_hex(&self) -> String {
+        self.rights_id
+            .iter()
+            .map(|v| format!("{:02x}", v))
+            .collect()
+    }
+}
+
+/// Makes the rights ID of the NCAs of `title_id` encrypted with the titlekey
+/// of `master_key_revision`.
+pub fn rights_id(title_id: u64, master_key_revision: u8) -> [u8; 0x10] {
+    let mut rights_id = [0; 0x10];
+    rights_id[..8].copy_from_slice(&title_id.to_be_bytes());
+    rights_id[0xF] = master_key_revision;
+    rights_id
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ticket() -> Ticket {
+        Ticket {
+            signature_type: SignatureType::Rsa2048Sha256,
+            signature: vec![0xFF; SignatureType::Rsa2048Sha256.size()],
+            issuer: COMMON_TICKET_ISSUER.to_string(),
+            titlekey_block: [0; 0x100],
+            format_version: 2,
+            titlekey_type: TitlekeyType::Common,
+            ticket_version: 0,
+            license_type: 0,
+            key_generation: 0,
+            property_mask: 0,
+            ticket_id: 0,
+            device_id: 0,
+            rights_id: [0x42; 0x10],
+            account_id: 0,
+        }
+    }
+
+    #[test]
+    fn strict_mode_checks_what_follows_the_ticket() {
+        let mut data = ticket().to_bytes();
+        let ticket = Ticket::from_bytes_with_mode(&data, ParseMode::Strict).unwrap();
+        assert_eq!(ticket.rights_id, [0x42; 0x10]);
+
+        data.extend_from_slice(&[0; 0x10]);
+        Ticket::from_bytes(&data).unwrap();
+        assert!(Ticket::from_bytes_with_mode(&data, ParseMode::Strict).is_err());
+
+        // Unless they are the section records of the ticket.
+        let offset = SignatureType::Rsa2048Sha256.padded_size();
+        LittleEndian::write_u32(&mut data[offset + 0x174..], 0x10);
+        Ticket::from_bytes_with_mode(&data, ParseMode::Strict).unwrap();
+    }
+}
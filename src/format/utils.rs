@@ -2,7 +2,6 @@ use serde::de::{Unexpected, Visitor};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use sha2::{Digest, Sha256};
 use std::fmt;
-use std::fs::File;
 use std::io::{Read, Seek, SeekFrom};
 
 pub fn align(size: usize, padding: usize) -> usize {
@@ -16,13 +15,13 @@ pub fn add_padding(vec: &mut Vec<u8>, padding: usize) {
 
 pub fn check_string_or_truncate(string: &mut String, name: &str, size: usize) {
     if string.len() >= size {
-        println!("Warning: Truncating {} to 0x{:x}", name, size - 1);
+        log::warn!("Truncating {} to 0x{:x}", name, size - 1);
         string.truncate(size);
     }
 }
 
-pub fn get_segment_data(
-    file: &mut File,
+pub fn get_segment_data<R: Read + Seek>(
+    file: &mut R,
     header: &elf::types::ProgramHeader,
 ) -> std::io::Result<Vec<u8>> {
     let mut data = vec![0; header.filesz as usize];
@@ -31,25 +30,13 @@ pub fn get_segment_data(
     Ok(data)
 }
 
-pub fn compress_lz4(uncompressed_data: &mut Vec<u8>) -> std::io::Result<Vec<u8>> {
-    lz4::block::compress(&uncompressed_data[..], None, false)
-}
-
-pub fn compress_blz(uncompressed_data: &mut Vec<u8>) -> blz_nx::BlzResult<Vec<u8>> {
-    let mut compressed_data =
-        vec![0; blz_nx::get_worst_compression_buffer_size(uncompressed_data.len())];
-    let res = blz_nx::compress_raw(&mut uncompressed_data[..], &mut compressed_data[..])?;
-    compressed_data.resize(res, 0);
-    Ok(compressed_data)
-}
-
 pub fn calculate_sha256(data: &[u8]) -> std::io::Result<Vec<u8>> {
     let mut hasher = Sha256::default();
     hasher.update(data);
     Ok(Vec::from(hasher.finalize().as_slice()))
 }
 
-#[derive(Default)]
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
 pub struct HexOrNum(pub u64);
 
 impl fmt::Debug for HexOrNum {
@@ -79,6 +66,18 @@ impl<'de> Deserialize<'de> for HexOrNum {
                 Ok(v)
             }
 
+            fn visit_i64<E>(self, v: i64) -> Result<u64, E>
+            where
+                E: serde::de::Error,
+            {
+                // Some formats, like TOML, only have signed integers.
+                if v < 0 {
+                    Err(E::invalid_value(Unexpected::Signed(v), &self))
+                } else {
+                    Ok(v as u64)
+                }
+            }
+
             fn visit_str<E>(self, v: &str) -> Result<u64, E>
             where
                 E: serde::de::Error,
@@ -108,3 +107,22 @@ impl Serialize for HexOrNum {
         serializer.collect_str(&format_args!("{:#010x}", self.0))
     }
 }
+
+/// Serializes a title ID as 16 hexadecimal digits, the way it is usually
+/// written.
+pub fn serialize_title_id<S>(id: &u64, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.collect_str(&format_args!("{:016x}", id))
+}
+
+/// Serializes bytes, e.g. a hash, as a string of hexadecimal digits. Takes
+/// a slice, as large arrays don't implement `AsRef<[u8]>`.
+pub fn serialize_hex_bytes<S>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    serializer.serialize_str(&hex)
+}
@@ -0,0 +1,453 @@
+//! Sources of files for the builders of the container formats.
+//!
+//! A `Vfs` is a tree of directories and files the RomFS and PFS0 builders can
+//! take their content from, whether it lives on the host filesystem, in memory
+//! or in an archive. Paths are `/`-separated and relative to the root of the
+//! tree, the root itself being `""`.
+
+use crate::format::pfs0::ReadSeek;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read, Seek};
+use std::path::PathBuf;
+use std::rc::Rc;
+use zip::ZipArchive;
+
+/// What a path of a `Vfs` points to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VfsMetadata {
+    pub is_dir: bool,
+    /// The size of the file, 0 for directories.
+    pub size: u64,
+}
+
+/// A child of a directory, as returned by `Vfs::read_dir`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VfsEntry {
+    pub name: String,
+    pub metadata: VfsMetadata,
+}
+
+/// A read-only tree of directories and files.
+pub trait Vfs {
+    /// Lists the children of the directory at `path`, sorted by name.
+    fn read_dir(&self, path: &str) -> io::Result<Vec<VfsEntry>>;
+
+    /// Opens the file at `path`.
+    fn open(&self, path: &str) -> io::Result<Box<dyn ReadSeek>>;
+
+    fn metadata(&self, path: &str) -> io::Result<VfsMetadata>;
+}
+
+/// Joins the name of a child to the path of its directory.
+pub fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+/// Lists every file below the directory at `path`, depth first, along with
+/// their size. Paths are relative to the root of `vfs`.
+pub fn walk_files(vfs: &dyn Vfs, path: &str) -> io::Result<Vec<(String, u64)>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![path.to_string()];
+    while let Some(dir) = dirs.pop() {
+        for entry in vfs.read_dir(&dir)?.into_iter().rev() {
+            let entry_path = join(&dir, &entry.name);
+            if entry.metadata.is_dir {
+                dirs.push(entry_path);
+            } else {
+                files.push((entry_path, entry.metadata.size));
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Strips the leading and trailing slashes of `path`, rejecting the `.` and
+/// `..` components.
+fn normalize(path: &str) -> io::Result<String> {
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" => continue,
+            "." | ".." => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid path {}", path),
+                ))
+            }
+            _ => components.push(component),
+        }
+    }
+    Ok(components.join("/"))
+}
+
+fn not_found(path: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("No entry at /{}", path))
+}
+
+/// A directory of the host filesystem.
+///
+/// Symbolic links to files are followed. Symbolic links to directories are
+/// rejected, as they could make the tree infinite.
+pub struct HostFs {
+    root: PathBuf,
+}
+
+impl HostFs {
+    pub fn new<P: Into<PathBuf>>(root: P) -> HostFs {
+        HostFs { root: root.into() }
+    }
+
+    fn host_path(&self, path: &str) -> io::Result<PathBuf> {
+        Ok(self.root.join(normalize(path)?))
+    }
+}
+
+impl Vfs for HostFs {
+    fn read_dir(&self, path: &str) -> io::Result<Vec<VfsEntry>> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(self.host_path(path)?)? {
+            let entry = entry?;
+            let entry_path = entry.path();
+            let name = entry.file_name().into_string().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} has no valid file name", entry_path.display()),
+                )
+            })?;
+            let metadata = fs::metadata(&entry_path)?;
+            if metadata.is_dir() && entry.file_type()?.is_symlink() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Can't handle symlink \"{}\"", entry_path.display()),
+                ));
+            }
+            entries.push(VfsEntry {
+                name,
+                metadata: VfsMetadata {
+                    is_dir: metadata.is_dir(),
+                    size: if metadata.is_dir() { 0 } else { metadata.len() },
+                },
+            });
+        }
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+
+    fn open(&self, path: &str) -> io::Result<Box<dyn ReadSeek>> {
+        Ok(Box::new(File::open(self.host_path(path)?)?))
+    }
+
+    fn metadata(&self, path: &str) -> io::Result<VfsMetadata> {
+        let metadata = fs::metadata(self.host_path(path)?)?;
+        Ok(VfsMetadata {
+            is_dir: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+        })
+    }
+}
+
+/// A tree of files held in memory.
+///
+/// ```
+/// use linkle::format::vfs::{MemoryFs, Vfs};
+///
+/// let mut fs = MemoryFs::new();
+/// fs.insert("data/a.bin", vec![1, 2, 3])?;
+/// fs.insert_dir("empty")?;
+/// assert_eq!(fs.metadata("data/a.bin")?.size, 3);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug, Default)]
+pub struct MemoryFs {
+    files: BTreeMap<String, Rc<[u8]>>,
+    dirs: BTreeSet<String>,
+}
+
+impl MemoryFs {
+    pub fn new() -> MemoryFs {
+        MemoryFs::default()
+    }
+
+    /// Adds a file holding `data` at `path`, along with its parent
+    /// directories. Replaces any file previously added at the same path.
+    pub fn insert(&mut self, path: &str, data: Vec<u8>) -> io::Result<&mut MemoryFs> {
+        let path = normalize(path)?;
+        if path.is_empty() || self.dirs.contains(&path) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("A directory exists at /{}", path),
+            ));
+        }
+        if let Some(slash) = path.rfind('/') {
+            let parent = path[..slash].to_string();
+            self.insert_dir(&parent)?;
+        }
+        self.files.insert(path, data.into());
+        Ok(self)
+    }
+
+    /// Adds an empty directory at `path`, along with its parents.
+    pub fn insert_dir(&mut self, path: &str) -> io::Result<&mut MemoryFs> {
+        let path = normalize(path)?;
+        let mut dir = String::new();
+        for component in path.split('/').filter(|v| !v.is_empty()) {
+            dir = join(&dir, component);
+            if self.files.contains_key(&dir) {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("A file exists at /{}", dir),
+                ));
+            }
+            self.dirs.insert(dir.clone());
+        }
+        Ok(self)
+    }
+}
+
+impl Vfs for MemoryFs {
+    fn read_dir(&self, path: &str) -> io::Result<Vec<VfsEntry>> {
+        let path = normalize(path)?;
+        if !path.is_empty() && !self.dirs.contains(&path) {
+            return Err(not_found(&path));
+        }
+        let prefix = join(&path, "");
+        let child_name = |key: &str| -> Option<String> {
+            let name = key.strip_prefix(prefix.as_str())?;
+            if name.is_empty() || name.contains('/') {
+                None
+            } else {
+                Some(name.to_string())
+            }
+        };
+        let mut entries = Vec::new();
+        for dir in &self.dirs {
+            if let Some(name) = child_name(dir) {
+                entries.push(VfsEntry {
+                    name,
+                    metadata: VfsMetadata {
+                        is_dir: true,
+                        size: 0,
+                    },
+                });
+            }
+        }
+        for (file, data) in &self.files {
+            if let Some(name) = child_name(file) {
+                entries.push(VfsEntry {
+                    name,
+                    metadata: VfsMetadata {
+                        is_dir: false,
+                        size: data.len() as u64,
+                    },
+                });
+            }
+        }
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+
+    fn open(&self, path: &str) -> io::Result<Box<dyn ReadSeek>> {
+        let path = normalize(path)?;
+        match self.files.get(&path) {
+            Some(data) => Ok(Box::new(Cursor::new(data.clone()))),
+            None => Err(not_found(&path)),
+        }
+    }
+
+    fn metadata(&self, path: &str) -> io::Result<VfsMetadata> {
+        let path = normalize(path)?;
+        if path.is_empty() || self.dirs.contains(&path) {
+            Ok(VfsMetadata {
+                is_dir: true,
+                size: 0,
+            })
+        } else if let Some(data) = self.files.get(&path) {
+            Ok(VfsMetadata {
+                is_dir: false,
+                size: data.len() as u64,
+            })
+        } else {
+            Err(not_found(&path))
+        }
+    }
+}
+
+/// The content of a ZIP archive.
+///
+/// Directories missing from the archive are implied by the paths of their
+/// files. Files get decompressed to memory when opened, as ZIP entries can't
+/// be seeked in.
+pub struct ZipFs<R: Read + Seek> {
+    archive: RefCell<ZipArchive<R>>,
+    /// The index of the files in the archive, `None` for directories.
+    entries: BTreeMap<String, (Option<usize>, VfsMetadata)>,
+}
+
+impl<R: Read + Seek> ZipFs<R> {
+    pub fn new(reader: R) -> io::Result<ZipFs<R>> {
+        let mut archive = ZipArchive::new(reader)?;
+        let mut fs = MemoryFs::new();
+        let mut entries = BTreeMap::new();
+        for index in 0..archive.len() {
+            let entry = archive.by_index(index)?;
+            let path = normalize(entry.name())?;
+            if path.is_empty() {
+                continue;
+            }
+            // Let MemoryFs check the tree is consistent and find the implied
+            // directories.
+            if entry.is_dir() {
+                fs.insert_dir(&path)?;
+            } else {
+                fs.insert(&path, Vec::new())?;
+                let metadata = VfsMetadata {
+                    is_dir: false,
+                    size: entry.size(),
+                };
+                entries.insert(path, (Some(index), metadata));
+            }
+        }
+        for dir in fs.dirs {
+            let metadata = VfsMetadata {
+                is_dir: true,
+                size: 0,
+            };
+            entries.insert(dir, (None, metadata));
+        }
+        Ok(ZipFs {
+            archive: RefCell::new(archive),
+            entries,
+        })
+    }
+}
+
+impl<R: Read + Seek> Vfs for ZipFs<R> {
+    fn read_dir(&self, path: &str) -> io::Result<Vec<VfsEntry>> {
+        let path = normalize(path)?;
+        if !path.is_empty() && !self.metadata(&path)?.is_dir {
+            return Err(not_found(&path));
+        }
+        let prefix = join(&path, "");
+        let mut entries = Vec::new();
+        for (key, (_, metadata)) in self.entries.range(prefix.clone()..) {
+            let name = match key.strip_prefix(prefix.as_str()) {
+                Some(name) => name,
+                None => break,
+            };
+            if !name.is_empty() && !name.contains('/') {
+                entries.push(VfsEntry {
+                    name: name.to_string(),
+                    metadata: *metadata,
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    fn open(&self, path: &str) -> io::Result<Box<dyn ReadSeek>> {
+        let path = normalize(path)?;
+        let index = match self.entries.get(&path) {
+            Some((Some(index), _)) => *index,
+            _ => return Err(not_found(&path)),
+        };
+        let mut archive = self.archive.borrow_mut();
+        let mut entry = archive.by_index(index)?;
+        let mut data = Vec::with_capacity(entry.size() as usize);
+        entry.read_to_end(&mut data)?;
+        Ok(Box::new(Cursor::new(data)))
+    }
+
+    fn metadata(&self, path: &str) -> io::Result<VfsMetadata> {
+        let path = normalize(path)?;
+        if path.is_empty() {
+            return Ok(VfsMetadata {
+                is_dir: true,
+                size: 0,
+            });
+        }
+        match self.entries.get(&path) {
+            Some((_, metadata)) => Ok(*metadata),
+            None => Err(not_found(&path)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::format::romfs::{RomFsBuilder, RomFsEntry, RomFsReader};
+
+    #[test]
+    fn romfs_builds_from_memory() {
+        let mut fs = MemoryFs::new();
+        fs.insert("a/b.bin", vec![0x42; 0x21]).unwrap();
+        fs.insert_dir("empty").unwrap();
+        let mut builder = RomFsBuilder::new();
+        builder.add_vfs("data", Rc::new(fs)).unwrap();
+        let mut data = Vec::new();
+        builder.build().write(&mut data).unwrap();
+
+        let mut romfs = RomFsReader::from_reader(Cursor::new(data)).unwrap();
+        let paths = romfs
+            .entries()
+            .unwrap()
+            .iter()
+            .map(|v| v.path().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, ["/data", "/data/a", "/data/a/b.bin", "/data/empty"]);
+        let (offset, size) = match romfs.find_file("/data/a/b.bin").unwrap() {
+            Some(RomFsEntry::File { offset, size, .. }) => (offset, size),
+            entry => panic!("Unexpected entry {:?}", entry),
+        };
+        let mut content = Vec::new();
+        romfs
+            .open_file(offset, size)
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        assert_eq!(content, vec![0x42; 0x21]);
+    }
+
+    #[test]
+    fn zip_lists_implied_directories() {
+        let mut data = Cursor::new(Vec::new());
+        {
+            let mut zip = zip::ZipWriter::new(&mut data);
+            let options = zip::write::FileOptions::default()
+                .compression_method(zip::CompressionMethod::Stored);
+            zip.start_file("a/b/c.bin", options).unwrap();
+            std::io::Write::write_all(&mut zip, b"abc").unwrap();
+            zip.start_file("d.bin", options).unwrap();
+            zip.finish().unwrap();
+        }
+        data.set_position(0);
+
+        let fs = ZipFs::new(data).unwrap();
+        let names = |path| {
+            fs.read_dir(path)
+                .unwrap()
+                .into_iter()
+                .map(|v| v.name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(""), ["a", "d.bin"]);
+        assert_eq!(names("a"), ["b"]);
+        assert_eq!(
+            walk_files(&fs, "").unwrap(),
+            [("a/b/c.bin".to_string(), 3), ("d.bin".to_string(), 0)]
+        );
+        let mut content = Vec::new();
+        fs.open("/a/b/c.bin")
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        assert_eq!(content, b"abc");
+    }
+}
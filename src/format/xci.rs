@@ -0,0 +1,241 @@
+//! Gamecard images. Their content is in an HFS0 holding the partitions of the
+//! card (`update`, `normal`, `secure` and `logo`), themselves HFS0s.
+
+use crate::error::Error;
+use crate::format::container::{ContainerEntry, SwitchFormat};
+use crate::format::detect::FileFormat;
+use crate::format::parse::{ParseMode, Regions};
+use crate::format::pfs0::{self, Pfs0, ReadSeek};
+use crate::format::utils::serialize_title_id;
+use crate::utils::{ReadRange, TryClone};
+use byteorder::{ByteOrder, LittleEndian};
+use serde_derive::Serialize;
+use snafu::Backtrace;
+use snafu::GenerateBacktrace;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+
+fn invalid_xci(error: &'static str) -> Error {
+    Error::InvalidXci {
+        error,
+        backtrace: Backtrace::generate(),
+    }
+}
+
+/// The unencrypted part of the gamecard header.
+#[derive(Debug, Clone, Serialize)]
+pub struct XciHeader {
+    #[serde(serialize_with = "serialize_title_id")]
+    pub package_id: u64,
+    /// Capacity of the card in GiB, if the size byte is a known one.
+    pub rom_size: Option<u32>,
+    pub version: u8,
+    pub flags: u8,
+    /// Offset of the end of the data written on the card, in bytes.
+    pub valid_data_end: u64,
+    /// Offset and size of the header of the root HFS0, in bytes.
+    pub root_partition_offset: u64,
+    pub root_partition_header_size: u64,
+}
+
+impl XciHeader {
+    pub fn parse(data: &[u8]) -> Result<XciHeader, Error> {
+        if data.len() < 0x200 || &data[0x100..0x104] != b"HEAD" {
+            return Err(invalid_xci("magic is wrong"));
+        }
+        let rom_size = match data[0x10D] {
+            0xFA => Some(1),
+            0xF8 => Some(2),
+            0xF0 => Some(4),
+            0xE0 => Some(8),
+            0xE1 => Some(16),
+            0xE2 => Some(32),
+            _ => None,
+        };
+        Ok(XciHeader {
+            package_id: LittleEndian::read_u64(&data[0x110..]),
+            rom_size,
+            version: data[0x10E],
+            flags: data[0x10F],
+            valid_data_end: (u64::from(LittleEndian::read_u32(&data[0x118..])) + 1) * 0x200,
+            root_partition_offset: LittleEndian::read_u64(&data[0x130..]),
+            root_partition_header_size: LittleEndian::read_u64(&data[0x138..]),
+        })
+    }
+}
+
+/// A partition of the card, with its offset in the image.
+#[derive(Debug, Clone, Serialize)]
+pub struct XciPartition {
+    pub name: String,
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// A gamecard image opened for reading. Partitions are opened on demand.
+pub struct Xci<R> {
+    file: R,
+    header: XciHeader,
+    partitions: Vec<XciPartition>,
+    mode: ParseMode,
+}
+
+impl<R: Read + Seek + TryClone + 'static> Xci<R> {
+    pub fn from_reader(file: R) -> Result<Xci<R>, Error> {
+        Xci::from_reader_with_mode(file, ParseMode::Lenient)
+    }
+
+    /// Same as `from_reader`, checking the file as told by `mode`. The
+    /// partitions are opened with the same mode.
+    pub fn from_reader_with_mode(mut file: R, mode: ParseMode) -> Result<Xci<R>, Error> {
+        let mut data = vec![0; 0x200];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut data)?;
+        let header = XciHeader::parse(&data)?;
+
+        let root_offset = header.root_partition_offset;
+        let file_size = file.seek(SeekFrom::End(0))?;
+        let root_size = file_size
+            .checked_sub(root_offset)
+            .ok_or_else(|| invalid_xci("root partition is out of bounds"))?;
+        let root = ReadRange::new(file.try_clone()?, root_offset, root_size);
+        let partitions: Vec<_> = pfs0::read_entries(&mut BufReader::new(root), mode)?
+            .into_iter()
+            .map(|entry| XciPartition {
+                name: entry.name,
+                offset: root_offset + entry.offset,
+                size: entry.size,
+            })
+            .collect();
+
+        if mode.is_strict() {
+            let mut regions = Regions::new(file_size);
+            regions.add("header", 0, 0x200)?;
+            regions.add(
+                "root partition header",
+                root_offset,
+                header.root_partition_header_size,
+            )?;
+            for partition in &partitions {
+                let region = format!("{} partition", partition.name);
+                regions.add(region, partition.offset, partition.size)?;
+            }
+            regions.check_overlaps()?;
+        }
+        Ok(Xci {
+            file,
+            header,
+            partitions,
+            mode,
+        })
+    }
+
+    pub fn header(&self) -> &XciHeader {
+        &self.header
+    }
+
+    pub fn partitions(&self) -> &[XciPartition] {
+        &self.partitions
+    }
+
+    /// Opens the partition `name`, or returns `None` if the card doesn't
+    /// have it.
+    pub fn partition(&self, name: &str) -> Result<Option<Pfs0>, Error> {
+        let partition = match self.partitions.iter().find(|v| v.name == name) {
+            Some(partition) => partition,
+            None => return Ok(None),
+        };
+        let reader = ReadRange::new(self.file.try_clone()?, partition.offset, partition.size);
+        Ok(Some(Pfs0::from_reader_with_mode(reader, self.mode)?))
+    }
+
+    pub fn into_inner(self) -> R {
+        self.file
+    }
+}
+
+/// Entries are the files of every partition, named by the partition and
+/// their name, e.g. `secure/<content ID>.nca`.
+impl<R: Read + Seek + TryClone + 'static> SwitchFormat for Xci<R> {
+    fn detect(header: &[u8]) -> bool {
+        FileFormat::detect(header) == Some(FileFormat::Xci)
+    }
+
+    fn list_entries(&self) -> Result<Vec<ContainerEntry>, Error> {
+        let mut entries = Vec::new();
+        for partition in &self.partitions {
+            let pfs0 = match self.partition(&partition.name)? {
+                Some(pfs0) => pfs0,
+                None => continue,
+            };
+            for entry in pfs0.list_entries()? {
+                entries.push(ContainerEntry {
+                    name: format!("{}/{}", partition.name, entry.name),
+                    size: entry.size,
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    fn extract_entry(&mut self, name: &str) -> Result<Box<dyn ReadSeek + '_>, Error> {
+        let not_found = || {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No entry named {} in the XCI", name),
+            )
+        };
+        let slash = name.find('/').ok_or_else(not_found)?;
+        let pfs0 = self.partition(&name[..slash])?.ok_or_else(not_found)?;
+        let file = pfs0.into_file(&name[slash + 1..]).ok_or_else(not_found)??;
+        Ok(Box::new(file))
+    }
+
+    fn metadata(&self) -> Result<serde_json::Value, Error> {
+        Ok(serde_json::json!({
+            "header": self.header,
+            "partitions": self.partitions,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn xci_entries_are_listed_by_partition() {
+        let mut secure = Pfs0::new();
+        secure
+            .push_data("a.nca".to_string(), vec![0x42; 0x20])
+            .unwrap();
+        let mut secure_data = io::Cursor::new(Vec::new());
+        secure.write_pfs0(&mut secure_data).unwrap();
+        let mut root = Pfs0::new();
+        root.push_data("secure".to_string(), secure_data.into_inner())
+            .unwrap();
+        let mut root_data = io::Cursor::new(Vec::new());
+        root.write_pfs0(&mut root_data).unwrap();
+
+        let mut data = vec![0; 0x200];
+        data[0x100..0x104].copy_from_slice(b"HEAD");
+        data[0x10D] = 0xFA;
+        LittleEndian::write_u64(&mut data[0x130..], 0x200);
+        data.extend_from_slice(&root_data.into_inner());
+
+        let mut xci = Xci::from_reader(io::Cursor::new(data)).unwrap();
+        assert_eq!(xci.header().rom_size, Some(1));
+        let entries = xci.list_entries().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(
+            (entries[0].name.as_str(), entries[0].size),
+            ("secure/a.nca", 0x20)
+        );
+        let mut content = Vec::new();
+        xci.extract_entry("secure/a.nca")
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        assert_eq!(content, [0x42; 0x20]);
+        assert!(xci.extract_entry("normal/a.nca").is_err());
+    }
+}
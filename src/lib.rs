@@ -1,4 +1,15 @@
+#[cfg(feature = "async")]
+pub mod aio;
+#[cfg(feature = "crypto")]
+pub mod crypto;
 pub mod error;
+pub mod filter;
 pub mod format;
+#[cfg(feature = "binaries")]
+pub mod logger;
+#[cfg(feature = "crypto")]
 pub mod pki;
+pub mod template;
 mod utils;
+
+pub use crate::utils::TryClone;
@@ -0,0 +1,99 @@
+//! A minimal logger for the linkle binaries, writing their messages and the
+//! ones of linkle to stderr. It is only built with the `binaries` feature:
+//! the library only uses the `log` macros, so programs using it see nothing
+//! unless they install a logger of their own.
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// The environment variable setting the level: `off`, `error`, `warn`,
+/// `info`, `debug` or `trace`.
+pub const LOG_ENV: &str = "LINKLE_LOG";
+
+const LEVELS: [LevelFilter; 6] = [
+    LevelFilter::Off,
+    LevelFilter::Error,
+    LevelFilter::Warn,
+    LevelFilter::Info,
+    LevelFilter::Debug,
+    LevelFilter::Trace,
+];
+
+struct StderrLogger {
+    /// Prefixes of the targets whose messages are shown. Dependencies have
+    /// debug logs of their own, which nobody asked for.
+    targets: &'static [&'static str],
+}
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+            && self
+                .targets
+                .iter()
+                .any(|target| metadata.target().starts_with(target))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match record.level() {
+            log::Level::Error => eprintln!("Error: {}", record.args()),
+            log::Level::Warn => eprintln!("Warning: {}", record.args()),
+            _ => eprintln!("{}", record.args()),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the stderr logger, showing the messages whose target starts with
+/// one of `targets`, e.g. `linkle` and the crate name of the binary. The
+/// level is `info`, or the one of `LINKLE_LOG`, raised by `verbose` steps and
+/// lowered by `quiet` ones.
+pub fn init(targets: &'static [&'static str], verbose: usize, quiet: usize) {
+    let base = match std::env::var(LOG_ENV) {
+        Ok(level) => match level.parse::<LevelFilter>() {
+            Ok(level) => level,
+            Err(_) => {
+                eprintln!("Warning: ignoring invalid {} {}", LOG_ENV, level);
+                LevelFilter::Info
+            }
+        },
+        Err(_) => LevelFilter::Info,
+    };
+    let index = (base as usize + verbose).saturating_sub(quiet);
+    let level = LEVELS[index.min(LEVELS.len() - 1)];
+    let logger = Box::leak(Box::new(StderrLogger { targets }));
+    if log::set_logger(logger).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use log::Level;
+
+    #[test]
+    fn messages_of_the_given_targets_are_shown() {
+        log::set_max_level(LevelFilter::Info);
+        let logger = StderrLogger {
+            targets: &["cargo_nro", "linkle"],
+        };
+        let metadata = |target| {
+            Metadata::builder()
+                .level(Level::Info)
+                .target(target)
+                .build()
+        };
+        assert!(logger.enabled(&metadata("cargo_nro")));
+        assert!(logger.enabled(&metadata("linkle::format::nxo")));
+        assert!(!logger.enabled(&metadata("globset")));
+
+        let logger = StderrLogger {
+            targets: &["linkle"],
+        };
+        assert!(!logger.enabled(&metadata("cargo_nro")));
+    }
+}
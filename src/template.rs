@@ -0,0 +1,70 @@
+//! Ready-to-edit templates of the NPDM and NACP JSON descriptors of new
+//! programs, used by `linkle new`. JSON has no comments, so they are written
+//! as `"//"` keys, which the descriptor parsers ignore.
+
+/// The kinds of programs with a template.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgramKind {
+    /// A background process started at boot, without a NACP.
+    Sysmodule,
+    Application,
+}
+
+const SYSMODULE_NPDM: &str = include_str!("template/sysmodule.npdm.json");
+const APPLICATION_NPDM: &str = include_str!("template/app.npdm.json");
+const APPLICATION_NACP: &str = include_str!("template/app.nacp.json");
+
+/// Replaces the `"{{field}}"` strings of `template` with the JSON strings of
+/// their values.
+fn fill(template: &str, fields: &[(&str, &str)]) -> String {
+    let mut filled = template.to_string();
+    for (field, value) in fields {
+        let placeholder = format!("\"{{{{{}}}}}\"", field);
+        let value = serde_json::Value::from(*value).to_string();
+        filled = filled.replace(&placeholder, &value);
+    }
+    filled
+}
+
+/// The NPDM JSON of a program of the given kind, with the capabilities it
+/// usually needs.
+pub fn npdm(kind: ProgramKind, name: &str, title_id: u64) -> String {
+    let template = match kind {
+        ProgramKind::Sysmodule => SYSMODULE_NPDM,
+        ProgramKind::Application => APPLICATION_NPDM,
+    };
+    let title_id = format!("0x{:016x}", title_id);
+    fill(template, &[("name", name), ("title_id", &title_id)])
+}
+
+/// The NACP JSON of an application, using the homebrew preset.
+pub fn nacp(name: &str, author: &str, title_id: u64) -> String {
+    let title_id = format!("{:016x}", title_id);
+    fill(
+        APPLICATION_NACP,
+        &[("name", name), ("author", author), ("title_id", &title_id)],
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::format::nacp::NacpFile;
+    use crate::format::npdm::Npdm;
+
+    #[test]
+    fn templates_build() {
+        for &kind in &[ProgramKind::Sysmodule, ProgramKind::Application] {
+            let npdm: Npdm =
+                serde_json::from_str(&npdm(kind, "Test \"quoted\"", 0x0100_0000_0000_1000))
+                    .unwrap();
+            assert_eq!(npdm.name, "Test \"quoted\"");
+            assert_eq!(npdm.title_id.0, 0x0100_0000_0000_1000);
+            npdm.to_bytes(&[0; 0x100]).unwrap();
+        }
+        let mut nacp: NacpFile =
+            serde_json::from_str(&nacp("Test", "Author", 0x0100_0000_0001_0000)).unwrap();
+        assert_eq!(nacp.title_id.as_deref(), Some("0100000000010000"));
+        nacp.write(&mut Vec::new()).unwrap();
+    }
+}
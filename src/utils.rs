@@ -10,7 +10,35 @@ pub fn align_up<T: Num + Not<Output = T> + BitAnd<Output = T> + Copy>(addr: T, a
     align_down(addr + (align - T::one()), align)
 }
 
+/// Moves `pos` by `offset`, as done by `SeekFrom::Current` and `SeekFrom::End`.
+/// Returns `None` if the result is negative or overflows.
+pub(crate) fn add_offset(pos: u64, offset: i64) -> Option<u64> {
+    if offset < 0 {
+        pos.checked_sub(-offset as u64)
+    } else {
+        pos.checked_add(offset as u64)
+    }
+}
+
+/// Reads into `buf` until it is full or the end of `input`, returning how
+/// much was read.
+#[cfg(feature = "crypto")]
+pub(crate) fn read_full<R: io::Read>(mut input: R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match input.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(read)
+}
+
 // Why is this not a trait...
+/// Readers that can be cloned into an independent handle on the same data,
+/// so the files of an archive can be read separately.
 pub trait TryClone: Sized {
     fn try_clone(&self) -> std::io::Result<Self>;
 }
@@ -21,6 +49,12 @@ impl TryClone for std::fs::File {
     }
 }
 
+impl<T: Clone> TryClone for io::Cursor<T> {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(self.clone())
+    }
+}
+
 pub struct ReadRange<R> {
     inner: R,
     start_from: u64,
@@ -44,6 +78,17 @@ impl<R> ReadRange<R> {
     }
 }
 
+impl<R: TryClone> TryClone for ReadRange<R> {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(ReadRange {
+            inner: self.inner.try_clone()?,
+            start_from: self.start_from,
+            size: self.size,
+            inner_pos: self.inner_pos,
+        })
+    }
+}
+
 impl<R: io::Read> io::Read for ReadRange<R> {
     fn read(&mut self, mut buf: &mut [u8]) -> io::Result<usize> {
         if self.size < self.inner_pos + buf.len() as u64 {